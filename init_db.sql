@@ -6,4 +6,11 @@ CREATE TABLE IF NOT EXISTS transcode_files (
     error_message VARCHAR,
     file_size BIGINT NOT NULL,
     ffprobe_info VARCHAR
-)
\ No newline at end of file
+);
+
+CREATE TABLE IF NOT EXISTS locks (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    pid BIGINT NOT NULL,
+    hostname VARCHAR NOT NULL,
+    started_on BIGINT NOT NULL
+);
@@ -0,0 +1,8 @@
+CREATE TABLE runs (
+    id INTEGER PRIMARY KEY,
+    started_on BIGINT NOT NULL,
+    ffmpeg_version VARCHAR NOT NULL,
+    libraries VARCHAR NOT NULL
+);
+
+ALTER TABLE transcode_files ADD COLUMN run_id INTEGER REFERENCES runs (id);
@@ -0,0 +1,2 @@
+ALTER TABLE transcode_files ADD COLUMN claimed_by VARCHAR;
+ALTER TABLE transcode_files ADD COLUMN claimed_at BIGINT;
@@ -0,0 +1,3 @@
+ALTER TABLE transcode_files ADD COLUMN output_path VARCHAR;
+ALTER TABLE transcode_files ADD COLUMN verified_at BIGINT;
+ALTER TABLE transcode_files ADD COLUMN verify_error VARCHAR;
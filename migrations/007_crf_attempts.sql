@@ -0,0 +1,9 @@
+CREATE TABLE crf_attempts (
+    id INTEGER PRIMARY KEY,
+    file_id INTEGER NOT NULL,
+    crf INTEGER NOT NULL,
+    output_size BIGINT NOT NULL,
+    attempted_on BIGINT NOT NULL
+);
+
+CREATE INDEX crf_attempts_file_id ON crf_attempts (file_id);
@@ -0,0 +1,7 @@
+ALTER TABLE transcode_files ADD COLUMN error_details BLOB;
+
+-- messages stored before they were sanitized can contain escape sequences and
+-- the whole ffmpeg output
+UPDATE transcode_files
+SET error_details = compress_error_details(error_message), error_message = sanitize_error(error_message)
+WHERE error_message IS NOT NULL;
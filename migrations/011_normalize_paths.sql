@@ -0,0 +1,12 @@
+-- macOS returns decomposed (NFD) file names while paths from elsewhere are
+-- usually composed (NFC), which added the same file twice. Keep the row that was
+-- updated last, then store the paths in the form the transcoder looks them up in.
+DELETE FROM transcode_files WHERE rowid IN (
+    SELECT CASE WHEN d.updated_on > n.updated_on THEN n.rowid ELSE d.rowid END
+    FROM transcode_files AS d
+    JOIN transcode_files AS n ON n.path = normalize_path(d.path) AND n.rowid != d.rowid
+);
+
+DELETE FROM crf_attempts WHERE file_id NOT IN (SELECT rowid FROM transcode_files);
+
+UPDATE OR IGNORE transcode_files SET path = normalize_path(path) WHERE path != nfc(path);
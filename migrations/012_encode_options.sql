@@ -0,0 +1 @@
+ALTER TABLE transcode_files ADD COLUMN encode_options VARCHAR;
@@ -0,0 +1,38 @@
+-- Completed files moved out of the working tables by `archive`. The columns
+-- follow transcode_files, new columns there have to be added here as well.
+CREATE TABLE archive_transcode_files (
+    id INTEGER PRIMARY KEY,
+    archived_on BIGINT NOT NULL,
+    "path" VARCHAR NOT NULL UNIQUE,
+    "status" VARCHAR NOT NULL,
+    created_on BIGINT NOT NULL,
+    updated_on BIGINT NOT NULL,
+    error_message VARCHAR,
+    file_size BIGINT NOT NULL,
+    ffprobe_info VARCHAR,
+    run_id INTEGER REFERENCES runs (id),
+    thumbnail_path VARCHAR,
+    claimed_by VARCHAR,
+    claimed_at BIGINT,
+    encode_seconds REAL,
+    library VARCHAR,
+    output_path VARCHAR,
+    verified_at BIGINT,
+    verify_error VARCHAR,
+    skip_reason VARCHAR,
+    error_details BLOB,
+    encoder_rule VARCHAR,
+    encode_options VARCHAR,
+    audio_hash VARCHAR
+);
+
+-- file_id is the id of the archived file
+CREATE TABLE archive_crf_attempts (
+    id INTEGER PRIMARY KEY,
+    file_id INTEGER NOT NULL,
+    crf INTEGER NOT NULL,
+    output_size BIGINT NOT NULL,
+    attempted_on BIGINT NOT NULL
+);
+
+CREATE INDEX archive_crf_attempts_file_id ON archive_crf_attempts (file_id);
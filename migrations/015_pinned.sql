@@ -0,0 +1,5 @@
+-- Files that `pin` keeps out of every transcode run, with an optional note
+ALTER TABLE transcode_files ADD COLUMN pinned BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE transcode_files ADD COLUMN note VARCHAR;
+ALTER TABLE archive_transcode_files ADD COLUMN pinned BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE archive_transcode_files ADD COLUMN note VARCHAR;
@@ -0,0 +1,7 @@
+-- CPU time and peak memory of the last successful encode of a file
+ALTER TABLE transcode_files ADD COLUMN cpu_seconds REAL;
+ALTER TABLE transcode_files ADD COLUMN peak_rss INTEGER;
+ALTER TABLE transcode_files ADD COLUMN cpu_percent REAL;
+ALTER TABLE archive_transcode_files ADD COLUMN cpu_seconds REAL;
+ALTER TABLE archive_transcode_files ADD COLUMN peak_rss INTEGER;
+ALTER TABLE archive_transcode_files ADD COLUMN cpu_percent REAL;
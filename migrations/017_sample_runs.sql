@@ -0,0 +1,2 @@
+-- Runs started with --sample-random, to try settings on a few files
+ALTER TABLE runs ADD COLUMN sample INTEGER NOT NULL DEFAULT 0;
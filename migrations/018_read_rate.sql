@@ -0,0 +1,5 @@
+-- How the source was read during the last successful encode, and how fast
+ALTER TABLE transcode_files ADD COLUMN io_mode TEXT;
+ALTER TABLE transcode_files ADD COLUMN read_rate REAL;
+ALTER TABLE archive_transcode_files ADD COLUMN io_mode TEXT;
+ALTER TABLE archive_transcode_files ADD COLUMN read_rate REAL;
@@ -0,0 +1,44 @@
+-- Files get an id column that users refer to them by. The implicit rowid they
+-- were referred to by before can change with VACUUM, an INTEGER PRIMARY KEY
+-- can't. The ids are the current rowids, so crf_attempts.file_id and the IDs
+-- users already saw stay valid. AUTOINCREMENT keeps the ids of forgotten files
+-- from being given out again.
+CREATE TABLE transcode_files_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    "path" VARCHAR NOT NULL UNIQUE,
+    "status" VARCHAR NOT NULL DEFAULT 'pending',
+    created_on BIGINT NOT NULL,
+    updated_on BIGINT NOT NULL,
+    error_message VARCHAR,
+    file_size BIGINT NOT NULL,
+    ffprobe_info VARCHAR,
+    run_id INTEGER REFERENCES runs (id),
+    thumbnail_path VARCHAR,
+    claimed_by VARCHAR,
+    claimed_at BIGINT,
+    encode_seconds REAL,
+    library VARCHAR,
+    output_path VARCHAR,
+    verified_at BIGINT,
+    verify_error VARCHAR,
+    skip_reason VARCHAR,
+    error_details BLOB,
+    encoder_rule VARCHAR,
+    encode_options VARCHAR,
+    audio_hash VARCHAR,
+    pinned BOOLEAN NOT NULL DEFAULT 0,
+    note VARCHAR,
+    cpu_seconds REAL,
+    peak_rss INTEGER,
+    cpu_percent REAL,
+    io_mode TEXT,
+    read_rate REAL
+);
+
+INSERT INTO transcode_files_new (id, path, status, created_on, updated_on, error_message, file_size, ffprobe_info, run_id, thumbnail_path, claimed_by, claimed_at, encode_seconds, library, output_path, verified_at, verify_error, skip_reason, error_details, encoder_rule, encode_options, audio_hash, pinned, note, cpu_seconds, peak_rss, cpu_percent, io_mode, read_rate)
+SELECT rowid, path, status, created_on, updated_on, error_message, file_size, ffprobe_info, run_id, thumbnail_path, claimed_by, claimed_at, encode_seconds, library, output_path, verified_at, verify_error, skip_reason, error_details, encoder_rule, encode_options, audio_hash, pinned, note, cpu_seconds, peak_rss, cpu_percent, io_mode, read_rate
+FROM transcode_files;
+
+DROP TABLE transcode_files;
+
+ALTER TABLE transcode_files_new RENAME TO transcode_files;
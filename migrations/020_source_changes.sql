@@ -0,0 +1,6 @@
+-- When the source was last modified as the scan saw it, and what changed in it
+-- since then when a transcode failed, as JSON
+ALTER TABLE transcode_files ADD COLUMN source_modified BIGINT;
+ALTER TABLE transcode_files ADD COLUMN source_changes VARCHAR;
+ALTER TABLE archive_transcode_files ADD COLUMN source_modified BIGINT;
+ALTER TABLE archive_transcode_files ADD COLUMN source_changes VARCHAR;
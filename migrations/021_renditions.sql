@@ -0,0 +1,4 @@
+-- The outputs of the renditions of a file transcoded with --renditions, with
+-- their sizes, as JSON
+ALTER TABLE transcode_files ADD COLUMN renditions VARCHAR;
+ALTER TABLE archive_transcode_files ADD COLUMN renditions VARCHAR;
@@ -0,0 +1,3 @@
+-- Runs select the files of one status ordered by size, which an index on both
+-- keeps from scanning the whole table on large databases.
+CREATE INDEX transcode_files_status_size ON transcode_files (status, file_size);
@@ -0,0 +1,3 @@
+-- The id of an archived file in transcode_files, so that `unarchive` gives it
+-- back the id users know it by. Files archived before are given a new id.
+ALTER TABLE archive_transcode_files ADD COLUMN file_id INTEGER;
@@ -0,0 +1,61 @@
+use jiff::{RoundMode, SignedDuration, Span, SpanRound, Timestamp, Unit};
+
+/// How long a file queued at `created_on` has been in the queue at `now`.
+pub fn queue_age(created_on: Timestamp, now: Timestamp) -> SignedDuration {
+    now.duration_since(created_on).max(SignedDuration::ZERO)
+}
+
+/// Formats an age in its largest unit up to days, rounded down, e.g. `95d`,
+/// `5h` or `12m`.
+pub fn format_age(age: SignedDuration) -> String {
+    let unit = if age >= SignedDuration::from_hours(24) {
+        Unit::Day
+    } else if age >= SignedDuration::from_hours(1) {
+        Unit::Hour
+    } else {
+        Unit::Minute
+    };
+    let span = Span::try_from(age.max(SignedDuration::ZERO))
+        .and_then(|span| {
+            span.round(
+                SpanRound::new()
+                    .largest(unit)
+                    .smallest(unit)
+                    .mode(RoundMode::Trunc)
+                    .days_are_24_hours(),
+            )
+        })
+        .unwrap_or_default();
+    format!("{span:#}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_queue_age() {
+        let created_on = Timestamp::from_second(1_700_000_000).unwrap();
+        assert_eq!(
+            SignedDuration::from_hours(36),
+            queue_age(created_on, created_on + SignedDuration::from_hours(36))
+        );
+        // a clock that went backwards doesn't make files younger than new
+        assert_eq!(
+            SignedDuration::ZERO,
+            queue_age(created_on, created_on - SignedDuration::from_secs(5))
+        );
+    }
+
+    #[test]
+    fn test_format_age() {
+        let days = |days: i64| SignedDuration::from_hours(days * 24);
+        assert_eq!("95d", format_age(days(95)));
+        assert_eq!("400d", format_age(days(400)));
+        assert_eq!("1d", format_age(days(1) + SignedDuration::from_hours(23)));
+        assert_eq!("5h", format_age(SignedDuration::from_mins(5 * 60 + 59)));
+        assert_eq!("12m", format_age(SignedDuration::from_secs(12 * 60 + 30)));
+        assert_eq!("0s", format_age(SignedDuration::from_secs(20)));
+        assert_eq!("0s", format_age(SignedDuration::from_secs(-20)));
+    }
+}
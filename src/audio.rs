@@ -0,0 +1,666 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::ffprobe::{FfProbe, Stream};
+
+/// Codecs that are efficient enough to keep as long as their bitrate isn't excessive.
+const COPY_CODECS: &[&str] = &["aac", "opus", "vorbis", "mp3", "ac3", "eac3"];
+
+/// Words in the title or handler name of commentary and audio description tracks.
+const COMMENTARY_WORDS: &[&str] = &["commentary", "director", "audio description", "descriptive"];
+
+/// Whether an audio stream is a commentary or an audio description, by its
+/// dispositions or by the words in its title or handler name, compared
+/// case-insensitively.
+pub fn is_commentary(stream: &Stream) -> bool {
+    let disposition = &stream.disposition;
+    if disposition.comment != 0 || disposition.visual_impaired != 0 || disposition.descriptions != 0
+    {
+        return true;
+    }
+    let Some(tags) = &stream.tags else {
+        return false;
+    };
+    [&tags.title, &tags.handler_name]
+        .into_iter()
+        .flatten()
+        .map(|name| name.to_lowercase())
+        .any(|name| COMMENTARY_WORDS.iter().any(|word| name.contains(word)))
+}
+
+/// Summary of an audio stream, taken from the ffprobe info.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AudioTrack {
+    /// Index among the file's audio streams, as used in `-c:a:N`.
+    pub index: usize,
+    pub codec: String,
+    /// Bits per second, if known.
+    pub bitrate: Option<u64>,
+    pub channels: Option<u32>,
+    /// The title of the stream, or its handler name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    /// Marked as the default audio track.
+    #[serde(default)]
+    pub default: bool,
+    /// A commentary or audio description, see [`is_commentary`].
+    #[serde(default)]
+    pub commentary: bool,
+}
+
+impl AudioTrack {
+    pub fn from_probe(info: &FfProbe) -> Vec<AudioTrack> {
+        info.streams
+            .iter()
+            .filter(|s| s.is_audio())
+            .enumerate()
+            .map(|(index, stream)| AudioTrack {
+                index,
+                codec: stream.codec_name.clone().unwrap_or_default(),
+                bitrate: [&stream.bit_rate, &stream.max_bit_rate]
+                    .into_iter()
+                    .find_map(|b| b.as_deref().and_then(|b| b.parse().ok())),
+                channels: stream.channels.map(|c| c as u32),
+                title: stream
+                    .tags
+                    .as_ref()
+                    .and_then(|tags| tags.title.clone().or_else(|| tags.handler_name.clone())),
+                default: stream.disposition.default != 0,
+                commentary: is_commentary(stream),
+            })
+            .collect()
+    }
+}
+
+impl fmt::Display for AudioTrack {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "audio track {} ({}", self.index, self.codec)?;
+        if let Some(bitrate) = self.bitrate {
+            write!(f, ", {} kb/s", bitrate / 1000)?;
+        }
+        if let Some(channels) = self.channels {
+            write!(f, ", {channels} ch")?;
+        }
+        if let Some(title) = &self.title {
+            write!(f, ", \"{title}\"")?;
+        }
+        write!(f, ")")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct AudioOptions {
+    /// Re-encode tracks above this bitrate, or in a codec that's not efficient.
+    /// When not set, all tracks are copied.
+    pub reencode_above: Option<u64>,
+    /// Codec for re-encoded tracks.
+    pub codec: String,
+    /// Bitrate for re-encoded stereo tracks, mono tracks get half of it.
+    pub bitrate: u64,
+    /// Leave all audio out of the output.
+    #[serde(default)]
+    pub drop: bool,
+    /// Leave commentary and audio description tracks out of the output.
+    #[serde(default)]
+    pub drop_commentary: bool,
+    /// Tracks in other codecs are encoded, e.g. the ones a `--device` plays.
+    /// Any codec can be copied when empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allowed_codecs: Vec<String>,
+}
+
+impl AudioOptions {
+    /// Whether the audio tracks are mapped one by one, because some of them may
+    /// be encoded.
+    pub fn maps_tracks(&self) -> bool {
+        self.reencode_above.is_some() || !self.allowed_codecs.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioDecision {
+    Copy,
+    Encode {
+        codec: String,
+        bitrate: u64,
+    },
+    /// Left out of the output with `--drop-audio` or `--drop-commentary`.
+    Drop,
+}
+
+impl fmt::Display for AudioDecision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioDecision::Copy => write!(f, "copy"),
+            AudioDecision::Encode { codec, bitrate } => {
+                write!(f, "encode to {codec} at {} kb/s", bitrate / 1000)
+            }
+            AudioDecision::Drop => write!(f, "drop"),
+        }
+    }
+}
+
+/// Decides whether to copy an audio track. Tracks in an efficient codec are copied
+/// unless their bitrate is above the threshold, tracks without bitrate info are
+/// only copied when their codec is efficient. Tracks in a codec that isn't in
+/// `allowed_codecs` are always encoded.
+pub fn decide(track: &AudioTrack, options: &AudioOptions) -> AudioDecision {
+    if options.drop {
+        return AudioDecision::Drop;
+    }
+    let allowed =
+        options.allowed_codecs.is_empty() || options.allowed_codecs.contains(&track.codec);
+    let copy = match options.reencode_above {
+        None => true,
+        Some(threshold) => {
+            COPY_CODECS.contains(&track.codec.as_str())
+                && track.bitrate.is_none_or(|bitrate| bitrate <= threshold)
+        }
+    };
+    if copy && allowed {
+        return AudioDecision::Copy;
+    }
+
+    let target = options.bitrate * track.channels.unwrap_or(2).clamp(1, 2) as u64 / 2;
+    AudioDecision::Encode {
+        codec: options.codec.clone(),
+        bitrate: track.bitrate.map_or(target, |bitrate| bitrate.min(target)),
+    }
+}
+
+/// Decides for all audio tracks of a file. With `drop_commentary`, commentary
+/// tracks are dropped, except for the default track, or the first one if no track
+/// is marked as the default, so that there always is some audio left.
+pub fn decide_all(
+    tracks: &[AudioTrack],
+    options: &AudioOptions,
+) -> Vec<(AudioTrack, AudioDecision)> {
+    let kept = tracks
+        .iter()
+        .find(|track| track.default)
+        .or(tracks.first())
+        .map(|track| track.index);
+    tracks
+        .iter()
+        .map(|track| {
+            let decision =
+                if options.drop_commentary && track.commentary && Some(track.index) != kept {
+                    AudioDecision::Drop
+                } else {
+                    decide(track, options)
+                };
+            (track.clone(), decision)
+        })
+        .collect()
+}
+
+/// ffmpeg arguments for the audio tracks. Without a threshold, all audio is copied
+/// with ffmpeg's default stream selection. Dropped audio keeps the default
+/// selection for the video and subtitles. When only some tracks are dropped, the
+/// others are mapped one by one.
+pub fn audio_args(
+    decisions: &[(AudioTrack, AudioDecision)],
+    options: &AudioOptions,
+) -> Vec<String> {
+    if options.drop {
+        return vec!["-an".into()];
+    }
+    let dropped = decisions
+        .iter()
+        .any(|(_, decision)| *decision == AudioDecision::Drop);
+    if !options.maps_tracks() && !dropped {
+        return vec!["-c:a".into(), "copy".into()];
+    }
+
+    let mut args: Vec<String> = vec!["-map".into(), "0:v:0".into()];
+    let kept: Vec<_> = decisions
+        .iter()
+        .filter(|(_, decision)| *decision != AudioDecision::Drop)
+        .collect();
+    if dropped {
+        for (track, _) in &kept {
+            args.extend(["-map".into(), format!("0:a:{}", track.index)]);
+        }
+    } else {
+        args.extend(["-map".into(), "0:a?".into()]);
+    }
+    // the output streams are numbered without the dropped tracks
+    for (index, (_, decision)) in kept.into_iter().enumerate() {
+        match decision {
+            AudioDecision::Copy => {
+                args.extend([format!("-c:a:{index}"), "copy".into()]);
+            }
+            AudioDecision::Encode { codec, bitrate } => args.extend([
+                format!("-c:a:{index}"),
+                codec.clone(),
+                format!("-b:a:{index}"),
+                bitrate.to_string(),
+            ]),
+            AudioDecision::Drop => {}
+        }
+    }
+    args
+}
+
+/// Estimated size of the dropped audio tracks of a file that is `duration`
+/// seconds long. Tracks without bitrate info count as nothing.
+pub fn dropped_bytes(decisions: &[(AudioTrack, AudioDecision)], duration: f64) -> u64 {
+    decisions
+        .iter()
+        .filter(|(_, decision)| *decision == AudioDecision::Drop)
+        .filter_map(|(track, _)| track.bitrate)
+        .map(|bitrate| (bitrate as f64 / 8.0 * duration) as u64)
+        .sum()
+}
+
+/// Parses bitrates like `128k`, `1.5M` or `192000`.
+pub fn parse_bitrate(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, multiplier) = match value.char_indices().last() {
+        Some((index, 'k' | 'K')) => (&value[..index], 1000.0),
+        Some((index, 'm' | 'M')) => (&value[..index], 1_000_000.0),
+        _ => (value, 1.0),
+    };
+    match number.parse::<f64>() {
+        Ok(number) if number > 0.0 => Ok((number * multiplier) as u64),
+        _ => Err(format!(
+            "invalid bitrate '{value}', expected e.g. 128k or 192000"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffprobe::fixtures;
+
+    fn track(codec: &str, bitrate: Option<u64>, channels: Option<u32>) -> AudioTrack {
+        AudioTrack {
+            index: 0,
+            codec: codec.into(),
+            bitrate,
+            channels,
+            title: None,
+            default: false,
+            commentary: false,
+        }
+    }
+
+    fn options(reencode_above: Option<u64>) -> AudioOptions {
+        AudioOptions {
+            reencode_above,
+            codec: "aac".into(),
+            bitrate: 160_000,
+            drop: false,
+            drop_commentary: false,
+            allowed_codecs: vec![],
+        }
+    }
+
+    fn encode(bitrate: u64) -> AudioDecision {
+        AudioDecision::Encode {
+            codec: "aac".into(),
+            bitrate,
+        }
+    }
+
+    #[test]
+    fn test_copy_by_default() {
+        let options = options(None);
+        assert_eq!(
+            AudioDecision::Copy,
+            decide(&track("flac", None, Some(2)), &options)
+        );
+        assert_eq!(vec!["-c:a", "copy"], audio_args(&[], &options));
+    }
+
+    #[test]
+    fn test_allowed_codecs() {
+        let options = AudioOptions {
+            allowed_codecs: vec!["aac".into(), "ac3".into()],
+            ..options(None)
+        };
+        assert!(options.maps_tracks());
+        assert_eq!(
+            AudioDecision::Copy,
+            decide(&track("ac3", Some(640_000), Some(6)), &options)
+        );
+        assert_eq!(
+            encode(160_000),
+            decide(&track("flac", None, Some(6)), &options)
+        );
+        let decisions = vec![(track("flac", None, Some(2)), encode(160_000))];
+        assert_eq!(
+            vec![
+                "-map", "0:v:0", "-map", "0:a?", "-c:a:0", "aac", "-b:a:0", "160000"
+            ],
+            audio_args(&decisions, &options)
+        );
+        // the bitrate limit still applies to allowed codecs
+        let options = AudioOptions {
+            allowed_codecs: vec!["aac".into(), "flac".into()],
+            ..options.clone()
+        };
+        let limited = AudioOptions {
+            reencode_above: Some(256_000),
+            ..options
+        };
+        assert_eq!(
+            encode(160_000),
+            decide(&track("flac", None, Some(2)), &limited)
+        );
+    }
+
+    #[test]
+    fn test_efficient_codecs() {
+        let options = options(Some(256_000));
+        assert_eq!(
+            AudioDecision::Copy,
+            decide(&track("aac", Some(128_000), Some(2)), &options)
+        );
+        assert_eq!(
+            AudioDecision::Copy,
+            decide(&track("opus", Some(256_000), Some(6)), &options)
+        );
+        assert_eq!(
+            encode(160_000),
+            decide(&track("ac3", Some(640_000), Some(6)), &options)
+        );
+    }
+
+    #[test]
+    fn test_missing_bitrate() {
+        let options = options(Some(256_000));
+        assert_eq!(
+            AudioDecision::Copy,
+            decide(&track("aac", None, Some(2)), &options)
+        );
+        assert_eq!(
+            encode(160_000),
+            decide(&track("dts", None, Some(6)), &options)
+        );
+        assert_eq!(
+            encode(160_000),
+            decide(&track("wmav2", None, None), &options)
+        );
+    }
+
+    #[test]
+    fn test_lossless_codecs() {
+        let options = options(Some(256_000));
+        for codec in ["flac", "truehd", "pcm_s16le", "alac"] {
+            assert_eq!(
+                encode(160_000),
+                decide(&track(codec, Some(1_411_000), Some(2)), &options)
+            );
+            assert_eq!(
+                encode(160_000),
+                decide(&track(codec, None, Some(2)), &options)
+            );
+        }
+    }
+
+    #[test]
+    fn test_mono_commentary() {
+        let options = options(Some(256_000));
+        assert_eq!(
+            AudioDecision::Copy,
+            decide(&track("aac", Some(64_000), Some(1)), &options)
+        );
+        assert_eq!(
+            encode(80_000),
+            decide(&track("pcm_s16le", Some(705_600), Some(1)), &options)
+        );
+        // never encode at a higher bitrate than the source
+        assert_eq!(
+            encode(48_000),
+            decide(&track("wmav2", Some(48_000), Some(1)), &options)
+        );
+    }
+
+    #[test]
+    fn test_per_track_args() {
+        let options = options(Some(256_000));
+        let tracks = [
+            AudioTrack {
+                index: 0,
+                ..track("flac", None, Some(2))
+            },
+            AudioTrack {
+                index: 1,
+                ..track("aac", Some(64_000), Some(1))
+            },
+        ];
+        let decisions: Vec<_> = tracks
+            .into_iter()
+            .map(|t| {
+                let decision = decide(&t, &options);
+                (t, decision)
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                "-map", "0:v:0", "-map", "0:a?", "-c:a:0", "aac", "-b:a:0", "160000", "-c:a:1",
+                "copy"
+            ],
+            audio_args(&decisions, &options)
+        );
+    }
+
+    #[test]
+    fn test_drop_audio() {
+        let drop = AudioOptions {
+            drop: true,
+            ..options(Some(256_000))
+        };
+        let tracks = [
+            AudioTrack {
+                index: 0,
+                ..track("ac3", Some(448_000), Some(6))
+            },
+            AudioTrack {
+                index: 1,
+                ..track("aac", Some(64_000), Some(1))
+            },
+            AudioTrack {
+                index: 2,
+                ..track("pcm_s16le", None, Some(2))
+            },
+        ];
+        let decisions: Vec<_> = tracks
+            .into_iter()
+            .map(|t| {
+                let decision = decide(&t, &drop);
+                (t, decision)
+            })
+            .collect();
+        assert!(
+            decisions
+                .iter()
+                .all(|(_, decision)| *decision == AudioDecision::Drop)
+        );
+        let args = audio_args(&decisions, &drop);
+        assert_eq!(vec!["-an"], args);
+        assert!(!args.iter().any(|arg| arg.starts_with("0:a")));
+        // the default selection is replaced as well
+        let copy_all = AudioOptions {
+            drop: true,
+            ..options(None)
+        };
+        assert_eq!(vec!["-an"], audio_args(&decisions, &copy_all));
+
+        // 512 kb/s for 10 seconds, the track without a bitrate isn't counted
+        assert_eq!(640_000, dropped_bytes(&decisions, 10.0));
+        let copied = [(track("aac", Some(128_000), Some(2)), AudioDecision::Copy)];
+        assert_eq!(0, dropped_bytes(&copied, 10.0));
+    }
+
+    #[test]
+    fn test_tracks_from_probe() {
+        let info = FfProbe {
+            streams: vec![
+                Stream {
+                    codec_type: Some("video".into()),
+                    ..Default::default()
+                },
+                Stream {
+                    codec_type: Some("audio".into()),
+                    codec_name: Some("aac".into()),
+                    max_bit_rate: Some("128000".into()),
+                    channels: Some(2),
+                    ..Default::default()
+                },
+                Stream {
+                    codec_type: Some("audio".into()),
+                    codec_name: Some("flac".into()),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let tracks = AudioTrack::from_probe(&info);
+        assert_eq!(
+            vec![
+                track("aac", Some(128_000), Some(2)),
+                AudioTrack {
+                    index: 1,
+                    ..track("flac", None, None)
+                }
+            ],
+            tracks
+        );
+    }
+
+    #[test]
+    fn test_is_commentary() {
+        let fixtures = fixtures();
+        // "SoundHandler" of the mp4 and "Surround 5.1" of the mkv
+        let audio = |info: &FfProbe| info.streams.iter().find(|s| s.is_audio()).unwrap().clone();
+        let mp4 = audio(&fixtures[0]);
+        let mkv = audio(&fixtures[1]);
+        assert!(!is_commentary(&mp4));
+        assert!(!is_commentary(&mkv));
+
+        let titled = |title: &str| {
+            let mut stream = mkv.clone();
+            stream.tags.as_mut().unwrap().title = Some(title.into());
+            stream
+        };
+        assert!(is_commentary(&titled("Director's Commentary")));
+        assert!(is_commentary(&titled("COMMENTARY with the cast")));
+        assert!(is_commentary(&titled("Audio Description")));
+        assert!(!is_commentary(&titled("Stereo")));
+
+        let mut handler = mp4.clone();
+        handler.tags.as_mut().unwrap().handler_name = Some("Commentary".into());
+        assert!(is_commentary(&handler));
+        let mut untagged = mp4.clone();
+        untagged.tags = None;
+        assert!(!is_commentary(&untagged));
+
+        let mut comment = mkv.clone();
+        comment.disposition.comment = 1;
+        assert!(is_commentary(&comment));
+        let mut described = mkv.clone();
+        described.disposition.visual_impaired = 1;
+        assert!(is_commentary(&described));
+
+        // the stored info keeps what's needed to recognize them
+        let mut info = fixtures[1].clone();
+        let index = info.streams.iter().position(|s| s.is_audio()).unwrap();
+        for mut stream in [titled("Commentary"), described] {
+            stream.disposition.default = 0;
+            info.streams.push(stream);
+        }
+        info.streams[index].disposition.default = 1;
+        let tracks = AudioTrack::from_probe(&info.stripped());
+        let flags: Vec<_> = tracks.iter().map(|t| (t.default, t.commentary)).collect();
+        assert_eq!(vec![(true, false), (false, true), (false, true)], flags);
+        assert_eq!(Some("Commentary".into()), tracks[1].title);
+    }
+
+    fn commentary(index: usize, default: bool) -> AudioTrack {
+        AudioTrack {
+            index,
+            title: Some("Commentary".into()),
+            default,
+            commentary: true,
+            ..track("aac", Some(128_000), Some(2))
+        }
+    }
+
+    #[test]
+    fn test_drop_commentary() {
+        let options = AudioOptions {
+            drop_commentary: true,
+            ..options(None)
+        };
+        let tracks = [
+            commentary(0, false),
+            AudioTrack {
+                index: 1,
+                default: true,
+                ..track("ac3", Some(448_000), Some(6))
+            },
+            commentary(2, false),
+        ];
+        let decisions = decide_all(&tracks, &options);
+        let kinds: Vec<_> = decisions.iter().map(|(_, d)| d.clone()).collect();
+        assert_eq!(
+            vec![
+                AudioDecision::Drop,
+                AudioDecision::Copy,
+                AudioDecision::Drop
+            ],
+            kinds
+        );
+        // the kept track is mapped by itself, numbered from zero in the output
+        assert_eq!(
+            vec!["-map", "0:v:0", "-map", "0:a:1", "-c:a:0", "copy"],
+            audio_args(&decisions, &options)
+        );
+        assert_eq!(256_000, dropped_bytes(&decisions, 8.0));
+
+        // without the flag, nothing is dropped
+        let decisions = decide_all(&tracks, &super::tests::options(None));
+        assert!(decisions.iter().all(|(_, d)| *d == AudioDecision::Copy));
+        assert_eq!(vec!["-c:a", "copy"], audio_args(&decisions, &options));
+    }
+
+    #[test]
+    fn test_keep_some_audio() {
+        let options = AudioOptions {
+            drop_commentary: true,
+            ..options(Some(256_000))
+        };
+        // the default track stays, even if it looks like a commentary
+        let decisions = decide_all(&[commentary(0, false), commentary(1, true)], &options);
+        let kept: Vec<_> = decisions
+            .iter()
+            .filter(|(_, d)| *d != AudioDecision::Drop)
+            .map(|(t, _)| t.index)
+            .collect();
+        assert_eq!(vec![1], kept);
+        // without a default track, the first one stays
+        let decisions = decide_all(&[commentary(0, false), commentary(1, false)], &options);
+        assert_eq!(AudioDecision::Copy, decisions[0].1);
+        assert_eq!(AudioDecision::Drop, decisions[1].1);
+        assert_eq!(
+            vec!["-map", "0:v:0", "-map", "0:a:0", "-c:a:0", "copy"],
+            audio_args(&decisions, &options)
+        );
+        assert!(decide_all(&[], &options).is_empty());
+    }
+
+    #[test]
+    fn test_parse_bitrate() {
+        assert_eq!(Ok(128_000), parse_bitrate("128k"));
+        assert_eq!(Ok(1_500_000), parse_bitrate("1.5M"));
+        assert_eq!(Ok(192_000), parse_bitrate("192000"));
+        assert!(parse_bitrate("fast").is_err());
+        assert!(parse_bitrate("-5k").is_err());
+    }
+}
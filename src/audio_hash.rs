@@ -0,0 +1,271 @@
+use std::fmt;
+
+use camino::Utf8Path;
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+use crate::audio::{AudioDecision, AudioOptions, AudioTrack};
+use crate::binaries::{self, Binary};
+use crate::ffprobe::commandline_error;
+
+/// Outcome of comparing the copied audio of an output with its source, stored in
+/// the `audio_hash` column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioHash {
+    Match,
+    /// The audio differs, e.g. because the muxer dropped or trimmed packets.
+    Mismatch,
+}
+
+impl AudioHash {
+    /// The value stored in the `audio_hash` column.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AudioHash::Match => "match",
+            AudioHash::Mismatch => "mismatch",
+        }
+    }
+}
+
+impl fmt::Display for AudioHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioHash::Match => write!(f, "matches the source"),
+            AudioHash::Mismatch => write!(f, "differs from the source"),
+        }
+    }
+}
+
+/// Result of [`check`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum AudioCheck {
+    Match,
+    Mismatch {
+        source: String,
+        output: String,
+    },
+    /// The audio isn't expected to be identical, or there is nothing to compare.
+    Skipped(&'static str),
+}
+
+impl AudioCheck {
+    /// The result to record, `None` when nothing was compared.
+    pub fn result(&self) -> Option<AudioHash> {
+        match self {
+            AudioCheck::Match => Some(AudioHash::Match),
+            AudioCheck::Mismatch { .. } => Some(AudioHash::Mismatch),
+            AudioCheck::Skipped(_) => None,
+        }
+    }
+}
+
+/// Arguments that hash the packets of the first audio stream without decoding them.
+fn hash_args(input: &Utf8Path) -> Vec<String> {
+    [
+        "-v",
+        "error",
+        "-i",
+        input.as_str(),
+        "-map",
+        "0:a:0",
+        "-c",
+        "copy",
+        "-f",
+        "md5",
+        "-",
+    ]
+    .map(String::from)
+    .to_vec()
+}
+
+/// Reads the hash from the output of ffmpeg's md5 muxer, `MD5=<hex>`.
+fn parse_hash(output: &str) -> Option<String> {
+    output
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("MD5="))
+        .filter(|hash| hash.len() == 32 && hash.chars().all(|c| c.is_ascii_hexdigit()))
+        .map(str::to_lowercase)
+}
+
+/// Hashes the first audio stream of a file with ffmpeg.
+pub fn md5(input: &Utf8Path) -> Result<String> {
+    let output = binaries::command(Binary::Ffmpeg)
+        .args(hash_args(input))
+        .output()
+        .map_err(|e| binaries::spawn_error(Binary::Ffmpeg, e))?;
+    if !output.status.success() {
+        return Err(commandline_error("ffmpeg", output));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    parse_hash(&stdout).ok_or_else(|| eyre!("unexpected output of the md5 muxer: {stdout}"))
+}
+
+/// Compares the first audio stream of the output with the source's, when it was
+/// copied and so should be bit-identical.
+pub fn check(
+    decisions: &[(AudioTrack, AudioDecision)],
+    options: &AudioOptions,
+    source: &Utf8Path,
+    output: &Utf8Path,
+    hash: impl Fn(&Utf8Path) -> Result<String>,
+) -> Result<AudioCheck> {
+    let Some((_, decision)) = decisions.first() else {
+        return Ok(AudioCheck::Skipped("the source has no audio"));
+    };
+    match decision {
+        AudioDecision::Copy => {}
+        AudioDecision::Encode { .. } => return Ok(AudioCheck::Skipped("the audio is re-encoded")),
+        AudioDecision::Drop => return Ok(AudioCheck::Skipped("the audio is dropped")),
+    }
+    // ffmpeg's default stream selection may pick another track than the first
+    if !options.maps_tracks() && decisions.len() > 1 {
+        return Ok(AudioCheck::Skipped(
+            "ffmpeg picks one of several audio tracks",
+        ));
+    }
+    let source = hash(source)?;
+    let output = hash(output)?;
+    Ok(if source == output {
+        AudioCheck::Match
+    } else {
+        AudioCheck::Mismatch { source, output }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    fn track(index: usize) -> AudioTrack {
+        AudioTrack {
+            index,
+            codec: "aac".into(),
+            bitrate: Some(128_000),
+            channels: Some(2),
+            title: None,
+            default: false,
+            commentary: false,
+        }
+    }
+
+    fn options(reencode_above: Option<u64>) -> AudioOptions {
+        AudioOptions {
+            reencode_above,
+            codec: "aac".into(),
+            bitrate: 160_000,
+            drop: false,
+            drop_commentary: false,
+            allowed_codecs: vec![],
+        }
+    }
+
+    #[test]
+    fn test_hash_args() {
+        assert_eq!(
+            "-v error -i /a b.mkv -map 0:a:0 -c copy -f md5 -",
+            hash_args("/a b.mkv".into()).join(" ")
+        );
+    }
+
+    #[test]
+    fn test_parse_hash() {
+        assert_eq!(
+            Some("d41d8cd98f00b204e9800998ecf8427e".into()),
+            parse_hash("MD5=D41D8CD98F00B204E9800998ECF8427E\n")
+        );
+        assert_eq!(None, parse_hash(""));
+        assert_eq!(None, parse_hash("MD5=nothex"));
+        assert_eq!(None, parse_hash("SHA256=d41d8cd98f00b204e9800998ecf8427e"));
+    }
+
+    #[test]
+    fn test_check() -> Result<()> {
+        let hashed = RefCell::new(vec![]);
+        let hash = |path: &Utf8Path| -> Result<String> {
+            hashed.borrow_mut().push(path.to_string());
+            Ok(match path.as_str() {
+                "/trimmed_av1.mp4" => "bbbb".into(),
+                _ => "aaaa".into(),
+            })
+        };
+        let copy = [(track(0), AudioDecision::Copy)];
+
+        let result = check(
+            &copy,
+            &options(None),
+            "/a.mkv".into(),
+            "/a_av1.mp4".into(),
+            hash,
+        )?;
+        assert_eq!(AudioCheck::Match, result);
+        assert_eq!(Some(AudioHash::Match), result.result());
+        assert_eq!(vec!["/a.mkv", "/a_av1.mp4"], hashed.take());
+
+        let result = check(
+            &copy,
+            &options(None),
+            "/trimmed.mkv".into(),
+            "/trimmed_av1.mp4".into(),
+            hash,
+        )?;
+        assert_eq!(
+            AudioCheck::Mismatch {
+                source: "aaaa".into(),
+                output: "bbbb".into()
+            },
+            result
+        );
+        assert_eq!(Some(AudioHash::Mismatch), result.result());
+
+        // re-encoded audio can't match and isn't hashed at all
+        hashed.take();
+        let encoded = [(
+            track(0),
+            AudioDecision::Encode {
+                codec: "aac".into(),
+                bitrate: 128_000,
+            },
+        )];
+        let skipped = [
+            check(&encoded, &options(Some(1)), "/a".into(), "/b".into(), hash)?,
+            check(&[], &options(None), "/a".into(), "/b".into(), hash)?,
+            check(
+                &[
+                    (track(0), AudioDecision::Copy),
+                    (track(1), AudioDecision::Copy),
+                ],
+                &options(None),
+                "/a".into(),
+                "/b".into(),
+                hash,
+            )?,
+        ];
+        for result in skipped {
+            assert!(matches!(result, AudioCheck::Skipped(_)), "{result:?}");
+            assert_eq!(None, result.result());
+        }
+        assert!(hashed.take().is_empty());
+
+        // with explicit mapping the first track is the first track of the output
+        let mapped = check(
+            &[
+                (track(0), AudioDecision::Copy),
+                (track(1), AudioDecision::Copy),
+            ],
+            &options(Some(1_000_000)),
+            "/a".into(),
+            "/b".into(),
+            hash,
+        )?;
+        assert_eq!(AudioCheck::Match, mapped);
+
+        // errors of the hash are passed on
+        let failing = |_: &Utf8Path| -> Result<String> { Err(eyre!("no audio")) };
+        assert!(check(&copy, &options(None), "/a".into(), "/b".into(), failing).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,278 @@
+/// Sources below this many bits per pixel are already compressed a lot, so the
+/// first attempt starts one step higher.
+const LOW_BITS_PER_PIXEL: f64 = 0.05;
+
+pub const DEFAULT_STEP: u8 = 3;
+pub const DEFAULT_MAX_CRF: u8 = 40;
+
+/// Options for retrying files that didn't shrink enough at higher CRF values.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AutoCrf {
+    /// How much the CRF goes up with every attempt.
+    pub step: u8,
+    /// The highest CRF that is tried.
+    pub max_crf: u8,
+}
+
+/// One encode of a file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Attempt {
+    pub crf: u8,
+    pub output_size: u64,
+}
+
+/// What to do after an attempt.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decision {
+    /// The last attempt saved enough, keep its output.
+    Keep,
+    /// Encode again at this CRF.
+    Retry { crf: u8 },
+    /// Discard the output and leave the source as it is.
+    GiveUp,
+}
+
+/// Whether an output saves at least `min_savings` (a fraction) of the source size.
+pub fn saves_enough(source_size: u64, output_size: u64, min_savings: f64) -> bool {
+    output_size < source_size
+        && (source_size - output_size) as f64 >= source_size as f64 * min_savings
+}
+
+/// The CRF of the first attempt.
+pub fn starting_crf(crf: u8, bits_per_pixel: Option<f64>, auto_crf: Option<&AutoCrf>) -> u8 {
+    match (auto_crf, bits_per_pixel) {
+        (Some(auto_crf), Some(bits_per_pixel)) if bits_per_pixel < LOW_BITS_PER_PIXEL => crf
+            .saturating_add(auto_crf.step)
+            .min(auto_crf.max_crf)
+            .max(crf),
+        _ => crf,
+    }
+}
+
+/// The most attempts a file that starts at `starting_crf` can take.
+pub fn max_attempts(starting_crf: u8, auto_crf: Option<&AutoCrf>) -> u32 {
+    match auto_crf {
+        Some(auto_crf) if auto_crf.step > 0 && starting_crf < auto_crf.max_crf => {
+            1 + (auto_crf.max_crf - starting_crf).div_ceil(auto_crf.step) as u32
+        }
+        _ => 1,
+    }
+}
+
+/// Decides what to do after the last attempt in `history`. Without auto CRF every
+/// output that doesn't save enough is given up on. Retries stop at the maximum
+/// CRF, when a higher CRF didn't make the output smaller, or when the run is
+/// stopping.
+pub fn decide(
+    history: &[Attempt],
+    source_size: u64,
+    min_savings: f64,
+    auto_crf: Option<&AutoCrf>,
+    stopping: bool,
+) -> Decision {
+    let Some(last) = history.last() else {
+        return Decision::GiveUp;
+    };
+    if saves_enough(source_size, last.output_size, min_savings) {
+        return Decision::Keep;
+    }
+    let Some(auto_crf) = auto_crf else {
+        return Decision::GiveUp;
+    };
+    if stopping || auto_crf.step == 0 || last.crf >= auto_crf.max_crf {
+        return Decision::GiveUp;
+    }
+    if let [.., previous, _] = history
+        && last.output_size >= previous.output_size
+    {
+        return Decision::GiveUp;
+    }
+    let crf = last.crf.saturating_add(auto_crf.step).min(auto_crf.max_crf);
+    if history.iter().any(|attempt| attempt.crf == crf) {
+        return Decision::GiveUp;
+    }
+    Decision::Retry { crf }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AUTO: AutoCrf = AutoCrf {
+        step: 3,
+        max_crf: 35,
+    };
+
+    fn attempt(crf: u8, output_size: u64) -> Attempt {
+        Attempt { crf, output_size }
+    }
+
+    #[test]
+    fn test_saves_enough() {
+        assert!(saves_enough(100, 99, 0.0));
+        assert!(!saves_enough(100, 100, 0.0));
+        assert!(!saves_enough(100, 120, 0.0));
+        assert!(saves_enough(100, 80, 0.2));
+        assert!(!saves_enough(100, 81, 0.2));
+        assert!(!saves_enough(0, 0, 0.0));
+    }
+
+    #[test]
+    fn test_max_attempts() {
+        assert_eq!(1, max_attempts(24, None));
+        // 24, 27, 30, 33 and 35
+        assert_eq!(5, max_attempts(24, Some(&AUTO)));
+        assert_eq!(2, max_attempts(32, Some(&AUTO)));
+        assert_eq!(1, max_attempts(35, Some(&AUTO)));
+        assert_eq!(1, max_attempts(40, Some(&AUTO)));
+    }
+
+    #[test]
+    fn test_starting_crf() {
+        assert_eq!(24, starting_crf(24, Some(0.01), None));
+        assert_eq!(24, starting_crf(24, Some(0.2), Some(&AUTO)));
+        assert_eq!(24, starting_crf(24, None, Some(&AUTO)));
+        assert_eq!(27, starting_crf(24, Some(0.01), Some(&AUTO)));
+        assert_eq!(35, starting_crf(34, Some(0.01), Some(&AUTO)));
+        // a CRF above the cap is never lowered
+        assert_eq!(40, starting_crf(40, Some(0.01), Some(&AUTO)));
+    }
+
+    #[test]
+    fn test_keep() {
+        for auto_crf in [None, Some(&AUTO)] {
+            for stopping in [false, true] {
+                assert_eq!(
+                    Decision::Keep,
+                    decide(&[attempt(24, 50)], 100, 0.3, auto_crf, stopping)
+                );
+                assert_eq!(
+                    Decision::Keep,
+                    decide(
+                        &[attempt(24, 90), attempt(27, 60)],
+                        100,
+                        0.3,
+                        auto_crf,
+                        stopping
+                    )
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_without_auto_crf() {
+        assert_eq!(
+            Decision::GiveUp,
+            decide(&[attempt(24, 90)], 100, 0.3, None, false)
+        );
+        assert_eq!(
+            Decision::GiveUp,
+            decide(&[attempt(24, 150)], 100, 0.0, None, false)
+        );
+    }
+
+    #[test]
+    fn test_ladder() {
+        let mut history = vec![attempt(24, 95)];
+        let mut crfs = vec![];
+        while let Decision::Retry { crf } = decide(&history, 100, 0.5, Some(&AUTO), false) {
+            crfs.push(crf);
+            let last = history.last().unwrap().output_size;
+            history.push(attempt(crf, last - 5));
+        }
+        assert_eq!(vec![27, 30, 33, 35], crfs);
+        assert_eq!(
+            Decision::GiveUp,
+            decide(&history, 100, 0.5, Some(&AUTO), false)
+        );
+    }
+
+    #[test]
+    fn test_reaches_threshold() {
+        let history = [attempt(24, 90), attempt(27, 75)];
+        assert_eq!(
+            Decision::Retry { crf: 30 },
+            decide(&history, 100, 0.3, Some(&AUTO), false)
+        );
+        let history = [attempt(24, 90), attempt(27, 75), attempt(30, 68)];
+        assert_eq!(
+            Decision::Keep,
+            decide(&history, 100, 0.3, Some(&AUTO), false)
+        );
+    }
+
+    #[test]
+    fn test_give_up() {
+        // stopping the run
+        assert_eq!(
+            Decision::GiveUp,
+            decide(&[attempt(24, 90)], 100, 0.3, Some(&AUTO), true)
+        );
+        // at the cap, or above it from the start
+        assert_eq!(
+            Decision::GiveUp,
+            decide(&[attempt(35, 90)], 100, 0.3, Some(&AUTO), false)
+        );
+        assert_eq!(
+            Decision::GiveUp,
+            decide(&[attempt(40, 90)], 100, 0.3, Some(&AUTO), false)
+        );
+        // a higher CRF that didn't help
+        assert_eq!(
+            Decision::GiveUp,
+            decide(
+                &[attempt(24, 90), attempt(27, 90)],
+                100,
+                0.3,
+                Some(&AUTO),
+                false
+            )
+        );
+        // no step and no history
+        let no_step = AutoCrf { step: 0, ..AUTO };
+        assert_eq!(
+            Decision::GiveUp,
+            decide(&[attempt(24, 90)], 100, 0.3, Some(&no_step), false)
+        );
+        assert_eq!(Decision::GiveUp, decide(&[], 100, 0.3, Some(&AUTO), false));
+    }
+
+    #[test]
+    fn test_never_repeats_a_crf() {
+        let history = [attempt(33, 99), attempt(35, 98)];
+        let auto_crf = AutoCrf {
+            step: 3,
+            max_crf: 36,
+        };
+        assert_eq!(
+            Decision::Retry { crf: 36 },
+            decide(&history, 100, 0.5, Some(&auto_crf), false)
+        );
+        let history = [attempt(36, 99), attempt(33, 98)];
+        assert_eq!(
+            Decision::GiveUp,
+            decide(&history, 100, 0.5, Some(&auto_crf), false)
+        );
+    }
+
+    #[test]
+    fn test_retries_are_bounded() {
+        // every combination of caps and steps ends in a bounded number of attempts
+        for max_crf in 20..=63 {
+            for step in 1..=10 {
+                let auto_crf = AutoCrf { step, max_crf };
+                let mut history =
+                    vec![attempt(starting_crf(24, Some(0.01), Some(&auto_crf)), 1000)];
+                while let Decision::Retry { crf } =
+                    decide(&history, 100, 0.0, Some(&auto_crf), false)
+                {
+                    assert!(crf > history.last().unwrap().crf && crf <= max_crf);
+                    let last = history.last().unwrap().output_size;
+                    history.push(attempt(crf, last - 1));
+                    assert!(history.len() <= 64);
+                }
+            }
+        }
+    }
+}
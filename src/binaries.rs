@@ -0,0 +1,239 @@
+use std::env;
+use std::ffi::OsStr;
+use std::fmt;
+use std::io;
+use std::process::Command;
+use std::sync::OnceLock;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::Report;
+use serde::Deserialize;
+
+use crate::Result;
+
+/// The external programs the transcoder runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Binary {
+    Ffmpeg,
+    Ffprobe,
+}
+
+impl Binary {
+    pub fn name(self) -> &'static str {
+        match self {
+            Binary::Ffmpeg => "ffmpeg",
+            Binary::Ffprobe => "ffprobe",
+        }
+    }
+
+    /// Environment variable that overrides the path, ahead of the config file.
+    pub fn env_var(self) -> &'static str {
+        match self {
+            Binary::Ffmpeg => "TRANSCODER_FFMPEG",
+            Binary::Ffprobe => "TRANSCODER_FFPROBE",
+        }
+    }
+}
+
+impl fmt::Display for Binary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Paths to the binaries from the `[binaries]` section of the config file.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BinaryPaths {
+    pub ffmpeg: Option<Utf8PathBuf>,
+    pub ffprobe: Option<Utf8PathBuf>,
+}
+
+static CONFIGURED: OnceLock<BinaryPaths> = OnceLock::new();
+
+/// Sets the paths from the config file. Only the first call has an effect.
+pub fn configure(paths: BinaryPaths) {
+    let _ = CONFIGURED.set(paths);
+}
+
+/// The path the binary was set to, if any: the environment variable first, then
+/// the config file.
+fn override_path(binary: Binary) -> Option<Utf8PathBuf> {
+    if let Ok(path) = env::var(binary.env_var())
+        && !path.is_empty()
+    {
+        return Some(path.into());
+    }
+    let configured = CONFIGURED.get()?;
+    match binary {
+        Binary::Ffmpeg => configured.ffmpeg.clone(),
+        Binary::Ffprobe => configured.ffprobe.clone(),
+    }
+}
+
+/// A command that runs the binary. Errors from spawning it should go through
+/// [`spawn_error`].
+pub fn command(binary: Binary) -> Command {
+    match override_path(binary) {
+        Some(path) => Command::new(path),
+        None => Command::new(binary.name()),
+    }
+}
+
+fn install_hint() -> &'static str {
+    if cfg!(target_os = "macos") {
+        "install it with `brew install ffmpeg`"
+    } else if cfg!(windows) {
+        "install it with `winget install Gyan.FFmpeg` and restart the terminal"
+    } else {
+        "install the ffmpeg package, e.g. `sudo apt install ffmpeg` or `sudo dnf install ffmpeg`"
+    }
+}
+
+/// A binary that couldn't be found.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MissingBinary {
+    pub binary: Binary,
+    /// The configured path that doesn't exist.
+    pub override_path: Option<Utf8PathBuf>,
+    /// The `PATH` that was searched.
+    pub search_path: String,
+}
+
+impl fmt::Display for MissingBinary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let binary = self.binary;
+        match &self.override_path {
+            Some(path) => writeln!(
+                f,
+                "{binary} was not found at {path}, which is set by {} or the [binaries] section of the config file",
+                binary.env_var()
+            )?,
+            None => writeln!(
+                f,
+                "{binary} was not found in PATH: {}",
+                if self.search_path.is_empty() {
+                    "(empty)"
+                } else {
+                    &self.search_path
+                }
+            )?,
+        }
+        write!(
+            f,
+            "{binary} is part of ffmpeg, {}. Set {} to use a binary outside of PATH",
+            install_hint(),
+            binary.env_var()
+        )
+    }
+}
+
+impl std::error::Error for MissingBinary {}
+
+/// Finds the binary at `override_path`, or in the directories of `search_path`.
+pub fn locate(
+    binary: Binary,
+    override_path: Option<&Utf8Path>,
+    search_path: Option<&OsStr>,
+) -> Result<Utf8PathBuf, MissingBinary> {
+    let missing = || MissingBinary {
+        binary,
+        override_path: override_path.map(Utf8Path::to_owned),
+        search_path: search_path
+            .map(|p| p.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+    };
+    if let Some(path) = override_path {
+        return if path.is_file() {
+            Ok(path.to_owned())
+        } else {
+            Err(missing())
+        };
+    }
+    let file_name = format!("{}{}", binary.name(), env::consts::EXE_SUFFIX);
+    search_path
+        .into_iter()
+        .flat_map(env::split_paths)
+        .filter_map(|directory| Utf8PathBuf::from_path_buf(directory).ok())
+        .map(|directory| directory.join(&file_name))
+        .find(|path| path.is_file())
+        .ok_or_else(missing)
+}
+
+/// Checks that the binaries can be found before a command starts working.
+pub fn check(binaries: &[Binary]) -> Result<()> {
+    let search_path = env::var_os("PATH");
+    for &binary in binaries {
+        locate(
+            binary,
+            override_path(binary).as_deref(),
+            search_path.as_deref(),
+        )?;
+    }
+    Ok(())
+}
+
+/// Turns the error from spawning the binary into one that says which binary is
+/// missing and how to install it.
+pub fn spawn_error(binary: Binary, error: io::Error) -> Report {
+    if error.kind() != io::ErrorKind::NotFound {
+        return error.into();
+    }
+    let search_path = env::var_os("PATH");
+    match locate(
+        binary,
+        override_path(binary).as_deref(),
+        search_path.as_deref(),
+    ) {
+        Err(missing) => missing.into(),
+        Ok(_) => error.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn test_missing_override() {
+        let error = locate(
+            Binary::Ffprobe,
+            Some("/nonexistent/bin/ffprobe".into()),
+            Some(OsStr::new("/usr/bin")),
+        )
+        .unwrap_err();
+        let message = error.to_string();
+        assert!(message.contains("ffprobe was not found at /nonexistent/bin/ffprobe"));
+        assert!(message.contains("TRANSCODER_FFPROBE"));
+        assert!(message.contains("install"));
+    }
+
+    #[test]
+    fn test_search_path() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let directory = Utf8Path::from_path(tempdir.path()).unwrap();
+        let ffmpeg = directory.join(format!("ffmpeg{}", env::consts::EXE_SUFFIX));
+        fs::write(&ffmpeg, b"")?;
+        let search_path = env::join_paths(["/nonexistent", directory.as_str()])?;
+
+        assert_eq!(
+            ffmpeg,
+            locate(Binary::Ffmpeg, None, Some(&search_path)).unwrap()
+        );
+        let error = locate(Binary::Ffprobe, None, Some(&search_path)).unwrap_err();
+        assert!(error.to_string().contains(directory.as_str()));
+        assert!(
+            error
+                .to_string()
+                .starts_with("ffprobe was not found in PATH")
+        );
+        let error = locate(Binary::Ffprobe, None, None).unwrap_err();
+        assert!(error.to_string().contains("PATH: (empty)"));
+
+        let error = spawn_error(Binary::Ffmpeg, io::Error::other("broken pipe"));
+        assert!(error.downcast_ref::<MissingBinary>().is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,189 @@
+use crate::ffprobe::{FfProbe, Stream};
+
+/// Share of the file above which the audio is flagged by `show`, in percent.
+pub const DEFAULT_MAX_AUDIO_SHARE: f64 = 25.0;
+
+/// How many bytes of a file one stream takes up.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamSize {
+    pub index: i64,
+    /// `video`, `audio`, `subtitle` and so on.
+    pub kind: String,
+    pub codec: String,
+    /// `None` when neither the stream nor the container says.
+    pub bytes: Option<u64>,
+    /// Whether the size was estimated from the container bitrate instead of
+    /// the stream's own bitrate.
+    pub estimated: bool,
+}
+
+/// How the size of a file splits between its streams.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SizeBreakdown {
+    pub streams: Vec<StreamSize>,
+    /// Container overhead and whatever no stream accounts for.
+    pub overhead: u64,
+    pub total: u64,
+}
+
+impl SizeBreakdown {
+    /// Share of the total in percent.
+    pub fn share(&self, bytes: u64) -> f64 {
+        if self.total == 0 {
+            0.0
+        } else {
+            bytes as f64 / self.total as f64 * 100.0
+        }
+    }
+
+    /// Bytes of all audio streams together.
+    pub fn audio_bytes(&self) -> u64 {
+        self.streams
+            .iter()
+            .filter(|stream| stream.kind == "audio")
+            .filter_map(|stream| stream.bytes)
+            .sum()
+    }
+
+    /// The share of the audio when it is above `max_share` percent.
+    pub fn excessive_audio(&self, max_share: f64) -> Option<f64> {
+        Some(self.share(self.audio_bytes())).filter(|&share| share > max_share)
+    }
+}
+
+fn stream_bytes(stream: &Stream, duration: f64) -> Option<u64> {
+    let duration = stream
+        .duration
+        .as_deref()
+        .and_then(|duration| duration.parse().ok())
+        .unwrap_or(duration);
+    stream
+        .bitrate()
+        .map(|bitrate| (bitrate as f64 / 8.0 * duration) as u64)
+}
+
+/// Splits `file_size` between the streams of a file by their bitrates. Video
+/// streams without a bitrate share what the other streams leave of the total,
+/// other streams without one count as unknown. Returns `None` when the duration
+/// is unknown.
+pub fn breakdown(info: &FfProbe, file_size: u64) -> Option<SizeBreakdown> {
+    let duration = info.duration().filter(|&duration| duration > 0.0)?;
+    let total = match file_size {
+        0 => (info.bitrate() as f64 / 8.0 * duration) as u64,
+        size => size,
+    };
+    let mut streams: Vec<StreamSize> = info
+        .streams
+        .iter()
+        .map(|stream| StreamSize {
+            index: stream.index,
+            kind: stream.codec_type.clone().unwrap_or_default(),
+            codec: stream.codec_name.clone().unwrap_or_default(),
+            bytes: stream_bytes(stream, duration),
+            estimated: false,
+        })
+        .collect();
+    let known: u64 = streams.iter().filter_map(|stream| stream.bytes).sum();
+    let mut remaining = total.saturating_sub(known);
+
+    let unknown_video: Vec<_> = streams
+        .iter_mut()
+        .filter(|stream| stream.kind == "video" && stream.bytes.is_none())
+        .collect();
+    if let Some(each) = remaining.checked_div(unknown_video.len() as u64) {
+        for stream in unknown_video {
+            stream.bytes = Some(each);
+            stream.estimated = true;
+        }
+        remaining = 0;
+    }
+    Some(SizeBreakdown {
+        streams,
+        overhead: remaining,
+        total,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffprobe::fixtures;
+
+    fn sizes(breakdown: &SizeBreakdown) -> Vec<(&str, Option<u64>, bool)> {
+        breakdown
+            .streams
+            .iter()
+            .map(|stream| (stream.kind.as_str(), stream.bytes, stream.estimated))
+            .collect()
+    }
+
+    #[test]
+    fn test_stream_bitrates() {
+        // h264_aac.mp4, both streams have a bitrate
+        let info = &fixtures()[0];
+        let breakdown = breakdown(info, info.size()).unwrap();
+        assert_eq!(
+            vec![
+                ("video", Some(780_107_605), false),
+                ("audio", Some(31_215_847), false)
+            ],
+            sizes(&breakdown)
+        );
+        assert_eq!(813_590_422, breakdown.total);
+        assert_eq!(2_266_970, breakdown.overhead);
+        assert_eq!(None, breakdown.excessive_audio(DEFAULT_MAX_AUDIO_SHARE));
+        assert!(breakdown.excessive_audio(3.0).is_some());
+    }
+
+    #[test]
+    fn test_statistics_tags() {
+        // hevc_ac3_subtitles.mkv, the video only has mkvmerge's statistics
+        let info = &fixtures()[1];
+        let breakdown = breakdown(info, info.size()).unwrap();
+        let sizes = sizes(&breakdown);
+        assert_eq!(("video", Some(15_082_104_142), false), sizes[0]);
+        assert_eq!(("audio", Some(646_162_240), false), sizes[1]);
+        assert_eq!(("subtitle", Some(3_028), false), sizes[2]);
+        assert_eq!(27_741_820, breakdown.overhead);
+        assert!((breakdown.share(breakdown.audio_bytes()) - 4.1).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_missing_bitrates() {
+        let mut info = fixtures()[1].clone();
+        for stream in &mut info.streams {
+            stream.bit_rate = None;
+            stream.tags = None;
+        }
+        info.streams[1].bit_rate = Some("640000".into());
+        let breakdown = breakdown(&info, 10_000_000_000).unwrap();
+        // the video gets everything the audio leaves
+        assert_eq!(
+            vec![
+                ("video", Some(9_353_837_760), true),
+                ("audio", Some(646_162_240), false),
+                ("subtitle", None, false)
+            ],
+            sizes(&breakdown)
+        );
+        assert_eq!(0, breakdown.overhead);
+
+        // without a file size the container bitrate gives the total
+        let breakdown = super::breakdown(&info, 0).unwrap();
+        assert_eq!(15_756_113_955, breakdown.total);
+
+        info.format.duration = None;
+        assert_eq!(None, super::breakdown(&info, 1000));
+    }
+
+    #[test]
+    fn test_audio_share() {
+        let mut info = fixtures()[0].clone();
+        // a 640 kb/s track next to a small video
+        info.streams[0].bit_rate = Some("1000000".into());
+        info.streams[1].bit_rate = Some("640000".into());
+        let breakdown = breakdown(&info, 270_000_000).unwrap();
+        let share = breakdown.excessive_audio(DEFAULT_MAX_AUDIO_SHARE).unwrap();
+        assert!((share - 38.5).abs() < 0.1, "{share}");
+    }
+}
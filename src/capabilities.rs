@@ -0,0 +1,130 @@
+use std::fmt;
+use std::process::Command;
+
+use tracing::{debug, info};
+
+/// Concurrent NVENC session limit of consumer GPUs for a driver version.
+/// Professional GPUs are not limited and return `None`.
+fn session_limit(driver_major: u32, gpu_name: &str) -> Option<u32> {
+    const PROFESSIONAL: &[&str] = &[
+        "quadro",
+        "tesla",
+        "rtx a",
+        "nvidia a",
+        "nvidia l",
+        "nvidia t4",
+    ];
+    let name = gpu_name.to_lowercase();
+    if PROFESSIONAL.iter().any(|p| name.contains(p)) {
+        return None;
+    }
+
+    Some(match driver_major {
+        0..=440 => 2,
+        441..=529 => 3,
+        530..=550 => 5,
+        _ => 8,
+    })
+}
+
+/// Parses a line of `nvidia-smi --query-gpu=driver_version,name --format=csv,noheader`.
+fn parse_nvidia_smi(line: &str) -> Option<(u32, &str)> {
+    let (version, name) = line.split_once(',')?;
+    let major = version.trim().split('.').next()?.parse().ok()?;
+    Some((major, name.trim()))
+}
+
+/// Detects how many NVENC sessions can run at the same time, using `nvidia-smi`.
+/// Returns `None` when the limit is unknown or there is no limit.
+pub fn nvenc_session_limit() -> Option<u32> {
+    let output = Command::new("nvidia-smi")
+        .args(["--query-gpu=driver_version,name", "--format=csv,noheader"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        debug!("nvidia-smi failed: {:?}", output.status);
+        return None;
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let (driver, name) = parse_nvidia_smi(stdout.lines().next()?)?;
+    let limit = session_limit(driver, name);
+    info!("detected {name} with driver {driver}, NVENC session limit: {limit:?}");
+    limit
+}
+
+/// An NVENC encode that failed because all of the GPU's sessions were in use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionLimitReached;
+
+impl fmt::Display for SessionLimitReached {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "all NVENC sessions of the GPU are in use")
+    }
+}
+
+impl std::error::Error for SessionLimitReached {}
+
+/// Whether ffmpeg's stderr says that NVENC couldn't open another session. The
+/// driver reports the session limit as running out of memory, older drivers as
+/// an incompatible client key.
+pub fn is_session_limit_error(stderr: &str) -> bool {
+    stderr.lines().any(|line| {
+        line.contains("_nvenc @")
+            && line.contains("OpenEncodeSessionEx failed")
+            && (line.contains("out of memory (10)")
+                || line.contains("incompatible client key (21)"))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_nvidia_smi() {
+        assert_eq!(
+            Some((535, "NVIDIA GeForce RTX 4070")),
+            parse_nvidia_smi("535.183.01, NVIDIA GeForce RTX 4070")
+        );
+        assert_eq!(None, parse_nvidia_smi("garbage"));
+    }
+
+    #[test]
+    fn test_is_session_limit_error() {
+        let limit_reached = [
+            "[av1_nvenc @ 0x5611c0a3e2c0] OpenEncodeSessionEx failed: out of memory (10): (no details)\n\
+             [vost#0:0/av1_nvenc @ 0x5611c0a3d8c0] Error while opening encoder - maybe incorrect parameters such as bit_rate, rate, width or height.\n\
+             Error while filtering: Generic error in an external library",
+            "[hevc_nvenc @ 0x55d0d8bd6a40] OpenEncodeSessionEx failed: incompatible client key (21): (no details)\n\
+             Error initializing output stream 0:0 -- Error while opening encoder for output stream #0:0",
+        ];
+        for stderr in limit_reached {
+            assert!(is_session_limit_error(stderr), "{stderr}");
+        }
+        let other_errors = [
+            // no NVIDIA GPU or driver
+            "[av1_nvenc @ 0x55b4f2e3c500] Cannot load libnvidia-encode.so.1\n\
+             [av1_nvenc @ 0x55b4f2e3c500] The minimum required Nvidia driver for nvenc is 520.56.06 or newer",
+            "[av1_nvenc @ 0x5582] OpenEncodeSessionEx failed: unsupported device (2): (no details)",
+            "[av1_nvenc @ 0x5601] No capable devices found",
+            // running out of GPU memory later isn't about sessions
+            "[av1_nvenc @ 0x5601] EncodePicture failed!: out of memory (10)",
+            "[libsvtav1 @ 0x5601] out of memory (10)",
+            "",
+        ];
+        for stderr in other_errors {
+            assert!(!is_session_limit_error(stderr), "{stderr}");
+        }
+    }
+
+    #[test]
+    fn test_session_limit() {
+        assert_eq!(Some(2), session_limit(418, "GeForce GTX 1080"));
+        assert_eq!(Some(3), session_limit(470, "NVIDIA GeForce RTX 3060"));
+        assert_eq!(Some(5), session_limit(535, "NVIDIA GeForce RTX 4070"));
+        assert_eq!(Some(8), session_limit(560, "NVIDIA GeForce RTX 4090"));
+        assert_eq!(None, session_limit(535, "Quadro RTX 4000"));
+        assert_eq!(None, session_limit(535, "NVIDIA RTX A2000"));
+    }
+}
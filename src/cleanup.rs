@@ -0,0 +1,167 @@
+use std::collections::{HashMap, HashSet};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use tracing::warn;
+use walkdir::WalkDir;
+
+use crate::paths::TempFile;
+use crate::sidecar;
+
+/// Files that are known to the database, which decide whether a file named like
+/// an old-style temp file is a leftover.
+pub struct KnownFiles {
+    /// File stems of the sources by directory.
+    stems: HashMap<Utf8PathBuf, HashSet<String>>,
+    /// Ids of files that a worker is transcoding right now, possibly on another machine.
+    claimed: HashSet<i64>,
+    /// Ids of files that are still to be transcoded, whose resumable encodes are kept.
+    unfinished: HashSet<i64>,
+}
+
+impl KnownFiles {
+    pub fn new<'a>(
+        sources: impl IntoIterator<Item = &'a Utf8Path>,
+        claimed: impl IntoIterator<Item = i64>,
+        unfinished: impl IntoIterator<Item = i64>,
+    ) -> Self {
+        let mut stems: HashMap<Utf8PathBuf, HashSet<String>> = HashMap::new();
+        for source in sources {
+            if let (Some(directory), Some(stem)) = (source.parent(), source.file_stem()) {
+                stems
+                    .entry(directory.to_owned())
+                    .or_default()
+                    .insert(stem.into());
+            }
+        }
+        KnownFiles {
+            stems,
+            claimed: claimed.into_iter().collect(),
+            unfinished: unfinished.into_iter().collect(),
+        }
+    }
+
+    /// An old-style temp file is a leftover if there is a source with that stem next
+    /// to it, or, in directories without sources like the temp directory, anywhere.
+    fn has_legacy_source(&self, directory: &Utf8Path, stem: &str) -> bool {
+        match self.stems.get(directory) {
+            Some(stems) => stems.contains(stem),
+            None => self.stems.values().any(|stems| stems.contains(stem)),
+        }
+    }
+}
+
+/// Finds temp files below `root` that were left behind by runs that crashed or were
+/// killed, and sidecars whose output is gone. Files of running workers are kept.
+/// The directories of resumable encodes are leftovers once their file is finished
+/// or no longer in the database, and are returned as a whole.
+pub fn find_leftovers(
+    root: &Utf8Path,
+    known: &KnownFiles,
+    pid_is_alive: impl Fn(u32) -> bool,
+) -> Vec<Utf8PathBuf> {
+    let mut leftovers = vec![];
+    let mut walker = WalkDir::new(root).into_iter();
+    while let Some(entry) = walker.next() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("error while walking directory: {}", e);
+                continue;
+            }
+        };
+        let Some(path) = Utf8Path::from_path(entry.path()) else {
+            continue;
+        };
+        if entry.file_type().is_dir() {
+            if let Some(TempFile::Resume { id }) = TempFile::parse(path) {
+                walker.skip_current_dir();
+                if !known.claimed.contains(&id) && !known.unfinished.contains(&id) {
+                    leftovers.push(path.to_owned());
+                }
+            }
+            continue;
+        }
+        if !entry.file_type().is_file() {
+            continue;
+        }
+        let directory = path.parent().unwrap_or(Utf8Path::new("."));
+        let is_leftover = match TempFile::parse(path) {
+            Some(TempFile::Current { id, pid }) => {
+                !known.claimed.contains(&id) && !pid_is_alive(pid)
+            }
+            Some(TempFile::Legacy { stem }) => known.has_legacy_source(directory, &stem),
+            Some(TempFile::Resume { .. }) => false,
+            // a sidecar report whose output is gone
+            None => sidecar::output_of(path).is_some_and(|output| !output.exists()),
+        };
+        if is_leftover {
+            leftovers.push(path.to_owned());
+        }
+    }
+    leftovers.sort();
+    leftovers
+}
+
+/// The size of a file, or of all files in a directory.
+pub fn disk_usage(path: &Utf8Path) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(|entry| entry.ok()?.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::Result;
+
+    #[test]
+    fn test_find_leftovers() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let root = Utf8Path::from_path(tempdir.path()).unwrap();
+        let movies = root.join("movies");
+        let tmp = root.join("tmp");
+        fs::create_dir_all(&movies)?;
+        fs::create_dir_all(&tmp)?;
+        for name in [
+            "movies/a.mkv",
+            "movies/a_tmp.mp4",
+            "movies/holiday_tmp.mp4",
+            "movies/.transcoder-1-100.tmp.mp4",
+            "movies/.transcoder-2-200.tmp.mp4",
+            "movies/.transcoder-3-100.tmp.mp4",
+            "tmp/b_tmp.mp4",
+            "tmp/.transcoder-4-100.tmp.mp4",
+            "tmp/.transcoder-resume-3/part-0-00000.mkv",
+            "tmp/.transcoder-resume-5/part-0-00000.mkv",
+            "tmp/.transcoder-resume-6/state.json",
+            "movies/a_av1.mp4",
+            "movies/a_av1.mp4.transcoder.json",
+            "movies/c_av1.mp4.transcoder.json",
+        ] {
+            let path = root.join(name);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::write(path, b"")?;
+        }
+        let sources = [movies.join("a.mkv"), movies.join("b.avi")];
+        let known = KnownFiles::new(sources.iter().map(|p| p.as_path()), [3], [5]);
+
+        let leftovers = find_leftovers(root, &known, |pid| pid == 200);
+        assert_eq!(
+            vec![
+                movies.join(".transcoder-1-100.tmp.mp4"),
+                movies.join("a_tmp.mp4"),
+                movies.join("c_av1.mp4.transcoder.json"),
+                tmp.join(".transcoder-4-100.tmp.mp4"),
+                tmp.join(".transcoder-resume-6"),
+                tmp.join("b_tmp.mp4"),
+            ],
+            leftovers
+        );
+        Ok(())
+    }
+}
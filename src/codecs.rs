@@ -0,0 +1,389 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::collect::VideoFile;
+use crate::ffprobe::{self, FfProbe};
+
+/// Codecs that are already efficient enough and are not transcoded by default.
+pub const DEFAULT_EXCLUDED_CODECS: &[&str] = &["hevc", "av1"];
+
+/// The properties of a video that exclusion rules look at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CodecInfo<'a> {
+    pub codec: &'a str,
+    pub profile: Option<&'a str>,
+    /// Overall bitrate in bits per second, 0 if unknown.
+    pub bitrate: u64,
+    pub bits_per_pixel: Option<f64>,
+}
+
+impl<'a> From<&'a VideoFile> for CodecInfo<'a> {
+    fn from(file: &'a VideoFile) -> Self {
+        CodecInfo {
+            codec: &file.codec,
+            profile: file.profile.as_deref(),
+            bitrate: file.bitrate,
+            bits_per_pixel: file.bits_per_pixel(),
+        }
+    }
+}
+
+impl<'a> From<&'a FfProbe> for CodecInfo<'a> {
+    fn from(info: &'a FfProbe) -> Self {
+        CodecInfo {
+            codec: info.video_codec(),
+            profile: info.video_profile(),
+            bitrate: info.bitrate(),
+            bits_per_pixel: ffprobe::bits_per_pixel(
+                info.video_bitrate().unwrap_or_default(),
+                info.resolution(),
+                info.frame_rate(),
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operator {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Equal,
+    NotEqual,
+}
+
+impl Operator {
+    /// Two character operators first, so that `<=` isn't read as `<`.
+    const ALL: [(&'static str, Operator); 6] = [
+        ("<=", Operator::LessOrEqual),
+        (">=", Operator::GreaterOrEqual),
+        ("!=", Operator::NotEqual),
+        ("<", Operator::Less),
+        (">", Operator::Greater),
+        ("=", Operator::Equal),
+    ];
+
+    /// Splits the operator off the start of a comparison like `>=50`.
+    pub fn split(s: &str) -> Option<(Operator, &str)> {
+        Operator::ALL
+            .iter()
+            .find_map(|(symbol, operator)| Some((*operator, s.strip_prefix(symbol)?)))
+    }
+
+    pub fn compare(self, left: f64, right: f64) -> bool {
+        match self {
+            Operator::Less => left < right,
+            Operator::LessOrEqual => left <= right,
+            Operator::Greater => left > right,
+            Operator::GreaterOrEqual => left >= right,
+            Operator::Equal => left == right,
+            Operator::NotEqual => left != right,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Condition {
+    BitsPerPixel(Operator, f64),
+    Bitrate(Operator, f64),
+    /// Profiles are compared case-insensitively, only with `=` and `!=`.
+    Profile {
+        equal: bool,
+        profile: String,
+    },
+}
+
+/// Parses bitrates like `8000000`, `8000k` or `8M`, in bits per second.
+fn parse_bitrate(value: &str) -> Result<f64, String> {
+    let value = value.trim();
+    let (number, multiplier) = match value.char_indices().last() {
+        Some((index, 'k' | 'K')) => (&value[..index], 1e3),
+        Some((index, 'm' | 'M')) => (&value[..index], 1e6),
+        Some((index, 'g' | 'G')) => (&value[..index], 1e9),
+        _ => (value, 1.0),
+    };
+    number
+        .parse::<f64>()
+        .map(|n| n * multiplier)
+        .map_err(|_| format!("invalid bitrate '{value}', expected e.g. 8M or 800k"))
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (field, operator, value) = Operator::ALL
+            .iter()
+            .find_map(|(symbol, operator)| {
+                s.split_once(symbol)
+                    .map(|(field, value)| (field.trim(), *operator, value.trim()))
+            })
+            .ok_or_else(|| format!("expected a comparison like bpp<0.08 in '{s}'"))?;
+        match field.to_lowercase().as_str() {
+            "bpp" => {
+                let value = value
+                    .parse()
+                    .map_err(|_| format!("invalid bits per pixel '{value}'"))?;
+                Ok(Condition::BitsPerPixel(operator, value))
+            }
+            "bitrate" => Ok(Condition::Bitrate(operator, parse_bitrate(value)?)),
+            "profile" => match operator {
+                Operator::Equal | Operator::NotEqual => Ok(Condition::Profile {
+                    equal: operator == Operator::Equal,
+                    profile: value.to_lowercase(),
+                }),
+                _ => Err(format!(
+                    "profiles can only be compared with = or != in '{s}'"
+                )),
+            },
+            _ => Err(format!(
+                "unknown field '{field}' in '{s}', expected bpp, bitrate or profile"
+            )),
+        }
+    }
+}
+
+impl Condition {
+    /// Files without the property never match a numeric comparison.
+    fn matches(&self, info: &CodecInfo) -> bool {
+        match self {
+            Condition::BitsPerPixel(operator, value) => info
+                .bits_per_pixel
+                .is_some_and(|bpp| operator.compare(bpp, *value)),
+            Condition::Bitrate(operator, value) => {
+                info.bitrate > 0 && operator.compare(info.bitrate as f64, *value)
+            }
+            Condition::Profile { equal, profile } => {
+                let same = info.profile.is_some_and(|p| p.to_lowercase() == *profile);
+                same == *equal
+            }
+        }
+    }
+}
+
+/// Excludes files of a codec, optionally only those that match all conditions,
+/// e.g. `hevc:bpp<0.08` or `h264:profile=High 10`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodecRule {
+    text: String,
+    codec: String,
+    conditions: Vec<Condition>,
+}
+
+impl FromStr for CodecRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (codec, conditions) = match s.split_once(':') {
+            Some((codec, conditions)) => (codec, Some(conditions)),
+            None => (s, None),
+        };
+        let codec = codec.trim().to_lowercase();
+        if codec.is_empty() {
+            return Err(format!("missing codec name in '{s}'"));
+        }
+        let conditions = conditions
+            .into_iter()
+            .flat_map(|c| c.split(','))
+            .map(str::parse)
+            .collect::<Result<_, _>>()?;
+        Ok(CodecRule {
+            text: s.trim().into(),
+            codec,
+            conditions,
+        })
+    }
+}
+
+impl fmt::Display for CodecRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl CodecRule {
+    /// Whether the rule looks at the bits per pixel, which makes it a judgement on
+    /// the file's compression rather than its codec.
+    pub fn uses_bits_per_pixel(&self) -> bool {
+        self.conditions
+            .iter()
+            .any(|c| matches!(c, Condition::BitsPerPixel(..)))
+    }
+
+    pub fn matches(&self, info: &CodecInfo) -> bool {
+        info.codec.eq_ignore_ascii_case(&self.codec)
+            && self.conditions.iter().all(|c| c.matches(info))
+    }
+}
+
+/// The rules that decide which files are not transcoded because their codec is
+/// already efficient.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CodecRules(Vec<CodecRule>);
+
+impl Default for CodecRules {
+    fn default() -> Self {
+        CodecRules(
+            DEFAULT_EXCLUDED_CODECS
+                .iter()
+                .map(|codec| codec.parse().expect("default rules are valid"))
+                .collect(),
+        )
+    }
+}
+
+impl CodecRules {
+    /// No rules, so that files of any codec are transcoded.
+    pub fn none() -> Self {
+        CodecRules(vec![])
+    }
+
+    /// The given rules, or the default ones if there are none.
+    pub fn new(rules: Vec<CodecRule>) -> Self {
+        if rules.is_empty() {
+            CodecRules::default()
+        } else {
+            CodecRules(rules)
+        }
+    }
+
+    /// The first rule that excludes the file.
+    pub fn excluding(&self, info: &CodecInfo) -> Option<&CodecRule> {
+        self.0.iter().find(|rule| rule.matches(info))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info<'a>(codec: &'a str, profile: Option<&'a str>, bitrate: u64) -> CodecInfo<'a> {
+        // 1080p at 25 fps
+        CodecInfo {
+            codec,
+            profile,
+            bitrate,
+            bits_per_pixel: ffprobe::bits_per_pixel(bitrate, (1920, 1080), 25.0),
+        }
+    }
+
+    fn rule(text: &str) -> CodecRule {
+        text.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            CodecRule {
+                text: "hevc".into(),
+                codec: "hevc".into(),
+                conditions: vec![],
+            },
+            rule("hevc")
+        );
+        assert_eq!(
+            vec![
+                Condition::BitsPerPixel(Operator::Less, 0.08),
+                Condition::Bitrate(Operator::GreaterOrEqual, 8e6),
+                Condition::Profile {
+                    equal: false,
+                    profile: "main 10".into()
+                },
+            ],
+            rule("HEVC:bpp<0.08,bitrate>=8M,profile!=Main 10").conditions
+        );
+        assert_eq!(
+            vec![Condition::Bitrate(Operator::LessOrEqual, 800e3)],
+            rule("h264:bitrate<=800k").conditions
+        );
+        assert_eq!("hevc:bpp<0.08", rule("hevc:bpp<0.08").to_string());
+        assert!(rule("hevc:profile=Main,bpp<0.08").uses_bits_per_pixel());
+        assert!(!rule("hevc:bitrate<8M").uses_bits_per_pixel());
+
+        for invalid in [
+            "",
+            ":bpp<1",
+            "hevc:",
+            "hevc:bpp",
+            "hevc:bpp<fast",
+            "hevc:fps<30",
+            "hevc:profile<Main",
+            "hevc:bitrate>8X",
+        ] {
+            assert!(invalid.parse::<CodecRule>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_bits_per_pixel() {
+        // 5 Mbit/s is about 0.1 bits per pixel for 1080p25
+        let rule = rule("hevc:bpp<0.08");
+        assert!(!rule.matches(&info("hevc", None, 5_000_000)));
+        assert!(rule.matches(&info("hevc", None, 3_000_000)));
+        assert!(!rule.matches(&info("h264", None, 3_000_000)));
+        // unknown bitrate
+        assert!(!rule.matches(&info("hevc", None, 0)));
+    }
+
+    #[test]
+    fn test_bits_per_pixel_of_the_video() {
+        // 6 Mbit/s in total, but 4 of them are DTS audio
+        let mut probe = ffprobe::fixtures()[1].clone();
+        probe.streams[0].tags = None;
+        probe.streams[1].bit_rate = Some("4000000".into());
+        probe.format.bit_rate = Some("6000000".into());
+        let info = CodecInfo::from(&probe);
+        assert_eq!(6_000_000, info.bitrate);
+        let bpp = info.bits_per_pixel.unwrap();
+        assert!(
+            (bpp - 2e6 / (3840.0 * 1600.0 * 24000.0 / 1001.0)).abs() < 1e-6,
+            "{bpp}"
+        );
+        // the whole file would be about 0.04
+        assert!(rule("hevc:bpp<0.02").matches(&info));
+    }
+
+    #[test]
+    fn test_profile_and_bitrate() {
+        let high10 = rule("h264:profile=High 10");
+        assert!(high10.matches(&info("h264", Some("High 10"), 1)));
+        assert!(high10.matches(&info("h264", Some("high 10"), 1)));
+        assert!(!high10.matches(&info("h264", Some("High"), 1)));
+        assert!(!high10.matches(&info("h264", None, 1)));
+
+        let not_main = rule("hevc:profile!=Main");
+        assert!(not_main.matches(&info("hevc", Some("Main 10"), 1)));
+        assert!(not_main.matches(&info("hevc", None, 1)));
+        assert!(!not_main.matches(&info("hevc", Some("Main"), 1)));
+
+        let both = rule("hevc:profile=Main,bitrate<10M");
+        assert!(both.matches(&info("hevc", Some("Main"), 9_000_000)));
+        assert!(!both.matches(&info("hevc", Some("Main"), 20_000_000)));
+    }
+
+    #[test]
+    fn test_rules() {
+        let defaults = CodecRules::default();
+        assert_eq!(defaults, CodecRules::new(vec![]));
+        assert!(
+            defaults
+                .excluding(&info("hevc", None, 30_000_000))
+                .is_some()
+        );
+        assert!(defaults.excluding(&info("av1", None, 0)).is_some());
+        assert!(defaults.excluding(&info("h264", None, 0)).is_none());
+
+        // old NVENC HEVC at a high bitrate is transcoded, efficient HEVC isn't
+        let rules = CodecRules::new(vec![rule("hevc:bpp<0.08"), rule("av1")]);
+        assert_eq!(
+            Some("hevc:bpp<0.08"),
+            rules
+                .excluding(&info("hevc", None, 3_000_000))
+                .map(|r| r.to_string())
+                .as_deref()
+        );
+        assert!(rules.excluding(&info("hevc", None, 30_000_000)).is_none());
+        assert!(rules.excluding(&info("av1", None, 30_000_000)).is_some());
+    }
+}
@@ -1,16 +1,25 @@
 use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 use std::time::Duration;
 
 use camino::{Utf8Path, Utf8PathBuf};
-use clap::ValueEnum;
 use indicatif::{ProgressBar, ProgressStyle};
+use jiff::Timestamp;
 use rayon::prelude::{IntoParallelIterator, ParallelIterator};
 use tracing::{debug, info, warn};
+use unicode_normalization::UnicodeNormalization;
 use walkdir::{DirEntry, WalkDir};
 
-use crate::database::{Database, NewTranscodeFile, TranscodeFile};
-use crate::ffprobe::ffprobe;
 use crate::Result;
+use crate::audio::AudioTrack;
+use crate::codecs::{CodecInfo, CodecRules};
+use crate::database::{
+    Database, FileFilter, InsertSummary, NewSkippedFile, NewTranscodeFile, ScanSkipReason,
+    TranscodeFile, TranscodeStatus,
+};
+use crate::ffprobe::{self, Chapter, FfProbe, ffprobe};
+use crate::paths;
 
 fn file_name_short(path: &Utf8Path, len: usize) -> Cow<'_, str> {
     let name = path.file_name().unwrap_or_default();
@@ -24,109 +33,536 @@ fn file_name_short(path: &Utf8Path, len: usize) -> Cow<'_, str> {
 
 #[derive(Debug, Clone)]
 pub struct VideoFile {
-    pub rowid: i64,
+    pub id: i64,
     pub path: Utf8PathBuf,
     /// Duration in seconds.
     pub duration: f64,
     pub resolution: (u32, u32),
+    /// Overall bitrate of the file, 0 if unknown.
     pub bitrate: u64,
+    /// Bitrate of the video stream alone, 0 if unknown.
+    pub video_bitrate: u64,
+    /// Summed bitrate of the audio streams that have one.
+    pub audio_bitrate: u64,
     pub frame_rate: f64,
     pub codec: String,
+    /// The codec profile of the video stream.
+    pub profile: Option<String>,
+    pub container: String,
     pub file_size: u64,
+    pub status: TranscodeStatus,
+    pub audio_tracks: Vec<AudioTrack>,
+    pub pix_fmt: Option<String>,
+    /// The start time, when it is far enough from zero to be shifted to zero.
+    pub start_offset: Option<f64>,
+    /// Pinned files are never transcoded.
+    pub pinned: bool,
+    /// Where the last transcode of the file was written to.
+    pub output_path: Option<Utf8PathBuf>,
+    /// The `title` tag of the container.
+    pub title: Option<String>,
+    pub chapters: Vec<Chapter>,
 }
 
 impl From<TranscodeFile> for VideoFile {
     fn from(value: TranscodeFile) -> Self {
         let info = value.ffprobe().expect("ffprobe info must be present");
         VideoFile {
-            rowid: value.rowid,
-            path: value.path,
+            pinned: value.pinned,
+            output_path: value.output_path,
+            ..VideoFile::from_probe(
+                value.id,
+                value.path,
+                value.file_size as u64,
+                value.status,
+                &info,
+            )
+        }
+    }
+}
+
+/// Standard resolution tiers that files are grouped by in the stats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ResolutionTier {
+    Sd,
+    Hd720,
+    Hd1080,
+    Qhd1440,
+    Uhd4k,
+    Uhd8k,
+    Other,
+}
+
+impl fmt::Display for ResolutionTier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ResolutionTier::Sd => "SD",
+            ResolutionTier::Hd720 => "720p",
+            ResolutionTier::Hd1080 => "1080p",
+            ResolutionTier::Qhd1440 => "1440p",
+            ResolutionTier::Uhd4k => "4K",
+            ResolutionTier::Uhd8k => "8K",
+            ResolutionTier::Other => "Other",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The number of lines the picture would have at 16:9, e.g. 1080 for 1920x800.
+pub fn lines((width, height): (u32, u32)) -> u32 {
+    let (long, short) = (width.max(height), width.min(height));
+    short.max(long * 9 / 16)
+}
+
+/// Buckets a resolution into a tier. The tier is decided by the number of lines
+/// the picture would have at 16:9, which is the shorter side or 9/16 of the longer
+/// side, whichever is bigger. That puts cropped widescreen (1920x800), portrait
+/// (1080x1920) and anamorphic (1440x1080) videos into the tier they were mastered
+/// at, where looking only at the width or the height doesn't.
+pub fn resolution_tier(resolution: (u32, u32)) -> ResolutionTier {
+    if resolution.0 == 0 || resolution.1 == 0 {
+        return ResolutionTier::Other;
+    }
+    match lines(resolution) {
+        0..=576 => ResolutionTier::Sd,
+        577..=800 => ResolutionTier::Hd720,
+        801..=1200 => ResolutionTier::Hd1080,
+        1201..=1600 => ResolutionTier::Qhd1440,
+        1601..=2400 => ResolutionTier::Uhd4k,
+        2401..=4800 => ResolutionTier::Uhd8k,
+        _ => ResolutionTier::Other,
+    }
+}
+
+impl VideoFile {
+    fn from_probe(
+        id: i64,
+        path: Utf8PathBuf,
+        file_size: u64,
+        status: TranscodeStatus,
+        info: &FfProbe,
+    ) -> Self {
+        VideoFile {
+            id,
+            path,
             duration: info.duration().unwrap_or_default(),
             resolution: info.resolution(),
             bitrate: info.bitrate(),
+            video_bitrate: info.video_bitrate().unwrap_or_default(),
+            audio_bitrate: info.audio_bitrate(),
             frame_rate: info.frame_rate(),
             codec: info.video_codec().to_owned(),
-            file_size: value.file_size as u64,
+            profile: info.video_profile().map(String::from),
+            container: info.container(),
+            file_size,
+            status,
+            audio_tracks: AudioTrack::from_probe(info),
+            pix_fmt: info.pix_fmt().map(String::from),
+            start_offset: info.start_offset(),
+            pinned: false,
+            output_path: None,
+            title: info.title().map(String::from),
+            chapters: info.chapters.clone(),
         }
     }
+
+    /// Probes a file that doesn't have to be in the database.
+    pub fn probe(path: &Utf8Path) -> Result<Self> {
+        let info = ffprobe(path)?;
+        let file_size = std::fs::metadata(path)?.len();
+        Ok(VideoFile::from_probe(
+            0,
+            path.to_owned(),
+            file_size,
+            TranscodeStatus::Pending,
+            &info,
+        ))
+    }
+
+    pub fn tier(&self) -> ResolutionTier {
+        resolution_tier(self.resolution)
+    }
+
+    /// Bits per pixel per frame of the source's video, a measure of how much it's
+    /// compressed. The audio isn't counted, a file with a large audio track can
+    /// still have cheap video.
+    pub fn bits_per_pixel(&self) -> Option<f64> {
+        ffprobe::bits_per_pixel(self.video_bitrate, self.resolution, self.frame_rate)
+    }
+
+    /// Whether the audio takes more of the bitrate than the video, so that
+    /// re-encoding the audio saves more than re-encoding the video.
+    pub fn is_audio_dominated(&self) -> bool {
+        ffprobe::is_audio_dominated(self.video_bitrate, self.audio_bitrate)
+    }
 }
 
-const EXTENSIONS: &[&str] = &["mp4", "mkv", "avi", "mov", "wmv", "flv", "webm", "m4v"];
+/// How many ffprobe processes run at once by default. Probing is mostly waiting
+/// for the disk, and many concurrent probes make a spinning disk seek constantly.
+/// The ignored test `probe_parallel_bench` compares values on real storage; the
+/// default is a guess that hasn't been checked with it yet.
+pub const DEFAULT_PROBE_PARALLEL: usize = 4;
+
+type ProbeResults = Vec<(Utf8PathBuf, u64, Result<FfProbe>)>;
+
+/// Runs `probe` on the files while showing a progress bar, with at most `parallel`
+/// probes at once. The probes run on their own thread pool rather than rayon's
+/// global one, which is sized to the number of CPUs.
+pub fn probe_files(
+    files: Vec<(Utf8PathBuf, u64)>,
+    parallel: usize,
+    probe: impl Fn(&Utf8Path) -> Result<FfProbe> + Sync,
+) -> Result<ProbeResults> {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(parallel.max(1))
+        .thread_name(|index| format!("ffprobe-{index}"))
+        .build()?;
+    let progress = ProgressBar::new(files.len() as u64).with_style(
+        ProgressStyle::default_bar()
+            .template("{msg} {wide_bar:.cyan/blue} {eta}")
+            .expect("bad progressbar template"),
+    );
+    progress.tick();
+
+    let results = pool.install(|| {
+        files
+            .into_par_iter()
+            .map(|(path, size)| {
+                let result = probe(&path);
+                (path, size, result)
+            })
+            .inspect(|p| {
+                let name = file_name_short(&p.0, 40);
+                progress.set_message(format!("Processing {:40}", name));
+                progress.inc(1);
+            })
+            .collect()
+    });
 
-#[derive(Debug, Clone, Copy, ValueEnum)]
-pub enum FileSortOrder {
-    BiggestFirst,
+    progress.finish_and_clear();
+    Ok(results)
+}
+
+/// Whether a file's content changed enough that it needs to be transcoded again.
+fn content_changed(old: &FfProbe, old_size: u64, new: &FfProbe, new_size: u64) -> bool {
+    let duration_changed = match (old.duration(), new.duration()) {
+        (Some(old), Some(new)) => (old - new).abs() > 1.0,
+        (old, new) => old.is_some() != new.is_some(),
+    };
+    old_size != new_size || old.video_codec() != new.video_codec() || duration_changed
+}
+
+#[derive(Debug, Default)]
+pub struct ReprobeSummary {
+    pub changed: usize,
+    pub unchanged: usize,
+    pub missing: usize,
+    pub failed: usize,
+}
+
+/// Runs ffprobe again for all files matching the filter and stores the results.
+/// Files whose content changed are reset to `Pending`.
+pub fn reprobe(
+    database: &Database,
+    filter: &FileFilter,
+    probe_parallel: usize,
+) -> Result<ReprobeSummary> {
+    let rows = database.list_filtered(filter, None)?;
+    let mut summary = ReprobeSummary::default();
+    let mut existing = HashMap::new();
+    let mut files = vec![];
+    for row in rows {
+        match row.path.metadata() {
+            Ok(metadata) => {
+                files.push((row.path.clone(), metadata.len()));
+                let modified = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|time| Timestamp::try_from(time).ok());
+                existing.insert(row.path.clone(), (row, modified));
+            }
+            Err(e) => {
+                warn!("file {} is missing: {}", row.path, e);
+                summary.missing += 1;
+            }
+        }
+    }
+
+    for (path, size, result) in probe_files(files, probe_parallel, |path| ffprobe(path))? {
+        let (row, modified) = &existing[&path];
+        match result {
+            Ok(ffprobe) => {
+                let old = row.ffprobe().unwrap_or_default();
+                let changed = content_changed(&old, row.file_size as u64, &ffprobe, size);
+                if changed {
+                    info!("{path} changed since it was scanned");
+                    summary.changed += 1;
+                } else {
+                    summary.unchanged += 1;
+                }
+                database.update_probe(row.id, size, &ffprobe, *modified, changed)?;
+            }
+            Err(e) => {
+                warn!("ffprobe failed for {}: {:?}", path, e);
+                summary.failed += 1;
+            }
+        }
+    }
+
+    Ok(summary)
+}
+
+/// The path below the scan root that exclude patterns are matched against, so
+/// that patterns don't depend on where the library is mounted. Directories end in
+/// a slash, which lets `Extras/` skip the directory itself. The root is empty.
+pub fn relative_to_root(path: &Utf8Path, root: &Utf8Path, is_dir: bool) -> String {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    if is_dir && !relative.as_str().is_empty() {
+        format!("{relative}/")
+    } else {
+        relative.to_string()
+    }
+}
+
+/// The extensions of the files a scan looks at when `--extensions` isn't given.
+pub const DEFAULT_EXTENSIONS: &[&str] = &["mp4", "mkv", "avi", "mov", "wmv", "flv", "webm", "m4v"];
+
+/// Parses an extension for `--extensions`, which has to be lowercase letters and
+/// digits without the dot.
+pub fn parse_extension(s: &str) -> Result<String, String> {
+    if s.is_empty() {
+        return Err("the extension is empty".into());
+    }
+    if let Some(c) = s
+        .chars()
+        .find(|c| !c.is_ascii_lowercase() && !c.is_ascii_digit())
+    {
+        return Err(format!(
+            "invalid extension '{s}': '{c}' isn't a lowercase letter or digit, pass e.g. m2ts instead of .M2TS"
+        ));
+    }
+    Ok(s.to_owned())
+}
+
+/// The extensions to scan for: `extensions` instead of the defaults when given,
+/// plus the `extra` ones.
+pub fn scan_extensions(extensions: Vec<String>, extra: Vec<String>) -> Vec<String> {
+    let base = if extensions.is_empty() {
+        DEFAULT_EXTENSIONS.iter().map(|e| e.to_string()).collect()
+    } else {
+        extensions
+    };
+    let mut result: Vec<String> = vec![];
+    for extension in base.into_iter().chain(extra) {
+        if !result.contains(&extension) {
+            result.push(extension);
+        }
+    }
+    result
+}
+
+/// The lowercased extension of the path, if it is one of `extensions`.
+fn matching_extension(path: &Utf8Path, extensions: &[String]) -> Option<String> {
+    let extension = path.extension()?.to_lowercase();
+    extensions.contains(&extension).then_some(extension)
+}
+
+/// The name of the file that leaves the directory it is in and everything below
+/// it out of scans and transcodes, unless `[scan] ignore-file` names another.
+pub const DEFAULT_IGNORE_FILE: &str = ".notranscode";
+
+/// Whether the directory contains the ignore file.
+pub fn has_ignore_file(directory: &Utf8Path, ignore_file: &str) -> bool {
+    directory.join(ignore_file).is_file()
+}
+
+/// The closest directory above the path that contains the ignore file, if any.
+pub fn ignoring_directory<'a>(path: &'a Utf8Path, ignore_file: &str) -> Option<&'a Utf8Path> {
+    path.ancestors()
+        .skip(1)
+        .filter(|directory| !directory.as_str().is_empty())
+        .find(|directory| has_ignore_file(directory, ignore_file))
+}
+
+/// What a scan adds to the database.
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    /// Paths below the scan root containing one of these strings are skipped.
+    pub exclude: Vec<String>,
+    /// Match `exclude` against the absolute path instead.
+    pub exclude_absolute: bool,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// Library that the scanned files are stamped with.
+    pub library: Option<String>,
+    pub codecs: CodecRules,
+    /// Add skipped files with the status `Skipped` and the reason.
+    pub record_skipped: bool,
+    /// Maximum number of concurrent ffprobe processes.
+    pub probe_parallel: usize,
+    /// Lowercase extensions of the files to look at, without the dot.
+    pub extensions: Vec<String>,
+    /// Directories containing a file of this name aren't walked.
+    pub ignore_file: String,
+    /// Probe files again that ffprobe failed for, even if they didn't change since.
+    pub retry_failed_probes: bool,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            exclude: vec![],
+            exclude_absolute: false,
+            min_size: None,
+            max_size: None,
+            library: None,
+            codecs: CodecRules::default(),
+            record_skipped: false,
+            probe_parallel: DEFAULT_PROBE_PARALLEL,
+            extensions: scan_extensions(vec![], vec![]),
+            ignore_file: DEFAULT_IGNORE_FILE.into(),
+            retry_failed_probes: false,
+        }
+    }
 }
 
 pub struct Collector {
     database: Database,
-
-    exclude: Vec<String>,
     base_path: Utf8PathBuf,
-    min_size: Option<u64>,
+    options: ScanOptions,
+}
+
+#[derive(Debug, Default)]
+pub struct ScanSummary {
+    pub files: InsertSummary,
+    /// Number of files excluded by each codec rule, in the order of the rules.
+    pub excluded: Vec<(String, usize)>,
+    /// Number of skipped files that were recorded with `--record-skipped`.
+    pub recorded_skipped: usize,
+    /// Number of files found with each of the extensions, including the ones
+    /// that no file has.
+    pub extensions: BTreeMap<String, usize>,
+    /// Number of directories left out because they contain the ignore file.
+    pub ignored_directories: usize,
+    /// Number of files that weren't probed because ffprobe failed for them
+    /// before and they didn't change since.
+    pub known_bad: usize,
+}
+
+/// The files found by walking the scan root, before they are probed.
+#[derive(Debug, Default)]
+struct Walk {
+    /// The files to probe with their size and modification time.
+    files: Vec<(Utf8PathBuf, u64, Option<Timestamp>)>,
+    skipped: Vec<NewSkippedFile>,
+    extensions: BTreeMap<String, usize>,
+    ignored_directories: usize,
 }
 
 impl Collector {
-    pub fn new(
-        database: Database,
-        base_path: Utf8PathBuf,
-        exclude: Vec<String>,
-        min_size: Option<u64>,
-    ) -> Self {
+    pub fn new(database: Database, base_path: Utf8PathBuf, mut options: ScanOptions) -> Self {
+        options.exclude = options
+            .exclude
+            .iter()
+            .map(|pattern| pattern.nfc().collect())
+            .collect();
         Self {
             database,
-            exclude,
             base_path,
-            min_size,
+            options,
         }
     }
 
     fn is_excluded(&self, e: &DirEntry) -> bool {
         let path = Utf8Path::from_path(e.path()).expect("path must be utf-8");
-        let is_excluded = self.exclude.iter().any(|p| path.as_str().contains(p));
+        let matched = if self.options.exclude_absolute {
+            path.to_string()
+        } else {
+            relative_to_root(path, &self.base_path, e.file_type().is_dir())
+        };
+        // the patterns are composed as well, so that they match either form
+        let matched: String = matched.nfc().collect();
+        let is_excluded = self.options.exclude.iter().any(|p| matched.contains(p));
         debug!("{} is excluded: {}", path, is_excluded);
         is_excluded
     }
 
-    pub fn gather_files(&self) -> Result<Vec<Utf8PathBuf>> {
-        let progress = ProgressBar::new_spinner();
-        progress.set_message("Gathering files...");
-        progress.enable_steady_tick(Duration::from_millis(250));
-
-        info!("gathering files at {}", self.base_path);
-        if self.base_path.is_file() {
-            info!("path argument is a file, not a directory, returning it");
-            return Ok(vec![self.base_path.clone()]);
-        }
+    /// Walks the scan root for files with one of the extensions, sorting out the
+    /// ones that are excluded or have the wrong size.
+    fn walk(&self) -> Walk {
+        let record_skipped = self.options.record_skipped;
         let mut files = vec![];
+        let mut skipped = vec![];
+        let mut extensions: BTreeMap<String, usize> = self
+            .options
+            .extensions
+            .iter()
+            .map(|extension| (extension.clone(), 0))
+            .collect();
+        let mut ignored_directories = 0;
         let walker = WalkDir::new(&self.base_path).into_iter();
-        for entry in walker.filter_entry(|e| !self.is_excluded(e)) {
+        // Excluded directories are only walked to record the files in them. A path
+        // below an excluded directory contains the pattern as well, with or without
+        // the trailing slash that directories are matched with. Directories with
+        // the ignore file are never walked.
+        let filter = |e: &DirEntry| {
+            if e.file_type().is_dir() {
+                let path = Utf8Path::from_path(e.path()).expect("path must be utf-8");
+                if has_ignore_file(path, &self.options.ignore_file) {
+                    info!("ignoring {path}, it contains {}", self.options.ignore_file);
+                    ignored_directories += 1;
+                    return false;
+                }
+            }
+            record_skipped || !self.is_excluded(e)
+        };
+        for entry in walker.filter_entry(filter) {
             match entry {
                 Ok(entry) => {
                     if entry.file_type().is_file() {
                         let path = Utf8Path::from_path(entry.path()).expect("path must be utf-8");
-                        if let (Some(stem), Some(ext)) = (path.file_stem(), path.extension()) {
-                            if EXTENSIONS.contains(&ext) && !stem.ends_with("_tmp") {
-                                match path.metadata() {
-                                    Ok(metadata) => {
-                                        let size = metadata.len();
-                                        if let Some(min_size) = self.min_size {
-                                            if size <= min_size {
-                                                debug!(
-                                                    "skipping file {} because it is too small",
-                                                    path
-                                                );
-                                                continue;
-                                            }
+                        if let Some(extension) = matching_extension(path, &self.options.extensions)
+                            && !paths::is_temp_file(path)
+                        {
+                            *extensions.entry(extension).or_default() += 1;
+                            match path.metadata() {
+                                Ok(metadata) => {
+                                    let size = metadata.len();
+                                    let reason = if self.is_excluded(&entry) {
+                                        Some(ScanSkipReason::Pattern)
+                                    } else if let Some(min_size) = self.options.min_size
+                                        && size <= min_size
+                                    {
+                                        debug!("skipping file {} because it is too small", path);
+                                        Some(ScanSkipReason::Size)
+                                    } else if let Some(max_size) = self.options.max_size
+                                        && size > max_size
+                                    {
+                                        debug!("skipping file {} because it is too large", path);
+                                        Some(ScanSkipReason::Size)
+                                    } else {
+                                        None
+                                    };
+                                    match reason {
+                                        Some(reason) => skipped.push(NewSkippedFile {
+                                            path: path.to_owned(),
+                                            file_size: size,
+                                            reason,
+                                            ffprobe_info: None,
+                                        }),
+                                        None => {
+                                            info!("found video file: {path}");
+                                            let modified = metadata
+                                                .modified()
+                                                .ok()
+                                                .and_then(|time| Timestamp::try_from(time).ok());
+                                            files.push((path.to_owned(), size, modified));
                                         }
-                                        info!("found video file: {path}");
-
-                                        files.push((path.to_owned(), size));
-                                    }
-                                    Err(e) => {
-                                        warn!("skipping file {} because of error: {}", path, e)
                                     }
                                 }
+                                Err(e) => {
+                                    warn!("skipping file {} because of error: {}", path, e)
+                                }
                             }
                         }
                     }
@@ -134,27 +570,112 @@ impl Collector {
                 Err(e) => warn!("error while walking directory: {}", e),
             }
         }
-        progress.finish_and_clear();
-
-        let progress = ProgressBar::new(files.len() as u64).with_style(
-            ProgressStyle::default_bar().template("{msg} {wide_bar:.cyan/blue} {eta}")?,
-        );
-        progress.tick();
+        Walk {
+            files,
+            skipped,
+            extensions,
+            ignored_directories,
+        }
+    }
 
-        let mut files: Vec<_> = files
-            .into_par_iter()
-            .flat_map(|(path, size)| ffprobe(&path).map(|ffprobe| (path, ffprobe, size)))
-            .inspect(|p| {
-                let name = file_name_short(&p.0, 40);
-                progress.set_message(format!("Processing {:40}", name));
-                progress.inc(1);
+    /// Leaves out the files that ffprobe failed for in an earlier scan, unless
+    /// they were modified since or `retry_failed_probes` is set. Returns the files
+    /// to probe and how many were left out.
+    fn skip_known_bad(
+        &self,
+        database: &Database,
+        files: Vec<(Utf8PathBuf, u64, Option<Timestamp>)>,
+    ) -> Result<(Vec<(Utf8PathBuf, u64)>, usize)> {
+        let failures = if self.options.retry_failed_probes {
+            HashMap::new()
+        } else {
+            let paths: Vec<_> = files.iter().map(|(path, _, _)| path.clone()).collect();
+            database.probe_failures(&paths)?
+        };
+        let mut known_bad = 0;
+        let files = files
+            .into_iter()
+            .filter(|(path, _, modified)| {
+                // the modification time only has to be older, a file that was
+                // replaced since the failure is probed again
+                let unchanged = failures
+                    .get(path)
+                    .is_some_and(|failed_at| modified.is_some_and(|m| m < *failed_at));
+                if unchanged {
+                    debug!("not probing {path}, ffprobe failed for it before");
+                    known_bad += 1;
+                }
+                !unchanged
             })
+            .map(|(path, size, _)| (path, size))
             .collect();
+        Ok((files, known_bad))
+    }
+
+    pub fn gather_files(&self) -> Result<ScanSummary> {
+        let progress = ProgressBar::new_spinner();
+        progress.set_message("Gathering files...");
+        progress.enable_steady_tick(Duration::from_millis(250));
 
+        info!("gathering files at {}", self.base_path);
+        let record_skipped = self.options.record_skipped;
+        let Walk {
+            files,
+            mut skipped,
+            extensions,
+            ignored_directories,
+        } = self.walk();
         progress.finish_and_clear();
+        // the files of a scan are in few directories, which are listed once
+        // to check the case of their paths
+        let database = self.database.with_dir_listings();
+        let modified: Vec<_> = files
+            .iter()
+            .filter_map(|(path, _, modified)| Some((path.clone(), (*modified)?)))
+            .collect();
+        let (files, known_bad) = self.skip_known_bad(&database, files)?;
+
+        let mut files: Vec<_> =
+            probe_files(files, self.options.probe_parallel, |path| ffprobe(path))?
+                .into_iter()
+                .filter_map(|(path, size, result)| match result {
+                    Ok(ffprobe) => Some((path, ffprobe, size)),
+                    Err(e) => {
+                        warn!("skipping file {} because ffprobe failed: {:?}", path, e);
+                        skipped.push(NewSkippedFile {
+                            path,
+                            file_size: size,
+                            reason: ScanSkipReason::ProbeFailed,
+                            ffprobe_info: None,
+                        });
+                        None
+                    }
+                })
+                .collect();
 
-        let excluded_codecs = &["hevc", "av1"];
-        files.retain(|(_, ffprobe, _)| !excluded_codecs.contains(&ffprobe.video_codec()));
+        let mut excluded: Vec<(String, usize)> = vec![];
+        files.retain(|(path, ffprobe, size)| {
+            let Some(rule) = self.options.codecs.excluding(&CodecInfo::from(ffprobe)) else {
+                return true;
+            };
+            debug!("excluding {path} because of codec rule {rule}");
+            skipped.push(NewSkippedFile {
+                path: path.clone(),
+                file_size: *size,
+                reason: if rule.uses_bits_per_pixel() {
+                    ScanSkipReason::Bpp
+                } else {
+                    ScanSkipReason::Codec
+                },
+                ffprobe_info: Some(ffprobe.clone()),
+            });
+            let rule = rule.to_string();
+            match excluded.iter_mut().find(|(r, _)| *r == rule) {
+                Some((_, count)) => *count += 1,
+                None => excluded.push((rule, 1)),
+            }
+            false
+        });
 
         info!("gathered {} files", files.len());
 
@@ -166,8 +687,405 @@ impl Collector {
                 ffprobe_info: f.1.clone(),
             })
             .collect();
-        self.database.insert_batch(&records)?;
+        let summary = database.insert_batch(&records)?;
+        database.set_source_modified(&modified)?;
+        // probe failures are always recorded, so that the next scan doesn't have
+        // to wait for ffprobe to fail on them again
+        if !record_skipped {
+            skipped.retain(|file| file.reason == ScanSkipReason::ProbeFailed);
+        }
+        let recorded_skipped = database.insert_skipped(&skipped)?;
+        if let Some(library) = &self.options.library {
+            let paths: Vec<_> = records
+                .into_iter()
+                .map(|r| r.path)
+                .chain(skipped.into_iter().map(|s| s.path))
+                .collect();
+            let stamped = database.set_library(&paths, library)?;
+            info!("stamped {stamped} files with library {library}");
+        }
+        Ok(ScanSummary {
+            files: summary,
+            excluded,
+            recorded_skipped,
+            extensions,
+            ignored_directories,
+            known_bad,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffprobe::{Format, Stream};
+
+    fn probe(codec: &str, duration: &str) -> FfProbe {
+        FfProbe {
+            streams: vec![Stream {
+                codec_type: Some("video".into()),
+                codec_name: Some(codec.into()),
+                ..Default::default()
+            }],
+            format: Format {
+                duration: Some(duration.into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_extension() {
+        assert_eq!(Ok("m2ts".to_string()), parse_extension("m2ts"));
+        assert_eq!(Ok("mp4".to_string()), parse_extension("mp4"));
+        for invalid in ["", ".ts", "MKV", "mkv ", "m 2ts", "tar.gz"] {
+            assert!(parse_extension(invalid).is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn test_scan_extensions() {
+        let strings = |s: &[&str]| s.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        assert_eq!(strings(DEFAULT_EXTENSIONS), scan_extensions(vec![], vec![]));
+        assert_eq!(
+            strings(&["ts", "m2ts", "mpg"]),
+            scan_extensions(strings(&["ts", "m2ts", "mpg"]), vec![])
+        );
+        let extended = scan_extensions(vec![], strings(&["ts", "mkv"]));
+        assert_eq!(DEFAULT_EXTENSIONS.len() + 1, extended.len());
+        assert_eq!("ts", extended.last().unwrap());
+        assert_eq!(
+            strings(&["avi", "mpg"]),
+            scan_extensions(strings(&["avi"]), strings(&["mpg", "avi"]))
+        );
+    }
+
+    #[test]
+    fn test_matching_extension() {
+        let extensions = scan_extensions(vec!["ts".into(), "m2ts".into()], vec![]);
+        assert_eq!(
+            Some("m2ts".to_string()),
+            matching_extension("/dvr/News.M2TS".into(), &extensions)
+        );
+        assert_eq!(
+            Some("ts".to_string()),
+            matching_extension("/dvr/show.ts".into(), &extensions)
+        );
+        assert_eq!(
+            None,
+            matching_extension("/dvr/show.mkv".into(), &extensions)
+        );
+        assert_eq!(None, matching_extension("/dvr/ts".into(), &extensions));
+        assert_eq!(
+            None,
+            matching_extension("/dvr/show.ts.part".into(), &extensions)
+        );
+    }
+
+    #[test]
+    fn test_resolution_tier() {
+        for (resolution, tier) in [
+            ((1920, 1080), ResolutionTier::Hd1080),
+            ((1916, 1076), ResolutionTier::Hd1080),
+            ((1920, 1036), ResolutionTier::Hd1080),
+            // ultrawide crops
+            ((1920, 800), ResolutionTier::Hd1080),
+            ((3840, 1600), ResolutionTier::Uhd4k),
+            ((1280, 536), ResolutionTier::Hd720),
+            // portrait
+            ((1080, 1920), ResolutionTier::Hd1080),
+            ((720, 1280), ResolutionTier::Hd720),
+            ((2160, 3840), ResolutionTier::Uhd4k),
+            // anamorphic
+            ((1440, 1080), ResolutionTier::Hd1080),
+            ((720, 576), ResolutionTier::Sd),
+            ((720, 480), ResolutionTier::Sd),
+            ((960, 720), ResolutionTier::Hd720),
+            ((640, 360), ResolutionTier::Sd),
+            ((2560, 1440), ResolutionTier::Qhd1440),
+            ((4096, 2160), ResolutionTier::Uhd4k),
+            ((7680, 4320), ResolutionTier::Uhd8k),
+            ((15360, 8640), ResolutionTier::Other),
+            ((0, 0), ResolutionTier::Other),
+        ] {
+            assert_eq!(tier, resolution_tier(resolution), "{resolution:?}");
+        }
+    }
+
+    #[test]
+    fn test_content_changed() {
+        let old = probe("h264", "100.0");
+        assert!(!content_changed(&old, 100, &probe("h264", "100.4"), 100));
+        assert!(content_changed(&old, 100, &probe("h264", "100.0"), 101));
+        assert!(content_changed(&old, 100, &probe("hevc", "100.0"), 100));
+        assert!(content_changed(&old, 100, &probe("h264", "50.0"), 100));
+    }
+
+    #[test]
+    fn test_relative_to_root() {
+        for root in ["/mnt/sam/videos", "/mnt/sam/videos/"] {
+            let root = Utf8Path::new(root);
+            assert_eq!(
+                "Extras/a.mkv",
+                relative_to_root("/mnt/sam/videos/Extras/a.mkv".into(), root, false)
+            );
+            assert_eq!(
+                "Extras/",
+                relative_to_root("/mnt/sam/videos/Extras".into(), root, true)
+            );
+            // directly in the root, and the root itself
+            assert_eq!(
+                "movie.mkv",
+                relative_to_root("/mnt/sam/videos/movie.mkv".into(), root, false)
+            );
+            assert_eq!("", relative_to_root("/mnt/sam/videos".into(), root, true));
+        }
+        assert_eq!(
+            "Samples/b.mkv",
+            relative_to_root("./Samples/b.mkv".into(), ".".into(), false)
+        );
+        // paths outside of the root are matched as they are
+        assert_eq!(
+            "/other/c.mkv",
+            relative_to_root("/other/c.mkv".into(), "/mnt".into(), false)
+        );
+    }
+
+    #[test]
+    fn test_ignore_file() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let root = Utf8Path::from_path(tempdir.path()).unwrap();
+        for (path, contents) in [
+            ("a.mkv", "video"),
+            ("movies/b.mkv", "video"),
+            ("movies/.notranscode", ""),
+            ("shows/c.mkv", "video"),
+            ("shows/kids/d.mkv", "video"),
+            ("shows/kids/.notranscode", ""),
+            ("shows/kids/season 1/e.mkv", "video"),
+            ("shows/news/f.mkv", "video"),
+            // a directory of that name doesn't count
+            ("shows/news/.notranscode/g.mkv", "video"),
+            ("shows/news/deep/.notranscode", ""),
+            ("shows/news/deep/h.mkv", "video"),
+        ] {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            std::fs::write(path, contents)?;
+        }
+        let walk = |options: ScanOptions| -> Result<(Vec<String>, usize)> {
+            let collector = Collector::new(Database::in_memory()?, root.to_owned(), options);
+            let walk = collector.walk();
+            let mut files: Vec<_> = walk
+                .files
+                .iter()
+                .map(|(path, _, _)| relative_to_root(path, root, false))
+                .collect();
+            files.sort();
+            Ok((files, walk.ignored_directories))
+        };
+
+        let (files, ignored) = walk(ScanOptions::default())?;
+        assert_eq!(
+            vec![
+                "a.mkv",
+                "shows/c.mkv",
+                "shows/news/.notranscode/g.mkv",
+                "shows/news/f.mkv"
+            ],
+            files
+        );
+        assert_eq!(3, ignored);
+
+        // even files that would be recorded as skipped aren't looked at
+        let (files, ignored) = walk(ScanOptions {
+            record_skipped: true,
+            exclude: vec!["shows/".into()],
+            ..Default::default()
+        })?;
+        assert_eq!(vec!["a.mkv"], files);
+        assert_eq!(3, ignored);
+
+        let (files, ignored) = walk(ScanOptions {
+            ignore_file: ".skip".into(),
+            ..Default::default()
+        })?;
+        assert_eq!(8, files.len());
+        assert_eq!(0, ignored);
+
+        // an ignore file in the root leaves out everything
+        std::fs::write(root.join(".notranscode"), "")?;
+        assert_eq!((vec![], 1), walk(ScanOptions::default())?);
+
+        assert_eq!(
+            Some(root),
+            ignoring_directory(&root.join("shows/c.mkv"), DEFAULT_IGNORE_FILE)
+        );
+        assert_eq!(
+            Some(root.join("shows/kids").as_path()),
+            ignoring_directory(&root.join("shows/kids/season 1/e.mkv"), DEFAULT_IGNORE_FILE)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_known_bad_files() -> Result<()> {
+        use std::time::{Duration, SystemTime};
+
+        let tempdir = tempfile::tempdir()?;
+        let root = Utf8Path::from_path(tempdir.path()).unwrap();
+        let database = Database::in_memory()?;
+        let hour = Duration::from_secs(3600);
+        for (name, modified) in [
+            ("broken.mkv", SystemTime::now() - hour),
+            // replaced with a good copy after ffprobe failed
+            ("replaced.mkv", SystemTime::now() + hour),
+            ("good.mkv", SystemTime::now() - hour),
+        ] {
+            let path = root.join(name);
+            std::fs::write(&path, "video")?;
+            std::fs::File::options()
+                .write(true)
+                .open(&path)?
+                .set_modified(modified)?;
+        }
+        let failed = ["broken.mkv", "replaced.mkv"].map(|name| NewSkippedFile {
+            path: root.join(name),
+            file_size: 5,
+            reason: ScanSkipReason::ProbeFailed,
+            ffprobe_info: None,
+        });
+        database.insert_skipped(&failed)?;
+
+        let probed = |options: ScanOptions| -> Result<(Vec<String>, usize)> {
+            let collector = Collector::new(database.clone(), root.to_owned(), options);
+            let (files, known_bad) = collector.skip_known_bad(&database, collector.walk().files)?;
+            let mut files: Vec<_> = files
+                .iter()
+                .map(|(path, _)| relative_to_root(path, root, false))
+                .collect();
+            files.sort();
+            Ok((files, known_bad))
+        };
+        assert_eq!(
+            (vec!["good.mkv".into(), "replaced.mkv".into()], 1),
+            probed(ScanOptions::default())?
+        );
+        assert_eq!(
+            (
+                vec![
+                    "broken.mkv".into(),
+                    "good.mkv".into(),
+                    "replaced.mkv".into()
+                ],
+                0
+            ),
+            probed(ScanOptions {
+                retry_failed_probes: true,
+                ..Default::default()
+            })?
+        );
+
+        // files that were skipped for other reasons are probed
+        database.forget(&FileFilter::default())?;
+        database.insert_skipped(&[NewSkippedFile {
+            path: root.join("broken.mkv"),
+            file_size: 5,
+            reason: ScanSkipReason::Size,
+            ffprobe_info: None,
+        }])?;
+        assert_eq!(0, probed(ScanOptions::default())?.1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_probe_parallel() -> Result<()> {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::thread;
+
+        let files: Vec<_> = (0..32)
+            .map(|i| (Utf8PathBuf::from(format!("/{i}.mkv")), i))
+            .collect();
+        for parallel in [1, 3] {
+            let running = AtomicUsize::new(0);
+            let high_water_mark = AtomicUsize::new(0);
+            let results = probe_files(files.clone(), parallel, |path| {
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                high_water_mark.fetch_max(now, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(5));
+                running.fetch_sub(1, Ordering::SeqCst);
+                if path.as_str() == "/7.mkv" {
+                    color_eyre::eyre::bail!("not a video");
+                }
+                Ok(probe("h264", "1.0"))
+            })?;
+
+            assert_eq!(32, results.len());
+            assert_eq!(1, results.iter().filter(|r| r.2.is_err()).count());
+            assert!(high_water_mark.load(Ordering::SeqCst) <= parallel);
+        }
+        Ok(())
+    }
 
-        Ok(files.into_iter().map(|f| f.0).collect())
+    /// Probes the videos below `TRANSCODER_PROBE_BENCH_DIR` with different
+    /// `--probe-parallel` values and prints how long each took, to compare them
+    /// on real storage, e.g. a USB disk:
+    ///
+    /// `TRANSCODER_PROBE_BENCH_DIR=/mnt/usb/videos cargo test --release probe_parallel_bench -- --ignored --nocapture`
+    ///
+    /// Every value probes the same files. Run as root, the page cache is dropped
+    /// before each of them so that they all read from the disk, otherwise the
+    /// files are probed once up front and all values read from a warm cache,
+    /// which leaves out the disk. The disk shouldn't be busy with anything else
+    /// meanwhile.
+    #[test]
+    #[ignore = "needs ffprobe and a directory of videos"]
+    fn probe_parallel_bench() -> Result<()> {
+        use std::process::Command;
+        use std::thread;
+        use std::time::Instant;
+
+        let Ok(dir) = std::env::var("TRANSCODER_PROBE_BENCH_DIR") else {
+            println!("set TRANSCODER_PROBE_BENCH_DIR to a directory of videos");
+            return Ok(());
+        };
+        let collector = Collector::new(Database::in_memory()?, dir.into(), ScanOptions::default());
+        let files: Vec<_> = collector
+            .walk()
+            .files
+            .into_iter()
+            .map(|(path, size, _)| (path, size))
+            .collect();
+        let cpus = thread::available_parallelism().map_or(16, |n| n.get());
+        let mut levels = vec![1, 2, DEFAULT_PROBE_PARALLEL, 8, cpus];
+        levels.sort();
+        levels.dedup();
+        // writing to drop_caches only frees clean pages, sync first
+        let drop_caches = || {
+            Command::new("sync").status().is_ok_and(|s| s.success())
+                && std::fs::write("/proc/sys/vm/drop_caches", "3").is_ok()
+        };
+        let cold = drop_caches();
+        if !cold {
+            println!("can't drop the page cache, comparing with a warm cache instead");
+            probe_files(files.clone(), cpus, |path| ffprobe(path))?;
+        }
+        for parallel in levels {
+            if cold {
+                drop_caches();
+            }
+            let start = Instant::now();
+            let results = probe_files(files.clone(), parallel, |path| ffprobe(path))?;
+            let elapsed = start.elapsed();
+            let failed = results.iter().filter(|r| r.2.is_err()).count();
+            println!(
+                "--probe-parallel {parallel}: {} files in {:.1}s, {:.1} files/s, {failed} failed",
+                files.len(),
+                elapsed.as_secs_f64(),
+                files.len() as f64 / elapsed.as_secs_f64()
+            );
+        }
+        Ok(())
     }
 }
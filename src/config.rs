@@ -0,0 +1,547 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::sync::Mutex;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info};
+
+use crate::Result;
+use crate::binaries::BinaryPaths;
+use crate::device::DeviceProfile;
+use crate::device::VideoCodec;
+use crate::encoder_rules::EncoderRules;
+use crate::energy::EnergyConfig;
+use crate::estimate::EstimateConfig;
+use crate::low_memory::LowMemory;
+use crate::renditions::RenditionProfile;
+use crate::speed::{self, Family, Preset, Speed};
+use crate::transcode::GpuMode;
+
+/// Name of the config file that is read from the current directory by default.
+pub const CONFIG_FILE_NAME: &str = "transcoder.toml";
+
+/// Name of the per-directory override file.
+pub const OVERRIDE_FILE_NAME: &str = ".transcoder.toml";
+
+pub const DEFAULT_CRF: u8 = 24;
+
+/// Encoding settings that can be given on the command line, in the config file
+/// or in a per-directory override file. Every field is optional so the layers
+/// can be merged.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct TranscodeSettings {
+    pub crf: Option<u8>,
+    pub speed: Option<Speed>,
+    /// The encoder's own preset, deprecated in favor of `speed`.
+    pub effort: Option<u8>,
+    pub film_grain: Option<u8>,
+    pub ten_bit: Option<bool>,
+    pub max_fps: Option<f64>,
+    pub reproducible: Option<bool>,
+    pub low_memory: Option<bool>,
+}
+
+impl TranscodeSettings {
+    /// Fills the fields that aren't set with the ones from `fallback`.
+    pub fn or(&self, fallback: &TranscodeSettings) -> TranscodeSettings {
+        // the speed and the effort are one setting, the effort of a fallback
+        // mustn't take over from a speed
+        let pace = if self.speed.is_some() || self.effort.is_some() {
+            self
+        } else {
+            fallback
+        };
+        TranscodeSettings {
+            crf: self.crf.or(fallback.crf),
+            speed: pace.speed,
+            effort: pace.effort,
+            film_grain: self.film_grain.or(fallback.film_grain),
+            ten_bit: self.ten_bit.or(fallback.ten_bit),
+            max_fps: self.max_fps.or(fallback.max_fps),
+            reproducible: self.reproducible.or(fallback.reproducible),
+            low_memory: self.low_memory.or(fallback.low_memory),
+        }
+    }
+}
+
+/// The settings that are used to encode a single file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct EncodeSettings {
+    pub crf: u8,
+    /// Missing in the options recorded before there was a scale, which have
+    /// the effort instead.
+    #[serde(default)]
+    pub speed: Speed,
+    /// The encoder's own preset, which takes precedence over the speed.
+    #[serde(default)]
+    pub effort: Option<u8>,
+    pub film_grain: Option<u8>,
+    pub ten_bit: bool,
+    pub max_fps: Option<f64>,
+    /// Fix the encoder's threads and leave out the metadata that changes between
+    /// runs, so that encoding the same source again gives the same bytes.
+    #[serde(default)]
+    pub reproducible: bool,
+    /// The SVT-AV1 parameters of `--low-memory`, for the file's resolution once
+    /// it's known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub low_memory: Option<LowMemory>,
+}
+
+impl EncodeSettings {
+    /// The preset of the encoder for the speed or the effort.
+    pub fn preset(&self, gpu: Option<&GpuMode>, codec: VideoCodec) -> Preset {
+        speed::resolve(self.speed, self.effort, Family::of(gpu, codec))
+    }
+}
+
+/// Resolves the settings for a file. Earlier layers win: command line flags
+/// first, then the directory override, then the config file, then the defaults,
+/// whose CRF is the one of the codec that is encoded to.
+pub fn merge(
+    cli: &TranscodeSettings,
+    directory: Option<&TranscodeSettings>,
+    config: &TranscodeSettings,
+    codec: VideoCodec,
+) -> EncodeSettings {
+    let layers: Vec<&TranscodeSettings> = [Some(cli), directory, Some(config)]
+        .into_iter()
+        .flatten()
+        .collect();
+
+    // the layer that sets either decides, with the speed winning within a layer
+    let (speed, effort) = layers
+        .iter()
+        .find_map(|l| match (l.speed, l.effort) {
+            (Some(speed), _) => Some((speed, None)),
+            (None, Some(effort)) => Some((Speed::DEFAULT, Some(effort))),
+            (None, None) => None,
+        })
+        .unwrap_or_default();
+    EncodeSettings {
+        crf: layers
+            .iter()
+            .find_map(|l| l.crf)
+            .or(codec.default_crf())
+            .unwrap_or(DEFAULT_CRF),
+        speed,
+        effort,
+        film_grain: layers.iter().find_map(|l| l.film_grain),
+        ten_bit: layers.iter().find_map(|l| l.ten_bit).unwrap_or_default(),
+        max_fps: layers.iter().find_map(|l| l.max_fps),
+        reproducible: layers
+            .iter()
+            .find_map(|l| l.reproducible)
+            .unwrap_or_default(),
+        low_memory: layers
+            .iter()
+            .find_map(|l| l.low_memory)
+            .unwrap_or_default()
+            .then(LowMemory::default),
+    }
+}
+
+/// Defaults for one library, from a `[libraries.<name>]` section of the config file.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LibraryConfig {
+    /// Directories that `scan --library <name>` scans when no path is given.
+    pub roots: Vec<Utf8PathBuf>,
+    /// Added to the `--exclude` patterns when scanning the library.
+    pub exclude: Vec<String>,
+    /// Encoding settings that take precedence over the `[transcode]` section.
+    pub transcode: TranscodeSettings,
+}
+
+/// Settings for `scan` and `reprobe`, from the `[scan]` section of the config file.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ScanConfig {
+    /// Maximum number of concurrent ffprobe processes.
+    pub probe_parallel: Option<usize>,
+    /// Name of the file that opts a directory out of scans and transcodes,
+    /// `.notranscode` by default.
+    pub ignore_file: Option<String>,
+}
+
+impl ScanConfig {
+    pub fn ignore_file(&self) -> String {
+        self.ignore_file
+            .clone()
+            .unwrap_or_else(|| crate::collect::DEFAULT_IGNORE_FILE.into())
+    }
+}
+
+/// Settings for what is stored in the database, from the `[database]` section of
+/// the config file.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// Error messages are cut off after this many characters, keeping the end.
+    pub max_error_length: Option<usize>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub transcode: TranscodeSettings,
+    pub scan: ScanConfig,
+    pub database: DatabaseConfig,
+    pub binaries: BinaryPaths,
+    pub energy: EnergyConfig,
+    pub estimate: EstimateConfig,
+    /// Per-file encoder choices, see [`EncoderRules`].
+    #[serde(rename = "encoder-rules")]
+    pub encoder_rules: EncoderRules,
+    pub libraries: BTreeMap<String, LibraryConfig>,
+    /// Profiles for `--device`, which replace the built-in ones of the same name.
+    pub devices: BTreeMap<String, DeviceProfile>,
+    /// Profiles for `--renditions`, which replace the built-in ones of the same name.
+    pub renditions: BTreeMap<String, RenditionProfile>,
+}
+
+impl Config {
+    /// Loads the config file at `path`, or `transcoder.toml` in the current
+    /// directory if no path is given. A missing default config file is not an error.
+    pub fn load(path: Option<&Utf8Path>) -> Result<Self> {
+        let (path, required) = match path {
+            Some(path) => (path, true),
+            None => (Utf8Path::new(CONFIG_FILE_NAME), false),
+        };
+        if !required && !path.is_file() {
+            debug!("no config file found at {path}, using defaults");
+            return Ok(Config::default());
+        }
+
+        info!("loading config file {path}");
+        let contents = fs::read_to_string(path)?;
+        Ok(toml::from_str(&contents)?)
+    }
+
+    /// The library's section. Libraries don't have to be in the config file, so
+    /// an unknown name gets empty defaults.
+    pub fn library(&self, name: Option<&str>) -> LibraryConfig {
+        name.and_then(|name| self.libraries.get(name))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// The config file's encoding settings for files of the library: the library's
+    /// own settings, falling back to the `[transcode]` section.
+    pub fn transcode_settings(&self, library: Option<&str>) -> TranscodeSettings {
+        self.library(library).transcode.or(&self.transcode)
+    }
+}
+
+/// A directory override that applies to a file.
+#[derive(Debug, Clone)]
+pub struct DirectoryOverride {
+    pub path: Utf8PathBuf,
+    pub settings: TranscodeSettings,
+}
+
+/// Looks up the nearest `.transcoder.toml` for files, caching the result per directory.
+#[derive(Default)]
+pub struct DirectoryOverrides {
+    cache: Mutex<HashMap<Utf8PathBuf, Option<DirectoryOverride>>>,
+}
+
+impl DirectoryOverrides {
+    pub fn for_file(&self, file: &Utf8Path) -> Result<Option<DirectoryOverride>> {
+        match file.parent() {
+            Some(directory) => self.for_directory(directory),
+            None => Ok(None),
+        }
+    }
+
+    fn for_directory(&self, directory: &Utf8Path) -> Result<Option<DirectoryOverride>> {
+        if let Some(cached) = self.cache.lock().unwrap().get(directory) {
+            return Ok(cached.clone());
+        }
+
+        let override_file = directory.join(OVERRIDE_FILE_NAME);
+        let result = if override_file.is_file() {
+            debug!("found directory override {override_file}");
+            let contents = fs::read_to_string(&override_file)?;
+            let settings = toml::from_str(&contents).map_err(|e| {
+                color_eyre::eyre::eyre!("invalid override file {override_file}: {e}")
+            })?;
+            Some(DirectoryOverride {
+                path: override_file,
+                settings,
+            })
+        } else {
+            match directory.parent() {
+                Some(parent) => self.for_directory(parent)?,
+                None => None,
+            }
+        };
+
+        self.cache
+            .lock()
+            .unwrap()
+            .insert(directory.to_owned(), result.clone());
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_defaults() {
+        let settings = merge(
+            &TranscodeSettings::default(),
+            None,
+            &TranscodeSettings::default(),
+            VideoCodec::Av1,
+        );
+        assert_eq!(
+            EncodeSettings {
+                crf: DEFAULT_CRF,
+                speed: Speed::DEFAULT,
+                effort: None,
+                film_grain: None,
+                ten_bit: false,
+                max_fps: None,
+                reproducible: false,
+                low_memory: None,
+            },
+            settings
+        );
+    }
+
+    #[test]
+    fn test_merge_precedence() {
+        let cli = TranscodeSettings {
+            crf: Some(20),
+            ..Default::default()
+        };
+        let directory = TranscodeSettings {
+            crf: Some(30),
+            effort: Some(4),
+            film_grain: Some(8),
+            ..Default::default()
+        };
+        let config = TranscodeSettings {
+            crf: Some(28),
+            effort: Some(6),
+            ten_bit: Some(true),
+            max_fps: Some(30.0),
+            ..Default::default()
+        };
+
+        let settings = merge(&cli, Some(&directory), &config, VideoCodec::Av1);
+        assert_eq!(20, settings.crf);
+        assert_eq!(Some(4), settings.effort);
+        assert_eq!(Some(8), settings.film_grain);
+        assert!(settings.ten_bit);
+        assert_eq!(Some(30.0), settings.max_fps);
+
+        let settings = merge(
+            &TranscodeSettings::default(),
+            None,
+            &config,
+            VideoCodec::Av1,
+        );
+        assert_eq!(28, settings.crf);
+        assert_eq!(Some(6), settings.effort);
+    }
+
+    #[test]
+    fn test_merge_cli_turns_off() {
+        let cli = TranscodeSettings {
+            ten_bit: Some(false),
+            ..Default::default()
+        };
+        let directory = TranscodeSettings {
+            ten_bit: Some(true),
+            ..Default::default()
+        };
+        let settings = merge(&cli, Some(&directory), &directory, VideoCodec::Av1);
+        assert!(!settings.ten_bit);
+
+        let settings = merge(
+            &TranscodeSettings::default(),
+            Some(&directory),
+            &TranscodeSettings::default(),
+            VideoCodec::Av1,
+        );
+        assert!(settings.ten_bit);
+    }
+
+    #[test]
+    fn test_merge_codec_default() {
+        // --codec without --crf only changes the default, the layers still win
+        let directory = TranscodeSettings {
+            crf: Some(30),
+            ..Default::default()
+        };
+        let config = TranscodeSettings {
+            crf: Some(26),
+            ..Default::default()
+        };
+        let none = TranscodeSettings::default();
+        let hevc = |directory, config| merge(&none, directory, config, VideoCodec::Hevc).crf;
+        assert_eq!(30, hevc(Some(&directory), &config));
+        assert_eq!(26, hevc(None, &config));
+        assert_eq!(28, hevc(None, &none));
+        assert_eq!(31, merge(&none, None, &none, VideoCodec::Vp9).crf);
+        assert_eq!(DEFAULT_CRF, merge(&none, None, &none, VideoCodec::Av1).crf);
+    }
+
+    #[test]
+    fn test_merge_speed_and_effort() {
+        let speed = |speed: u8| TranscodeSettings {
+            speed: Some(Speed::try_from(speed).unwrap()),
+            ..Default::default()
+        };
+        let effort = |effort: u8| TranscodeSettings {
+            effort: Some(effort),
+            ..Default::default()
+        };
+        let merged = |cli: &TranscodeSettings, directory: &TranscodeSettings| {
+            let settings = merge(cli, Some(directory), &speed(9), VideoCodec::Av1);
+            (settings.speed.value(), settings.effort)
+        };
+        // the first layer with either wins
+        assert_eq!((2, None), merged(&speed(2), &effort(4)));
+        assert_eq!(
+            (Speed::DEFAULT.value(), Some(4)),
+            merged(&effort(4), &speed(2))
+        );
+        assert_eq!((2, None), merged(&TranscodeSettings::default(), &speed(2)));
+        assert_eq!(
+            (9, None),
+            merged(&TranscodeSettings::default(), &TranscodeSettings::default())
+        );
+        // within a layer, the speed wins
+        let both = TranscodeSettings {
+            speed: Some(Speed::try_from(3).unwrap()),
+            effort: Some(12),
+            ..Default::default()
+        };
+        assert_eq!((3, None), merged(&both, &effort(4)));
+        // a library's effort isn't overruled by the speed of [transcode]
+        assert_eq!(Some(4), effort(4).or(&speed(2)).effort);
+        assert_eq!(None, effort(4).or(&speed(2)).speed);
+
+        let settings = merge(
+            &speed(10),
+            None,
+            &TranscodeSettings::default(),
+            VideoCodec::Av1,
+        );
+        assert_eq!(Preset::Svt(13), settings.preset(None, VideoCodec::Av1));
+        assert_eq!(
+            Preset::Nvenc(1),
+            settings.preset(Some(&GpuMode::Nvidia), VideoCodec::Av1)
+        );
+    }
+
+    #[test]
+    fn test_invalid_speed() {
+        assert!(toml::from_str::<Config>("[transcode]\nspeed = 11").is_err());
+        let config: Config = toml::from_str("[transcode]\nspeed = 10").unwrap();
+        assert_eq!(Some(10), config.transcode.speed.map(Speed::value));
+    }
+
+    #[test]
+    fn test_library_settings() -> Result<()> {
+        let config: Config = toml::from_str(
+            r#"
+            [transcode]
+            crf = 28
+            effort = 6
+
+            [scan]
+            probe-parallel = 2
+            ignore-file = ".skip-me"
+
+            [libraries.movies]
+            roots = ["/media/movies"]
+
+            [libraries.home-videos]
+            roots = ["/media/home", "/media/phone"]
+            exclude = ["thumbnails"]
+            transcode = { crf = 32, film-grain = 10 }
+            "#,
+        )?;
+        assert_eq!(
+            vec!["/media/home", "/media/phone"],
+            config.library(Some("home-videos")).roots
+        );
+        assert_eq!(
+            vec!["thumbnails"],
+            config.library(Some("home-videos")).exclude
+        );
+        assert_eq!(LibraryConfig::default(), config.library(Some("unknown")));
+        assert_eq!(Some(2), config.scan.probe_parallel);
+        assert_eq!(".skip-me", config.scan.ignore_file());
+        assert_eq!(".notranscode", ScanConfig::default().ignore_file());
+
+        let home = config.transcode_settings(Some("home-videos"));
+        assert_eq!(Some(32), home.crf);
+        assert_eq!(Some(6), home.effort);
+        assert_eq!(Some(10), home.film_grain);
+        assert_eq!(config.transcode, config.transcode_settings(Some("movies")));
+        assert_eq!(config.transcode, config.transcode_settings(None));
+
+        // the command line and directory overrides still win over the library
+        let cli = TranscodeSettings {
+            effort: Some(4),
+            ..Default::default()
+        };
+        let directory = TranscodeSettings {
+            crf: Some(20),
+            ..Default::default()
+        };
+        let settings = merge(&cli, Some(&directory), &home, VideoCodec::Av1);
+        assert_eq!(20, settings.crf);
+        assert_eq!(Some(4), settings.effort);
+        assert_eq!(Some(10), settings.film_grain);
+
+        let error = toml::from_str::<Config>("[libraries.movies]\nroot = \"/media\"");
+        assert!(error.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_override_file() -> Result<()> {
+        let settings: TranscodeSettings = toml::from_str("crf = 30\nten-bit = true\nmax-fps = 30")?;
+        assert_eq!(Some(30), settings.crf);
+        assert_eq!(Some(true), settings.ten_bit);
+        assert_eq!(Some(30.0), settings.max_fps);
+
+        let error = toml::from_str::<TranscodeSettings>("crf_value = 30");
+        assert!(error.is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_nearest_override() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let root = Utf8PathBuf::try_from(tempdir.path().to_owned())?;
+        let nested = root.join("anime").join("season 1");
+        fs::create_dir_all(&nested)?;
+        fs::write(root.join(OVERRIDE_FILE_NAME), "crf = 30")?;
+        fs::write(
+            root.join("anime").join(OVERRIDE_FILE_NAME),
+            "film-grain = 8",
+        )?;
+
+        let overrides = DirectoryOverrides::default();
+        let found = overrides.for_file(&nested.join("1.mkv"))?.unwrap();
+        assert_eq!(root.join("anime").join(OVERRIDE_FILE_NAME), found.path);
+        assert_eq!(Some(8), found.settings.film_grain);
+
+        let found = overrides.for_file(&root.join("1.mkv"))?.unwrap();
+        assert_eq!(Some(30), found.settings.crf);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,371 @@
+use std::fmt;
+use std::str::FromStr;
+
+use clap::ValueEnum;
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+use crate::device::{CodecLevel, VideoCodec, VideoLimits};
+use crate::ffprobe::FfProbe;
+use crate::transcode::GpuMode;
+
+/// An AV1 level like 5.1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Level {
+    major: u8,
+    minor: u8,
+}
+
+impl Level {
+    /// The level from the `seq_level_idx` in the sequence header, which ffprobe reports as `level`.
+    pub fn from_seq_level_idx(index: i64) -> Option<Level> {
+        // 31 means the stream has no level constraints
+        if !(0..=23).contains(&index) {
+            return None;
+        }
+        Some(Level {
+            major: 2 + (index / 4) as u8,
+            minor: (index % 4) as u8,
+        })
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let error = || format!("invalid AV1 level '{value}', expected 2.0 to 7.3, e.g. 5.1");
+        let (major, minor) = value.trim().split_once('.').unwrap_or((value.trim(), "0"));
+        let major: u8 = major.parse().map_err(|_| error())?;
+        let minor: u8 = minor.parse().map_err(|_| error())?;
+        if !(2..=7).contains(&major) || minor > 3 {
+            return Err(error());
+        }
+        Ok(Level { major, minor })
+    }
+}
+
+impl TryFrom<String> for Level {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<Level> for String {
+    fn from(level: Level) -> Self {
+        level.to_string()
+    }
+}
+
+impl From<Level> for CodecLevel {
+    fn from(level: Level) -> Self {
+        CodecLevel {
+            major: level.major,
+            minor: level.minor,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// AV1 profiles. Main covers 8 and 10-bit 4:2:0, which is what most devices decode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Profile {
+    Main,
+    High,
+    Professional,
+}
+
+impl Profile {
+    fn index(self) -> u8 {
+        match self {
+            Profile::Main => 0,
+            Profile::High => 1,
+            Profile::Professional => 2,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Profile::Main => "main",
+            Profile::High => "high",
+            Profile::Professional => "professional",
+        }
+    }
+}
+
+/// Limits for the encoded stream, so that the output plays on a given device.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Constraints {
+    pub max_level: Option<Level>,
+    pub profile: Option<Profile>,
+    /// The video of a `--device`, which may be another codec than AV1.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub video: Option<VideoLimits>,
+}
+
+impl Constraints {
+    /// The constraints for the video a device plays. AV1 levels and profiles
+    /// are checked here as well, those of other codecs by the device profile.
+    pub fn for_device(video: &VideoLimits) -> Result<Constraints> {
+        let mut constraints = Constraints {
+            video: Some(video.clone()),
+            ..Default::default()
+        };
+        if video.codec == VideoCodec::Av1 {
+            constraints.max_level = video
+                .max_level
+                .map(|level| level.to_string().parse::<Level>())
+                .transpose()
+                .map_err(|e| eyre!(e))?;
+            constraints.profile = video
+                .encoder_profile(false)
+                .map(|profile| Profile::from_str(profile, true))
+                .transpose()
+                .map_err(|e| eyre!(e))?;
+        }
+        Ok(constraints)
+    }
+
+    /// The constraints of `--codec` without a device: none for AV1, the codec
+    /// without limits for the others.
+    pub fn for_codec(codec: VideoCodec) -> Constraints {
+        match codec {
+            VideoCodec::Av1 => Constraints::default(),
+            codec => Constraints {
+                video: Some(VideoLimits {
+                    codec,
+                    max_level: None,
+                    profiles: vec![],
+                }),
+                ..Default::default()
+            },
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.max_level.is_none() && self.profile.is_none() && self.video.is_none()
+    }
+
+    /// The codec that is encoded to.
+    pub fn codec(&self) -> VideoCodec {
+        self.video
+            .as_ref()
+            .map_or(VideoCodec::Av1, |video| video.codec)
+    }
+
+    /// Encoder arguments for the constraints. libsvtav1 takes them as `-svtav1-params`,
+    /// which are returned separately so they can be combined with other parameters.
+    pub fn encoder_args(&self, gpu: Option<&GpuMode>, ten_bit: bool) -> (Vec<String>, Vec<String>) {
+        let mut args = vec![];
+        let mut svt_params = vec![];
+        if let Some(video) = &self.video
+            && video.codec != VideoCodec::Av1
+        {
+            return (video.encoder_args(gpu, ten_bit), svt_params);
+        }
+        match gpu {
+            Some(_) => {
+                if let Some(profile) = self.profile {
+                    args.extend(["-profile:v".into(), profile.name().into()]);
+                }
+                if let Some(level) = self.max_level {
+                    args.extend(["-level".into(), level.to_string()]);
+                }
+            }
+            None => {
+                if let Some(profile) = self.profile {
+                    svt_params.push(format!("profile={}", profile.index()));
+                }
+                if let Some(level) = self.max_level {
+                    svt_params.push(format!("level={}{}", level.major, level.minor));
+                }
+            }
+        }
+        (args, svt_params)
+    }
+
+    /// Compares the profile and level of an encoded file's video stream against
+    /// the constraints and describes every violation.
+    pub fn violations(&self, info: &FfProbe) -> Vec<String> {
+        let Some(stream) = info
+            .streams
+            .iter()
+            .find(|s| s.codec_type.as_deref() == Some("video"))
+        else {
+            return vec!["the output has no video stream".into()];
+        };
+
+        let mut violations = vec![];
+        if let Some(profile) = self.profile {
+            match stream.profile.as_deref() {
+                Some(actual) if actual.eq_ignore_ascii_case(profile.name()) => {}
+                actual => violations.push(format!(
+                    "profile is {}, expected {}",
+                    actual.unwrap_or("unknown"),
+                    profile.name()
+                )),
+            }
+        }
+        if let Some(max_level) = self.max_level {
+            match stream.level.and_then(Level::from_seq_level_idx) {
+                Some(level) if level <= max_level => {}
+                Some(level) => {
+                    violations.push(format!("level is {level}, above the maximum {max_level}"))
+                }
+                None => violations.push(format!(
+                    "level is unknown or unconstrained, expected at most {max_level}"
+                )),
+            }
+        }
+        violations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffprobe::Stream;
+
+    fn probe(profile: &str, level: i64) -> FfProbe {
+        FfProbe {
+            streams: vec![Stream {
+                codec_type: Some("video".into()),
+                codec_name: Some("av1".into()),
+                profile: Some(profile.into()),
+                level: Some(level),
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    fn constraints(level: &str, profile: Profile) -> Constraints {
+        Constraints {
+            max_level: Some(level.parse().unwrap()),
+            profile: Some(profile),
+            video: None,
+        }
+    }
+
+    #[test]
+    fn test_parse_level() {
+        assert_eq!(Ok(Level { major: 5, minor: 1 }), "5.1".parse());
+        assert_eq!(Ok(Level { major: 4, minor: 0 }), "4".parse());
+        for invalid in ["1.0", "5.4", "8.0", "5.1.1", "high", ""] {
+            assert!(invalid.parse::<Level>().is_err(), "input: {invalid}");
+        }
+    }
+
+    #[test]
+    fn test_seq_level_idx() {
+        assert_eq!(Some("2.0".parse().unwrap()), Level::from_seq_level_idx(0));
+        assert_eq!(Some("5.1".parse().unwrap()), Level::from_seq_level_idx(13));
+        assert_eq!(Some("7.3".parse().unwrap()), Level::from_seq_level_idx(23));
+        assert_eq!(None, Level::from_seq_level_idx(31));
+        assert_eq!(None, Level::from_seq_level_idx(-99));
+    }
+
+    #[test]
+    fn test_encoder_args() {
+        let constraints = constraints("5.1", Profile::Main);
+        assert_eq!(
+            (vec![], vec!["profile=0".into(), "level=51".into()]),
+            constraints.encoder_args(None, false)
+        );
+        assert_eq!(
+            (
+                vec![
+                    "-profile:v".into(),
+                    "main".into(),
+                    "-level".into(),
+                    "5.1".into()
+                ],
+                vec![]
+            ),
+            constraints.encoder_args(Some(&GpuMode::Nvidia), false)
+        );
+        assert_eq!(
+            (vec![], vec![]),
+            Constraints::default().encoder_args(None, false)
+        );
+    }
+
+    #[test]
+    fn test_violations() {
+        let constraints = constraints("5.1", Profile::Main);
+        assert!(constraints.violations(&probe("Main", 12)).is_empty());
+        assert!(constraints.violations(&probe("Main", 13)).is_empty());
+        assert_eq!(
+            vec![
+                "profile is High, expected main",
+                "level is 6.0, above the maximum 5.1"
+            ],
+            constraints.violations(&probe("High", 16))
+        );
+        assert_eq!(
+            vec!["level is unknown or unconstrained, expected at most 5.1"],
+            constraints.violations(&probe("Main", 31))
+        );
+        assert_eq!(
+            vec!["the output has no video stream"],
+            constraints.violations(&FfProbe::default())
+        );
+    }
+
+    #[test]
+    fn test_for_device() -> Result<()> {
+        let av1 = VideoLimits {
+            codec: VideoCodec::Av1,
+            max_level: Some("5.1".parse().unwrap()),
+            profiles: vec!["main".into()],
+        };
+        let constraints = Constraints::for_device(&av1)?;
+        assert_eq!(VideoCodec::Av1, constraints.codec());
+        assert_eq!(
+            (vec![], vec!["profile=0".into(), "level=51".into()]),
+            constraints.encoder_args(None, true)
+        );
+
+        let hevc = VideoLimits {
+            codec: VideoCodec::Hevc,
+            max_level: Some("5.1".parse().unwrap()),
+            profiles: vec!["main".into(), "main 10".into()],
+        };
+        let constraints = Constraints::for_device(&hevc)?;
+        assert_eq!(VideoCodec::Hevc, constraints.codec());
+        assert_eq!(None, constraints.max_level);
+        assert!(!constraints.is_empty());
+        assert_eq!(
+            (
+                vec![
+                    "-profile:v".into(),
+                    "main10".into(),
+                    "-level".into(),
+                    "5.1".into()
+                ],
+                vec![]
+            ),
+            constraints.encoder_args(Some(&GpuMode::Qsv), true)
+        );
+        assert_eq!(VideoCodec::Av1, Constraints::default().codec());
+
+        // AV1 levels start at 2.0
+        let invalid = VideoLimits {
+            max_level: Some("1.1".parse().unwrap()),
+            ..av1
+        };
+        assert!(Constraints::for_device(&invalid).is_err());
+        Ok(())
+    }
+}
@@ -1,38 +1,172 @@
-use std::fmt;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{fmt, fs};
 
-use camino::Utf8PathBuf;
-use jiff::Timestamp;
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::{Args, ValueEnum};
+use color_eyre::eyre::bail;
+use jiff::{SignedDuration, Timestamp};
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::params;
+use rusqlite::functions::FunctionFlags;
+use rusqlite::types::{Value, ValueRef};
+use rusqlite::{Connection, OptionalExtension, params, params_from_iter};
 use serde::{Deserialize, Serialize};
 use serde_rusqlite::from_rows;
-use tracing::info;
+use tracing::{info, warn};
+use unicode_normalization::UnicodeNormalization;
 
 use crate::Result;
-use crate::ffprobe::FfProbe;
+use crate::audio_hash::AudioHash;
+use crate::diff::{self, FileChange, FileState, SnapshotDiff, SnapshotTooOld};
+use crate::encoder_rules;
+use crate::error_message::{self, DEFAULT_MAX_LENGTH};
+use crate::ffprobe::{FfProbe, container_name};
+use crate::io_limit::IoMode;
+use crate::lock::LockHolder;
+use crate::paths::{self, DirListings};
+use crate::renditions::RenditionOutput;
+use crate::resolved_options::ResolvedOptions;
+use crate::resources::ResourceUsage;
+use crate::results::FileResult;
+use crate::source_changes::SourceChange;
+use crate::version::FfmpegVersion;
+use crate::where_sql::{self, WhereSql};
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+/// Schema changes applied on top of `init_db.sql`, in order. The number of applied
+/// migrations is stored in `PRAGMA user_version`.
+const MIGRATIONS: &[&str] = &[
+    include_str!("../migrations/001_runs.sql"),
+    include_str!("../migrations/002_thumbnails.sql"),
+    include_str!("../migrations/003_claims.sql"),
+    include_str!("../migrations/004_encode_time.sql"),
+    include_str!("../migrations/005_libraries.sql"),
+    include_str!("../migrations/006_verify.sql"),
+    include_str!("../migrations/007_crf_attempts.sql"),
+    include_str!("../migrations/008_skipped.sql"),
+    include_str!("../migrations/009_error_details.sql"),
+    include_str!("../migrations/010_encoder_rule.sql"),
+    include_str!("../migrations/011_normalize_paths.sql"),
+    include_str!("../migrations/012_encode_options.sql"),
+    include_str!("../migrations/013_audio_hash.sql"),
+    include_str!("../migrations/014_archive.sql"),
+    include_str!("../migrations/015_pinned.sql"),
+    include_str!("../migrations/016_resource_usage.sql"),
+    include_str!("../migrations/017_sample_runs.sql"),
+    include_str!("../migrations/018_read_rate.sql"),
+    include_str!("../migrations/019_file_ids.sql"),
+    include_str!("../migrations/020_source_changes.sql"),
+    include_str!("../migrations/021_renditions.sql"),
+    include_str!("../migrations/022_status_size_index.sql"),
+    include_str!("../migrations/023_archive_file_ids.sql"),
+];
+
+/// The columns of `transcode_files` that [`TranscodeFile`] is read from, besides
+/// the id. Listed instead of `*` so that columns added by newer versions
+/// don't get in the way.
+const FILE_COLUMNS: &str = "path, status, created_on, updated_on, error_message, file_size, ffprobe_info, run_id, thumbnail_path, claimed_by, claimed_at, encode_seconds, library, output_path, verified_at, verify_error, skip_reason, encoder_rule, encode_options, audio_hash, pinned, note, cpu_seconds, peak_rss, cpu_percent, io_mode, read_rate, source_modified, source_changes, renditions";
+
+/// The columns of `transcode_files` that `archive` copies into
+/// `archive_transcode_files` and back. The id is kept in `file_id` there.
+const ARCHIVED_COLUMNS: &str = "path, status, created_on, updated_on, error_message, file_size, ffprobe_info, run_id, thumbnail_path, claimed_by, claimed_at, encode_seconds, library, output_path, verified_at, verify_error, skip_reason, error_details, encoder_rule, encode_options, audio_hash, pinned, note, cpu_seconds, peak_rss, cpu_percent, io_mode, read_rate, source_modified, source_changes, renditions";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, ValueEnum)]
 #[serde(rename_all = "lowercase")]
 pub enum TranscodeStatus {
     Pending,
+    /// Claimed by a worker that is transcoding it.
+    #[serde(rename = "in_progress")]
+    InProgress,
+    /// Encoded with `--no-finalize`, the output waits for `finalize` or `accept`
+    /// to be checked and put in place.
+    Encoded,
+    /// Encoded with `--no-finalize`, and the output was thrown away by `reject`.
+    /// Only transcoded again with `--force`.
+    Rejected,
     Success,
     Error,
+    /// Found by a scan with `--record-skipped` but not added to the queue.
+    Skipped,
+    /// Transcoded, and the original was deleted by `reclaim`.
+    Reclaimed,
+}
+
+impl TranscodeStatus {
+    /// The value stored in the `status` column.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TranscodeStatus::Pending => "pending",
+            TranscodeStatus::InProgress => "in_progress",
+            TranscodeStatus::Encoded => "encoded",
+            TranscodeStatus::Rejected => "rejected",
+            TranscodeStatus::Success => "success",
+            TranscodeStatus::Error => "error",
+            TranscodeStatus::Skipped => "skipped",
+            TranscodeStatus::Reclaimed => "reclaimed",
+        }
+    }
 }
 
 impl fmt::Display for TranscodeStatus {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             TranscodeStatus::Pending => write!(f, "Pending"),
+            TranscodeStatus::InProgress => write!(f, "In progress"),
+            TranscodeStatus::Encoded => write!(f, "Encoded"),
+            TranscodeStatus::Rejected => write!(f, "Rejected"),
             TranscodeStatus::Success => write!(f, "Success"),
             TranscodeStatus::Error => write!(f, "Error"),
+            TranscodeStatus::Skipped => write!(f, "Skipped"),
+            TranscodeStatus::Reclaimed => write!(f, "Reclaimed"),
         }
     }
 }
 
+/// Why a scan with `--record-skipped` didn't add a file to the queue, or why
+/// `transcode` took a queued file out of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ScanSkipReason {
+    /// Matched an `--exclude` pattern.
+    Pattern,
+    /// Outside of `--min-size` and `--max-size`.
+    Size,
+    /// Excluded by a codec rule.
+    Codec,
+    /// Excluded by a codec rule with a bits per pixel condition.
+    Bpp,
+    ProbeFailed,
+    /// A directory above the file contains the ignore file.
+    IgnoreFile,
+}
+
+impl ScanSkipReason {
+    /// The value stored in the `skip_reason` column.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScanSkipReason::Pattern => "pattern",
+            ScanSkipReason::Size => "size",
+            ScanSkipReason::Codec => "codec",
+            ScanSkipReason::Bpp => "bpp",
+            ScanSkipReason::ProbeFailed => "probe-failed",
+            ScanSkipReason::IgnoreFile => "ignore-file",
+        }
+    }
+}
+
+impl fmt::Display for ScanSkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TranscodeFile {
-    pub rowid: i64,
+    /// The id users refer to the file by. It is kept by VACUUM and never given to
+    /// another file.
+    pub id: i64,
     pub path: Utf8PathBuf,
     pub status: TranscodeStatus,
     #[serde(with = "jiff::fmt::serde::timestamp::second::required")]
@@ -41,15 +175,421 @@ pub struct TranscodeFile {
     pub updated_on: Timestamp,
     pub error_message: Option<String>,
     pub file_size: i64,
-    pub ffprobe_info: String,
+    pub ffprobe_info: ProbeJson,
+    /// The run that last transcoded this file.
+    pub run_id: Option<i64>,
+    pub thumbnail_path: Option<Utf8PathBuf>,
+    /// The worker transcoding this file, as `name:pid`.
+    pub claimed_by: Option<String>,
+    /// When the worker last confirmed its claim, in the database's clock.
+    #[serde(with = "jiff::fmt::serde::timestamp::second::optional")]
+    pub claimed_at: Option<Timestamp>,
+    /// How long the last successful encode took, without time the system was suspended.
+    pub encode_seconds: Option<f64>,
+    /// The library the file was scanned into with `scan --library`.
+    pub library: Option<String>,
+    /// Where the transcoded file was written, the source path itself when it was replaced.
+    pub output_path: Option<Utf8PathBuf>,
+    /// When the output was last checked with `verify`.
+    #[serde(with = "jiff::fmt::serde::timestamp::second::optional")]
+    pub verified_at: Option<Timestamp>,
+    /// The decode errors of the last verification, `None` if it passed.
+    pub verify_error: Option<String>,
+    /// Why the file has the status `Skipped`.
+    pub skip_reason: Option<ScanSkipReason>,
+    /// The encoder rule from the config file that the last transcode used.
+    pub encoder_rule: Option<String>,
+    /// The resolved options of the last encode as JSON.
+    pub encode_options: Option<String>,
+    /// Whether the copied audio of the output matched the source, when
+    /// `--verify-audio-hash` compared them.
+    pub audio_hash: Option<AudioHash>,
+    /// Set by `pin`, keeps the file out of every transcode run, even with `--force`.
+    pub pinned: bool,
+    /// Why the file was pinned.
+    pub note: Option<String>,
+    /// CPU time of the last successful encode, where it could be collected.
+    pub cpu_seconds: Option<f64>,
+    /// The most memory ffmpeg had resident during the last successful encode, in bytes.
+    pub peak_rss: Option<i64>,
+    /// CPU time per wall time of the last successful encode, above 100 for
+    /// several busy cores.
+    pub cpu_percent: Option<f64>,
+    /// How the last successful encode read the source.
+    pub io_mode: Option<IoMode>,
+    /// The average rate the last successful encode read the source at, in bytes
+    /// per second.
+    pub read_rate: Option<f64>,
+    /// When the source was last modified, as the scan or `reprobe` saw it.
+    #[serde(with = "jiff::fmt::serde::timestamp::second::optional")]
+    pub source_modified: Option<Timestamp>,
+    /// What changed in the source since it was scanned, as JSON, when the last
+    /// transcode failed.
+    pub source_changes: Option<String>,
+    /// The outputs of `--renditions` as JSON, when the last transcode had them.
+    pub renditions: Option<String>,
 }
 
 impl TranscodeFile {
     pub fn ffprobe(&self) -> Option<FfProbe> {
-        serde_json::from_str(&self.ffprobe_info).ok()
+        let json = self.ffprobe_info.json().ok()?;
+        serde_json::from_str(&json).ok()
+    }
+
+    pub fn resolved_options(&self) -> Result<Option<ResolvedOptions>> {
+        self.encode_options
+            .as_deref()
+            .map(ResolvedOptions::from_json)
+            .transpose()
+    }
+
+    pub fn source_changes(&self) -> Result<Vec<SourceChange>> {
+        match &self.source_changes {
+            Some(json) => Ok(serde_json::from_str(json)?),
+            None => Ok(vec![]),
+        }
+    }
+
+    pub fn renditions(&self) -> Result<Vec<RenditionOutput>> {
+        match &self.renditions {
+            Some(json) => Ok(serde_json::from_str(json)?),
+            None => Ok(vec![]),
+        }
+    }
+}
+
+/// Marks a value of the `ffprobe_info` column as zstd compressed JSON. Plain JSON
+/// never starts with a zero byte.
+const COMPRESSED_PREFIX: u8 = 0;
+
+/// A value of the `ffprobe_info` column, plain JSON text or JSON that
+/// `compact --compress` compressed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProbeJson(Vec<u8>);
+
+impl ProbeJson {
+    pub fn compress(json: &str) -> Result<Self> {
+        let mut bytes = vec![COMPRESSED_PREFIX];
+        bytes.extend(error_message::compress(json)?);
+        Ok(ProbeJson(bytes))
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.0.first() == Some(&COMPRESSED_PREFIX)
+    }
+
+    /// The JSON, decompressed if necessary.
+    pub fn json(&self) -> Result<String> {
+        match self.0.split_first() {
+            Some((&COMPRESSED_PREFIX, compressed)) => error_message::decompress(compressed),
+            _ => Ok(String::from_utf8(self.0.clone())?),
+        }
+    }
+
+    /// The value to store in the column, text for plain JSON.
+    fn to_sql(&self) -> Value {
+        if self.is_compressed() {
+            Value::Blob(self.0.clone())
+        } else {
+            Value::Text(String::from_utf8_lossy(&self.0).into_owned())
+        }
+    }
+}
+
+impl From<String> for ProbeJson {
+    fn from(json: String) -> Self {
+        ProbeJson(json.into_bytes())
+    }
+}
+
+impl Serialize for ProbeJson {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let json = self.json().map_err(serde::ser::Error::custom)?;
+        serializer.serialize_str(&json)
+    }
+}
+
+impl<'de> Deserialize<'de> for ProbeJson {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl<'de> serde::de::Visitor<'de> for Visitor {
+            type Value = ProbeJson;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("JSON text or compressed JSON")
+            }
+
+            fn visit_str<E>(self, v: &str) -> Result<ProbeJson, E> {
+                Ok(ProbeJson(v.as_bytes().to_vec()))
+            }
+
+            fn visit_bytes<E>(self, v: &[u8]) -> Result<ProbeJson, E> {
+                Ok(ProbeJson(v.to_vec()))
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<ProbeJson, A::Error> {
+                let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or_default());
+                while let Some(byte) = seq.next_element()? {
+                    bytes.push(byte);
+                }
+                Ok(ProbeJson(bytes))
+            }
+
+            fn visit_unit<E>(self) -> Result<ProbeJson, E> {
+                Ok(ProbeJson::default())
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+/// Outcome of [`Database::compact`].
+#[derive(Debug, Default)]
+pub struct CompactSummary {
+    /// Rows whose ffprobe info was rewritten.
+    pub rewritten: usize,
+    /// Rows whose ffprobe info couldn't be read and was left as it was.
+    pub unreadable: usize,
+    /// Size of the database file in bytes.
+    pub size_before: u64,
+    pub size_after: u64,
+}
+
+/// A database whose schema was migrated by a newer version than this one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchemaTooNew {
+    /// The number of migrations applied to the database.
+    pub found: usize,
+    /// The number of migrations this version knows.
+    pub supported: usize,
+}
+
+impl fmt::Display for SchemaTooNew {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "database schema v{} is newer than this binary supports (v{}); upgrade transcoder or use --database to point elsewhere",
+            self.found, self.supported
+        )
+    }
+}
+
+impl std::error::Error for SchemaTooNew {}
+
+/// A database path that can't be used because of where it is, e.g. in a
+/// directory that doesn't exist or isn't writable.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DatabaseLocation {
+    pub path: Utf8PathBuf,
+    pub reason: String,
+}
+
+impl fmt::Display for DatabaseLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "can't use {} as the database: {}; use --database to point elsewhere",
+            self.path, self.reason
+        )
+    }
+}
+
+impl std::error::Error for DatabaseLocation {}
+
+/// Checks that the database at `path` can be created or written to, so that a
+/// wrong location is reported as such instead of as an SQLite error.
+fn check_location(path: &Utf8Path) -> Result<(), DatabaseLocation> {
+    let error = |reason: String| DatabaseLocation {
+        path: path.to_owned(),
+        reason,
+    };
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_str().is_empty() => parent,
+        _ => Utf8Path::new("."),
+    };
+    if !parent.is_dir() {
+        return Err(if parent.exists() {
+            error(format!("{parent} is not a directory"))
+        } else {
+            error(format!("the directory {parent} doesn't exist"))
+        });
+    }
+    // SQLite takes an empty file as a new database
+    match fs::OpenOptions::new().create(true).append(true).open(path) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::PermissionDenied => Err(if path.exists() {
+            error("the file isn't writable".into())
+        } else {
+            error(format!("the directory {parent} isn't writable"))
+        }),
+        Err(e) => Err(error(e.to_string())),
+    }
+}
+
+/// A database that a command only reads from doesn't exist, which is usually a
+/// command run before the first scan or in the wrong directory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MissingDatabase {
+    pub path: Utf8PathBuf,
+}
+
+impl fmt::Display for MissingDatabase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "there is no database at {}; run `transcoder scan <path>` first or use --database to point elsewhere",
+            self.path
+        )
+    }
+}
+
+impl std::error::Error for MissingDatabase {}
+
+/// Outcome of [`Database::unarchive`].
+#[derive(Debug, Default)]
+pub struct UnarchiveSummary {
+    pub restored: usize,
+    /// Archived files that were left in the archive because their path was
+    /// scanned again since.
+    pub conflicts: Vec<Utf8PathBuf>,
+}
+
+/// Filters for selecting rows from the database.
+#[derive(Debug, Clone, Default, Args)]
+pub struct FileFilter {
+    /// Only include files with this status
+    #[clap(long)]
+    pub status: Option<TranscodeStatus>,
+
+    /// Only include files whose path contains this string
+    #[clap(long = "path")]
+    pub path_contains: Option<String>,
+
+    /// Only include files in this container format (e.g. avi, asf, mp4, matroska)
+    #[clap(long)]
+    pub container: Option<String>,
+
+    /// Only include files transcoded with an ffmpeg build whose version or
+    /// library versions contain this string
+    #[clap(long)]
+    pub encoder_version: Option<String>,
+
+    /// Only include files that were scanned into this library
+    #[clap(long)]
+    pub library: Option<String>,
+
+    /// Only include files that have been pending for longer than this, e.g. 30d,
+    /// and stayed pending through at least --stale-runs runs since they were queued
+    #[clap(long, value_parser = crate::reclaim::parse_grace_period, conflicts_with = "status")]
+    pub stale: Option<SignedDuration>,
+
+    /// How many runs a file has to have stayed pending through for --stale
+    #[clap(long, default_value_t = 1, requires = "stale")]
+    pub stale_runs: u32,
+
+    /// Only include files last transcoded by the run with this id
+    #[clap(long)]
+    pub run: Option<i64>,
+
+    /// Only include files matching this SQL condition on the columns of the
+    /// transcode_files table, e.g. "file_size > 5e9". Values can be passed with
+    /// ? and --param. The ffprobe info is read with ffprobe_json(ffprobe_info)
+    #[clap(long = "where", value_name = "SQL")]
+    pub where_sql: Option<WhereSql>,
+
+    /// A value for the next ? of --where, bound as a number when it is one
+    #[clap(long, requires = "where_sql")]
+    pub param: Vec<String>,
+}
+
+impl FileFilter {
+    /// Checks that --where has as many ? as there are --param values.
+    pub fn check(&self) -> Result<()> {
+        let placeholders = self.where_sql.as_ref().map_or(0, WhereSql::placeholders);
+        if placeholders != self.param.len() {
+            bail!(
+                "--where has {placeholders} placeholders (?), but {} values were given with --param",
+                self.param.len()
+            );
+        }
+        Ok(())
+    }
+
+    fn where_clause(&self) -> (String, Vec<Value>) {
+        let mut conditions = vec![];
+        let mut params = vec![];
+        match self.status {
+            Some(status) => {
+                params.push(Value::Text(status.as_str().into()));
+                conditions.push(format!("status = ?{}", params.len()));
+            }
+            // skipped files are only listed when asked for
+            None => conditions.push("status != 'skipped'".into()),
+        }
+        if let Some(path) = &self.path_contains {
+            params.push(Value::Text(path.nfc().collect()));
+            conditions.push(format!("instr(nfc(path), ?{}) > 0", params.len()));
+        }
+        if let Some(container) = &self.container {
+            params.push(Value::Text(container_name(container)));
+            conditions.push(format!(
+                "container_name(json_extract(ffprobe_json(ffprobe_info), '$.format.format_name')) = ?{}",
+                params.len()
+            ));
+        }
+        if let Some(version) = &self.encoder_version {
+            params.push(Value::Text(version.clone()));
+            conditions.push(format!(
+                "run_id IN (SELECT id FROM runs WHERE instr(ffmpeg_version, ?{0}) > 0 OR instr(libraries, ?{0}) > 0)",
+                params.len()
+            ));
+        }
+        if let Some(library) = &self.library {
+            params.push(Value::Text(library.clone()));
+            conditions.push(format!("library = ?{}", params.len()));
+        }
+        if let Some(run) = self.run {
+            params.push(Value::Integer(run));
+            conditions.push(format!("run_id = ?{}", params.len()));
+        }
+        if let Some(where_sql) = &self.where_sql {
+            conditions.push(where_sql.to_sql(params.len() + 1));
+            params.extend(self.param.iter().map(|param| where_sql::param_value(param)));
+        }
+        if let Some(stale) = self.stale {
+            params.push(Value::Integer((Timestamp::now() - stale).as_second()));
+            conditions.push(format!(
+                "status = 'pending' AND created_on <= ?{}",
+                params.len()
+            ));
+            params.push(Value::Integer(self.stale_runs.into()));
+            conditions.push(format!(
+                "(SELECT COUNT(*) FROM runs WHERE started_on > created_on) >= ?{}",
+                params.len()
+            ));
+        }
+
+        if conditions.is_empty() {
+            (String::new(), params)
+        } else {
+            (format!("WHERE {}", conditions.join(" AND ")), params)
+        }
     }
 }
 
+/// The order [`Database::iter_filtered`] reads files in. Files of the same size
+/// are read in the order of their paths, so that every run picks the same ones.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SizeOrder {
+    Descending,
+    Ascending,
+}
+
 #[derive(Debug)]
 pub struct NewTranscodeFile {
     pub path: Utf8PathBuf,
@@ -57,16 +597,306 @@ pub struct NewTranscodeFile {
     pub ffprobe_info: FfProbe,
 }
 
+/// A file that a scan found but didn't add to the queue.
+#[derive(Debug)]
+pub struct NewSkippedFile {
+    pub path: Utf8PathBuf,
+    pub file_size: u64,
+    pub reason: ScanSkipReason,
+    /// Missing for files that were skipped before probing them.
+    pub ffprobe_info: Option<FfProbe>,
+}
+
+/// A transcode run and the ffmpeg build it used.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Run {
+    pub id: i64,
+    #[serde(with = "jiff::fmt::serde::timestamp::second::required")]
+    pub started_on: Timestamp,
+    pub ffmpeg_version: String,
+    /// JSON object of library names to versions.
+    pub libraries: String,
+    /// Whether the run encoded a random sample with `--sample-random`.
+    pub sample: bool,
+}
+
+impl Run {
+    pub fn ffmpeg(&self) -> FfmpegVersion {
+        FfmpegVersion {
+            version: self.ffmpeg_version.clone(),
+            libraries: serde_json::from_str(&self.libraries).unwrap_or_default(),
+        }
+    }
+}
+
+/// Number and total size of the files with one status.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StatusCount {
+    pub status: TranscodeStatus,
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// One encode of a file by `--auto-crf`, or the only one without it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CrfAttempt {
+    pub crf: u8,
+    pub output_size: i64,
+    #[serde(with = "jiff::fmt::serde::timestamp::second::required")]
+    pub attempted_on: Timestamp,
+}
+
+/// The last result of a transcoded or failed file, for `stats --history`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    /// The run that last transcoded the file.
+    pub run_id: Option<i64>,
+    #[serde(with = "jiff::fmt::serde::timestamp::second::optional")]
+    pub run_started_on: Option<Timestamp>,
+    pub status: TranscodeStatus,
+    /// When the last encode of the file finished, or when it failed. Not the
+    /// time it was last updated, which `reclaim` moves.
+    #[serde(with = "jiff::fmt::serde::timestamp::second::required")]
+    pub finished_on: Timestamp,
+    pub file_size: i64,
+    /// The size of the last encode, `None` for failed files.
+    pub output_size: Option<i64>,
+    pub encode_seconds: Option<f64>,
+    pub ffprobe_info: ProbeJson,
+}
+
+impl HistoryEntry {
+    pub fn ffprobe(&self) -> Option<FfProbe> {
+        let json = self.ffprobe_info.json().ok()?;
+        serde_json::from_str(&json).ok()
+    }
+}
+
+/// Outcome of [`Database::insert_batch`].
+#[derive(Debug, Default)]
+pub struct InsertSummary {
+    pub inserted: usize,
+    /// Files whose path was already in the database.
+    pub existing: usize,
+    /// Files that could not be inserted.
+    pub failed: Vec<Utf8PathBuf>,
+}
+
+/// Inserts rows that aren't in the database yet, returning how many were inserted.
+/// Files that an earlier scan skipped are added to the queue, archived files are
+/// left alone.
+fn insert_rows(
+    connection: &Connection,
+    files: &[NewTranscodeFile],
+    now: i64,
+    normalize: &impl Fn(&Utf8Path) -> Utf8PathBuf,
+) -> Result<usize> {
+    let mut statement = connection.prepare_cached("INSERT INTO transcode_files (path, created_on, updated_on, file_size, ffprobe_info) SELECT ?1, ?2, ?3, ?4, ?5 WHERE NOT EXISTS (SELECT 1 FROM archive_transcode_files WHERE path = ?1) ON CONFLICT (path) DO UPDATE SET status = 'pending', skip_reason = NULL, updated_on = excluded.updated_on, file_size = excluded.file_size, ffprobe_info = excluded.ffprobe_info WHERE status = 'skipped'")?;
+    let mut inserted = 0;
+    for file in files {
+        let json_info = serde_json::to_string(&file.ffprobe_info)?;
+        inserted += statement.execute(params![
+            normalize(&file.path).as_str(),
+            now,
+            now,
+            file.file_size as i64,
+            json_info
+        ])?;
+    }
+    Ok(inserted)
+}
+
+fn insert_chunk(
+    connection: &mut Connection,
+    files: &[NewTranscodeFile],
+    now: i64,
+    normalize: &impl Fn(&Utf8Path) -> Utf8PathBuf,
+) -> Result<usize> {
+    let tx = connection.transaction()?;
+    let inserted = insert_rows(&tx, files, now, normalize)?;
+    tx.commit()?;
+    Ok(inserted)
+}
+
 #[derive(Clone)]
 pub struct Database {
     db: Pool<SqliteConnectionManager>,
+    /// Longer error messages are cut off, with the full text stored compressed.
+    max_error_length: usize,
+    /// Whether the full text of cut off error messages is stored.
+    error_details: bool,
+    /// Whether paths are stored in the case they have on disk, see
+    /// [`paths::canonical_case`].
+    case_insensitive: bool,
+    /// Directory listings kept while checking the case of paths, see
+    /// [`Database::with_dir_listings`].
+    listings: Option<Arc<DirListings>>,
+    /// The database file, `None` for databases in memory.
+    path: Option<Utf8PathBuf>,
+}
+
+/// Sets up a new connection: waits for other processes' writes instead of failing
+/// and registers the custom SQL functions used in queries and migrations.
+fn init_connection(connection: &mut Connection) -> rusqlite::Result<()> {
+    let flags = FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC;
+    connection.busy_timeout(Duration::from_secs(30))?;
+    connection.create_scalar_function("container_name", 1, flags, |context| {
+        let format_name: Option<String> = context.get(0)?;
+        Ok(format_name.map(|name| container_name(&name)))
+    })?;
+    connection.create_scalar_function("ffprobe_json", 1, flags, |context| {
+        let info: Option<ProbeJson> = match context.get_raw(0) {
+            ValueRef::Text(text) | ValueRef::Blob(text) => Some(ProbeJson(text.to_vec())),
+            _ => None,
+        };
+        info.map(|info| info.json())
+            .transpose()
+            .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))
+    })?;
+    connection.create_scalar_function("nfc", 1, flags, |context| {
+        let text: Option<String> = context.get(0)?;
+        Ok(text.map(|t| t.nfc().collect::<String>()))
+    })?;
+    // looks at the filesystem, so it isn't deterministic
+    connection.create_scalar_function(
+        "normalize_path",
+        1,
+        FunctionFlags::SQLITE_UTF8,
+        |context| {
+            let path: Option<String> = context.get(0)?;
+            Ok(path.map(|p| paths::normalize_unicode(Utf8Path::new(&p)).into_string()))
+        },
+    )?;
+    connection.create_scalar_function("sanitize_error", 1, flags, |context| {
+        let message: Option<String> = context.get(0)?;
+        Ok(message.map(|m| error_message::sanitize(&m, DEFAULT_MAX_LENGTH).message))
+    })?;
+    connection.create_scalar_function("compress_error_details", 1, flags, |context| {
+        let message: Option<String> = context.get(0)?;
+        let full_text =
+            message.and_then(|m| error_message::sanitize(&m, DEFAULT_MAX_LENGTH).full_text);
+        full_text
+            .map(|text| error_message::compress(&text))
+            .transpose()
+            .map_err(|e| rusqlite::Error::UserFunctionError(e.into()))
+    })
+}
+
+fn bytes_saved_in(connection: &Connection, schema: &str) -> Result<u64> {
+    let saved: i64 = connection.query_row(
+        &format!("SELECT COALESCE(SUM(f.file_size - a.output_size), 0) FROM {schema}.transcode_files f JOIN {schema}.crf_attempts a ON a.id = (SELECT MAX(id) FROM {schema}.crf_attempts WHERE file_id = f.rowid) WHERE f.status IN ('success', 'reclaimed')"),
+        [],
+        |row| row.get(0),
+    )?;
+    Ok(saved.max(0) as u64)
+}
+
+/// The transcoded and failed files of one set of tables, see
+/// [`Database::history`].
+fn history_select(files: &str, attempts: &str) -> String {
+    format!(
+        "SELECT f.run_id, r.started_on AS run_started_on, f.status, \
+         CASE WHEN f.status = 'error' THEN f.updated_on ELSE COALESCE(a.attempted_on, f.updated_on) END AS finished_on, \
+         f.file_size, CASE WHEN f.status = 'error' THEN NULL ELSE a.output_size END AS output_size, \
+         f.encode_seconds, f.ffprobe_info \
+         FROM {files} f LEFT JOIN runs r ON r.id = f.run_id \
+         LEFT JOIN {attempts} a ON a.id = (SELECT MAX(id) FROM {attempts} WHERE file_id = f.id) \
+         WHERE f.status IN ('encoded', 'success', 'reclaimed', 'error')"
+    )
+}
+
+/// An SQLite URI that opens the file read-only.
+fn read_only_uri(path: &Utf8Path) -> String {
+    let path = path
+        .as_str()
+        .replace('%', "%25")
+        .replace('?', "%3f")
+        .replace('#', "%23");
+    format!("file:{path}?mode=ro")
+}
+
+/// Compares the main database with the one attached as `old`.
+fn diff_attached(connection: &Connection) -> Result<SnapshotDiff> {
+    let version: usize = connection.query_row("PRAGMA old.user_version", [], |row| row.get(0))?;
+    if version < diff::MIN_SCHEMA_VERSION {
+        return Err(SnapshotTooOld { found: version }.into());
+    }
+    if version > MIGRATIONS.len() {
+        return Err(SchemaTooNew {
+            found: version,
+            supported: MIGRATIONS.len(),
+        }
+        .into());
+    }
+    let files = |schema: &str, archive: bool| {
+        let mut sql = format!("SELECT path, status, file_size FROM {schema}.transcode_files");
+        if archive {
+            sql.push_str(&format!(
+                " UNION ALL SELECT path, status, file_size FROM {schema}.archive_transcode_files"
+            ));
+        }
+        sql
+    };
+    // the archive tables came with migration 14
+    let sql = format!(
+        "WITH old_files AS ({}), new_files AS ({}) \
+         SELECT COALESCE(n.path, o.path), o.status, o.file_size, n.status, n.file_size \
+         FROM old_files o FULL OUTER JOIN new_files n ON n.path = o.path \
+         WHERE o.path IS NULL OR n.path IS NULL OR o.status != n.status OR o.file_size != n.file_size",
+        files("old", version >= 14),
+        files("main", true),
+    );
+    let mut statement = connection.prepare(&sql)?;
+    let state = |status: Option<String>, file_size: Option<i64>| {
+        status.map(|status| FileState {
+            status,
+            file_size: file_size.unwrap_or_default() as u64,
+        })
+    };
+    let files = statement
+        .query_map([], |row| {
+            Ok(FileChange {
+                path: row.get::<_, String>(0)?.into(),
+                old: state(row.get(1)?, row.get(2)?),
+                new: state(row.get(3)?, row.get(4)?),
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+    Ok(SnapshotDiff::new(
+        files,
+        bytes_saved_in(connection, "old")?,
+        bytes_saved_in(connection, "main")?,
+    ))
 }
 
 impl Database {
-    pub fn new() -> Result<Self> {
-        let manager = SqliteConnectionManager::file("transcoder.db");
+    /// Opens the database at `path`, creating it if it doesn't exist.
+    pub fn open(path: &Utf8Path) -> Result<Self> {
+        check_location(path)?;
+        Self::connect(path)
+    }
+
+    /// Opens the database at `path` for a command that only reads it, which
+    /// leaves a missing database alone instead of creating an empty one.
+    pub fn open_existing(path: &Utf8Path) -> Result<Self> {
+        if !path.is_file() {
+            return Err(MissingDatabase {
+                path: paths::absolute(path),
+            }
+            .into());
+        }
+        Self::connect(path)
+    }
+
+    fn connect(path: &Utf8Path) -> Result<Self> {
+        let manager = SqliteConnectionManager::file(path).with_init(init_connection);
         let this = Self {
             db: Pool::new(manager)?,
+            max_error_length: DEFAULT_MAX_LENGTH,
+            error_details: true,
+            case_insensitive: false,
+            listings: None,
+            path: Some(path.to_owned()),
         };
         this.init_database()?;
         Ok(this)
@@ -74,18 +904,87 @@ impl Database {
 
     #[cfg(test)]
     pub fn in_memory() -> Result<Self> {
-        let manager = SqliteConnectionManager::memory();
+        let manager = SqliteConnectionManager::memory().with_init(init_connection);
         let this = Self {
             db: Pool::new(manager)?,
+            max_error_length: DEFAULT_MAX_LENGTH,
+            error_details: true,
+            case_insensitive: false,
+            listings: None,
+            path: None,
         };
         this.init_database()?;
         Ok(this)
     }
 
+    /// Sets the length that error messages are cut off at.
+    pub fn with_max_error_length(self, max_error_length: usize) -> Self {
+        Self {
+            max_error_length,
+            ..self
+        }
+    }
+
+    /// Sets whether the full text of cut off error messages is stored, which
+    /// isn't needed when `--log-dir` keeps it.
+    pub fn with_error_details(self, error_details: bool) -> Self {
+        Self {
+            error_details,
+            ..self
+        }
+    }
+
+    /// Sets whether paths are stored and looked up in the case they have on disk,
+    /// for case-insensitive filesystems.
+    pub fn with_case_insensitive_paths(self, case_insensitive: bool) -> Self {
+        Self {
+            case_insensitive,
+            ..self
+        }
+    }
+
+    /// A handle that keeps the directory listings it reads to check the case of
+    /// paths, for a scan that looks up many files in the same directories.
+    /// Files created in those directories in the meantime aren't seen.
+    pub fn with_dir_listings(&self) -> Self {
+        Self {
+            listings: Some(Arc::default()),
+            ..self.clone()
+        }
+    }
+
+    /// The path as it is stored, see [`paths::normalize`].
+    fn normalize(&self, path: &Utf8Path) -> Utf8PathBuf {
+        paths::normalize(path, self.case_insensitive, self.listings.as_deref())
+    }
+
+    /// The database file, `None` for databases in memory.
+    pub fn path(&self) -> Option<&Utf8Path> {
+        self.path.as_deref()
+    }
+
+    /// Creates the tables and applies the migrations the database is missing.
+    /// Databases migrated by a newer version are refused before anything is
+    /// changed.
     fn init_database(&self) -> Result<()> {
-        let sql = include_str!("../init_db.sql");
-        let connection = self.db.get()?;
-        connection.execute(sql, ())?;
+        let mut connection = self.db.get()?;
+        let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        if version > MIGRATIONS.len() {
+            return Err(SchemaTooNew {
+                found: version,
+                supported: MIGRATIONS.len(),
+            }
+            .into());
+        }
+        connection.execute_batch(include_str!("../init_db.sql"))?;
+
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            info!("applying database migration {}", index + 1);
+            let tx = connection.transaction()?;
+            tx.execute_batch(migration)?;
+            tx.pragma_update(None, "user_version", index + 1)?;
+            tx.commit()?;
+        }
         Ok(())
     }
 
@@ -94,152 +993,2643 @@ impl Database {
         let connection = self.db.get()?;
         let now = Timestamp::now().as_second();
 
-        connection.execute("INSERT INTO transcode_files (path, created_on, updated_on, file_size) VALUES (?1, ?2, ?3, ?4)", params![
-            file.path.as_str(),
+        let json_info = serde_json::to_string(&file.ffprobe_info)?;
+        connection.execute("INSERT INTO transcode_files (path, created_on, updated_on, file_size, ffprobe_info) VALUES (?1, ?2, ?3, ?4, ?5)", params![
+            self.normalize(&file.path).as_str(),
             now,
             now,
             file.file_size as i64,
+            json_info,
         ])?;
 
         Ok(())
     }
 
+    /// Whether no file was ever queued, or all of them were forgotten or archived.
+    pub fn is_empty(&self) -> Result<bool> {
+        let connection = self.db.get()?;
+        let empty = connection.query_row(
+            "SELECT NOT EXISTS (SELECT 1 FROM transcode_files)",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(empty)
+    }
+
     pub fn list(&self) -> Result<Vec<TranscodeFile>> {
-        self.list_limit(None)
+        self.list_filtered(&FileFilter::default(), None)
     }
 
-    pub fn list_limit(&self, count: Option<i64>) -> Result<Vec<TranscodeFile>> {
+    pub fn list_filtered(
+        &self,
+        filter: &FileFilter,
+        count: Option<i64>,
+    ) -> Result<Vec<TranscodeFile>> {
         let connection = self.db.get()?;
-        let mut statement = connection
-            .prepare("SELECT rowid, * FROM transcode_files ORDER BY file_size DESC LIMIT ?1")?;
-        let res = from_rows::<TranscodeFile>(statement.query([count.unwrap_or(i64::MAX)])?);
+        let (where_clause, mut params) = filter.where_clause();
+        params.push(Value::Integer(count.unwrap_or(i64::MAX)));
+        let sql = format!(
+            "SELECT id, {FILE_COLUMNS} FROM transcode_files {where_clause} ORDER BY file_size DESC, path ASC, id ASC LIMIT ?{}",
+            params.len()
+        );
+        let mut statement = connection.prepare(&sql)?;
+        let res = from_rows::<TranscodeFile>(statement.query(params_from_iter(params))?);
         let rows: Result<_, serde_rusqlite::Error> = res.collect();
         Ok(rows?)
     }
 
-    pub fn insert_batch(&self, files: &[NewTranscodeFile]) -> Result<()> {
-        info!("inserting batch of {} files", files.len());
-        let mut connection = self.db.get()?;
+    /// One page of the files matching `filter`, ordered by size and then by
+    /// path, starting after the file `after` as `(file_size, path)`. Paging by
+    /// the last row instead of an offset means files that change status between
+    /// pages are neither skipped nor read twice.
+    pub fn page_filtered(
+        &self,
+        filter: &FileFilter,
+        order: SizeOrder,
+        after: Option<(i64, &Utf8Path)>,
+        page_size: usize,
+    ) -> Result<Vec<TranscodeFile>> {
+        let connection = self.db.get()?;
+        let (mut where_clause, mut params) = filter.where_clause();
+        let (compare, direction) = match order {
+            SizeOrder::Descending => ("<", "DESC"),
+            SizeOrder::Ascending => (">", "ASC"),
+        };
+        if let Some((file_size, path)) = after {
+            params.push(Value::Integer(file_size));
+            params.push(Value::Text(path.to_string()));
+            let condition = format!(
+                "(file_size {compare} ?{0} OR (file_size = ?{0} AND path > ?{1}))",
+                params.len() - 1,
+                params.len()
+            );
+            where_clause = if where_clause.is_empty() {
+                format!("WHERE {condition}")
+            } else {
+                format!("{where_clause} AND {condition}")
+            };
+        }
+        params.push(Value::Integer(page_size as i64));
+        let sql = format!(
+            "SELECT id, {FILE_COLUMNS} FROM transcode_files {where_clause} ORDER BY file_size {direction}, path ASC, id ASC LIMIT ?{}",
+            params.len()
+        );
+        let mut statement = connection.prepare(&sql)?;
+        let res = from_rows::<TranscodeFile>(statement.query(params_from_iter(params))?);
+        let rows: Result<_, serde_rusqlite::Error> = res.collect();
+        Ok(rows?)
+    }
 
-        let now = Timestamp::now().as_second();
+    /// The files matching `filter` ordered by size, read from the database a
+    /// page at a time as the iterator is advanced.
+    pub fn iter_filtered<'a>(
+        &'a self,
+        filter: &'a FileFilter,
+        order: SizeOrder,
+        page_size: usize,
+    ) -> impl Iterator<Item = Result<TranscodeFile>> + 'a {
+        let mut after: Option<(i64, Utf8PathBuf)> = None;
+        let mut page = std::vec::IntoIter::default();
+        let mut done = false;
+        std::iter::from_fn(move || {
+            loop {
+                if let Some(file) = page.next() {
+                    return Some(Ok(file));
+                }
+                if done {
+                    return None;
+                }
+                let last = after.as_ref().map(|(size, path)| (*size, path.as_path()));
+                match self.page_filtered(filter, order, last, page_size) {
+                    Ok(rows) => {
+                        done = rows.len() < page_size;
+                        after = rows.last().map(|f| (f.file_size, f.path.clone()));
+                        page = rows.into_iter();
+                    }
+                    Err(e) => {
+                        done = true;
+                        return Some(Err(e));
+                    }
+                }
+            }
+        })
+    }
+
+    /// Archived files with the id they had before they were archived, or their
+    /// id in the archive if an older version archived them.
+    pub fn list_archived(&self, filter: &FileFilter) -> Result<Vec<TranscodeFile>> {
+        let connection = self.db.get()?;
+        let (where_clause, params) = filter.where_clause();
+        let mut statement = connection.prepare(&format!(
+            "SELECT COALESCE(file_id, id) AS id, {FILE_COLUMNS} FROM archive_transcode_files {where_clause} ORDER BY file_size DESC, path ASC, id ASC"
+        ))?;
+        let res = from_rows::<TranscodeFile>(statement.query(params_from_iter(params))?);
+        let rows: Result<_, serde_rusqlite::Error> = res.collect();
+        Ok(rows?)
+    }
+
+    /// Moves transcoded and reclaimed files that haven't changed for `older_than`
+    /// and their CRF attempts into the archive tables, where scans and the other
+    /// commands don't see them. Returns how many files were archived.
+    pub fn archive(&self, older_than: SignedDuration) -> Result<usize> {
+        let mut connection = self.db.get()?;
+        let now = Timestamp::now();
+        let cutoff = (now - older_than).as_second();
         let tx = connection.transaction()?;
+        let ids: Vec<i64> = {
+            let mut statement = tx.prepare(
+                "SELECT id FROM transcode_files WHERE status IN ('success', 'reclaimed') AND updated_on < ?1",
+            )?;
+            statement
+                .query_map([cutoff], |row| row.get(0))?
+                .collect::<Result<_, _>>()?
+        };
         {
-            let mut statement = tx.prepare("INSERT INTO transcode_files (path, created_on, updated_on, file_size, ffprobe_info) VALUES (?1, ?2, ?3, ?4, ?5) ON CONFLICT (path) DO NOTHING")?;
-            for file in files {
-                let json_info = serde_json::to_string(&file.ffprobe_info)?;
-                statement.execute(params![
-                    file.path.as_str(),
-                    now,
-                    now,
-                    file.file_size as i64,
-                    json_info
-                ])?;
+            let mut archive_file = tx.prepare(&format!(
+                "INSERT INTO archive_transcode_files (archived_on, file_id, {ARCHIVED_COLUMNS}) SELECT ?1, id, {ARCHIVED_COLUMNS} FROM transcode_files WHERE id = ?2"
+            ))?;
+            let mut archive_attempts = tx.prepare(
+                "INSERT INTO archive_crf_attempts (file_id, crf, output_size, attempted_on) SELECT ?1, crf, output_size, attempted_on FROM crf_attempts WHERE file_id = ?2 ORDER BY id",
+            )?;
+            let mut delete_attempts = tx.prepare("DELETE FROM crf_attempts WHERE file_id = ?1")?;
+            let mut delete_file = tx.prepare("DELETE FROM transcode_files WHERE id = ?1")?;
+            for &id in &ids {
+                archive_file.execute(params![now.as_second(), id])?;
+                let archived_id = tx.last_insert_rowid();
+                archive_attempts.execute(params![archived_id, id])?;
+                delete_attempts.execute([id])?;
+                delete_file.execute([id])?;
             }
         }
-
         tx.commit()?;
+        Ok(ids.len())
+    }
 
-        Ok(())
+    /// Moves the archived files whose path matches the glob pattern back into the
+    /// working tables. Files whose path is in the working tables again stay in the
+    /// archive.
+    pub fn unarchive(&self, pattern: &str) -> Result<UnarchiveSummary> {
+        let mut connection = self.db.get()?;
+        let tx = connection.transaction()?;
+        let archived: Vec<(i64, String)> = {
+            let mut statement = tx.prepare("SELECT id, path FROM archive_transcode_files")?;
+            statement
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<_, _>>()?
+        };
+        let mut summary = UnarchiveSummary::default();
+        {
+            let mut exists =
+                tx.prepare("SELECT EXISTS (SELECT 1 FROM transcode_files WHERE path = ?1)")?;
+            let mut restore_file = tx.prepare(&format!(
+                "INSERT INTO transcode_files (id, {ARCHIVED_COLUMNS}) SELECT file_id, {ARCHIVED_COLUMNS} FROM archive_transcode_files WHERE id = ?1"
+            ))?;
+            let mut restore_attempts = tx.prepare(
+                "INSERT INTO crf_attempts (file_id, crf, output_size, attempted_on) SELECT ?1, crf, output_size, attempted_on FROM archive_crf_attempts WHERE file_id = ?2 ORDER BY id",
+            )?;
+            let mut delete_attempts =
+                tx.prepare("DELETE FROM archive_crf_attempts WHERE file_id = ?1")?;
+            let mut delete_file =
+                tx.prepare("DELETE FROM archive_transcode_files WHERE id = ?1")?;
+            for (id, path) in archived {
+                if !encoder_rules::glob_matches(pattern, &path) {
+                    continue;
+                }
+                if exists.query_row([&path], |row| row.get(0))? {
+                    summary.conflicts.push(path.into());
+                    continue;
+                }
+                restore_file.execute([id])?;
+                let file_id = tx.last_insert_rowid();
+                restore_attempts.execute(params![file_id, id])?;
+                delete_attempts.execute([id])?;
+                delete_file.execute([id])?;
+                summary.restored += 1;
+            }
+        }
+        tx.commit()?;
+        Ok(summary)
     }
 
-    pub fn set_file_status(
-        &self,
-        rowid: i64,
-        status: TranscodeStatus,
-        error_message: Option<String>,
-    ) -> Result<()> {
-        info!("Setting file status for rowid {} to {:?}", rowid, status);
+    /// Files with a recorded encode time, to estimate the speed of future encodes.
+    pub fn encoded_files(&self) -> Result<Vec<TranscodeFile>> {
+        let connection = self.db.get()?;
+        let mut statement = connection.prepare(&format!(
+            "SELECT id, {FILE_COLUMNS} FROM transcode_files WHERE encode_seconds IS NOT NULL"
+        ))?;
+        let res = from_rows::<TranscodeFile>(statement.query([])?);
+        let rows: Result<_, serde_rusqlite::Error> = res.collect();
+        Ok(rows?)
+    }
+
+    /// Inserts files in chunks, committing each chunk separately. If a chunk fails,
+    /// its rows are retried one by one so that only the bad rows are skipped.
+    pub fn insert_batch(&self, files: &[NewTranscodeFile]) -> Result<InsertSummary> {
+        const CHUNK_SIZE: usize = 500;
+
+        info!("inserting batch of {} files", files.len());
+        let mut connection = self.db.get()?;
+        let now = Timestamp::now().as_second();
+        let chunks = files.len().div_ceil(CHUNK_SIZE);
+        let mut summary = InsertSummary::default();
+
+        for (index, chunk) in files.chunks(CHUNK_SIZE).enumerate() {
+            if chunks > 1 {
+                info!("inserting chunk {} of {chunks}", index + 1);
+            }
+            match insert_chunk(&mut connection, chunk, now, &|path| self.normalize(path)) {
+                Ok(inserted) => {
+                    summary.inserted += inserted;
+                    summary.existing += chunk.len() - inserted;
+                }
+                Err(e) => {
+                    warn!(
+                        "inserting chunk {} failed, retrying its files one by one: {e}",
+                        index + 1
+                    );
+                    for file in chunk {
+                        match insert_rows(&connection, std::slice::from_ref(file), now, &|path| {
+                            self.normalize(path)
+                        }) {
+                            Ok(1) => summary.inserted += 1,
+                            Ok(_) => summary.existing += 1,
+                            Err(e) => {
+                                warn!("skipping file {}: {e}", file.path);
+                                summary.failed.push(file.path.clone());
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(summary)
+    }
+
+    /// Records files that a scan skipped. Files that are already known, skipped,
+    /// archived or not, are left alone. Returns how many were inserted.
+    pub fn insert_skipped(&self, files: &[NewSkippedFile]) -> Result<usize> {
+        let mut connection = self.db.get()?;
+        let now = Timestamp::now().as_second();
+        let tx = connection.transaction()?;
+        let mut inserted = 0;
+        {
+            let mut statement = tx.prepare_cached("INSERT INTO transcode_files (path, status, skip_reason, created_on, updated_on, file_size, ffprobe_info) SELECT ?1, 'skipped', ?2, ?3, ?4, ?5, ?6 WHERE NOT EXISTS (SELECT 1 FROM archive_transcode_files WHERE path = ?1) ON CONFLICT (path) DO UPDATE SET skip_reason = excluded.skip_reason, updated_on = excluded.updated_on, file_size = excluded.file_size WHERE status = 'skipped'")?;
+            for file in files {
+                let json_info = serde_json::to_string(
+                    file.ffprobe_info.as_ref().unwrap_or(&FfProbe::default()),
+                )?;
+                inserted += statement.execute(params![
+                    self.normalize(&file.path).as_str(),
+                    file.reason.as_str(),
+                    now,
+                    now,
+                    file.file_size as i64,
+                    json_info
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(inserted)
+    }
+
+    /// When ffprobe last failed for each of the paths that it failed for, going
+    /// by the files recorded as skipped with `probe-failed`.
+    pub fn probe_failures(&self, paths: &[Utf8PathBuf]) -> Result<HashMap<Utf8PathBuf, Timestamp>> {
+        let connection = self.db.get()?;
+        let mut statement = connection.prepare_cached(
+            "SELECT updated_on FROM transcode_files WHERE path = ?1 AND status = 'skipped' AND skip_reason = 'probe-failed'",
+        )?;
+        let mut failures = HashMap::new();
+        for path in paths {
+            let failed_at: Option<i64> = statement
+                .query_row([self.normalize(path).as_str()], |row| row.get(0))
+                .optional()?;
+            if let Some(failed_at) = failed_at {
+                failures.insert(path.clone(), Timestamp::from_second(failed_at)?);
+            }
+        }
+        Ok(failures)
+    }
+
+    /// Removes the files matching the filter from the database, returning how many
+    /// were removed.
+    pub fn forget(&self, filter: &FileFilter) -> Result<usize> {
+        let mut connection = self.db.get()?;
+        let (where_clause, params) = filter.where_clause();
+        let tx = connection.transaction()?;
+        tx.execute(
+            &format!(
+                "DELETE FROM crf_attempts WHERE file_id IN (SELECT id FROM transcode_files {where_clause})"
+            ),
+            params_from_iter(params.iter()),
+        )?;
+        let removed = tx.execute(
+            &format!("DELETE FROM transcode_files {where_clause}"),
+            params_from_iter(params.iter()),
+        )?;
+        tx.commit()?;
+        Ok(removed)
+    }
+
+    /// Takes a queued file out of the queue, like a scan with `--record-skipped`
+    /// would have.
+    pub fn set_skipped(&self, id: i64, reason: ScanSkipReason) -> Result<()> {
+        info!("Skipping file {id}: {reason}");
+        let connection = self.db.get()?;
+        connection.execute(
+            "UPDATE transcode_files SET status = 'skipped', skip_reason = ?1, updated_on = ?2, claimed_by = NULL, claimed_at = NULL WHERE id = ?3",
+            params![reason.as_str(), Timestamp::now().as_second(), id],
+        )?;
+        Ok(())
+    }
+
+    pub fn set_file_status(
+        &self,
+        id: i64,
+        status: TranscodeStatus,
+        error_message: Option<String>,
+    ) -> Result<()> {
+        info!("Setting file status for file {} to {:?}", id, status);
+        let sanitized = error_message.map(|m| error_message::sanitize(&m, self.max_error_length));
+        let details = match sanitized.as_ref().and_then(|s| s.full_text.as_deref()) {
+            Some(full_text) if self.error_details => Some(error_message::compress(full_text)?),
+            _ => None,
+        };
         let connection = self.db.get()?;
         let now = Timestamp::now().as_second();
         connection.execute(
-            "UPDATE transcode_files SET status = ?1, updated_on = ?2, error_message = ?3 WHERE rowid = ?4",
-            params![status as i32, now, error_message, rowid],
+            "UPDATE transcode_files SET status = ?1, updated_on = ?2, error_message = ?3, error_details = ?4, source_changes = NULL, claimed_by = NULL, claimed_at = NULL WHERE id = ?5",
+            params![
+                status.as_str(),
+                now,
+                sanitized.map(|s| s.message),
+                details,
+                id
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The full text of a file's error message when the stored one was cut off.
+    pub fn error_details(&self, id: i64) -> Result<Option<String>> {
+        let connection = self.db.get()?;
+        let details: Option<Vec<u8>> = connection.query_row(
+            "SELECT error_details FROM transcode_files WHERE id = ?1",
+            params![id],
+            |row| row.get(0),
+        )?;
+        details.map(|d| error_message::decompress(&d)).transpose()
+    }
+
+    /// How many runs were started after `since`.
+    pub fn runs_started_since(&self, since: Timestamp) -> Result<u64> {
+        let connection = self.db.get()?;
+        let count: i64 = connection.query_row(
+            "SELECT COUNT(*) FROM runs WHERE started_on > ?1",
+            [since.as_second()],
+            |row| row.get(0),
+        )?;
+        Ok(count as u64)
+    }
+
+    /// Records the start of a transcode run, returning its id.
+    pub fn insert_run(&self, ffmpeg: &FfmpegVersion, sample: bool) -> Result<i64> {
+        let connection = self.db.get()?;
+        connection.execute(
+            "INSERT INTO runs (started_on, ffmpeg_version, libraries, sample) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                Timestamp::now().as_second(),
+                ffmpeg.version,
+                serde_json::to_string(&ffmpeg.libraries)?,
+                sample
+            ],
+        )?;
+        Ok(connection.last_insert_rowid())
+    }
+
+    pub fn get_run(&self, id: i64) -> Result<Option<Run>> {
+        let connection = self.db.get()?;
+        let mut statement = connection.prepare(
+            "SELECT id, started_on, ffmpeg_version, libraries, sample FROM runs WHERE id = ?1",
+        )?;
+        let res = from_rows::<Run>(statement.query([id])?);
+        let rows: Result<Vec<_>, serde_rusqlite::Error> = res.collect();
+        Ok(rows?.into_iter().next())
+    }
+
+    /// The run that started last.
+    pub fn latest_run(&self) -> Result<Option<Run>> {
+        let connection = self.db.get()?;
+        let mut statement = connection.prepare(
+            "SELECT id, started_on, ffmpeg_version, libraries, sample FROM runs ORDER BY id DESC LIMIT 1",
+        )?;
+        let res = from_rows::<Run>(statement.query([])?);
+        let rows: Result<Vec<_>, serde_rusqlite::Error> = res.collect();
+        Ok(rows?.into_iter().next())
+    }
+
+    /// Number and size of the files per status, only of the files last
+    /// transcoded by `run_id` if given.
+    pub fn status_counts(&self, run_id: Option<i64>) -> Result<Vec<StatusCount>> {
+        let connection = self.db.get()?;
+        let mut statement = connection.prepare(
+            "SELECT status, COUNT(*) AS files, COALESCE(SUM(file_size), 0) AS bytes FROM transcode_files WHERE ?1 IS NULL OR run_id = ?1 GROUP BY status ORDER BY status",
+        )?;
+        let res = from_rows::<StatusCount>(statement.query([run_id])?);
+        let rows: Result<Vec<_>, serde_rusqlite::Error> = res.collect();
+        Ok(rows?)
+    }
+
+    /// Bytes saved by the transcoded files, compared with the size of their last
+    /// encode.
+    pub fn bytes_saved(&self) -> Result<u64> {
+        let connection = self.db.get()?;
+        bytes_saved_in(&connection, "main")
+    }
+
+    /// The last result of every transcoded or failed file, archived ones
+    /// included, that finished at or after `since`, oldest first.
+    pub fn history(&self, since: Option<Timestamp>) -> Result<Vec<HistoryEntry>> {
+        let connection = self.db.get()?;
+        let mut statement = connection.prepare(&format!(
+            "SELECT * FROM ({} UNION ALL {}) WHERE ?1 IS NULL OR finished_on >= ?1 ORDER BY finished_on",
+            history_select("transcode_files", "crf_attempts"),
+            history_select("archive_transcode_files", "archive_crf_attempts"),
+        ))?;
+        let res = from_rows::<HistoryEntry>(statement.query([since.map(|t| t.as_second())])?);
+        let rows: Result<Vec<_>, serde_rusqlite::Error> = res.collect();
+        Ok(rows?)
+    }
+
+    /// Compares the files with an older copy of the database, matched by path.
+    /// The copy is only read. Archived files count as still being there.
+    pub fn diff_snapshot(&self, snapshot: &Utf8Path) -> Result<SnapshotDiff> {
+        if !snapshot.is_file() {
+            bail!("the snapshot {snapshot} does not exist");
+        }
+        let connection = self.db.get()?;
+        connection.execute("ATTACH DATABASE ?1 AS old", [read_only_uri(snapshot)])?;
+        let diff = diff_attached(&connection);
+        // the connection goes back to the pool
+        connection.execute("DETACH DATABASE old", [])?;
+        diff
+    }
+
+    /// Stamps the files with a library name, replacing the library they had before.
+    pub fn set_library(&self, paths: &[Utf8PathBuf], library: &str) -> Result<usize> {
+        let mut connection = self.db.get()?;
+        let tx = connection.transaction()?;
+        let mut updated = 0;
+        {
+            let mut statement =
+                tx.prepare_cached("UPDATE transcode_files SET library = ?1 WHERE path = ?2")?;
+            for path in paths {
+                updated += statement.execute(params![library, self.normalize(path).as_str()])?;
+            }
+        }
+        tx.commit()?;
+        Ok(updated)
+    }
+
+    /// Removes the rows in `remove` with their attempts and stores the row `keep`
+    /// under `path`.
+    pub fn merge_duplicates(&self, keep: i64, path: &Utf8Path, remove: &[i64]) -> Result<()> {
+        let mut connection = self.db.get()?;
+        let tx = connection.transaction()?;
+        {
+            let mut delete_attempts = tx.prepare("DELETE FROM crf_attempts WHERE file_id = ?1")?;
+            let mut delete_file = tx.prepare("DELETE FROM transcode_files WHERE id = ?1")?;
+            for id in remove {
+                delete_attempts.execute([id])?;
+                delete_file.execute([id])?;
+            }
+        }
+        tx.execute(
+            "UPDATE transcode_files SET path = ?1 WHERE id = ?2",
+            params![path.as_str(), keep],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn set_encode_time(&self, id: i64, seconds: f64) -> Result<()> {
+        let connection = self.db.get()?;
+        connection.execute(
+            "UPDATE transcode_files SET encode_seconds = ?1 WHERE id = ?2",
+            params![seconds, id],
+        )?;
+        Ok(())
+    }
+
+    pub fn set_resource_usage(&self, id: i64, usage: &ResourceUsage) -> Result<()> {
+        let connection = self.db.get()?;
+        connection.execute(
+            "UPDATE transcode_files SET cpu_seconds = ?1, peak_rss = ?2, cpu_percent = ?3 WHERE id = ?4",
+            params![
+                usage.cpu_time.as_secs_f64(),
+                usage.peak_rss as i64,
+                usage.cpu_percent(),
+                id
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn set_read_rate(&self, id: i64, mode: IoMode, rate: f64) -> Result<()> {
+        let connection = self.db.get()?;
+        connection.execute(
+            "UPDATE transcode_files SET io_mode = ?1, read_rate = ?2 WHERE id = ?3",
+            params![mode.as_str(), rate, id],
+        )?;
+        Ok(())
+    }
+
+    pub fn set_encoder_rule(&self, id: i64, rule: Option<&str>) -> Result<()> {
+        let connection = self.db.get()?;
+        connection.execute(
+            "UPDATE transcode_files SET encoder_rule = ?1 WHERE id = ?2",
+            params![rule, id],
+        )?;
+        Ok(())
+    }
+
+    pub fn set_encode_options(&self, id: i64, options: &ResolvedOptions) -> Result<()> {
+        let connection = self.db.get()?;
+        connection.execute(
+            "UPDATE transcode_files SET encode_options = ?1 WHERE id = ?2",
+            params![options.to_json()?, id],
+        )?;
+        Ok(())
+    }
+
+    pub fn insert_crf_attempt(&self, id: i64, crf: u8, output_size: u64) -> Result<()> {
+        let connection = self.db.get()?;
+        connection.execute(
+            "INSERT INTO crf_attempts (file_id, crf, output_size, attempted_on) VALUES (?1, ?2, ?3, ?4)",
+            params![id, crf, output_size as i64, Timestamp::now().as_second()],
+        )?;
+        Ok(())
+    }
+
+    /// The attempts to encode a file, oldest first.
+    pub fn crf_attempts(&self, id: i64) -> Result<Vec<CrfAttempt>> {
+        let connection = self.db.get()?;
+        let mut statement = connection.prepare(
+            "SELECT crf, output_size, attempted_on FROM crf_attempts WHERE file_id = ?1 ORDER BY id",
+        )?;
+        let res = from_rows::<CrfAttempt>(statement.query([id])?);
+        let rows: Result<_, serde_rusqlite::Error> = res.collect();
+        Ok(rows?)
+    }
+
+    pub fn set_output_path(&self, id: i64, path: &Utf8Path) -> Result<()> {
+        let connection = self.db.get()?;
+        connection.execute(
+            "UPDATE transcode_files SET output_path = ?1 WHERE id = ?2",
+            params![path.as_str(), id],
+        )?;
+        Ok(())
+    }
+
+    /// Records how a file ended, with its output when it was transcoded.
+    pub fn record_result(&self, result: &FileResult) -> Result<()> {
+        if let Some(output_path) = &result.output_path {
+            self.set_output_path(result.id, output_path)?;
+        }
+        self.set_file_status(result.id, result.status, result.error_message.clone())?;
+        if !result.source_changes.is_empty() {
+            self.set_source_changes(result.id, &result.source_changes)?;
+        }
+        if !result.renditions.is_empty() {
+            self.set_renditions(result.id, &result.renditions)?;
+        }
+        Ok(())
+    }
+
+    /// Records the outputs of a file's renditions.
+    pub fn set_renditions(&self, id: i64, renditions: &[RenditionOutput]) -> Result<()> {
+        let connection = self.db.get()?;
+        connection.execute(
+            "UPDATE transcode_files SET renditions = ?1 WHERE id = ?2",
+            params![serde_json::to_string(renditions)?, id],
+        )?;
+        Ok(())
+    }
+
+    /// Records what changed in a file's source since it was scanned.
+    pub fn set_source_changes(&self, id: i64, changes: &[SourceChange]) -> Result<()> {
+        let connection = self.db.get()?;
+        connection.execute(
+            "UPDATE transcode_files SET source_changes = ?1 WHERE id = ?2",
+            params![serde_json::to_string(changes)?, id],
+        )?;
+        Ok(())
+    }
+
+    /// Records when the sources were last modified as a scan saw them, for the
+    /// files that were queued without it. Returns how many were updated.
+    pub fn set_source_modified(&self, files: &[(Utf8PathBuf, Timestamp)]) -> Result<usize> {
+        let mut connection = self.db.get()?;
+        let tx = connection.transaction()?;
+        let mut updated = 0;
+        {
+            let mut statement = tx.prepare_cached(
+                "UPDATE transcode_files SET source_modified = ?1 WHERE path = ?2 AND source_modified IS NULL",
+            )?;
+            for (path, modified) in files {
+                updated += statement
+                    .execute(params![modified.as_second(), self.normalize(path).as_str()])?;
+            }
+        }
+        tx.commit()?;
+        Ok(updated)
+    }
+
+    /// Records the result of verifying a file's output now.
+    pub fn set_verification(&self, id: i64, error: Option<&str>) -> Result<()> {
+        let connection = self.db.get()?;
+        connection.execute(
+            "UPDATE transcode_files SET verified_at = ?1, verify_error = ?2 WHERE id = ?3",
+            params![Timestamp::now().as_second(), error, id],
+        )?;
+        Ok(())
+    }
+
+    /// Records whether the copied audio of a file's output matched the source.
+    pub fn set_audio_hash(&self, id: i64, result: AudioHash) -> Result<()> {
+        let connection = self.db.get()?;
+        connection.execute(
+            "UPDATE transcode_files SET audio_hash = ?1 WHERE id = ?2",
+            params![result.as_str(), id],
+        )?;
+        Ok(())
+    }
+
+    /// Files whose output failed the last verification or whose audio differs
+    /// from the source.
+    pub fn verify_failed(&self) -> Result<Vec<TranscodeFile>> {
+        let connection = self.db.get()?;
+        let mut statement = connection.prepare(&format!(
+            "SELECT id, {FILE_COLUMNS} FROM transcode_files WHERE verify_error IS NOT NULL OR audio_hash = 'mismatch'"
+        ))?;
+        let res = from_rows::<TranscodeFile>(statement.query([])?);
+        let rows: Result<_, serde_rusqlite::Error> = res.collect();
+        Ok(rows?)
+    }
+
+    /// Pins the files whose path matches the glob pattern, so that they are never
+    /// transcoded. A note replaces the one stored with the file. Returns how many
+    /// files were pinned.
+    pub fn pin(&self, pattern: &str, note: Option<&str>) -> Result<usize> {
+        self.set_pinned(pattern, true, note)
+    }
+
+    /// Unpins the files whose path matches the glob pattern and removes their
+    /// notes. Returns how many files were unpinned.
+    pub fn unpin(&self, pattern: &str) -> Result<usize> {
+        self.set_pinned(pattern, false, None)
+    }
+
+    fn set_pinned(&self, pattern: &str, pinned: bool, note: Option<&str>) -> Result<usize> {
+        let mut connection = self.db.get()?;
+        let tx = connection.transaction()?;
+        let files: Vec<(i64, String)> = {
+            // pinning again updates the note, unpinning only touches pinned files
+            let mut statement =
+                tx.prepare("SELECT id, path FROM transcode_files WHERE pinned OR ?1")?;
+            statement
+                .query_map([pinned], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<_, _>>()?
+        };
+        let mut changed = 0;
+        {
+            let mut update = tx.prepare(if pinned {
+                "UPDATE transcode_files SET pinned = 1, note = COALESCE(?2, note) WHERE id = ?1"
+            } else {
+                "UPDATE transcode_files SET pinned = 0, note = NULL WHERE id = ?1"
+            })?;
+            for (id, path) in files {
+                if !encoder_rules::glob_matches(pattern, &path) {
+                    continue;
+                }
+                if pinned {
+                    update.execute(params![id, note])?;
+                } else {
+                    update.execute([id])?;
+                }
+                changed += 1;
+            }
+        }
+        tx.commit()?;
+        Ok(changed)
+    }
+
+    /// Puts a file back into the queue, forgetting its output and verification.
+    pub fn requeue(&self, id: i64) -> Result<()> {
+        let connection = self.db.get()?;
+        connection.execute(
+            "UPDATE transcode_files SET status = 'pending', error_message = NULL, error_details = NULL, updated_on = ?1, output_path = NULL, verified_at = NULL, verify_error = NULL, audio_hash = NULL, source_changes = NULL, renditions = NULL WHERE id = ?2",
+            params![Timestamp::now().as_second(), id],
+        )?;
+        Ok(())
+    }
+
+    /// Marks an encoded file as rejected and forgets its output. Returns whether
+    /// the file was still encoded.
+    pub fn reject(&self, id: i64) -> Result<bool> {
+        let connection = self.db.get()?;
+        let changed = connection.execute(
+            "UPDATE transcode_files SET status = 'rejected', updated_on = ?1, output_path = NULL WHERE id = ?2 AND status = 'encoded'",
+            params![Timestamp::now().as_second(), id],
+        )?;
+        Ok(changed > 0)
+    }
+
+    pub fn set_file_run(&self, id: i64, run_id: i64) -> Result<()> {
+        let connection = self.db.get()?;
+        connection.execute(
+            "UPDATE transcode_files SET run_id = ?1 WHERE id = ?2",
+            params![run_id, id],
+        )?;
+        Ok(())
+    }
+
+    /// Claims a file for a worker if its status is still `expected`, i.e. no other
+    /// worker claimed or finished it in the meantime. Returns whether the claim succeeded.
+    pub fn claim(&self, id: i64, worker: &str, expected: TranscodeStatus) -> Result<bool> {
+        let connection = self.db.get()?;
+        let claimed = connection.execute(
+            "UPDATE transcode_files SET status = ?1, claimed_by = ?2, claimed_at = unixepoch() WHERE id = ?3 AND status = ?4",
+            params![
+                TranscodeStatus::InProgress.as_str(),
+                worker,
+                id,
+                expected.as_str()
+            ],
+        )?;
+        Ok(claimed == 1)
+    }
+
+    /// Refreshes a claim so that it isn't considered stale.
+    pub fn touch_claim(&self, id: i64, worker: &str) -> Result<()> {
+        let connection = self.db.get()?;
+        connection.execute(
+            "UPDATE transcode_files SET claimed_at = unixepoch() WHERE id = ?1 AND claimed_by = ?2",
+            params![id, worker],
+        )?;
+        Ok(())
+    }
+
+    /// Gives up a claim on a file that is still in progress, returning it to the queue.
+    pub fn release_claim(&self, id: i64, worker: &str) -> Result<()> {
+        let connection = self.db.get()?;
+        connection.execute(
+            "UPDATE transcode_files SET status = ?1, claimed_by = NULL, claimed_at = NULL WHERE id = ?2 AND claimed_by = ?3 AND status = ?4",
+            params![
+                TranscodeStatus::Pending.as_str(),
+                id,
+                worker,
+                TranscodeStatus::InProgress.as_str()
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Returns claims that weren't refreshed for longer than `max_age` to the queue.
+    /// The age is computed with the database's clock only, so clock differences
+    /// between machines don't matter.
+    pub fn reclaim_stale(&self, max_age: SignedDuration) -> Result<usize> {
+        let connection = self.db.get()?;
+        let reclaimed = connection.execute(
+            "UPDATE transcode_files SET status = ?1, claimed_by = NULL, claimed_at = NULL WHERE status = ?2 AND claimed_at <= unixepoch() - ?3",
+            params![
+                TranscodeStatus::Pending.as_str(),
+                TranscodeStatus::InProgress.as_str(),
+                max_age.as_secs()
+            ],
         )?;
+        Ok(reclaimed)
+    }
+
+    /// Files that are claimed by a worker.
+    pub fn claims(&self) -> Result<Vec<TranscodeFile>> {
+        self.list_filtered(
+            &FileFilter {
+                status: Some(TranscodeStatus::InProgress),
+                ..Default::default()
+            },
+            None,
+        )
+    }
+
+    /// The current time according to the database, used to compute claim ages.
+    pub fn now(&self) -> Result<Timestamp> {
+        let connection = self.db.get()?;
+        let seconds: i64 = connection.query_row("SELECT unixepoch()", [], |row| row.get(0))?;
+        Ok(Timestamp::from_second(seconds)?)
+    }
+
+    pub fn set_thumbnail(&self, id: i64, path: &Utf8Path) -> Result<()> {
+        let connection = self.db.get()?;
+        connection.execute(
+            "UPDATE transcode_files SET thumbnail_path = ?1 WHERE id = ?2",
+            params![path.as_str(), id],
+        )?;
+        Ok(())
+    }
+
+    pub fn get(&self, id: i64) -> Result<Option<TranscodeFile>> {
+        let connection = self.db.get()?;
+        let mut statement = connection.prepare(&format!(
+            "SELECT id, {FILE_COLUMNS} FROM transcode_files WHERE id = ?1"
+        ))?;
+        let res = from_rows::<TranscodeFile>(statement.query([id])?);
+        let rows: Result<Vec<_>, serde_rusqlite::Error> = res.collect();
+        Ok(rows?.into_iter().next())
+    }
+
+    pub fn get_by_path(&self, path: &Utf8Path) -> Result<Option<TranscodeFile>> {
+        let connection = self.db.get()?;
+        let mut statement = connection.prepare(&format!(
+            "SELECT id, {FILE_COLUMNS} FROM transcode_files WHERE path = ?1"
+        ))?;
+        let path = self.normalize(path);
+        let res = from_rows::<TranscodeFile>(statement.query([path.as_str()])?);
+        let rows: Result<Vec<_>, serde_rusqlite::Error> = res.collect();
+        Ok(rows?.into_iter().next())
+    }
+
+    /// Takes the instance lock. If another instance holds it, returns that holder instead.
+    pub fn take_lock(&self, holder: &LockHolder) -> Result<Option<LockHolder>> {
+        let connection = self.db.get()?;
+        let inserted = connection.execute(
+            "INSERT OR IGNORE INTO locks (id, pid, hostname, started_on) VALUES (1, ?1, ?2, ?3)",
+            params![holder.pid, holder.hostname, holder.started_on.as_second()],
+        )?;
+        if inserted == 1 {
+            Ok(None)
+        } else {
+            self.lock_holder()
+        }
+    }
+
+    pub fn lock_holder(&self) -> Result<Option<LockHolder>> {
+        let connection = self.db.get()?;
+        let mut statement = connection.prepare("SELECT pid, hostname, started_on FROM locks")?;
+        let res = from_rows::<LockHolder>(statement.query([])?);
+        let rows: Result<Vec<_>, serde_rusqlite::Error> = res.collect();
+        Ok(rows?.into_iter().next())
+    }
+
+    /// Releases the lock if it's held by `holder`.
+    pub fn release_lock(&self, holder: &LockHolder) -> Result<()> {
+        let connection = self.db.get()?;
+        connection.execute(
+            "DELETE FROM locks WHERE pid = ?1 AND hostname = ?2",
+            params![holder.pid, holder.hostname],
+        )?;
+        Ok(())
+    }
+
+    /// Removes the lock regardless of who holds it.
+    pub fn clear_lock(&self) -> Result<()> {
+        let connection = self.db.get()?;
+        connection.execute("DELETE FROM locks", ())?;
+        Ok(())
+    }
+
+    /// Stores fresh ffprobe info and modification time for a row, optionally
+    /// resetting it to `Pending`.
+    pub fn update_probe(
+        &self,
+        id: i64,
+        file_size: u64,
+        ffprobe_info: &FfProbe,
+        modified: Option<Timestamp>,
+        reset_status: bool,
+    ) -> Result<()> {
+        let connection = self.db.get()?;
+        let now = Timestamp::now().as_second();
+        let json_info = serde_json::to_string(ffprobe_info)?;
+        connection.execute(
+            "UPDATE transcode_files SET file_size = ?1, ffprobe_info = ?2, updated_on = ?3, source_modified = ?4 WHERE id = ?5",
+            params![
+                file_size as i64,
+                json_info,
+                now,
+                modified.map(|m| m.as_second()),
+                id
+            ],
+        )?;
+        if reset_status {
+            connection.execute(
+                "UPDATE transcode_files SET status = ?1, error_message = NULL, error_details = NULL, source_changes = NULL WHERE id = ?2",
+                params![TranscodeStatus::Pending.as_str(), id],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Size of the database file in bytes.
+    fn size(connection: &Connection) -> Result<u64> {
+        let pages: u64 = connection.query_row("PRAGMA page_count", [], |row| row.get(0))?;
+        let page_size: u64 = connection.query_row("PRAGMA page_size", [], |row| row.get(0))?;
+        Ok(pages * page_size)
+    }
+
+    /// Strips the fields that are never read from the stored ffprobe info,
+    /// compresses it or stores it as plain JSON again, and vacuums the database.
+    pub fn compact(&self, compress: bool) -> Result<CompactSummary> {
+        let mut connection = self.db.get()?;
+        let mut summary = CompactSummary {
+            size_before: Self::size(&connection)?,
+            ..Default::default()
+        };
+        let rows: Vec<(i64, ProbeJson)> = {
+            let mut statement = connection.prepare(
+                "SELECT id, ffprobe_info FROM transcode_files WHERE ffprobe_info IS NOT NULL",
+            )?;
+            from_rows::<(i64, ProbeJson)>(statement.query([])?).collect::<Result<_, _>>()?
+        };
+        let tx = connection.transaction()?;
+        {
+            let mut statement =
+                tx.prepare("UPDATE transcode_files SET ffprobe_info = ?1 WHERE id = ?2")?;
+            for (id, info) in rows {
+                let Some(json) = info
+                    .json()
+                    .ok()
+                    .and_then(|json| serde_json::from_str::<FfProbe>(&json).ok())
+                    .and_then(|probe| probe.to_compact_json().ok())
+                else {
+                    warn!("could not read the ffprobe info of file {id}, leaving it as it is");
+                    summary.unreadable += 1;
+                    continue;
+                };
+                let info = if compress {
+                    ProbeJson::compress(&json)?
+                } else {
+                    ProbeJson::from(json)
+                };
+                statement.execute(params![info.to_sql(), id])?;
+                summary.rewritten += 1;
+            }
+        }
+        tx.commit()?;
+        connection.execute_batch("VACUUM")?;
+        summary.size_after = Self::size(&connection)?;
+        Ok(summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Reverse;
+
+    use super::*;
+    use crate::ffprobe::{Format, ffprobe};
+    use crate::testsupport::{self, Sample};
+
+    #[test]
+    fn test_insert_row() -> Result<()> {
+        let db = Database::in_memory()?;
+
+        db.insert(NewTranscodeFile {
+            path: "/stuff/1.mp4".into(),
+            file_size: 696969,
+            ffprobe_info: FfProbe::default(),
+        })?;
+
+        let rows = db.list()?;
+        assert_eq!(1, rows.len());
+        assert_eq!(696969, rows[0].file_size);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_batch() -> Result<()> {
+        let db = Database::in_memory()?;
+
+        let files: Vec<_> = (0..100)
+            .map(|i| NewTranscodeFile {
+                path: format!("/stuff/{i}.mp4").into(),
+                file_size: 69 * i,
+                ffprobe_info: FfProbe::default(),
+            })
+            .collect();
+
+        let summary = db.insert_batch(&files)?;
+        assert_eq!(100, summary.inserted);
+        let summary = db.insert_batch(&files)?;
+        assert_eq!(0, summary.inserted);
+        assert_eq!(100, summary.existing);
+
+        let actual = db.list()?;
+        assert_eq!(100, actual.len());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_batch_skips_bad_rows() -> Result<()> {
+        let db = Database::in_memory()?;
+        db.db.get()?.execute_batch(
+            "CREATE TRIGGER poison BEFORE INSERT ON transcode_files WHEN NEW.path = '/stuff/poison.mp4'
+             BEGIN SELECT RAISE(ABORT, 'poisoned'); END",
+        )?;
+
+        let mut files: Vec<_> = (0..1200)
+            .map(|i| NewTranscodeFile {
+                path: format!("/stuff/{i}.mp4").into(),
+                file_size: i,
+                ffprobe_info: FfProbe::default(),
+            })
+            .collect();
+        files.insert(
+            700,
+            NewTranscodeFile {
+                path: "/stuff/poison.mp4".into(),
+                file_size: 1,
+                ffprobe_info: FfProbe::default(),
+            },
+        );
+
+        let summary = db.insert_batch(&files)?;
+        assert_eq!(1200, summary.inserted);
+        assert_eq!(vec![Utf8PathBuf::from("/stuff/poison.mp4")], summary.failed);
+        assert_eq!(1200, db.list()?.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_duplicate_path() -> Result<()> {
+        let db = Database::in_memory()?;
+
+        db.insert(NewTranscodeFile {
+            path: "/1.mp4".into(),
+            file_size: 5,
+            ffprobe_info: FfProbe::default(),
+        })?;
+
+        let error = db.insert(NewTranscodeFile {
+            path: "/1.mp4".into(),
+            file_size: 5,
+            ffprobe_info: FfProbe::default(),
+        });
+
+        assert!(error.is_err());
+        let err = error.unwrap_err();
+        assert!(err.to_string().contains("UNIQUE constraint failed"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_set_file_status() -> Result<()> {
+        let db = Database::in_memory()?;
+        db.insert(NewTranscodeFile {
+            path: "/1.mp4".into(),
+            file_size: 5,
+            ffprobe_info: FfProbe::default(),
+        })?;
+        let id = db.list()?[0].id;
+
+        db.set_file_status(id, TranscodeStatus::Error, Some("oops".into()))?;
+        let rows = db.list()?;
+        assert_eq!(TranscodeStatus::Error, rows[0].status);
+        assert_eq!(Some("oops"), rows[0].error_message.as_deref());
+
+        db.update_probe(id, 10, &FfProbe::default(), None, true)?;
+        let rows = db.list()?;
+        assert_eq!(TranscodeStatus::Pending, rows[0].status);
+        assert_eq!(10, rows[0].file_size);
+        assert!(rows[0].error_message.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_long_error_message() -> Result<()> {
+        let db = Database::in_memory()?.with_max_error_length(25);
+        db.insert(NewTranscodeFile {
+            path: "/1.mp4".into(),
+            file_size: 5,
+            ffprobe_info: FfProbe::default(),
+        })?;
+        let id = db.list()?[0].id;
+
+        let output = format!("{}\n\x1b[31mConversion failed!\x1b[0m", "x".repeat(50));
+        db.set_file_status(id, TranscodeStatus::Error, Some(output))?;
+        let message = db.list()?[0].error_message.clone().unwrap();
+        assert_eq!("[...] \nConversion failed!", message);
+        let full_text = db.error_details(id)?.unwrap();
+        assert_eq!(format!("{}\nConversion failed!", "x".repeat(50)), full_text);
+
+        db.set_file_status(id, TranscodeStatus::Error, Some("short".into()))?;
+        assert_eq!(None, db.error_details(id)?);
+
+        // with --log-dir the full text is only in the log
+        let db = db.with_error_details(false);
+        let output = format!("{}\nConversion failed!", "x".repeat(50));
+        db.set_file_status(id, TranscodeStatus::Error, Some(output))?;
+        assert_eq!(
+            "[...] \nConversion failed!",
+            db.list()?[0].error_message.clone().unwrap()
+        );
+        assert_eq!(None, db.error_details(id)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sanitize_migration() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let path = Utf8Path::from_path(tempdir.path()).unwrap().join("test.db");
+        let db = Database::open(&path)?;
+        db.insert(NewTranscodeFile {
+            path: "/1.mp4".into(),
+            file_size: 5,
+            ffprobe_info: FfProbe::default(),
+        })?;
+        let output = format!("\x1b[1m{}\x1b[0m\nConversion failed!", "x".repeat(5000));
+        {
+            // a database from before the migration, with an unsanitized message
+            let connection = db.db.get()?;
+            connection.execute_batch("ALTER TABLE transcode_files DROP COLUMN error_details")?;
+            connection.execute("UPDATE transcode_files SET error_message = ?1", [&output])?;
+            connection.execute_batch(MIGRATIONS[8])?;
+        }
+
+        let file = db.list()?.remove(0);
+        let message = file.error_message.unwrap();
+        assert_eq!(DEFAULT_MAX_LENGTH, message.chars().count());
+        assert!(!message.contains('\x1b'));
+        assert!(message.ends_with("Conversion failed!"));
+        let full_text = db.error_details(file.id)?.unwrap();
+        assert_eq!(5000 + "\nConversion failed!".len(), full_text.len());
+        Ok(())
+    }
+
+    /// "Café" composed and as "e" with a combining accent.
+    const NFC_PATH: &str = "/nonexistent/Caf\u{e9}.mkv";
+    const NFD_PATH: &str = "/nonexistent/Cafe\u{301}.mkv";
+
+    #[test]
+    fn test_unicode_normalization() -> Result<()> {
+        let db = Database::in_memory()?;
+        let file = |path: &str| NewTranscodeFile {
+            path: path.into(),
+            file_size: 5,
+            ffprobe_info: FfProbe::default(),
+        };
+        db.insert(file(NFD_PATH))?;
+        let summary = db.insert_batch(&[file(NFC_PATH), file(NFD_PATH)])?;
+        assert_eq!(0, summary.inserted);
+        assert_eq!(2, summary.existing);
+
+        let rows = db.list()?;
+        assert_eq!(1, rows.len());
+        assert_eq!(NFC_PATH, rows[0].path);
+        for path in [NFC_PATH, NFD_PATH] {
+            let found = db.get_by_path(path.into())?.unwrap();
+            assert_eq!(rows[0].id, found.id);
+        }
+        assert_eq!(1, db.set_library(&[NFD_PATH.into()], "films")?);
+
+        let filter = FileFilter {
+            path_contains: Some("Cafe\u{301}".into()),
+            ..Default::default()
+        };
+        assert_eq!(1, db.list_filtered(&filter, None)?.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_paths_migration() -> Result<()> {
+        let db = Database::in_memory()?;
+        let connection = db.db.get()?;
+        // rows from before paths were normalized
+        for (path, updated_on) in [
+            (NFC_PATH, 10),
+            (NFD_PATH, 20),
+            ("/nonexistent/Cafe\u{301}2.mkv", 5),
+        ] {
+            connection.execute(
+                "INSERT INTO transcode_files (path, created_on, updated_on, file_size, ffprobe_info) VALUES (?1, 0, ?2, 5, '{}')",
+                params![path, updated_on],
+            )?;
+        }
+        let old = connection.query_row(
+            "SELECT id FROM transcode_files WHERE path = ?1",
+            [NFC_PATH],
+            |row| row.get::<_, i64>(0),
+        )?;
+        connection.execute(
+            "INSERT INTO crf_attempts (file_id, crf, output_size, attempted_on) VALUES (?1, 24, 1, 0)",
+            [old],
+        )?;
+        drop(connection);
+        let migration = MIGRATIONS
+            .iter()
+            .find(|m| m.contains("normalize_path"))
+            .unwrap();
+        db.db.get()?.execute_batch(migration)?;
+
+        let rows = db.list()?;
+        let paths: Vec<_> = rows.iter().map(|r| r.path.as_str()).collect();
+        assert_eq!(vec![NFC_PATH, "/nonexistent/Caf\u{e9}2.mkv"], paths);
+        // the row that was updated last is kept
+        assert_eq!(20, rows[0].updated_on.as_second());
+        assert!(db.crf_attempts(old)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_ids_migration() -> Result<()> {
+        let db = Database::in_memory()?;
+        {
+            // a table from before the migration, whose rows only had a rowid
+            let connection = db.db.get()?;
+            connection.execute_batch(&format!(
+                "CREATE TABLE old_files AS SELECT {ARCHIVED_COLUMNS} FROM transcode_files WHERE 0;
+                 DROP TABLE transcode_files;
+                 ALTER TABLE old_files RENAME TO transcode_files;"
+            ))?;
+            for (rowid, path) in [(3, "/a.mkv"), (7, "/b.mkv")] {
+                connection.execute(
+                    "INSERT INTO transcode_files (rowid, path, status, created_on, updated_on, file_size, ffprobe_info, pinned) VALUES (?1, ?2, 'pending', 0, 0, 5, '{}', 0)",
+                    params![rowid, path],
+                )?;
+            }
+            connection.execute(
+                "INSERT INTO crf_attempts (file_id, crf, output_size, attempted_on) VALUES (7, 24, 1, 0)",
+                [],
+            )?;
+            connection.execute_batch(MIGRATIONS[18])?;
+            // the columns of later migrations that the rebuilt table doesn't have yet
+            connection.execute_batch(
+                "ALTER TABLE transcode_files ADD COLUMN source_modified BIGINT;
+                 ALTER TABLE transcode_files ADD COLUMN source_changes VARCHAR;
+                 ALTER TABLE transcode_files ADD COLUMN renditions VARCHAR;",
+            )?;
+        }
+
+        // the ids are the rowids, the attempts still belong to their file
+        assert_eq!("/a.mkv", db.get(3)?.unwrap().path);
+        assert_eq!("/b.mkv", db.get(7)?.unwrap().path);
+        assert_eq!(1, db.crf_attempts(7)?.len());
+        db.insert(NewTranscodeFile {
+            path: "/c.mkv".into(),
+            file_size: 5,
+            ffprobe_info: FfProbe::default(),
+        })?;
+        assert_eq!(8, db.get_by_path("/c.mkv".into())?.unwrap().id);
+        Ok(())
+    }
+
+    #[test]
+    fn test_ids_survive_vacuum() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let path = Utf8Path::from_path(tempdir.path())
+            .unwrap()
+            .join("transcoder.db");
+        let db = Database::open(&path)?;
+        let files: Vec<_> = (0..5)
+            .map(|index| NewTranscodeFile {
+                path: format!("/videos/{index}.mkv").into(),
+                file_size: 1000 + index,
+                ffprobe_info: FfProbe::default(),
+            })
+            .collect();
+        db.insert_batch(&files)?;
+        let ids = |db: &Database| -> Result<Vec<(Utf8PathBuf, i64)>> {
+            let mut ids: Vec<_> = db.list()?.into_iter().map(|f| (f.path, f.id)).collect();
+            ids.sort();
+            Ok(ids)
+        };
+        // gaps at the start and the end, which VACUUM closes for rowids
+        let forgotten = |name: &str| FileFilter {
+            path_contains: Some(name.into()),
+            ..Default::default()
+        };
+        db.forget(&forgotten("/0.mkv"))?;
+        db.forget(&forgotten("/4.mkv"))?;
+        let before = ids(&db)?;
+        assert_eq!(3, before.len());
+
+        db.compact(false)?;
+        drop(db);
+        let db = Database::open(&path)?;
+        assert_eq!(before, ids(&db)?);
+        // the id of a forgotten file isn't given to a new one
+        db.insert(NewTranscodeFile {
+            path: "/videos/new.mkv".into(),
+            file_size: 5,
+            ffprobe_info: FfProbe::default(),
+        })?;
+        let new = db.get_by_path("/videos/new.mkv".into())?.unwrap().id;
+        assert!(before.iter().all(|(_, id)| new > *id + 1), "{new}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_ids_survive_archive() -> Result<()> {
+        let db = Database::in_memory()?;
+        for path in ["/videos/a.mkv", "/videos/b.mkv", "/videos/c.mkv"] {
+            db.insert(NewTranscodeFile {
+                path: path.into(),
+                file_size: 1000,
+                ffprobe_info: FfProbe::default(),
+            })?;
+        }
+        let id = |path: &str| -> Result<i64> { Ok(db.get_by_path(path.into())?.unwrap().id) };
+        let a = id("/videos/a.mkv")?;
+        db.insert_crf_attempt(a, 24, 900)?;
+        db.set_file_status(a, TranscodeStatus::Success, None)?;
+        db.db
+            .get()?
+            .execute("UPDATE transcode_files SET updated_on = 0", [])?;
+
+        assert_eq!(1, db.archive(SignedDuration::from_hours(24))?);
+        let archived = db.list_archived(&FileFilter::default())?;
+        assert_eq!(a, archived[0].id);
+        // files added in the meantime don't take its id
+        db.insert(NewTranscodeFile {
+            path: "/videos/d.mkv".into(),
+            file_size: 1000,
+            ffprobe_info: FfProbe::default(),
+        })?;
+        assert_ne!(a, id("/videos/d.mkv")?);
+
+        assert_eq!(1, db.unarchive("/videos/*")?.restored);
+        assert_eq!(a, id("/videos/a.mkv")?);
+        assert_eq!(1, db.crf_attempts(a)?.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_renditions() -> Result<()> {
+        let db = Database::in_memory()?;
+        db.insert(NewTranscodeFile {
+            path: "/videos/a.mkv".into(),
+            file_size: 1000,
+            ffprobe_info: FfProbe::default(),
+        })?;
+        let id = db.get_by_path("/videos/a.mkv".into())?.unwrap().id;
+        let renditions = vec![
+            RenditionOutput {
+                name: "archive".into(),
+                output_path: "/videos/a_archive.mp4".into(),
+                output_size: 600,
+            },
+            RenditionOutput {
+                name: "share".into(),
+                output_path: "/videos/a_share.mp4".into(),
+                output_size: 100,
+            },
+        ];
+        db.record_result(&FileResult {
+            renditions: renditions.clone(),
+            ..FileResult::transcoded(id, "/videos/a.mkv".into(), "/videos/a_archive.mp4".into())
+        })?;
+        let file = db.get(id)?.unwrap();
+        assert_eq!(Some("/videos/a_archive.mp4".into()), file.output_path);
+        assert_eq!(renditions, file.renditions()?);
+
+        // a result without renditions leaves them alone, a requeue forgets them
+        db.record_result(&FileResult::transcoded(
+            id,
+            "/videos/a.mkv".into(),
+            "/videos/a_archive.mp4".into(),
+        ))?;
+        assert_eq!(renditions, db.get(id)?.unwrap().renditions()?);
+        db.requeue(id)?;
+        assert!(db.get(id)?.unwrap().renditions()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_source_changes() -> Result<()> {
+        let db = Database::in_memory()?;
+        db.insert(NewTranscodeFile {
+            path: "/videos/a.mkv".into(),
+            file_size: 1000,
+            ffprobe_info: FfProbe::default(),
+        })?;
+        let id = db.get_by_path("/videos/a.mkv".into())?.unwrap().id;
+        let scanned = Timestamp::from_second(100)?;
+        let files = [("/videos/a.mkv".into(), scanned)];
+        assert_eq!(1, db.set_source_modified(&files)?);
+        // a later scan doesn't overwrite what the file was queued with
+        let rescanned = [("/videos/a.mkv".into(), Timestamp::from_second(200)?)];
+        assert_eq!(0, db.set_source_modified(&rescanned)?);
+        assert_eq!(Some(scanned), db.get(id)?.unwrap().source_modified);
+
+        db.set_file_status(id, TranscodeStatus::Error, Some("it broke".into()))?;
+        db.set_source_changes(id, &[SourceChange::Gone])?;
+        assert_eq!(
+            vec![SourceChange::Gone],
+            db.get(id)?.unwrap().source_changes()?
+        );
+        // the changes belong to the failure they explain
+        db.set_file_status(id, TranscodeStatus::Pending, None)?;
+        assert!(db.get(id)?.unwrap().source_changes()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_filtered() -> Result<()> {
+        let db = Database::in_memory()?;
+        let files: Vec<_> = ["/movies/a.mkv", "/movies/b.mkv", "/shows/c.mkv"]
+            .into_iter()
+            .map(|path| NewTranscodeFile {
+                path: path.into(),
+                file_size: 5,
+                ffprobe_info: FfProbe::default(),
+            })
+            .collect();
+        db.insert_batch(&files)?;
+        let id = db
+            .list()?
+            .iter()
+            .find(|f| f.path == "/movies/b.mkv")
+            .unwrap()
+            .id;
+        db.set_file_status(id, TranscodeStatus::Success, None)?;
+
+        let filter = FileFilter {
+            path_contains: Some("/movies/".into()),
+            ..Default::default()
+        };
+        assert_eq!(2, db.list_filtered(&filter, None)?.len());
+
+        let filter = FileFilter {
+            status: Some(TranscodeStatus::Pending),
+            path_contains: Some("/movies/".into()),
+            ..Default::default()
+        };
+        let rows = db.list_filtered(&filter, None)?;
+        assert_eq!(1, rows.len());
+        assert_eq!("/movies/a.mkv", rows[0].path);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_iter_filtered_pages() -> Result<()> {
+        let db = Database::in_memory()?;
+        let sizes = [5, 3, 9, 5, 1, 5, 7, 3, 9, 2, 5];
+        let files: Vec<_> = sizes
+            .iter()
+            .enumerate()
+            .map(|(i, &file_size)| NewTranscodeFile {
+                path: format!("/movies/{i}.mkv").into(),
+                file_size,
+                ffprobe_info: FfProbe::default(),
+            })
+            .collect();
+        db.insert_batch(&files)?;
+        let done = db
+            .list()?
+            .into_iter()
+            .find(|f| f.path == "/movies/3.mkv")
+            .unwrap();
+        db.set_file_status(done.id, TranscodeStatus::Success, None)?;
+
+        let filter = FileFilter {
+            status: Some(TranscodeStatus::Pending),
+            ..Default::default()
+        };
+        let mut expected: Vec<_> = db.list_filtered(&filter, None)?;
+        expected.sort_by_key(|f| (Reverse(f.file_size), f.path.clone()));
+        let key = |files: Vec<TranscodeFile>| -> Vec<_> {
+            files
+                .into_iter()
+                .map(|f| (f.file_size, f.path.to_string()))
+                .collect()
+        };
+        let expected = key(expected);
+        assert_eq!(10, expected.len());
+
+        for page_size in [1, 2, 3, 10, 100] {
+            let rows: Vec<_> = db
+                .iter_filtered(&filter, SizeOrder::Descending, page_size)
+                .collect::<Result<_>>()?;
+            assert_eq!(expected, key(rows), "page size {page_size}");
+
+            let rows: Vec<_> = db
+                .iter_filtered(&filter, SizeOrder::Ascending, page_size)
+                .collect::<Result<_>>()?;
+            let mut ascending = expected.clone();
+            ascending.sort();
+            assert_eq!(ascending, key(rows), "page size {page_size}");
+        }
+
+        // a file finished by another worker while the first page is in use
+        // doesn't shift the next page
+        let mut rows = db.iter_filtered(&filter, SizeOrder::Descending, 3);
+        let first: Vec<_> = rows.by_ref().take(3).map(|f| f.unwrap()).collect();
+        db.set_file_status(first[0].id, TranscodeStatus::Success, None)?;
+        let mut seen = key(first);
+        seen.extend(key(rows.map(|f| f.unwrap()).collect()));
+        assert_eq!(expected, seen);
+        Ok(())
+    }
+
+    #[test]
+    fn test_where_filter() -> Result<()> {
+        let db = Database::in_memory()?;
+        let files: Vec<_> = [
+            ("/a.avi", 6_000_000_000),
+            ("/b.mkv", 7_000_000_000),
+            ("/c.avi", 5),
+        ]
+        .into_iter()
+        .map(|(path, file_size)| NewTranscodeFile {
+            path: path.into(),
+            file_size,
+            ffprobe_info: FfProbe::default(),
+        })
+        .collect();
+        db.insert_batch(&files)?;
+        let paths = |filter: &FileFilter| -> Result<Vec<Utf8PathBuf>> {
+            filter.check()?;
+            Ok(db
+                .list_filtered(filter, None)?
+                .into_iter()
+                .map(|f| f.path)
+                .collect())
+        };
+
+        let filter = FileFilter {
+            where_sql: Some("file_size > 5e9 AND path LIKE '%.avi'".parse().unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(vec!["/a.avi"], paths(&filter)?);
+
+        // the placeholders come after the parameters of the other filters
+        let filter = FileFilter {
+            status: Some(TranscodeStatus::Pending),
+            path_contains: Some("/".into()),
+            where_sql: Some("file_size > ? AND path LIKE ?".parse().unwrap()),
+            param: vec!["5e9".into(), "%.avi".into()],
+            ..Default::default()
+        };
+        assert_eq!(vec!["/a.avi"], paths(&filter)?);
+        let filter = FileFilter {
+            param: vec!["5e9".into()],
+            ..filter
+        };
+        assert!(paths(&filter).is_err());
+
+        let filter = FileFilter {
+            status: Some(TranscodeStatus::Pending),
+            where_sql: Some("file_size < ?".parse().unwrap()),
+            param: vec!["100".into()],
+            ..Default::default()
+        };
+        assert_eq!(1, db.forget(&filter)?);
+        assert_eq!(2, db.list()?.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_stale_filter() -> Result<()> {
+        let db = Database::in_memory()?;
+        let files: Vec<_> = ["/old.mkv", "/older.mkv", "/new.mkv", "/done.mkv"]
+            .into_iter()
+            .map(|path| NewTranscodeFile {
+                path: path.into(),
+                file_size: 5,
+                ffprobe_info: FfProbe::default(),
+            })
+            .collect();
+        db.insert_batch(&files)?;
+        let now = Timestamp::now();
+        let days_ago = |days: i64| (now - SignedDuration::from_hours(days * 24)).as_second();
+        let connection = db.db.get()?;
+        for (path, days) in [
+            ("/old.mkv", 40),
+            ("/older.mkv", 100),
+            ("/new.mkv", 2),
+            ("/done.mkv", 100),
+        ] {
+            connection.execute(
+                "UPDATE transcode_files SET created_on = ?1 WHERE path = ?2",
+                params![days_ago(days), path],
+            )?;
+        }
+        connection.execute(
+            "UPDATE transcode_files SET status = 'success' WHERE path = '/done.mkv'",
+            [],
+        )?;
+        // one run before the old file was queued, two after it
+        for days in [50, 30, 1] {
+            connection.execute(
+                "INSERT INTO runs (started_on, ffmpeg_version, libraries) VALUES (?1, '7.0', '{}')",
+                [days_ago(days)],
+            )?;
+        }
+
+        let stale = |stale_runs: u32| -> Result<Vec<Utf8PathBuf>> {
+            let filter = FileFilter {
+                stale: Some(SignedDuration::from_hours(30 * 24)),
+                stale_runs,
+                ..Default::default()
+            };
+            let mut paths: Vec<_> = db
+                .list_filtered(&filter, None)?
+                .into_iter()
+                .map(|f| f.path)
+                .collect();
+            paths.sort();
+            Ok(paths)
+        };
+        assert_eq!(vec!["/old.mkv", "/older.mkv"], stale(2)?);
+        assert_eq!(vec!["/older.mkv"], stale(3)?);
+
+        let older = db.get_by_path("/older.mkv".into())?.unwrap();
+        assert_eq!(3, db.runs_started_since(older.created_on)?);
+        assert_eq!(0, db.runs_started_since(now)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_snapshot() -> Result<()> {
+        let seed = |db: &Database, files: &[(&str, i64, TranscodeStatus)]| -> Result<()> {
+            for &(path, file_size, status) in files {
+                db.insert(NewTranscodeFile {
+                    path: path.into(),
+                    file_size: file_size as u64,
+                    ffprobe_info: FfProbe::default(),
+                })?;
+                let id = db.get_by_path(path.into())?.unwrap().id;
+                db.set_file_status(id, status, None)?;
+                if status == TranscodeStatus::Success {
+                    db.insert_crf_attempt(id, 24, file_size as u64 / 4)?;
+                }
+            }
+            Ok(())
+        };
+        let tempdir = tempfile::tempdir()?;
+        let directory = Utf8Path::from_path(tempdir.path()).unwrap();
+        let snapshot = directory.join("before #1.db");
+        let old = Database::open(&snapshot)?;
+        seed(
+            &old,
+            &[
+                ("/a.mkv", 100, TranscodeStatus::Pending),
+                ("/b.mkv", 50, TranscodeStatus::Pending),
+                ("/c.mkv", 30, TranscodeStatus::Pending),
+                ("/d.mkv", 40, TranscodeStatus::Success),
+            ],
+        )?;
+        drop(old);
+        let db = Database::in_memory()?;
+        seed(
+            &db,
+            &[
+                ("/a.mkv", 100, TranscodeStatus::Success),
+                ("/c.mkv", 35, TranscodeStatus::Pending),
+                ("/d.mkv", 40, TranscodeStatus::Success),
+                ("/e.mkv", 10, TranscodeStatus::Pending),
+            ],
+        )?;
+        // archived files are still there
+        db.db.get()?.execute(
+            "UPDATE transcode_files SET updated_on = 0 WHERE path = '/d.mkv'",
+            [],
+        )?;
+        assert_eq!(1, db.archive(SignedDuration::from_hours(1))?);
+
+        let diff = db.diff_snapshot(&snapshot)?;
+        let changes: Vec<_> = diff
+            .files
+            .iter()
+            .map(|f| (f.path.as_str(), f.kind()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("/a.mkv", "status"),
+                ("/b.mkv", "removed"),
+                ("/c.mkv", "size"),
+                ("/e.mkv", "added")
+            ],
+            changes
+        );
+        assert_eq!(
+            Some(FileState {
+                status: "pending".into(),
+                file_size: 30
+            }),
+            diff.files[2].old
+        );
+        assert_eq!(1, diff.transitions["pending -> success"]);
+        // the archived file's savings aren't counted in the working tables
+        assert_eq!((30, 75), (diff.old_saved, diff.new_saved));
+        // the connection can attach the next snapshot
+        assert_eq!(diff, db.diff_snapshot(&snapshot)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_old_snapshot() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let directory = Utf8Path::from_path(tempdir.path()).unwrap();
+        let snapshot = directory.join("old.db");
+        let connection = Connection::open(&snapshot)?;
+        connection.execute_batch(include_str!("../init_db.sql"))?;
+        connection.pragma_update(None, "user_version", 5)?;
+        drop(connection);
+
+        let db = Database::in_memory()?;
+        let error = db.diff_snapshot(&snapshot).unwrap_err();
+        assert_eq!(
+            Some(&SnapshotTooOld { found: 5 }),
+            error.downcast_ref::<SnapshotTooOld>()
+        );
+        // the snapshot is left as it was
+        let connection = Connection::open(&snapshot)?;
+        let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        assert_eq!(5, version);
+
+        assert!(db.diff_snapshot(&directory.join("missing.db")).is_err());
+        assert!(!directory.join("missing.db").exists());
         Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::ffprobe::ffprobe;
+    #[test]
+    fn test_container_filter() -> Result<()> {
+        let db = Database::in_memory()?;
+        let files: Vec<_> = [("/a.avi", "avi"), ("/b.mp4", "mov,mp4,m4a,3gp,3g2,mj2")]
+            .into_iter()
+            .map(|(path, format_name)| NewTranscodeFile {
+                path: path.into(),
+                file_size: 5,
+                ffprobe_info: FfProbe {
+                    format: Format {
+                        format_name: format_name.into(),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            })
+            .collect();
+        db.insert_batch(&files)?;
+
+        let filter = FileFilter {
+            container: Some("avi".into()),
+            ..Default::default()
+        };
+        let rows = db.list_filtered(&filter, None)?;
+        assert_eq!(1, rows.len());
+        assert_eq!("/a.avi", rows[0].path);
+
+        let filter = FileFilter {
+            container: Some("mov".into()),
+            ..Default::default()
+        };
+        let rows = db.list_filtered(&filter, None)?;
+        assert_eq!(1, rows.len());
+        assert_eq!("/b.mp4", rows[0].path);
+
+        Ok(())
+    }
 
     #[test]
-    fn test_insert_row() -> Result<()> {
+    fn test_library_filter() -> Result<()> {
+        let db = Database::in_memory()?;
+        let files: Vec<_> = ["/movies/a.mkv", "/home/b.mkv", "/home/c.mkv"]
+            .into_iter()
+            .map(|path| NewTranscodeFile {
+                path: path.into(),
+                file_size: 5,
+                ffprobe_info: FfProbe::default(),
+            })
+            .collect();
+        db.insert_batch(&files)?;
+        assert_eq!(1, db.set_library(&["/movies/a.mkv".into()], "movies")?);
+        assert_eq!(
+            2,
+            db.set_library(&["/home/b.mkv".into(), "/home/c.mkv".into()], "home-videos")?
+        );
+
+        let filter = FileFilter {
+            library: Some("movies".into()),
+            ..Default::default()
+        };
+        let rows = db.list_filtered(&filter, None)?;
+        assert_eq!(1, rows.len());
+        assert_eq!("/movies/a.mkv", rows[0].path);
+
+        // scanning a file into another library moves it
+        db.set_library(&["/home/c.mkv".into()], "movies")?;
+        assert_eq!(2, db.list_filtered(&filter, None)?.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_crf_attempts() -> Result<()> {
         let db = Database::in_memory()?;
+        db.insert(NewTranscodeFile {
+            path: "/1.mp4".into(),
+            file_size: 100,
+            ffprobe_info: FfProbe::default(),
+        })?;
+        let id = db.list()?[0].id;
+        assert!(db.crf_attempts(id)?.is_empty());
+
+        db.insert_crf_attempt(id, 24, 95)?;
+        db.insert_crf_attempt(id, 27, 70)?;
+        let attempts: Vec<_> = db
+            .crf_attempts(id)?
+            .into_iter()
+            .map(|a| (a.crf, a.output_size))
+            .collect();
+        assert_eq!(vec![(24, 95), (27, 70)], attempts);
+        Ok(())
+    }
 
+    #[test]
+    fn test_encode_options() -> Result<()> {
+        let db = Database::in_memory()?;
         db.insert(NewTranscodeFile {
-            path: "/stuff/1.mp4".into(),
-            file_size: 696969,
+            path: "/1.mp4".into(),
+            file_size: 100,
             ffprobe_info: FfProbe::default(),
         })?;
+        let id = db.list()?[0].id;
+        assert_eq!(None, db.get(id)?.unwrap().resolved_options()?);
+        assert!(db.get(id + 1)?.is_none());
 
-        let rows = db.list()?;
-        assert_eq!(1, rows.len());
-        assert_eq!(696969, rows[0].file_size);
+        let options = ResolvedOptions::new(
+            None,
+            &crate::config::merge(
+                &Default::default(),
+                None,
+                &Default::default(),
+                crate::device::VideoCodec::Av1,
+            ),
+            &Default::default(),
+            &crate::audio::AudioOptions {
+                reencode_above: None,
+                codec: "aac".into(),
+                bitrate: 160_000,
+                drop: false,
+                drop_commentary: false,
+                allowed_codecs: vec![],
+            },
+            &[],
+        );
+        db.set_encode_options(id, &options)?;
+        assert_eq!(Some(options), db.get(id)?.unwrap().resolved_options()?);
+        Ok(())
+    }
 
+    #[test]
+    fn test_scan_skip_reason_serialization() -> Result<()> {
+        for (reason, text) in [
+            (ScanSkipReason::Pattern, "pattern"),
+            (ScanSkipReason::Size, "size"),
+            (ScanSkipReason::Codec, "codec"),
+            (ScanSkipReason::Bpp, "bpp"),
+            (ScanSkipReason::ProbeFailed, "probe-failed"),
+            (ScanSkipReason::IgnoreFile, "ignore-file"),
+        ] {
+            assert_eq!(text, reason.as_str());
+            assert_eq!(format!("\"{text}\""), serde_json::to_string(&reason)?);
+            assert_eq!(reason, serde_json::from_str(&format!("\"{text}\""))?);
+        }
         Ok(())
     }
 
     #[test]
-    fn test_insert_batch() -> Result<()> {
+    fn test_skipped_files() -> Result<()> {
         let db = Database::in_memory()?;
+        db.insert_batch(&[NewTranscodeFile {
+            path: "/queued.mkv".into(),
+            file_size: 5,
+            ffprobe_info: FfProbe::default(),
+        }])?;
+        let skipped = [
+            ("/small.mkv", ScanSkipReason::Size),
+            ("/broken.mkv", ScanSkipReason::ProbeFailed),
+            ("/queued.mkv", ScanSkipReason::Pattern),
+        ]
+        .map(|(path, reason)| NewSkippedFile {
+            path: path.into(),
+            file_size: 1,
+            reason,
+            ffprobe_info: None,
+        });
+        assert_eq!(2, db.insert_skipped(&skipped)?);
 
-        let files: Vec<_> = (0..100)
-            .map(|i| NewTranscodeFile {
-                path: format!("/stuff/{i}.mp4").into(),
-                file_size: 69 * i,
+        // skipped files stay out of the queue and default listings
+        let paths: Vec<_> = db.list()?.into_iter().map(|f| f.path).collect();
+        assert_eq!(vec!["/queued.mkv"], paths);
+        let filter = FileFilter {
+            status: Some(TranscodeStatus::Skipped),
+            ..Default::default()
+        };
+        let rows = db.list_filtered(&filter, None)?;
+        assert_eq!(2, rows.len());
+        let small = db.get_by_path("/small.mkv".into())?.unwrap();
+        assert_eq!(Some(ScanSkipReason::Size), small.skip_reason);
+
+        // a later scan records why it skipped the file this time, queued files stay queued
+        let again = [
+            ("/broken.mkv", ScanSkipReason::Pattern),
+            ("/queued.mkv", ScanSkipReason::Size),
+        ]
+        .map(|(path, reason)| NewSkippedFile {
+            path: path.into(),
+            file_size: 1,
+            reason,
+            ffprobe_info: None,
+        });
+        assert_eq!(1, db.insert_skipped(&again)?);
+        let broken = db.get_by_path("/broken.mkv".into())?.unwrap();
+        assert_eq!(Some(ScanSkipReason::Pattern), broken.skip_reason);
+        assert_eq!(
+            TranscodeStatus::Pending,
+            db.get_by_path("/queued.mkv".into())?.unwrap().status
+        );
+
+        // a later scan that doesn't skip the file queues it
+        let summary = db.insert_batch(&[NewTranscodeFile {
+            path: "/small.mkv".into(),
+            file_size: 10,
+            ffprobe_info: FfProbe::default(),
+        }])?;
+        assert_eq!(1, summary.inserted);
+        let small = db.get_by_path("/small.mkv".into())?.unwrap();
+        assert_eq!(TranscodeStatus::Pending, small.status);
+        assert_eq!(None, small.skip_reason);
+
+        let queued = db.get_by_path("/queued.mkv".into())?.unwrap();
+        db.set_skipped(queued.id, ScanSkipReason::IgnoreFile)?;
+        let queued = db.get_by_path("/queued.mkv".into())?.unwrap();
+        assert_eq!(TranscodeStatus::Skipped, queued.status);
+        assert_eq!(Some(ScanSkipReason::IgnoreFile), queued.skip_reason);
+
+        assert_eq!(2, db.forget(&filter)?);
+        assert!(db.get_by_path("/broken.mkv".into())?.is_none());
+        assert_eq!(1, db.list()?.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_migrations_are_applied_once() -> Result<()> {
+        let db = Database::in_memory()?;
+        db.init_database()?;
+        let connection = db.db.get()?;
+        let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        assert_eq!(MIGRATIONS.len(), version);
+        Ok(())
+    }
+
+    #[test]
+    fn test_encoder_version_filter() -> Result<()> {
+        let db = Database::in_memory()?;
+        let files: Vec<_> = ["/a.mkv", "/b.mkv", "/c.mkv"]
+            .into_iter()
+            .map(|path| NewTranscodeFile {
+                path: path.into(),
+                file_size: 5,
                 ffprobe_info: FfProbe::default(),
             })
             .collect();
-
-        db.insert_batch(&files)?;
         db.insert_batch(&files)?;
+        let good = db.insert_run(
+            &FfmpegVersion {
+                version: "7.0.1".into(),
+                libraries: [("libavcodec".into(), "61.3.100".into())].into(),
+            },
+            false,
+        )?;
+        let bad = db.insert_run(
+            &FfmpegVersion {
+                version: "N-113045-g6d1b6a2b3c-20231230".into(),
+                libraries: [("libavcodec".into(), "60.37.100".into())].into(),
+            },
+            true,
+        )?;
+        let a = db.get_by_path("/a.mkv".into())?.unwrap();
+        let b = db.get_by_path("/b.mkv".into())?.unwrap();
+        db.set_file_run(a.id, good)?;
+        db.set_file_run(b.id, bad)?;
 
-        let actual = db.list()?;
-        assert_eq!(100, actual.len());
+        for version in ["g6d1b6a2b3c", "60.37"] {
+            let filter = FileFilter {
+                encoder_version: Some(version.into()),
+                ..Default::default()
+            };
+            let rows = db.list_filtered(&filter, None)?;
+            assert_eq!(1, rows.len());
+            assert_eq!("/b.mkv", rows[0].path);
+        }
+
+        let filter = FileFilter {
+            run: Some(good),
+            ..Default::default()
+        };
+        let rows = db.list_filtered(&filter, None)?;
+        assert_eq!(1, rows.len());
+        assert_eq!("/a.mkv", rows[0].path);
 
+        let run = db.get_run(bad)?.unwrap();
+        assert_eq!("60.37.100", run.ffmpeg().libraries["libavcodec"]);
+        assert!(run.sample);
+        assert!(!db.get_run(good)?.unwrap().sample);
+        assert!(db.get_by_path("/c.mkv".into())?.unwrap().run_id.is_none());
         Ok(())
     }
 
     #[test]
-    fn test_insert_duplicate_path() -> Result<()> {
+    fn test_claims() -> Result<()> {
         let db = Database::in_memory()?;
-
         db.insert(NewTranscodeFile {
             path: "/1.mp4".into(),
             file_size: 5,
             ffprobe_info: FfProbe::default(),
         })?;
+        let id = db.list()?[0].id;
 
-        let error = db.insert(NewTranscodeFile {
+        assert!(db.claim(id, "a:1", TranscodeStatus::Pending)?);
+        assert!(!db.claim(id, "b:2", TranscodeStatus::Pending)?);
+        let claims = db.claims()?;
+        assert_eq!(1, claims.len());
+        assert_eq!(Some("a:1"), claims[0].claimed_by.as_deref());
+
+        // only the owner can release the claim
+        db.release_claim(id, "b:2")?;
+        assert_eq!(1, db.claims()?.len());
+        db.release_claim(id, "a:1")?;
+        assert!(db.claims()?.is_empty());
+        assert_eq!(TranscodeStatus::Pending, db.list()?[0].status);
+
+        assert!(db.claim(id, "b:2", TranscodeStatus::Pending)?);
+        db.set_file_status(id, TranscodeStatus::Success, None)?;
+        let row = &db.list()?[0];
+        assert_eq!(TranscodeStatus::Success, row.status);
+        assert!(row.claimed_by.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reclaim_stale() -> Result<()> {
+        let db = Database::in_memory()?;
+        db.insert(NewTranscodeFile {
             path: "/1.mp4".into(),
             file_size: 5,
             ffprobe_info: FfProbe::default(),
-        });
+        })?;
+        let id = db.list()?[0].id;
+        assert!(db.claim(id, "a:1", TranscodeStatus::Pending)?);
 
-        assert!(error.is_err());
-        let err = error.unwrap_err();
-        assert!(err.to_string().contains("UNIQUE constraint failed"));
+        assert_eq!(0, db.reclaim_stale(SignedDuration::from_hours(1))?);
+        db.db.get()?.execute(
+            "UPDATE transcode_files SET claimed_at = unixepoch() - 7200",
+            [],
+        )?;
+        assert_eq!(1, db.reclaim_stale(SignedDuration::from_hours(1))?);
+        assert!(db.claim(id, "b:2", TranscodeStatus::Pending)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_claims() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let path = Utf8Path::from_path(tempdir.path())
+            .unwrap()
+            .join("transcoder.db");
+        let db = Database::open(&path)?;
+        let files: Vec<_> = (0..50)
+            .map(|i| NewTranscodeFile {
+                path: format!("/stuff/{i}.mp4").into(),
+                file_size: i,
+                ffprobe_info: FfProbe::default(),
+            })
+            .collect();
+        db.insert_batch(&files)?;
+        let ids: Vec<_> = db.list()?.iter().map(|f| f.id).collect();
+
+        let claimed: Vec<Vec<i64>> = std::thread::scope(|s| {
+            let workers: Vec<_> = ["a:1", "b:2"]
+                .into_iter()
+                .map(|worker| {
+                    let ids = &ids;
+                    let path = &path;
+                    s.spawn(move || {
+                        let db = Database::open(path).unwrap();
+                        ids.iter()
+                            .copied()
+                            .filter(|&id| db.claim(id, worker, TranscodeStatus::Pending).unwrap())
+                            .collect()
+                    })
+                })
+                .collect();
+            workers.into_iter().map(|w| w.join().unwrap()).collect()
+        });
 
+        let mut all: Vec<_> = claimed.concat();
+        all.sort();
+        let mut expected = ids.clone();
+        expected.sort();
+        assert_eq!(expected, all);
         Ok(())
     }
 
     #[test]
     fn test_ffprobe_info() -> Result<()> {
+        if !testsupport::has_ffmpeg() {
+            return Ok(());
+        }
+        let (db, paths) = testsupport::seeded_database(&[Sample::SMALL])?;
+        let rows = db.list()?;
+        assert_eq!(1, rows.len());
+        assert_eq!(Some(ffprobe(&paths[0])?), rows[0].ffprobe());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_audio_hash() -> Result<()> {
+        let db = Database::in_memory()?;
+        for path in ["/a.mkv", "/b.mkv"] {
+            db.insert(NewTranscodeFile {
+                path: path.into(),
+                file_size: 5,
+                ffprobe_info: FfProbe::default(),
+            })?;
+        }
+        let a = db.get_by_path("/a.mkv".into())?.unwrap();
+        let b = db.get_by_path("/b.mkv".into())?.unwrap();
+        assert_eq!(None, a.audio_hash);
+        db.set_audio_hash(a.id, AudioHash::Match)?;
+        db.set_audio_hash(b.id, AudioHash::Mismatch)?;
+        assert_eq!(Some(AudioHash::Match), db.get(a.id)?.unwrap().audio_hash);
+
+        // a mismatch counts as a failed verification until the file is requeued
+        let failed: Vec<_> = db.verify_failed()?.into_iter().map(|f| f.path).collect();
+        assert_eq!(vec![Utf8PathBuf::from("/b.mkv")], failed);
+        db.requeue(b.id)?;
+        assert_eq!(None, db.get(b.id)?.unwrap().audio_hash);
+        assert!(db.verify_failed()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_usage() -> Result<()> {
+        let db = Database::in_memory()?;
+        db.insert(NewTranscodeFile {
+            path: "/a.mkv".into(),
+            file_size: 5,
+            ffprobe_info: FfProbe::default(),
+        })?;
+        let file = db.get_by_path("/a.mkv".into())?.unwrap();
+        assert_eq!(None, file.cpu_seconds);
+        db.set_resource_usage(
+            file.id,
+            &ResourceUsage {
+                cpu_time: Duration::from_secs(300),
+                peak_rss: 2_000_000_000,
+                wall_time: Duration::from_secs(60),
+            },
+        )?;
+        let file = db.get(file.id)?.unwrap();
+        assert_eq!(Some(300.0), file.cpu_seconds);
+        assert_eq!(Some(2_000_000_000), file.peak_rss);
+        assert_eq!(Some(500.0), file.cpu_percent);
+
+        assert_eq!(None, file.io_mode);
+        db.set_read_rate(file.id, IoMode::Staged, 40e6)?;
+        let file = db.get(file.id)?.unwrap();
+        assert_eq!(Some(IoMode::Staged), file.io_mode);
+        assert_eq!(Some(40e6), file.read_rate);
+        Ok(())
+    }
+
+    #[test]
+    fn test_probe_json() -> Result<()> {
+        let json = r#"{"format":{"format_name":"avi"}}"#;
+        let plain = ProbeJson::from(json.to_string());
+        assert!(!plain.is_compressed());
+        assert_eq!(json, plain.json()?);
+        let compressed = ProbeJson::compress(json)?;
+        assert!(compressed.is_compressed());
+        assert_eq!(json, compressed.json()?);
+        assert_eq!(
+            serde_json::to_string(&plain)?,
+            serde_json::to_string(&compressed)?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact() -> Result<()> {
         let db = Database::in_memory()?;
-        let ffprobe = ffprobe("./samples/claire.mp4")?;
+        let fixtures = crate::ffprobe::fixtures();
+        for (index, info) in fixtures.iter().enumerate() {
+            db.insert(NewTranscodeFile {
+                path: format!("/videos/{index}").into(),
+                file_size: 1000,
+                ffprobe_info: info.clone(),
+            })?;
+        }
+        db.db.get()?.execute(
+            r#"INSERT INTO transcode_files (path, created_on, updated_on, file_size, ffprobe_info) VALUES ('/videos/broken', 0, 0, 5, '"not ffprobe output"')"#,
+            [],
+        )?;
+        let probes = |db: &Database| -> Result<Vec<Option<FfProbe>>> {
+            Ok(db.list()?.iter().map(TranscodeFile::ffprobe).collect())
+        };
+        let stripped: Vec<_> = fixtures.iter().map(|info| Some(info.stripped())).collect();
 
-        let file = NewTranscodeFile {
-            path: "./samples/claire.mp4".into(),
-            file_size: 130 * 1000 * 1000,
-            ffprobe_info: ffprobe.clone(),
+        let summary = db.compact(true)?;
+        assert_eq!(2, summary.rewritten);
+        assert_eq!(1, summary.unreadable);
+        assert!(db.list()?[0].ffprobe_info.is_compressed());
+        assert_eq!(stripped, probes(&db)?[..2]);
+        let filter = FileFilter {
+            container: Some("mkv".into()),
+            ..Default::default()
         };
-        db.insert(file)?;
-        let rows = db.list()?;
-        assert_eq!(1, rows.len());
-        assert!(rows[0].ffprobe().is_some());
+        let matroska = db.list_filtered(&filter, None)?;
+        assert_eq!(1, matroska.len());
+        assert_eq!("/videos/1", matroska[0].path);
+
+        // files probed again are stored as plain JSON next to the compressed ones
+        db.update_probe(1, 1000, &fixtures[0], None, false)?;
+        assert!(!db.list()?[0].ffprobe_info.is_compressed());
+        assert_eq!(Some(fixtures[0].clone()), probes(&db)?[0]);
+
+        let summary = db.compact(false)?;
+        assert_eq!(2, summary.rewritten);
+        assert!(db.list()?.iter().all(|f| !f.ffprobe_info.is_compressed()));
+        assert_eq!(stripped, probes(&db)?[..2]);
+        assert_eq!(None, probes(&db)?[2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact_size() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let path = Utf8Path::from_path(tempdir.path())
+            .unwrap()
+            .join("transcoder.db");
+        let db = Database::open(&path)?;
+        let fixtures = crate::ffprobe::fixtures();
+        let files: Vec<_> = (0..2000)
+            .map(|index| {
+                let mut info = fixtures[index % 2].clone();
+                info.format.filename = format!("/videos/{index}.mkv");
+                NewTranscodeFile {
+                    path: format!("/videos/{index}.mkv").into(),
+                    file_size: 1000,
+                    ffprobe_info: info,
+                }
+            })
+            .collect();
+        db.insert_batch(&files)?;
+
+        let summary = db.compact(false)?;
+        assert_eq!(2000, summary.rewritten);
+        assert!(summary.size_after * 3 < summary.size_before);
+        let summary = db.compact(true)?;
+        assert!(summary.size_after < summary.size_before);
+        assert_eq!(std::fs::metadata(&path)?.len(), summary.size_after);
+        Ok(())
+    }
+
+    #[test]
+    fn test_schema_too_new() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let path = Utf8Path::from_path(tempdir.path())
+            .unwrap()
+            .join("transcoder.db");
+        let db = Database::open(&path)?;
+        db.db
+            .get()?
+            .pragma_update(None, "user_version", MIGRATIONS.len() + 2)?;
+        drop(db);
+
+        let error = Database::open(&path).err().unwrap();
+        let too_new = error.downcast_ref::<SchemaTooNew>().unwrap();
+        assert_eq!(MIGRATIONS.len() + 2, too_new.found);
+        assert_eq!(MIGRATIONS.len(), too_new.supported);
+        assert!(
+            error
+                .to_string()
+                .contains("newer than this binary supports")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_database_location() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let dir = Utf8Path::from_path(tempdir.path()).unwrap();
+        let location = |path: &Utf8Path| -> DatabaseLocation {
+            let error = Database::open(path).err().unwrap();
+            error.downcast_ref::<DatabaseLocation>().unwrap().clone()
+        };
+
+        let missing = dir.join("missing/transcoder.db");
+        assert_eq!(
+            format!(
+                "can't use {missing} as the database: the directory {dir}/missing doesn't exist; \
+                 use --database to point elsewhere"
+            ),
+            location(&missing).to_string()
+        );
+        fs::write(dir.join("file"), b"")?;
+        assert_eq!(
+            format!("{dir}/file is not a directory"),
+            location(&dir.join("file/transcoder.db")).reason
+        );
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let read_only = dir.join("read-only");
+            fs::create_dir(&read_only)?;
+            fs::set_permissions(&read_only, fs::Permissions::from_mode(0o555))?;
+            // root writes anyway
+            if fs::write(read_only.join("probe"), b"").is_err() {
+                assert_eq!(
+                    format!("the directory {read_only} isn't writable"),
+                    location(&read_only.join("transcoder.db")).reason
+                );
+            }
+        }
+
+        // a new database in a directory that exists
+        let path = dir.join("transcoder.db");
+        Database::open(&path)?;
+        assert!(Database::open(&path)?.is_empty()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_open_existing() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let dir = Utf8Path::from_path(tempdir.path())
+            .unwrap()
+            .canonicalize_utf8()?;
+        let path = dir.join("transcoder.db");
+        let error = Database::open_existing(&path).err().unwrap();
+        assert_eq!(
+            Some(&MissingDatabase { path: path.clone() }),
+            error.downcast_ref::<MissingDatabase>()
+        );
+        assert!(!path.exists());
+
+        Database::open(&path)?;
+        assert!(Database::open_existing(&path)?.is_empty()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_columns() -> Result<()> {
+        let db = Database::in_memory()?;
+        db.db.get()?.execute_batch(
+            "ALTER TABLE transcode_files ADD COLUMN future VARCHAR DEFAULT 'x';
+             ALTER TABLE runs ADD COLUMN future VARCHAR DEFAULT 'x';",
+        )?;
+        db.insert(NewTranscodeFile {
+            path: "/videos/a.mkv".into(),
+            file_size: 1000,
+            ffprobe_info: FfProbe::default(),
+        })?;
+        let file = db.get_by_path("/videos/a.mkv".into())?.unwrap();
+        assert_eq!(file.id, db.get(file.id)?.unwrap().id);
+        assert_eq!(1, db.list_filtered(&FileFilter::default(), None)?.len());
+        let run = db.insert_run(
+            &FfmpegVersion {
+                version: "7.0.1".into(),
+                libraries: Default::default(),
+            },
+            false,
+        )?;
+        assert_eq!("7.0.1", db.get_run(run)?.unwrap().ffmpeg().version);
+        Ok(())
+    }
+
+    #[test]
+    fn test_pin() -> Result<()> {
+        let db = Database::in_memory()?;
+        for path in [
+            "/videos/reference/a.mkv",
+            "/videos/reference/b.mkv",
+            "/videos/c.mkv",
+        ] {
+            db.insert(NewTranscodeFile {
+                path: path.into(),
+                file_size: 1000,
+                ffprobe_info: FfProbe::default(),
+            })?;
+        }
+        assert_eq!(2, db.pin("**/reference/*", Some("reference encode"))?);
+        // pinning again without a note keeps the note
+        assert_eq!(1, db.pin("**/a.mkv", None)?);
+        let a = db.get_by_path("/videos/reference/a.mkv".into())?.unwrap();
+        assert!(a.pinned);
+        assert_eq!(Some("reference encode".into()), a.note);
+        assert!(!db.get_by_path("/videos/c.mkv".into())?.unwrap().pinned);
+
+        assert_eq!(1, db.unpin("/videos/reference/b.mkv")?);
+        let b = db.get_by_path("/videos/reference/b.mkv".into())?.unwrap();
+        assert!(!b.pinned);
+        assert_eq!(None, b.note);
+        assert_eq!(0, db.pin("/other/**", None)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_archive() -> Result<()> {
+        let db = Database::in_memory()?;
+        let files: Vec<_> = ["/Films/old.mkv", "/Films/recent.mkv", "/Shows/old.mkv"]
+            .into_iter()
+            .map(|path| NewTranscodeFile {
+                path: path.into(),
+                file_size: 100,
+                ffprobe_info: FfProbe::default(),
+            })
+            .collect();
+        db.insert_batch(&files)?;
+        db.insert(NewTranscodeFile {
+            path: "/Films/pending.mkv".into(),
+            file_size: 100,
+            ffprobe_info: FfProbe::default(),
+        })?;
+        let id = |path: &str| -> Result<i64> { Ok(db.get_by_path(path.into())?.unwrap().id) };
+        let old = id("/Films/old.mkv")?;
+        db.insert_crf_attempt(old, 24, 95)?;
+        db.insert_crf_attempt(old, 27, 70)?;
+        db.set_output_path(old, "/Films/old_av1.mp4".into())?;
+        db.set_verification(old, None)?;
+        db.set_file_status(old, TranscodeStatus::Success, None)?;
+        db.set_file_status(id("/Films/recent.mkv")?, TranscodeStatus::Success, None)?;
+        db.set_file_status(id("/Shows/old.mkv")?, TranscodeStatus::Reclaimed, None)?;
+        db.db.get()?.execute(
+            "UPDATE transcode_files SET updated_on = 0 WHERE path != '/Films/recent.mkv'",
+            [],
+        )?;
+        let before = db.get(old)?.unwrap();
+
+        // pending files aren't archived however old they are
+        assert_eq!(2, db.archive(SignedDuration::from_hours(24))?);
+        let paths = |files: Vec<TranscodeFile>| -> Vec<String> {
+            files.into_iter().map(|f| f.path.into_string()).collect()
+        };
+        assert_eq!(
+            vec!["/Films/pending.mkv", "/Films/recent.mkv"],
+            paths(db.list()?)
+        );
+        assert!(db.get_by_path("/Films/old.mkv".into())?.is_none());
+        let count: i64 =
+            db.db
+                .get()?
+                .query_row("SELECT COUNT(*) FROM crf_attempts", [], |row| row.get(0))?;
+        assert_eq!(0, count);
+        let filter = FileFilter {
+            path_contains: Some("Films".into()),
+            ..Default::default()
+        };
+        assert_eq!(vec!["/Films/old.mkv"], paths(db.list_archived(&filter)?));
+
+        // scans don't add archived files again
+        let summary = db.insert_batch(&files)?;
+        assert_eq!(0, summary.inserted);
+        assert_eq!(2, db.list()?.len());
+
+        let summary = db.unarchive("/Films/**")?;
+        assert_eq!(1, summary.restored);
+        assert!(summary.conflicts.is_empty());
+        let restored = db.get_by_path("/Films/old.mkv".into())?.unwrap();
+        assert_eq!(TranscodeStatus::Success, restored.status);
+        assert_eq!(before.updated_on, restored.updated_on);
+        assert_eq!(before.output_path, restored.output_path);
+        assert_eq!(before.verified_at, restored.verified_at);
+        let attempts: Vec<_> = db
+            .crf_attempts(restored.id)?
+            .into_iter()
+            .map(|a| (a.crf, a.output_size))
+            .collect();
+        assert_eq!(vec![(24, 95), (27, 70)], attempts);
+        assert_eq!(
+            vec!["/Shows/old.mkv"],
+            paths(db.list_archived(&FileFilter::default())?)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_history() -> Result<()> {
+        let db = Database::in_memory()?;
+        let files: Vec<_> = ["/a.mkv", "/b.mkv", "/c.mkv", "/d.mkv", "/e.mkv"]
+            .into_iter()
+            .map(|path| NewTranscodeFile {
+                path: path.into(),
+                file_size: 100,
+                ffprobe_info: FfProbe::default(),
+            })
+            .collect();
+        db.insert_batch(&files)?;
+        let run = db.insert_run(
+            &FfmpegVersion {
+                version: "7.0.1".into(),
+                libraries: Default::default(),
+            },
+            false,
+        )?;
+        let id = |path: &str| -> Result<i64> { Ok(db.get_by_path(path.into())?.unwrap().id) };
+        for (path, status) in [
+            ("/a.mkv", TranscodeStatus::Success),
+            ("/b.mkv", TranscodeStatus::Reclaimed),
+            ("/c.mkv", TranscodeStatus::Error),
+            ("/d.mkv", TranscodeStatus::Success),
+        ] {
+            let id = id(path)?;
+            db.set_file_run(id, run)?;
+            if status != TranscodeStatus::Error {
+                db.insert_crf_attempt(id, 24, 40)?;
+            }
+            db.set_file_status(id, status, None)?;
+        }
+        // an earlier encode of the failed file doesn't count as its output
+        db.insert_crf_attempt(id("/c.mkv")?, 24, 40)?;
+        let connection = db.db.get()?;
+        connection.execute("UPDATE crf_attempts SET attempted_on = 1000", [])?;
+        // reclaiming the source later doesn't move the encode
+        connection.execute(
+            "UPDATE transcode_files SET updated_on = 5000 WHERE path = '/b.mkv'",
+            [],
+        )?;
+        connection.execute(
+            "UPDATE transcode_files SET updated_on = 2000 WHERE path = '/c.mkv'",
+            [],
+        )?;
+        connection.execute(
+            "UPDATE transcode_files SET updated_on = 0 WHERE path = '/d.mkv'",
+            [],
+        )?;
+        drop(connection);
+        // archived files are still part of the history
+        assert_eq!(2, db.archive(SignedDuration::from_hours(24))?);
+
+        let history = db.history(None)?;
+        let mut entries: Vec<_> = history
+            .iter()
+            .map(|e| (e.finished_on.as_second(), e.status, e.output_size))
+            .collect();
+        entries.sort();
+        assert_eq!(
+            vec![
+                (1000, TranscodeStatus::Success, Some(40)),
+                (1000, TranscodeStatus::Success, Some(40)),
+                (1000, TranscodeStatus::Reclaimed, Some(40)),
+                (2000, TranscodeStatus::Error, None),
+            ],
+            entries
+        );
+        assert!(history.iter().all(|e| e.run_id == Some(run)));
+        assert!(history.iter().all(|e| e.run_started_on.is_some()));
+
+        let since = Timestamp::from_second(1500)?;
+        assert_eq!(1, db.history(Some(since))?.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unarchive_conflict() -> Result<()> {
+        let db = Database::in_memory()?;
+        db.insert(NewTranscodeFile {
+            path: "/a.mkv".into(),
+            file_size: 100,
+            ffprobe_info: FfProbe::default(),
+        })?;
+        let id = db.list()?[0].id;
+        db.set_file_status(id, TranscodeStatus::Success, None)?;
+        db.db
+            .get()?
+            .execute("UPDATE transcode_files SET updated_on = 0", [])?;
+        assert_eq!(1, db.archive(SignedDuration::from_hours(24))?);
 
+        // the same path added by hand, since scans leave archived paths alone
+        db.insert(NewTranscodeFile {
+            path: "/a.mkv".into(),
+            file_size: 50,
+            ffprobe_info: FfProbe::default(),
+        })?;
+        let summary = db.unarchive("**")?;
+        assert_eq!(0, summary.restored);
+        assert_eq!(vec![Utf8PathBuf::from("/a.mkv")], summary.conflicts);
+        assert_eq!(1, db.list_archived(&FileFilter::default())?.len());
+        assert_eq!(50, db.list()?[0].file_size);
         Ok(())
     }
 }
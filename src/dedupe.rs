@@ -0,0 +1,217 @@
+use std::collections::BTreeMap;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use tracing::info;
+
+use crate::Result;
+use crate::database::{Database, FileFilter, TranscodeFile, TranscodeStatus};
+use crate::paths::{self, DirListings};
+
+/// Rows whose paths name the same file, and which of them is kept.
+#[derive(Debug, Clone)]
+pub struct Duplicates {
+    /// The path the kept row is stored under.
+    pub path: Utf8PathBuf,
+    pub keep: TranscodeFile,
+    pub remove: Vec<TranscodeFile>,
+}
+
+/// How far a file got, rows that got further are kept.
+fn progress(status: TranscodeStatus) -> u8 {
+    match status {
+        TranscodeStatus::Skipped => 0,
+        TranscodeStatus::Pending => 1,
+        TranscodeStatus::Error => 2,
+        TranscodeStatus::Rejected => 3,
+        TranscodeStatus::InProgress => 4,
+        TranscodeStatus::Encoded => 5,
+        TranscodeStatus::Success => 6,
+        TranscodeStatus::Reclaimed => 7,
+    }
+}
+
+/// Groups the files by their path in `canonical` form. Of the rows of a group, the
+/// one with the most progress is kept, the one updated last if several got as far.
+/// Returns the groups with more than one row and the single rows that aren't
+/// stored in canonical form yet.
+pub fn find_duplicates(
+    files: Vec<TranscodeFile>,
+    canonical: impl Fn(&Utf8Path) -> Utf8PathBuf,
+) -> Vec<Duplicates> {
+    let mut groups: BTreeMap<Utf8PathBuf, Vec<TranscodeFile>> = BTreeMap::new();
+    for file in files {
+        groups.entry(canonical(&file.path)).or_default().push(file);
+    }
+    groups
+        .into_iter()
+        .filter_map(|(path, mut files)| {
+            files.sort_by_key(|file| (progress(file.status), file.updated_on, file.id));
+            let keep = files.pop()?;
+            (!files.is_empty() || keep.path != path).then_some(Duplicates {
+                path,
+                keep,
+                remove: files,
+            })
+        })
+        .collect()
+}
+
+/// Merges the rows whose paths differ only in case, keeping the one with the
+/// most progress, and stores the paths in the case they have on disk.
+pub fn dedupe_paths(database: &Database, dry_run: bool) -> Result<Vec<Duplicates>> {
+    let files = database.list_filtered(&FileFilter::default(), None)?;
+    let listings = DirListings::default();
+    let duplicates = find_duplicates(files, |path| paths::normalize(path, true, Some(&listings)));
+    for duplicate in &duplicates {
+        let removed: Vec<i64> = duplicate.remove.iter().map(|file| file.id).collect();
+        if dry_run {
+            info!(
+                "would keep {} as {} and remove {} rows",
+                duplicate.keep.path,
+                duplicate.path,
+                removed.len()
+            );
+        } else {
+            info!(
+                "keeping {} as {}, removing {} rows",
+                duplicate.keep.path,
+                duplicate.path,
+                removed.len()
+            );
+            database.merge_duplicates(duplicate.keep.id, &duplicate.path, &removed)?;
+        }
+    }
+    Ok(duplicates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::NewTranscodeFile;
+    use crate::ffprobe::FfProbe;
+
+    fn lowercase(path: &Utf8Path) -> Utf8PathBuf {
+        path.as_str().to_lowercase().into()
+    }
+
+    #[test]
+    fn test_find_duplicates() -> Result<()> {
+        let database = Database::in_memory()?;
+        for path in [
+            "/Movies/Film.mkv",
+            "/movies/film.mkv",
+            "/MOVIES/FILM.MKV",
+            "/movies/other.mkv",
+            "/Movies/Single.mkv",
+        ] {
+            database.insert(NewTranscodeFile {
+                path: path.into(),
+                file_size: 10,
+                ffprobe_info: FfProbe::default(),
+            })?;
+        }
+        let id = |path: &str| -> Result<i64> { Ok(database.get_by_path(path.into())?.unwrap().id) };
+        database.set_file_status(id("/movies/film.mkv")?, TranscodeStatus::Success, None)?;
+        database.set_file_status(id("/MOVIES/FILM.MKV")?, TranscodeStatus::Error, None)?;
+
+        let files = database.list_filtered(&FileFilter::default(), None)?;
+        let duplicates = find_duplicates(files, lowercase);
+        let summary: Vec<_> = duplicates
+            .iter()
+            .map(|d| {
+                let mut removed: Vec<_> = d.remove.iter().map(|f| f.path.as_str()).collect();
+                removed.sort();
+                (d.path.as_str(), d.keep.path.as_str(), removed)
+            })
+            .collect();
+        // the transcoded row wins, a single row is only renamed
+        assert_eq!(
+            vec![
+                (
+                    "/movies/film.mkv",
+                    "/movies/film.mkv",
+                    vec!["/MOVIES/FILM.MKV", "/Movies/Film.mkv"]
+                ),
+                ("/movies/single.mkv", "/Movies/Single.mkv", vec![]),
+            ],
+            summary
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_most_recent_wins() -> Result<()> {
+        let database = Database::in_memory()?;
+        for path in ["/a/B.mkv", "/a/b.mkv"] {
+            database.insert(NewTranscodeFile {
+                path: path.into(),
+                file_size: 10,
+                ffprobe_info: FfProbe::default(),
+            })?;
+        }
+        let older = database.get_by_path("/a/B.mkv".into())?.unwrap();
+        let mut newer = database.get_by_path("/a/b.mkv".into())?.unwrap();
+        newer.updated_on = older.updated_on + jiff::SignedDuration::from_secs(60);
+        let duplicates = find_duplicates(vec![newer, older], lowercase);
+        assert_eq!(1, duplicates.len());
+        assert_eq!("/a/b.mkv", duplicates[0].keep.path);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dedupe_paths() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let root = Utf8Path::from_path(tempdir.path()).unwrap();
+        std::fs::create_dir(root.join("Movies"))?;
+        std::fs::write(root.join("Movies/Film.mkv"), b"")?;
+
+        let database = Database::in_memory()?;
+        for path in [root.join("movies/film.mkv"), root.join("MOVIES/Film.mkv")] {
+            database.insert(NewTranscodeFile {
+                path,
+                file_size: 10,
+                ffprobe_info: FfProbe::default(),
+            })?;
+        }
+        let done = database
+            .get_by_path(&root.join("MOVIES/Film.mkv"))?
+            .unwrap();
+        database.insert_crf_attempt(done.id, 24, 5)?;
+        database.set_file_status(done.id, TranscodeStatus::Success, None)?;
+        let pending = database
+            .get_by_path(&root.join("movies/film.mkv"))?
+            .unwrap();
+        database.insert_crf_attempt(pending.id, 30, 5)?;
+
+        assert_eq!(1, dedupe_paths(&database, true)?.len());
+        assert_eq!(
+            2,
+            database.list_filtered(&FileFilter::default(), None)?.len()
+        );
+
+        assert_eq!(1, dedupe_paths(&database, false)?.len());
+        let files = database.list_filtered(&FileFilter::default(), None)?;
+        assert_eq!(1, files.len());
+        assert_eq!(root.join("Movies/Film.mkv"), files[0].path);
+        assert_eq!(TranscodeStatus::Success, files[0].status);
+        assert_eq!(1, database.crf_attempts(files[0].id)?.len());
+        assert!(database.crf_attempts(pending.id)?.is_empty());
+
+        // a case-insensitive database finds the row under every spelling
+        let database = database.with_case_insensitive_paths(true);
+        let found = database
+            .get_by_path(&root.join("MOVIES/FILM.mkv"))?
+            .unwrap();
+        assert_eq!(done.id, found.id);
+        assert!(dedupe_paths(&database, false)?.is_empty());
+
+        // and a deleted file under the spelling it was stored with
+        std::fs::remove_file(root.join("Movies/Film.mkv"))?;
+        let found = database
+            .get_by_path(&root.join("Movies/Film.mkv"))?
+            .unwrap();
+        assert_eq!(done.id, found.id);
+        assert!(dedupe_paths(&database, false)?.is_empty());
+        Ok(())
+    }
+}
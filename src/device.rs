@@ -0,0 +1,875 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use clap::ValueEnum;
+use color_eyre::eyre::{bail, eyre};
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+use crate::constraints::Level;
+use crate::ffprobe::{FfProbe, Stream};
+use crate::transcode::GpuMode;
+
+/// The profiles that come with the transcoder, see the file for the format.
+const BUILTIN_PROFILES: &str = include_str!("devices.toml");
+
+/// The container of the outputs for devices.
+const DEVICE_CONTAINER: Container = Container::Mp4;
+
+/// Subtitle codecs that are text, which players render themselves. Everything
+/// else is pictures that have to be burned into the video.
+const TEXT_SUBTITLES: &[&str] = &["mov_text", "subrip", "srt", "ass", "ssa", "webvtt", "text"];
+
+/// A video codec the transcoder can encode to. H.264 is only encoded for
+/// devices and renditions, `--codec` doesn't offer it. VP9 is only encoded
+/// with `--codec`, into WebM.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum VideoCodec {
+    #[default]
+    Av1,
+    Hevc,
+    #[value(skip)]
+    H264,
+    Vp9,
+}
+
+impl VideoCodec {
+    /// The codec of ffprobe's name, if it can be encoded to.
+    pub fn from_name(name: &str) -> Option<VideoCodec> {
+        [
+            VideoCodec::Av1,
+            VideoCodec::Hevc,
+            VideoCodec::H264,
+            VideoCodec::Vp9,
+        ]
+        .into_iter()
+        .find(|codec| codec.name() == name)
+    }
+
+    /// The name ffprobe reports for the codec.
+    pub fn name(self) -> &'static str {
+        match self {
+            VideoCodec::Av1 => "av1",
+            VideoCodec::Hevc => "hevc",
+            VideoCodec::H264 => "h264",
+            VideoCodec::Vp9 => "vp9",
+        }
+    }
+
+    /// The CRF used when none is given. The AV1 default comes from the config.
+    pub fn default_crf(self) -> Option<u8> {
+        match self {
+            VideoCodec::Av1 => None,
+            VideoCodec::Hevc => Some(28),
+            VideoCodec::H264 => Some(23),
+            VideoCodec::Vp9 => Some(31),
+        }
+    }
+
+    /// The container the outputs are written in.
+    pub fn container(self) -> Container {
+        match self {
+            VideoCodec::Vp9 => Container::WebM,
+            _ => Container::Mp4,
+        }
+    }
+
+    /// The level of a stream, from the number ffprobe reports as `level`.
+    fn level(self, level: i64) -> Option<CodecLevel> {
+        match self {
+            VideoCodec::Av1 => Level::from_seq_level_idx(level).map(CodecLevel::from),
+            // level_idc is ten times the level
+            VideoCodec::H264 if level > 0 => Some(CodecLevel {
+                major: (level / 10) as u8,
+                minor: (level % 10) as u8,
+            }),
+            // general_level_idc is thirty times the level
+            VideoCodec::Hevc if level > 0 => Some(CodecLevel {
+                major: (level / 30) as u8,
+                minor: (level % 30 / 3) as u8,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for VideoCodec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The container of an output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    Mp4,
+    /// For VP9, which older browsers only play in WebM.
+    WebM,
+}
+
+impl Container {
+    pub const ALL: [Container; 2] = [Container::Mp4, Container::WebM];
+
+    /// The extension of the files, which ffmpeg picks the muxer by.
+    pub fn extension(self) -> &'static str {
+        match self {
+            Container::Mp4 => "mp4",
+            Container::WebM => "webm",
+        }
+    }
+
+    /// The encoder of re-encoded audio when none is given.
+    pub fn default_audio_encoder(self) -> &'static str {
+        match self {
+            Container::Mp4 => "aac",
+            Container::WebM => "libopus",
+        }
+    }
+
+    /// The audio codecs the container takes, as ffprobe names them. Any
+    /// codec when empty.
+    pub fn audio_codecs(self) -> &'static [&'static str] {
+        match self {
+            Container::Mp4 => &[],
+            Container::WebM => &["opus", "vorbis"],
+        }
+    }
+}
+
+impl fmt::Display for Container {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.extension())
+    }
+}
+
+/// A level of any of the codecs, like 4.1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct CodecLevel {
+    pub major: u8,
+    pub minor: u8,
+}
+
+impl FromStr for CodecLevel {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let error = || format!("invalid level '{value}', expected e.g. 4.1");
+        let (major, minor) = value.trim().split_once('.').unwrap_or((value.trim(), "0"));
+        Ok(CodecLevel {
+            major: major.parse().map_err(|_| error())?,
+            minor: minor.parse().map_err(|_| error())?,
+        })
+    }
+}
+
+impl TryFrom<String> for CodecLevel {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl From<CodecLevel> for String {
+    fn from(level: CodecLevel) -> Self {
+        level.to_string()
+    }
+}
+
+impl fmt::Display for CodecLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// Video in one codec that a device plays.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct VideoLimits {
+    pub codec: VideoCodec,
+    pub max_level: Option<CodecLevel>,
+    /// The profiles as ffprobe names them, e.g. `main 10`, compared ignoring
+    /// case. Any profile when empty.
+    #[serde(default)]
+    pub profiles: Vec<String>,
+}
+
+impl VideoLimits {
+    /// The profile the encoder targets: the first one with the bit depth that
+    /// is encoded, or the first one at all.
+    pub fn encoder_profile(&self, ten_bit: bool) -> Option<&str> {
+        self.profiles
+            .iter()
+            .find(|profile| profile.contains("10") == ten_bit)
+            .or(self.profiles.first())
+            .map(String::as_str)
+    }
+
+    /// Profile and level arguments of the H.264 and HEVC encoders. The AV1
+    /// encoders get them from the [`Constraints`](crate::constraints::Constraints).
+    pub fn encoder_args(&self, gpu: Option<&GpuMode>, ten_bit: bool) -> Vec<String> {
+        let mut args = vec![];
+        if self.codec == VideoCodec::Av1 {
+            return args;
+        }
+        if let Some(profile) = self.encoder_profile(ten_bit) {
+            // the encoders name them without spaces, and constrained baseline
+            // is what they encode for baseline
+            let profile = profile
+                .to_lowercase()
+                .replace("constrained ", "")
+                .replace(' ', "");
+            args.extend(["-profile:v".into(), profile]);
+        }
+        if let Some(level) = self.max_level {
+            match (gpu, self.codec) {
+                (None, VideoCodec::Hevc) => {
+                    args.extend(["-x265-params".into(), format!("level-idc={level}")])
+                }
+                _ => args.extend(["-level".into(), level.to_string()]),
+            }
+        }
+        args
+    }
+
+    /// What is wrong with a video stream of this codec.
+    fn violations(&self, stream: &Stream) -> Vec<Violation> {
+        let mut violations = vec![];
+        let profile = stream.profile.as_deref();
+        if !self.profiles.is_empty()
+            && !profile.is_some_and(|profile| {
+                self.profiles
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(profile))
+            })
+        {
+            violations.push(Violation::Profile {
+                codec: self.codec,
+                profile: profile.map(String::from),
+            });
+        }
+        if let Some(max) = self.max_level {
+            let level = stream.level.and_then(|level| self.codec.level(level));
+            if level.is_none_or(|level| level > max) {
+                violations.push(Violation::Level {
+                    codec: self.codec,
+                    level,
+                    max,
+                });
+            }
+        }
+        violations
+    }
+}
+
+/// What happens to the subtitles.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SubtitlePolicy {
+    /// The device shows text subtitles, which are kept as `mov_text`. Picture
+    /// subtitles don't play directly and are left out of outputs.
+    #[default]
+    Text,
+    /// The device doesn't show embedded subtitles, outputs don't have any.
+    Drop,
+}
+
+/// Everything a device plays directly, without a media server converting it.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct DeviceProfile {
+    /// The key of the profile, filled in by [`find`].
+    #[serde(skip)]
+    pub name: String,
+    #[serde(default)]
+    pub description: Option<String>,
+    /// The first entry is what files are encoded to.
+    pub video: Vec<VideoLimits>,
+    /// Containers by the names of [`FfProbe::container`]. Must include mp4,
+    /// which all outputs are.
+    pub containers: Vec<String>,
+    /// Pixel formats of the video, any when empty.
+    #[serde(default)]
+    pub pix_fmts: Vec<String>,
+    /// Audio codecs by their ffprobe names, any when empty.
+    #[serde(default)]
+    pub audio_codecs: Vec<String>,
+    #[serde(default)]
+    pub subtitles: SubtitlePolicy,
+}
+
+/// A reason that a file doesn't play directly on a device.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Violation {
+    Container(String),
+    NoVideo,
+    VideoCodec(String),
+    Profile {
+        codec: VideoCodec,
+        profile: Option<String>,
+    },
+    Level {
+        codec: VideoCodec,
+        level: Option<CodecLevel>,
+        max: CodecLevel,
+    },
+    PixelFormat(Option<String>),
+    /// An audio stream, by its index among the audio streams.
+    AudioCodec {
+        index: usize,
+        codec: String,
+    },
+    /// A picture subtitle stream, by its index among the subtitle streams.
+    PictureSubtitles {
+        index: usize,
+        codec: String,
+    },
+}
+
+impl Violation {
+    /// Whether the video has to be encoded again to fix it, instead of only
+    /// being copied into a new file.
+    pub fn needs_encode(&self) -> bool {
+        matches!(
+            self,
+            Violation::NoVideo
+                | Violation::VideoCodec(_)
+                | Violation::Profile { .. }
+                | Violation::Level { .. }
+                | Violation::PixelFormat(_)
+        )
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::Container(container) => write!(f, "the container is {container}"),
+            Violation::NoVideo => write!(f, "there is no video stream"),
+            Violation::VideoCodec(codec) => write!(f, "the video is {codec}"),
+            Violation::Profile { codec, profile } => write!(
+                f,
+                "the {codec} profile is {}",
+                profile.as_deref().unwrap_or("unknown")
+            ),
+            Violation::Level {
+                codec,
+                level: Some(level),
+                max,
+            } => write!(f, "the {codec} level is {level}, above {max}"),
+            Violation::Level {
+                codec,
+                level: None,
+                max,
+            } => write!(f, "the {codec} level is unknown, expected at most {max}"),
+            Violation::PixelFormat(pix_fmt) => write!(
+                f,
+                "the pixel format is {}",
+                pix_fmt.as_deref().unwrap_or("unknown")
+            ),
+            Violation::AudioCodec { index, codec } => {
+                write!(f, "audio track {index} is {codec}")
+            }
+            Violation::PictureSubtitles { index, codec } => {
+                write!(f, "subtitle track {index} is pictures ({codec})")
+            }
+        }
+    }
+}
+
+/// The violations as one line for logs and errors.
+pub fn describe(violations: &[Violation]) -> String {
+    violations
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// What a file needs to play on a device.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Conformance {
+    /// The file plays as it is.
+    Conforms,
+    /// The video plays, but the file has to be written again with other audio,
+    /// subtitles or container.
+    Remux(Vec<Violation>),
+    Encode(Vec<Violation>),
+}
+
+impl DeviceProfile {
+    /// The video that files are encoded to.
+    pub fn target(&self) -> &VideoLimits {
+        &self.video[0]
+    }
+
+    /// The codecs of the video the device plays.
+    pub fn codecs(&self) -> Vec<VideoCodec> {
+        self.video.iter().map(|video| video.codec).collect()
+    }
+
+    /// Whether the device plays 10-bit video.
+    pub fn plays_ten_bit(&self) -> bool {
+        self.pix_fmts.is_empty() || self.pix_fmts.iter().any(|pix_fmt| pix_fmt == "yuv420p10le")
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.video.is_empty() {
+            bail!("device {} doesn't play any video", self.name);
+        }
+        if !self
+            .containers
+            .iter()
+            .any(|c| c == DEVICE_CONTAINER.extension())
+        {
+            bail!(
+                "device {} doesn't play {DEVICE_CONTAINER}, which all outputs for devices are",
+                self.name
+            );
+        }
+        if self.target().codec.container() != DEVICE_CONTAINER {
+            bail!(
+                "device {} is encoded to {}, which is only written to {}: list another codec first",
+                self.name,
+                self.target().codec,
+                self.target().codec.container()
+            );
+        }
+        Ok(())
+    }
+
+    /// Checks the stored probe of a file against the profile and describes
+    /// every reason it doesn't play directly.
+    pub fn violations(&self, info: &FfProbe) -> Vec<Violation> {
+        let mut violations = vec![];
+        let container = info.container();
+        if !self.containers.contains(&container) {
+            violations.push(Violation::Container(container));
+        }
+
+        match info
+            .streams
+            .iter()
+            .find(|s| s.codec_type.as_deref() == Some("video"))
+        {
+            None => violations.push(Violation::NoVideo),
+            Some(stream) => {
+                let codec = stream.codec_name.as_deref().unwrap_or_default();
+                match self.video.iter().find(|video| video.codec.name() == codec) {
+                    Some(video) => violations.extend(video.violations(stream)),
+                    None => violations.push(Violation::VideoCodec(codec.into())),
+                }
+                if !self.pix_fmts.is_empty()
+                    && !stream
+                        .pix_fmt
+                        .as_ref()
+                        .is_some_and(|pix_fmt| self.pix_fmts.contains(pix_fmt))
+                {
+                    violations.push(Violation::PixelFormat(stream.pix_fmt.clone()));
+                }
+            }
+        }
+
+        let audio = info.streams.iter().filter(|s| s.is_audio());
+        for (index, stream) in audio.enumerate() {
+            let codec = stream.codec_name.clone().unwrap_or_default();
+            if !self.audio_codecs.is_empty() && !self.audio_codecs.contains(&codec) {
+                violations.push(Violation::AudioCodec { index, codec });
+            }
+        }
+
+        if self.subtitles == SubtitlePolicy::Text {
+            for (index, codec) in subtitle_codecs(info).into_iter().enumerate() {
+                if !TEXT_SUBTITLES.contains(&codec) {
+                    violations.push(Violation::PictureSubtitles {
+                        index,
+                        codec: codec.into(),
+                    });
+                }
+            }
+        }
+        violations
+    }
+
+    pub fn conformance(&self, info: &FfProbe) -> Conformance {
+        let violations = self.violations(info);
+        if violations.is_empty() {
+            Conformance::Conforms
+        } else if violations.iter().any(Violation::needs_encode) {
+            Conformance::Encode(violations)
+        } else {
+            Conformance::Remux(violations)
+        }
+    }
+
+    /// The audio arguments with the streams mapped one by one, followed by
+    /// the subtitles the device shows. ffmpeg's default selection would pick
+    /// the first subtitle stream, even if it is pictures.
+    pub fn stream_args(&self, info: &FfProbe, audio_args: &[String]) -> Vec<String> {
+        let mut args = vec![];
+        if !audio_args.iter().any(|arg| arg == "-map") {
+            args.extend(["-map".into(), "0:v:0".into()]);
+            if !audio_args.iter().any(|arg| arg == "-an") {
+                args.extend(["-map".into(), "0:a?".into()]);
+            }
+        }
+        args.extend(audio_args.iter().cloned());
+        let text: Vec<_> = subtitle_codecs(info)
+            .into_iter()
+            .enumerate()
+            .filter(|(_, codec)| TEXT_SUBTITLES.contains(codec))
+            .map(|(index, _)| index)
+            .collect();
+        if self.subtitles == SubtitlePolicy::Drop || text.is_empty() {
+            args.push("-sn".into());
+        } else {
+            for index in text {
+                args.extend(["-map".into(), format!("0:s:{index}")]);
+            }
+            args.extend(["-c:s".into(), "mov_text".into()]);
+        }
+        args
+    }
+}
+
+/// The codecs of the subtitle streams, in order.
+fn subtitle_codecs(info: &FfProbe) -> Vec<&str> {
+    info.streams
+        .iter()
+        .filter(|s| s.codec_type.as_deref() == Some("subtitle"))
+        .map(|s| s.codec_name.as_deref().unwrap_or_default())
+        .collect()
+}
+
+/// The built-in profiles by name.
+pub fn builtin() -> BTreeMap<String, DeviceProfile> {
+    toml::from_str(BUILTIN_PROFILES).expect("the built-in device profiles are valid")
+}
+
+/// The profile of that name from the config file, or else the built-in one.
+pub fn find(name: &str, configured: &BTreeMap<String, DeviceProfile>) -> Result<DeviceProfile> {
+    let mut profiles = builtin();
+    profiles.extend(configured.clone());
+    let names: Vec<_> = profiles.keys().cloned().collect();
+    let mut profile = profiles.remove(name).ok_or_else(|| {
+        eyre!(
+            "unknown device {name}, the known ones are {}",
+            names.join(", ")
+        )
+    })?;
+    profile.name = name.into();
+    profile.validate()?;
+    Ok(profile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffprobe::fixtures;
+
+    fn profile(name: &str) -> DeviceProfile {
+        find(name, &BTreeMap::new()).unwrap()
+    }
+
+    /// 1080p H.264 High 4.0 with AAC in MP4.
+    fn h264_aac() -> FfProbe {
+        fixtures()[0].clone()
+    }
+
+    /// 4K HEVC Main 10 5.1 with AC-3 and SubRip in Matroska.
+    fn hevc_ac3_subtitles() -> FfProbe {
+        fixtures()[1].clone()
+    }
+
+    /// The first fixture with its video as AV1.
+    fn av1(profile: &str, seq_level_idx: i64) -> FfProbe {
+        let mut info = h264_aac();
+        let video = &mut info.streams[0];
+        video.codec_name = Some("av1".into());
+        video.profile = Some(profile.into());
+        video.level = Some(seq_level_idx);
+        info
+    }
+
+    #[test]
+    fn test_builtin_profiles() {
+        for (name, profile) in builtin() {
+            assert!(find(&name, &BTreeMap::new()).is_ok(), "{name}");
+            assert!(profile.description.is_some(), "{name}");
+        }
+        assert_eq!(VideoCodec::Av1, profile("chromecast").target().codec);
+        assert_eq!(VideoCodec::Hevc, profile("appletv4k").target().codec);
+        assert_eq!(vec![VideoCodec::H264], profile("webh264").codecs());
+        let error = find("toaster", &BTreeMap::new()).unwrap_err();
+        assert!(
+            error.to_string().contains("appletv4k, chromecast, webh264"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn test_codec_level() {
+        assert_eq!(Some("4.0".parse().unwrap()), VideoCodec::H264.level(40));
+        assert_eq!(Some("4.1".parse().unwrap()), VideoCodec::H264.level(41));
+        assert_eq!(Some("5.1".parse().unwrap()), VideoCodec::Hevc.level(153));
+        assert_eq!(Some("4.1".parse().unwrap()), VideoCodec::Hevc.level(123));
+        assert_eq!(Some("4.0".parse().unwrap()), VideoCodec::Hevc.level(120));
+        assert_eq!(Some("5.1".parse().unwrap()), VideoCodec::Av1.level(13));
+        assert_eq!(None, VideoCodec::Av1.level(31));
+        assert_eq!(None, VideoCodec::H264.level(-99));
+        assert!("4.2".parse::<CodecLevel>().unwrap() > "4.1".parse().unwrap());
+        assert!("high".parse::<CodecLevel>().is_err());
+    }
+
+    #[test]
+    fn test_chromecast() {
+        let chromecast = profile("chromecast");
+        assert_eq!(Conformance::Conforms, chromecast.conformance(&h264_aac()));
+        assert_eq!(
+            Conformance::Conforms,
+            chromecast.conformance(&av1("Main", 12))
+        );
+        // 4K HEVC Main 10 in Matroska with AC-3 and SubRip plays as it is
+        assert_eq!(
+            Conformance::Conforms,
+            chromecast.conformance(&hevc_ac3_subtitles())
+        );
+
+        assert_eq!(
+            Conformance::Encode(vec![
+                Violation::Profile {
+                    codec: VideoCodec::Av1,
+                    profile: Some("High".into())
+                },
+                Violation::Level {
+                    codec: VideoCodec::Av1,
+                    level: Some("6.0".parse().unwrap()),
+                    max: "5.1".parse().unwrap()
+                }
+            ]),
+            chromecast.conformance(&av1("High", 16))
+        );
+
+        let mut pgs = hevc_ac3_subtitles();
+        pgs.streams[2].codec_name = Some("hdmv_pgs_subtitle".into());
+        assert_eq!(
+            Conformance::Remux(vec![Violation::PictureSubtitles {
+                index: 0,
+                codec: "hdmv_pgs_subtitle".into()
+            }]),
+            chromecast.conformance(&pgs)
+        );
+    }
+
+    #[test]
+    fn test_appletv4k() {
+        let appletv = profile("appletv4k");
+        assert_eq!(Conformance::Conforms, appletv.conformance(&h264_aac()));
+        // the video plays, only the container needs to change
+        assert_eq!(
+            Conformance::Remux(vec![Violation::Container("matroska".into())]),
+            appletv.conformance(&hevc_ac3_subtitles())
+        );
+        assert_eq!(
+            Conformance::Encode(vec![Violation::VideoCodec("av1".into())]),
+            appletv.conformance(&av1("Main", 8))
+        );
+
+        let mut flac = h264_aac();
+        flac.streams[1].codec_name = Some("flac".into());
+        assert_eq!(
+            Conformance::Remux(vec![Violation::AudioCodec {
+                index: 0,
+                codec: "flac".into()
+            }]),
+            appletv.conformance(&flac)
+        );
+    }
+
+    #[test]
+    fn test_webh264() {
+        let web = profile("webh264");
+        assert_eq!(Conformance::Conforms, web.conformance(&h264_aac()));
+        assert_eq!(
+            Conformance::Encode(vec![
+                Violation::Container("matroska".into()),
+                Violation::VideoCodec("hevc".into()),
+                Violation::PixelFormat(Some("yuv420p10le".into())),
+                Violation::AudioCodec {
+                    index: 0,
+                    codec: "ac3".into()
+                },
+            ]),
+            web.conformance(&hevc_ac3_subtitles())
+        );
+
+        let mut high_level = h264_aac();
+        high_level.streams[0].level = Some(51);
+        high_level.streams[0].profile = Some("High 10".into());
+        high_level.streams[0].pix_fmt = Some("yuv420p10le".into());
+        let violations = web.violations(&high_level);
+        assert_eq!(
+            vec![
+                "the h264 profile is High 10",
+                "the h264 level is 5.1, above 4.1",
+                "the pixel format is yuv420p10le"
+            ],
+            violations.iter().map(|v| v.to_string()).collect::<Vec<_>>()
+        );
+
+        // without a level, the stream may need any decoder
+        high_level.streams[0].level = None;
+        assert!(web.violations(&high_level).contains(&Violation::Level {
+            codec: VideoCodec::H264,
+            level: None,
+            max: "4.1".parse().unwrap()
+        }));
+        let audio_only = FfProbe {
+            streams: vec![h264_aac().streams[1].clone()],
+            format: h264_aac().format,
+            ..Default::default()
+        };
+        assert_eq!(vec![Violation::NoVideo], web.violations(&audio_only));
+    }
+
+    #[test]
+    fn test_configured_profiles() -> Result<()> {
+        let configured: BTreeMap<String, DeviceProfile> = toml::from_str(
+            r#"
+            [tv]
+            containers = ["mp4"]
+
+            [[tv.video]]
+            codec = "hevc"
+
+            [webh264]
+            containers = ["mp4"]
+            audio-codecs = ["aac"]
+
+            [[webh264.video]]
+            codec = "h264"
+            max-level = "5.1"
+
+            [mkv-only]
+            containers = ["matroska"]
+
+            [[mkv-only.video]]
+            codec = "av1"
+
+            [browser]
+            containers = ["mp4", "webm"]
+
+            [[browser.video]]
+            codec = "vp9"
+            "#,
+        )?;
+        let tv = find("tv", &configured)?;
+        assert_eq!("tv", tv.name);
+        assert_eq!(SubtitlePolicy::Text, tv.subtitles);
+        // without limits, any HEVC in MP4 plays
+        assert_eq!(
+            Conformance::Conforms,
+            tv.conformance(&{
+                let mut info = hevc_ac3_subtitles();
+                info.format.format_name = "mov,mp4,m4a,3gp,3g2,mj2".into();
+                info
+            })
+        );
+
+        // configured profiles replace the built-in ones
+        let web = find("webh264", &configured)?;
+        let mut level_5 = h264_aac();
+        level_5.streams[0].level = Some(50);
+        assert_eq!(Conformance::Conforms, web.conformance(&level_5));
+
+        let error = find("mkv-only", &configured).unwrap_err();
+        assert!(error.to_string().contains("doesn't play mp4"), "{error}");
+        let error = find("browser", &configured).unwrap_err();
+        assert!(
+            error.to_string().contains("only written to webm"),
+            "{error}"
+        );
+        assert!(
+            toml::from_str::<DeviceProfile>("containers = [\"mp4\"]\nvideo = []\ncolor = 1")
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_encoder_args() {
+        let appletv = profile("appletv4k");
+        assert_eq!(
+            "-profile:v main -x265-params level-idc=5.1",
+            appletv.target().encoder_args(None, false).join(" ")
+        );
+        assert_eq!(
+            "-profile:v main10 -level 5.1",
+            appletv
+                .target()
+                .encoder_args(Some(&GpuMode::Nvidia), true)
+                .join(" ")
+        );
+        let web = profile("webh264");
+        assert_eq!(
+            "-profile:v high -level 4.1",
+            web.target().encoder_args(None, false).join(" ")
+        );
+        assert_eq!(Some("high"), web.target().encoder_profile(true));
+        let baseline = VideoLimits {
+            codec: VideoCodec::H264,
+            max_level: None,
+            profiles: vec!["Constrained Baseline".into()],
+        };
+        assert_eq!(
+            "-profile:v baseline",
+            baseline.encoder_args(None, false).join(" ")
+        );
+        assert!(
+            profile("chromecast")
+                .target()
+                .encoder_args(None, false)
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_stream_args() {
+        let subtitles = hevc_ac3_subtitles();
+        let chromecast = profile("chromecast");
+        let copy = ["-c:a".to_string(), "copy".to_string()];
+        assert_eq!(
+            "-map 0:v:0 -map 0:a? -c:a copy -map 0:s:0 -c:s mov_text",
+            chromecast.stream_args(&subtitles, &copy).join(" ")
+        );
+        let mapped: Vec<String> = ["-map", "0:v:0", "-map", "0:a:1", "-c:a:0", "copy"]
+            .map(String::from)
+            .into();
+        assert_eq!(
+            "-map 0:v:0 -map 0:a:1 -c:a:0 copy -map 0:s:0 -c:s mov_text",
+            chromecast.stream_args(&subtitles, &mapped).join(" ")
+        );
+        assert_eq!(
+            "-map 0:v:0 -an -sn",
+            chromecast
+                .stream_args(&h264_aac(), &["-an".into()])
+                .join(" ")
+        );
+
+        // picture subtitles are left out
+        let mut pgs = hevc_ac3_subtitles();
+        pgs.streams[2].codec_name = Some("hdmv_pgs_subtitle".into());
+        assert!(
+            chromecast
+                .stream_args(&pgs, &copy)
+                .ends_with(&["-sn".into()])
+        );
+        assert_eq!(
+            "-map 0:v:0 -map 0:a? -c:a copy -sn",
+            profile("webh264").stream_args(&subtitles, &copy).join(" ")
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use camino::Utf8PathBuf;
+use serde::Serialize;
+
+/// The oldest schema version a snapshot can have to be compared, the one that
+/// stores paths in the same Unicode normalization as now.
+pub const MIN_SCHEMA_VERSION: usize = 11;
+
+/// A snapshot from a version that is too old to compare with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotTooOld {
+    /// The number of migrations applied to the snapshot.
+    pub found: usize,
+}
+
+impl fmt::Display for SnapshotTooOld {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the snapshot has database schema v{}, but at least v{MIN_SCHEMA_VERSION} is needed to compare it; open it once with this version of transcoder (e.g. `transcoder --database <snapshot> stats`) to migrate it",
+            self.found
+        )
+    }
+}
+
+impl std::error::Error for SnapshotTooOld {}
+
+/// A file in one snapshot.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FileState {
+    pub status: String,
+    pub file_size: u64,
+}
+
+/// How a file differs between the old snapshot and the current database.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FileChange {
+    pub path: Utf8PathBuf,
+    /// `None` when the file was added since the snapshot.
+    pub old: Option<FileState>,
+    /// `None` when the file was removed since the snapshot.
+    pub new: Option<FileState>,
+}
+
+impl FileChange {
+    pub fn kind(&self) -> &'static str {
+        match (&self.old, &self.new) {
+            (None, _) => "added",
+            (_, None) => "removed",
+            (Some(old), Some(new)) if old.status != new.status => "status",
+            _ => "size",
+        }
+    }
+}
+
+/// What changed between an old snapshot of the database and the current one.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SnapshotDiff {
+    pub added: usize,
+    pub removed: usize,
+    pub status_changed: usize,
+    /// Files whose size changed without their status changing.
+    pub size_changed: usize,
+    /// How many files went from one status to another, e.g. `pending -> success`.
+    pub transitions: BTreeMap<String, usize>,
+    /// Bytes saved by transcoded files in the old snapshot.
+    pub old_saved: u64,
+    /// Bytes saved by transcoded files now.
+    pub new_saved: u64,
+    pub files: Vec<FileChange>,
+}
+
+impl SnapshotDiff {
+    /// Sorts the changes by path and counts them.
+    pub fn new(mut files: Vec<FileChange>, old_saved: u64, new_saved: u64) -> Self {
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        let mut diff = SnapshotDiff {
+            old_saved,
+            new_saved,
+            ..Default::default()
+        };
+        for file in &files {
+            match (&file.old, &file.new) {
+                (None, _) => diff.added += 1,
+                (_, None) => diff.removed += 1,
+                (Some(old), Some(new)) if old.status != new.status => {
+                    diff.status_changed += 1;
+                    *diff
+                        .transitions
+                        .entry(format!("{} -> {}", old.status, new.status))
+                        .or_default() += 1;
+                }
+                _ => diff.size_changed += 1,
+            }
+        }
+        diff.files = files;
+        diff
+    }
+
+    /// How many more bytes are saved now than in the snapshot.
+    pub fn saved_since(&self) -> i64 {
+        self.new_saved as i64 - self.old_saved as i64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty() && self.old_saved == self.new_saved
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(status: &str, file_size: u64) -> Option<FileState> {
+        Some(FileState {
+            status: status.into(),
+            file_size,
+        })
+    }
+
+    #[test]
+    fn test_counts() {
+        let change = |path: &str, old, new| FileChange {
+            path: path.into(),
+            old,
+            new,
+        };
+        let diff = SnapshotDiff::new(
+            vec![
+                change("/c.mkv", state("pending", 5), state("success", 5)),
+                change("/a.mkv", None, state("pending", 5)),
+                change("/b.mkv", state("pending", 5), None),
+                change("/d.mkv", state("pending", 5), state("success", 5)),
+                change("/e.mkv", state("error", 5), state("error", 7)),
+            ],
+            100,
+            40,
+        );
+        assert_eq!(
+            (1, 1, 2, 1),
+            (
+                diff.added,
+                diff.removed,
+                diff.status_changed,
+                diff.size_changed
+            )
+        );
+        assert_eq!(
+            BTreeMap::from([("pending -> success".to_string(), 2)]),
+            diff.transitions
+        );
+        let kinds: Vec<_> = diff.files.iter().map(FileChange::kind).collect();
+        assert_eq!(vec!["added", "removed", "status", "status", "size"], kinds);
+        assert_eq!(-60, diff.saved_since());
+        assert!(!diff.is_empty());
+        assert!(SnapshotDiff::new(vec![], 3, 3).is_empty());
+    }
+}
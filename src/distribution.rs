@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+
+/// How many files fall into one group and how big they are together.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Share {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// Files grouped by a key like the codec or the resolution tier, as shown by
+/// `stats`.
+#[derive(Debug, Clone)]
+pub struct Distribution<K> {
+    groups: BTreeMap<K, Share>,
+    total: Share,
+}
+
+impl<K: Ord> Default for Distribution<K> {
+    fn default() -> Self {
+        Distribution {
+            groups: BTreeMap::new(),
+            total: Share::default(),
+        }
+    }
+}
+
+impl<K: Ord> Distribution<K> {
+    /// Counts one file of `bytes` for `key`.
+    pub fn insert(&mut self, key: K, bytes: u64) {
+        let share = self.groups.entry(key).or_default();
+        share.files += 1;
+        share.bytes += bytes;
+        self.total.files += 1;
+        self.total.bytes += bytes;
+    }
+
+    pub fn total(&self) -> Share {
+        self.total
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    /// The groups with the most files first. Groups with as many files are
+    /// ordered by size, then by key.
+    pub fn sorted(&self) -> Vec<(&K, Share)> {
+        let mut groups: Vec<_> = self
+            .groups
+            .iter()
+            .map(|(key, &share)| (key, share))
+            .collect();
+        // the sort is stable, so equal groups stay in key order
+        groups.sort_by(|(_, a), (_, b)| b.files.cmp(&a.files).then(b.bytes.cmp(&a.bytes)));
+        groups
+    }
+
+    /// Share of all files in percent.
+    pub fn files_percent(&self, share: Share) -> f64 {
+        percent(share.files, self.total.files)
+    }
+
+    /// Share of all bytes in percent.
+    pub fn bytes_percent(&self, share: Share) -> f64 {
+        percent(share.bytes, self.total.bytes)
+    }
+}
+
+impl<K: Ord> FromIterator<(K, u64)> for Distribution<K> {
+    fn from_iter<I: IntoIterator<Item = (K, u64)>>(iter: I) -> Self {
+        let mut distribution = Distribution::default();
+        for (key, bytes) in iter {
+            distribution.insert(key, bytes);
+        }
+        distribution
+    }
+}
+
+fn percent(part: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 / total as f64 * 100.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn share(files: u64, bytes: u64) -> Share {
+        Share { files, bytes }
+    }
+
+    #[test]
+    fn test_sorted_by_count() {
+        let distribution: Distribution<&str> = [
+            ("hevc", 10),
+            ("h264", 100),
+            ("av1", 5),
+            ("h264", 200),
+            ("h264", 300),
+            ("hevc", 20),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            vec![
+                (&"h264", share(3, 600)),
+                (&"hevc", share(2, 30)),
+                (&"av1", share(1, 5)),
+            ],
+            distribution.sorted()
+        );
+        assert_eq!(share(6, 635), distribution.total());
+        assert_eq!(50.0, distribution.files_percent(share(3, 600)));
+        assert!((distribution.bytes_percent(share(2, 127)) - 20.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_ties() {
+        let distribution: Distribution<&str> =
+            [("vp9", 10), ("mpeg4", 10), ("h264", 10), ("hevc", 50)]
+                .into_iter()
+                .collect();
+        let keys: Vec<_> = distribution.sorted().into_iter().map(|(k, _)| *k).collect();
+        // the bigger group wins a tie, then the key decides
+        assert_eq!(vec!["hevc", "h264", "mpeg4", "vp9"], keys);
+    }
+
+    #[test]
+    fn test_empty() {
+        let distribution = Distribution::<String>::default();
+        assert!(distribution.is_empty());
+        assert!(distribution.sorted().is_empty());
+        assert_eq!(Share::default(), distribution.total());
+        assert_eq!(0.0, distribution.files_percent(Share::default()));
+        assert_eq!(0.0, distribution.bytes_percent(Share::default()));
+
+        // files without any bytes don't divide by zero either
+        let distribution: Distribution<&str> = [("h264", 0)].into_iter().collect();
+        assert_eq!(100.0, distribution.files_percent(distribution.total()));
+        assert_eq!(0.0, distribution.bytes_percent(distribution.total()));
+    }
+}
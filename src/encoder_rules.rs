@@ -0,0 +1,346 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::codecs::Operator;
+use crate::collect::{self, VideoFile};
+use crate::config::TranscodeSettings;
+use crate::transcode::GpuMode;
+
+/// The encoder a rule picks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoder {
+    /// libsvtav1
+    Cpu,
+    Nvidia,
+    Qsv,
+    Vaapi,
+    Amf,
+}
+
+impl Encoder {
+    pub fn from_gpu(gpu: Option<&GpuMode>) -> Self {
+        match gpu {
+            None => Encoder::Cpu,
+            Some(GpuMode::Nvidia) => Encoder::Nvidia,
+            Some(GpuMode::Qsv) => Encoder::Qsv,
+            Some(GpuMode::Vaapi) => Encoder::Vaapi,
+            Some(GpuMode::Amf) => Encoder::Amf,
+        }
+    }
+
+    pub fn gpu(self) -> Option<GpuMode> {
+        match self {
+            Encoder::Cpu => None,
+            Encoder::Nvidia => Some(GpuMode::Nvidia),
+            Encoder::Qsv => Some(GpuMode::Qsv),
+            Encoder::Vaapi => Some(GpuMode::Vaapi),
+            Encoder::Amf => Some(GpuMode::Amf),
+        }
+    }
+}
+
+/// A comparison with a number, like `<0.08` or `>=50`. Resolutions can be
+/// written as `>=2160p`.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Comparison {
+    operator: Operator,
+    value: f64,
+}
+
+impl FromStr for Comparison {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (operator, value) = Operator::split(s.trim())
+            .ok_or_else(|| format!("expected a comparison like >=50 in '{s}'"))?;
+        let value = value.trim();
+        let value = value
+            .strip_suffix(['p', 'P'])
+            .unwrap_or(value)
+            .parse()
+            .map_err(|_| format!("invalid number '{value}' in '{s}'"))?;
+        Ok(Comparison { operator, value })
+    }
+}
+
+impl TryFrom<String> for Comparison {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Comparison {
+    /// Unknown values never match.
+    fn matches(&self, value: Option<f64>) -> bool {
+        value.is_some_and(|v| self.operator.compare(v, self.value))
+    }
+}
+
+/// Whether `path` matches a glob pattern. `*` and `?` don't match across a `/`,
+/// `**` matches any number of directories.
+pub fn glob_matches(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let path: Vec<char> = path.chars().collect();
+    glob_match(&pattern, &path)
+}
+
+fn glob_match(pattern: &[char], path: &[char]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        ['*', '*', '/', rest @ ..] => {
+            glob_match(rest, path)
+                || path
+                    .iter()
+                    .enumerate()
+                    .any(|(index, &c)| c == '/' && glob_match(rest, &path[index + 1..]))
+        }
+        ['*', '*', rest @ ..] => (0..=path.len()).any(|index| glob_match(rest, &path[index..])),
+        ['*', rest @ ..] => (0..=path.len())
+            .take_while(|&index| index == 0 || path[index - 1] != '/')
+            .any(|index| glob_match(rest, &path[index..])),
+        ['?', rest @ ..] => path.first().is_some_and(|&c| c != '/') && glob_match(rest, &path[1..]),
+        [c, rest @ ..] => path.first() == Some(c) && glob_match(rest, &path[1..]),
+    }
+}
+
+/// One `[[encoder-rules]]` entry of the config file. A file matches when it
+/// matches all of the conditions that are given.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncoderRule {
+    /// Shown in the logs and recorded for the files, `rule <n>` if not set.
+    pub name: Option<String>,
+    /// The codec of the source, compared case-insensitively.
+    pub codec: Option<String>,
+    /// Lines of the picture at 16:9, as for the resolution tiers.
+    pub resolution: Option<Comparison>,
+    pub fps: Option<Comparison>,
+    pub bpp: Option<Comparison>,
+    /// Glob pattern for the absolute path, e.g. `**/Screencasts/**`.
+    pub path: Option<String>,
+    /// The encoder for matching files. Keeps the one from the command line if
+    /// not set.
+    pub encoder: Option<Encoder>,
+    /// Encoding settings that take precedence over the config file's.
+    pub transcode: TranscodeSettings,
+}
+
+impl EncoderRule {
+    pub fn matches(&self, file: &VideoFile) -> bool {
+        let frame_rate = Some(file.frame_rate).filter(|&fps| fps > 0.0);
+        let lines = Some(collect::lines(file.resolution) as f64).filter(|&lines| lines > 0.0);
+        self.codec
+            .as_ref()
+            .is_none_or(|codec| codec.eq_ignore_ascii_case(&file.codec))
+            && self.resolution.is_none_or(|c| c.matches(lines))
+            && self.fps.is_none_or(|c| c.matches(frame_rate))
+            && self.bpp.is_none_or(|c| c.matches(file.bits_per_pixel()))
+            && self
+                .path
+                .as_ref()
+                .is_none_or(|pattern| glob_matches(pattern, file.path.as_str()))
+    }
+}
+
+/// A rule that matched a file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchedRule<'a> {
+    pub name: String,
+    pub rule: &'a EncoderRule,
+}
+
+impl fmt::Display for MatchedRule<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+/// The encoder rules, in the order they are tried.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(transparent)]
+pub struct EncoderRules(Vec<EncoderRule>);
+
+impl EncoderRules {
+    /// The first rule that matches the file.
+    pub fn first_match(&self, file: &VideoFile) -> Option<MatchedRule<'_>> {
+        self.0
+            .iter()
+            .enumerate()
+            .find(|(_, rule)| rule.matches(file))
+            .map(|(index, rule)| MatchedRule {
+                name: rule
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| format!("rule {}", index + 1)),
+                rule,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::database::TranscodeStatus;
+
+    fn video(path: &str, codec: &str, resolution: (u32, u32), bitrate: u64) -> VideoFile {
+        VideoFile {
+            id: 1,
+            path: path.into(),
+            duration: 60.0,
+            resolution,
+            bitrate,
+            video_bitrate: bitrate,
+            audio_bitrate: 0,
+            frame_rate: 25.0,
+            codec: codec.into(),
+            profile: None,
+            container: "matroska".into(),
+            file_size: 1000,
+            status: TranscodeStatus::Pending,
+            audio_tracks: vec![],
+            pix_fmt: None,
+            start_offset: None,
+            pinned: false,
+            output_path: None,
+            title: None,
+            chapters: vec![],
+        }
+    }
+
+    fn rules(toml: &str) -> EncoderRules {
+        toml::from_str::<Config>(toml).unwrap().encoder_rules
+    }
+
+    #[test]
+    fn test_comparison() {
+        let comparison: Comparison = ">=2160p".parse().unwrap();
+        assert_eq!(Operator::GreaterOrEqual, comparison.operator);
+        assert_eq!(2160.0, comparison.value);
+        let comparison: Comparison = " < 0.08 ".parse().unwrap();
+        assert!(comparison.matches(Some(0.05)));
+        assert!(!comparison.matches(Some(0.08)));
+        assert!(!comparison.matches(None));
+        let comparison: Comparison = "=25".parse().unwrap();
+        assert!(comparison.matches(Some(25.0)));
+
+        for invalid in ["", "50", ">=", ">fast", "~50"] {
+            assert!(invalid.parse::<Comparison>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_glob() {
+        let path = "/mnt/sam/Screencasts/2024/talk.mkv";
+        assert!(glob_matches("**/Screencasts/**", path));
+        assert!(glob_matches("/mnt/*/Screencasts/**/*.mkv", path));
+        assert!(glob_matches("**/*.mkv", path));
+        assert!(glob_matches("**/talk.???", path));
+        assert!(glob_matches("/mnt/sam/Screencasts/2024/talk.mkv", path));
+        assert!(!glob_matches("/mnt/*/talk.mkv", path));
+        assert!(!glob_matches("*.mkv", path));
+        assert!(!glob_matches("**/Films/**", path));
+        assert!(!glob_matches("**/*.mp4", path));
+        // `**/` also matches no directories
+        assert!(glob_matches("/mnt/**/sam/**", path));
+        assert!(glob_matches("**", path));
+    }
+
+    #[test]
+    fn test_parse() {
+        let rules = rules(
+            r#"
+            [[encoder-rules]]
+            name = "screencasts"
+            path = "**/Screencasts/**"
+            encoder = "nvidia"
+            transcode = { crf = 30 }
+
+            [[encoder-rules]]
+            codec = "MPEG2video"
+            resolution = "<=576p"
+            fps = ">=50"
+            bpp = ">0.2"
+            encoder = "cpu"
+            "#,
+        );
+        assert_eq!(2, rules.0.len());
+        assert_eq!(Some(Encoder::Nvidia), rules.0[0].encoder);
+        assert_eq!(Some(30), rules.0[0].transcode.crf);
+        assert_eq!(Some(Encoder::Cpu), rules.0[1].encoder);
+        assert_eq!(
+            Some(Comparison {
+                operator: Operator::LessOrEqual,
+                value: 576.0
+            }),
+            rules.0[1].resolution
+        );
+
+        for invalid in [
+            "[[encoder-rules]]\nencoder = \"amd\"",
+            "[[encoder-rules]]\nfps = \"fast\"",
+            "[[encoder-rules]]\nfps = 30",
+            "[[encoder-rules]]\nheight = \">720\"",
+        ] {
+            assert!(toml::from_str::<Config>(invalid).is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn test_first_match() {
+        let rules = rules(
+            r#"
+            [[encoder-rules]]
+            name = "screencasts"
+            path = "**/Screencasts/**"
+            encoder = "nvidia"
+
+            [[encoder-rules]]
+            name = "grainy film"
+            codec = "h264"
+            resolution = ">=1080p"
+            bpp = ">0.15"
+            encoder = "cpu"
+            transcode = { film-grain = 10 }
+
+            [[encoder-rules]]
+            resolution = "<720"
+            encoder = "qsv"
+            "#,
+        );
+        let name = |file: &VideoFile| rules.first_match(file).map(|m| m.to_string());
+
+        // the first matching rule wins
+        let screencast = video("/v/Screencasts/h264.mkv", "h264", (1920, 1080), 20_000_000);
+        assert_eq!(Some("screencasts".into()), name(&screencast));
+        let film = video("/v/Films/film.mkv", "h264", (1920, 800), 20_000_000);
+        let matched = rules.first_match(&film).unwrap();
+        assert_eq!("grainy film", matched.name);
+        assert_eq!(Some(10), matched.rule.transcode.film_grain);
+        assert_eq!(Some(Encoder::Cpu), matched.rule.encoder);
+        // all conditions have to match
+        let compressed = video("/v/Films/film.mkv", "h264", (1920, 1080), 2_000_000);
+        assert_eq!(None, name(&compressed));
+        let hevc = video("/v/Films/film.mkv", "hevc", (1920, 1080), 20_000_000);
+        assert_eq!(None, name(&hevc));
+        // unnamed rules are numbered
+        let small = video("/v/Old/clip.avi", "mpeg4", (640, 480), 1_000_000);
+        assert_eq!(Some("rule 3".into()), name(&small));
+        // unknown resolutions and bitrates don't match numeric conditions
+        let unknown = video("/v/Films/film.mkv", "h264", (0, 0), 0);
+        assert_eq!(None, name(&unknown));
+
+        assert_eq!(None, EncoderRules::default().first_match(&film));
+        let catch_all = EncoderRules(vec![EncoderRule::default()]);
+        assert_eq!(
+            Some("rule 1".into()),
+            catch_all.first_match(&film).map(|m| m.name)
+        );
+    }
+}
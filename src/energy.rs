@@ -0,0 +1,393 @@
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::Deserialize;
+use tracing::{debug, info};
+
+/// Where Linux exposes the RAPL energy counters.
+const POWERCAP_ROOT: &str = "/sys/class/powercap";
+
+const JOULES_PER_KWH: f64 = 3.6e6;
+
+/// A source of the energy the machine used.
+pub trait EnergyMeter: Send + Sync {
+    /// Energy used since the meter was created, in joules. `None` when the
+    /// source can't be read anymore.
+    fn joules(&self) -> Option<f64>;
+
+    /// Name of the source for the summary.
+    fn source(&self) -> String;
+}
+
+/// One RAPL zone, e.g. a CPU package.
+#[derive(Debug)]
+struct RaplZone {
+    energy: Utf8PathBuf,
+    /// The counter wraps around to zero after this many microjoules.
+    max_range: u64,
+}
+
+#[derive(Debug, Default)]
+struct RaplCounters {
+    last: Vec<u64>,
+    microjoules: u64,
+}
+
+/// Reads the RAPL counters of the CPU packages. They only cover the CPU and
+/// usually the integrated GPU, not discrete GPUs or the rest of the system.
+#[derive(Debug)]
+pub struct RaplMeter {
+    zones: Vec<RaplZone>,
+    counters: Mutex<RaplCounters>,
+}
+
+fn read_u64(path: &Utf8Path) -> Option<u64> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+impl RaplMeter {
+    /// Finds the package zones below `root`. Sub-zones like `intel-rapl:0:0` are
+    /// part of their package and are left out. Returns `None` when there are no
+    /// zones or the counters aren't readable, which they are only for root on
+    /// most distributions.
+    pub fn discover(root: &Utf8Path) -> Option<RaplMeter> {
+        let mut zones: Vec<_> = fs::read_dir(root)
+            .ok()?
+            .filter_map(|entry| Utf8PathBuf::from_path_buf(entry.ok()?.path()).ok())
+            .filter(|path| {
+                path.file_name()
+                    .and_then(|name| name.strip_prefix("intel-rapl:"))
+                    .is_some_and(|zone| !zone.contains(':'))
+            })
+            .filter_map(|path| {
+                Some(RaplZone {
+                    max_range: read_u64(&path.join("max_energy_range_uj"))?,
+                    energy: path.join("energy_uj"),
+                })
+            })
+            .collect();
+        zones.sort_by(|a, b| a.energy.cmp(&b.energy));
+        let last = zones
+            .iter()
+            .map(|zone| read_u64(&zone.energy))
+            .collect::<Option<Vec<_>>>()?;
+        if zones.is_empty() {
+            return None;
+        }
+        debug!("reading energy from {} RAPL zones", zones.len());
+        Some(RaplMeter {
+            zones,
+            counters: Mutex::new(RaplCounters {
+                last,
+                microjoules: 0,
+            }),
+        })
+    }
+}
+
+impl EnergyMeter for RaplMeter {
+    fn joules(&self) -> Option<f64> {
+        let mut counters = self.counters.lock().unwrap();
+        for (index, zone) in self.zones.iter().enumerate() {
+            let value = read_u64(&zone.energy)?;
+            let last = counters.last[index];
+            counters.microjoules += if value >= last {
+                value - last
+            } else {
+                zone.max_range - last + value
+            };
+            counters.last[index] = value;
+        }
+        Some(counters.microjoules as f64 / 1e6)
+    }
+
+    fn source(&self) -> String {
+        "RAPL".into()
+    }
+}
+
+/// Assumes the machine draws a constant power while transcoding.
+#[derive(Debug)]
+pub struct ConstantPower {
+    watts: f64,
+    since: Instant,
+}
+
+impl ConstantPower {
+    pub fn new(watts: f64) -> Self {
+        ConstantPower {
+            watts,
+            since: Instant::now(),
+        }
+    }
+}
+
+impl EnergyMeter for ConstantPower {
+    fn joules(&self) -> Option<f64> {
+        Some(self.since.elapsed().as_secs_f64() * self.watts)
+    }
+
+    fn source(&self) -> String {
+        format!("estimated at {} W", self.watts)
+    }
+}
+
+/// Power draw estimates per encoder, from the `[energy.watts]` section of the
+/// config file.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncoderWatts {
+    pub cpu: Option<f64>,
+    pub nvidia: Option<f64>,
+    pub qsv: Option<f64>,
+    pub vaapi: Option<f64>,
+    pub amf: Option<f64>,
+}
+
+/// The `[energy]` section of the config file.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct EnergyConfig {
+    pub kwh_price: Option<f64>,
+    pub watts: EncoderWatts,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EnergyOptions {
+    /// Estimated power draw of the encoder in use, preferred over RAPL.
+    pub watts: Option<f64>,
+    /// Price of a kWh, for the cost in the summary.
+    pub kwh_price: Option<f64>,
+}
+
+/// The meter for the options: a configured estimate, then RAPL, or nothing.
+pub fn meter(options: &EnergyOptions) -> Option<Box<dyn EnergyMeter>> {
+    match options.watts {
+        Some(watts) => Some(Box::new(ConstantPower::new(watts))),
+        None => RaplMeter::discover(Utf8Path::new(POWERCAP_ROOT))
+            .map(|meter| Box::new(meter) as Box<dyn EnergyMeter>),
+    }
+}
+
+/// The wall time and energy of one file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileUsage {
+    pub path: Utf8PathBuf,
+    pub wall_time: Duration,
+    pub joules: f64,
+}
+
+impl FileUsage {
+    pub fn kwh(&self) -> f64 {
+        self.joules / JOULES_PER_KWH
+    }
+}
+
+#[derive(Debug)]
+struct ActiveUsage {
+    path: Utf8PathBuf,
+    started: Instant,
+    joules: f64,
+}
+
+#[derive(Debug, Default)]
+struct TrackerState {
+    last_reading: Option<f64>,
+    active: HashMap<i64, ActiveUsage>,
+    finished: Vec<FileUsage>,
+    /// Set when the meter couldn't be read, after which nothing is reported.
+    failed: bool,
+}
+
+/// Attributes the energy of a run to the files. Energy used while several files
+/// are transcoding is split evenly between them.
+pub struct EnergyTracker {
+    meter: Box<dyn EnergyMeter>,
+    state: Mutex<TrackerState>,
+}
+
+impl EnergyTracker {
+    pub fn new(meter: Box<dyn EnergyMeter>) -> Self {
+        EnergyTracker {
+            meter,
+            state: Mutex::default(),
+        }
+    }
+
+    /// Reads the meter and splits the energy since the last reading between the
+    /// active files.
+    fn advance(&self, state: &mut TrackerState) {
+        let Some(reading) = self.meter.joules() else {
+            if !state.failed {
+                info!("the energy meter can't be read anymore, not reporting energy");
+            }
+            state.failed = true;
+            return;
+        };
+        if let Some(last) = state.last_reading
+            && !state.active.is_empty()
+        {
+            let share = (reading - last).max(0.0) / state.active.len() as f64;
+            for usage in state.active.values_mut() {
+                usage.joules += share;
+            }
+        }
+        state.last_reading = Some(reading);
+    }
+
+    pub fn start_file(&self, id: i64, path: &Utf8Path) {
+        let mut state = self.state.lock().unwrap();
+        self.advance(&mut state);
+        state.active.insert(
+            id,
+            ActiveUsage {
+                path: path.to_owned(),
+                started: Instant::now(),
+                joules: 0.0,
+            },
+        );
+    }
+
+    pub fn finish_file(&self, id: i64) {
+        let mut state = self.state.lock().unwrap();
+        self.advance(&mut state);
+        if let Some(usage) = state.active.remove(&id) {
+            state.finished.push(FileUsage {
+                path: usage.path,
+                wall_time: usage.started.elapsed(),
+                joules: usage.joules,
+            });
+        }
+    }
+
+    /// The usage of the finished files, or `None` if the meter failed.
+    pub fn report(&self) -> Option<EnergyReport> {
+        let state = self.state.lock().unwrap();
+        (!state.failed).then(|| EnergyReport {
+            source: self.meter.source(),
+            files: state.finished.clone(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnergyReport {
+    /// Where the numbers come from.
+    pub source: String,
+    pub files: Vec<FileUsage>,
+}
+
+impl EnergyReport {
+    pub fn kwh(&self) -> f64 {
+        self.files.iter().map(FileUsage::kwh).sum()
+    }
+
+    pub fn wall_time(&self) -> Duration {
+        self.files.iter().map(|f| f.wall_time).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+    use crate::Result;
+
+    /// A meter that reports the joules it's set to, `u64::MAX` for a failure.
+    struct FakeMeter(Arc<AtomicU64>);
+
+    impl EnergyMeter for FakeMeter {
+        fn joules(&self) -> Option<f64> {
+            match self.0.load(Ordering::SeqCst) {
+                u64::MAX => None,
+                joules => Some(joules as f64),
+            }
+        }
+
+        fn source(&self) -> String {
+            "fake".into()
+        }
+    }
+
+    fn zone(root: &Utf8Path, name: &str, energy: u64, max_range: u64) -> Result<()> {
+        let directory = root.join(name);
+        fs::create_dir_all(&directory)?;
+        fs::write(directory.join("energy_uj"), format!("{energy}\n"))?;
+        fs::write(
+            directory.join("max_energy_range_uj"),
+            format!("{max_range}\n"),
+        )?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_rapl() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let root = Utf8Path::from_path(tempdir.path()).unwrap();
+        assert!(RaplMeter::discover(root).is_none());
+        assert!(RaplMeter::discover(&root.join("missing")).is_none());
+
+        zone(root, "intel-rapl:0", 1_000_000, 10_000_000)?;
+        zone(root, "intel-rapl:0:0", 500_000, 10_000_000)?;
+        zone(root, "intel-rapl:1", 9_000_000, 10_000_000)?;
+        zone(root, "intel-rapl-mmio:0", 0, 10_000_000)?;
+        let meter = RaplMeter::discover(root).unwrap();
+        assert_eq!(2, meter.zones.len());
+        assert_eq!(Some(0.0), meter.joules());
+
+        // the core sub-zone is part of package 0 and isn't counted, package 1 wraps
+        zone(root, "intel-rapl:0", 3_000_000, 10_000_000)?;
+        zone(root, "intel-rapl:0:0", 2_500_000, 10_000_000)?;
+        zone(root, "intel-rapl:1", 500_000, 10_000_000)?;
+        assert_eq!(Some(3.5), meter.joules());
+
+        // counters that become unreadable
+        fs::remove_file(root.join("intel-rapl:1/energy_uj"))?;
+        assert_eq!(None, meter.joules());
+        Ok(())
+    }
+
+    #[test]
+    fn test_constant_power() {
+        let meter = ConstantPower {
+            watts: 100.0,
+            since: Instant::now() - Duration::from_secs(36),
+        };
+        let joules = meter.joules().unwrap();
+        assert!((3600.0..3700.0).contains(&joules));
+        assert_eq!("estimated at 100 W", meter.source());
+    }
+
+    #[test]
+    fn test_tracker() {
+        let reading = Arc::new(AtomicU64::new(0));
+        let tracker = EnergyTracker::new(Box::new(FakeMeter(reading.clone())));
+        let set = |joules: u64| reading.store(joules, Ordering::SeqCst);
+        tracker.start_file(1, "/a.mkv".into());
+        set(100);
+        tracker.start_file(2, "/b.mkv".into());
+        set(300);
+        tracker.finish_file(1);
+        set(400);
+        tracker.finish_file(2);
+
+        let report = tracker.report().unwrap();
+        assert_eq!("fake", report.source);
+        let joules: Vec<_> = report
+            .files
+            .iter()
+            .map(|f| (f.path.as_str(), f.joules))
+            .collect();
+        assert_eq!(vec![("/a.mkv", 200.0), ("/b.mkv", 200.0)], joules);
+        assert!((report.kwh() - 400.0 / 3.6e6).abs() < 1e-12);
+
+        set(u64::MAX);
+        tracker.start_file(3, "/c.mkv".into());
+        assert_eq!(None, tracker.report());
+    }
+}
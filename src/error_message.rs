@@ -0,0 +1,157 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use crate::Result;
+
+/// Default for the longest error message that is stored in the `error_message`
+/// column, in characters.
+pub const DEFAULT_MAX_LENGTH: usize = 2000;
+
+/// zstd level for the full text, which is rarely read back.
+const COMPRESSION_LEVEL: i32 = 9;
+
+/// Marks the start of a message that was cut off.
+const TRUNCATED_MARKER: &str = "[...] ";
+
+/// CSI sequences like colors and cursor movement, OSC sequences like window
+/// titles and hyperlinks, and the remaining two character escapes.
+static ANSI_REGEX: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"\x1b\[[0-?]*[ -/]*[@-~]|\x1b\][^\x07\x1b]*(?:\x07|\x1b\\)?|\x1b[@-_]").unwrap()
+});
+
+/// Removes ANSI escape sequences and control characters other than newlines and
+/// tabs. Carriage returns, which ffmpeg uses to redraw its progress line, become
+/// newlines.
+pub fn strip_ansi(message: &str) -> String {
+    ANSI_REGEX
+        .replace_all(message, "")
+        .replace("\r\n", "\n")
+        .replace('\r', "\n")
+        .chars()
+        .filter(|c| !c.is_control() || matches!(c, '\n' | '\t'))
+        .collect()
+}
+
+/// Keeps the last `max_length` characters of the message, where ffmpeg puts the
+/// actual error, and marks the start as cut off.
+pub fn truncate_tail(message: &str, max_length: usize) -> String {
+    let length = message.chars().count();
+    if length <= max_length {
+        return message.to_string();
+    }
+    let marker_length = TRUNCATED_MARKER.chars().count();
+    if max_length <= marker_length {
+        return message.chars().skip(length - max_length).collect();
+    }
+    let tail: String = message
+        .chars()
+        .skip(length - (max_length - marker_length))
+        .collect();
+    format!("{TRUNCATED_MARKER}{tail}")
+}
+
+/// An error message ready to be stored.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sanitized {
+    pub message: String,
+    /// The whole message without escapes, when `message` had to be cut off.
+    pub full_text: Option<String>,
+}
+
+pub fn sanitize(message: &str, max_length: usize) -> Sanitized {
+    let stripped = strip_ansi(message);
+    let truncated = truncate_tail(&stripped, max_length);
+    if truncated == stripped {
+        Sanitized {
+            message: stripped,
+            full_text: None,
+        }
+    } else {
+        Sanitized {
+            message: truncated,
+            full_text: Some(stripped),
+        }
+    }
+}
+
+/// The last line with text, which is usually the one that says what went wrong.
+pub fn summary(message: &str) -> &str {
+    message
+        .lines()
+        .map(str::trim)
+        .rfind(|line| !line.is_empty())
+        .unwrap_or_default()
+}
+
+pub fn compress(text: &str) -> Result<Vec<u8>> {
+    Ok(zstd::encode_all(text.as_bytes(), COMPRESSION_LEVEL)?)
+}
+
+pub fn decompress(data: &[u8]) -> Result<String> {
+    let bytes = zstd::decode_all(data)?;
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_ansi() {
+        assert_eq!("plain text", strip_ansi("plain text"));
+        assert_eq!(
+            "Error opening input: No such file",
+            strip_ansi("\x1b[1;31mError opening input:\x1b[0m No such file")
+        );
+        assert_eq!(
+            "link",
+            strip_ansi("\x1b]8;;https://x\x1b\\link\x1b]8;;\x1b\\")
+        );
+        assert_eq!("title", strip_ansi("\x1b]0;ffmpeg\x07title"));
+        assert_eq!("ab\tc", strip_ansi("a\x1b[2K\x1b[1Gb\x00\x08\tc"));
+        assert_eq!(
+            "frame=1\nframe=2\nline\n",
+            strip_ansi("frame=1\rframe=2\r\nline\n")
+        );
+        assert_eq!("ünïcödé", strip_ansi("ünï\x1b[33mcödé"));
+    }
+
+    #[test]
+    fn test_truncate_tail() {
+        assert_eq!("short", truncate_tail("short", 5));
+        assert_eq!("abcdefghij", truncate_tail("abcdefghij", 10));
+        assert_eq!("[...] hij", truncate_tail("abcdefghij", 9));
+        assert_eq!("hij", truncate_tail("abcdefghij", 3));
+        // cuts at character boundaries
+        assert_eq!("[...] äöü", truncate_tail("äöüäöüäöüäöü", 9));
+    }
+
+    #[test]
+    fn test_sanitize() -> Result<()> {
+        let short = sanitize("\x1b[31mfailed\x1b[0m", 100);
+        assert_eq!("failed", short.message);
+        assert_eq!(None, short.full_text);
+
+        let long = format!("\x1b[31m{}\x1b[0m\nConversion failed!", "x".repeat(100));
+        let sanitized = sanitize(&long, 30);
+        assert_eq!(30, sanitized.message.chars().count());
+        assert!(sanitized.message.ends_with("\nConversion failed!"));
+        let full_text = sanitized.full_text.unwrap();
+        assert_eq!(
+            format!("{}\nConversion failed!", "x".repeat(100)),
+            full_text
+        );
+        assert_eq!(full_text, decompress(&compress(&full_text)?)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_summary() {
+        assert_eq!(
+            "Conversion failed!",
+            summary("frame=1\nConversion failed!\n\n")
+        );
+        assert_eq!("one line", summary("  one line "));
+        assert_eq!("", summary("\n\n"));
+    }
+}
@@ -0,0 +1,595 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Write;
+use std::time::Duration;
+
+use camino::Utf8PathBuf;
+use human_repr::HumanDuration;
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+use crate::collect::{self, ResolutionTier, VideoFile};
+use crate::database::TranscodeFile;
+use crate::transcode::GpuMode;
+
+/// Encode speeds are normalized to this many pixels per frame.
+const REFERENCE_PIXELS: f64 = 1920.0 * 1080.0;
+
+/// Encode speeds in seconds of 1080p video per second to assume without history,
+/// from the `[estimate.speed]` section of the config file.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DefaultSpeeds {
+    pub cpu: Option<f64>,
+    pub nvidia: Option<f64>,
+    pub qsv: Option<f64>,
+    pub vaapi: Option<f64>,
+    pub amf: Option<f64>,
+}
+
+/// The `[estimate]` section of the config file.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EstimateConfig {
+    pub speed: DefaultSpeeds,
+}
+
+/// Rough encode speed in seconds of 1080p video per second, used without history.
+pub fn default_speed(gpu: Option<&GpuMode>, speeds: &DefaultSpeeds) -> f64 {
+    match gpu {
+        None => speeds.cpu.unwrap_or(1.0),
+        Some(GpuMode::Nvidia) => speeds.nvidia.unwrap_or(6.0),
+        Some(GpuMode::Qsv) => speeds.qsv.unwrap_or(4.0),
+        Some(GpuMode::Vaapi) => speeds.vaapi.unwrap_or(4.0),
+        Some(GpuMode::Amf) => speeds.amf.unwrap_or(4.0),
+    }
+}
+
+/// How much work a frame is compared to a 1080p frame.
+fn relative_pixels((width, height): (u32, u32)) -> f64 {
+    if width == 0 || height == 0 {
+        return 1.0;
+    }
+    width as f64 * height as f64 / REFERENCE_PIXELS
+}
+
+/// The speed of previous encodes, normalized to 1080p. `None` without any history.
+pub fn measured_speed(files: &[TranscodeFile]) -> Option<f64> {
+    let mut media_seconds = 0.0;
+    let mut encode_seconds = 0.0;
+    for file in files {
+        let (Some(seconds), Some(info)) = (file.encode_seconds, file.ffprobe()) else {
+            continue;
+        };
+        if let Some(duration) = info.duration()
+            && seconds > 0.0
+        {
+            media_seconds += duration * relative_pixels(info.resolution());
+            encode_seconds += seconds;
+        }
+    }
+    (encode_seconds > 0.0).then(|| media_seconds / encode_seconds)
+}
+
+/// Files whose encodes are expected to run at a similar speed.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Bucket {
+    pub tier: ResolutionTier,
+    /// The codec of the source, which decides how fast it decodes.
+    pub codec: String,
+}
+
+impl Bucket {
+    fn of(file: &VideoFile) -> Self {
+        Bucket {
+            tier: file.tier(),
+            codec: file.codec.clone(),
+        }
+    }
+
+    /// The bucket of a file from the database, `None` without ffprobe info.
+    fn of_encoded(file: &TranscodeFile) -> Option<Self> {
+        let info = file.ffprobe()?;
+        Some(Bucket {
+            tier: collect::resolution_tier(info.resolution()),
+            codec: info.video_codec().to_owned(),
+        })
+    }
+}
+
+impl fmt::Display for Bucket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.tier, self.codec)
+    }
+}
+
+/// Where the speed for a bucket came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpeedSource {
+    /// Encodes of files in the same bucket.
+    Bucket,
+    /// Encodes of all files, when none are in the bucket.
+    History,
+    /// No encodes were recorded.
+    Default,
+}
+
+impl fmt::Display for SpeedSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SpeedSource::Bucket => write!(f, "measured"),
+            SpeedSource::History => write!(f, "measured on other files"),
+            SpeedSource::Default => write!(f, "default speed"),
+        }
+    }
+}
+
+/// Encode speeds measured per bucket, falling back to the speed of all encodes and
+/// then to a default.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Speeds {
+    buckets: BTreeMap<Bucket, f64>,
+    overall: Option<f64>,
+    default: f64,
+}
+
+impl Speeds {
+    pub fn measure(encoded: &[TranscodeFile], default: f64) -> Self {
+        let mut files: BTreeMap<Bucket, Vec<TranscodeFile>> = BTreeMap::new();
+        for file in encoded {
+            if let Some(bucket) = Bucket::of_encoded(file) {
+                files.entry(bucket).or_default().push(file.clone());
+            }
+        }
+        Speeds {
+            buckets: files
+                .into_iter()
+                .filter_map(|(bucket, files)| Some((bucket, measured_speed(&files)?)))
+                .collect(),
+            overall: measured_speed(encoded),
+            default,
+        }
+    }
+
+    pub fn speed(&self, bucket: &Bucket) -> (f64, SpeedSource) {
+        match (self.buckets.get(bucket), self.overall) {
+            (Some(&speed), _) => (speed, SpeedSource::Bucket),
+            (None, Some(speed)) => (speed, SpeedSource::History),
+            (None, None) => (self.default, SpeedSource::Default),
+        }
+    }
+
+    pub fn for_file(&self, file: &VideoFile) -> (f64, SpeedSource) {
+        self.speed(&Bucket::of(file))
+    }
+
+    /// The speed of all encodes, `None` without history.
+    pub fn overall(&self) -> Option<f64> {
+        self.overall
+    }
+
+    pub fn default(&self) -> f64 {
+        self.default
+    }
+}
+
+/// The expected encode time of the files in one bucket.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BucketEstimate {
+    pub bucket: Bucket,
+    pub files: usize,
+    pub time: Duration,
+    pub source: SpeedSource,
+}
+
+/// Expected encode times of the files per bucket, longest first.
+pub fn estimate_by_bucket(files: &[VideoFile], speeds: &Speeds) -> Vec<BucketEstimate> {
+    let mut estimates: BTreeMap<Bucket, BucketEstimate> = BTreeMap::new();
+    for file in files {
+        let bucket = Bucket::of(file);
+        let (speed, source) = speeds.speed(&bucket);
+        let estimate = estimates
+            .entry(bucket.clone())
+            .or_insert_with(|| BucketEstimate {
+                bucket,
+                files: 0,
+                time: Duration::ZERO,
+                source,
+            });
+        estimate.files += 1;
+        estimate.time += encode_time(file, speed);
+    }
+    let mut estimates: Vec<_> = estimates.into_values().collect();
+    estimates.sort_by_key(|e| std::cmp::Reverse(e.time));
+    estimates
+}
+
+/// Expected encode time for a file at the given normalized speed.
+pub fn encode_time(file: &VideoFile, speed: f64) -> Duration {
+    Duration::from_secs_f64(file.duration * relative_pixels(file.resolution) / speed)
+}
+
+/// The predicted encode time of a queue compared with the duration of its videos.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Prediction {
+    pub media: Duration,
+    pub encode: Duration,
+    pub buckets: Vec<BucketEstimate>,
+}
+
+impl Prediction {
+    pub fn of(files: &[VideoFile], speeds: &Speeds) -> Self {
+        let buckets = estimate_by_bucket(files, speeds);
+        Prediction {
+            media: Duration::from_secs_f64(files.iter().map(|f| f.duration.max(0.0)).sum()),
+            encode: buckets.iter().map(|b| b.time).sum(),
+            buckets,
+        }
+    }
+
+    /// Whether encoding the queue is predicted to take longer than `max`, or
+    /// longer than the videos play without it.
+    pub fn is_too_slow(&self, max: Option<Duration>) -> bool {
+        match max {
+            Some(max) => self.encode > max,
+            None => self.encode > self.media,
+        }
+    }
+
+    /// How many seconds an encode takes per second of video.
+    pub fn ratio(&self) -> f64 {
+        if self.media.is_zero() {
+            return 0.0;
+        }
+        self.encode.as_secs_f64() / self.media.as_secs_f64()
+    }
+}
+
+impl fmt::Display for Prediction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "encoding {} of video is predicted to take {} ({:.1}x real time)",
+            self.media.human_duration(),
+            self.encode.human_duration(),
+            self.ratio()
+        )
+    }
+}
+
+/// Warns about a queue that is predicted to be too slow and decides whether to
+/// start it. Interactive sessions have to `confirm` it, others only get the
+/// warning.
+pub fn confirm_slow_queue(
+    prediction: &Prediction,
+    max: Option<Duration>,
+    interactive: bool,
+    out: &mut impl Write,
+    confirm: impl FnOnce() -> Result<bool>,
+) -> Result<bool> {
+    if !prediction.is_too_slow(max) {
+        return Ok(true);
+    }
+    writeln!(out, "Warning: {prediction}")?;
+    if let Some(max) = max {
+        writeln!(
+            out,
+            "That is more than --max-predicted-duration of {}",
+            max.human_duration()
+        )?;
+    }
+    for bucket in &prediction.buckets {
+        writeln!(
+            out,
+            "\t{}: {} files, {} ({})",
+            bucket.bucket,
+            bucket.files,
+            bucket.time.human_duration(),
+            bucket.source
+        )?;
+    }
+    if !interactive {
+        return Ok(true);
+    }
+    confirm()
+}
+
+/// A file in the queue of the next run, with running totals.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct QueueEntry {
+    pub path: Utf8PathBuf,
+    pub file_size: u64,
+    pub estimated_seconds: u64,
+    pub cumulative_size: u64,
+    pub cumulative_seconds: u64,
+}
+
+pub fn queue_entries(files: &[VideoFile], speeds: &Speeds) -> Vec<QueueEntry> {
+    let mut cumulative_size = 0;
+    let mut cumulative_seconds = 0;
+    files
+        .iter()
+        .map(|file| {
+            let (speed, _) = speeds.for_file(file);
+            let estimated_seconds = encode_time(file, speed).as_secs();
+            cumulative_size += file.file_size;
+            cumulative_seconds += estimated_seconds;
+            QueueEntry {
+                path: file.path.clone(),
+                file_size: file.file_size,
+                estimated_seconds,
+                cumulative_size,
+                cumulative_seconds,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{Database, NewTranscodeFile, TranscodeStatus};
+    use crate::ffprobe::{FfProbe, Format, Stream};
+
+    fn probe(width: i64, height: i64, duration: &str) -> FfProbe {
+        probe_codec("h264", width, height, duration)
+    }
+
+    fn probe_codec(codec: &str, width: i64, height: i64, duration: &str) -> FfProbe {
+        FfProbe {
+            streams: vec![Stream {
+                codec_type: Some("video".into()),
+                codec_name: Some(codec.into()),
+                width: Some(width),
+                height: Some(height),
+                ..Default::default()
+            }],
+            format: Format {
+                duration: Some(duration.into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    fn video(path: &str, resolution: (u32, u32), duration: f64, file_size: u64) -> VideoFile {
+        video_codec("h264", path, resolution, duration, file_size)
+    }
+
+    fn video_codec(
+        codec: &str,
+        path: &str,
+        resolution: (u32, u32),
+        duration: f64,
+        file_size: u64,
+    ) -> VideoFile {
+        VideoFile {
+            id: 0,
+            path: path.into(),
+            duration,
+            resolution,
+            bitrate: 0,
+            video_bitrate: 0,
+            audio_bitrate: 0,
+            frame_rate: 24.0,
+            codec: codec.into(),
+            profile: None,
+            container: "mp4".into(),
+            file_size,
+            status: TranscodeStatus::Pending,
+            audio_tracks: vec![],
+            pix_fmt: None,
+            start_offset: None,
+            pinned: false,
+            output_path: None,
+            title: None,
+            chapters: vec![],
+        }
+    }
+
+    #[test]
+    fn test_measured_speed() -> Result<()> {
+        let db = Database::in_memory()?;
+        db.insert_batch(&[
+            NewTranscodeFile {
+                path: "/hd.mkv".into(),
+                file_size: 5,
+                ffprobe_info: probe(1920, 1080, "600.0"),
+            },
+            NewTranscodeFile {
+                path: "/uhd.mkv".into(),
+                file_size: 5,
+                ffprobe_info: probe(3840, 2160, "300.0"),
+            },
+            NewTranscodeFile {
+                path: "/pending.mkv".into(),
+                file_size: 5,
+                ffprobe_info: probe(1920, 1080, "300.0"),
+            },
+        ])?;
+        assert_eq!(None, measured_speed(&db.encoded_files()?));
+
+        let hd = db.get_by_path("/hd.mkv".into())?.unwrap();
+        let uhd = db.get_by_path("/uhd.mkv".into())?.unwrap();
+        db.set_encode_time(hd.id, 300.0)?;
+        db.set_encode_time(uhd.id, 600.0)?;
+        // 600s of 1080p plus 300s of 4K, which counts four times, in 900s
+        assert_eq!(Some(2.0), measured_speed(&db.encoded_files()?));
+        Ok(())
+    }
+
+    /// Encodes of 1080p h264 at 2x and 4K hevc at 0.5x.
+    fn history() -> Result<Vec<TranscodeFile>> {
+        let db = Database::in_memory()?;
+        db.insert_batch(&[
+            NewTranscodeFile {
+                path: "/hd.mkv".into(),
+                file_size: 5,
+                ffprobe_info: probe_codec("h264", 1920, 1080, "600.0"),
+            },
+            NewTranscodeFile {
+                path: "/uhd.mkv".into(),
+                file_size: 5,
+                ffprobe_info: probe_codec("hevc", 3840, 2160, "75.0"),
+            },
+        ])?;
+        let hd = db.get_by_path("/hd.mkv".into())?.unwrap();
+        let uhd = db.get_by_path("/uhd.mkv".into())?.unwrap();
+        db.set_encode_time(hd.id, 300.0)?;
+        db.set_encode_time(uhd.id, 600.0)?;
+        db.encoded_files()
+    }
+
+    #[test]
+    fn test_speeds() -> Result<()> {
+        let speeds = Speeds::measure(&history()?, 1.0);
+        let bucket = |tier, codec: &str| Bucket {
+            tier,
+            codec: codec.into(),
+        };
+        assert_eq!(
+            (2.0, SpeedSource::Bucket),
+            speeds.speed(&bucket(ResolutionTier::Hd1080, "h264"))
+        );
+        assert_eq!(
+            (0.5, SpeedSource::Bucket),
+            speeds.speed(&bucket(ResolutionTier::Uhd4k, "hevc"))
+        );
+        // 600s of 1080p plus 75s of 4K, which counts four times, in 900s
+        assert_eq!(
+            (1.0, SpeedSource::History),
+            speeds.speed(&bucket(ResolutionTier::Uhd4k, "h264"))
+        );
+
+        let speeds = Speeds::measure(&[], 6.0);
+        assert_eq!(
+            (6.0, SpeedSource::Default),
+            speeds.speed(&bucket(ResolutionTier::Hd1080, "h264"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_estimate_by_bucket() -> Result<()> {
+        let speeds = Speeds::measure(&history()?, 1.0);
+        let pending = [
+            video_codec("h264", "/a.mkv", (1920, 1080), 600.0, 1),
+            video_codec("h264", "/b.mkv", (1920, 1080), 160.0, 1),
+            video_codec("hevc", "/c.mkv", (3840, 2160), 50.0, 1),
+            video_codec("mpeg2video", "/d.mpg", (720, 576), 1000.0, 1),
+        ];
+        let estimates: Vec<_> = estimate_by_bucket(&pending, &speeds)
+            .into_iter()
+            .map(|e| (e.bucket.to_string(), e.files, e.time.as_secs(), e.source))
+            .collect();
+        assert_eq!(
+            vec![
+                ("4K hevc".to_string(), 1, 400, SpeedSource::Bucket),
+                ("1080p h264".into(), 2, 380, SpeedSource::Bucket),
+                ("SD mpeg2video".into(), 1, 200, SpeedSource::History),
+            ],
+            estimates
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_speed() {
+        let configured = DefaultSpeeds {
+            nvidia: Some(10.0),
+            ..Default::default()
+        };
+        assert_eq!(10.0, default_speed(Some(&GpuMode::Nvidia), &configured));
+        assert_eq!(4.0, default_speed(Some(&GpuMode::Qsv), &configured));
+        assert_eq!(1.0, default_speed(None, &configured));
+    }
+
+    #[test]
+    fn test_prediction() -> Result<()> {
+        let speeds = Speeds::measure(&history()?, 1.0);
+        let fast = [video_codec("h264", "/a.mkv", (1920, 1080), 600.0, 1)];
+        let prediction = Prediction::of(&fast, &speeds);
+        assert_eq!(300, prediction.encode.as_secs());
+        assert_eq!(600, prediction.media.as_secs());
+        assert!(!prediction.is_too_slow(None));
+        assert!(prediction.is_too_slow(Some(Duration::from_secs(60))));
+
+        let slow = [
+            video_codec("hevc", "/b.mkv", (3840, 2160), 50.0, 1),
+            video_codec("h264", "/a.mkv", (1920, 1080), 600.0, 1),
+        ];
+        let prediction = Prediction::of(&slow, &speeds);
+        assert_eq!(700, prediction.encode.as_secs());
+        assert!(prediction.is_too_slow(None));
+        assert!(!prediction.is_too_slow(Some(Duration::from_secs(3600))));
+        assert!(prediction.to_string().ends_with("(1.1x real time)"));
+
+        assert!(!Prediction::of(&[], &speeds).is_too_slow(None));
+        Ok(())
+    }
+
+    #[test]
+    fn test_confirm_slow_queue() -> Result<()> {
+        let speeds = Speeds::measure(&history()?, 1.0);
+        let slow = Prediction::of(
+            &[video_codec("hevc", "/b.mkv", (3840, 2160), 500.0, 1)],
+            &speeds,
+        );
+        let fast = Prediction::of(
+            &[video_codec("h264", "/a.mkv", (1920, 1080), 600.0, 1)],
+            &speeds,
+        );
+        let unexpected = || -> Result<bool> { panic!("asked for confirmation") };
+
+        // fast queues start without a word
+        let mut out = vec![];
+        assert!(confirm_slow_queue(&fast, None, true, &mut out, unexpected)?);
+        assert!(out.is_empty());
+
+        // slow ones need a confirmation in interactive sessions
+        let mut out = vec![];
+        assert!(!confirm_slow_queue(&slow, None, true, &mut out, || Ok(
+            false
+        ))?);
+        let warning = String::from_utf8(out)?;
+        assert!(warning.starts_with("Warning: encoding"), "{warning}");
+        assert!(warning.contains("\t4K hevc: 1 files"), "{warning}");
+        let mut out = vec![];
+        assert!(confirm_slow_queue(&slow, None, true, &mut out, || Ok(
+            true
+        ))?);
+
+        // and are only warned about otherwise
+        let mut out = vec![];
+        assert!(confirm_slow_queue(
+            &slow, None, false, &mut out, unexpected
+        )?);
+        assert!(!out.is_empty());
+
+        let mut out = vec![];
+        let max = Some(Duration::from_secs(60));
+        assert!(!confirm_slow_queue(&fast, max, true, &mut out, || Ok(
+            false
+        ))?);
+        assert!(String::from_utf8(out)?.contains("--max-predicted-duration"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_queue_entries() {
+        let files = [
+            video("/a.mkv", (1920, 1080), 100.0, 1000),
+            video("/b.mkv", (3840, 2160), 100.0, 500),
+            video("/c.mkv", (0, 0), 50.0, 10),
+        ];
+        let entries = queue_entries(&files, &Speeds::measure(&[], 2.0));
+        let totals: Vec<_> = entries
+            .iter()
+            .map(|e| (e.estimated_seconds, e.cumulative_size, e.cumulative_seconds))
+            .collect();
+        assert_eq!(
+            vec![(50, 1000, 50), (200, 1500, 250), (25, 1510, 275)],
+            totals
+        );
+    }
+}
@@ -1,15 +1,31 @@
-use std::process::{Command, Output};
+use std::fmt;
+use std::io::Read;
+use std::process::{Child, Output, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use camino::Utf8Path;
-use serde::{Deserialize, Serialize};
+use color_eyre::eyre::bail;
+use human_repr::HumanDuration;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::Value;
 use tracing::{debug, info};
 
 use crate::Result;
+use crate::binaries::{self, Binary};
 
+/// Start times closer to zero than this many seconds are left alone.
+const START_TIME_EPSILON: f64 = 0.001;
+
+/// The output of ffprobe. Missing fields get their defaults, so stored info that
+/// was stripped with [`FfProbe::stripped`] can be read back.
 #[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
 pub struct FfProbe {
     pub streams: Vec<Stream>,
     pub format: Format,
+    /// Info probed before chapters were asked for has none.
+    pub chapters: Vec<Chapter>,
 }
 
 impl FfProbe {
@@ -28,6 +44,31 @@ impl FfProbe {
             .unwrap_or_default()
     }
 
+    /// The bitrate of the video stream. Without one of its own, it's what the
+    /// audio leaves of the container bitrate. Audio streams without a bitrate
+    /// count as nothing, `None` when neither is known.
+    pub fn video_bitrate(&self) -> Option<u64> {
+        let stream = self
+            .streams
+            .iter()
+            .find(|s| s.codec_type.as_deref() == Some("video"));
+        if let Some(bitrate) = stream.and_then(Stream::bitrate).filter(|b| *b > 0) {
+            return Some(bitrate);
+        }
+        self.bitrate()
+            .checked_sub(self.audio_bitrate())
+            .filter(|b| *b > 0)
+    }
+
+    /// The summed bitrate of the audio streams that have one.
+    pub fn audio_bitrate(&self) -> u64 {
+        self.streams
+            .iter()
+            .filter(|s| s.is_audio())
+            .filter_map(Stream::bitrate)
+            .sum()
+    }
+
     pub fn resolution(&self) -> (u32, u32) {
         let video_stream = self
             .streams
@@ -57,6 +98,50 @@ impl FfProbe {
             .unwrap_or_default()
     }
 
+    /// The codec profile of the video stream, e.g. `High 10` or `Main`.
+    pub fn video_profile(&self) -> Option<&str> {
+        self.streams
+            .iter()
+            .find(|s| s.codec_type.as_deref() == Some("video"))
+            .and_then(|s| s.profile.as_deref())
+    }
+
+    /// The pixel format of the video stream.
+    pub fn pix_fmt(&self) -> Option<&str> {
+        self.streams
+            .iter()
+            .find(|s| s.codec_type.as_deref() == Some("video"))
+            .and_then(|s| s.pix_fmt.as_deref())
+    }
+
+    /// The `title` tag of the container, e.g. "Director's Cut".
+    pub fn title(&self) -> Option<&str> {
+        self.format
+            .tags
+            .as_ref()
+            .and_then(|tags| tags.title.as_deref())
+            .map(str::trim)
+            .filter(|title| !title.is_empty())
+    }
+
+    /// The normalized name of the container format.
+    pub fn container(&self) -> String {
+        container_name(&self.format.format_name)
+    }
+
+    /// The start time of the container or one of its streams that is furthest
+    /// from zero, when it is further than a millisecond. iPhone `.mov` files with
+    /// edit lists start at e.g. -0.023 seconds, which can leave their outputs
+    /// with an A/V offset or a black first frame.
+    pub fn start_offset(&self) -> Option<f64> {
+        std::iter::once(&self.format.start_time)
+            .chain(self.streams.iter().map(|s| &s.start_time))
+            .filter_map(|start| start.as_deref()?.parse::<f64>().ok())
+            .filter(|start| start.abs() > START_TIME_EPSILON)
+            .max_by(|a, b| a.abs().total_cmp(&b.abs()))
+    }
+
+    #[allow(dead_code)]
     pub fn size(&self) -> u64 {
         self.format
             .size
@@ -64,9 +149,112 @@ impl FfProbe {
             .and_then(|s| s.parse().ok())
             .unwrap_or_default()
     }
+
+    /// Only the fields that are read anywhere, everything else is left at its
+    /// default.
+    pub fn stripped(&self) -> FfProbe {
+        FfProbe {
+            streams: self
+                .streams
+                .iter()
+                .map(|stream| Stream {
+                    index: stream.index,
+                    codec_name: stream.codec_name.clone(),
+                    codec_type: stream.codec_type.clone(),
+                    r_frame_rate: stream.r_frame_rate.clone(),
+                    width: stream.width,
+                    height: stream.height,
+                    profile: stream.profile.clone(),
+                    level: stream.level,
+                    pix_fmt: stream.pix_fmt.clone(),
+                    channels: stream.channels,
+                    bit_rate: stream.bit_rate.clone(),
+                    max_bit_rate: stream.max_bit_rate.clone(),
+                    start_time: stream.start_time.clone(),
+                    tags: stream.tags.as_ref().and_then(|tags| {
+                        let mut kept = StreamTags {
+                            bps: tags.bps.clone(),
+                            title: tags.title.clone(),
+                            ..Default::default()
+                        };
+                        // the titles and dispositions tell commentary tracks apart
+                        if stream.is_audio() {
+                            kept.handler_name = tags.handler_name.clone();
+                        }
+                        (kept != StreamTags::default()).then_some(kept)
+                    }),
+                    disposition: if stream.is_audio() {
+                        Disposition {
+                            default: stream.disposition.default,
+                            comment: stream.disposition.comment,
+                            visual_impaired: stream.disposition.visual_impaired,
+                            descriptions: stream.disposition.descriptions,
+                            ..Default::default()
+                        }
+                    } else {
+                        Disposition::default()
+                    },
+                    ..Default::default()
+                })
+                .collect(),
+            format: Format {
+                format_name: self.format.format_name.clone(),
+                start_time: self.format.start_time.clone(),
+                duration: self.format.duration.clone(),
+                size: self.format.size.clone(),
+                bit_rate: self.format.bit_rate.clone(),
+                tags: self.title().map(|title| FormatTags {
+                    title: Some(title.into()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            chapters: self
+                .chapters
+                .iter()
+                .map(|chapter| Chapter {
+                    id: chapter.id,
+                    start_time: chapter.start_time,
+                    end_time: chapter.end_time,
+                    tags: chapter.title().map(|title| ChapterTags {
+                        title: Some(title.into()),
+                    }),
+                })
+                .collect(),
+        }
+    }
+
+    /// The stripped info as JSON, without the null and empty values that
+    /// deserialize to the same defaults.
+    pub fn to_compact_json(&self) -> Result<String> {
+        let mut value = serde_json::to_value(self.stripped())?;
+        remove_empty(&mut value);
+        Ok(serde_json::to_string(&value)?)
+    }
+}
+
+/// Removes null values, empty strings, arrays and objects from the objects in
+/// `value`. Returns whether `value` itself is empty.
+fn remove_empty(value: &mut Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::String(s) => s.is_empty(),
+        Value::Array(values) => {
+            for value in values.iter_mut() {
+                remove_empty(value);
+            }
+            values.is_empty()
+        }
+        Value::Object(fields) => {
+            fields.retain(|_, value| !remove_empty(value));
+            fields.is_empty()
+        }
+        Value::Bool(_) | Value::Number(_) => false,
+    }
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
 pub struct Stream {
     pub index: i64,
     pub codec_name: Option<String>,
@@ -99,6 +287,7 @@ pub struct Stream {
     pub duration_ts: Option<i64>,
     pub duration: Option<String>,
     pub bit_rate: Option<String>,
+    #[serde(skip_serializing_if = "Disposition::is_default")]
     pub disposition: Disposition,
     pub tags: Option<StreamTags>,
     pub profile: Option<String>,
@@ -117,11 +306,14 @@ pub struct Stream {
     pub nal_length_size: Option<String>,
     pub field_order: Option<String>,
     pub id: Option<String>,
-    #[serde(default)]
     pub side_data_list: Vec<SideData>,
 }
 
 impl Stream {
+    pub fn is_audio(&self) -> bool {
+        self.codec_type.as_deref() == Some("audio")
+    }
+
     pub fn resolution(&self) -> (u32, u32) {
         match (self.width, self.height) {
             (Some(width), Some(height)) => (width as u32, height as u32),
@@ -140,38 +332,78 @@ impl Stream {
             _ => 0.0,
         }
     }
+
+    /// The bitrate of the stream, from the statistics tags that mkvmerge writes
+    /// when the container doesn't store one.
+    pub fn bitrate(&self) -> Option<u64> {
+        self.bit_rate
+            .as_deref()
+            .or_else(|| self.tags.as_ref()?.bps.as_deref())
+            .and_then(|bitrate| bitrate.parse().ok())
+    }
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
 pub struct SideData {
     pub side_data_type: String,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
 pub struct Disposition {
+    #[serde(skip_serializing_if = "is_zero")]
     pub default: i64,
+    #[serde(skip_serializing_if = "is_zero")]
     pub dub: i64,
+    #[serde(skip_serializing_if = "is_zero")]
     pub original: i64,
+    #[serde(skip_serializing_if = "is_zero")]
     pub comment: i64,
+    #[serde(skip_serializing_if = "is_zero")]
     pub lyrics: i64,
+    #[serde(skip_serializing_if = "is_zero")]
     pub karaoke: i64,
+    #[serde(skip_serializing_if = "is_zero")]
     pub forced: i64,
+    #[serde(skip_serializing_if = "is_zero")]
     pub hearing_impaired: i64,
+    #[serde(skip_serializing_if = "is_zero")]
     pub visual_impaired: i64,
+    /// An audio description for the visually impaired.
+    #[serde(skip_serializing_if = "is_zero")]
+    pub descriptions: i64,
+    #[serde(skip_serializing_if = "is_zero")]
     pub clean_effects: i64,
+    #[serde(skip_serializing_if = "is_zero")]
     pub attached_pic: i64,
+    #[serde(skip_serializing_if = "is_zero")]
     pub timed_thumbnails: i64,
 }
 
+fn is_zero(value: &i64) -> bool {
+    *value == 0
+}
+
+impl Disposition {
+    fn is_default(&self) -> bool {
+        *self == Disposition::default()
+    }
+}
+
 #[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
 pub struct StreamTags {
     pub language: Option<String>,
     pub creation_time: Option<String>,
     pub handler_name: Option<String>,
+    pub title: Option<String>,
     pub encoder: Option<String>,
+    #[serde(rename = "BPS")]
+    pub bps: Option<String>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
 pub struct Format {
     pub filename: String,
     pub nb_streams: i64,
@@ -186,6 +418,76 @@ pub struct Format {
     pub tags: Option<FormatTags>,
 }
 
+/// A chapter of a file. ffprobe versions differ in whether they print the
+/// times as strings or as numbers, both are read.
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Chapter {
+    pub id: i64,
+    #[serde(deserialize_with = "seconds")]
+    pub start_time: Option<f64>,
+    #[serde(deserialize_with = "seconds")]
+    pub end_time: Option<f64>,
+    pub tags: Option<ChapterTags>,
+}
+
+impl Chapter {
+    pub fn title(&self) -> Option<&str> {
+        self.tags
+            .as_ref()
+            .and_then(|tags| tags.title.as_deref())
+            .map(str::trim)
+            .filter(|title| !title.is_empty())
+    }
+}
+
+impl fmt::Display for Chapter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let time = |seconds: Option<f64>| match seconds {
+            Some(seconds) => {
+                let millis = (seconds * 1000.0).round() as u64;
+                format!(
+                    "{}:{:02}:{:02}.{:03}",
+                    millis / 3_600_000,
+                    millis / 60_000 % 60,
+                    millis / 1000 % 60,
+                    millis % 1000
+                )
+            }
+            None => "?".into(),
+        };
+        write!(f, "{} - {}", time(self.start_time), time(self.end_time))?;
+        if let Some(title) = self.title() {
+            write!(f, " \"{title}\"")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct ChapterTags {
+    pub title: Option<String>,
+}
+
+/// Reads a time in seconds that is a string (`"300.000000"`), a number or
+/// missing. Times ffprobe doesn't know, like `"N/A"`, are `None`.
+fn seconds<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<f64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Seconds {
+        Number(f64),
+        Text(String),
+    }
+
+    Ok(match Option::<Seconds>::deserialize(deserializer)? {
+        Some(Seconds::Number(seconds)) => Some(seconds),
+        Some(Seconds::Text(seconds)) => seconds.parse().ok(),
+        None => None,
+    })
+}
+
 impl Format {
     #[allow(dead_code)]
     pub fn duration(&self) -> Option<f64> {
@@ -210,13 +512,47 @@ pub struct FormatTags {
     pub compatible_brands: Option<String>,
     pub creation_time: Option<String>,
     pub encoder: Option<String>,
+    pub title: Option<String>,
+}
+
+/// Whether the audio takes more of the bitrate than the video. Re-encoding the
+/// video of such a file saves little, its audio has to be encoded instead.
+pub fn is_audio_dominated(video_bitrate: u64, audio_bitrate: u64) -> bool {
+    video_bitrate > 0 && audio_bitrate > video_bitrate
+}
+
+/// Bits per pixel per frame, a measure of how much a video is compressed. `None`
+/// when the bitrate, resolution or frame rate is unknown.
+pub fn bits_per_pixel(bitrate: u64, (width, height): (u32, u32), frame_rate: f64) -> Option<f64> {
+    let pixels_per_second = width as f64 * height as f64 * frame_rate;
+    (bitrate > 0 && pixels_per_second > 0.0).then(|| bitrate as f64 / pixels_per_second)
+}
+
+/// Maps ffprobe's format names (e.g. "mov,mp4,m4a,3gp,3g2,mj2") and common file
+/// extensions to a single container name, so that the same container is always
+/// displayed and filtered by the same name.
+pub fn container_name(format_name: &str) -> String {
+    let name = format_name
+        .split(',')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_lowercase();
+    match name.as_str() {
+        "mov" | "mp4" | "m4a" | "m4v" | "3gp" | "3g2" | "mj2" => "mp4".into(),
+        "matroska" | "mkv" | "webm" => "matroska".into(),
+        "asf" | "wmv" | "wma" => "asf".into(),
+        "mpegts" | "ts" | "m2ts" => "mpegts".into(),
+        _ => name,
+    }
 }
 
 pub fn commandline_error(command_name: &str, output: Output) -> color_eyre::Report {
     use color_eyre::eyre::eyre;
 
-    let stdout = std::str::from_utf8(&output.stdout).unwrap();
-    let stderr = std::str::from_utf8(&output.stderr).unwrap();
+    // the stderr of an encode is only its tail, which can start inside a character
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
     eyre!(
         "command {} failed with exit code {}, stdout:\n'{}'\nstderr:\n'{}'",
         command_name,
@@ -226,40 +562,356 @@ pub fn commandline_error(command_name: &str, output: Output) -> color_eyre::Repo
     )
 }
 
-pub fn ffprobe(path: impl AsRef<Utf8Path>) -> Result<FfProbe> {
-    info!("ffprobe {}", path.as_ref());
-    let args = &[
+fn probe_args(path: &Utf8Path) -> [&str; 8] {
+    [
         "-v",
         "error",
         "-print_format",
         "json",
         "-show_format",
         "-show_streams",
-        path.as_ref().as_str(),
-    ];
+        "-show_chapters",
+        path.as_str(),
+    ]
+}
+
+pub fn ffprobe(path: impl AsRef<Utf8Path>) -> Result<FfProbe> {
+    info!("ffprobe {}", path.as_ref());
+    let output = binaries::command(Binary::Ffprobe)
+        .args(probe_args(path.as_ref()))
+        .output()
+        .map_err(|e| binaries::spawn_error(Binary::Ffprobe, e))?;
+    parse_output(path.as_ref(), output)
+}
+
+/// Like [`ffprobe`], but kills ffprobe when it takes longer than `timeout`, e.g.
+/// on storage that stopped responding.
+pub fn ffprobe_with_timeout(path: &Utf8Path, timeout: Duration) -> Result<FfProbe> {
+    info!("ffprobe {path}");
+    let child = binaries::command(Binary::Ffprobe)
+        .args(probe_args(path))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| binaries::spawn_error(Binary::Ffprobe, e))?;
+    let output =
+        wait_with_timeout(child, timeout).map_err(|e| e.wrap_err(format!("ffprobe of {path}")))?;
+    parse_output(path, output)
+}
+
+/// How often [`wait_with_timeout`] checks whether the command is done.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Waits for a command with piped output like [`Child::wait_with_output`],
+/// unless it takes longer than `timeout`, then it is killed.
+///
+/// [`Child::wait_with_output`]: std::process::Child::wait_with_output
+fn wait_with_timeout(mut child: Child, timeout: Duration) -> Result<Output> {
+    // read while waiting, a command that fills the pipe would never finish
+    let read = |mut pipe: Box<dyn Read + Send>| {
+        thread::spawn(move || {
+            let mut buffer = vec![];
+            let _ = pipe.read_to_end(&mut buffer);
+            buffer
+        })
+    };
+    let stdout = read(Box::new(child.stdout.take().expect("stdout is piped")));
+    let stderr = read(Box::new(child.stderr.take().expect("stderr is piped")));
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            bail!("gave up after {}", timeout.human_duration());
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+    Ok(Output {
+        status,
+        stdout: stdout.join().unwrap_or_default(),
+        stderr: stderr.join().unwrap_or_default(),
+    })
+}
 
-    let output = Command::new("ffprobe").args(args).output()?;
+fn parse_output(path: &Utf8Path, output: Output) -> Result<FfProbe> {
     if output.status.success() {
         let json: FfProbe = serde_json::from_slice(&output.stdout)?;
         debug!("ffprobe output: {:#?}", json);
-        info!("{}: {}", path.as_ref(), json.video_codec());
+        info!("{}: {}", path, json.video_codec());
         Ok(json)
     } else {
         Err(commandline_error("ffprobe", output))
     }
 }
 
+/// Real ffprobe output of a few typical files.
+#[cfg(test)]
+pub fn fixtures() -> Vec<FfProbe> {
+    [
+        include_str!("../fixtures/ffprobe/h264_aac.mp4.json"),
+        include_str!("../fixtures/ffprobe/hevc_ac3_subtitles.mkv.json"),
+    ]
+    .into_iter()
+    .map(|json| serde_json::from_str(json).unwrap())
+    .collect()
+}
+
+/// Real ffprobe output of an episode with chapters.
+#[cfg(test)]
+pub fn chaptered_fixture() -> FfProbe {
+    serde_json::from_str(include_str!(
+        "../fixtures/ffprobe/h264_eac3_chapters.mkv.json"
+    ))
+    .unwrap()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testsupport::{self, Sample};
+
+    #[cfg(unix)]
+    #[test]
+    fn test_wait_with_timeout() -> Result<()> {
+        use std::process::Command;
+
+        let spawn = |script: &str| {
+            Command::new("sh")
+                .args(["-c", script])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+        };
+        let output = wait_with_timeout(spawn("echo out; echo err >&2")?, Duration::from_secs(10))?;
+        assert!(output.status.success());
+        assert_eq!(b"out\n".as_slice(), output.stdout);
+        assert_eq!(b"err\n".as_slice(), output.stderr);
+
+        let start = Instant::now();
+        let error = wait_with_timeout(spawn("sleep 10")?, Duration::from_millis(200)).unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert!(error.to_string().starts_with("gave up after"), "{error}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_container_name() {
+        assert_eq!("mp4", container_name("mov,mp4,m4a,3gp,3g2,mj2"));
+        assert_eq!("mp4", container_name("mov"));
+        assert_eq!("matroska", container_name("matroska,webm"));
+        assert_eq!("matroska", container_name("MKV"));
+        assert_eq!("asf", container_name("asf"));
+        assert_eq!("asf", container_name("wmv"));
+        assert_eq!("avi", container_name("avi"));
+        assert_eq!("flv", container_name("flv"));
+        assert_eq!("mpegts", container_name("mpegts"));
+        assert_eq!("", container_name(""));
+    }
 
     #[test]
     fn test_serialization_and_deserialization() -> Result<()> {
-        let input_file = "samples/claire.mp4";
+        if !testsupport::has_ffmpeg() {
+            return Ok(());
+        }
+        let input_file = testsupport::sample(Sample::MKV)?;
         let ffprobe_output = ffprobe(input_file)?;
         let serialized = serde_json::to_string(&ffprobe_output)?;
         let deserialized: FfProbe = serde_json::from_str(&serialized)?;
         assert_eq!(ffprobe_output, deserialized);
         Ok(())
     }
+
+    #[test]
+    fn test_video_bitrate() {
+        let fixtures = fixtures();
+        assert_eq!(Some(4_798_327), fixtures[0].video_bitrate());
+        assert_eq!(192_003, fixtures[0].audio_bitrate());
+        // only mkvmerge's statistics tags
+        assert_eq!(Some(14_938_271), fixtures[1].video_bitrate());
+        assert_eq!(640_000, fixtures[1].audio_bitrate());
+
+        // without a bitrate for the video, the audio is taken from the container's
+        let mut info = fixtures[1].clone();
+        info.streams[0].tags = None;
+        assert_eq!(Some(15_605_853 - 640_000), info.video_bitrate());
+        // audio without a bitrate counts as nothing
+        for stream in &mut info.streams {
+            stream.bit_rate = None;
+            stream.tags = None;
+        }
+        assert_eq!(Some(15_605_853), info.video_bitrate());
+        assert_eq!(0, info.audio_bitrate());
+        info.format.bit_rate = None;
+        assert_eq!(None, info.video_bitrate());
+    }
+
+    #[test]
+    fn test_audio_dominated() {
+        // 6 Mbit/s in total, 4 of them DTS
+        let mut info = fixtures()[1].clone();
+        info.streams[0].tags = None;
+        info.streams[1].bit_rate = Some("4000000".into());
+        info.format.bit_rate = Some("6000000".into());
+        assert_eq!(Some(2_000_000), info.video_bitrate());
+        assert!(is_audio_dominated(2_000_000, info.audio_bitrate()));
+
+        assert!(!is_audio_dominated(
+            fixtures()[1].video_bitrate().unwrap(),
+            fixtures()[1].audio_bitrate()
+        ));
+        // more audio than the whole container is broken info, not a verdict
+        info.format.bit_rate = Some("3000000".into());
+        assert_eq!(None, info.video_bitrate());
+        assert!(!is_audio_dominated(0, 4_000_000));
+    }
+
+    #[test]
+    fn test_title() {
+        let titles: Vec<_> = fixtures()
+            .iter()
+            .map(|info| info.title().map(String::from))
+            .collect();
+        assert_eq!(
+            vec![
+                Some("Blade Runner (Director's Cut)".into()),
+                Some("Dune".into())
+            ],
+            titles
+        );
+        let blank = FfProbe {
+            format: Format {
+                tags: Some(FormatTags {
+                    title: Some("  ".into()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(None, blank.title());
+        assert_eq!(None, FfProbe::default().title());
+    }
+
+    #[test]
+    fn test_start_offset() {
+        for info in fixtures() {
+            assert_eq!(None, info.start_offset());
+        }
+        let iphone: FfProbe = serde_json::from_str(include_str!(
+            "../fixtures/ffprobe/iphone_edit_list.mov.json"
+        ))
+        .unwrap();
+        assert_eq!(Some(-0.02322), iphone.start_offset());
+
+        // the stream furthest from zero counts, tiny offsets don't
+        let info = |format: &str, streams: &[&str]| FfProbe {
+            streams: streams
+                .iter()
+                .map(|start| Stream {
+                    start_time: Some(start.to_string()),
+                    ..Default::default()
+                })
+                .collect(),
+            format: Format {
+                start_time: Some(format.into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            Some(1.4),
+            info("1.400000", &["1.400000", "1.380000"]).start_offset()
+        );
+        assert_eq!(
+            Some(-0.05),
+            info("0.000000", &["0.010000", "-0.050000"]).start_offset()
+        );
+        assert_eq!(None, info("0.000500", &["-0.000200", "N/A"]).start_offset());
+        assert_eq!(None, FfProbe::default().start_offset());
+    }
+
+    #[test]
+    fn test_chapters() -> Result<()> {
+        // info of the fixtures was probed without -show_chapters
+        for info in fixtures() {
+            assert!(info.chapters.is_empty());
+        }
+
+        let info = chaptered_fixture();
+        let chapters: Vec<_> = info.chapters.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            vec![
+                "0:00:00.000 - 0:01:35.512 \"Intro\"",
+                "0:01:35.512 - 0:22:22.050 \"Chapter 02\"",
+                "0:22:22.050 - 0:43:58.221 \"Chapter 03\"",
+                "0:43:58.221 - 0:45:12.085 \"Credits\"",
+            ],
+            chapters
+        );
+        assert_eq!(-3409876529016842201, info.chapters[1].id);
+        // stripping keeps them
+        let stripped: FfProbe = serde_json::from_str(&info.to_compact_json()?)?;
+        assert_eq!(info.chapters, stripped.chapters);
+
+        // older ffprobe versions print the times as numbers, unknown ones as N/A
+        let chapters: Vec<Chapter> = serde_json::from_str(
+            r#"[
+                {"id": 0, "start_time": 95.512, "end_time": "1342.050000"},
+                {"id": 1, "start_time": "N/A", "tags": {"title": " "}}
+            ]"#,
+        )?;
+        assert_eq!(Some(95.512), chapters[0].start_time);
+        assert_eq!(Some(1342.05), chapters[0].end_time);
+        assert_eq!(None, chapters[1].start_time);
+        assert_eq!(None, chapters[1].end_time);
+        assert_eq!(None, chapters[1].title());
+        assert_eq!("? - ?", chapters[1].to_string());
+        Ok(())
+    }
+
+    #[test]
+    fn test_stripped() -> Result<()> {
+        use crate::audio::AudioTrack;
+
+        for info in fixtures() {
+            let json = info.to_compact_json()?;
+            let stripped: FfProbe = serde_json::from_str(&json)?;
+            assert_eq!(info.stripped(), stripped);
+            assert!(json.len() * 4 < serde_json::to_string(&info)?.len());
+
+            // everything that is read from the info is still there
+            assert_eq!(info.duration(), stripped.duration());
+            assert_eq!(info.bitrate(), stripped.bitrate());
+            assert_eq!(info.resolution(), stripped.resolution());
+            assert_eq!(info.frame_rate(), stripped.frame_rate());
+            assert_eq!(info.video_codec(), stripped.video_codec());
+            assert_eq!(info.video_profile(), stripped.video_profile());
+            assert_eq!(info.pix_fmt(), stripped.pix_fmt());
+            assert_eq!(info.container(), stripped.container());
+            assert_eq!(info.size(), stripped.size());
+            assert_eq!(info.start_offset(), stripped.start_offset());
+            assert_eq!(info.title(), stripped.title());
+            assert_eq!(
+                info.streams.iter().map(Stream::bitrate).collect::<Vec<_>>(),
+                stripped
+                    .streams
+                    .iter()
+                    .map(Stream::bitrate)
+                    .collect::<Vec<_>>()
+            );
+            assert_eq!(
+                AudioTrack::from_probe(&info),
+                AudioTrack::from_probe(&stripped)
+            );
+            let levels = |info: &FfProbe| info.streams.iter().map(|s| s.level).collect::<Vec<_>>();
+            assert_eq!(levels(&info), levels(&stripped));
+
+            // stripping again changes nothing
+            assert_eq!(json, stripped.to_compact_json()?);
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,155 @@
+use camino::Utf8Path;
+use sysinfo::Disks;
+
+const GIB: u64 = 1024 * 1024 * 1024;
+
+/// The largest file the filesystem can hold, for filesystems where that matters for video files.
+pub fn file_size_limit(fs_type: &str) -> Option<u64> {
+    match fs_type {
+        "msdos" | "iso9660" => Some(4 * GIB - 1),
+        "hfs" => Some(2 * GIB - 1),
+        _ => None,
+    }
+}
+
+/// Returns the filesystem's limit if a file of `size` bytes doesn't fit on it.
+pub fn exceeds_limit(fs_type: &str, size: u64) -> Option<u64> {
+    file_size_limit(fs_type).filter(|&limit| size > limit)
+}
+
+/// The type of the filesystem the path is on, if it's one with a file size limit.
+#[cfg(target_os = "linux")]
+pub fn filesystem_type(path: &Utf8Path) -> Option<&'static str> {
+    use std::ffi::CString;
+
+    const MSDOS_SUPER_MAGIC: i64 = 0x4d44;
+    const HFS_SUPER_MAGIC: i64 = 0x4244;
+    const ISOFS_SUPER_MAGIC: i64 = 0x9660;
+
+    let path = CString::new(path.as_str()).ok()?;
+    // SAFETY: `path` is a valid C string and `stat` is a plain struct that statfs fills in.
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    // f_type is a different integer type depending on the architecture
+    #[allow(clippy::unnecessary_cast)]
+    match stat.f_type as i64 {
+        MSDOS_SUPER_MAGIC => Some("msdos"),
+        HFS_SUPER_MAGIC => Some("hfs"),
+        ISOFS_SUPER_MAGIC => Some("iso9660"),
+        _ => None,
+    }
+}
+
+/// The type of the filesystem the path is on, if it's one with a file size limit.
+#[cfg(target_os = "macos")]
+pub fn filesystem_type(path: &Utf8Path) -> Option<&'static str> {
+    use std::ffi::{CStr, CString};
+
+    let path = CString::new(path.as_str()).ok()?;
+    // SAFETY: `path` is a valid C string and `stat` is a plain struct that statfs fills in.
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    // SAFETY: f_fstypename is a NUL-terminated string.
+    let name = unsafe { CStr::from_ptr(stat.f_fstypename.as_ptr()) };
+    // "hfs" on macOS is HFS+, which has no relevant limit
+    match name.to_str().ok()? {
+        "msdos" => Some("msdos"),
+        "cd9660" => Some("iso9660"),
+        _ => None,
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn filesystem_type(_path: &Utf8Path) -> Option<&'static str> {
+    None
+}
+
+/// File names up to this many bytes fit on every common filesystem. eCryptfs
+/// takes the fewest, most others take 255.
+pub const SAFE_NAME_LENGTH: usize = 143;
+
+/// The longest file name most filesystems take, in bytes.
+pub const DEFAULT_NAME_LENGTH: usize = 255;
+
+/// The longest file name the filesystem of the directory takes, in bytes. A
+/// directory that doesn't exist yet is on the filesystem of its closest ancestor
+/// that does.
+#[cfg(unix)]
+pub fn max_name_length(directory: &Utf8Path) -> Option<usize> {
+    use std::ffi::CString;
+
+    directory.ancestors().find_map(|directory| {
+        let path = CString::new(directory.as_str()).ok()?;
+        // SAFETY: `path` is a valid C string and `stat` is a plain struct that statvfs fills in.
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+            return None;
+        }
+        usize::try_from(stat.f_namemax).ok().filter(|&max| max > 0)
+    })
+}
+
+#[cfg(not(unix))]
+pub fn max_name_length(_directory: &Utf8Path) -> Option<usize> {
+    None
+}
+
+/// The id of the device the path is on, which differs between filesystems.
+#[cfg(unix)]
+pub fn device_id(path: &Utf8Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    std::fs::metadata(path).ok().map(|m| m.dev())
+}
+
+#[cfg(not(unix))]
+pub fn device_id(_path: &Utf8Path) -> Option<u64> {
+    None
+}
+
+/// Free space on the filesystem the path is on, in bytes.
+pub fn available_space(path: &Utf8Path) -> Option<u64> {
+    let path = path.canonicalize().ok()?;
+    let disks = Disks::new_with_refreshed_list();
+    disks
+        .iter()
+        .filter(|disk| path.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().as_os_str().len())
+        .map(|disk| disk.available_space())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_size_limit() {
+        assert_eq!(Some(4 * GIB - 1), file_size_limit("msdos"));
+        assert_eq!(Some(2 * GIB - 1), file_size_limit("hfs"));
+        assert_eq!(None, file_size_limit("ext4"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_max_name_length() -> crate::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        let max = max_name_length(dir).unwrap();
+        assert!(max >= SAFE_NAME_LENGTH, "{max}");
+        // a directory that doesn't exist yet is where it would be created
+        assert_eq!(Some(max), max_name_length(&dir.join("missing/deeper")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_exceeds_limit() {
+        assert_eq!(None, exceeds_limit("msdos", 3 * GIB));
+        assert_eq!(None, exceeds_limit("msdos", 4 * GIB - 1));
+        assert_eq!(Some(4 * GIB - 1), exceeds_limit("msdos", 4 * GIB));
+        assert_eq!(None, exceeds_limit("btrfs", 100 * GIB));
+    }
+}
@@ -0,0 +1,359 @@
+//! The steps after an encode, for files encoded with `--no-finalize`: checking
+//! the output, putting it in place and marking the file as transcoded. They can
+//! run later and on another machine, e.g. the one that owns the storage.
+
+use std::fmt;
+use std::fs;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use tracing::{info, warn};
+
+use crate::Result;
+use crate::database::{Database, TranscodeFile};
+use crate::paths;
+use crate::results::FileResult;
+use crate::sidecar;
+use crate::verify::{self, Window};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FinalizeOptions {
+    /// Decode the output before putting it in place.
+    pub verify: bool,
+    /// Replace the source with the output.
+    pub replace: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct FinalizeSummary {
+    pub finalized: usize,
+    /// Files whose output couldn't be put in place, with the reason.
+    pub failed: Vec<(Utf8PathBuf, String)>,
+}
+
+/// Why an output wasn't put in place.
+#[derive(Debug, Clone, PartialEq)]
+enum Failure {
+    /// The output is missing or not smaller than the source, or moving it failed.
+    Check(String),
+    /// Decoding the output found errors.
+    Verification(String),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Check(reason) => write!(f, "{reason}"),
+            Failure::Verification(errors) => write!(f, "the output failed verification: {errors}"),
+        }
+    }
+}
+
+/// Checks the output of one file and puts it in place, returning where it is.
+/// Running it again after an interruption is safe: a source that was already
+/// replaced is recognized by being smaller than when it was scanned, which only
+/// an output that passed the checks is. A WebM output replaces the source under
+/// its own extension, see [`paths::replacement`].
+fn finalize_file(
+    file: &TranscodeFile,
+    options: FinalizeOptions,
+    decode: &(impl Fn(&Utf8Path, Option<Window>) -> Result<String> + Sync),
+) -> Result<Utf8PathBuf, Failure> {
+    let output = file
+        .output_path
+        .as_deref()
+        .ok_or_else(|| Failure::Check("no output was recorded".into()))?;
+    let source_size = file.file_size as u64;
+    let replacement = paths::replacement(&file.path, output);
+    let replaced = options.replace
+        && !output.is_file()
+        && fs::metadata(&replacement).is_ok_and(|metadata| metadata.len() < source_size);
+    let current = if replaced { &replacement } else { output };
+    let size = fs::metadata(current)
+        .map_err(|_| Failure::Check(format!("the output {output} is missing")))?
+        .len();
+    if size >= source_size {
+        return Err(Failure::Check(format!(
+            "the output ({size} bytes) is not smaller than the source ({source_size} bytes)"
+        )));
+    }
+    if options.verify {
+        let duration = file
+            .ffprobe()
+            .and_then(|info| info.duration())
+            .unwrap_or_default();
+        if let Some(errors) = verify::verify_file(current, duration, None, decode) {
+            return Err(Failure::Verification(errors));
+        }
+    }
+    if !options.replace {
+        return Ok(output.to_owned());
+    }
+    if !replaced {
+        paths::move_file(output, &replacement).map_err(|e| {
+            Failure::Check(format!("could not replace the source with {output}: {e}"))
+        })?;
+    }
+    if replacement != file.path && file.path.is_file() {
+        fs::remove_file(&file.path).map_err(|e| {
+            Failure::Check(format!(
+                "could not remove the source replaced by {replacement}: {e}"
+            ))
+        })?;
+    }
+    Ok(replacement)
+}
+
+/// Finalizes the files one after the other and records the results, in the
+/// sidecars of the outputs as well. Files whose output fails a check get the
+/// status error, their outputs are left alone.
+pub fn finalize(
+    database: &Database,
+    files: Vec<TranscodeFile>,
+    options: FinalizeOptions,
+    decode: impl Fn(&Utf8Path, Option<Window>) -> Result<String> + Sync,
+) -> Result<FinalizeSummary> {
+    let mut summary = FinalizeSummary::default();
+    for file in files {
+        match finalize_file(&file, options, &decode) {
+            Ok(output) => {
+                info!("{}: finalized {output}", file.path);
+                if options.verify {
+                    database.set_verification(file.id, None)?;
+                }
+                database.record_result(&FileResult::transcoded(file.id, &file.path, &output))?;
+                sidecar::try_update(database, file.id, file.output_path.as_deref());
+                summary.finalized += 1;
+            }
+            Err(failure) => {
+                warn!("Could not finalize {}: {failure}", file.path);
+                if let Failure::Verification(errors) = &failure {
+                    database.set_verification(file.id, Some(errors))?;
+                }
+                database.record_result(&FileResult::failed(
+                    file.id,
+                    &file.path,
+                    failure.to_string(),
+                ))?;
+                sidecar::try_update(database, file.id, None);
+                summary.failed.push((file.path, failure.to_string()));
+            }
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::database::{FileFilter, NewTranscodeFile, TranscodeStatus};
+    use crate::ffprobe::FfProbe;
+
+    const SOURCE: &[u8] = b"the source, before it was encoded";
+    const OUTPUT: &[u8] = b"the output";
+
+    /// A source in a temp directory with an output encoded with `--no-finalize`.
+    struct Setup {
+        _tempdir: tempfile::TempDir,
+        db: Database,
+        source: Utf8PathBuf,
+        output: Utf8PathBuf,
+    }
+
+    fn setup() -> Result<Setup> {
+        setup_named("movie.mkv", "movie_av1.mp4")
+    }
+
+    fn setup_named(source: &str, output: &str) -> Result<Setup> {
+        let tempdir = tempfile::tempdir()?;
+        let directory = Utf8Path::from_path(tempdir.path()).unwrap().to_owned();
+        let source = directory.join(source);
+        let output = directory.join(output);
+        fs::write(&source, SOURCE)?;
+        fs::write(&output, OUTPUT)?;
+        let db = Database::in_memory()?;
+        db.insert_batch(&[NewTranscodeFile {
+            path: source.clone(),
+            file_size: SOURCE.len() as u64,
+            ffprobe_info: FfProbe::default(),
+        }])?;
+        let id = db.get_by_path(&source)?.unwrap().id;
+        db.record_result(&FileResult::encoded(id, &source, &output))?;
+        Ok(Setup {
+            _tempdir: tempdir,
+            db,
+            source,
+            output,
+        })
+    }
+
+    impl Setup {
+        fn encoded(&self) -> Result<Vec<TranscodeFile>> {
+            self.db.list_filtered(
+                &FileFilter {
+                    status: Some(TranscodeStatus::Encoded),
+                    ..Default::default()
+                },
+                None,
+            )
+        }
+
+        fn file(&self) -> Result<TranscodeFile> {
+            Ok(self.db.get_by_path(&self.source)?.unwrap())
+        }
+    }
+
+    fn intact(_: &Utf8Path, _: Option<Window>) -> Result<String> {
+        Ok(String::new())
+    }
+
+    #[test]
+    fn test_finalize_next_to_the_source() -> Result<()> {
+        let setup = setup()?;
+        assert_eq!(1, setup.encoded()?.len());
+        let summary = finalize(
+            &setup.db,
+            setup.encoded()?,
+            FinalizeOptions::default(),
+            intact,
+        )?;
+        assert_eq!(1, summary.finalized);
+        let file = setup.file()?;
+        assert_eq!(TranscodeStatus::Success, file.status);
+        assert_eq!(Some(&setup.output), file.output_path.as_ref());
+        assert_eq!(SOURCE, fs::read(&setup.source)?);
+        assert!(file.verified_at.is_none());
+
+        // nothing is left for a second run
+        assert!(setup.encoded()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_and_verify() -> Result<()> {
+        let setup = setup()?;
+        sidecar::write(&setup.db, &setup.file()?, None)?;
+        let options = FinalizeOptions {
+            verify: true,
+            replace: true,
+        };
+        let decoded = AtomicUsize::new(0);
+        let summary = finalize(&setup.db, setup.encoded()?, options, |path, window| {
+            assert_eq!(setup.output, path);
+            assert_eq!(None, window);
+            decoded.fetch_add(1, Ordering::SeqCst);
+            Ok(String::new())
+        })?;
+        assert_eq!(1, summary.finalized);
+        assert_eq!(1, decoded.load(Ordering::SeqCst));
+        let file = setup.file()?;
+        assert_eq!(TranscodeStatus::Success, file.status);
+        assert_eq!(Some(&setup.source), file.output_path.as_ref());
+        assert!(file.verified_at.is_some() && file.verify_error.is_none());
+        assert_eq!(OUTPUT, fs::read(&setup.source)?);
+        assert!(!setup.output.exists());
+        // the sidecar moved along with the output
+        assert!(!sidecar::path_for(&setup.output).exists());
+        let report = sidecar::Sidecar::read(&sidecar::path_for(&setup.source))?;
+        assert_eq!(TranscodeStatus::Success, report.result.status);
+        assert!(report.verification.verified_at.is_some());
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace_with_webm() -> Result<()> {
+        let setup = setup_named("movie.mp4", "movie_vp9.webm")?;
+        let files = setup.encoded()?;
+        let options = FinalizeOptions {
+            verify: false,
+            replace: true,
+        };
+        let summary = finalize(&setup.db, files.clone(), options, intact)?;
+        assert_eq!(1, summary.finalized);
+        // the output is next to where the source was, under its own extension
+        let replacement = setup.source.with_extension("webm");
+        assert_eq!(Some(&replacement), setup.file()?.output_path.as_ref());
+        assert_eq!(OUTPUT, fs::read(&replacement)?);
+        assert!(!setup.source.exists());
+        assert!(!setup.output.exists());
+
+        // and is recognized when finalizing again
+        let summary = finalize(&setup.db, files, options, intact)?;
+        assert_eq!(1, summary.finalized);
+        assert_eq!(OUTPUT, fs::read(&replacement)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_interrupted_after_replacing() -> Result<()> {
+        // the source was replaced, but the status wasn't written
+        let setup = setup()?;
+        let files = setup.encoded()?;
+        fs::rename(&setup.output, &setup.source)?;
+        let options = FinalizeOptions {
+            verify: true,
+            replace: true,
+        };
+        let summary = finalize(&setup.db, files.clone(), options, |path, _| {
+            assert_eq!(setup.source, path);
+            Ok(String::new())
+        })?;
+        assert_eq!(1, summary.finalized);
+        let file = setup.file()?;
+        assert_eq!(TranscodeStatus::Success, file.status);
+        assert_eq!(Some(&setup.source), file.output_path.as_ref());
+        assert_eq!(OUTPUT, fs::read(&setup.source)?);
+
+        // finalizing the same rows again changes nothing
+        let summary = finalize(&setup.db, files, options, intact)?;
+        assert_eq!(1, summary.finalized);
+        assert_eq!(OUTPUT, fs::read(&setup.source)?);
+        assert_eq!(Some(setup.source.clone()), setup.file()?.output_path);
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_checks() -> Result<()> {
+        let setup = setup()?;
+        let broken = |_: &Utf8Path, _: Option<Window>| Ok("corrupt decoded frame".to_string());
+        let options = FinalizeOptions {
+            verify: true,
+            replace: true,
+        };
+        let summary = finalize(&setup.db, setup.encoded()?, options, broken)?;
+        assert_eq!(0, summary.finalized);
+        assert_eq!(
+            "the output failed verification: corrupt decoded frame",
+            summary.failed[0].1
+        );
+        let file = setup.file()?;
+        assert_eq!(TranscodeStatus::Error, file.status);
+        assert_eq!(Some("corrupt decoded frame"), file.verify_error.as_deref());
+        // the source is left alone
+        assert_eq!(SOURCE, fs::read(&setup.source)?);
+        assert!(setup.output.exists());
+
+        let file = setup.file()?;
+        fs::write(&setup.output, SOURCE)?;
+        assert_eq!(
+            Err(Failure::Check(format!(
+                "the output ({0} bytes) is not smaller than the source ({0} bytes)",
+                SOURCE.len()
+            ))),
+            finalize_file(&file, options, &intact)
+        );
+
+        // without replacing, a missing output isn't mistaken for a replaced source
+        fs::remove_file(&setup.output)?;
+        fs::write(&setup.source, OUTPUT)?;
+        assert_eq!(
+            Err(Failure::Check(format!(
+                "the output {} is missing",
+                setup.output
+            ))),
+            finalize_file(&file, FinalizeOptions::default(), &intact)
+        );
+        Ok(())
+    }
+}
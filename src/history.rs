@@ -0,0 +1,330 @@
+//! `stats --history`: files encoded, bytes saved, speed and failures per ISO
+//! week or per run, to see how they trend over months.
+//!
+//! A file counts in the week its last encode finished, in the local time zone,
+//! not in the week its run started, so a run over a weekend is split between
+//! the two weeks. Only the last result of each file is known: a file that
+//! failed and was encoded by a later run counts once, for the later run.
+
+use std::collections::BTreeMap;
+
+use color_eyre::eyre::eyre;
+use jiff::civil::{Date, ISOWeekDate, Weekday};
+use jiff::tz::TimeZone;
+use jiff::{Timestamp, ToSpan};
+use serde::Serialize;
+
+use crate::Result;
+use crate::database::{HistoryEntry, TranscodeStatus};
+
+/// What the files of one week or run add up to.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Totals {
+    /// Files that were encoded, whether or not the source was replaced or
+    /// reclaimed since.
+    pub files: u64,
+    pub failures: u64,
+    /// The size of the sources of the encoded files.
+    pub bytes_before: u64,
+    /// The size of their outputs.
+    pub bytes_after: u64,
+    /// Seconds of video encoded, of the files with a recorded encode time.
+    pub media_seconds: f64,
+    pub encode_seconds: f64,
+}
+
+impl Totals {
+    fn add(&mut self, entry: &HistoryEntry) {
+        if entry.status == TranscodeStatus::Error {
+            self.failures += 1;
+            return;
+        }
+        self.files += 1;
+        if let Some(output_size) = entry.output_size {
+            self.bytes_before += entry.file_size.max(0) as u64;
+            self.bytes_after += output_size.max(0) as u64;
+        }
+        let duration = entry.ffprobe().and_then(|info| info.duration());
+        if let (Some(seconds), Some(duration)) = (entry.encode_seconds, duration)
+            && seconds > 0.0
+        {
+            self.media_seconds += duration;
+            self.encode_seconds += seconds;
+        }
+    }
+
+    /// Bytes saved, negative when the outputs were bigger.
+    pub fn saved(&self) -> i64 {
+        self.bytes_before as i64 - self.bytes_after as i64
+    }
+
+    /// Seconds of video encoded per second, `None` without encode times.
+    pub fn speed(&self) -> Option<f64> {
+        (self.encode_seconds > 0.0).then(|| self.media_seconds / self.encode_seconds)
+    }
+
+    /// Share of the finished files that failed, in percent.
+    pub fn failure_percent(&self) -> f64 {
+        match self.files + self.failures {
+            0 => 0.0,
+            total => self.failures as f64 / total as f64 * 100.0,
+        }
+    }
+}
+
+/// One ISO week.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Week {
+    /// The Monday the week starts on.
+    pub starts: Date,
+    #[serde(flatten)]
+    pub totals: Totals,
+}
+
+impl Week {
+    /// The ISO week, e.g. `2026-W41`.
+    pub fn label(&self) -> String {
+        let week = self.starts.iso_week_date();
+        format!("{:04}-W{:02}", week.year(), week.week())
+    }
+}
+
+/// One transcode run.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Run {
+    pub run_id: i64,
+    #[serde(with = "jiff::fmt::serde::timestamp::second::optional")]
+    pub started_on: Option<Timestamp>,
+    /// When the last file of the run finished.
+    #[serde(with = "jiff::fmt::serde::timestamp::second::required")]
+    pub finished_on: Timestamp,
+    #[serde(flatten)]
+    pub totals: Totals,
+}
+
+/// The Monday of the ISO week that `timestamp` is in, in `tz`.
+pub fn week_start(timestamp: Timestamp, tz: &TimeZone) -> Date {
+    let date = timestamp.to_zoned(tz.clone()).date();
+    monday(date.iso_week_date())
+}
+
+fn monday(week: ISOWeekDate) -> Date {
+    ISOWeekDate::new(week.year(), week.week(), Weekday::Monday)
+        .expect("every ISO week has a Monday")
+        .date()
+}
+
+/// The start of the week `weeks - 1` weeks before the one `now` is in, so that
+/// `weeks` weeks are shown including the current one.
+pub fn since(now: Timestamp, weeks: u32, tz: &TimeZone) -> Result<Timestamp> {
+    let back = i64::from(weeks.saturating_sub(1));
+    let monday = week_start(now, tz)
+        .checked_sub(back.weeks())
+        .map_err(|_| eyre!("--weeks {weeks} goes back too far"))?;
+    Ok(monday.to_zoned(tz.clone())?.timestamp())
+}
+
+/// The totals of every week from the first one with a result, or from `since`,
+/// to the one `now` is in. Weeks without results are included so that gaps
+/// show up when plotting.
+pub fn by_week(
+    entries: &[HistoryEntry],
+    since: Option<Timestamp>,
+    now: Timestamp,
+    tz: &TimeZone,
+) -> Vec<Week> {
+    let mut weeks: BTreeMap<Date, Totals> = BTreeMap::new();
+    for entry in entries {
+        weeks
+            .entry(week_start(entry.finished_on, tz))
+            .or_default()
+            .add(entry);
+    }
+    let first = since
+        .map(|since| week_start(since, tz))
+        .or_else(|| weeks.keys().next().copied());
+    let last = week_start(now, tz).max(weeks.keys().last().copied().unwrap_or(Date::MIN));
+    let mut week = match first {
+        Some(first) => first,
+        None => return vec![],
+    };
+    while week <= last {
+        weeks.entry(week).or_default();
+        match week.checked_add(1.week()) {
+            Ok(next) => week = next,
+            Err(_) => break,
+        }
+    }
+    weeks
+        .into_iter()
+        .map(|(starts, totals)| Week { starts, totals })
+        .collect()
+}
+
+/// The totals of every run with a result, oldest first. Files without a run
+/// are left out.
+pub fn by_run(entries: &[HistoryEntry]) -> Vec<Run> {
+    let mut runs: BTreeMap<i64, Run> = BTreeMap::new();
+    for entry in entries {
+        let Some(run_id) = entry.run_id else {
+            continue;
+        };
+        let run = runs.entry(run_id).or_insert_with(|| Run {
+            run_id,
+            started_on: entry.run_started_on,
+            finished_on: entry.finished_on,
+            totals: Totals::default(),
+        });
+        run.finished_on = run.finished_on.max(entry.finished_on);
+        run.totals.add(entry);
+    }
+    runs.into_values().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use jiff::civil::date;
+
+    use super::*;
+    use crate::database::ProbeJson;
+
+    // POSIX rules, so that the tests don't depend on the system's time zone
+    // database
+    const BERLIN: &str = "CET-1CEST,M3.5.0,M10.5.0/3";
+    const NEW_YORK: &str = "EST5EDT,M3.2.0,M11.1.0";
+
+    fn entry(run_id: i64, finished_on: &str, status: TranscodeStatus) -> HistoryEntry {
+        let probe = r#"{"streams":[],"format":{"duration":"600.0"}}"#;
+        HistoryEntry {
+            run_id: Some(run_id),
+            run_started_on: Some("2026-01-01T00:00:00Z".parse().unwrap()),
+            status,
+            finished_on: finished_on.parse().unwrap(),
+            file_size: 1000,
+            output_size: (status != TranscodeStatus::Error).then_some(400),
+            encode_seconds: Some(300.0),
+            ffprobe_info: ProbeJson::compress(probe).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_week_start() {
+        let utc = TimeZone::UTC;
+        for (timestamp, monday) in [
+            // Sunday night is still the week before
+            ("2026-10-11T23:59:59Z", date(2026, 10, 5)),
+            ("2026-10-12T00:00:00Z", date(2026, 10, 12)),
+            // 2021-01-03 is a Sunday in the last ISO week of 2020
+            ("2021-01-03T12:00:00Z", date(2020, 12, 28)),
+            // 2024-12-30 is the Monday of ISO week 1 of 2025
+            ("2025-01-01T12:00:00Z", date(2024, 12, 30)),
+        ] {
+            assert_eq!(monday, week_start(timestamp.parse().unwrap(), &utc));
+        }
+        let week = Week {
+            starts: date(2024, 12, 30),
+            totals: Totals::default(),
+        };
+        assert_eq!("2025-W01", week.label());
+        let week = Week {
+            starts: date(2020, 12, 28),
+            totals: Totals::default(),
+        };
+        assert_eq!("2020-W53", week.label());
+    }
+
+    #[test]
+    fn test_week_start_time_zones() {
+        // Sunday 23:30 UTC is already Monday in Berlin and still Sunday in New York
+        let timestamp: Timestamp = "2026-10-11T23:30:00Z".parse().unwrap();
+        let berlin = TimeZone::posix(BERLIN).unwrap();
+        let new_york = TimeZone::posix(NEW_YORK).unwrap();
+        assert_eq!(date(2026, 10, 12), week_start(timestamp, &berlin));
+        assert_eq!(date(2026, 10, 5), week_start(timestamp, &new_york));
+
+        // the week starts at local midnight, also in a week that ends daylight
+        // saving time
+        let since = since("2026-10-28T12:00:00Z".parse().unwrap(), 2, &berlin).unwrap();
+        assert_eq!("2026-10-18T22:00:00Z".parse::<Timestamp>().unwrap(), since);
+        let since = since_utc(1);
+        assert_eq!("2026-10-12T00:00:00Z".parse::<Timestamp>().unwrap(), since);
+    }
+
+    fn since_utc(weeks: u32) -> Timestamp {
+        since(
+            "2026-10-16T12:00:00Z".parse().unwrap(),
+            weeks,
+            &TimeZone::UTC,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_by_week() {
+        let entries = vec![
+            entry(1, "2026-09-22T10:00:00Z", TranscodeStatus::Success),
+            // the run continues into the next week
+            entry(2, "2026-10-04T22:00:00Z", TranscodeStatus::Success),
+            entry(2, "2026-10-05T02:00:00Z", TranscodeStatus::Reclaimed),
+            entry(2, "2026-10-05T03:00:00Z", TranscodeStatus::Error),
+        ];
+        let now = "2026-10-16T12:00:00Z".parse().unwrap();
+        let weeks = by_week(&entries, None, now, &TimeZone::UTC);
+        let labels: Vec<_> = weeks.iter().map(Week::label).collect();
+        assert_eq!(vec!["2026-W39", "2026-W40", "2026-W41", "2026-W42"], labels);
+        let counts: Vec<_> = weeks
+            .iter()
+            .map(|w| (w.totals.files, w.totals.failures))
+            .collect();
+        assert_eq!(vec![(1, 0), (1, 0), (1, 1), (0, 0)], counts);
+
+        let week = &weeks[2].totals;
+        assert_eq!(1000, week.bytes_before);
+        assert_eq!(400, week.bytes_after);
+        assert_eq!(600, week.saved());
+        assert_eq!(Some(2.0), week.speed());
+        assert_eq!(50.0, week.failure_percent());
+        assert_eq!(None, weeks[3].totals.speed());
+
+        // in New York, the file after midnight UTC still finished on Sunday
+        let tz = TimeZone::posix(NEW_YORK).unwrap();
+        let weeks = by_week(&entries, None, now, &tz);
+        assert_eq!((2, 1), (weeks[1].totals.files, weeks[1].totals.failures));
+
+        // --weeks starts at its week even when it had no results
+        let weeks = by_week(&[], Some(since_utc(2)), now, &TimeZone::UTC);
+        let labels: Vec<_> = weeks.iter().map(Week::label).collect();
+        assert_eq!(vec!["2026-W41", "2026-W42"], labels);
+
+        assert!(by_week(&[], None, now, &TimeZone::UTC).is_empty());
+    }
+
+    #[test]
+    fn test_by_run() {
+        let mut without_run = entry(1, "2026-10-01T00:00:00Z", TranscodeStatus::Success);
+        without_run.run_id = None;
+        let entries = vec![
+            entry(2, "2026-10-04T22:00:00Z", TranscodeStatus::Success),
+            entry(1, "2026-09-22T10:00:00Z", TranscodeStatus::Error),
+            entry(2, "2026-10-05T02:00:00Z", TranscodeStatus::Encoded),
+            without_run,
+        ];
+        let runs = by_run(&entries);
+        assert_eq!(2, runs.len());
+        assert_eq!(
+            (1, 0, 1),
+            (
+                runs[0].run_id,
+                runs[0].totals.files,
+                runs[0].totals.failures
+            )
+        );
+        assert_eq!(0, runs[0].totals.bytes_before);
+        assert_eq!(2, runs[1].totals.files);
+        assert_eq!(1200, runs[1].totals.saved());
+        assert_eq!(
+            "2026-10-05T02:00:00Z".parse::<Timestamp>().unwrap(),
+            runs[1].finished_on
+        );
+    }
+}
@@ -0,0 +1,287 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::thread::{Scope, ScopedJoinHandle};
+
+use color_eyre::eyre::{bail, eyre};
+use tiny_http::{Header, Response, Server};
+use tracing::{info, warn};
+
+use crate::Result;
+use crate::status::{QueueSnapshot, RunStatus, StatusSnapshot};
+
+#[derive(Debug, Clone)]
+pub struct HttpOptions {
+    pub listen: SocketAddr,
+    /// Token that clients have to send, either as `Authorization: Bearer <token>`
+    /// or as a `?token=<token>` query parameter.
+    pub token: Option<String>,
+}
+
+impl HttpOptions {
+    pub fn new(listen: SocketAddr, token: Option<String>) -> Result<Self> {
+        if !listen.ip().is_loopback() && token.is_none() {
+            bail!("--listen-token is required when listening on a non-loopback address ({listen})");
+        }
+        Ok(Self { listen, token })
+    }
+}
+
+struct Reply {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Reply {
+    fn text(status: u16, body: &str) -> Self {
+        Reply {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.into(),
+        }
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn render_html(snapshot: &StatusSnapshot) -> String {
+    let mut html = String::from(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><meta http-equiv=\"refresh\" content=\"5\">\
+         <title>transcoder</title></head><body>\n<h1>transcoder</h1>\n",
+    );
+    html.push_str(&format!(
+        "<p>{} of {} files finished: {} encoded, {} skipped, {} failed{}</p>\n<ul>\n",
+        snapshot.finished_files,
+        snapshot.total_files,
+        snapshot.encoded_files,
+        snapshot.skipped_files(),
+        snapshot.failed_files,
+        if snapshot.paused { " (paused)" } else { "" }
+    ));
+    for file in &snapshot.active {
+        let percent = if file.duration_ms > 0 {
+            file.position_ms as f64 / file.duration_ms as f64 * 100.0
+        } else {
+            0.0
+        };
+        html.push_str(&format!(
+            "<li>{} <progress value=\"{}\" max=\"{}\"></progress> {:.1}%</li>\n",
+            escape_html(file.path.file_name().unwrap_or_default()),
+            file.position_ms,
+            file.duration_ms,
+            percent
+        ));
+    }
+    html.push_str("</ul>\n</body></html>\n");
+    html
+}
+
+fn is_authorized(url: &str, authorization: Option<&str>, token: Option<&str>) -> bool {
+    let Some(token) = token else {
+        return true;
+    };
+    let from_header = authorization.and_then(|h| h.strip_prefix("Bearer "));
+    let from_query = url
+        .split_once('?')
+        .into_iter()
+        .flat_map(|(_, query)| query.split('&'))
+        .find_map(|pair| pair.strip_prefix("token="));
+    from_header == Some(token) || from_query == Some(token)
+}
+
+fn route(
+    method: &str,
+    url: &str,
+    authorization: Option<&str>,
+    token: Option<&str>,
+    status: &RunStatus,
+    queue: &dyn Fn() -> Result<QueueSnapshot>,
+    set_paused: &dyn Fn(bool),
+) -> Reply {
+    if !is_authorized(url, authorization, token) {
+        return Reply::text(401, "unauthorized");
+    }
+
+    let path = url.split('?').next().unwrap_or_default();
+    match (method, path) {
+        ("GET", "/") => Reply {
+            status: 200,
+            content_type: "text/html; charset=utf-8",
+            body: render_html(&status.snapshot()),
+        },
+        ("GET", "/status") => Reply {
+            status: 200,
+            content_type: "application/json",
+            body: serde_json::to_string(&status.snapshot()).expect("snapshot must serialize"),
+        },
+        ("GET", "/snapshot") => match queue() {
+            Ok(mut snapshot) => {
+                snapshot.run = Some(status.snapshot());
+                Reply {
+                    status: 200,
+                    content_type: "application/json",
+                    body: serde_json::to_string(&snapshot).expect("snapshot must serialize"),
+                }
+            }
+            Err(e) => {
+                warn!("could not read the queue status: {e:?}");
+                Reply::text(500, "could not read the queue status")
+            }
+        },
+        ("POST", "/pause") => {
+            set_paused(true);
+            Reply::text(200, "paused")
+        }
+        ("POST", "/resume") => {
+            set_paused(false);
+            Reply::text(200, "resumed")
+        }
+        _ => Reply::text(404, "not found"),
+    }
+}
+
+/// HTTP server for the status page, running until [`StatusServer::shutdown`] is called.
+pub struct StatusServer<'scope> {
+    server: Arc<Server>,
+    handle: ScopedJoinHandle<'scope, ()>,
+}
+
+impl StatusServer<'_> {
+    pub fn start<'scope, 'env>(
+        scope: &'scope Scope<'scope, 'env>,
+        options: &HttpOptions,
+        status: &'env RunStatus,
+        queue: impl Fn() -> Result<QueueSnapshot> + Send + 'scope,
+        set_paused: impl Fn(bool) + Send + 'scope,
+    ) -> Result<StatusServer<'scope>> {
+        let server = Server::http(options.listen)
+            .map_err(|e| eyre!("could not listen on {}: {e}", options.listen))?;
+        let server = Arc::new(server);
+        info!("serving status page on http://{}", options.listen);
+
+        let token = options.token.clone();
+        let incoming = server.clone();
+        let handle = scope.spawn(move || {
+            for request in incoming.incoming_requests() {
+                let authorization = request
+                    .headers()
+                    .iter()
+                    .find(|h| h.field.equiv("Authorization"))
+                    .map(|h| h.value.to_string());
+                let reply = route(
+                    request.method().as_str(),
+                    request.url(),
+                    authorization.as_deref(),
+                    token.as_deref(),
+                    status,
+                    &queue,
+                    &set_paused,
+                );
+                let content_type = Header::from_bytes("Content-Type", reply.content_type)
+                    .expect("content type must be a valid header");
+                let response = Response::from_string(reply.body)
+                    .with_status_code(reply.status)
+                    .with_header(content_type);
+                if let Err(e) = request.respond(response) {
+                    warn!("failed to send HTTP response: {}", e);
+                }
+            }
+        });
+
+        Ok(StatusServer { server, handle })
+    }
+
+    pub fn shutdown(self) {
+        self.server.unblock();
+        if self.handle.join().is_err() {
+            warn!("status server thread panicked");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    use super::*;
+    use crate::database::Database;
+
+    #[test]
+    fn test_non_loopback_requires_token() {
+        assert!(HttpOptions::new("127.0.0.1:8990".parse().unwrap(), None).is_ok());
+        assert!(HttpOptions::new("0.0.0.0:8990".parse().unwrap(), None).is_err());
+        assert!(HttpOptions::new("0.0.0.0:8990".parse().unwrap(), Some("secret".into())).is_ok());
+    }
+
+    #[test]
+    fn test_authorization() {
+        assert!(is_authorized("/status", None, None));
+        assert!(!is_authorized("/status", None, Some("secret")));
+        assert!(is_authorized(
+            "/status",
+            Some("Bearer secret"),
+            Some("secret")
+        ));
+        assert!(is_authorized("/status?token=secret", None, Some("secret")));
+        assert!(!is_authorized("/status?token=wrong", None, Some("secret")));
+    }
+
+    #[test]
+    fn test_routes() {
+        let status = RunStatus::new(3);
+        status.start_file("/videos/<b>.mkv".into(), 1000);
+        status.update_file("/videos/<b>.mkv".into(), 250);
+        let paused = AtomicBool::new(false);
+        let set_paused = |p| paused.store(p, Ordering::SeqCst);
+        let database = Database::in_memory().unwrap();
+        let queue = || QueueSnapshot::collect(&database);
+
+        let reply = route("GET", "/status", None, None, &status, &queue, &set_paused);
+        assert_eq!(200, reply.status);
+        let snapshot: StatusSnapshot = serde_json::from_str(&reply.body).unwrap();
+        assert_eq!(status.snapshot(), snapshot);
+
+        let reply = route("GET", "/snapshot", None, None, &status, &queue, &set_paused);
+        assert_eq!(200, reply.status);
+        let snapshot: QueueSnapshot = serde_json::from_str(&reply.body).unwrap();
+        assert_eq!(Some(status.snapshot()), snapshot.run);
+        let failing = || -> Result<QueueSnapshot> { Err(eyre!("database is locked")) };
+        let reply = route(
+            "GET",
+            "/snapshot",
+            None,
+            None,
+            &status,
+            &failing,
+            &set_paused,
+        );
+        assert_eq!(500, reply.status);
+
+        let reply = route("GET", "/", None, None, &status, &queue, &set_paused);
+        assert!(reply.body.contains("&lt;b&gt;.mkv"));
+        assert!(reply.body.contains("25.0%"));
+
+        route("POST", "/pause", None, None, &status, &queue, &set_paused);
+        assert!(paused.load(Ordering::SeqCst));
+        route("POST", "/resume", None, None, &status, &queue, &set_paused);
+        assert!(!paused.load(Ordering::SeqCst));
+
+        let reply = route("GET", "/nope", None, None, &status, &queue, &set_paused);
+        assert_eq!(404, reply.status);
+        let reply = route(
+            "GET",
+            "/status",
+            None,
+            Some("secret"),
+            &status,
+            &queue,
+            &set_paused,
+        );
+        assert_eq!(401, reply.status);
+    }
+}
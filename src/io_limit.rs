@@ -0,0 +1,355 @@
+//! Limits on how fast sources are read, for libraries on shared storage where an
+//! encode reading at full speed saturates the link for everyone else. ffmpeg can
+//! pace its own reads with `-readrate`, or the source is copied to the temp
+//! directory at the limit first and encoded from there.
+
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::Result;
+
+/// How much is read and written at once while staging a source.
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// How an encode reads its source, stored in the `io_mode` column.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum IoMode {
+    /// ffmpeg reads the source where it is, paced with -readrate under a
+    /// --read-rate-limit
+    #[default]
+    Direct,
+    /// The source is copied to the --tmp-dir first, at most at the
+    /// --read-rate-limit, and encoded from there
+    Staged,
+}
+
+impl IoMode {
+    /// The value stored in the `io_mode` column.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            IoMode::Direct => "direct",
+            IoMode::Staged => "staged",
+        }
+    }
+}
+
+impl fmt::Display for IoMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Parses `--read-rate-limit`, in MB/s, to bytes per second.
+pub fn parse_rate(value: &str) -> Result<u64, String> {
+    match value.trim().parse::<f64>() {
+        Ok(rate) if rate > 0.0 && rate.is_finite() => Ok((rate * 1_000_000.0) as u64),
+        _ => Err(format!(
+            "invalid rate '{value}', expected MB/s greater than 0, e.g. 40 or 2.5"
+        )),
+    }
+}
+
+/// A rate formatted like `--read-rate-limit` takes it.
+pub fn format_rate(bytes_per_second: f64) -> String {
+    format!("{:.1} MB/s", bytes_per_second / 1_000_000.0)
+}
+
+/// The `-readrate` that makes ffmpeg read a file at `limit` bytes per second on
+/// average: the limit as a multiple of the file's own bitrate. ffmpeg paces by
+/// the timestamps of what it reads, so parts with a higher bitrate are read
+/// faster than the limit. `None` when the file has no duration to pace by.
+pub fn readrate(limit: u64, file_size: u64, duration: f64) -> Option<f64> {
+    if file_size == 0 || duration.is_nan() || duration <= 0.0 {
+        return None;
+    }
+    Some(limit as f64 * duration / file_size as f64)
+}
+
+/// Allows bytes through at `rate` per second on average, with bursts of up to
+/// a quarter of a second. The current time is passed in, so that the schedule
+/// can be tested without waiting.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    /// Negative while bytes that were taken are still owed.
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket for `rate` bytes per second.
+    pub fn new(rate: u64, now: Instant) -> Self {
+        let capacity = rate as f64 / 4.0;
+        TokenBucket {
+            rate: rate as f64,
+            capacity,
+            tokens: capacity,
+            last: now,
+        }
+    }
+
+    /// Takes `bytes` and returns how long to wait before passing them on to
+    /// stay within the rate.
+    pub fn take(&mut self, bytes: u64, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity) - bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// What a copy read and how long it took.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CopyStats {
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+impl CopyStats {
+    /// The average rate in bytes per second.
+    pub fn rate(&self) -> f64 {
+        if self.elapsed.is_zero() {
+            self.bytes as f64
+        } else {
+            self.bytes as f64 / self.elapsed.as_secs_f64()
+        }
+    }
+}
+
+/// Copies everything from `reader` to `writer` in chunks, at most at `rate`
+/// bytes per second on average if there is a rate. `now` and `sleep` are the
+/// clock, the real one outside of tests.
+pub fn copy_limited(
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+    rate: Option<u64>,
+    mut now: impl FnMut() -> Instant,
+    mut sleep: impl FnMut(Duration),
+) -> io::Result<CopyStats> {
+    let start = now();
+    let mut bucket = rate.map(|rate| TokenBucket::new(rate, start));
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut bytes = 0;
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if let Some(bucket) = &mut bucket {
+            let wait = bucket.take(read as u64, now());
+            if !wait.is_zero() {
+                sleep(wait);
+            }
+        }
+        writer.write_all(&buffer[..read])?;
+        bytes += read as u64;
+    }
+    writer.flush()?;
+    Ok(CopyStats {
+        bytes,
+        elapsed: now().saturating_duration_since(start),
+    })
+}
+
+/// The copy of a source for `--io-mode staged`, removed when it is dropped.
+#[derive(Debug)]
+pub struct StagedCopy {
+    pub path: Utf8PathBuf,
+    pub stats: CopyStats,
+}
+
+impl Drop for StagedCopy {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Could not remove the staged copy {}: {e}", self.path);
+        }
+    }
+}
+
+/// Copies a source to `destination` for `--io-mode staged`. A copy that fails
+/// halfway is removed.
+pub fn stage(source: &Utf8Path, destination: &Utf8Path, rate: Option<u64>) -> Result<StagedCopy> {
+    let copy = || -> Result<CopyStats> {
+        let mut reader = File::open(source)?;
+        let mut writer = File::create(destination)?;
+        let stats = copy_limited(&mut reader, &mut writer, rate, Instant::now, thread::sleep)?;
+        writer.sync_all()?;
+        Ok(stats)
+    };
+    match copy() {
+        Ok(stats) => Ok(StagedCopy {
+            path: destination.to_owned(),
+            stats,
+        }),
+        Err(e) => {
+            let _ = fs::remove_file(destination);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    /// A clock that only moves when it sleeps.
+    struct FakeClock {
+        now: Cell<Instant>,
+        slept: Cell<Duration>,
+    }
+
+    impl FakeClock {
+        fn new() -> Self {
+            FakeClock {
+                now: Cell::new(Instant::now()),
+                slept: Cell::new(Duration::ZERO),
+            }
+        }
+
+        fn now(&self) -> Instant {
+            self.now.get()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            self.now.set(self.now.get() + duration);
+            self.slept.set(self.slept.get() + duration);
+        }
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1000, start);
+        // the burst of a quarter of a second goes through right away
+        assert_eq!(Duration::ZERO, bucket.take(250, start));
+        assert_eq!(Duration::from_millis(500), bucket.take(500, start));
+        // after waiting, the debt is paid off
+        let later = start + Duration::from_millis(500);
+        assert_eq!(Duration::ZERO, bucket.take(0, later));
+        // a long pause doesn't save up more than the burst
+        let much_later = later + Duration::from_secs(60);
+        assert_eq!(Duration::ZERO, bucket.take(250, much_later));
+        assert_eq!(Duration::from_millis(100), bucket.take(100, much_later));
+    }
+
+    #[test]
+    fn test_copy_limited() -> Result<()> {
+        let source: Vec<u8> = (0..10 * CHUNK_SIZE + 123)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let clock = FakeClock::new();
+        let rate = 2 * CHUNK_SIZE as u64;
+        let mut copy = vec![];
+        let stats = copy_limited(
+            &mut source.as_slice(),
+            &mut copy,
+            Some(rate),
+            || clock.now(),
+            |duration| clock.sleep(duration),
+        )?;
+        assert_eq!(source, copy);
+        assert_eq!(source.len() as u64, stats.bytes);
+        // five seconds for ten chunks, less the burst the copy started with
+        let expected = source.len() as f64 / rate as f64 - 0.25;
+        assert!(
+            (stats.elapsed.as_secs_f64() - expected).abs() < 0.01,
+            "{stats:?}"
+        );
+        assert!(stats.rate() <= rate as f64 * 1.1, "{}", stats.rate());
+
+        // without a rate, it never waits
+        let clock = FakeClock::new();
+        let mut copy = vec![];
+        let stats = copy_limited(
+            &mut source.as_slice(),
+            &mut copy,
+            None,
+            || clock.now(),
+            |duration| clock.sleep(duration),
+        )?;
+        assert_eq!(source, copy);
+        assert_eq!(Duration::ZERO, clock.slept.get());
+        assert_eq!(Duration::ZERO, stats.elapsed);
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_errors() {
+        struct Broken;
+
+        impl Read for Broken {
+            fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+                Err(io::Error::other("the share went away"))
+            }
+        }
+
+        let clock = FakeClock::new();
+        let error = copy_limited(
+            &mut Broken,
+            &mut vec![],
+            Some(1000),
+            || clock.now(),
+            |duration| clock.sleep(duration),
+        )
+        .unwrap_err();
+        assert_eq!("the share went away", error.to_string());
+    }
+
+    #[test]
+    fn test_stage() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        let source = dir.join("movie.mkv");
+        fs::write(&source, b"the movie")?;
+        let staged = stage(&source, &dir.join("staged.mkv"), None)?;
+        assert_eq!(9, staged.stats.bytes);
+        assert_eq!(b"the movie".as_slice(), fs::read(&staged.path)?);
+        drop(staged);
+        assert!(!dir.join("staged.mkv").exists());
+        assert!(source.exists());
+
+        // a copy into a directory that doesn't exist leaves nothing behind
+        let missing = dir.join("gone/staged.mkv");
+        assert!(stage(&source, &missing, None).is_err());
+        assert!(!missing.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_readrate() {
+        // 10 GB over two hours is about 1.4 MB/s, so 7 MB/s is five times real time
+        let factor = readrate(7_000_000, 10_000_000_000, 7200.0).unwrap();
+        assert!((factor - 5.04).abs() < 1e-9, "{factor}");
+        assert_eq!(None, readrate(7_000_000, 0, 7200.0));
+        assert_eq!(None, readrate(7_000_000, 1000, 0.0));
+        assert_eq!(None, readrate(7_000_000, 1000, f64::NAN));
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(Ok(40_000_000), parse_rate("40"));
+        assert_eq!(Ok(2_500_000), parse_rate("2.5"));
+        for invalid in ["0", "-1", "fast", "", "inf"] {
+            assert!(parse_rate(invalid).is_err(), "{invalid}");
+        }
+        assert_eq!("12.5 MB/s", format_rate(12_500_000.0));
+    }
+}
@@ -0,0 +1,180 @@
+use std::fmt;
+
+use color_eyre::eyre::bail;
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use sysinfo::{Pid, ProcessesToUpdate, System};
+use tracing::{info, warn};
+
+use crate::Result;
+use crate::database::Database;
+
+/// The process that holds the instance lock.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LockHolder {
+    pub pid: u32,
+    pub hostname: String,
+    #[serde(with = "jiff::fmt::serde::timestamp::second::required")]
+    pub started_on: Timestamp,
+}
+
+impl LockHolder {
+    pub fn current() -> Self {
+        LockHolder {
+            pid: std::process::id(),
+            hostname: System::host_name().unwrap_or_else(|| "unknown".into()),
+            started_on: Timestamp::now(),
+        }
+    }
+}
+
+/// Identifies this process when claiming files, as `name:pid`. The name defaults
+/// to the hostname.
+pub fn worker_id(name: Option<&str>) -> String {
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => LockHolder::current().hostname,
+    };
+    format!("{}:{}", name, std::process::id())
+}
+
+impl fmt::Display for LockHolder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "pid {} on {} (since {})",
+            self.pid, self.hostname, self.started_on
+        )
+    }
+}
+
+/// Guard for the instance lock, released when dropped.
+pub struct InstanceLock {
+    database: Database,
+    holder: LockHolder,
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        if let Err(e) = self.database.release_lock(&self.holder) {
+            warn!("failed to release the instance lock: {:?}", e);
+        }
+    }
+}
+
+/// Whether a process with the given pid is running on this machine.
+pub fn pid_is_alive(pid: u32) -> bool {
+    let pid = Pid::from_u32(pid);
+    let mut system = System::new();
+    system.refresh_processes(ProcessesToUpdate::Some(&[pid]), true);
+    system.process(pid).is_some()
+}
+
+/// Takes the instance lock for commands that modify the database or files.
+///
+/// With `force_unlock`, an existing lock is removed first, but only if its process
+/// is no longer running. Whether a process on another machine is running can't be
+/// checked, so its lock is never removed.
+pub fn acquire(
+    database: &Database,
+    holder: LockHolder,
+    force_unlock: bool,
+    is_alive: impl Fn(u32) -> bool,
+) -> Result<InstanceLock> {
+    if force_unlock && let Some(existing) = database.lock_holder()? {
+        if existing.hostname != holder.hostname {
+            bail!(
+                "the lock is held by {existing}, another machine, so it can't be checked whether \
+                 it's still running. Use --force-unlock on {} once it has stopped.",
+                existing.hostname
+            );
+        }
+        if is_alive(existing.pid) {
+            bail!("the lock is held by {existing}, which is still running");
+        }
+        info!("removing stale lock held by {existing}");
+        database.clear_lock()?;
+    }
+
+    match database.take_lock(&holder)? {
+        None => Ok(InstanceLock {
+            database: database.clone(),
+            holder,
+        }),
+        Some(existing) => bail!(
+            "another transcoder instance is using this database: {existing}. \
+             If that process is no longer running, use --force-unlock."
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn holder(pid: u32, hostname: &str) -> LockHolder {
+        LockHolder {
+            pid,
+            hostname: hostname.into(),
+            started_on: Timestamp::from_second(1_700_000_000).unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_lock_is_exclusive() -> Result<()> {
+        let db = Database::in_memory()?;
+        let lock = acquire(&db, holder(1, "host"), false, |_| true)?;
+
+        let error = acquire(&db, holder(2, "host"), false, |_| true)
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("pid 1 on host"));
+
+        drop(lock);
+        assert!(db.lock_holder()?.is_none());
+        let _lock = acquire(&db, holder(2, "host"), false, |_| true)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_force_unlock_stale_lock() -> Result<()> {
+        let db = Database::in_memory()?;
+        db.take_lock(&holder(1, "host"))?;
+
+        let _lock = acquire(&db, holder(2, "host"), true, |pid| pid != 1)?;
+        assert_eq!(Some(holder(2, "host")), db.lock_holder()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_force_unlock_refuses_running_process() -> Result<()> {
+        let db = Database::in_memory()?;
+        db.take_lock(&holder(1, "host"))?;
+
+        let error = acquire(&db, holder(2, "host"), true, |_| true)
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("still running"));
+        assert_eq!(Some(holder(1, "host")), db.lock_holder()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_force_unlock_refuses_other_machine() -> Result<()> {
+        let db = Database::in_memory()?;
+        db.take_lock(&holder(1, "nas"))?;
+
+        // even when a process with the pid isn't running here
+        let error = acquire(&db, holder(2, "desktop"), true, |_| false)
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("another machine"));
+        assert_eq!(Some(holder(1, "nas")), db.lock_holder()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_current_process_is_alive() {
+        assert!(pid_is_alive(std::process::id()));
+    }
+}
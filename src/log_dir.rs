@@ -0,0 +1,78 @@
+//! `--log-dir`: the log of every command written to a file per day, to look at
+//! what an unattended run did after the fact, including the full output of
+//! ffmpeg for the files that failed.
+
+use std::fs;
+
+use camino::Utf8Path;
+use color_eyre::eyre::Context;
+use tracing::Subscriber;
+use tracing::level_filters::LevelFilter;
+use tracing_appender::non_blocking::{NonBlockingBuilder, WorkerGuard};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::Layer;
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::Result;
+
+/// The level the files are written at without `--log`.
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::INFO;
+
+/// A layer that appends the log to `transcoder.<date>.log` in `dir`, starting a
+/// new file every day. The lines are written on a background thread, the ones
+/// that are still queued when the guard is dropped are written then.
+pub fn layer<S>(dir: &Utf8Path, level: Option<LevelFilter>) -> Result<(impl Layer<S>, WorkerGuard)>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fs::create_dir_all(dir).wrap_err_with(|| format!("can't create the log directory {dir}"))?;
+    let appender = RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix("transcoder")
+        .filename_suffix("log")
+        .build(dir)
+        .wrap_err_with(|| format!("can't write logs to {dir}"))?;
+    // a full queue blocks instead of dropping lines, the log is the record of the run
+    let (writer, guard) = NonBlockingBuilder::default().lossy(false).finish(appender);
+    let layer = tracing_subscriber::fmt::layer()
+        .with_ansi(false)
+        .with_writer(writer)
+        .with_filter(level.unwrap_or(DEFAULT_LEVEL));
+    Ok((layer, guard))
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing::{debug, info, warn};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    #[test]
+    fn test_log_dir() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let dir = Utf8Path::from_path(tempdir.path()).unwrap().join("logs");
+        let (layer, guard) = layer(&dir, None)?;
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            info!("Transcoding file a.mkv");
+            debug!("ffmpeg arguments");
+            warn!("Could not transcode file b.mkv: Conversion failed!");
+        });
+        drop(guard);
+
+        let files: Vec<_> = dir.read_dir_utf8()?.collect::<Result<_, _>>()?;
+        assert_eq!(1, files.len());
+        let name = files[0].file_name();
+        assert!(
+            name.starts_with("transcoder.") && name.ends_with(".log"),
+            "{name}"
+        );
+        let log = fs::read_to_string(files[0].path())?;
+        assert!(log.contains("Transcoding file a.mkv"));
+        assert!(log.contains("Conversion failed!"));
+        // the default level leaves out debug lines
+        assert!(!log.contains("ffmpeg arguments"));
+        Ok(())
+    }
+}
@@ -0,0 +1,133 @@
+//! `--low-memory`: SVT-AV1 parameters that trade a little efficiency for much
+//! less memory on small machines. A shorter lookahead, fewer hierarchical
+//! levels and fewer frames in parallel all mean fewer frames buffered at once,
+//! and every frame of a bigger picture costs more, so bigger tiers get the
+//! smaller values.
+//!
+//! The memory factors are estimates for the admission control, not
+//! measurements. The peak memory in the summary of a run shows what the
+//! parameters actually save on a machine.
+
+use serde::{Deserialize, Serialize};
+
+use crate::collect::{self, ResolutionTier};
+
+/// The parameters for one resolution tier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct LowMemory {
+    /// Frames SVT-AV1 looks ahead, `lookahead`.
+    pub lookahead: u8,
+    /// Levels of the prediction structure, `hierarchical-levels`.
+    pub hierarchical_levels: u8,
+    /// Level of parallelism, `lp`, which decides how many frames are in flight.
+    pub parallelism: u8,
+}
+
+/// The parameters of each tier and the share of the default parameters' memory
+/// they're expected to need. Tiers that aren't listed get the 1080p ones.
+const TIERS: &[(ResolutionTier, LowMemory, f64)] = &[
+    (ResolutionTier::Sd, params(33, 4, 4), 0.6),
+    (ResolutionTier::Hd720, params(25, 4, 4), 0.55),
+    (ResolutionTier::Hd1080, params(17, 3, 3), 0.5),
+    (ResolutionTier::Qhd1440, params(17, 3, 2), 0.45),
+    (ResolutionTier::Uhd4k, params(9, 3, 2), 0.4),
+    (ResolutionTier::Uhd8k, params(9, 2, 1), 0.35),
+];
+
+const fn params(lookahead: u8, hierarchical_levels: u8, parallelism: u8) -> LowMemory {
+    LowMemory {
+        lookahead,
+        hierarchical_levels,
+        parallelism,
+    }
+}
+
+fn tier(resolution: (u32, u32)) -> &'static (ResolutionTier, LowMemory, f64) {
+    let tier = collect::resolution_tier(resolution);
+    TIERS
+        .iter()
+        .find(|(t, _, _)| *t == tier)
+        .or_else(|| TIERS.iter().find(|(t, _, _)| *t == ResolutionTier::Hd1080))
+        .expect("there are parameters for 1080p")
+}
+
+/// The parameters for a video of this resolution.
+pub fn for_resolution(resolution: (u32, u32)) -> LowMemory {
+    tier(resolution).1
+}
+
+/// The share of the default parameters' memory that an encode of this
+/// resolution is expected to need with them.
+pub fn memory_factor(resolution: (u32, u32)) -> f64 {
+    tier(resolution).2
+}
+
+impl Default for LowMemory {
+    /// The 1080p parameters, until the resolution of a file is known.
+    fn default() -> Self {
+        for_resolution((1920, 1080))
+    }
+}
+
+impl LowMemory {
+    /// The `-svtav1-params` entries. `lp` is left out when the threads are
+    /// fixed already, e.g. for a reproducible encode.
+    pub fn svt_params(&self, threads_fixed: bool) -> Vec<String> {
+        let mut params = vec![
+            format!("lookahead={}", self.lookahead),
+            format!("hierarchical-levels={}", self.hierarchical_levels),
+        ];
+        if !threads_fixed {
+            params.push(format!("lp={}", self.parallelism));
+        }
+        params
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tiers() {
+        for (resolution, expected, factor) in [
+            ((720, 480), params(33, 4, 4), 0.6),
+            ((1280, 720), params(25, 4, 4), 0.55),
+            ((1920, 1080), params(17, 3, 3), 0.5),
+            // cropped widescreen is in the tier it was mastered at
+            ((1920, 800), params(17, 3, 3), 0.5),
+            ((2560, 1440), params(17, 3, 2), 0.45),
+            ((3840, 2160), params(9, 3, 2), 0.4),
+            ((7680, 4320), params(9, 2, 1), 0.35),
+            // unknown resolutions get the 1080p parameters
+            ((0, 0), params(17, 3, 3), 0.5),
+        ] {
+            assert_eq!(expected, for_resolution(resolution), "{resolution:?}");
+            assert_eq!(factor, memory_factor(resolution), "{resolution:?}");
+        }
+        assert_eq!(for_resolution((1920, 1080)), LowMemory::default());
+
+        // bigger pictures never buffer more frames or need a bigger share
+        for pair in TIERS.windows(2) {
+            let ((_, small, small_factor), (_, big, big_factor)) = (pair[0], pair[1]);
+            assert!(big.lookahead <= small.lookahead);
+            assert!(big.hierarchical_levels <= small.hierarchical_levels);
+            assert!(big.parallelism <= small.parallelism);
+            assert!(big_factor < small_factor);
+        }
+    }
+
+    #[test]
+    fn test_svt_params() {
+        let params = for_resolution((3840, 2160));
+        assert_eq!(
+            vec!["lookahead=9", "hierarchical-levels=3", "lp=2"],
+            params.svt_params(false)
+        );
+        assert_eq!(
+            vec!["lookahead=9", "hierarchical-levels=3"],
+            params.svt_params(true)
+        );
+    }
+}
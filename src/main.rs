@@ -1,53 +1,312 @@
 use std::collections::{BTreeMap, HashMap};
-use std::time::Instant;
+use std::io::{self, IsTerminal, Write};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
 
-use camino::Utf8PathBuf;
-use clap::{Parser, Subcommand};
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::{Parser, Subcommand, ValueEnum};
 use collect::VideoFile;
+use color_eyre::eyre::{bail, eyre};
 use human_repr::{HumanCount, HumanDuration};
-use tabled::settings::Style;
+use tabled::builder::Builder;
+use tabled::settings::location::ByColumnName;
+use tabled::settings::{Remove, Style};
 use tabled::{Table, Tabled};
-use tracing::info;
-use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::util::SubscriberInitExt;
+use tracing::{info, warn};
 use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::layer::{Layer, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
 
-use crate::collect::Collector;
-use crate::database::Database;
-use crate::transcode::{GpuMode, TranscodeOptions, Transcoder};
+use crate::audio::AudioOptions;
+use crate::autocrf::AutoCrf;
+use crate::binaries::{Binary, MissingBinary};
+use crate::breakdown::SizeBreakdown;
+use crate::codecs::{CodecRule, CodecRules};
+use crate::collect::{Collector, ScanOptions};
+use crate::config::{Config, TranscodeSettings};
+use crate::constraints::{Constraints, Level, Profile};
+use crate::database::{
+    Database, DatabaseLocation, FileFilter, MissingDatabase, TranscodeFile, TranscodeStatus,
+};
+use crate::device::VideoCodec;
+use crate::diff::{FileState, SnapshotDiff};
+use crate::distribution::Distribution;
+use crate::energy::{EnergyOptions, EnergyReport};
+use crate::finalize::FinalizeOptions;
+use crate::io_limit::IoMode;
+use crate::lock::LockHolder;
+use crate::paths::{CrossDevice, OutputPaths};
+use crate::plan::{Plan, PlanEntry};
+use crate::preflight::Verdict;
+use crate::progress::Throttle;
+use crate::renditions::RenditionArg;
+use crate::review::ReviewEntry;
+use crate::selection::{EmptyQueue, Selection, SelectionArgs};
+use crate::speed::Speed;
+use crate::status::QueueSnapshot;
+use crate::transcode::{GpuMode, StreamFormat, TranscodeOptions, Transcoder};
 
+mod age;
+mod audio;
+mod audio_hash;
+mod autocrf;
+mod binaries;
+mod breakdown;
+mod capabilities;
+mod cleanup;
+mod codecs;
 mod collect;
+mod config;
+mod constraints;
 mod database;
+mod dedupe;
+mod device;
+mod diff;
+mod distribution;
+mod encoder_rules;
+mod energy;
+mod error_message;
+mod estimate;
 mod ffprobe;
+mod filesystem;
+mod finalize;
+mod history;
+#[cfg(feature = "http")]
+mod http;
+mod io_limit;
+mod lock;
+mod log_dir;
+mod low_memory;
+mod ordering;
+mod output_template;
+mod paths;
+mod plan;
+mod power;
+mod preflight;
+mod progress;
+mod reclaim;
+mod renditions;
+mod resolved_options;
+mod resources;
+mod results;
+mod resume;
+mod review;
+mod sampling;
+mod scheduler;
+mod selection;
+mod sidecar;
+mod size;
+mod source_changes;
+mod speed;
+mod status;
+mod stderr;
+mod storage;
+#[cfg(test)]
+mod testsupport;
+mod thumbnails;
 mod transcode;
+mod verify;
+mod version;
+mod where_sql;
 
 pub type Result<T, E = color_eyre::Report> = std::result::Result<T, E>;
 
 #[derive(Subcommand, Debug)]
+#[allow(clippy::large_enum_variant)]
 pub enum Command {
     Scan {
-        /// Exclude files that contain this string
+        /// Exclude files whose path below the scanned directory contains this
+        /// string. Directories end in a slash, e.g. "Extras/"
         #[clap(short = 'E', long)]
         exclude: Vec<String>,
-        /// Minimum file size to transcode
+
+        /// Match the --exclude patterns against the absolute path
+        #[clap(long)]
+        exclude_absolute: bool,
+        /// Minimum file size to transcode, e.g. 500M, 1.5GB or 2GiB
+        #[clap(long, value_parser = size::parse_bytes)]
+        min_size: Option<u64>,
+
+        /// Maximum file size to transcode
+        #[clap(long, value_parser = size::parse_bytes)]
+        max_size: Option<u64>,
+
+        /// Don't add files of this codec, optionally only if they match all of the
+        /// comma separated conditions on bpp, bitrate or profile, e.g. "hevc:bpp<0.08"
+        /// or "h264:profile=High 10". Replaces the default of hevc and av1.
+        #[clap(long)]
+        exclude_codec: Vec<CodecRule>,
+
+        /// Add the files that are skipped to the database with the status skipped
+        /// and the reason, so that `list --status skipped` shows them. Files that
+        /// ffprobe fails for are always added
+        #[clap(long)]
+        record_skipped: bool,
+
+        /// Probe files that ffprobe failed for in an earlier scan again. Otherwise
+        /// they are only probed again once they were modified
+        #[clap(long)]
+        retry_failed_probes: bool,
+
+        /// Maximum number of concurrent ffprobe processes, independent of the
+        /// transcode --parallel. Keep it low for spinning disks [default: 4]
+        #[clap(long)]
+        probe_parallel: Option<usize>,
+
+        /// Stamp the scanned files with this library name. Without a path, the
+        /// library's roots from the config file are scanned.
         #[clap(long)]
-        min_size: Option<String>,
+        library: Option<String>,
+
+        /// Only look at files with these extensions instead of the default ones,
+        /// e.g. ts,m2ts,mpg. Lowercase and without the dot, files match in any case
+        #[clap(long, value_delimiter = ',', value_parser = collect::parse_extension)]
+        extensions: Vec<String>,
+
+        /// Look at files with these extensions as well
+        #[clap(long, value_delimiter = ',', value_parser = collect::parse_extension)]
+        extra_extensions: Vec<String>,
 
         /// The path to scan for video files
-        path: Utf8PathBuf,
+        path: Option<Utf8PathBuf>,
     },
+    /// Transcode the files in the database
+    ///
+    /// Encoding settings are resolved per file in this order: command line flags,
+    /// the nearest `.transcoder.toml` in the file's directory or one of its parents,
+    /// the `[transcode]` section of the config file, and finally the built-in defaults.
     Transcode {
-        /// Limit how many files to process
+        #[clap(flatten)]
+        selection: SelectionArgs,
+
+        /// CRF value to use for encoding [default: 24]
+        #[clap(short, long)]
+        crf: Option<u8>,
+
+        /// How fast to encode, from 0 (slowest, best quality) to 10 (fastest), the
+        /// same for every encoder [default: 6]. Maps to SVT-AV1 presets 0 to 13,
+        /// x264/x265 presets veryslow to veryfast, NVENC p7 to p1 and QSV 1 to 7
+        #[clap(short, long, conflicts_with = "effort")]
+        speed: Option<Speed>,
+
+        /// Deprecated, use --speed. The encoder's own preset: faster towards 13
+        /// for SVT-AV1, slower towards 7 for NVENC (p7) and QSV
         #[clap(short, long)]
-        number: Option<i64>,
+        effort: Option<u8>,
+
+        /// Film grain synthesis level (libsvtav1 only)
+        #[clap(long)]
+        film_grain: Option<u8>,
+
+        /// Encode with 10-bit color depth. `--ten-bit=false` turns it off when the
+        /// config file or a directory's config turns it on
+        #[clap(
+            long,
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "true"
+        )]
+        ten_bit: Option<bool>,
+
+        /// Limit the output frame rate
+        #[clap(long)]
+        max_fps: Option<f64>,
+
+        /// Make encoding the same source with the same settings give a
+        /// byte-identical output, e.g. to deduplicate or check backups: the
+        /// encoder's threads are fixed and metadata that depends on the time of the
+        /// run is left out. The ffmpeg arguments are recorded with the file. The
+        /// hardware encoders (NVENC, QSV) don't give the same output twice and
+        /// can't be used with it
+        #[clap(long, conflicts_with_all = ["gpu", "resumable"])]
+        reproducible: bool,
+
+        /// Use less memory with libsvtav1 for a slightly bigger output: a shorter
+        /// lookahead, fewer hierarchical levels and fewer frames in parallel,
+        /// smaller for bigger resolutions. More files fit in --max-memory at once
+        #[clap(long)]
+        low_memory: bool,
+
+        /// Highest AV1 level the output may use, e.g. 5.1 for most 4K TVs. Encoded
+        /// files are checked against it and violations are logged
+        #[clap(long)]
+        max_level: Option<Level>,
+
+        /// AV1 profile for the output
+        #[clap(long)]
+        profile: Option<Profile>,
+
+        /// Re-encode audio tracks with a bitrate above this (e.g. 256k) or in an
+        /// inefficient or lossless codec, and copy the others. All audio is copied by default
+        #[clap(long, value_parser = audio::parse_bitrate)]
+        copy_audio_only_above: Option<u64>,
+
+        /// Codec for re-encoded audio tracks [default: aac, libopus for --codec vp9]
+        #[clap(long)]
+        audio_codec: Option<String>,
+
+        /// Bitrate for re-encoded stereo audio tracks, mono tracks get half of it
+        #[clap(long, default_value = "160k", value_parser = audio::parse_bitrate)]
+        audio_bitrate: u64,
+
+        /// Leave all audio out of the outputs, e.g. for camera footage with
+        /// nothing but noise on its audio tracks. Video and subtitles are kept
+        #[clap(long, conflicts_with = "copy_audio_only_above")]
+        drop_audio: bool,
+
+        /// Leave commentary and audio description tracks out of the outputs,
+        /// recognized by their dispositions or by words like "commentary" in their
+        /// titles. The default or first audio track is always kept. Files scanned
+        /// by older versions need a `reprobe` first
+        #[clap(long, conflicts_with = "drop_audio")]
+        drop_commentary: bool,
+
+        /// Transcode the file with this ID again with the options recorded by its
+        /// last encode, ignoring the config file and the encoding flags. `list` and
+        /// `show` print the ID
+        #[clap(long, conflicts_with_all = [
+            "crf", "speed", "effort", "film_grain", "ten_bit", "max_fps", "reproducible",
+            "low_memory", "max_level", "profile",
+            "copy_audio_only_above", "drop_audio", "drop_commentary", "gpu", "auto_crf",
+            "device",
+        ])]
+        repeat_options: Option<i64>,
+
+        /// Transcode the files of a plan written by `queue --plan`, in its order and
+        /// with its options. Files whose size or modification time changed since
+        /// are skipped
+        #[clap(long, conflicts_with_all = [
+            "crf", "speed", "effort", "film_grain", "ten_bit", "max_fps", "reproducible",
+            "low_memory", "max_level", "profile",
+            "copy_audio_only_above", "drop_audio", "drop_commentary", "gpu", "auto_crf",
+            "device", "repeat_options", "number", "order", "sample_random", "status",
+            "path_contains", "container", "encoder_version", "library", "stale", "run", "where_sql",
+        ])]
+        plan: Option<Utf8PathBuf>,
+
+        /// Check that the copied audio of each output is bit-identical to the
+        /// source's by comparing the MD5 of their first audio streams. Differences,
+        /// e.g. from dropped packets, count as failed verifications. Files whose
+        /// audio is re-encoded aren't checked
+        #[clap(long)]
+        verify_audio_hash: bool,
 
-        /// CRF value to use for encoding
-        #[clap(short, long, default_value = "24")]
-        crf: u8,
+        /// Encode only this file and write it to stdout instead of a file, e.g.
+        /// `transcoder transcode --stdout movie.mkv | mpv -`. Logs and progress go
+        /// to stderr and the database isn't changed
+        #[clap(long, conflicts_with_all = [
+            "parallel", "replace", "dry_run", "repeat_options", "plan", "auto_crf", "resumable",
+            "min_savings", "min_free_space", "stop_after_saved", "reclaim_stale",
+            "number", "order", "force", "output_dir", "exclude_codec", "status",
+            "path_contains", "container", "encoder_version", "library", "device",
+            "read_rate_limit",
+        ])]
+        stdout: Option<Utf8PathBuf>,
 
-        /// Effort level to use for encoding
-        #[clap(short, long, default_value = "7")]
-        effort: u8,
+        /// Container for --stdout
+        #[clap(long, value_enum, default_value_t, requires = "stdout")]
+        stdout_format: StreamFormat,
 
         /// Dry run, don't do anything
         #[clap(short, long)]
@@ -56,16 +315,576 @@ pub enum Command {
         #[clap(short, long)]
         replace: bool,
 
-        /// Use the GPU for transcoding
+        /// Stop once the output is written, e.g. to check and put it in place later
+        /// on the machine that owns the storage with `transcoder finalize`. The
+        /// files get the status encoded until then
+        #[clap(long, conflicts_with_all = ["replace", "dry_run", "stdout"])]
+        no_finalize: bool,
+
+        /// Write a report next to each output, `<output>.transcoder.json`, for
+        /// media managers that ingest sidecar files: the source's path and hash,
+        /// the encode options, sizes, speed, verification and versions. `verify`
+        /// and `finalize` update the reports that exist
+        #[clap(long, conflicts_with_all = ["dry_run", "stdout"])]
+        sidecar: bool,
+
+        /// Encode each file into several outputs from one decode, e.g.
+        /// archive,share:stream for an AV1 archive and a 720p H.264 copy. Each is
+        /// <name>:<profile>, a bare name uses the profile of that name. Profiles
+        /// come from the [renditions.<profile>] sections of the config file, or
+        /// are the built-in archive (the run's settings) and stream (H.264 up to
+        /// 720p). The outputs are named <stem>_<name>.mp4 unless the profile has
+        /// a template, and are checked and kept or discarded one by one. They are
+        /// encoded on the CPU
+        #[clap(long, value_delimiter = ',', conflicts_with_all = [
+            "replace", "no_finalize", "stdout", "gpu", "device", "codec", "auto_crf",
+            "resumable", "repeat_options", "plan",
+        ])]
+        renditions: Vec<RenditionArg>,
+
+        /// Write temporary files to this directory. Also used for the outputs of files
+        /// in read-only directories when no --output-dir is given
+        #[clap(long)]
+        tmp_dir: Option<Utf8PathBuf>,
+
+        /// What to do when the --tmp-dir and the output are on different filesystems,
+        /// where the finished file can't just be renamed into place
+        #[clap(long, value_enum, default_value_t)]
+        cross_device: CrossDevice,
+
+        /// Use the GPU for transcoding, for every file. Without it, the encoder rules
+        /// of the config file pick the encoder per file
+        #[clap(long)]
+        gpu: Option<GpuMode>,
+
+        /// The render node VAAPI encodes on, for machines whose GPU isn't the
+        /// first one [default: /dev/dri/renderD128]
+        #[clap(long)]
+        gpu_device: Option<Utf8PathBuf>,
+
+        /// Number of files to process in parallel [default: 1 for CPU encoding,
+        /// 2 for QSV and VAAPI, up to 3 for Nvidia depending on the driver's session limit]
+        #[clap(short, long)]
+        parallel: Option<u32>,
+
+        /// Encode files on the CPU while all of the GPU's NVENC sessions are in
+        /// use, so that --parallel can go above the session limit. Without
+        /// --parallel, one file more than the GPU's default runs at once
+        #[clap(long, requires = "gpu")]
+        cpu_fill: bool,
+
+        /// Maximum predicted memory usage of all parallel encodes [default: 80% of system memory]
+        #[clap(long, value_parser = size::parse_bytes)]
+        max_memory: Option<u64>,
+
+        /// Stop starting new files when the output filesystem has less free space than this
+        #[clap(long, value_parser = size::parse_bytes)]
+        min_free_space: Option<u64>,
+
+        /// Stop starting new files once this much space has been saved
+        #[clap(long, value_parser = size::parse_bytes)]
+        stop_after_saved: Option<u64>,
+
+        /// Only keep outputs that are at least this many percent smaller than the original
+        #[clap(long, default_value_t = 0.0)]
+        min_savings: f64,
+
+        /// Compare outputs with the original minus its dropped audio tracks, so
+        /// that leaving out the audio doesn't count as savings of the video encode.
+        /// Changes nothing when the audio is kept
+        #[clap(long)]
+        video_only_size_check: bool,
+
+        /// Transcode files that didn't save enough again at a higher CRF, going up
+        /// in steps of 3 until --min-savings is met or --max-crf is reached
+        #[clap(long)]
+        auto_crf: bool,
+
+        /// Highest CRF tried by --auto-crf
+        #[clap(long, default_value_t = autocrf::DEFAULT_MAX_CRF, requires = "auto_crf")]
+        max_crf: u8,
+
+        /// Encode in one minute segments next to the temp file, so that an encode
+        /// interrupted by a crash or reboot continues from the last complete segment
+        /// when the file is transcoded again. The segments are joined at the end
+        #[clap(long)]
+        resumable: bool,
+
+        /// Read each source at most this fast on average, in MB/s, e.g. to keep
+        /// encodes from a NAS from saturating the network. Parallel encodes each
+        /// get the limit. How it is kept depends on --io-mode
+        #[clap(long, value_parser = io_limit::parse_rate)]
+        read_rate_limit: Option<u64>,
+
+        /// How sources are read
+        #[clap(
+            long,
+            value_enum,
+            default_value_t,
+            requires_if("staged", "tmp_dir"),
+            conflicts_with = "stdout"
+        )]
+        io_mode: IoMode,
+
+        /// Price of a kWh, to show what the run cost. The energy is read from the
+        /// CPU's RAPL counters, or estimated from the watts in the [energy.watts]
+        /// section of the config file
+        #[clap(long)]
+        kwh_price: Option<f64>,
+
+        /// Keep the system from sleeping or going idle while a file is transcoding
+        /// (systemd-inhibit on Linux, caffeinate on macOS)
+        #[clap(long)]
+        inhibit_sleep: bool,
+
+        /// Don't encode two seconds of a test source with the run's settings before
+        /// starting the queue. The test encode stops the run when ffmpeg rejects the
+        /// settings, instead of every file failing with the same error
+        #[clap(long)]
+        no_preflight_encode: bool,
+
+        /// How often the progress of each encode is logged, e.g. 30s or 5m [default: 30s]
+        #[clap(long, value_parser = reclaim::parse_grace_period)]
+        progress_log_interval: Option<jiff::SignedDuration>,
+
+        /// How long to wait for the storage of the sources when it disappears
+        /// during the run, e.g. a NAS whose mount dropped. No files are started
+        /// while it's gone, after this long the run stops with the remaining files
+        /// still pending [default: 30m]
+        #[clap(long, value_parser = reclaim::parse_grace_period)]
+        storage_patience: Option<jiff::SignedDuration>,
+
+        /// Kill ffmpeg and fail a file when its encodes take longer than this
+        /// together, e.g. 6h, so that a hung encode doesn't block a worker. The
+        /// retries of --auto-crf count towards it
+        #[clap(long, value_parser = reclaim::parse_grace_period)]
+        file_timeout: Option<jiff::SignedDuration>,
+
+        /// Name this machine uses to claim files [default: the hostname]
+        #[clap(long)]
+        worker_name: Option<String>,
+
+        /// Work through the queue together with other transcode runs, e.g. on
+        /// other machines using the same database, splitting it through per-file
+        /// claims. Without it the run takes the instance lock, so a second run
+        /// and commands that change the database are refused while it runs
+        #[clap(long)]
+        shared: bool,
+
+        /// Return files claimed by workers that haven't reported progress for this
+        /// long (e.g. 2h) to the queue
+        #[clap(long)]
+        reclaim_stale: Option<jiff::SignedDuration>,
+
+        /// Exit with an error when no file was actually encoded, e.g. because every
+        /// file was skipped. Catches filters that match nothing in cron jobs
+        #[clap(long, conflicts_with_all = ["dry_run", "stdout"])]
+        fail_if_nothing_done: bool,
+
+        /// Warn when encoding the selected files is predicted to take longer than
+        /// this, e.g. 7d, instead of when it is slower than real time. Interactive
+        /// sessions are asked whether to start anyway
+        #[clap(long, value_parser = reclaim::parse_grace_period)]
+        max_predicted_duration: Option<jiff::SignedDuration>,
+
+        /// Start without asking when the queue is predicted to be slow or the files
+        /// were picked with --where
+        #[clap(short, long)]
+        yes: bool,
+
+        /// Serve a status page and JSON status on this address, e.g. 127.0.0.1:8990
+        #[cfg(feature = "http")]
+        #[clap(long)]
+        listen: Option<std::net::SocketAddr>,
+
+        /// Token required to access the status page, mandatory for non-loopback addresses
+        #[cfg(feature = "http")]
+        #[clap(long, requires = "listen")]
+        listen_token: Option<String>,
+    },
+    /// Show which files the next transcode run would pick, in order, with estimated
+    /// encode times. Doesn't change any file or status
+    Queue {
+        #[clap(flatten)]
+        selection: SelectionArgs,
+
+        /// Encoder to assume for the time estimates when there is no encode history,
+        /// and to encode every file of a --plan with
+        #[clap(long)]
+        gpu: Option<GpuMode>,
+
+        /// Print the queue as JSON
+        #[clap(long)]
+        json: bool,
+
+        /// Also write the queue to this file as a plan for `transcode --plan`, with
+        /// the options every file is encoded with. They come from the config file,
+        /// the directory overrides and the encoder rules, and can be edited before
+        /// the plan is run
+        #[clap(long)]
+        plan: Option<Utf8PathBuf>,
+
+        /// Warn when encoding the queue is predicted to take longer than this,
+        /// e.g. 7d, instead of when it is slower than real time
+        #[clap(long, value_parser = reclaim::parse_grace_period)]
+        max_predicted_duration: Option<jiff::SignedDuration>,
+    },
+    Stats {
+        /// Only show the stats of this library. By default, the stats are grouped
+        /// by library.
+        #[clap(long)]
+        library: Option<String>,
+
+        /// Also list every exact resolution instead of only the tiers
+        #[clap(long)]
+        exact: bool,
+
+        /// Encoder to assume for the remaining time when there is no encode history
         #[clap(long)]
         gpu: Option<GpuMode>,
 
-        /// Number of files to process in parallel.
-        #[clap(short, long, default_value = "1")]
-        parallel: u32,
+        /// Show the files encoded, the bytes saved, the speed and the failures
+        /// per ISO week in the local time zone instead
+        #[clap(long, conflicts_with_all = ["library", "exact"])]
+        history: bool,
+
+        /// Only the last weeks of the history, counting the current one
+        #[clap(long, requires = "history", value_parser = clap::value_parser!(u32).range(1..))]
+        weeks: Option<u32>,
+
+        /// Show the history per run instead of per week
+        #[clap(long, requires = "history")]
+        by_run: bool,
+
+        /// How to print the history
+        #[clap(long, value_enum, default_value_t = OutputFormat::Text, requires = "history")]
+        format: OutputFormat,
+    },
+    /// List the files in the database with their status
+    List {
+        #[clap(flatten)]
+        filter: FileFilter,
+
+        /// Show additional columns
+        #[clap(short, long)]
+        wide: bool,
+
+        /// List the archived files instead
+        #[clap(long)]
+        archived: bool,
+
+        /// Only list pinned files
+        #[clap(long)]
+        pinned: bool,
+
+        /// Order of the files
+        #[clap(long, value_enum, default_value_t = ListOrder::Size)]
+        sort: ListOrder,
+    },
+    /// Create a thumbnail from the middle of each file for reviewing them
+    Thumbs {
+        /// Directory to write the thumbnails to
+        #[clap(long)]
+        dir: Utf8PathBuf,
+
+        #[clap(flatten)]
+        filter: FileFilter,
+    },
+    /// List the files that workers are currently transcoding
+    Workers,
+    /// Write the status of the queue as JSON for dashboards, replacing the file
+    /// atomically so that readers never see a partial snapshot
+    ExportStatus {
+        /// File to write the snapshot to
+        #[clap(long)]
+        output: Utf8PathBuf,
+
+        /// Keep rewriting the snapshot at this interval, e.g. 30s or 5m
+        #[clap(long, value_parser = reclaim::parse_grace_period)]
+        watch: Option<jiff::SignedDuration>,
+    },
+    /// Show what changed since an older copy of the database: added and removed
+    /// files, status and size changes, and the bytes saved
+    Diff {
+        /// The older copy of the database, which is only read
+        snapshot: Utf8PathBuf,
+
+        /// List every changed file
+        #[clap(short, long)]
+        verbose: bool,
+
+        #[clap(long, value_enum, default_value_t = OutputFormat::Text)]
+        format: OutputFormat,
+    },
+    /// Show everything known about a file in the database
+    Show {
+        path: Utf8PathBuf,
+
+        /// Print the options the last encode resolved for the file as JSON
+        #[clap(long)]
+        options: bool,
+
+        /// Point out files whose audio takes up more than this share of the size,
+        /// in percent
+        #[clap(long, default_value_t = breakdown::DEFAULT_MAX_AUDIO_SHARE)]
+        max_audio_share: f64,
+    },
+    /// Remove temporary files left behind by runs that crashed or were killed,
+    /// and `--sidecar` reports whose output is gone
+    ///
+    /// The segments of `--resumable` encodes are kept while their file is pending
+    /// or failed, so that it can still continue where it stopped.
+    Cleanup {
+        /// Directory to search, e.g. the library or the --tmp-dir of earlier runs
+        path: Utf8PathBuf,
+
+        /// Only list the files that would be removed
+        #[clap(short, long)]
+        dry_run: bool,
+    },
+    /// Run ffprobe again for files in the database and update the stored info
+    Reprobe {
+        /// Maximum number of concurrent ffprobe processes [default: 4]
+        #[clap(long)]
+        probe_parallel: Option<usize>,
+
+        #[clap(flatten)]
+        filter: FileFilter,
+    },
+    /// Decode the transcoded outputs to check them for corruption
+    ///
+    /// Only checks files transcoded by versions that record the output path.
+    /// Defaults to files with status success.
+    Verify {
+        /// Only decode this many seconds at several positions of each file
+        #[clap(long)]
+        sample_seconds: Option<f64>,
+
+        #[clap(flatten)]
+        filter: FileFilter,
+    },
+    /// Check the outputs of files encoded with `transcode --no-finalize` and put
+    /// them in place
+    ///
+    /// Works on the files with status encoded. Outputs that are missing, not
+    /// smaller than their source or fail verification leave the file with status
+    /// error. Running it again after an interruption is safe.
+    Finalize {
+        /// Decode each output completely before putting it in place
+        #[clap(long)]
+        verify: bool,
+
+        /// Replace the sources with their outputs
+        #[clap(short, long)]
+        replace: bool,
+
+        #[clap(flatten)]
+        filter: FileFilter,
+    },
+    /// List the outputs of `transcode --no-finalize` that wait to be accepted or
+    /// rejected, with a command to compare each one with its source
+    Review {
+        /// Play each source and output side by side with mpv, one after the other
+        #[clap(long)]
+        play: bool,
+
+        #[clap(flatten)]
+        filter: FileFilter,
+    },
+    /// Finalize reviewed outputs, replacing their sources
+    Accept {
+        /// Glob pattern for the paths of the sources, e.g. '**/Season 1/**'
+        pattern: String,
+
+        /// Decode each output completely before putting it in place
+        #[clap(long)]
+        verify: bool,
+
+        /// Leave the outputs next to their sources instead of replacing them
+        #[clap(long)]
+        keep_source: bool,
+    },
+    /// Delete reviewed outputs and leave their files out of transcode runs
+    /// unless they are run with --force
+    Reject {
+        /// Glob pattern for the paths of the sources
+        pattern: String,
+    },
+    /// Remove files from the database, e.g. `forget --status skipped`
+    Forget {
+        #[clap(flatten)]
+        filter: FileFilter,
+
+        /// Forget the files matching --where without asking
+        #[clap(short, long)]
+        yes: bool,
+    },
+    /// Put files back into the queue, by default the ones that failed
+    Retry {
+        /// Retry the files whose output failed verification or whose audio differs
+        /// from the source instead, removing the broken outputs
+        #[clap(long)]
+        verify_failed: bool,
+    },
+    /// Delete the originals of transcoded files whose outputs were verified at
+    /// least --older-than ago
+    ///
+    /// Files whose output is missing, unverified, failed verification or changed
+    /// size since it was encoded are kept. The rows of deleted originals get the
+    /// status reclaimed.
+    Reclaim {
+        /// Grace period after the verification, e.g. 30d or 2w
+        #[clap(long, value_parser = reclaim::parse_grace_period)]
+        older_than: jiff::SignedDuration,
+
+        /// Only list the originals that would be deleted
+        #[clap(short, long)]
+        dry_run: bool,
+
+        #[clap(flatten)]
+        filter: FileFilter,
+    },
+    /// Shrink the database: drop the parts of the stored ffprobe info that are
+    /// never read and vacuum it
+    Compact {
+        /// Also compress the ffprobe info. Without it, compressed info is stored
+        /// as plain JSON again
+        #[clap(long)]
+        compress: bool,
+    },
+    /// Move transcoded and reclaimed files that haven't changed for a while out of
+    /// the working tables. Archived files are left out of scans and other
+    /// commands, `list --archived` shows them
+    Archive {
+        /// How long the files have to be unchanged, e.g. 90d or 12w
+        #[clap(long, value_parser = reclaim::parse_grace_period)]
+        older_than: jiff::SignedDuration,
+    },
+    /// Move archived files back into the working tables
+    Unarchive {
+        /// Glob pattern for the paths of the files, e.g. '**/Films/**'
+        pattern: String,
+    },
+    /// Keep files out of every transcode run, even with --force, without removing
+    /// them from the database
+    Pin {
+        /// Glob pattern for the paths of the files, e.g. '**/Reference/**'
+        pattern: String,
+
+        /// Why the files are pinned, shown by `show` and `list --pinned`
+        #[clap(long)]
+        note: Option<String>,
+    },
+    /// Let pinned files be transcoded again and remove their notes
+    Unpin {
+        /// Glob pattern for the paths of the files
+        pattern: String,
     },
-    Stats,
-    List,
+    /// Merge the rows of files whose paths differ only in case, keeping the one
+    /// that got furthest, and store the paths in the case they have on disk
+    DedupePaths {
+        /// Only show what would be merged
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Merge a file into the database
+    Import {
+        /// Import the results of files that a run dumped next to the database
+        /// when it couldn't write them
+        #[clap(long)]
+        results_only: bool,
+        /// The file to import
+        file: Utf8PathBuf,
+    },
+}
+
+impl Command {
+    /// Whether the command takes the instance lock. Commands that only read
+    /// the database don't, and neither do `transcode --shared` runs, which
+    /// coordinate through per-file claims so that several machines can work
+    /// through the same database.
+    fn takes_lock(&self) -> bool {
+        match self {
+            Command::Transcode { shared, .. } => !shared,
+            Command::Scan { .. }
+            | Command::Reprobe { .. }
+            | Command::Thumbs { .. }
+            | Command::Verify { .. }
+            | Command::Finalize { .. }
+            | Command::Accept { .. }
+            | Command::Reject { .. }
+            | Command::Forget { .. }
+            | Command::Retry { .. }
+            | Command::Reclaim { .. }
+            | Command::Compact { .. }
+            | Command::Archive { .. }
+            | Command::Unarchive { .. }
+            | Command::Pin { .. }
+            | Command::Unpin { .. }
+            | Command::DedupePaths { .. }
+            | Command::Import { .. } => true,
+            Command::Queue { .. }
+            | Command::Cleanup { .. }
+            | Command::Stats { .. }
+            | Command::List { .. }
+            | Command::Show { .. }
+            | Command::Review { .. }
+            | Command::Workers
+            | Command::ExportStatus { .. }
+            | Command::Diff { .. } => false,
+        }
+    }
+
+    /// Whether the command only reads the database, so that a missing one is
+    /// reported instead of created.
+    fn reads_only(&self) -> bool {
+        matches!(
+            self,
+            Command::Transcode { dry_run: true, .. }
+                | Command::Queue { .. }
+                | Command::Stats { .. }
+                | Command::List { .. }
+                | Command::Workers
+                | Command::ExportStatus { .. }
+                | Command::Diff { .. }
+                | Command::Show { .. }
+                | Command::Review { .. }
+        )
+    }
+
+    /// The file filter of the commands that have one.
+    fn filter(&self) -> Option<&FileFilter> {
+        match self {
+            Command::Transcode { selection, .. } | Command::Queue { selection, .. } => {
+                Some(&selection.filter)
+            }
+            Command::List { filter, .. }
+            | Command::Thumbs { filter, .. }
+            | Command::Reprobe { filter, .. }
+            | Command::Verify { filter, .. }
+            | Command::Finalize { filter, .. }
+            | Command::Review { filter, .. }
+            | Command::Forget { filter, .. }
+            | Command::Reclaim { filter, .. } => Some(filter),
+            _ => None,
+        }
+    }
+}
+
+/// How a command prints its result.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+/// How `list` orders the files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ListOrder {
+    /// Largest files first
+    Size,
+    /// Files that have been in the queue the longest first
+    Age,
 }
 
 #[derive(Parser, Debug)]
@@ -74,145 +893,1853 @@ pub struct Args {
     #[clap(short, long)]
     pub log: Option<tracing::level_filters::LevelFilter>,
 
+    /// Also write the log to a file per day in this directory, at the --log level
+    /// or info. The full error output of failed files is kept there instead of
+    /// in the database
+    #[clap(long)]
+    pub log_dir: Option<Utf8PathBuf>,
+
+    /// Path to the config file [default: transcoder.toml]
+    #[clap(long)]
+    pub config: Option<Utf8PathBuf>,
+
+    /// Path to the database
+    #[clap(long, default_value = "transcoder.db")]
+    pub database: Utf8PathBuf,
+
+    /// Remove the lock of another transcoder instance on this machine that is no
+    /// longer running
+    #[clap(long)]
+    pub force_unlock: bool,
+
+    /// Store and look up paths in the case they have on disk, for case-insensitive
+    /// filesystems. On by default on macOS and Windows, turn it off with
+    /// `--case-insensitive-paths=false`. Run `dedupe-paths` once after turning it on
+    #[clap(
+        long,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true"
+    )]
+    pub case_insensitive_paths: Option<bool>,
+
     #[clap(subcommand)]
     pub command: Command,
 }
 
-fn parse_bytes(string: &str) -> Option<u64> {
-    let mut value = string.trim().to_string();
-    let suffix = value.split_off(value.len() - 1);
-    let value = value.parse::<u64>().ok()?;
-    let multiplier = match suffix.to_lowercase().as_str() {
-        "k" => 1024,
-        "m" => 1024 * 1024,
-        "g" => 1024 * 1024 * 1024,
-        _ => 1,
+fn print_energy(report: &EnergyReport, kwh_price: Option<f64>) {
+    #[derive(Tabled)]
+    struct EnergyEntry<'a> {
+        file: &'a str,
+        time: String,
+        #[tabled(rename = "energy (kWh)")]
+        energy: String,
+        cost: String,
+    }
+
+    let cost = |kwh: f64| kwh_price.map_or(String::new(), |price| format!("{:.2}", kwh * price));
+    let entries = report.files.iter().map(|usage| EnergyEntry {
+        file: usage.path.file_name().unwrap_or_default(),
+        time: usage.wall_time.human_duration().to_string(),
+        energy: format!("{:.3}", usage.kwh()),
+        cost: cost(usage.kwh()),
+    });
+    let mut table = Table::new(entries);
+    table.with(Style::modern());
+    if kwh_price.is_none() {
+        table.with(Remove::column(ByColumnName::new("cost")));
+    }
+    println!("{}", table);
+    print!(
+        "Energy ({}): {:.3} kWh for {} of transcoding",
+        report.source,
+        report.kwh(),
+        report.wall_time().human_duration()
+    );
+    match kwh_price {
+        Some(_) => println!(", cost {}", cost(report.kwh())),
+        None => println!(),
+    }
+}
+
+/// Prints the groups of a distribution with the most files first, with their
+/// share of the files and of the bytes.
+fn print_distribution<K: Ord>(
+    title: &str,
+    distribution: &Distribution<K>,
+    label: impl Fn(&K) -> String,
+) {
+    if distribution.is_empty() {
+        return;
+    }
+    let mut builder = Builder::default();
+    builder.push_record([title, "files", "files %", "size", "size %"]);
+    for (key, share) in distribution.sorted() {
+        builder.push_record([
+            label(key),
+            share.files.to_string(),
+            format!("{:.1}%", distribution.files_percent(share)),
+            share.bytes.human_count_bytes().to_string(),
+            format!("{:.1}%", distribution.bytes_percent(share)),
+        ]);
+    }
+    let total = distribution.total();
+    builder.push_record([
+        "total".to_string(),
+        total.files.to_string(),
+        String::new(),
+        total.bytes.human_count_bytes().to_string(),
+        String::new(),
+    ]);
+    let mut table = builder.build();
+    table.with(Style::modern());
+    println!("{}", table);
+}
+
+/// The columns `stats --history` shows for a week or run.
+fn history_columns(totals: &history::Totals) -> [String; 6] {
+    let saved = totals.saved();
+    [
+        totals.files.to_string(),
+        totals.bytes_before.human_count_bytes().to_string(),
+        totals.bytes_after.human_count_bytes().to_string(),
+        format!(
+            "{}{}",
+            if saved < 0 { "-" } else { "" },
+            saved.unsigned_abs().human_count_bytes()
+        ),
+        totals
+            .speed()
+            .map_or("-".to_string(), |speed| format!("{speed:.2}x")),
+        match totals.failures {
+            0 => "0".to_string(),
+            failures => format!("{failures} ({:.1}%)", totals.failure_percent()),
+        },
+    ]
+}
+
+const HISTORY_HEADER: [&str; 6] = ["files", "before", "after", "saved", "speed", "failures"];
+
+fn print_history_weeks(weeks: &[history::Week]) {
+    if weeks.is_empty() {
+        println!("No files were transcoded yet");
+        return;
+    }
+    let mut builder = Builder::default();
+    builder.push_record(["week", "starts"].into_iter().chain(HISTORY_HEADER));
+    for week in weeks {
+        builder.push_record(
+            [week.label(), week.starts.to_string()]
+                .into_iter()
+                .chain(history_columns(&week.totals)),
+        );
+    }
+    let mut table = builder.build();
+    table.with(Style::modern());
+    println!("{}", table);
+}
+
+fn print_history_runs(runs: &[history::Run], tz: &jiff::tz::TimeZone) {
+    if runs.is_empty() {
+        println!("No files were transcoded yet");
+        return;
+    }
+    let local = |timestamp: jiff::Timestamp| {
+        timestamp
+            .to_zoned(tz.clone())
+            .strftime("%Y-%m-%d %H:%M")
+            .to_string()
     };
-    Some(value * multiplier)
+    let mut builder = Builder::default();
+    builder.push_record(
+        ["run", "started", "finished"]
+            .into_iter()
+            .chain(HISTORY_HEADER),
+    );
+    for run in runs {
+        builder.push_record(
+            [
+                run.run_id.to_string(),
+                run.started_on.map_or("-".to_string(), local),
+                local(run.finished_on),
+            ]
+            .into_iter()
+            .chain(history_columns(&run.totals)),
+        );
+    }
+    let mut table = builder.build();
+    table.with(Style::modern());
+    println!("{}", table);
 }
 
-fn print_stats(files: &[VideoFile]) {
+fn print_stats(files: &[VideoFile], exact: bool) {
     let total_size: u64 = files.iter().map(|f| f.file_size).sum();
     let total_files = files.len();
 
     println!("Total files: {}", total_files);
     println!("Total size: {}", total_size.human_count_bytes());
-
-    let codec_distribution =
-        files
-            .iter()
-            .map(|f| f.codec.as_str())
-            .fold(HashMap::new(), |mut acc, codec| {
-                *acc.entry(codec).or_insert(0) += 1;
-                acc
-            });
-    println!("File counts by codec:");
-    for (codec, count) in codec_distribution {
-        println!("\t{}: {}", codec, count);
-    }
     let total_duration = files.iter().map(|f| f.duration).sum::<f64>();
     println!("Total duration: {}", total_duration.human_duration());
 
-    let resolution_distribution =
+    let by = |key: fn(&VideoFile) -> String| -> Distribution<String> {
+        files.iter().map(|f| (key(f), f.file_size)).collect()
+    };
+    print_distribution("codec", &by(|f| f.codec.clone()), String::clone);
+    print_distribution("container", &by(|f| f.container.clone()), String::clone);
+    let tiers: Distribution<_> = files.iter().map(|f| (f.tier(), f.file_size)).collect();
+    print_distribution("resolution", &tiers, ToString::to_string);
+    if exact {
+        let resolutions: Distribution<_> =
+            files.iter().map(|f| (f.resolution, f.file_size)).collect();
+        print_distribution("exact resolution", &resolutions, |(width, height)| {
+            format!("{width}x{height}")
+        });
+    }
+    let statuses: Distribution<_> = files.iter().map(|f| (f.status, f.file_size)).collect();
+    print_distribution("status", &statuses, ToString::to_string);
+
+    let audio_dominated: Vec<_> = files.iter().filter(|f| f.is_audio_dominated()).collect();
+    if !audio_dominated.is_empty() {
+        let size: u64 = audio_dominated.iter().map(|f| f.file_size).sum();
+        println!(
+            "Audio-dominated files: {} ({}), their audio has a higher bitrate than their video. \
+             Re-encoding the video saves little, --copy-audio-only-above with --audio-codec opus \
+             saves more",
+            audio_dominated.len(),
+            size.human_count_bytes()
+        );
+    }
+}
+
+fn print_diff(snapshot: &Utf8Path, diff: &SnapshotDiff, verbose: bool) {
+    #[derive(Tabled)]
+    struct TableEntry<'a> {
+        change: &'static str,
+        path: &'a str,
+        status: String,
+        size: String,
+    }
+
+    if diff.is_empty() {
+        println!("Nothing changed since {snapshot}");
+        return;
+    }
+    println!(
+        "Since {snapshot}: {} added, {} removed, {} changed status, {} changed size",
+        diff.added, diff.removed, diff.status_changed, diff.size_changed
+    );
+    for (transition, count) in &diff.transitions {
+        println!("\t{transition}: {count}");
+    }
+    let since = diff.saved_since();
+    println!(
+        "Saved: {} then, {} now ({}{})",
+        diff.old_saved.human_count_bytes(),
+        diff.new_saved.human_count_bytes(),
+        if since < 0 { "-" } else { "+" },
+        since.unsigned_abs().human_count_bytes()
+    );
+    if !verbose || diff.files.is_empty() {
+        return;
+    }
+    let describe = |state: Option<&FileState>, field: fn(&FileState) -> String| {
+        state.map_or("-".to_string(), field)
+    };
+    let entries = diff.files.iter().map(|file| TableEntry {
+        change: file.kind(),
+        path: file.path.as_str(),
+        status: format!(
+            "{} -> {}",
+            describe(file.old.as_ref(), |s| s.status.clone()),
+            describe(file.new.as_ref(), |s| s.status.clone())
+        ),
+        size: format!(
+            "{} -> {}",
+            describe(file.old.as_ref(), |s| s
+                .file_size
+                .human_count_bytes()
+                .to_string()),
+            describe(file.new.as_ref(), |s| s
+                .file_size
+                .human_count_bytes()
+                .to_string())
+        ),
+    });
+    let mut table = Table::new(entries);
+    table.with(Style::modern());
+    println!("{}", table);
+}
+
+/// Asks a yes or no question on the terminal, defaulting to no.
+fn ask(question: &str) -> Result<bool> {
+    eprint!("{question} [y/N] ");
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Asks before a command changes the files matching `--where`, which is easy
+/// to get wrong. Without a terminal to ask on, `--yes` has to be passed.
+fn confirm_where(count: usize, action: &str, yes: bool) -> Result<bool> {
+    if yes || count == 0 {
+        return Ok(true);
+    }
+    if !io::stdin().is_terminal() {
+        bail!("{count} files match --where, pass --yes to {action} them");
+    }
+    ask(&format!("{count} files match --where, {action} them?"))
+}
+
+fn print_remaining_time(estimates: &[estimate::BucketEstimate]) {
+    let total: Duration = estimates.iter().map(|e| e.time).sum();
+    let files: usize = estimates.iter().map(|e| e.files).sum();
+    println!(
+        "Estimated remaining encode time: {} for {} pending files",
+        total.human_duration(),
         files
-            .iter()
-            .map(|f| f.resolution)
-            .fold(BTreeMap::new(), |mut acc, res| {
-                *acc.entry(res).or_insert(0) += 1;
-                acc
-            });
-    println!("File counts by resolution:");
-    for (resolution, count) in resolution_distribution {
-        println!("\t{}x{}: {}", resolution.0, resolution.1, count);
+    );
+    for estimate in estimates {
+        println!(
+            "\t{}: {} files, {} ({})",
+            estimate.bucket,
+            estimate.files,
+            estimate.time.human_duration(),
+            estimate.source
+        );
+    }
+    if estimates
+        .iter()
+        .any(|e| e.source != estimate::SpeedSource::Bucket)
+    {
+        println!(
+            "Buckets without encodes of their own use the speed of all encodes, or the \
+             default speed from [estimate.speed] without any"
+        );
+    }
+}
+
+/// Prints how the size of a file splits between its streams.
+fn print_breakdown(breakdown: &SizeBreakdown, max_audio_share: f64) {
+    #[derive(Tabled)]
+    struct TableEntry {
+        stream: String,
+        codec: String,
+        size: String,
+        share: String,
+    }
+
+    let size = |bytes: u64, estimated: bool| {
+        let prefix = if estimated { "~" } else { "" };
+        (
+            format!("{prefix}{}", bytes.human_count_bytes()),
+            format!("{prefix}{:.1}%", breakdown.share(bytes)),
+        )
+    };
+    let mut entries: Vec<_> = breakdown
+        .streams
+        .iter()
+        .map(|stream| {
+            let (size, share) = match stream.bytes {
+                Some(bytes) => size(bytes, stream.estimated),
+                None => ("unknown".into(), "-".into()),
+            };
+            TableEntry {
+                stream: format!("#{} {}", stream.index, stream.kind),
+                codec: stream.codec.clone(),
+                size,
+                share,
+            }
+        })
+        .collect();
+    if breakdown.overhead > 0 {
+        let (size, share) = size(breakdown.overhead, false);
+        entries.push(TableEntry {
+            stream: "overhead".into(),
+            codec: String::new(),
+            size,
+            share,
+        });
+    }
+    let mut table = Table::new(entries);
+    table.with(Style::modern());
+    println!("{table}");
+    if let Some(share) = breakdown.excessive_audio(max_audio_share) {
+        println!(
+            "Audio is {share:.0}% of the size, re-encoding it with --copy-audio-only-above and --audio-codec saves more than a lower CRF"
+        );
+    }
+}
+
+fn print_file(database: &Database, file: &TranscodeFile, max_audio_share: f64) -> Result<()> {
+    println!("ID: {}", file.id);
+    println!("Path: {}", file.path);
+    if let Some(title) = file.ffprobe().as_ref().and_then(|info| info.title()) {
+        println!("Title: {title}");
+    }
+    println!("Status: {}", file.status);
+    if file.pinned {
+        match &file.note {
+            Some(note) => println!("Pinned: {note}"),
+            None => println!("Pinned"),
+        }
+    }
+    println!("Size: {}", file.file_size.human_count_bytes());
+    if let Some(info) = file.ffprobe() {
+        let (width, height) = info.resolution();
+        println!("Codec: {}", info.video_codec());
+        println!("Container: {}", info.container());
+        println!("Resolution: {}x{}", width, height);
+        if let Some(duration) = info.duration() {
+            println!("Duration: {}", duration.human_duration());
+        }
+        if let Some(breakdown) = breakdown::breakdown(&info, file.file_size as u64) {
+            print_breakdown(&breakdown, max_audio_share);
+        }
+        if !info.chapters.is_empty() {
+            println!("Chapters:");
+            for chapter in &info.chapters {
+                println!("\t{chapter}");
+            }
+        }
+    }
+    println!("Added: {}", file.created_on);
+    println!("Updated: {}", file.updated_on);
+    if let Some(thumbnail) = &file.thumbnail_path {
+        println!("Thumbnail: {}", thumbnail);
+    }
+    if let Some(rule) = &file.encoder_rule {
+        println!("Encoder rule: {}", rule);
+    }
+    let renditions = file.renditions()?;
+    if !renditions.is_empty() {
+        println!("Renditions:");
+        for rendition in renditions {
+            println!(
+                "\t{}: {} ({})",
+                rendition.name,
+                rendition.output_path,
+                rendition.output_size.human_count_bytes()
+            );
+        }
+    }
+    if let Some(audio_hash) = file.audio_hash {
+        println!("Audio: {audio_hash}");
+    }
+    if let (Some(cpu_seconds), Some(cpu_percent)) = (file.cpu_seconds, file.cpu_percent) {
+        println!(
+            "CPU time: {} ({cpu_percent:.0}% CPU)",
+            Duration::from_secs_f64(cpu_seconds).human_duration()
+        );
+    }
+    if let Some(peak_rss) = file.peak_rss {
+        println!("Peak memory: {}", (peak_rss as u64).human_count_bytes());
+    }
+    if let (Some(io_mode), Some(read_rate)) = (file.io_mode, file.read_rate) {
+        println!(
+            "Read rate: {} ({io_mode})",
+            io_limit::format_rate(read_rate)
+        );
+    }
+    if let Some(error) = &file.error_message {
+        let full_text = database.error_details(file.id)?;
+        println!("Error: {}", full_text.as_deref().unwrap_or(error));
+    }
+    let changes = file.source_changes()?;
+    if !changes.is_empty() {
+        println!("Changed since it was queued:");
+        for change in changes {
+            println!("\t{change}");
+        }
+    }
+    let attempts = database.crf_attempts(file.id)?;
+    if attempts.len() > 1 {
+        println!("Attempts:");
+        for attempt in attempts {
+            println!(
+                "\tCRF {}: {} on {}",
+                attempt.crf,
+                (attempt.output_size as u64).human_count_bytes(),
+                attempt.attempted_on
+            );
+        }
+    }
+    if let Some(run_id) = file.run_id
+        && let Some(run) = database.get_run(run_id)?
+    {
+        let ffmpeg = run.ffmpeg();
+        let sample = if run.sample { " (a random sample)" } else { "" };
+        println!("Transcoded in run {}{sample} on {}", run.id, run.started_on);
+        println!("Encoder: ffmpeg {}", ffmpeg.version);
+        for (library, version) in &ffmpeg.libraries {
+            println!("\t{}: {}", library, version);
+        }
+    }
+    Ok(())
+}
+
+/// Exit code for a broken configuration or environment, from sysexits.h.
+const EXIT_CONFIG: u8 = 78;
+
+/// Exit code for missing input, here an empty queue or a missing database, from
+/// sysexits.h.
+const EXIT_NOINPUT: u8 = 66;
+
+/// The files whose outputs wait for `finalize`, `accept` or `reject`.
+fn encoded_files(database: &Database) -> Result<Vec<TranscodeFile>> {
+    database.list_filtered(
+        &FileFilter {
+            status: Some(TranscodeStatus::Encoded),
+            ..Default::default()
+        },
+        None,
+    )
+}
+
+fn open_database(command: &Command, path: &Utf8Path) -> Result<Database> {
+    if command.reads_only() {
+        Database::open_existing(path)
+    } else {
+        Database::open(path)
+    }
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(report) => {
+            eprintln!("Error: {report:?}");
+            if report.downcast_ref::<MissingBinary>().is_some()
+                || report.downcast_ref::<DatabaseLocation>().is_some()
+            {
+                ExitCode::from(EXIT_CONFIG)
+            } else if report.downcast_ref::<EmptyQueue>().is_some()
+                || report.downcast_ref::<MissingDatabase>().is_some()
+            {
+                ExitCode::from(EXIT_NOINPUT)
+            } else {
+                ExitCode::FAILURE
+            }
+        }
     }
 }
 
-fn main() -> Result<()> {
+fn run() -> Result<()> {
     let start = Instant::now();
     let args = Args::parse();
-    let database = Database::new()?;
 
+    // with --stdout, stdout carries the video
+    let log_writer = if matches!(
+        args.command,
+        Command::Transcode {
+            stdout: Some(_),
+            ..
+        }
+    ) {
+        BoxMakeWriter::new(std::io::stderr)
+    } else {
+        BoxMakeWriter::new(std::io::stdout)
+    };
+    let (log_file, _log_guard) = match &args.log_dir {
+        Some(dir) => {
+            let (layer, guard) = log_dir::layer(dir, args.log)?;
+            (Some(layer), Some(guard))
+        }
+        None => (None, None),
+    };
     tracing_subscriber::registry()
-        .with(tracing_subscriber::fmt::layer())
-        .with(EnvFilter::new(match args.log {
-            Some(level) => level.to_string(),
-            None => "off".to_string(),
-        }))
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_writer(log_writer)
+                .with_filter(EnvFilter::new(match args.log {
+                    Some(level) => level.to_string(),
+                    None => "off".to_string(),
+                })),
+        )
+        .with(log_file)
         .init();
     color_eyre::install()?;
+    let config = Config::load(args.config.as_deref())?;
+    binaries::configure(config.binaries.clone());
+    let database = open_database(&args.command, &args.database)?
+        .with_error_details(args.log_dir.is_none())
+        .with_max_error_length(
+            config
+                .database
+                .max_error_length
+                .unwrap_or(error_message::DEFAULT_MAX_LENGTH),
+        )
+        .with_case_insensitive_paths(
+            args.case_insensitive_paths
+                .unwrap_or_else(|| paths::case_insensitive_by_default(std::env::consts::OS)),
+        );
+
+    if let Some(filter) = args.command.filter() {
+        filter.check()?;
+    }
+
+    let _lock = if args.command.takes_lock() {
+        Some(lock::acquire(
+            &database,
+            LockHolder::current(),
+            args.force_unlock,
+            lock::pid_is_alive,
+        )?)
+    } else {
+        None
+    };
 
     match args.command {
         Command::Scan {
             exclude,
+            exclude_absolute,
             min_size,
+            max_size,
+            exclude_codec,
+            record_skipped,
+            retry_failed_probes,
+            probe_parallel,
+            library,
+            extensions,
+            extra_extensions,
             path,
         } => {
-            let min_size = min_size.as_deref().and_then(parse_bytes);
-            let collector = Collector::new(database.clone(), path, exclude, min_size);
-            collector.gather_files()?;
+            binaries::check(&[Binary::Ffprobe])?;
+            let defaults = config.library(library.as_deref());
+            let roots = match (path, &library) {
+                (Some(path), _) => vec![path],
+                (None, Some(library)) if !defaults.roots.is_empty() => defaults.roots,
+                (None, Some(library)) => {
+                    bail!("library {library} has no roots in the config file, pass a path to scan")
+                }
+                (None, None) => bail!("pass a path to scan or a --library with configured roots"),
+            };
+            let options = ScanOptions {
+                exclude: exclude.into_iter().chain(defaults.exclude).collect(),
+                exclude_absolute,
+                min_size,
+                max_size,
+                library: library.clone(),
+                codecs: CodecRules::new(exclude_codec),
+                record_skipped,
+                probe_parallel: probe_parallel
+                    .or(config.scan.probe_parallel)
+                    .unwrap_or(collect::DEFAULT_PROBE_PARALLEL),
+                extensions: collect::scan_extensions(extensions, extra_extensions),
+                ignore_file: config.scan.ignore_file(),
+                retry_failed_probes,
+            };
+            for root in roots {
+                let collector = Collector::new(database.clone(), root.clone(), options.clone());
+                let summary = collector.gather_files()?;
+                let label = library
+                    .as_deref()
+                    .map(|library| format!(" (library {library})"))
+                    .unwrap_or_default();
+                println!(
+                    "{root}{label}: {} new files, {} already known, {} failed",
+                    summary.files.inserted,
+                    summary.files.existing,
+                    summary.files.failed.len()
+                );
+                let found: Vec<_> = summary
+                    .extensions
+                    .iter()
+                    .map(|(extension, count)| format!("{extension}: {count}"))
+                    .collect();
+                println!("\tfiles by extension: {}", found.join(", "));
+                for (rule, count) in &summary.excluded {
+                    println!("\texcluded by codec rule {rule}: {count}");
+                }
+                if summary.known_bad > 0 {
+                    println!(
+                        "\tskipped {} known-bad files, pass --retry-failed-probes to probe them again",
+                        summary.known_bad
+                    );
+                }
+                if summary.ignored_directories > 0 {
+                    println!(
+                        "\tignored {} directories with {}",
+                        summary.ignored_directories, options.ignore_file
+                    );
+                }
+                if record_skipped {
+                    println!("\trecorded {} skipped files", summary.recorded_skipped);
+                }
+            }
         }
         Command::Transcode {
             crf,
+            speed,
             effort,
+            film_grain,
+            ten_bit,
+            max_fps,
+            reproducible,
+            low_memory,
+            max_level,
+            profile,
+            copy_audio_only_above,
+            audio_codec,
+            audio_bitrate,
+            drop_audio,
+            drop_commentary,
+            repeat_options,
+            plan,
+            verify_audio_hash,
+            stdout,
+            stdout_format,
             dry_run,
             replace,
+            no_finalize,
+            sidecar,
+            renditions,
+            tmp_dir,
+            cross_device,
             gpu,
+            gpu_device,
             parallel,
-            number,
+            cpu_fill,
+            selection,
+            max_memory,
+            min_free_space,
+            stop_after_saved,
+            min_savings,
+            video_only_size_check,
+            auto_crf,
+            max_crf,
+            resumable,
+            read_rate_limit,
+            io_mode,
+            kwh_price,
+            inhibit_sleep,
+            no_preflight_encode,
+            progress_log_interval,
+            storage_patience,
+            file_timeout,
+            worker_name,
+            reclaim_stale,
+            fail_if_nothing_done,
+            max_predicted_duration,
+            yes,
+            shared: _,
+            #[cfg(feature = "http")]
+            listen,
+            #[cfg(feature = "http")]
+            listen_token,
         } => {
-            let files = database.list_limit(number)?;
+            if !dry_run {
+                binaries::check(&[Binary::Ffmpeg, Binary::Ffprobe])?;
+            }
+            if effort.is_some() {
+                warn!(
+                    "--effort is deprecated, its number means something else for every encoder. \
+                     Use --speed 0 (slowest) to {} (fastest) instead",
+                    Speed::MAX
+                );
+            }
+            if config
+                .transcode_settings(selection.filter.library.as_deref())
+                .effort
+                .is_some()
+            {
+                warn!("effort in the config file is deprecated, use speed instead");
+            }
+            if io_mode == IoMode::Staged && resumable {
+                bail!("--resumable can't continue encodes of a staged copy, use --io-mode direct");
+            }
+            if let Some(max_age) = reclaim_stale {
+                let reclaimed = database.reclaim_stale(max_age)?;
+                if reclaimed > 0 {
+                    info!("returned {reclaimed} stale claims to the queue");
+                }
+            }
+            if !replace
+                && selection.output_dir.is_none()
+                && selection.output_template.may_overwrite_source()
+            {
+                bail!(
+                    "--output-template {} gives some outputs the name of their source, \
+                     pass --output-dir or --replace to use it",
+                    selection.output_template
+                );
+            }
+            let renditions = renditions::resolve(&renditions, &config.renditions)?;
+            let device = selection.device(&config.devices)?;
+            let codec = selection.codec(device.as_ref());
+            let container = codec.container();
+            let audio_codec =
+                audio_codec.unwrap_or_else(|| container.default_audio_encoder().into());
+            if let Some(device) = &device {
+                if max_level.is_some() || profile.is_some() {
+                    bail!("--device {} sets the level and profile itself", device.name);
+                }
+                if !device.audio_codecs.is_empty() && !device.audio_codecs.contains(&audio_codec) {
+                    bail!(
+                        "{} doesn't play --audio-codec {audio_codec}, pick one of {}",
+                        device.name,
+                        device.audio_codecs.join(", ")
+                    );
+                }
+            }
+            if codec != VideoCodec::Av1 && (max_level.is_some() || profile.is_some()) {
+                bail!(
+                    "--max-level and --profile are AV1 levels and profiles, they don't apply to --codec {codec}"
+                );
+            }
+            if codec == VideoCodec::Vp9 && gpu.is_some() {
+                bail!("--codec vp9 is only encoded on the CPU, leave out --gpu");
+            }
+            let container_audio = container.audio_codecs();
+            if !container_audio.is_empty()
+                && !container_audio.contains(&audio_codec.trim_start_matches("lib"))
+            {
+                bail!(
+                    "{container} doesn't take --audio-codec {audio_codec}, pick one of {}",
+                    container_audio.join(", ")
+                );
+            }
+            let default_crf = config::merge(
+                &TranscodeSettings {
+                    crf,
+                    ..Default::default()
+                },
+                None,
+                &config.transcode_settings(selection.filter.library.as_deref()),
+                codec,
+            )
+            .crf;
+            let paths = OutputPaths {
+                tmp_dir,
+                ..selection.output_paths(default_crf, device.as_ref())
+            };
+            let repeat = repeat_options
+                .map(|id| -> Result<_> {
+                    let file = database
+                        .get(id)?
+                        .ok_or_else(|| eyre!("there is no file with the ID {id}"))?;
+                    let options = file
+                        .resolved_options()?
+                        .ok_or_else(|| eyre!("no options were recorded for {}", file.path))?;
+                    if !file.path.is_file() {
+                        bail!("{} doesn't exist anymore", file.path);
+                    }
+                    Ok((file, options))
+                })
+                .transpose()?;
+            let plan = plan.map(|path| Plan::read(&path)).transpose()?;
+            let force = selection.force || repeat.is_some();
+            let library = selection.filter.library.clone();
+            let where_sql = repeat.is_none() && selection.filter.where_sql.is_some();
+            let order = plan.is_none().then_some(selection.order);
+            let (selection, repeat, planned) = match (repeat, &plan) {
+                (Some((file, options)), _) => (
+                    Selection {
+                        files: vec![VideoFile::from(file)],
+                        ..Default::default()
+                    },
+                    Some(options),
+                    HashMap::new(),
+                ),
+                (None, Some(plan)) => {
+                    let (selection, planned) = plan.select(&database, &paths, force)?;
+                    (selection, None, planned)
+                }
+                (None, None) if stdout.is_some() => (Selection::default(), None, HashMap::new()),
+                (None, None) => (
+                    selection::select_from_database(
+                        &database,
+                        &selection,
+                        &paths,
+                        device.as_ref(),
+                    )?,
+                    None,
+                    HashMap::new(),
+                ),
+            };
+            // the options that apply to the whole run when they were recorded
+            let recorded = match (&repeat, &plan) {
+                (Some(repeat), _) => Some(repeat.clone()),
+                (None, Some(plan)) => plan.shared_options()?.cloned(),
+                (None, None) => None,
+            };
+            let gpu = match (&repeat, &plan) {
+                (Some(repeat), _) => repeat.gpu(),
+                (None, Some(plan)) => plan.gpu(),
+                (None, None) => gpu,
+            };
+            for skipped in &selection.skipped {
+                info!("skipping {}: {}", skipped.path, skipped.reason);
+            }
+            for (reason, count) in selection::skip_counts(&selection.skipped) {
+                println!("Skipping {count} files: {reason}");
+            }
+            if where_sql && !dry_run && !confirm_where(selection.files.len(), "transcode", yes)? {
+                println!("Not transcoding any files");
+                return Ok(());
+            }
+            if let Some(seed) = selection.sample_seed {
+                println!(
+                    "Transcoding a random sample of {} files (--seed {seed})",
+                    selection.files.len()
+                );
+            }
+            if stdout.is_none() {
+                let speeds = estimate::Speeds::measure(
+                    &database.encoded_files()?,
+                    estimate::default_speed(gpu.as_ref(), &config.estimate.speed),
+                );
+                let start = estimate::confirm_slow_queue(
+                    &estimate::Prediction::of(&selection.files, &speeds),
+                    max_predicted_duration.map(|max| max.unsigned_abs()),
+                    !dry_run && !yes && io::stdin().is_terminal(),
+                    &mut io::stderr(),
+                    || ask("Start anyway?"),
+                )?;
+                if !start {
+                    println!("Not starting the slow queue");
+                    return Ok(());
+                }
+            }
+            let gpu_sessions = (gpu == Some(GpuMode::Nvidia))
+                .then(capabilities::nvenc_session_limit)
+                .flatten();
+            let explicit_parallel = parallel.is_some();
+            let mut parallel = transcode::resolve_parallel(parallel, gpu.as_ref(), || gpu_sessions);
+            if cpu_fill && !explicit_parallel {
+                parallel += 1;
+            }
+            let watts = match gpu {
+                Some(GpuMode::Nvidia) => config.energy.watts.nvidia,
+                Some(GpuMode::Qsv) => config.energy.watts.qsv,
+                Some(GpuMode::Vaapi) => config.energy.watts.vaapi,
+                Some(GpuMode::Amf) => config.energy.watts.amf,
+                None => config.energy.watts.cpu,
+            };
             let transcode_options = TranscodeOptions {
-                crf,
-                effort,
+                cli: TranscodeSettings {
+                    crf,
+                    speed,
+                    effort,
+                    film_grain,
+                    ten_bit,
+                    max_fps,
+                    reproducible: reproducible.then_some(true),
+                    low_memory: low_memory.then_some(true),
+                },
+                config: config.transcode_settings(library.as_deref()),
                 dry_run,
                 replace,
+                no_finalize,
+                sidecar,
+                renditions,
+                force,
+                worker: lock::worker_id(worker_name.as_deref()),
+                paths,
+                cross_device,
                 gpu,
+                gpu_device,
+                encoder_rules: config.encoder_rules.clone(),
+                audio: match &recorded {
+                    Some(recorded) => recorded.audio.clone(),
+                    None => AudioOptions {
+                        reencode_above: copy_audio_only_above,
+                        codec: audio_codec,
+                        bitrate: audio_bitrate,
+                        drop: drop_audio,
+                        drop_commentary,
+                        allowed_codecs: match &device {
+                            Some(device) => device.audio_codecs.clone(),
+                            None => container_audio.iter().map(|c| c.to_string()).collect(),
+                        },
+                    },
+                },
+                inhibit_sleep,
+                preflight_encode: !no_preflight_encode,
+                sample_run: selection.sample_seed.is_some(),
+                storage_patience: storage_patience.map_or(storage::DEFAULT_PATIENCE, |patience| {
+                    patience.unsigned_abs()
+                }),
+                file_timeout: file_timeout.map(|timeout| timeout.unsigned_abs()),
+                constraints: match (&recorded, &device) {
+                    (Some(recorded), _) => recorded.constraints.clone(),
+                    (None, Some(device)) => Constraints::for_device(device.target())?,
+                    (None, None) if codec != VideoCodec::Av1 => Constraints::for_codec(codec),
+                    (None, None) => Constraints {
+                        max_level,
+                        profile,
+                        video: None,
+                    },
+                },
+                device,
+                verify_audio_hash,
                 parallel,
+                gpu_sessions,
+                cpu_fill,
+                ignore_file: config.scan.ignore_file(),
+                max_memory: max_memory.unwrap_or_else(transcode::default_memory_budget),
+                min_free_space,
+                stop_after_saved,
+                min_savings: min_savings / 100.0,
+                video_only_size_check,
+                auto_crf: auto_crf.then_some(AutoCrf {
+                    step: autocrf::DEFAULT_STEP,
+                    max_crf,
+                }),
+                resumable,
+                read_rate_limit,
+                io_mode,
+                energy: EnergyOptions {
+                    watts,
+                    kwh_price: kwh_price.or(config.energy.kwh_price),
+                },
+                repeat,
+                planned,
+                order,
                 progress_hidden: args.log.is_some(),
+                progress_log_interval: progress_log_interval
+                    .map_or(progress::DEFAULT_LOG_INTERVAL, |interval| {
+                        interval.unsigned_abs()
+                    }),
+                #[cfg(feature = "http")]
+                http: listen
+                    .map(|listen| http::HttpOptions::new(listen, listen_token))
+                    .transpose()?,
             };
-            let files: Vec<_> = files.into_iter().map(From::from).collect();
-            let transcoder = Transcoder::new(database, transcode_options, files);
+            let transcoder = Transcoder::new(database, transcode_options, selection.files);
+            if let Some(input) = stdout {
+                let file = VideoFile::probe(&input)?;
+                return transcoder.stream(&file, stdout_format);
+            }
             transcoder.transcode_all()?;
+            if dry_run {
+                #[derive(Tabled)]
+                struct VerdictEntry {
+                    file: String,
+                    verdict: String,
+                    encoder_rule: String,
+                }
+
+                let entries: Vec<_> = selection
+                    .skipped
+                    .iter()
+                    .map(|skipped| VerdictEntry {
+                        file: skipped.path.to_string(),
+                        verdict: Verdict::Skip(skipped.reason.to_string()).to_string(),
+                        encoder_rule: String::new(),
+                    })
+                    .chain(
+                        transcoder
+                            .verdicts()
+                            .into_iter()
+                            .map(|(path, verdict, rule)| VerdictEntry {
+                                file: path.to_string(),
+                                verdict: verdict.to_string(),
+                                encoder_rule: rule.unwrap_or_default(),
+                            }),
+                    )
+                    .collect();
+                let any_rules = entries.iter().any(|e| !e.encoder_rule.is_empty());
+                let mut table = Table::new(entries);
+                table.with(Style::modern());
+                if !any_rules {
+                    table.with(Remove::column(ByColumnName::new("encoder_rule")));
+                }
+                println!("{}", table);
+            }
             let duration = start.elapsed();
             info!("total duration: {}", duration.human_duration());
+            if !dry_run {
+                let summary = transcoder.summary(&selection.skipped);
+                info!(
+                    "{} encoded, {} skipped because they were done, {} skipped otherwise, {} failed",
+                    summary.transcoded,
+                    summary.skipped_existing,
+                    summary.skipped_ignored,
+                    summary.failed
+                );
+                println!("{summary}");
+                if let Some(usage) = transcoder.usage_summary(duration) {
+                    println!("{usage}");
+                }
+                if let Some(report) = transcoder.energy_report()
+                    && !report.files.is_empty()
+                {
+                    print_energy(&report, kwh_price.or(config.energy.kwh_price));
+                }
+                if fail_if_nothing_done && summary.nothing_done() {
+                    bail!("nothing was transcoded: {summary}");
+                }
+            }
+        }
+        Command::Stats {
+            history: true,
+            weeks,
+            by_run,
+            format,
+            ..
+        } => {
+            let tz = jiff::tz::TimeZone::system();
+            let now = jiff::Timestamp::now();
+            let since = weeks
+                .map(|weeks| history::since(now, weeks, &tz))
+                .transpose()?;
+            let entries = database.history(since)?;
+            match (by_run, format) {
+                (false, OutputFormat::Json) => {
+                    let weeks = history::by_week(&entries, since, now, &tz);
+                    println!("{}", serde_json::to_string_pretty(&weeks)?)
+                }
+                (true, OutputFormat::Json) => {
+                    println!(
+                        "{}",
+                        serde_json::to_string_pretty(&history::by_run(&entries))?
+                    )
+                }
+                (false, OutputFormat::Text) => {
+                    print_history_weeks(&history::by_week(&entries, since, now, &tz))
+                }
+                (true, OutputFormat::Text) => print_history_runs(&history::by_run(&entries), &tz),
+            }
         }
-        Command::Stats => {
+        Command::Stats {
+            library,
+            exact,
+            gpu,
+            ..
+        } => {
+            let filter = FileFilter {
+                library,
+                ..Default::default()
+            };
+            let skipped = database.list_filtered(
+                &FileFilter {
+                    status: Some(TranscodeStatus::Skipped),
+                    ..filter.clone()
+                },
+                None,
+            )?;
+            let speeds = estimate::Speeds::measure(
+                &database.encoded_files()?,
+                estimate::default_speed(gpu.as_ref(), &config.estimate.speed),
+            );
+            let pending = database.list_filtered(
+                &FileFilter {
+                    status: Some(TranscodeStatus::Pending),
+                    ..filter.clone()
+                },
+                None,
+            )?;
+            let oldest_pending = pending
+                .iter()
+                .min_by_key(|f| f.created_on)
+                .map(|f| (f.path.clone(), f.created_on));
+            let pending: Vec<VideoFile> = pending.into_iter().map(VideoFile::from).collect();
+            let mut libraries: BTreeMap<Option<String>, Vec<VideoFile>> = BTreeMap::new();
+            for file in database.list_filtered(&filter, None)? {
+                libraries
+                    .entry(file.library.clone())
+                    .or_default()
+                    .push(file.into());
+            }
+            if libraries.keys().all(Option::is_none) {
+                print_stats(libraries.get(&None).map_or(&[], Vec::as_slice), exact);
+            } else {
+                for (index, (library, files)) in libraries.iter().enumerate() {
+                    if index > 0 {
+                        println!();
+                    }
+                    println!("Library: {}", library.as_deref().unwrap_or("(none)"));
+                    print_stats(files, exact);
+                }
+            }
+            if !skipped.is_empty() {
+                let reasons: Distribution<_> = skipped
+                    .iter()
+                    .filter_map(|f| Some((f.skip_reason?, f.file_size as u64)))
+                    .collect();
+                println!();
+                println!("Skipped files: {}", skipped.len());
+                print_distribution("reason", &reasons, ToString::to_string);
+            }
+            if let Some((path, created_on)) = oldest_pending {
+                println!();
+                println!(
+                    "Oldest pending file: {path}, queued {} ago, {} runs since",
+                    age::format_age(age::queue_age(created_on, jiff::Timestamp::now())),
+                    database.runs_started_since(created_on)?
+                );
+            }
+            if !pending.is_empty() {
+                println!();
+                print_remaining_time(&estimate::estimate_by_bucket(&pending, &speeds));
+            }
+        }
+        Command::Thumbs { dir, filter } => {
+            let files = database.list_filtered(&filter, None)?;
+            let summary = thumbnails::generate(&database, files, &dir, thumbnails::extract_frame)?;
+            println!(
+                "{} thumbnails created, {} already existed, {} failed",
+                summary.created, summary.existing, summary.failed
+            );
+        }
+        Command::Queue {
+            selection,
+            gpu,
+            json,
+            max_predicted_duration,
+            plan,
+        } => {
+            let device = selection.device(&config.devices)?;
+            let codec = selection.codec(device.as_ref());
+            let default_crf = config::merge(
+                &TranscodeSettings::default(),
+                None,
+                &config.transcode_settings(selection.filter.library.as_deref()),
+                codec,
+            )
+            .crf;
+            let paths = selection.output_paths(default_crf, device.as_ref());
+            let library = selection.filter.library.clone();
+            let selection =
+                selection::select_from_database(&database, &selection, &paths, device.as_ref())?;
+            if let Some(seed) = selection.sample_seed {
+                eprintln!(
+                    "A random sample of {} files (--seed {seed})",
+                    selection.files.len()
+                );
+            }
+            if let Some(path) = &plan {
+                let options = TranscodeOptions {
+                    config: config.transcode_settings(library.as_deref()),
+                    paths: paths.clone(),
+                    gpu: gpu.clone(),
+                    encoder_rules: config.encoder_rules.clone(),
+                    audio: AudioOptions {
+                        codec: codec.container().default_audio_encoder().into(),
+                        allowed_codecs: match &device {
+                            Some(device) => device.audio_codecs.clone(),
+                            None => codec
+                                .container()
+                                .audio_codecs()
+                                .iter()
+                                .map(|c| c.to_string())
+                                .collect(),
+                        },
+                        ..TranscodeOptions::dry_run().audio
+                    },
+                    constraints: match &device {
+                        Some(device) => Constraints::for_device(device.target())?,
+                        None => Constraints::for_codec(codec),
+                    },
+                    device: device.clone(),
+                    ..TranscodeOptions::dry_run()
+                };
+                let transcoder = Transcoder::new(database.clone(), options, vec![]);
+                let entries = selection
+                    .files
+                    .iter()
+                    .map(|file| PlanEntry::new(&file.path, transcoder.resolved_options(file)?))
+                    .collect::<Result<_>>()?;
+                Plan::new(entries).write(path)?;
+                eprintln!(
+                    "Wrote a plan of {} files to {path}, run it with `transcode --plan {path}`",
+                    selection.files.len()
+                );
+            }
+            let speeds = estimate::Speeds::measure(
+                &database.encoded_files()?,
+                estimate::default_speed(gpu.as_ref(), &config.estimate.speed),
+            );
+            let entries = estimate::queue_entries(&selection.files, &speeds);
+            estimate::confirm_slow_queue(
+                &estimate::Prediction::of(&selection.files, &speeds),
+                max_predicted_duration.map(|max| max.unsigned_abs()),
+                false,
+                &mut io::stderr(),
+                || Ok(true),
+            )?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&entries)?);
+                return Ok(());
+            }
+
+            #[derive(Tabled)]
+            struct QueueRow<'a> {
+                file_name: &'a str,
+                file_size: String,
+                total_size: String,
+                time: String,
+                total_time: String,
+            }
+
+            let rows = entries.iter().map(|e| QueueRow {
+                file_name: e.path.file_name().unwrap_or_default(),
+                file_size: e.file_size.human_count_bytes().to_string(),
+                total_size: e.cumulative_size.human_count_bytes().to_string(),
+                time: Duration::from_secs(e.estimated_seconds)
+                    .human_duration()
+                    .to_string(),
+                total_time: Duration::from_secs(e.cumulative_seconds)
+                    .human_duration()
+                    .to_string(),
+            });
+            let mut table = Table::new(rows);
+            table.with(Style::modern());
+            println!("{}", table);
+            for (reason, count) in selection::skip_counts(&selection.skipped) {
+                println!("Skipping {count} files: {reason}");
+            }
+            match speeds.overall() {
+                Some(speed) => println!(
+                    "Times are based on the measured speeds per resolution and codec, \
+                     {speed:.2}x realtime at 1080p overall"
+                ),
+                None => println!(
+                    "Times assume {:.2}x realtime at 1080p, there is no encode history yet",
+                    speeds.default()
+                ),
+            }
+        }
+        Command::Cleanup { path, dry_run } => {
             let files = database.list()?;
-            let video_files: Vec<_> = files.into_iter().map(From::from).collect();
-            print_stats(&video_files);
+            let known = cleanup::KnownFiles::new(
+                files.iter().map(|f| f.path.as_path()),
+                files
+                    .iter()
+                    .filter(|f| f.status == TranscodeStatus::InProgress)
+                    .map(|f| f.id),
+                files
+                    .iter()
+                    .filter(|f| {
+                        matches!(f.status, TranscodeStatus::Pending | TranscodeStatus::Error)
+                    })
+                    .map(|f| f.id),
+            );
+            let leftovers = cleanup::find_leftovers(&path, &known, lock::pid_is_alive);
+            let mut freed = 0;
+            for leftover in &leftovers {
+                let size = cleanup::disk_usage(leftover);
+                if dry_run {
+                    println!("Would remove {leftover}");
+                } else if leftover.is_dir() {
+                    std::fs::remove_dir_all(leftover)?;
+                    println!("Removed {leftover}");
+                } else {
+                    std::fs::remove_file(leftover)?;
+                    println!("Removed {leftover}");
+                }
+                freed += size;
+            }
+            println!(
+                "{} leftover files, {}",
+                leftovers.len(),
+                freed.human_count_bytes()
+            );
         }
-        Command::List => {
+        Command::Workers => {
+            #[derive(Tabled)]
+            struct WorkerEntry<'a> {
+                worker: &'a str,
+                file_name: &'a str,
+                last_update: String,
+            }
+
+            let now = database.now()?;
+            let claims = database.claims()?;
+            let entries: Vec<_> = claims
+                .iter()
+                .map(|f| WorkerEntry {
+                    worker: f.claimed_by.as_deref().unwrap_or("unknown"),
+                    file_name: f.path.file_name().unwrap_or_default(),
+                    last_update: f.claimed_at.map_or("unknown".to_string(), |claimed_at| {
+                        let age = now.duration_since(claimed_at).as_secs().max(0) as u64;
+                        format!("{} ago", Duration::from_secs(age).human_duration())
+                    }),
+                })
+                .collect();
+            if entries.is_empty() {
+                println!("No files are being transcoded");
+            } else {
+                let mut table = Table::new(entries);
+                table.with(Style::modern());
+                println!("{}", table);
+            }
+        }
+        Command::ExportStatus { output, watch } => {
+            // the interval runs from the start of one write to the next
+            let mut schedule =
+                Throttle::new(watch.map_or(Duration::ZERO, |watch| watch.unsigned_abs()));
+            loop {
+                std::thread::sleep(schedule.remaining(Instant::now()));
+                schedule.ready(Instant::now());
+                let exported = QueueSnapshot::collect(&database)
+                    .and_then(|snapshot| snapshot.to_json())
+                    .and_then(|json| paths::write_atomically(&output, json.as_bytes()));
+                match watch {
+                    // a busy database or a full disk shouldn't end the watch
+                    Some(_) => {
+                        if let Err(e) = exported {
+                            warn!("could not export the status to {output}: {e:?}");
+                        }
+                    }
+                    None => break exported?,
+                }
+            }
+        }
+        Command::Diff {
+            snapshot,
+            verbose,
+            format,
+        } => {
+            let diff = database.diff_snapshot(&snapshot)?;
+            match format {
+                OutputFormat::Json => println!("{}", serde_json::to_string_pretty(&diff)?),
+                OutputFormat::Text => print_diff(&snapshot, &diff, verbose),
+            }
+        }
+        Command::Show {
+            path,
+            options,
+            max_audio_share,
+        } => {
+            let file = match database.get_by_path(&path)? {
+                Some(file) => Some(file),
+                None => match path.canonicalize_utf8() {
+                    Ok(path) => database.get_by_path(&path)?,
+                    Err(_) => None,
+                },
+            };
+            match file {
+                Some(file) if options => match file.resolved_options()? {
+                    Some(options) => println!("{}", serde_json::to_string_pretty(&options)?),
+                    None => bail!("no options were recorded for {}", file.path),
+                },
+                Some(file) => print_file(&database, &file, max_audio_share)?,
+                None => bail!("{path} is not in the database"),
+            }
+        }
+        Command::Reprobe {
+            probe_parallel,
+            filter,
+        } => {
+            let probe_parallel = probe_parallel
+                .or(config.scan.probe_parallel)
+                .unwrap_or(collect::DEFAULT_PROBE_PARALLEL);
+            let summary = collect::reprobe(&database, &filter, probe_parallel)?;
+            println!(
+                "{} changed, {} unchanged, {} missing, {} failed",
+                summary.changed, summary.unchanged, summary.missing, summary.failed
+            );
+        }
+        Command::Verify {
+            sample_seconds,
+            mut filter,
+        } => {
+            filter.status.get_or_insert(TranscodeStatus::Success);
+            let files = database.list_filtered(&filter, None)?;
+            let summary = verify::verify(&database, files, sample_seconds, verify::decode)?;
+            for (output, error) in &summary.failed {
+                println!("{output}: {error}");
+            }
+            println!(
+                "{} passed, {} failed, {} without a recorded output",
+                summary.passed,
+                summary.failed.len(),
+                summary.unrecorded
+            );
+            if !summary.failed.is_empty() {
+                println!("Run `transcoder retry --verify-failed` to transcode them again");
+            }
+        }
+        Command::Finalize {
+            verify,
+            replace,
+            mut filter,
+        } => {
+            filter.status = Some(TranscodeStatus::Encoded);
+            let files = database.list_filtered(&filter, None)?;
+            let summary = finalize::finalize(
+                &database,
+                files,
+                FinalizeOptions { verify, replace },
+                verify::decode,
+            )?;
+            for (path, error) in &summary.failed {
+                println!("{path}: {error}");
+            }
+            println!(
+                "{} finalized, {} failed",
+                summary.finalized,
+                summary.failed.len()
+            );
+        }
+        Command::Review { play, mut filter } => {
+            filter.status = Some(TranscodeStatus::Encoded);
+            let files = database.list_filtered(&filter, None)?;
+            for entry in files.iter().filter_map(ReviewEntry::new) {
+                let output_size = match entry.output_size {
+                    Some(size) => size.human_count_bytes().to_string(),
+                    None => "missing".to_string(),
+                };
+                println!(
+                    "{}: {} -> {output_size}",
+                    entry.path,
+                    entry.source_size.human_count_bytes()
+                );
+                if play && entry.output_size.is_some() {
+                    entry.play()?;
+                } else {
+                    println!("  {}", entry.mpv_command());
+                }
+            }
+            println!(
+                "{} files to review, `transcoder accept <pattern>` or `transcoder reject <pattern>` them",
+                files.len()
+            );
+        }
+        Command::Accept {
+            pattern,
+            verify,
+            keep_source,
+        } => {
+            let files = review::matching(encoded_files(&database)?, &pattern);
+            let summary = finalize::finalize(
+                &database,
+                files,
+                FinalizeOptions {
+                    verify,
+                    replace: !keep_source,
+                },
+                verify::decode,
+            )?;
+            for (path, error) in &summary.failed {
+                println!("{path}: {error}");
+            }
+            println!(
+                "{} accepted, {} failed",
+                summary.finalized,
+                summary.failed.len()
+            );
+        }
+        Command::Reject { pattern } => {
+            let files = review::matching(encoded_files(&database)?, &pattern);
+            let summary = review::reject(&database, files)?;
+            println!(
+                "{} rejected, deleted {} of outputs",
+                summary.rejected,
+                summary.deleted_bytes.human_count_bytes()
+            );
+        }
+        Command::Reclaim {
+            older_than,
+            dry_run,
+            mut filter,
+        } => {
+            filter.status = Some(TranscodeStatus::Success);
+            let files = database.list_filtered(&filter, None)?;
+            let summary = reclaim::reclaim(&database, files, older_than, dry_run)?;
+            for (path, reason) in &summary.kept {
+                println!("Keeping {path}: {reason}");
+            }
+            for (path, _) in &summary.reclaimed {
+                if dry_run {
+                    println!("Would delete {path}");
+                } else {
+                    println!("Deleted {path}");
+                }
+            }
+            println!(
+                "{} {} originals, {}, kept {}",
+                if dry_run {
+                    "Would reclaim"
+                } else {
+                    "Reclaimed"
+                },
+                summary.reclaimed.len(),
+                summary.bytes().human_count_bytes(),
+                summary.kept.len()
+            );
+        }
+        Command::Compact { compress } => {
+            let summary = database.compact(compress)?;
+            if summary.unreadable > 0 {
+                println!(
+                    "Left the unreadable ffprobe info of {} files as it was",
+                    summary.unreadable
+                );
+            }
+            println!(
+                "Rewrote the ffprobe info of {} files, the database went from {} to {}",
+                summary.rewritten,
+                summary.size_before.human_count_bytes(),
+                summary.size_after.human_count_bytes()
+            );
+        }
+        Command::Archive { older_than } => {
+            let archived = database.archive(older_than)?;
+            println!("{archived} files archived");
+        }
+        Command::Unarchive { pattern } => {
+            let summary = database.unarchive(&pattern)?;
+            for path in &summary.conflicts {
+                println!("Kept {path} in the archive, it was scanned again since");
+            }
+            println!("{} files restored from the archive", summary.restored);
+        }
+        Command::Pin { pattern, note } => {
+            let pinned = database.pin(&pattern, note.as_deref())?;
+            println!("{pinned} files pinned");
+        }
+        Command::Unpin { pattern } => {
+            let unpinned = database.unpin(&pattern)?;
+            println!("{unpinned} files unpinned");
+        }
+        Command::DedupePaths { dry_run } => {
+            let duplicates = dedupe::dedupe_paths(&database, dry_run)?;
+            let removed: usize = duplicates.iter().map(|d| d.remove.len()).sum();
+            for duplicate in &duplicates {
+                println!(
+                    "{} ({}): {} duplicates",
+                    duplicate.path,
+                    duplicate.keep.status,
+                    duplicate.remove.len()
+                );
+            }
+            if dry_run {
+                println!(
+                    "Would remove {removed} duplicate rows and update {} files",
+                    duplicates.len()
+                );
+            } else {
+                println!(
+                    "Removed {removed} duplicate rows and updated {} files",
+                    duplicates.len()
+                );
+            }
+        }
+        Command::Import { results_only, file } => {
+            if !results_only {
+                bail!("only results can be imported so far, pass --results-only");
+            }
+            let dump = results::ResultsDump::read(&file)?;
+            let imported = results::import_results(&database, &dump)?;
+            println!(
+                "Imported the results of {imported} of {} files",
+                dump.results.len()
+            );
+        }
+        Command::Forget { filter, yes } => {
+            if filter.status.is_none() {
+                bail!("pass --status to choose which files to forget");
+            }
+            if filter.where_sql.is_some() {
+                let count = database.list_filtered(&filter, None)?.len();
+                if !confirm_where(count, "forget", yes)? {
+                    println!("Not forgetting any files");
+                    return Ok(());
+                }
+            }
+            let removed = database.forget(&filter)?;
+            println!("{removed} files removed from the database");
+        }
+        Command::Retry { verify_failed } => {
+            let files = if verify_failed {
+                database.verify_failed()?
+            } else {
+                let filter = FileFilter {
+                    status: Some(TranscodeStatus::Error),
+                    ..Default::default()
+                };
+                database.list_filtered(&filter, None)?
+            };
+            let mut requeued = 0;
+            for file in files {
+                if let Some(output) = &file.output_path {
+                    if paths::replaced_source(&file.path, output) {
+                        warn!(
+                            "{} replaced its source, there is nothing left to transcode it from",
+                            file.path
+                        );
+                        continue;
+                    }
+                    if output.is_file() {
+                        std::fs::remove_file(output)?;
+                        info!("removed broken output {output}");
+                    }
+                    sidecar::remove_orphan(output);
+                }
+                database.requeue(file.id)?;
+                requeued += 1;
+            }
+            println!("{requeued} files queued again");
+        }
+        Command::List {
+            filter,
+            wide,
+            archived,
+            pinned,
+            sort,
+        } => {
             #[derive(Tabled)]
             struct TableEntry<'a> {
+                id: i64,
                 file_name: &'a str,
+                title: String,
                 file_size: String,
+                age: String,
+                #[tabled(rename = "runs since")]
+                runs_since: String,
                 codec: String,
+                container: String,
                 resolution: String,
+                tier: String,
                 status: String,
+                error: &'a str,
+                note: &'a str,
             }
 
-            let files = database.list()?;
+            let mut files = if archived {
+                database.list_archived(&filter)?
+            } else {
+                database.list_filtered(&filter, None)?
+            };
+            if pinned {
+                files.retain(|f| f.pinned);
+            }
+            if sort == ListOrder::Age {
+                // sort_by_key is stable, files queued together stay largest first
+                files.sort_by_key(|f| f.created_on);
+            }
+            let now = jiff::Timestamp::now();
             let entries: Vec<_> = files
                 .iter()
-                .map(|f| TableEntry {
-                    file_name: f.path.file_name().unwrap_or_default(),
-                    file_size: f.file_size.human_count_bytes().to_string(),
-                    codec: f
-                        .ffprobe()
-                        .as_ref()
-                        .map_or("Unknown", |info| info.video_codec())
-                        .to_string(),
-                    resolution: f.ffprobe().as_ref().map_or("Unknown".to_string(), |info| {
-                        let (width, height) = info.resolution();
-                        format!("{}x{}", width, height)
-                    }),
-                    status: f.status.to_string(),
+                .map(|f| -> Result<_> {
+                    Ok(TableEntry {
+                        id: f.id,
+                        file_name: f.path.file_name().unwrap_or_default(),
+                        title: f
+                            .ffprobe()
+                            .as_ref()
+                            .and_then(|info| info.title().map(String::from))
+                            .unwrap_or_default(),
+                        file_size: f.file_size.human_count_bytes().to_string(),
+                        age: age::format_age(age::queue_age(f.created_on, now)),
+                        runs_since: match filter.stale {
+                            Some(_) => database.runs_started_since(f.created_on)?.to_string(),
+                            None => String::new(),
+                        },
+                        codec: f
+                            .ffprobe()
+                            .as_ref()
+                            .map_or("Unknown", |info| info.video_codec())
+                            .to_string(),
+                        container: f
+                            .ffprobe()
+                            .as_ref()
+                            .map_or("Unknown".to_string(), |info| info.container()),
+                        resolution: f.ffprobe().as_ref().map_or("Unknown".to_string(), |info| {
+                            let (width, height) = info.resolution();
+                            format!("{}x{}", width, height)
+                        }),
+                        tier: f.ffprobe().as_ref().map_or("Unknown".to_string(), |info| {
+                            collect::resolution_tier(info.resolution()).to_string()
+                        }),
+                        status: f.status.to_string(),
+                        error: f
+                            .error_message
+                            .as_deref()
+                            .map(error_message::summary)
+                            .unwrap_or_default(),
+                        note: f.note.as_deref().unwrap_or_default(),
+                    })
                 })
-                .collect();
+                .collect::<Result<_>>()?;
+            let any_errors = entries.iter().any(|e| !e.error.is_empty());
+            let any_notes = entries.iter().any(|e| !e.note.is_empty());
             let mut table = Table::new(entries);
             table.with(Style::modern());
+            if !wide {
+                table.with(Remove::column(ByColumnName::new("title")));
+                table.with(Remove::column(ByColumnName::new("container")));
+                table.with(Remove::column(ByColumnName::new("tier")));
+            }
+            if archived {
+                // archived files have an id of the archive, that no command takes
+                table.with(Remove::column(ByColumnName::new("id")));
+            }
+            if !any_errors {
+                table.with(Remove::column(ByColumnName::new("error")));
+            }
+            if !any_notes {
+                table.with(Remove::column(ByColumnName::new("note")));
+            }
+            if filter.stale.is_none() {
+                table.with(Remove::column(ByColumnName::new("runs since")));
+            }
             println!("{}", table);
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(args: &[&str]) -> Command {
+        Args::try_parse_from([&["transcoder"], args].concat())
+            .unwrap()
+            .command
+    }
+
+    #[test]
+    fn test_second_transcode_is_refused() -> Result<()> {
+        let database = Database::in_memory()?;
+        let holder = |pid| LockHolder {
+            pid,
+            ..LockHolder::current()
+        };
+        let transcode = command(&["transcode"]);
+        assert!(transcode.takes_lock());
+        let _lock = lock::acquire(&database, holder(1), false, |_| true)?;
+        let error = lock::acquire(&database, holder(2), false, |_| true)
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("pid 1"), "{error}");
+
+        // runs that split the queue with other machines don't take the lock
+        assert!(!command(&["transcode", "--shared"]).takes_lock());
+        assert!(!command(&["list"]).takes_lock());
+        assert!(command(&["forget"]).takes_lock());
+        Ok(())
+    }
+
+    #[test]
+    fn test_dry_run_needs_database() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let path = Utf8Path::from_path(tempdir.path())
+            .unwrap()
+            .join("transcoder.db");
+        let error = open_database(&command(&["transcode", "--dry-run"]), &path)
+            .err()
+            .unwrap();
+        assert!(error.downcast_ref::<MissingDatabase>().is_some());
+        assert!(!path.exists());
+
+        open_database(&command(&["transcode"]), &path)?;
+        assert!(path.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_ten_bit_flag() {
+        let ten_bit = |args: &[&str]| match command(&[&["transcode"], args].concat()) {
+            Command::Transcode { ten_bit, .. } => ten_bit,
+            _ => unreachable!(),
+        };
+        assert_eq!(None, ten_bit(&[]));
+        assert_eq!(Some(true), ten_bit(&["--ten-bit"]));
+        assert_eq!(Some(false), ten_bit(&["--ten-bit=false"]));
+    }
+}
@@ -0,0 +1,170 @@
+use std::cmp::Reverse;
+
+use clap::ValueEnum;
+
+use crate::collect::VideoFile;
+use crate::database::SizeOrder;
+use crate::estimate;
+
+/// What an ordering policy knows about a file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Job {
+    pub file_size: u64,
+    /// Expected encode effort in seconds of 1080p video.
+    pub work: f64,
+}
+
+impl From<&VideoFile> for Job {
+    fn from(file: &VideoFile) -> Self {
+        Job {
+            file_size: file.file_size,
+            work: estimate::encode_time(file, 1.0).as_secs_f64(),
+        }
+    }
+}
+
+/// Decides the order in which the scheduler hands out files to the parallel slots.
+pub trait OrderingPolicy {
+    /// Returns the indices of `jobs` in the order they should run.
+    fn order(&self, jobs: &[Job]) -> Vec<usize>;
+}
+
+pub struct BiggestFirst;
+
+impl OrderingPolicy for BiggestFirst {
+    fn order(&self, jobs: &[Job]) -> Vec<usize> {
+        let mut indices: Vec<_> = (0..jobs.len()).collect();
+        indices.sort_by_key(|&i| Reverse(jobs[i].file_size));
+        indices
+    }
+}
+
+pub struct SmallestFirst;
+
+impl OrderingPolicy for SmallestFirst {
+    fn order(&self, jobs: &[Job]) -> Vec<usize> {
+        let mut indices: Vec<_> = (0..jobs.len()).collect();
+        indices.sort_by_key(|&i| jobs[i].file_size);
+        indices
+    }
+}
+
+/// Starts the files that take longest to encode first, so that whenever a slot
+/// frees up it picks the longest remaining file. The short files end up filling
+/// the gaps at the end instead of one long file running alone, which is what
+/// ordering by size leads to when size and encode time don't match.
+pub struct Interleaved;
+
+impl OrderingPolicy for Interleaved {
+    fn order(&self, jobs: &[Job]) -> Vec<usize> {
+        let mut indices: Vec<_> = (0..jobs.len()).collect();
+        indices.sort_by(|&a, &b| jobs[b].work.total_cmp(&jobs[a].work));
+        indices
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum FileSortOrder {
+    BiggestFirst,
+    SmallestFirst,
+    /// Longest encode time first, which keeps parallel slots busy until the end
+    Interleaved,
+}
+
+impl FileSortOrder {
+    pub fn policy(&self) -> Box<dyn OrderingPolicy> {
+        match self {
+            FileSortOrder::BiggestFirst => Box::new(BiggestFirst),
+            FileSortOrder::SmallestFirst => Box::new(SmallestFirst),
+            FileSortOrder::Interleaved => Box::new(Interleaved),
+        }
+    }
+
+    /// The order to read the files from the database in, when the database can
+    /// sort them itself. The interleaved order needs the encode estimate of
+    /// every file, so it has to read them all first.
+    pub fn size_order(&self) -> Option<SizeOrder> {
+        match self {
+            FileSortOrder::BiggestFirst => Some(SizeOrder::Descending),
+            FileSortOrder::SmallestFirst => Some(SizeOrder::Ascending),
+            FileSortOrder::Interleaved => None,
+        }
+    }
+
+    /// Sorts the files by the policy. The policies sort stably, so files that
+    /// compare equal stay in the order of their paths, like the database has them.
+    pub fn sort(&self, mut files: Vec<VideoFile>) -> Vec<VideoFile> {
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        let jobs: Vec<Job> = files.iter().map(Job::from).collect();
+        let order = self.policy().order(&jobs);
+        let mut files: Vec<Option<VideoFile>> = files.into_iter().map(Some).collect();
+        order
+            .into_iter()
+            .map(|i| files[i].take().expect("orderings return every index once"))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Time until all jobs are done when every free slot takes the next job in order.
+    fn makespan(jobs: &[Job], order: &[usize], slots: usize) -> f64 {
+        let mut finish_times = vec![0.0_f64; slots];
+        for &index in order {
+            let slot = finish_times
+                .iter()
+                .enumerate()
+                .min_by(|a, b| a.1.total_cmp(b.1))
+                .map(|(slot, _)| slot)
+                .unwrap();
+            finish_times[slot] += jobs[index].work;
+        }
+        finish_times.into_iter().fold(0.0, f64::max)
+    }
+
+    fn job(file_size: u64, work: f64) -> Job {
+        Job { file_size, work }
+    }
+
+    #[test]
+    fn test_orders_are_permutations() {
+        let jobs = [job(3, 1.0), job(1, 3.0), job(2, 2.0)];
+        for order in FileSortOrder::value_variants() {
+            let mut indices = order.policy().order(&jobs);
+            indices.sort();
+            assert_eq!(vec![0, 1, 2], indices, "{order:?}");
+        }
+        assert_eq!(vec![0, 2, 1], BiggestFirst.order(&jobs));
+        assert_eq!(vec![1, 2, 0], SmallestFirst.order(&jobs));
+        assert_eq!(vec![1, 2, 0], Interleaved.order(&jobs));
+    }
+
+    #[test]
+    fn test_interleaved_shortens_the_tail() {
+        // high bitrate remuxes that encode quickly and one small but long 4K file
+        let mut jobs: Vec<_> = (0..9).map(|i| job(40 - i, 10.0)).collect();
+        jobs.push(job(5, 100.0));
+
+        let biggest_first = makespan(&jobs, &BiggestFirst.order(&jobs), 2);
+        let interleaved = makespan(&jobs, &Interleaved.order(&jobs), 2);
+        assert_eq!(140.0, biggest_first);
+        assert_eq!(100.0, interleaved);
+    }
+
+    #[test]
+    fn test_interleaved_is_never_worse() {
+        let jobs: Vec<_> = [7.0, 3.0, 12.0, 5.0, 5.0, 9.0, 1.0, 30.0, 2.0, 8.0]
+            .iter()
+            .enumerate()
+            .map(|(i, &work)| job(i as u64, work))
+            .collect();
+        for slots in 1..=4 {
+            let interleaved = makespan(&jobs, &Interleaved.order(&jobs), slots);
+            for policy in [&BiggestFirst as &dyn OrderingPolicy, &SmallestFirst] {
+                assert!(interleaved <= makespan(&jobs, &policy.order(&jobs), slots));
+            }
+        }
+    }
+}
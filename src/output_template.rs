@@ -0,0 +1,389 @@
+use std::fmt;
+use std::str::FromStr;
+
+use jiff::civil::Date;
+
+use crate::device::Container;
+
+/// The template of the default output names, `<stem>_av1.mp4` for AV1.
+pub const DEFAULT_TEMPLATE: &str = "{stem}_{codec}.{ext}";
+
+/// A placeholder of an output template.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    /// The name of the source without its extension.
+    Stem,
+    /// The extension of the output, without the dot.
+    Ext,
+    /// The video codec of the output.
+    Codec,
+    Crf,
+    /// The height of the source in pixels.
+    Height,
+    /// The day the file is transcoded, as YYYY-MM-DD.
+    Date,
+}
+
+impl Field {
+    const ALL: [Field; 6] = [
+        Field::Stem,
+        Field::Ext,
+        Field::Codec,
+        Field::Crf,
+        Field::Height,
+        Field::Date,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Field::Stem => "stem",
+            Field::Ext => "ext",
+            Field::Codec => "codec",
+            Field::Crf => "crf",
+            Field::Height => "height",
+            Field::Date => "date",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Placeholder(Field),
+}
+
+/// What the placeholders of a template stand for, for one file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NameFields<'a> {
+    pub stem: &'a str,
+    /// The name of the video codec, e.g. `av1`.
+    pub codec: &'a str,
+    /// The extension of the output's container, e.g. `mp4`.
+    pub ext: &'a str,
+    pub crf: u8,
+    pub height: u32,
+    pub date: Date,
+}
+
+/// The name of the output files, like `{stem} [AV1 CRF{crf}].{ext}`. `{{` and
+/// `}}` stand for literal braces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OutputTemplate {
+    template: String,
+    parts: Vec<Part>,
+}
+
+impl Default for OutputTemplate {
+    fn default() -> Self {
+        DEFAULT_TEMPLATE
+            .parse()
+            .expect("the default template is valid")
+    }
+}
+
+impl fmt::Display for OutputTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.template)
+    }
+}
+
+impl FromStr for OutputTemplate {
+    type Err = String;
+
+    fn from_str(template: &str) -> Result<Self, Self::Err> {
+        let mut parts = vec![];
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(format!("unclosed placeholder in '{template}'")),
+                        }
+                    }
+                    let field = Field::ALL
+                        .into_iter()
+                        .find(|field| field.name() == name)
+                        .ok_or_else(|| {
+                            format!(
+                                "unknown placeholder {{{name}}}, expected one of {}",
+                                Field::ALL
+                                    .map(|field| format!("{{{}}}", field.name()))
+                                    .join(", ")
+                            )
+                        })?;
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Placeholder(field));
+                }
+                '}' => {
+                    return Err(format!(
+                        "unmatched }} in '{template}', write }}}} for a brace"
+                    ));
+                }
+                '/' | '\\' => {
+                    return Err(format!(
+                        "'{template}' contains a path separator, it can only name a file"
+                    ));
+                }
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+        // without the stem, all outputs of a directory would get the same name
+        if !parts.contains(&Part::Placeholder(Field::Stem)) {
+            return Err(format!("'{template}' doesn't contain {{stem}}"));
+        }
+        Ok(OutputTemplate {
+            template: template.into(),
+            parts,
+        })
+    }
+}
+
+impl OutputTemplate {
+    /// The file name for the values, with characters that are invalid in file
+    /// names on this system replaced.
+    pub fn render(&self, fields: &NameFields) -> String {
+        sanitize(&self.render_raw(fields), cfg!(windows))
+    }
+
+    fn render_raw(&self, fields: &NameFields) -> String {
+        let mut name = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(literal) => name.push_str(literal),
+                Part::Placeholder(Field::Stem) => name.push_str(fields.stem),
+                Part::Placeholder(Field::Ext) => name.push_str(fields.ext),
+                Part::Placeholder(Field::Codec) => name.push_str(fields.codec),
+                Part::Placeholder(Field::Crf) => name.push_str(&fields.crf.to_string()),
+                Part::Placeholder(Field::Height) => name.push_str(&fields.height.to_string()),
+                Part::Placeholder(Field::Date) => name.push_str(&fields.date.to_string()),
+            }
+        }
+        name
+    }
+
+    /// Whether the output of some source would have the source's own name, e.g.
+    /// with `{stem}.{ext}` for MP4 sources. Only allowed when the outputs go to
+    /// another directory or replace the sources anyway.
+    pub fn may_overwrite_source(&self) -> bool {
+        // only the stem and extension can reproduce the source name
+        let fixed = self.parts.iter().all(|part| {
+            matches!(
+                part,
+                Part::Literal(_) | Part::Placeholder(Field::Stem | Field::Ext)
+            )
+        });
+        fixed
+            && Container::ALL.into_iter().any(|container| {
+                let ext = container.extension();
+                [format!("video.{ext}"), "video".into()]
+                    .into_iter()
+                    .any(|source| {
+                        let fields = NameFields {
+                            stem: "video",
+                            codec: "av1",
+                            ext,
+                            crf: 0,
+                            height: 0,
+                            date: Date::default(),
+                        };
+                        self.render_raw(&fields) == source
+                    })
+            })
+    }
+}
+
+/// Replaces the characters that can't be in a file name: path separators and
+/// NUL everywhere, and on Windows also `<>:"|?*`, control characters and
+/// trailing dots and spaces. Names that are only dots become underscores.
+fn sanitize(name: &str, windows: bool) -> String {
+    let invalid = |c: char| {
+        matches!(c, '/' | '\0')
+            || (windows
+                && (matches!(c, '\\' | '<' | '>' | ':' | '"' | '|' | '?' | '*') || c.is_control()))
+    };
+    let mut name: String = name
+        .chars()
+        .map(|c| if invalid(c) { '_' } else { c })
+        .collect();
+    if windows {
+        let kept = name.trim_end_matches(['.', ' ']).len();
+        let trimmed = name.len() - kept;
+        name.truncate(kept);
+        name.extend(std::iter::repeat_n('_', trimmed));
+    }
+    if name.is_empty() || name.chars().all(|c| c == '.') {
+        name = name.replace('.', "_");
+        if name.is_empty() {
+            name.push('_');
+        }
+    }
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(stem: &str) -> NameFields<'_> {
+        NameFields {
+            stem,
+            codec: "av1",
+            ext: "mp4",
+            crf: 24,
+            height: 1080,
+            date: Date::constant(2026, 10, 16),
+        }
+    }
+
+    fn render(template: &str, stem: &str) -> String {
+        template
+            .parse::<OutputTemplate>()
+            .unwrap()
+            .render(&fields(stem))
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!("movie_av1.mp4", render(DEFAULT_TEMPLATE, "movie"));
+        assert_eq!(
+            OutputTemplate::default().render(&fields("movie")),
+            render(DEFAULT_TEMPLATE, "movie")
+        );
+        assert_eq!(
+            "movie [AV1 CRF24].mp4",
+            render("{stem} [AV1 CRF{crf}].{ext}", "movie")
+        );
+        assert_eq!(
+            "2026-10-16 movie 1080p av1.mp4",
+            render("{date} {stem} {height}p {codec}.{ext}", "movie")
+        );
+        assert_eq!(
+            "movie_hevc.mp4",
+            OutputTemplate::default().render(&NameFields {
+                codec: "hevc",
+                ..fields("movie")
+            })
+        );
+        assert_eq!(
+            "movie_vp9.webm",
+            OutputTemplate::default().render(&NameFields {
+                codec: "vp9",
+                ext: "webm",
+                ..fields("movie")
+            })
+        );
+        assert_eq!("{movie}.mp4", render("{{{stem}}}.{ext}", "movie"));
+        assert_eq!("movie", render("{stem}", "movie"));
+        // placeholders may follow each other and repeat
+        assert_eq!("moviemovie24", render("{stem}{stem}{crf}", "movie"));
+        assert_eq!(
+            "{stem} [AV1 CRF{crf}].{ext}",
+            "{stem} [AV1 CRF{crf}].{ext}"
+                .parse::<OutputTemplate>()
+                .unwrap()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn test_invalid_templates() {
+        for (template, error) in [
+            ("", "doesn't contain {stem}"),
+            ("   ", "doesn't contain {stem}"),
+            ("{crf}{height}.{ext}", "doesn't contain {stem}"),
+            ("{ext}", "doesn't contain {stem}"),
+            ("..", "doesn't contain {stem}"),
+            ("{{stem}}.mp4", "doesn't contain {stem}"),
+            ("{stem", "unclosed placeholder"),
+            ("{stem}.{ext", "unclosed placeholder"),
+            ("{", "unclosed placeholder"),
+            ("{stem}}", "unmatched }"),
+            ("}{stem}", "unmatched }"),
+            ("{Stem}.mp4", "unknown placeholder {Stem}"),
+            ("{}{stem}", "unknown placeholder {}"),
+            ("{stem }", "unknown placeholder {stem }"),
+            ("{stem}{resolution}", "unknown placeholder {resolution}"),
+            ("../{stem}.{ext}", "path separator"),
+            ("{stem}/x.mp4", "path separator"),
+            ("/etc/{stem}", "path separator"),
+            ("..\\{stem}.{ext}", "path separator"),
+        ] {
+            let parsed = template.parse::<OutputTemplate>();
+            let message = parsed.expect_err(template);
+            assert!(message.contains(error), "{template}: {message}");
+        }
+    }
+
+    #[test]
+    fn test_may_overwrite_source() {
+        for template in [
+            "{stem}.{ext}",
+            "{stem}.mp4",
+            "{stem}.webm",
+            "{stem}",
+            "{stem}.{{ext}}.mp4",
+        ] {
+            let template: OutputTemplate = template.parse().unwrap();
+            let expected = !template.to_string().contains("{{");
+            assert_eq!(expected, template.may_overwrite_source(), "{template}");
+        }
+        for template in [
+            DEFAULT_TEMPLATE,
+            "{stem} [AV1 CRF{crf}].{ext}",
+            "{stem}.mkv",
+            "{stem}{crf}.{ext}",
+            "{stem}.{ext}.{ext}",
+        ] {
+            let template: OutputTemplate = template.parse().unwrap();
+            assert!(!template.may_overwrite_source(), "{template}");
+        }
+    }
+
+    #[test]
+    fn test_sanitize() {
+        assert_eq!("movie_av1.mp4", sanitize("movie_av1.mp4", false));
+        assert_eq!("a_b.mp4", sanitize("a/b.mp4", false));
+        assert_eq!("a_b.mp4", sanitize("a\0b.mp4", false));
+        // fine on Unix
+        assert_eq!(
+            "Q: what? <1> \"x\" a|b*.mp4",
+            sanitize("Q: what? <1> \"x\" a|b*.mp4", false)
+        );
+        assert_eq!(
+            "Q_ what_ _1_ _x_ a_b_.mp4",
+            sanitize("Q: what? <1> \"x\" a|b*.mp4", true)
+        );
+        assert_eq!("a_b_c", sanitize("a\\b\tc", true));
+        assert_eq!("movie__", sanitize("movie. ", true));
+        assert_eq!("movie. ", sanitize("movie. ", false));
+        assert_eq!("__", sanitize("..", false));
+        assert_eq!("_", sanitize(".", true));
+        assert_eq!("_", sanitize("", false));
+    }
+
+    #[test]
+    fn test_placeholder_values_are_sanitized() {
+        let template: OutputTemplate = "{stem}.{ext}".parse().unwrap();
+        // a stem can't contain a separator, but the name must not escape the directory either way
+        let name = template.render(&fields("../../etc/passwd"));
+        assert!(!name.contains('/'), "{name}");
+    }
+}
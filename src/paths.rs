@@ -0,0 +1,1144 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+
+use camino::{Utf8Component, Utf8Path, Utf8PathBuf};
+use clap::ValueEnum;
+use jiff::civil::Date;
+use tracing::{debug, info};
+use unicode_normalization::{UnicodeNormalization, is_nfc};
+
+use crate::Result;
+use crate::device::{Container, VideoCodec};
+use crate::filesystem;
+use crate::output_template::{NameFields, OutputTemplate};
+
+/// Where transcoded files and temporary files are written.
+#[derive(Debug, Clone, Default)]
+pub struct OutputPaths {
+    /// Write outputs to this directory instead of next to the source.
+    pub output_dir: Option<Utf8PathBuf>,
+    /// Write temporary files to this directory instead of the output directory.
+    pub tmp_dir: Option<Utf8PathBuf>,
+    /// Source directories that can't be written to. Outputs for files in these
+    /// directories fall back to the temp directory.
+    pub unwritable: HashSet<Utf8PathBuf>,
+    /// Directories earlier runs wrote outputs to, which are looked at for
+    /// existing outputs as well.
+    pub previous_output_dirs: Vec<Utf8PathBuf>,
+    /// The names of the outputs.
+    pub template: OutputTemplate,
+    /// The day `{date}` in the template stands for.
+    pub date: Date,
+    /// The CRF `{crf}` stands for where the settings of a file aren't resolved
+    /// yet, like when selecting files.
+    pub default_crf: u8,
+    /// The codec that is encoded to, which `{codec}` stands for by default.
+    pub codec: VideoCodec,
+    /// Other codecs that existing outputs may have, e.g. the ones a `--device`
+    /// plays. Only `codec` when empty.
+    pub accepted_codecs: Vec<VideoCodec>,
+}
+
+/// What the name of a file's output depends on besides the source path.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct OutputFields {
+    /// `None` for the default CRF of the run.
+    pub crf: Option<u8>,
+    /// `None` for the codec of the run. Remuxed files keep their codec.
+    pub codec: Option<VideoCodec>,
+    /// The height of the source.
+    pub height: u32,
+}
+
+/// Where an existing output of a file was found.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputLocation {
+    /// Where this run would write it.
+    Current,
+    /// The output path recorded with the file.
+    Recorded,
+    /// Next to the source, where runs without `--output-dir` write.
+    Sibling,
+    PreviousOutputDir,
+    /// The temp directory, where outputs for read-only source directories go.
+    TmpDir,
+}
+
+impl fmt::Display for OutputLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputLocation::Current => write!(f, "where it would be written"),
+            OutputLocation::Recorded => write!(f, "at the recorded output path"),
+            OutputLocation::Sibling => write!(f, "next to the source"),
+            OutputLocation::PreviousOutputDir => write!(f, "in a previous output directory"),
+            OutputLocation::TmpDir => write!(f, "in the temp directory"),
+        }
+    }
+}
+
+const TMP_PREFIX: &str = ".transcoder-";
+/// Between the IDs and the container's extension in the name of a temp file.
+const TMP_INFIX: &str = ".tmp.";
+const RESUME_PREFIX: &str = ".transcoder-resume-";
+/// Between the IDs and the source's extension in the name of a staged copy.
+const STAGE_INFIX: &str = ".stage";
+
+/// A temporary file written by the transcoder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TempFile {
+    /// `.transcoder-<id>-<pid>.tmp.mp4` (`.tmp.webm` for VP9),
+    /// `.transcoder-<id>-<pid>.<rendition>.tmp.mp4` for an output of `--renditions`,
+    /// or `.transcoder-<id>-<pid>.stage.<ext>` for the copy of a source with
+    /// `--io-mode staged`.
+    Current { id: i64, pid: u32 },
+    /// `<stem>_tmp.mp4`, written by older versions. Only a leftover if a source
+    /// file with that stem exists, otherwise it's a user's file.
+    Legacy { stem: String },
+    /// `.transcoder-resume-<id>`, the directory with the segments of a
+    /// `--resumable` encode.
+    Resume { id: i64 },
+}
+
+impl TempFile {
+    pub fn parse(path: &Utf8Path) -> Option<TempFile> {
+        let name = path.file_name()?;
+        if let Some(id) = name.strip_prefix(RESUME_PREFIX) {
+            return Some(TempFile::Resume {
+                id: id.parse().ok()?,
+            });
+        }
+        if let Some(ids) = name.strip_prefix(TMP_PREFIX).and_then(|rest| {
+            rest.rsplit_once(TMP_INFIX)
+                .filter(|(_, extension)| {
+                    Container::ALL
+                        .iter()
+                        .any(|container| container.extension() == *extension)
+                })
+                .map(|(ids, _)| ids)
+                .or_else(|| Some(rest.split_once(STAGE_INFIX)?.0))
+        }) {
+            let (id, pid) = ids.split_once('-')?;
+            let pid = pid.split_once('.').map_or(pid, |(pid, _)| pid);
+            return Some(TempFile::Current {
+                id: id.parse().ok()?,
+                pid: pid.parse().ok()?,
+            });
+        }
+        name.strip_suffix("_tmp.mp4")
+            .filter(|stem| !stem.is_empty())
+            .map(|stem| TempFile::Legacy { stem: stem.into() })
+    }
+}
+
+/// Whether the path is a temporary file of the current naming scheme or a segment
+/// of a resumable encode, which scans skip.
+pub fn is_temp_file(path: &Utf8Path) -> bool {
+    matches!(TempFile::parse(path), Some(TempFile::Current { .. }))
+        || path
+            .parent()
+            .is_some_and(|parent| matches!(TempFile::parse(parent), Some(TempFile::Resume { .. })))
+}
+
+fn source_dir(source: &Utf8Path) -> &Utf8Path {
+    source.parent().unwrap_or(Utf8Path::new("."))
+}
+
+/// FNV-1a, which unlike the standard library's hasher gives the same value in
+/// every version, so that later runs come up with the same shortened names.
+fn stable_hash(text: &str) -> u64 {
+    text.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// The name `render` gives the stem if it's at most `limit` bytes long.
+/// Otherwise the stem is cut, at a character boundary, and gets a hash of the
+/// whole stem, so that files whose stems only differ at the end still get
+/// different names.
+fn fit_name(stem: &str, limit: usize, render: impl Fn(&str) -> String) -> String {
+    let name = render(stem);
+    if name.len() <= limit {
+        return name;
+    }
+    let hash = format!("~{:08x}", stable_hash(stem) as u32);
+    let mut kept = stem.len();
+    loop {
+        kept = stem.floor_char_boundary(kept.saturating_sub(1));
+        let name = render(&format!("{}{hash}", &stem[..kept]));
+        if name.len() <= limit || kept == 0 {
+            return name;
+        }
+    }
+}
+
+impl OutputPaths {
+    /// The name of a file's output as the template renders it.
+    pub fn full_output_name(&self, source: &Utf8Path, fields: OutputFields) -> String {
+        self.render_name(source.file_stem().expect("file must have a name"), fields)
+    }
+
+    fn render_name(&self, stem: &str, fields: OutputFields) -> String {
+        self.template.render(&NameFields {
+            stem,
+            codec: fields.codec.unwrap_or(self.codec).name(),
+            ext: self.container().extension(),
+            crf: fields.crf.unwrap_or(self.default_crf),
+            height: fields.height,
+            date: self.date,
+        })
+    }
+
+    /// The name of a file's output in `directory`, with the stem shortened if
+    /// the whole name is longer than the directory's filesystem takes.
+    fn output_name(&self, source: &Utf8Path, fields: OutputFields, directory: &Utf8Path) -> String {
+        let stem = source.file_stem().expect("file must have a name");
+        let name = self.render_name(stem, fields);
+        if name.len() <= filesystem::SAFE_NAME_LENGTH {
+            return name;
+        }
+        let limit =
+            filesystem::max_name_length(directory).unwrap_or(filesystem::DEFAULT_NAME_LENGTH);
+        fit_name(stem, limit, |stem| self.render_name(stem, fields))
+    }
+
+    /// The container of the run's outputs.
+    pub fn container(&self) -> Container {
+        self.codec.container()
+    }
+
+    /// Whether an existing output with video in this codec counts as done.
+    pub fn accepts_codec(&self, codec: &str) -> bool {
+        self.codec.name() == codec || self.accepted_codecs.iter().any(|c| c.name() == codec)
+    }
+
+    /// The path of the transcoded file when not replacing the original.
+    pub fn output(&self, source: &Utf8Path, fields: OutputFields) -> Utf8PathBuf {
+        let source_dir = source_dir(source);
+        let directory = match (&self.output_dir, &self.tmp_dir) {
+            (Some(output_dir), _) => output_dir,
+            (None, Some(tmp_dir)) if self.unwritable.contains(source_dir) => tmp_dir,
+            _ => source_dir,
+        };
+        directory.join(self.output_name(source, fields, directory))
+    }
+
+    /// Every place this or an earlier run may have written an output of the file
+    /// to, in the order they are searched, without duplicates.
+    pub fn output_candidates(
+        &self,
+        source: &Utf8Path,
+        fields: OutputFields,
+        recorded: Option<&Utf8Path>,
+    ) -> Vec<(Utf8PathBuf, OutputLocation)> {
+        let in_directory =
+            |directory: &Utf8Path| directory.join(self.output_name(source, fields, directory));
+        let mut candidates = vec![(self.output(source, fields), OutputLocation::Current)];
+        // a replaced source is no sign of an output elsewhere
+        if let Some(recorded) = recorded.filter(|&recorded| recorded != source) {
+            candidates.push((recorded.to_owned(), OutputLocation::Recorded));
+        }
+        candidates.push((in_directory(source_dir(source)), OutputLocation::Sibling));
+        for directory in &self.previous_output_dirs {
+            candidates.push((in_directory(directory), OutputLocation::PreviousOutputDir));
+        }
+        if let Some(tmp_dir) = &self.tmp_dir {
+            candidates.push((in_directory(tmp_dir), OutputLocation::TmpDir));
+        }
+        let mut seen = HashSet::new();
+        candidates.retain(|(path, _)| seen.insert(path.clone()));
+        candidates
+    }
+
+    /// The path ffmpeg writes to before the file is moved into place. It's hidden
+    /// and named after the file's id and this process, so it can't clash with
+    /// user files or with other files or workers writing to the same directory.
+    pub fn tmp(&self, source: &Utf8Path, id: i64) -> Utf8PathBuf {
+        self.tmp_for_process(source, id, std::process::id())
+    }
+
+    fn tmp_for_process(&self, source: &Utf8Path, id: i64, pid: u32) -> Utf8PathBuf {
+        let extension = self.container().extension();
+        self.tmp_directory(source)
+            .join(format!("{TMP_PREFIX}{id}-{pid}{TMP_INFIX}{extension}"))
+    }
+
+    /// The temp file of one rendition of a `--renditions` encode, see
+    /// [`OutputPaths::tmp`].
+    pub fn rendition_tmp(&self, source: &Utf8Path, id: i64, rendition: &str) -> Utf8PathBuf {
+        let pid = std::process::id();
+        let extension = self.container().extension();
+        self.tmp_directory(source).join(format!(
+            "{TMP_PREFIX}{id}-{pid}.{rendition}{TMP_INFIX}{extension}"
+        ))
+    }
+
+    /// Where the source is copied to with `--io-mode staged`, in the temp directory
+    /// and with the source's extension. `None` without a temp directory.
+    pub fn staged(&self, source: &Utf8Path, id: i64) -> Option<Utf8PathBuf> {
+        let pid = std::process::id();
+        let name = match source.extension() {
+            Some(extension) => format!("{TMP_PREFIX}{id}-{pid}{STAGE_INFIX}.{extension}"),
+            None => format!("{TMP_PREFIX}{id}-{pid}{STAGE_INFIX}"),
+        };
+        Some(self.tmp_dir.as_ref()?.join(name))
+    }
+
+    /// The directory a `--resumable` encode keeps its segments and state in, next to
+    /// where the temp file goes. Unlike the temp file it doesn't depend on the
+    /// process, so a later run finds it.
+    pub fn resume_dir(&self, source: &Utf8Path, id: i64) -> Utf8PathBuf {
+        self.tmp_directory(source)
+            .join(format!("{RESUME_PREFIX}{id}"))
+    }
+
+    fn tmp_directory<'a>(&'a self, source: &'a Utf8Path) -> &'a Utf8Path {
+        match (&self.tmp_dir, &self.output_dir) {
+            (Some(directory), _) | (None, Some(directory)) => directory,
+            (None, None) => source_dir(source),
+        }
+    }
+
+    pub fn is_source_dir_writable(&self, source: &Utf8Path) -> bool {
+        !self.unwritable.contains(source_dir(source))
+    }
+
+    /// Whether transcoding writes to the source directories.
+    pub fn writes_to_source_dir(&self, replace: bool) -> bool {
+        replace || self.output_dir.is_none()
+    }
+}
+
+/// Checks whether files can be created in a directory by creating and removing a probe file.
+pub fn is_writable(directory: &Utf8Path) -> bool {
+    let probe = directory.join(format!(".transcoder-write-test-{}", std::process::id()));
+    match OpenOptions::new().write(true).create_new(true).open(&probe) {
+        Ok(_) => {
+            let _ = fs::remove_file(&probe);
+            true
+        }
+        Err(e) => {
+            debug!("{directory} is not writable: {e}");
+            false
+        }
+    }
+}
+
+/// Returns the source directories of the files that can't be written to,
+/// checking every distinct directory only once.
+pub fn unwritable_directories<'a>(
+    sources: impl IntoIterator<Item = &'a Utf8Path>,
+    is_writable: impl Fn(&Utf8Path) -> bool,
+) -> HashSet<Utf8PathBuf> {
+    let mut checked = HashMap::new();
+    for source in sources {
+        let directory = source_dir(source);
+        if !checked.contains_key(directory) {
+            checked.insert(directory.to_owned(), is_writable(directory));
+        }
+    }
+    checked
+        .into_iter()
+        .filter(|(_, writable)| !writable)
+        .map(|(directory, _)| directory)
+        .collect()
+}
+
+/// Whether two paths name the same file.
+#[cfg(unix)]
+fn same_file(a: &fs::Metadata, b: &fs::Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    a.dev() == b.dev() && a.ino() == b.ino()
+}
+
+#[cfg(not(unix))]
+fn same_file(_a: &fs::Metadata, _b: &fs::Metadata) -> bool {
+    true
+}
+
+/// The path in Unicode normalization form C, the form the database stores. macOS
+/// returns decomposed (NFD) names that open the same file in either form. On
+/// filesystems that keep names as they were written, a decomposed name is kept
+/// if only that form exists, or if both forms exist as different files.
+pub fn normalize_unicode(path: &Utf8Path) -> Utf8PathBuf {
+    if is_nfc(path.as_str()) {
+        return path.to_owned();
+    }
+    let composed = Utf8PathBuf::from(path.as_str().nfc().collect::<String>());
+    match (fs::metadata(path), fs::metadata(&composed)) {
+        (Ok(original), Ok(normalized)) if !same_file(&original, &normalized) => path.to_owned(),
+        (Ok(_), Err(_)) => path.to_owned(),
+        _ => composed,
+    }
+}
+
+/// The absolute path, with symlinks resolved if it exists, to show where a
+/// relative path like `transcoder.db` points.
+pub fn absolute(path: &Utf8Path) -> Utf8PathBuf {
+    if let Ok(canonical) = path.canonicalize_utf8() {
+        return canonical;
+    }
+    match std::env::current_dir()
+        .ok()
+        .and_then(|dir| Utf8PathBuf::try_from(dir).ok())
+    {
+        Some(dir) => dir.join(path),
+        None => path.to_owned(),
+    }
+}
+
+/// Whether paths are case-insensitive unless `--case-insensitive-paths` says
+/// otherwise, on the platforms whose filesystems usually are.
+pub fn case_insensitive_by_default(os: &str) -> bool {
+    matches!(os, "macos" | "windows")
+}
+
+/// The names in the directories that [`canonical_case`] looked at, kept for a
+/// scan so that its files don't list the same directories again and again.
+/// Directories that can't be read are remembered as well.
+#[derive(Debug, Default)]
+pub struct DirListings {
+    listings: Mutex<HashMap<Utf8PathBuf, Option<Arc<[String]>>>>,
+}
+
+impl DirListings {
+    fn names(&self, directory: &Utf8Path) -> Option<Arc<[String]>> {
+        let mut listings = self.listings.lock().unwrap();
+        if let Some(names) = listings.get(directory) {
+            return names.clone();
+        }
+        let names: Option<Arc<[String]>> = directory.read_dir_utf8().ok().map(|entries| {
+            entries
+                .filter_map(|entry| Some(entry.ok()?.file_name().to_owned()))
+                .collect()
+        });
+        listings.insert(directory.to_owned(), names.clone());
+        names
+    }
+}
+
+/// The path with its components in the case they are stored in on disk, for
+/// filesystems where `/Movies/Film.mkv` and `/movies/film.mkv` are the same
+/// file. A component that is stored in the given case is kept as it is, so both
+/// files of a case-sensitive filesystem keep their paths. From the first
+/// component that doesn't exist, the path is kept as given, so that a file that
+/// was deleted is still found under the spelling it was stored with.
+pub fn canonical_case(path: &Utf8Path, listings: &DirListings) -> Utf8PathBuf {
+    let mut canonical = Utf8PathBuf::new();
+    let mut components = path.components();
+    while let Some(component) = components.next() {
+        let Utf8Component::Normal(name) = component else {
+            canonical.push(component);
+            continue;
+        };
+        let directory = match canonical.as_str() {
+            "" => Utf8Path::new("."),
+            _ => &canonical,
+        };
+        let names = listings.names(directory);
+        let stored = names.as_deref().and_then(|names| {
+            names.iter().find(|stored| *stored == name).or_else(|| {
+                let name = name.to_lowercase();
+                names.iter().find(|stored| stored.to_lowercase() == name)
+            })
+        });
+        match stored {
+            Some(stored) => canonical.push(stored),
+            None => {
+                canonical.push(name);
+                canonical.extend(components);
+                break;
+            }
+        }
+    }
+    canonical
+}
+
+/// The path in the form the database stores: normalized to NFC and, with
+/// `case_insensitive`, in the case it has on disk. Directories are listed
+/// again unless `listings` keeps them.
+pub fn normalize(
+    path: &Utf8Path,
+    case_insensitive: bool,
+    listings: Option<&DirListings>,
+) -> Utf8PathBuf {
+    let path = normalize_unicode(path);
+    match (case_insensitive, listings) {
+        (false, _) => path,
+        (true, Some(listings)) => canonical_case(&path, listings),
+        (true, None) => canonical_case(&path, &DirListings::default()),
+    }
+}
+
+/// What to do when the temp file and the output are on different filesystems,
+/// where they can't be renamed into place.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum CrossDevice {
+    /// Copy the finished file to the output's filesystem
+    #[default]
+    Copy,
+    /// Fail the file before encoding it
+    Error,
+}
+
+/// Moves a file, replacing `to` atomically if it exists. Falls back to copying
+/// when source and destination are on different filesystems.
+pub fn move_file(from: &Utf8Path, to: &Utf8Path) -> Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+            info!("{from} and {to} are on different filesystems, copying");
+            copy_into_place(from, to)
+        }
+        result => Ok(result?),
+    }
+}
+
+/// Where an output replaces its source with `--replace`: the source's own path,
+/// unless the output is WebM and the source isn't. Players go by the extension
+/// there, so the output is put next to the source with `.webm` instead, and the
+/// source is removed once the output is in place.
+pub fn replacement(source: &Utf8Path, output: &Utf8Path) -> Utf8PathBuf {
+    let webm = Container::WebM.extension();
+    let is_webm = |path: &Utf8Path| {
+        path.extension()
+            .is_some_and(|extension| extension.eq_ignore_ascii_case(webm))
+    };
+    if is_webm(output) && !is_webm(source) {
+        source.with_extension(webm)
+    } else {
+        source.to_owned()
+    }
+}
+
+/// Whether the recorded output of a source is what replaced it.
+pub fn replaced_source(source: &Utf8Path, output: &Utf8Path) -> bool {
+    output == source || output == replacement(source, output)
+}
+
+/// Copies a file to a hidden file next to `to`, flushes it to disk and renames it
+/// into place before removing `from`. `to` is never left half written, and on
+/// errors the copy is removed while `from` is kept.
+fn copy_into_place(from: &Utf8Path, to: &Utf8Path) -> Result<()> {
+    let directory = to.parent().unwrap_or(Utf8Path::new("."));
+    let copy = directory.join(format!(
+        "{TMP_PREFIX}copy-{}-{}",
+        std::process::id(),
+        to.file_name().unwrap_or_default()
+    ));
+    let copied = fs::copy(from, &copy)
+        .and_then(|_| fs::File::open(&copy)?.sync_all())
+        .and_then(|_| fs::rename(&copy, to));
+    if let Err(e) = copied {
+        let _ = fs::remove_file(&copy);
+        return Err(e.into());
+    }
+    fs::remove_file(from)?;
+    Ok(())
+}
+
+/// Writes `contents` to a hidden file next to `path` and renames it into place,
+/// so that readers see either the old or the new contents, never a part.
+pub fn write_atomically(path: &Utf8Path, contents: &[u8]) -> Result<()> {
+    let directory = path.parent().unwrap_or(Utf8Path::new("."));
+    let tmp = directory.join(format!(
+        "{TMP_PREFIX}write-{}-{}",
+        std::process::id(),
+        path.file_name().unwrap_or_default()
+    ));
+    let written = fs::write(&tmp, contents)
+        .and_then(|_| fs::File::open(&tmp)?.sync_all())
+        .and_then(|_| fs::rename(&tmp, path));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    /// "é" composed and as "e" with a combining accent.
+    const NFC_NAME: &str = "caf\u{e9}.mkv";
+    const NFD_NAME: &str = "cafe\u{301}.mkv";
+
+    #[test]
+    fn test_case_insensitive_by_default() {
+        assert!(case_insensitive_by_default("macos"));
+        assert!(case_insensitive_by_default("windows"));
+        assert!(!case_insensitive_by_default("linux"));
+        assert!(!case_insensitive_by_default("freebsd"));
+    }
+
+    #[test]
+    fn test_absolute() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = Utf8Path::from_path(dir.path())
+            .unwrap()
+            .canonicalize_utf8()?;
+        fs::write(dir.join("a.db"), b"")?;
+        fs::create_dir(dir.join("sub"))?;
+        assert_eq!(dir.join("a.db"), absolute(&dir.join("sub/../a.db")));
+        let cwd = Utf8PathBuf::try_from(std::env::current_dir()?)?;
+        assert_eq!(cwd.join("missing.db"), absolute("missing.db".into()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_canonical_case() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let root = Utf8Path::from_path(tempdir.path()).unwrap();
+        fs::create_dir(root.join("Movies"))?;
+        fs::write(root.join("Movies/Film.mkv"), b"")?;
+        let canonical_case = |path: &Utf8Path| canonical_case(path, &DirListings::default());
+
+        let film = root.join("Movies/Film.mkv");
+        assert_eq!(film, canonical_case(&root.join("movies/film.mkv")));
+        assert_eq!(film, canonical_case(&root.join("MOVIES/FILM.MKV")));
+        assert_eq!(film, canonical_case(&film));
+        // missing files keep their spelling from the first missing component
+        assert_eq!(
+            Utf8PathBuf::from("/Nonexistent/Movies/New.mkv"),
+            canonical_case("/Nonexistent/Movies/New.mkv".into())
+        );
+        assert_eq!(
+            root.join("Movies/NEW.mkv"),
+            canonical_case(&root.join("movies/NEW.mkv"))
+        );
+
+        assert_eq!(film, normalize(&root.join("movies/film.MKV"), true, None));
+        assert_eq!(
+            root.join("movies/film.MKV"),
+            normalize(&root.join("movies/film.MKV"), false, None)
+        );
+
+        // on a case-sensitive filesystem both spellings can be different files
+        fs::write(root.join("Movies/film.mkv"), b"")?;
+        if fs::read_dir(root.join("Movies"))?.count() == 2 {
+            assert_eq!(
+                root.join("Movies/film.mkv"),
+                canonical_case(&root.join("movies/film.mkv"))
+            );
+            assert_eq!(film, canonical_case(&root.join("movies/Film.mkv")));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_canonical_case_of_deleted_file() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let root = Utf8Path::from_path(tempdir.path()).unwrap();
+        fs::create_dir(root.join("Movies"))?;
+        fs::write(root.join("Movies/Film.mkv"), b"")?;
+        let film = root.join("Movies/Film.mkv");
+        assert_eq!(
+            film,
+            canonical_case(&root.join("movies/film.mkv"), &DirListings::default())
+        );
+
+        // the stored spelling of a deleted file still finds it, and the
+        // directory that is left is in its case on disk
+        fs::remove_file(&film)?;
+        let listings = DirListings::default();
+        assert_eq!(film, canonical_case(&film, &listings));
+        assert_eq!(
+            film,
+            canonical_case(&root.join("movies/Film.mkv"), &listings)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_dir_listings_are_kept() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let root = Utf8Path::from_path(tempdir.path()).unwrap();
+        fs::create_dir(root.join("Movies"))?;
+        fs::write(root.join("Movies/Film.mkv"), b"")?;
+        let listings = DirListings::default();
+        let film = root.join("Movies/Film.mkv");
+        assert_eq!(
+            film,
+            canonical_case(&root.join("movies/film.mkv"), &listings)
+        );
+
+        // the listings are read once, so a directory that is gone in the
+        // meantime still resolves
+        fs::remove_file(&film)?;
+        fs::remove_dir(root.join("Movies"))?;
+        assert_eq!(
+            film,
+            canonical_case(&root.join("MOVIES/FILM.mkv"), &listings)
+        );
+        assert_eq!(
+            root.join("MOVIES/FILM.mkv"),
+            canonical_case(&root.join("MOVIES/FILM.mkv"), &DirListings::default())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalize_unicode() -> Result<()> {
+        assert_ne!(NFC_NAME, NFD_NAME);
+        let missing = Utf8Path::new("/nonexistent");
+        assert_eq!(
+            missing.join(NFC_NAME),
+            normalize_unicode(&missing.join(NFD_NAME))
+        );
+        assert_eq!(
+            missing.join(NFC_NAME),
+            normalize_unicode(&missing.join(NFC_NAME))
+        );
+
+        let tempdir = tempfile::tempdir()?;
+        let directory = Utf8Path::from_path(tempdir.path()).unwrap();
+        let decomposed = directory.join(NFD_NAME);
+        fs::write(&decomposed, b"video")?;
+        let composed = directory.join(NFC_NAME);
+        if composed.exists() {
+            // a normalization-insensitive filesystem, like on macOS
+            assert_eq!(composed, normalize_unicode(&decomposed));
+        } else {
+            // only the decomposed name opens the file
+            assert_eq!(decomposed, normalize_unicode(&decomposed));
+            // and two files whose names differ in normalization are different files
+            fs::write(&composed, b"other")?;
+            assert_eq!(decomposed, normalize_unicode(&decomposed));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_paths_next_to_source() {
+        let paths = OutputPaths::default();
+        assert_eq!(
+            "/movies/a_av1.mp4",
+            paths.output("/movies/a.mkv".into(), OutputFields::default())
+        );
+        assert_eq!(
+            "/movies/.transcoder-7-100.tmp.mp4",
+            paths.tmp_for_process("/movies/a.mkv".into(), 7, 100)
+        );
+        assert_eq!(
+            "/movies/.transcoder-resume-7",
+            paths.resume_dir("/movies/a.mkv".into(), 7)
+        );
+    }
+
+    #[test]
+    fn test_webm() {
+        let paths = OutputPaths {
+            codec: VideoCodec::Vp9,
+            ..Default::default()
+        };
+        assert_eq!(
+            "/movies/a_vp9.webm",
+            paths.output("/movies/a.mp4".into(), OutputFields::default())
+        );
+        let tmp = paths.tmp_for_process("/movies/a.mp4".into(), 7, 100);
+        assert_eq!("/movies/.transcoder-7-100.tmp.webm", tmp);
+        assert_eq!(
+            Some(TempFile::Current { id: 7, pid: 100 }),
+            TempFile::parse(&tmp)
+        );
+
+        // WebM replaces other sources under its own extension
+        assert_eq!("/movies/a.webm", replacement("/movies/a.mp4".into(), &tmp));
+        assert_eq!("/movies/a.WEBM", replacement("/movies/a.WEBM".into(), &tmp));
+        assert_eq!(
+            "/movies/a.mkv",
+            replacement(
+                "/movies/a.mkv".into(),
+                "/movies/.transcoder-7-100.tmp.mp4".into()
+            )
+        );
+        assert!(replaced_source(
+            "/movies/a.mp4".into(),
+            "/movies/a.webm".into()
+        ));
+        assert!(replaced_source(
+            "/movies/a.mp4".into(),
+            "/movies/a.mp4".into()
+        ));
+        assert!(!replaced_source(
+            "/movies/a.mp4".into(),
+            "/movies/a_vp9.webm".into()
+        ));
+    }
+
+    #[test]
+    fn test_paths_with_directories() {
+        let paths = OutputPaths {
+            output_dir: Some("/out".into()),
+            ..Default::default()
+        };
+        assert_eq!(
+            "/out/a_av1.mp4",
+            paths.output("/movies/a.mkv".into(), OutputFields::default())
+        );
+        assert_eq!(
+            "/out/.transcoder-7-100.tmp.mp4",
+            paths.tmp_for_process("/movies/a.mkv".into(), 7, 100)
+        );
+
+        let paths = OutputPaths {
+            tmp_dir: Some("/tmp".into()),
+            unwritable: HashSet::from(["/readonly".into()]),
+            ..Default::default()
+        };
+        assert_eq!(
+            "/movies/a_av1.mp4",
+            paths.output("/movies/a.mkv".into(), OutputFields::default())
+        );
+        assert_eq!(
+            "/tmp/a_av1.mp4",
+            paths.output("/readonly/a.mkv".into(), OutputFields::default())
+        );
+        assert_eq!(
+            "/tmp/.transcoder-7-100.tmp.mp4",
+            paths.tmp_for_process("/movies/a.mkv".into(), 7, 100)
+        );
+    }
+
+    #[test]
+    fn test_output_template() {
+        let paths = OutputPaths {
+            output_dir: Some("/mirror".into()),
+            previous_output_dirs: vec!["/old".into()],
+            template: "{stem} [AV1 CRF{crf}] {height}p {date}.{ext}"
+                .parse()
+                .unwrap(),
+            date: Date::constant(2026, 10, 16),
+            default_crf: 30,
+            ..Default::default()
+        };
+        let fields = OutputFields {
+            crf: Some(24),
+            height: 1080,
+            codec: None,
+        };
+        assert_eq!(
+            "/mirror/a [AV1 CRF24] 1080p 2026-10-16.mp4",
+            paths.output("/movies/a.mkv".into(), fields)
+        );
+        // the default CRF where the file's isn't known
+        assert_eq!(
+            "/mirror/a [AV1 CRF30] 1080p 2026-10-16.mp4",
+            paths.output(
+                "/movies/a.mkv".into(),
+                OutputFields {
+                    crf: None,
+                    ..fields
+                }
+            )
+        );
+        // earlier outputs are looked for under the same name
+        assert_eq!(
+            Utf8PathBuf::from("/old/a [AV1 CRF24] 1080p 2026-10-16.mp4"),
+            paths.output_candidates("/movies/a.mkv".into(), fields, None)[2].0
+        );
+
+        // remuxed files are named after their own codec
+        let paths = OutputPaths {
+            codec: VideoCodec::Hevc,
+            accepted_codecs: vec![VideoCodec::H264],
+            ..Default::default()
+        };
+        assert_eq!(
+            "/movies/a_hevc.mp4",
+            paths.output("/movies/a.mkv".into(), OutputFields::default())
+        );
+        assert_eq!(
+            "/movies/a_h264.mp4",
+            paths.output(
+                "/movies/a.mkv".into(),
+                OutputFields {
+                    codec: Some(VideoCodec::H264),
+                    ..fields
+                }
+            )
+        );
+        assert!(paths.accepts_codec("h264"));
+        assert!(!paths.accepts_codec("av1"));
+
+        let paths = OutputPaths {
+            output_dir: Some("/mirror".into()),
+            template: "{stem}.{ext}".parse().unwrap(),
+            ..Default::default()
+        };
+        assert_eq!(
+            "/mirror/a.mp4",
+            paths.output("/movies/a.mkv".into(), fields)
+        );
+    }
+
+    #[test]
+    fn test_output_candidates() {
+        let paths = OutputPaths {
+            output_dir: Some("/out".into()),
+            tmp_dir: Some("/tmp".into()),
+            previous_output_dirs: vec!["/old".into(), "/out".into()],
+            ..Default::default()
+        };
+        assert_eq!(
+            vec![
+                ("/out/a_av1.mp4".into(), OutputLocation::Current),
+                ("/archive/a.mp4".into(), OutputLocation::Recorded),
+                ("/movies/a_av1.mp4".into(), OutputLocation::Sibling),
+                ("/old/a_av1.mp4".into(), OutputLocation::PreviousOutputDir),
+                ("/tmp/a_av1.mp4".into(), OutputLocation::TmpDir),
+            ],
+            paths.output_candidates(
+                "/movies/a.mkv".into(),
+                OutputFields::default(),
+                Some("/archive/a.mp4".into())
+            )
+        );
+
+        // without --output-dir the sibling is the current location, and a
+        // replaced source doesn't count
+        let paths = OutputPaths::default();
+        assert_eq!(
+            vec![(
+                Utf8PathBuf::from("/movies/a_av1.mp4"),
+                OutputLocation::Current
+            )],
+            paths.output_candidates(
+                "/movies/a.mkv".into(),
+                OutputFields::default(),
+                Some("/movies/a.mkv".into())
+            )
+        );
+    }
+
+    #[test]
+    fn test_fit_name() {
+        let render = |stem: &str| format!("{stem}_av1.mp4");
+        assert_eq!("short_av1.mp4", fit_name("short", 255, render));
+
+        let long = "x".repeat(300);
+        let name = fit_name(&long, 255, render);
+        assert_eq!(255, name.len());
+        assert!(name.ends_with("_av1.mp4"), "{name}");
+        assert!(name.starts_with(&"x".repeat(200)), "{name}");
+        // the same stem always gets the same name, a different end another one
+        assert_eq!(name, fit_name(&long, 255, render));
+        assert_ne!(name, fit_name(&format!("{long}y"), 255, render));
+        // a name that fits exactly is left alone
+        let exact = "x".repeat(247);
+        assert_eq!(render(&exact), fit_name(&exact, 255, render));
+
+        // 3 bytes per character: 300 characters are 900 bytes
+        let japanese = "日本語".repeat(100);
+        let name = fit_name(&japanese, 255, render);
+        assert!(name.len() <= 255 && name.len() > 250, "{}", name.len());
+        assert!(name.starts_with("日本語日本語"), "{name}");
+        assert!(name.ends_with("_av1.mp4"), "{name}");
+        // characters of 4 bytes aren't cut in half either
+        let emoji = "🎬".repeat(100);
+        let name = fit_name(&emoji, 143, render);
+        assert!(name.len() <= 143, "{}", name.len());
+        assert!(name.starts_with("🎬"), "{name}");
+
+        // a template that doesn't fit at all keeps only the hash
+        let name = fit_name(&long, 10, |stem| format!("{stem}_{}", "y".repeat(20)));
+        assert!(name.starts_with('~'), "{name}");
+    }
+
+    #[test]
+    fn test_long_output_names() {
+        let stem = "A Very Long Title ".repeat(15);
+        let paths = OutputPaths {
+            output_dir: Some("/out".into()),
+            ..Default::default()
+        };
+        let source = Utf8PathBuf::from(format!("/movies/{stem}.mkv"));
+        let full = paths.full_output_name(&source, OutputFields::default());
+        assert!(full.len() > 255);
+        let output = paths.output(&source, OutputFields::default());
+        let name = output.file_name().unwrap();
+        assert!(name.len() <= filesystem::DEFAULT_NAME_LENGTH, "{name}");
+        assert!(name.ends_with("_av1.mp4"), "{name}");
+        assert_eq!(Utf8Path::new("/out"), output.parent().unwrap());
+        // existing outputs are looked for under the same name
+        let candidates = paths.output_candidates(&source, OutputFields::default(), None);
+        assert_eq!(output, candidates[0].0);
+        assert_eq!(name, candidates[1].0.file_name().unwrap());
+    }
+
+    #[test]
+    fn test_tmp_paths_dont_collide() {
+        let paths = OutputPaths {
+            tmp_dir: Some("/tmp".into()),
+            ..Default::default()
+        };
+        // same stem in different directories, and different extensions in one
+        let sources = [
+            (1, "/movies/a.mkv"),
+            (2, "/shows/a.mkv"),
+            (3, "/movies/a.avi"),
+        ];
+        let tmp_files: HashSet<_> = sources
+            .iter()
+            .map(|&(id, source)| paths.tmp(source.into(), id))
+            .collect();
+        assert_eq!(sources.len(), tmp_files.len());
+        // nor between workers transcoding the same file
+        assert_ne!(
+            paths.tmp_for_process("/movies/a.mkv".into(), 1, 100),
+            paths.tmp_for_process("/movies/a.mkv".into(), 1, 101)
+        );
+        // and a user's file named like the old temp files is left alone
+        assert!(!tmp_files.contains(Utf8Path::new("/tmp/a_tmp.mp4")));
+    }
+
+    #[test]
+    fn test_parse_temp_file() {
+        assert_eq!(
+            Some(TempFile::Current { id: 7, pid: 100 }),
+            TempFile::parse("/movies/.transcoder-7-100.tmp.mp4".into())
+        );
+        assert_eq!(
+            Some(TempFile::Legacy { stem: "a".into() }),
+            TempFile::parse("/movies/a_tmp.mp4".into())
+        );
+        assert_eq!(None, TempFile::parse("/movies/a.mp4".into()));
+        assert_eq!(None, TempFile::parse("/movies/_tmp.mp4".into()));
+        assert_eq!(
+            None,
+            TempFile::parse("/movies/.transcoder-x-1.tmp.mp4".into())
+        );
+        assert_eq!(
+            Some(TempFile::Resume { id: 7 }),
+            TempFile::parse("/movies/.transcoder-resume-7".into())
+        );
+        assert_eq!(
+            Some(TempFile::Current { id: 7, pid: 100 }),
+            TempFile::parse("/tmp/.transcoder-7-100.stage.mkv".into())
+        );
+        let paths = OutputPaths {
+            tmp_dir: Some("/tmp".into()),
+            ..Default::default()
+        };
+        let staged = paths.staged("/movies/a.m2ts".into(), 7).unwrap();
+        assert_eq!(Some("m2ts"), staged.extension());
+        assert!(matches!(
+            TempFile::parse(&staged),
+            Some(TempFile::Current { id: 7, .. })
+        ));
+        assert_eq!(
+            None,
+            OutputPaths::default().staged("/movies/a.mkv".into(), 7)
+        );
+        let rendition = paths.rendition_tmp("/movies/a.mkv".into(), 7, "share");
+        assert_ne!(paths.tmp("/movies/a.mkv".into(), 7), rendition);
+        assert_eq!(
+            Some(TempFile::Current {
+                id: 7,
+                pid: std::process::id()
+            }),
+            TempFile::parse(&rendition)
+        );
+        assert!(is_temp_file("/movies/.transcoder-7-100.tmp.mp4".into()));
+        assert!(is_temp_file(
+            "/movies/.transcoder-resume-7/part-0-00001.mkv".into()
+        ));
+        assert!(!is_temp_file("/movies/a_tmp.mp4".into()));
+    }
+
+    #[test]
+    fn test_directories_are_checked_once() {
+        let checked = RefCell::new(vec![]);
+        let sources = ["/a/1.mkv", "/a/2.mkv", "/b/1.mkv", "/a/3.mkv"];
+        let unwritable = unwritable_directories(sources.iter().map(Utf8Path::new), |dir| {
+            checked.borrow_mut().push(dir.to_owned());
+            dir != "/b"
+        });
+        assert_eq!(2, checked.borrow().len());
+        assert_eq!(HashSet::from([Utf8PathBuf::from("/b")]), unwritable);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_copy_into_place() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let tmp_dir = Utf8Path::from_path(tmp.path()).unwrap();
+        let output = tempfile::tempdir()?;
+        let output_dir = Utf8Path::from_path(output.path()).unwrap();
+        let from = tmp_dir.join(".transcoder-1-1.tmp.mp4");
+        let to = output_dir.join("a.mkv");
+        fs::write(&from, b"encoded")?;
+        fs::write(&to, b"original")?;
+
+        copy_into_place(&from, &to)?;
+        assert_eq!(b"encoded", fs::read(&to)?.as_slice());
+        assert!(!from.exists());
+        assert_eq!(1, fs::read_dir(output_dir)?.count());
+
+        // a failed copy leaves the destination and the source alone
+        fs::write(&from, b"encoded again")?;
+        let missing = output_dir.join("missing/a.mkv");
+        assert!(copy_into_place(&from, &missing).is_err());
+        assert!(from.is_file());
+        assert_eq!(1, fs::read_dir(output_dir)?.count());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_only_directory() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tempdir = tempfile::tempdir()?;
+        let directory = Utf8Path::from_path(tempdir.path()).unwrap();
+        assert!(is_writable(directory));
+
+        fs::set_permissions(directory, fs::Permissions::from_mode(0o555))?;
+        // root ignores directory permissions, in which case there's nothing to check
+        let ignores_permissions = fs::write(directory.join("check"), b"").is_ok();
+        if !ignores_permissions {
+            assert!(!is_writable(directory));
+        }
+        fs::set_permissions(directory, fs::Permissions::from_mode(0o755))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_atomically() -> Result<()> {
+        use std::sync::atomic::{AtomicBool, Ordering};
+
+        let tempdir = tempfile::tempdir()?;
+        let directory = Utf8Path::from_path(tempdir.path()).unwrap();
+        let path = directory.join("status.json");
+        let versions = [vec![b'a'; 256 * 1024], vec![b'b'; 64 * 1024]];
+        write_atomically(&path, &versions[0])?;
+
+        let done = AtomicBool::new(false);
+        std::thread::scope(|scope| -> Result<()> {
+            let reader = scope.spawn(|| {
+                while !done.load(Ordering::SeqCst) {
+                    let contents = fs::read(&path).unwrap();
+                    assert!(
+                        versions.contains(&contents),
+                        "read {} bytes",
+                        contents.len()
+                    );
+                }
+            });
+            for index in 0..200 {
+                write_atomically(&path, &versions[index % 2])?;
+            }
+            done.store(true, Ordering::SeqCst);
+            reader.join().unwrap();
+            Ok(())
+        })?;
+        // the temp files are gone
+        assert_eq!(1, fs::read_dir(directory)?.count());
+        Ok(())
+    }
+}
@@ -0,0 +1,426 @@
+//! Plans written by `queue --plan`: the files of a run in order with the options
+//! each one is encoded with. They can be reviewed and committed, and
+//! `transcode --plan` runs them as they are, however the queue has changed since.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::{bail, eyre};
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::Result;
+use crate::codecs::CodecRules;
+use crate::collect::VideoFile;
+use crate::database::Database;
+use crate::paths::{self, OutputPaths};
+use crate::preflight;
+use crate::resolved_options::ResolvedOptions;
+use crate::selection::{self, Selection, SkipReason, SkippedFile};
+use crate::transcode::GpuMode;
+
+/// The version of the plan format. Plans of other versions aren't run.
+pub const PLAN_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Plan {
+    pub version: u32,
+    pub created_at: Timestamp,
+    /// In the order they are transcoded.
+    pub entries: Vec<PlanEntry>,
+}
+
+/// A file of a plan, with its size and modification time when it was planned.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PlanEntry {
+    pub path: Utf8PathBuf,
+    pub file_size: u64,
+    pub modified: Timestamp,
+    pub options: ResolvedOptions,
+}
+
+/// How a planned file changed before the plan was run.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Drift {
+    Missing,
+    Size {
+        planned: u64,
+        current: u64,
+    },
+    Modified {
+        planned: Timestamp,
+        current: Timestamp,
+    },
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Drift::Missing => write!(f, "it is missing"),
+            Drift::Size { planned, current } => write!(
+                f,
+                "its size changed from {planned} to {current} bytes since it was planned"
+            ),
+            Drift::Modified { planned, current } => write!(
+                f,
+                "it was modified at {current}, after it was planned at {planned}"
+            ),
+        }
+    }
+}
+
+impl PlanEntry {
+    /// The entry for a file as it is on disk now.
+    pub fn new(path: &Utf8Path, options: ResolvedOptions) -> Result<Self> {
+        let metadata = fs::metadata(path)?;
+        Ok(PlanEntry {
+            path: path.to_owned(),
+            file_size: metadata.len(),
+            modified: Timestamp::try_from(metadata.modified()?)?,
+            options,
+        })
+    }
+
+    /// How the file changed since it was planned, if it did.
+    pub fn drift(&self) -> Option<Drift> {
+        let Ok(metadata) = fs::metadata(&self.path) else {
+            return Some(Drift::Missing);
+        };
+        if metadata.len() != self.file_size {
+            return Some(Drift::Size {
+                planned: self.file_size,
+                current: metadata.len(),
+            });
+        }
+        let current = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| Timestamp::try_from(modified).ok());
+        match current {
+            Some(current) if current == self.modified => None,
+            Some(current) => Some(Drift::Modified {
+                planned: self.modified,
+                current,
+            }),
+            None => Some(Drift::Missing),
+        }
+    }
+}
+
+impl Plan {
+    pub fn new(entries: Vec<PlanEntry>) -> Self {
+        Plan {
+            version: PLAN_VERSION,
+            created_at: Timestamp::now(),
+            entries,
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Parses a plan, checking its version before anything else so that plans
+    /// of other versions give a clear error instead of a missing field.
+    pub fn from_json(json: &str) -> Result<Self> {
+        #[derive(Deserialize)]
+        struct Header {
+            version: Option<u32>,
+        }
+
+        let header: Header = serde_json::from_str(json)?;
+        match header.version {
+            Some(PLAN_VERSION) => Ok(serde_json::from_str(json)?),
+            Some(version) => bail!(
+                "the plan has version {version}, this transcoder only runs plans of version \
+                 {PLAN_VERSION}. Write it again with `queue --plan`"
+            ),
+            None => bail!("the plan has no version, it wasn't written by `queue --plan`"),
+        }
+    }
+
+    pub fn write(&self, path: &Utf8Path) -> Result<()> {
+        paths::write_atomically(path, self.to_json()?.as_bytes())
+    }
+
+    pub fn read(path: &Utf8Path) -> Result<Self> {
+        let json = fs::read_to_string(path).map_err(|e| eyre!("could not read {path}: {e}"))?;
+        Plan::from_json(&json).map_err(|e| eyre!("{path} is not a valid plan: {e}"))
+    }
+
+    /// The options of the first entry, whose constraints and audio options are
+    /// used for the whole run. They apply to all files of a run, so the entries
+    /// have to agree on them.
+    pub fn shared_options(&self) -> Result<Option<&ResolvedOptions>> {
+        let Some(first) = self.entries.first().map(|entry| &entry.options) else {
+            return Ok(None);
+        };
+        if let Some(entry) = self.entries.iter().find(|entry| {
+            entry.options.constraints != first.constraints || entry.options.audio != first.audio
+        }) {
+            bail!(
+                "{} has other constraints or audio options than the first file of the plan, \
+                 they apply to the whole run. Split the plan into several",
+                entry.path
+            );
+        }
+        Ok(Some(first))
+    }
+
+    /// The GPU encoder, if all entries use the same one.
+    pub fn gpu(&self) -> Option<GpuMode> {
+        let mut encoders = self.entries.iter().map(|entry| entry.options.gpu());
+        let first = encoders.next()??;
+        encoders
+            .all(|gpu| gpu.as_ref() == Some(&first))
+            .then_some(first)
+    }
+
+    /// The files of the plan in its order, with the options planned for them by
+    /// file ID. Files that changed since they were planned are skipped, as are
+    /// the ones a run would skip anyway, like those that were transcoded in the
+    /// meantime. The codec rules were applied when the plan was written.
+    pub fn select(
+        &self,
+        database: &Database,
+        paths: &OutputPaths,
+        force: bool,
+    ) -> Result<(Selection, HashMap<i64, ResolvedOptions>)> {
+        let mut planned = HashMap::new();
+        let mut candidates = vec![];
+        let mut skipped = vec![];
+        for entry in &self.entries {
+            if let Some(drift) = entry.drift() {
+                warn!("Skipping {}: {drift}", entry.path);
+                skipped.push(SkippedFile {
+                    path: entry.path.clone(),
+                    reason: match drift {
+                        Drift::Missing => SkipReason::Missing,
+                        _ => SkipReason::ChangedSincePlan,
+                    },
+                });
+                continue;
+            }
+            let Some(file) = database.get_by_path(&entry.path)? else {
+                warn!("Skipping {}: it is not in the database", entry.path);
+                skipped.push(SkippedFile {
+                    path: entry.path.clone(),
+                    reason: SkipReason::Missing,
+                });
+                continue;
+            };
+            // a file that is in the plan twice is transcoded once
+            if planned.insert(file.id, entry.options.clone()).is_none() {
+                candidates.push(VideoFile::from(file));
+            }
+        }
+        let mut selection = selection::select(
+            candidates,
+            None,
+            paths,
+            force,
+            &CodecRules::none(),
+            |path| path.is_file(),
+            preflight::inspect_output,
+        );
+        selection.skipped.extend(skipped);
+        Ok((selection, planned))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::time::{Duration, SystemTime};
+
+    use super::*;
+    use crate::audio::AudioOptions;
+    use crate::config::EncodeSettings;
+    use crate::constraints::Constraints;
+    use crate::database::NewTranscodeFile;
+    use crate::ffprobe::FfProbe;
+
+    fn options(crf: u8, gpu: Option<&GpuMode>) -> ResolvedOptions {
+        ResolvedOptions::new(
+            gpu,
+            &EncodeSettings {
+                crf,
+                speed: "4".parse().unwrap(),
+                effort: None,
+                film_grain: Some(8),
+                ten_bit: true,
+                max_fps: None,
+                reproducible: false,
+                low_memory: None,
+            },
+            &Constraints::default(),
+            &AudioOptions {
+                reencode_above: None,
+                codec: "aac".into(),
+                bitrate: 160_000,
+                drop: false,
+                drop_commentary: false,
+                allowed_codecs: vec![],
+            },
+            &[],
+        )
+    }
+
+    /// Movies in a temp directory, in the database in the order given.
+    fn setup(directory: &Utf8Path, names: &[&str]) -> Result<Database> {
+        let db = Database::in_memory()?;
+        for name in names {
+            let path = directory.join(name);
+            fs::write(&path, name.as_bytes())?;
+            db.insert_batch(&[NewTranscodeFile {
+                path,
+                file_size: name.len() as u64,
+                ffprobe_info: FfProbe::default(),
+            }])?;
+        }
+        Ok(db)
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        setup(dir, &["a.mkv", "b.mkv"])?;
+        let plan = Plan::new(vec![
+            PlanEntry::new(&dir.join("b.mkv"), options(30, Some(&GpuMode::Nvidia)))?,
+            PlanEntry::new(&dir.join("a.mkv"), options(24, None))?,
+        ]);
+        assert_eq!(PLAN_VERSION, plan.version);
+        assert_eq!("b.mkv".len() as u64, plan.entries[0].file_size);
+        assert_eq!(plan, Plan::from_json(&plan.to_json()?)?);
+
+        let path = dir.join("plan.json");
+        plan.write(&path)?;
+        assert_eq!(plan, Plan::read(&path)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_versions() -> Result<()> {
+        let plan = Plan::new(vec![]);
+        let mut json: serde_json::Value = serde_json::from_str(&plan.to_json()?)?;
+        json["version"] = 2.into();
+        let error = Plan::from_json(&json.to_string()).unwrap_err().to_string();
+        assert!(error.contains("version 2"), "{error}");
+
+        json.as_object_mut().unwrap().remove("version");
+        let error = Plan::from_json(&json.to_string()).unwrap_err().to_string();
+        assert!(error.contains("no version"), "{error}");
+
+        // fields of newer versions in the options are ignored like in the database
+        let plan = Plan::new(vec![PlanEntry {
+            path: "/movies/a.mkv".into(),
+            file_size: 1,
+            modified: Timestamp::UNIX_EPOCH,
+            options: options(30, None),
+        }]);
+        let mut json: serde_json::Value = serde_json::from_str(&plan.to_json()?)?;
+        json["entries"][0]["options"]["unknown"] = true.into();
+        assert_eq!(plan, Plan::from_json(&json.to_string())?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_drift() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        let path = dir.join("movie.mkv");
+        fs::write(&path, b"planned")?;
+        let entry = PlanEntry::new(&path, options(30, None))?;
+        assert_eq!(None, entry.drift());
+
+        // rewritten with the same size, e.g. by a tagger
+        let modified = SystemTime::now() + Duration::from_secs(60);
+        File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(modified)?;
+        assert_eq!(
+            Some(Drift::Modified {
+                planned: entry.modified,
+                current: Timestamp::try_from(modified)?,
+            }),
+            entry.drift()
+        );
+
+        fs::write(&path, b"replaced by a new release")?;
+        assert_eq!(
+            Some(Drift::Size {
+                planned: 7,
+                current: 25
+            }),
+            entry.drift()
+        );
+
+        fs::remove_file(&path)?;
+        assert_eq!(Some(Drift::Missing), entry.drift());
+        Ok(())
+    }
+
+    #[test]
+    fn test_select() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        let db = setup(dir, &["a.mkv", "b.mkv", "c.mkv", "d.mkv"])?;
+        // planned in the opposite order of the database
+        let plan = Plan::new(
+            ["d.mkv", "c.mkv", "b.mkv", "a.mkv", "d.mkv"]
+                .into_iter()
+                .map(|name| PlanEntry::new(&dir.join(name), options(name.len() as u8, None)))
+                .collect::<Result<_>>()?,
+        );
+        // c was modified and a deleted before the plan runs
+        fs::write(dir.join("c.mkv"), b"a new release of c")?;
+        fs::remove_file(dir.join("a.mkv"))?;
+
+        let (selection, planned) = plan.select(&db, &OutputPaths::default(), false)?;
+        let files: Vec<_> = selection.files.iter().map(|f| f.path.clone()).collect();
+        assert_eq!(vec![dir.join("d.mkv"), dir.join("b.mkv")], files);
+        let skipped: Vec<_> = selection
+            .skipped
+            .iter()
+            .map(|s| (s.path.file_name().unwrap(), s.reason))
+            .collect();
+        assert_eq!(
+            vec![
+                ("c.mkv", SkipReason::ChangedSincePlan),
+                ("a.mkv", SkipReason::Missing)
+            ],
+            skipped
+        );
+        assert_eq!(2, planned.len());
+        let d = db.get_by_path(&dir.join("d.mkv"))?.unwrap();
+        assert_eq!(plan.entries[0].options, planned[&d.id]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_shared_options() -> Result<()> {
+        let entry = |gpu: Option<&GpuMode>| PlanEntry {
+            path: "/movies/a.mkv".into(),
+            file_size: 1,
+            modified: Timestamp::UNIX_EPOCH,
+            options: options(30, gpu),
+        };
+        let mut plan = Plan::new(vec![entry(Some(&GpuMode::Nvidia)), entry(None)]);
+        assert_eq!(Some(&plan.entries[0].options), plan.shared_options()?);
+        assert_eq!(None, plan.gpu());
+        plan.entries[1] = entry(Some(&GpuMode::Nvidia));
+        assert_eq!(Some(GpuMode::Nvidia), plan.gpu());
+
+        plan.entries[1].options.audio.drop = true;
+        assert!(plan.shared_options().is_err());
+        assert_eq!(None, Plan::new(vec![]).shared_options()?);
+        Ok(())
+    }
+}
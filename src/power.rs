@@ -0,0 +1,149 @@
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant, SystemTime};
+
+use tracing::{debug, warn};
+
+/// Gaps between the wall clock and the monotonic clock above this are treated as
+/// the system having been suspended.
+const SUSPEND_THRESHOLD: Duration = Duration::from_secs(30);
+
+/// The helper program that holds the inhibitor for as long as it runs. It exits on
+/// its own when the process with `pid` does, so a crash never leaves it behind.
+#[cfg(target_os = "linux")]
+fn inhibit_command(reason: &str, pid: u32) -> Option<(&'static str, Vec<String>)> {
+    Some((
+        "systemd-inhibit",
+        vec![
+            "--what=idle:sleep".into(),
+            "--who=transcoder".into(),
+            format!("--why={reason}"),
+            "--mode=block".into(),
+            "tail".into(),
+            format!("--pid={pid}"),
+            "-f".into(),
+            "/dev/null".into(),
+        ],
+    ))
+}
+
+#[cfg(target_os = "macos")]
+fn inhibit_command(_reason: &str, pid: u32) -> Option<(&'static str, Vec<String>)> {
+    Some((
+        "caffeinate",
+        vec!["-i".into(), "-w".into(), pid.to_string()],
+    ))
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn inhibit_command(_reason: &str, _pid: u32) -> Option<(&'static str, Vec<String>)> {
+    None
+}
+
+/// Keeps the system from going to sleep while it's alive.
+pub struct SleepInhibitor {
+    child: Child,
+}
+
+impl SleepInhibitor {
+    /// Takes an idle and sleep inhibitor. Returns `None` with a warning when the
+    /// platform has none or the helper can't be started.
+    pub fn acquire(reason: &str) -> Option<SleepInhibitor> {
+        let (program, args) = inhibit_command(reason, std::process::id())?;
+        match Command::new(program)
+            .args(&args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            Ok(child) => {
+                debug!("holding a sleep inhibitor: {reason}");
+                Some(SleepInhibitor { child })
+            }
+            Err(e) => {
+                warn!("Could not start {program} to keep the system awake: {e}");
+                None
+            }
+        }
+    }
+}
+
+impl Drop for SleepInhibitor {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// How much longer the wall clock advanced than the monotonic clock, which doesn't
+/// count time spent suspended, if it's enough to be a suspend.
+fn suspended_time(wall: Duration, monotonic: Duration) -> Option<Duration> {
+    wall.checked_sub(monotonic)
+        .filter(|&gap| gap >= SUSPEND_THRESHOLD)
+}
+
+/// Measures the time spent encoding, leaving out time the system was suspended.
+pub struct EncodeClock {
+    wall: SystemTime,
+    last_monotonic: Instant,
+    last_wall: SystemTime,
+    suspended: Duration,
+}
+
+impl EncodeClock {
+    pub fn start() -> Self {
+        let (monotonic, wall) = (Instant::now(), SystemTime::now());
+        EncodeClock {
+            wall,
+            last_monotonic: monotonic,
+            last_wall: wall,
+            suspended: Duration::ZERO,
+        }
+    }
+
+    /// Compares both clocks since the last call and returns the time the system
+    /// was suspended in between, if it was.
+    pub fn tick(&mut self) -> Option<Duration> {
+        let (monotonic, wall) = (Instant::now(), SystemTime::now());
+        let wall_delta = wall.duration_since(self.last_wall).unwrap_or_default();
+        let gap = suspended_time(wall_delta, monotonic - self.last_monotonic);
+        self.last_monotonic = monotonic;
+        self.last_wall = wall;
+        if let Some(gap) = gap {
+            self.suspended += gap;
+        }
+        gap
+    }
+
+    /// Time since the start without the suspended periods.
+    pub fn active(&self) -> Duration {
+        self.wall
+            .elapsed()
+            .unwrap_or_default()
+            .saturating_sub(self.suspended)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suspended_time() {
+        let secs = Duration::from_secs;
+        assert_eq!(None, suspended_time(secs(1), secs(1)));
+        assert_eq!(None, suspended_time(secs(10), secs(1)));
+        assert_eq!(Some(secs(3600)), suspended_time(secs(3601), secs(1)));
+        // the wall clock going backwards isn't a suspend
+        assert_eq!(None, suspended_time(secs(0), secs(5)));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_inhibit_command() {
+        let (program, args) = inhibit_command("Transcoding a.mkv", 42).unwrap();
+        assert_eq!("systemd-inhibit", program);
+        assert!(args.contains(&"--why=Transcoding a.mkv".to_string()));
+        assert!(args.contains(&"--pid=42".to_string()));
+    }
+}
@@ -0,0 +1,988 @@
+use std::fmt;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use human_repr::HumanCount;
+use tracing::debug;
+
+use crate::audio::{self, AudioDecision, AudioTrack};
+use crate::collect::{self, VideoFile};
+use crate::config::EncodeSettings;
+use crate::ffprobe::ffprobe;
+use crate::filesystem;
+use crate::paths::{self, CrossDevice, OutputFields, OutputLocation, OutputPaths};
+use crate::selection::SkipReason;
+use crate::transcode::{self, GpuMode, TranscodeOptions};
+
+/// Audio codecs the mp4 muxer refuses or only writes in experimental mode.
+const MP4_INCOMPATIBLE_AUDIO: &[&str] = &["wma", "pcm_", "adpcm_", "cook", "vorbis", "truehd"];
+
+/// Pixel formats all supported encoders take as they are. Anything else is converted
+/// by ffmpeg, which usually means dropping chroma resolution.
+const ENCODER_PIX_FMTS: &[&str] = &["yuv420p", "yuvj420p", "yuv420p10le", "nv12", "p010le"];
+
+/// A problem the transcoder expects for a file before running ffmpeg.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Finding {
+    Missing,
+    /// A directory above the file contains the ignore file.
+    Ignored {
+        directory: Utf8PathBuf,
+        ignore_file: String,
+    },
+    OutputExists {
+        path: Utf8PathBuf,
+        location: OutputLocation,
+    },
+    /// The source directory is read-only. With a fallback, the output goes there instead.
+    ReadOnlyDirectory {
+        directory: Utf8PathBuf,
+        fallback: Option<Utf8PathBuf>,
+    },
+    FileTooLarge {
+        directory: Utf8PathBuf,
+        fs_type: &'static str,
+        limit: u64,
+    },
+    LowDiskSpace {
+        directory: Utf8PathBuf,
+        available: u64,
+        min_free_space: u64,
+    },
+    /// Less space is free than the source takes up, which is the upper bound for the output.
+    OutputMayNotFit {
+        directory: Utf8PathBuf,
+        available: u64,
+    },
+    /// The temp file can't be renamed to the output, which is copied instead
+    /// unless `--cross-device error` was given.
+    CrossDevice {
+        tmp_directory: Utf8PathBuf,
+        directory: Utf8PathBuf,
+        mode: CrossDevice,
+    },
+    IncompatibleAudio(AudioTrack),
+    /// A commentary track that `--drop-commentary` leaves out.
+    DroppedCommentary(AudioTrack),
+    PixelFormat {
+        pix_fmt: String,
+        encoder: &'static str,
+    },
+    /// A hardware encoder for a `--reproducible` encode. Its output changes with
+    /// the driver and the load of the GPU.
+    NotReproducible {
+        encoder: &'static str,
+    },
+    /// The render node of a VAAPI encode doesn't exist.
+    MissingGpuDevice {
+        device: Utf8PathBuf,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Skip,
+    Failure,
+}
+
+impl Finding {
+    /// Why a file with this finding is skipped, for the findings that skip it.
+    pub fn skip_reason(&self) -> Option<SkipReason> {
+        match self {
+            Finding::Missing => Some(SkipReason::Missing),
+            Finding::Ignored { .. } => Some(SkipReason::IgnoreFile),
+            Finding::OutputExists { location, .. } => Some(SkipReason::OutputExists(*location)),
+            Finding::LowDiskSpace { .. } => Some(SkipReason::LowDiskSpace),
+            _ => None,
+        }
+    }
+
+    pub fn severity(&self) -> Severity {
+        match self {
+            Finding::Missing
+            | Finding::Ignored { .. }
+            | Finding::OutputExists { .. }
+            | Finding::LowDiskSpace { .. } => Severity::Skip,
+            Finding::ReadOnlyDirectory { fallback, .. } => match fallback {
+                Some(_) => Severity::Warning,
+                None => Severity::Failure,
+            },
+            Finding::CrossDevice { mode, .. } => match mode {
+                CrossDevice::Copy => Severity::Warning,
+                CrossDevice::Error => Severity::Failure,
+            },
+            Finding::FileTooLarge { .. }
+            | Finding::IncompatibleAudio(_)
+            | Finding::NotReproducible { .. }
+            | Finding::MissingGpuDevice { .. } => Severity::Failure,
+            Finding::OutputMayNotFit { .. }
+            | Finding::DroppedCommentary(_)
+            | Finding::PixelFormat { .. } => Severity::Warning,
+        }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Finding::Missing => write!(f, "file is missing"),
+            Finding::Ignored {
+                directory,
+                ignore_file,
+            } => write!(f, "{directory} contains {ignore_file}"),
+            Finding::OutputExists {
+                path,
+                location: OutputLocation::Current,
+            } => write!(f, "output {path} exists"),
+            Finding::OutputExists { path, location } => {
+                write!(f, "output {path} exists {location}")
+            }
+            Finding::ReadOnlyDirectory {
+                directory,
+                fallback: Some(fallback),
+            } => write!(
+                f,
+                "{directory} is read-only, writing the output to {fallback}"
+            ),
+            Finding::ReadOnlyDirectory {
+                directory,
+                fallback: None,
+            } => write!(
+                f,
+                "{directory} is read-only, use --output-dir or --tmp-dir, or don't use --replace"
+            ),
+            Finding::FileTooLarge {
+                directory,
+                fs_type,
+                limit,
+            } => write!(
+                f,
+                "{directory} is on a {fs_type} filesystem that can't hold files larger than {}, \
+                 use an --output-dir on a different filesystem",
+                limit.human_count_bytes()
+            ),
+            Finding::LowDiskSpace {
+                directory,
+                available,
+                min_free_space,
+            } => write!(
+                f,
+                "only {} free on the filesystem of {directory}, less than --min-free-space {}",
+                available.human_count_bytes(),
+                min_free_space.human_count_bytes()
+            ),
+            Finding::OutputMayNotFit {
+                directory,
+                available,
+            } => write!(
+                f,
+                "only {} free on the filesystem of {directory}, the output may not fit",
+                available.human_count_bytes()
+            ),
+            Finding::CrossDevice {
+                tmp_directory,
+                directory,
+                mode: CrossDevice::Copy,
+            } => write!(
+                f,
+                "{tmp_directory} and {directory} are on different filesystems, \
+                 the output will be copied into place"
+            ),
+            Finding::CrossDevice {
+                tmp_directory,
+                directory,
+                mode: CrossDevice::Error,
+            } => write!(
+                f,
+                "{tmp_directory} and {directory} are on different filesystems, use a --tmp-dir \
+                 on the output's filesystem or --cross-device copy"
+            ),
+            Finding::IncompatibleAudio(track) => {
+                write!(f, "{} audio in mp4 ({track})", audio_name(&track.codec))
+            }
+            Finding::DroppedCommentary(track) => write!(f, "drops the commentary {track}"),
+            Finding::PixelFormat { pix_fmt, encoder } => write!(
+                f,
+                "{encoder} doesn't support {pix_fmt}, ffmpeg will convert it to 4:2:0"
+            ),
+            Finding::NotReproducible { encoder } => write!(
+                f,
+                "{encoder} doesn't give the same output twice, --reproducible needs an encoder on the CPU"
+            ),
+            Finding::MissingGpuDevice { device } => write!(
+                f,
+                "there is no render node {device} to encode with VAAPI on, pick one with --gpu-device"
+            ),
+        }
+    }
+}
+
+fn audio_name(codec: &str) -> String {
+    if codec.starts_with("wma") {
+        "WMA".into()
+    } else if codec.starts_with("pcm_") {
+        "PCM".into()
+    } else {
+        codec.into()
+    }
+}
+
+/// What would happen to a file, based on its findings.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    /// The file would be transcoded, possibly with warnings.
+    Transcode(Vec<String>),
+    Skip(String),
+    Fail(String),
+}
+
+impl Verdict {
+    /// The most severe finding decides, warnings are kept for transcoded files.
+    pub fn from_findings(findings: &[Finding]) -> Verdict {
+        let worst = findings.iter().max_by_key(|f| f.severity());
+        match worst.map(|f| (f.severity(), f)) {
+            Some((Severity::Failure, finding)) => Verdict::Fail(finding.to_string()),
+            Some((Severity::Skip, finding)) => Verdict::Skip(finding.to_string()),
+            _ => Verdict::Transcode(findings.iter().map(|f| f.to_string()).collect()),
+        }
+    }
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Verdict::Transcode(warnings) if warnings.is_empty() => write!(f, "would transcode"),
+            Verdict::Transcode(warnings) => {
+                write!(f, "would transcode (warning: {})", warnings.join("; "))
+            }
+            Verdict::Skip(reason) => write!(f, "would skip: {reason}"),
+            Verdict::Fail(reason) => write!(f, "would fail: {reason}"),
+        }
+    }
+}
+
+/// Checks whether there is enough free space left to start transcoding the file.
+pub fn check_free_space(
+    file: &VideoFile,
+    paths: &OutputPaths,
+    min_free_space: u64,
+) -> Option<Finding> {
+    let tmp_file = paths.tmp(&file.path, file.id);
+    let directory = tmp_file.parent().unwrap_or(Utf8Path::new("."));
+    filesystem::available_space(directory)
+        .filter(|&available| available < min_free_space)
+        .map(|available| Finding::LowDiskSpace {
+            directory: directory.to_owned(),
+            available,
+            min_free_space,
+        })
+}
+
+/// Checks whether the temp file can be renamed to the output, comparing the
+/// devices of their directories.
+pub fn check_cross_device(
+    tmp_file: &Utf8Path,
+    output: &Utf8Path,
+    mode: CrossDevice,
+    device_id: impl Fn(&Utf8Path) -> Option<u64>,
+) -> Option<Finding> {
+    let tmp_directory = tmp_file.parent().unwrap_or(Utf8Path::new("."));
+    let directory = output.parent().unwrap_or(Utf8Path::new("."));
+    match (device_id(tmp_directory), device_id(directory)) {
+        (Some(tmp_device), Some(device)) if tmp_device != device => Some(Finding::CrossDevice {
+            tmp_directory: tmp_directory.to_owned(),
+            directory: directory.to_owned(),
+            mode,
+        }),
+        _ => None,
+    }
+}
+
+/// What [`find_existing_output`] needs to know about a possible output.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutputInfo {
+    pub size: u64,
+    /// `None` if ffprobe can't read the file.
+    pub video_codec: Option<String>,
+}
+
+/// Reads the size and, for files that aren't empty, the video codec of a
+/// possible output. `None` if there is no such file.
+pub fn inspect_output(path: &Utf8Path) -> Option<OutputInfo> {
+    let metadata = std::fs::metadata(path).ok().filter(|m| m.is_file())?;
+    let video_codec = match metadata.len() {
+        0 => None,
+        _ => ffprobe(path).ok().map(|info| info.video_codec().to_owned()),
+    };
+    Some(OutputInfo {
+        size: metadata.len(),
+        video_codec,
+    })
+}
+
+/// Searches every place an output of the file could have been written to, in
+/// the order of [`OutputPaths::output_candidates`], for one that is valid: not
+/// empty, readable by ffprobe and in a codec of the run. That rules out leftovers of crashed runs
+/// and unrelated files that happen to have the same name.
+pub fn find_existing_output(
+    paths: &OutputPaths,
+    source: &Utf8Path,
+    fields: OutputFields,
+    recorded: Option<&Utf8Path>,
+    inspect: impl Fn(&Utf8Path) -> Option<OutputInfo>,
+) -> Option<(Utf8PathBuf, OutputLocation)> {
+    paths
+        .output_candidates(source, fields, recorded)
+        .into_iter()
+        .find(|(path, _)| {
+            inspect(path).is_some_and(|info| {
+                let valid = info.size > 0
+                    && info
+                        .video_codec
+                        .as_deref()
+                        .is_some_and(|codec| paths.accepts_codec(codec));
+                if !valid {
+                    debug!("{source}: ignoring {path}, it isn't a valid output");
+                }
+                valid
+            })
+        })
+}
+
+/// Runs the cheap checks a transcode would otherwise only fail on halfway through.
+/// `paths` must have the read-only source directories filled in.
+pub fn preflight(
+    file: &VideoFile,
+    paths: &OutputPaths,
+    options: &TranscodeOptions,
+    settings: &EncodeSettings,
+    gpu: Option<&GpuMode>,
+) -> Vec<Finding> {
+    let mut findings = vec![];
+    if !file.path.is_file() {
+        findings.push(Finding::Missing);
+        return findings;
+    }
+    // the file may have been queued before the ignore file was put there
+    if let Some(directory) = collect::ignoring_directory(&file.path, &options.ignore_file) {
+        findings.push(Finding::Ignored {
+            directory: directory.to_owned(),
+            ignore_file: options.ignore_file.clone(),
+        });
+        return findings;
+    }
+    let fields = OutputFields {
+        crf: Some(settings.crf),
+        height: file.resolution.1,
+        codec: None,
+    };
+    let out_file = paths.output(&file.path, fields);
+    if !options.force
+        && let Some((path, location)) = find_existing_output(
+            paths,
+            &file.path,
+            fields,
+            file.output_path.as_deref(),
+            inspect_output,
+        )
+    {
+        findings.push(Finding::OutputExists { path, location });
+    }
+
+    if paths.writes_to_source_dir(options.replace) && !paths.is_source_dir_writable(&file.path) {
+        let directory = file.path.parent().unwrap_or(Utf8Path::new("."));
+        findings.push(Finding::ReadOnlyDirectory {
+            directory: directory.to_owned(),
+            fallback: paths.tmp_dir.clone().filter(|_| !options.replace),
+        });
+    }
+
+    let tmp_file = paths.tmp(&file.path, file.id);
+    let replacement = paths::replacement(&file.path, &tmp_file);
+    let final_file = if options.replace {
+        &replacement
+    } else {
+        &out_file
+    };
+    for output in [&tmp_file, final_file] {
+        let directory = output.parent().unwrap_or(Utf8Path::new("."));
+        if let Some(fs_type) = filesystem::filesystem_type(directory)
+            && let Some(limit) = filesystem::exceeds_limit(fs_type, file.file_size)
+        {
+            findings.push(Finding::FileTooLarge {
+                directory: directory.to_owned(),
+                fs_type,
+                limit,
+            });
+        }
+    }
+
+    if let Some(finding) = check_cross_device(
+        &tmp_file,
+        final_file,
+        options.cross_device,
+        filesystem::device_id,
+    ) {
+        findings.push(finding);
+    }
+
+    if let Some(min_free_space) = options.min_free_space
+        && let Some(finding) = check_free_space(file, paths, min_free_space)
+    {
+        findings.push(finding);
+    } else {
+        let directory = tmp_file.parent().unwrap_or(Utf8Path::new("."));
+        if let Some(available) = filesystem::available_space(directory)
+            && available < file.file_size
+        {
+            findings.push(Finding::OutputMayNotFit {
+                directory: directory.to_owned(),
+                available,
+            });
+        }
+    }
+
+    for (track, decision) in audio::decide_all(&file.audio_tracks, &options.audio) {
+        if decision == AudioDecision::Copy
+            && MP4_INCOMPATIBLE_AUDIO
+                .iter()
+                .any(|codec| track.codec.starts_with(codec))
+        {
+            findings.push(Finding::IncompatibleAudio(track));
+        } else if decision == AudioDecision::Drop && !options.audio.drop {
+            findings.push(Finding::DroppedCommentary(track));
+        }
+    }
+
+    if settings.reproducible && gpu.is_some() {
+        findings.push(Finding::NotReproducible {
+            encoder: transcode::encoder_name(gpu, options.constraints.codec()),
+        });
+    }
+
+    if gpu == Some(&GpuMode::Vaapi) {
+        let device = options
+            .gpu_device
+            .clone()
+            .unwrap_or_else(|| transcode::DEFAULT_VAAPI_DEVICE.into());
+        if !device.exists() {
+            findings.push(Finding::MissingGpuDevice { device });
+        }
+    }
+
+    if let Some(pix_fmt) = &file.pix_fmt
+        && !settings.ten_bit
+        && !ENCODER_PIX_FMTS.contains(&pix_fmt.as_str())
+    {
+        findings.push(Finding::PixelFormat {
+            pix_fmt: pix_fmt.clone(),
+            encoder: transcode::encoder_name(gpu, options.constraints.codec()),
+        });
+    }
+    findings
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::Result;
+    use crate::config::{self, TranscodeSettings};
+    use crate::database::TranscodeStatus;
+    use crate::device::VideoCodec;
+
+    fn options() -> TranscodeOptions {
+        TranscodeOptions::for_tests()
+    }
+
+    fn video(path: &Utf8Path) -> VideoFile {
+        std::fs::write(path, b"video").unwrap();
+        VideoFile {
+            id: 1,
+            path: path.to_owned(),
+            duration: 60.0,
+            resolution: (1920, 1080),
+            bitrate: 0,
+            video_bitrate: 0,
+            audio_bitrate: 0,
+            frame_rate: 24.0,
+            codec: "h264".into(),
+            profile: None,
+            container: "mp4".into(),
+            file_size: 5,
+            status: TranscodeStatus::Pending,
+            audio_tracks: vec![],
+            pix_fmt: Some("yuv420p".into()),
+            start_offset: None,
+            pinned: false,
+            output_path: None,
+            title: None,
+            chapters: vec![],
+        }
+    }
+
+    fn wma() -> AudioTrack {
+        AudioTrack {
+            index: 0,
+            codec: "wmav2".into(),
+            bitrate: Some(128_000),
+            channels: Some(2),
+            title: None,
+            default: false,
+            commentary: false,
+        }
+    }
+
+    fn check(file: &VideoFile, paths: &OutputPaths, options: &TranscodeOptions) -> Vec<Finding> {
+        preflight(file, paths, options, &settings(), options.gpu.as_ref())
+    }
+
+    fn settings() -> EncodeSettings {
+        config::merge(
+            &TranscodeSettings::default(),
+            None,
+            &TranscodeSettings::default(),
+            VideoCodec::Av1,
+        )
+    }
+
+    #[test]
+    fn test_clean_file() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let directory = Utf8Path::from_path(tempdir.path()).unwrap();
+        let file = video(&directory.join("a.mkv"));
+
+        let findings = check(&file, &OutputPaths::default(), &options());
+        assert_eq!(Vec::<Finding>::new(), findings);
+        assert_eq!(
+            "would transcode",
+            Verdict::from_findings(&findings).to_string()
+        );
+    }
+
+    #[test]
+    fn test_find_existing_output() {
+        let paths = OutputPaths {
+            output_dir: Some("/out".into()),
+            previous_output_dirs: vec!["/old".into()],
+            ..Default::default()
+        };
+        let av1 = |size| {
+            Some(OutputInfo {
+                size,
+                video_codec: Some("av1".into()),
+            })
+        };
+        let inspect = |path: &Utf8Path| match path.as_str() {
+            // left behind by a crashed run
+            "/out/a_av1.mp4" => av1(0),
+            // someone else's file with the same name
+            "/movies/a_av1.mp4" => Some(OutputInfo {
+                size: 1000,
+                video_codec: Some("h264".into()),
+            }),
+            "/old/a_av1.mp4" | "/old/b_av1.mp4" => av1(1000),
+            "/out/b_av1.mp4" => av1(1000),
+            "/out/c_av1.mp4" => Some(OutputInfo {
+                size: 1000,
+                video_codec: None,
+            }),
+            _ => None,
+        };
+
+        assert_eq!(
+            Some(("/old/a_av1.mp4".into(), OutputLocation::PreviousOutputDir)),
+            find_existing_output(
+                &paths,
+                "/movies/a.mkv".into(),
+                OutputFields::default(),
+                None,
+                inspect
+            )
+        );
+        // the first valid one wins
+        assert_eq!(
+            Some(("/out/b_av1.mp4".into(), OutputLocation::Current)),
+            find_existing_output(
+                &paths,
+                "/movies/b.mkv".into(),
+                OutputFields::default(),
+                None,
+                inspect
+            )
+        );
+        assert_eq!(
+            None,
+            find_existing_output(
+                &paths,
+                "/movies/c.mkv".into(),
+                OutputFields::default(),
+                None,
+                inspect
+            )
+        );
+        assert_eq!(
+            Some(("/old/b_av1.mp4".into(), OutputLocation::Recorded)),
+            find_existing_output(
+                &paths,
+                "/movies/c.mkv".into(),
+                OutputFields::default(),
+                Some("/old/b_av1.mp4".into()),
+                inspect
+            )
+        );
+    }
+
+    #[test]
+    fn test_missing_and_existing_output() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let directory = Utf8Path::from_path(tempdir.path()).unwrap();
+        let file = video(&directory.join("a.mkv"));
+        let output = directory.join("a_av1.mp4");
+
+        // empty or unreadable files aren't outputs
+        for contents in [&b""[..], b"not a video"] {
+            std::fs::write(&output, contents).unwrap();
+            assert!(check(&file, &OutputPaths::default(), &options()).is_empty());
+        }
+
+        let finding = Finding::OutputExists {
+            path: output.clone(),
+            location: OutputLocation::Sibling,
+        };
+        assert_eq!(
+            format!("would skip: output {output} exists next to the source"),
+            Verdict::from_findings(std::slice::from_ref(&finding)).to_string()
+        );
+        assert_eq!(
+            Some(SkipReason::OutputExists(OutputLocation::Sibling)),
+            finding.skip_reason()
+        );
+
+        std::fs::remove_file(&file.path).unwrap();
+        let findings = check(&file, &OutputPaths::default(), &options());
+        assert_eq!(vec![Finding::Missing], findings);
+        assert_eq!(
+            "would skip: file is missing",
+            Verdict::from_findings(&findings).to_string()
+        );
+        assert_eq!(Some(SkipReason::Missing), findings[0].skip_reason());
+    }
+
+    #[test]
+    fn test_ignore_file() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let directory = Utf8Path::from_path(tempdir.path()).unwrap();
+        let season = directory.join("show/season 1");
+        std::fs::create_dir_all(&season).unwrap();
+        let file = video(&season.join("e01.mkv"));
+        assert!(check(&file, &OutputPaths::default(), &options()).is_empty());
+
+        // marked after the file was queued
+        std::fs::write(directory.join("show/.notranscode"), "").unwrap();
+        let findings = check(&file, &OutputPaths::default(), &options());
+        assert_eq!(
+            vec![Finding::Ignored {
+                directory: directory.join("show"),
+                ignore_file: ".notranscode".into(),
+            }],
+            findings
+        );
+        assert_eq!(Some(SkipReason::IgnoreFile), findings[0].skip_reason());
+        assert_eq!(
+            format!("would skip: {directory}/show contains .notranscode"),
+            Verdict::from_findings(&findings).to_string()
+        );
+
+        let options = TranscodeOptions {
+            ignore_file: ".skip".into(),
+            ..options()
+        };
+        assert!(check(&file, &OutputPaths::default(), &options).is_empty());
+    }
+
+    #[test]
+    fn test_read_only_directory() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let directory = Utf8Path::from_path(tempdir.path()).unwrap();
+        let source_dir = directory.join("ro");
+        std::fs::create_dir(&source_dir).unwrap();
+        let file = video(&source_dir.join("a.mkv"));
+        let paths = OutputPaths {
+            unwritable: HashSet::from([source_dir.clone()]),
+            ..Default::default()
+        };
+
+        let findings = check(&file, &paths, &options());
+        assert_eq!(Severity::Failure, findings[0].severity());
+        assert!(matches!(
+            Verdict::from_findings(&findings),
+            Verdict::Fail(reason) if reason.starts_with(&format!("{source_dir} is read-only"))
+        ));
+
+        let tmp_dir = directory.join("tmp");
+        let paths = OutputPaths {
+            tmp_dir: Some(tmp_dir.clone()),
+            ..paths
+        };
+        let findings = check(&file, &paths, &options());
+        assert_eq!(
+            vec![Finding::ReadOnlyDirectory {
+                directory: source_dir.clone(),
+                fallback: Some(tmp_dir.clone()),
+            }],
+            findings
+        );
+        assert_eq!(
+            format!(
+                "would transcode (warning: {source_dir} is read-only, writing the output to {tmp_dir})"
+            ),
+            Verdict::from_findings(&findings).to_string()
+        );
+
+        let replace = TranscodeOptions {
+            replace: true,
+            ..options()
+        };
+        assert_eq!(
+            Severity::Failure,
+            check(&file, &paths, &replace)[0].severity()
+        );
+    }
+
+    #[test]
+    fn test_cross_device() -> Result<()> {
+        let tmp = tempfile::tempdir()?;
+        let tmp_dir = Utf8Path::from_path(tmp.path()).unwrap();
+        let output = tempfile::tempdir()?;
+        let output_dir = Utf8Path::from_path(output.path()).unwrap();
+        let tmp_file = tmp_dir.join(".transcoder-1-1.tmp.mp4");
+        let out_file = output_dir.join("a_av1.mp4");
+        // pretend the output directory is a different mount
+        let device_id = |path: &Utf8Path| Some(if path == output_dir { 2 } else { 1 });
+
+        let finding =
+            check_cross_device(&tmp_file, &out_file, CrossDevice::Copy, device_id).unwrap();
+        assert_eq!(Severity::Warning, finding.severity());
+        assert_eq!(
+            format!(
+                "{tmp_dir} and {output_dir} are on different filesystems, the output will be copied into place"
+            ),
+            finding.to_string()
+        );
+        let finding =
+            check_cross_device(&tmp_file, &out_file, CrossDevice::Error, device_id).unwrap();
+        assert!(matches!(
+            Verdict::from_findings(&[finding]),
+            Verdict::Fail(reason) if reason.ends_with("--cross-device copy")
+        ));
+
+        let same_device = tmp_dir.join("a_av1.mp4");
+        assert_eq!(
+            None,
+            check_cross_device(&tmp_file, &same_device, CrossDevice::Error, device_id)
+        );
+        // unknown devices, e.g. a missing output directory, aren't reported
+        assert_eq!(
+            None,
+            check_cross_device(&tmp_file, &out_file, CrossDevice::Error, |_| None)
+        );
+        // the real devices of two tempdirs are the same
+        assert_eq!(
+            None,
+            check_cross_device(
+                &tmp_file,
+                &out_file,
+                CrossDevice::Error,
+                filesystem::device_id
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_low_disk_space() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let directory = Utf8Path::from_path(tempdir.path()).unwrap();
+        let file = video(&directory.join("a.mkv"));
+        let options = TranscodeOptions {
+            min_free_space: Some(u64::MAX),
+            ..options()
+        };
+        let findings = check(&file, &OutputPaths::default(), &options);
+        // the filesystem of the tempdir may not be known in every sandbox
+        if filesystem::available_space(directory).is_some() {
+            assert!(matches!(findings[..], [Finding::LowDiskSpace { .. }]));
+            assert!(
+                Verdict::from_findings(&findings)
+                    .to_string()
+                    .starts_with("would skip: only ")
+            );
+        }
+    }
+
+    #[test]
+    fn test_incompatible_audio() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let directory = Utf8Path::from_path(tempdir.path()).unwrap();
+        let file = VideoFile {
+            audio_tracks: vec![wma()],
+            ..video(&directory.join("a.wmv"))
+        };
+
+        let findings = check(&file, &OutputPaths::default(), &options());
+        assert_eq!(vec![Finding::IncompatibleAudio(wma())], findings);
+        assert_eq!(
+            "would fail: WMA audio in mp4 (audio track 0 (wmav2, 128 kb/s, 2 ch))",
+            Verdict::from_findings(&findings).to_string()
+        );
+
+        // re-encoded tracks are fine
+        let mut reencode = options();
+        reencode.audio.reencode_above = Some(256_000);
+        assert!(check(&file, &OutputPaths::default(), &reencode).is_empty());
+    }
+
+    #[test]
+    fn test_dropped_commentary() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let directory = Utf8Path::from_path(tempdir.path()).unwrap();
+        let commentary = AudioTrack {
+            index: 1,
+            codec: "aac".into(),
+            title: Some("Commentary".into()),
+            commentary: true,
+            ..wma()
+        };
+        let file = VideoFile {
+            audio_tracks: vec![wma(), commentary.clone()],
+            ..video(&directory.join("a.mkv"))
+        };
+        let mut options = options();
+        options.audio.reencode_above = Some(256_000);
+        assert!(check(&file, &OutputPaths::default(), &options).is_empty());
+
+        options.audio.drop_commentary = true;
+        let findings = check(&file, &OutputPaths::default(), &options);
+        assert_eq!(vec![Finding::DroppedCommentary(commentary)], findings);
+        assert_eq!(
+            "would transcode (warning: drops the commentary audio track 1 (aac, 128 kb/s, 2 ch, \"Commentary\"))",
+            Verdict::from_findings(&findings).to_string()
+        );
+    }
+
+    #[test]
+    fn test_pixel_format() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let directory = Utf8Path::from_path(tempdir.path()).unwrap();
+        let file = VideoFile {
+            pix_fmt: Some("yuv422p10le".into()),
+            ..video(&directory.join("a.mov"))
+        };
+
+        let findings = check(&file, &OutputPaths::default(), &options());
+        assert_eq!(
+            vec![Finding::PixelFormat {
+                pix_fmt: "yuv422p10le".into(),
+                encoder: "libsvtav1",
+            }],
+            findings
+        );
+        assert_eq!(
+            "would transcode (warning: libsvtav1 doesn't support yuv422p10le, ffmpeg will convert it to 4:2:0)",
+            Verdict::from_findings(&findings).to_string()
+        );
+
+        let settings = EncodeSettings {
+            ten_bit: true,
+            ..settings()
+        };
+        assert!(preflight(&file, &OutputPaths::default(), &options(), &settings, None).is_empty());
+    }
+
+    #[test]
+    fn test_not_reproducible() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let directory = Utf8Path::from_path(tempdir.path()).unwrap();
+        let file = video(&directory.join("a.mkv"));
+        let settings = EncodeSettings {
+            reproducible: true,
+            ..settings()
+        };
+        let findings = preflight(
+            &file,
+            &OutputPaths::default(),
+            &options(),
+            &settings,
+            Some(&GpuMode::Nvidia),
+        );
+        assert_eq!(
+            vec![Finding::NotReproducible {
+                encoder: "av1_nvenc"
+            }],
+            findings
+        );
+        assert!(matches!(
+            Verdict::from_findings(&findings),
+            Verdict::Fail(_)
+        ));
+        assert!(preflight(&file, &OutputPaths::default(), &options(), &settings, None).is_empty());
+    }
+
+    #[test]
+    fn test_gpu_device() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let directory = Utf8Path::from_path(tempdir.path()).unwrap();
+        let file = video(&directory.join("a.mkv"));
+        let missing = directory.join("renderD129");
+        let options = TranscodeOptions {
+            gpu_device: Some(missing.clone()),
+            ..options()
+        };
+        let vaapi = Some(&GpuMode::Vaapi);
+        assert_eq!(
+            vec![Finding::MissingGpuDevice { device: missing }],
+            preflight(&file, &OutputPaths::default(), &options, &settings(), vaapi)
+        );
+        // the other encoders don't use it
+        assert!(
+            preflight(
+                &file,
+                &OutputPaths::default(),
+                &options,
+                &settings(),
+                Some(&GpuMode::Qsv)
+            )
+            .is_empty()
+        );
+        let options = TranscodeOptions {
+            gpu_device: Some(directory.to_owned()),
+            ..options
+        };
+        assert!(preflight(&file, &OutputPaths::default(), &options, &settings(), vaapi).is_empty());
+    }
+
+    #[test]
+    fn test_failure_wins() {
+        let findings = [
+            Finding::PixelFormat {
+                pix_fmt: "yuv444p".into(),
+                encoder: "av1_nvenc",
+            },
+            Finding::OutputExists {
+                path: "/a_av1.mp4".into(),
+                location: OutputLocation::Current,
+            },
+            Finding::IncompatibleAudio(wma()),
+        ];
+        assert!(matches!(
+            Verdict::from_findings(&findings),
+            Verdict::Fail(_)
+        ));
+        assert_eq!(
+            Verdict::Skip("output /a_av1.mp4 exists".into()),
+            Verdict::from_findings(&findings[..2])
+        );
+    }
+}
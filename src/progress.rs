@@ -0,0 +1,400 @@
+use std::time::{Duration, Instant};
+
+use human_repr::HumanDuration;
+
+/// How often the progress of an encode is logged by default.
+pub const DEFAULT_LOG_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How the progress bars move after ffmpeg reported a new position.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Advance {
+    /// Added to the file's bar and the total bar's position.
+    pub position: u64,
+    /// Work beyond the file's expected length. Added to the total bar's length and
+    /// position, so that the total stays accurate for the rest of the run.
+    pub overflow: u64,
+}
+
+/// Tracks the progress of one file in milliseconds, clamped to the file's length,
+/// across the attempts of a run. Only the attempt that is running counts towards
+/// the total bar, what earlier attempts added to it is taken back.
+#[derive(Debug, Clone)]
+pub struct FileProgress {
+    length: u64,
+    position: u64,
+    overflow: u64,
+    /// The attempt that is running, from 1.
+    attempt: u32,
+    /// How many attempts the file may take.
+    attempts: u32,
+}
+
+impl FileProgress {
+    pub fn new(length: u64) -> Self {
+        FileProgress {
+            length,
+            position: 0,
+            overflow: 0,
+            attempt: 1,
+            attempts: 1,
+        }
+    }
+
+    /// Sets how many attempts the file may take, e.g. the CRFs `--auto-crf`
+    /// can try.
+    pub fn with_attempts(self, attempts: u32) -> Self {
+        FileProgress {
+            attempts: attempts.max(1),
+            ..self
+        }
+    }
+
+    /// Starts the file over for another attempt. Returns what the attempts so
+    /// far advanced the total bar by, which has to be taken back from its
+    /// position, and the overflow from its length as well. `unplanned` attempts
+    /// weren't part of the expected number, like encoding on the CPU after the
+    /// GPU ran out of sessions.
+    pub fn restart(&mut self, unplanned: bool) -> Advance {
+        let credited = Advance {
+            position: self.position,
+            overflow: self.overflow,
+        };
+        self.position = 0;
+        self.overflow = 0;
+        self.attempt += 1;
+        if unplanned {
+            self.attempts += 1;
+        }
+        self.attempts = self.attempts.max(self.attempt);
+        credited
+    }
+
+    /// Like `attempt 2/3` for files that started over, `None` for the first attempt.
+    pub fn attempt_label(&self) -> Option<String> {
+        (self.attempt > 1).then(|| format!("attempt {}/{}", self.attempt, self.attempts))
+    }
+
+    /// Moves to the position ffmpeg reported. Positions going backwards are ignored.
+    pub fn update(&mut self, reported: u64) -> Advance {
+        let clamped = reported.min(self.length);
+        let overflow = reported.saturating_sub(self.length);
+        let advance = Advance {
+            position: clamped.saturating_sub(self.position),
+            overflow: overflow.saturating_sub(self.overflow),
+        };
+        self.position = self.position.max(clamped);
+        self.overflow = self.overflow.max(overflow);
+        advance
+    }
+
+    /// How far the reported position went beyond the expected length.
+    pub fn overflow(&self) -> u64 {
+        self.overflow
+    }
+}
+
+/// Lets something happen at most once per interval, like a progress log line or
+/// a status file write. The current time is passed in, so that the schedule can
+/// be tested without waiting.
+#[derive(Debug, Clone)]
+pub struct Throttle {
+    interval: Duration,
+    last: Option<Instant>,
+}
+
+impl Throttle {
+    /// A throttle that is ready right away.
+    pub fn new(interval: Duration) -> Self {
+        Throttle {
+            interval,
+            last: None,
+        }
+    }
+
+    /// A throttle that is first ready one interval after `now`.
+    pub fn starting_at(interval: Duration, now: Instant) -> Self {
+        Throttle {
+            interval,
+            last: Some(now),
+        }
+    }
+
+    /// Whether it's time again. When it is, the next interval starts at `now`.
+    pub fn ready(&mut self, now: Instant) -> bool {
+        let ready = self
+            .last
+            .is_none_or(|last| now.saturating_duration_since(last) >= self.interval);
+        if ready {
+            self.last = Some(now);
+        }
+        ready
+    }
+
+    /// How long until it's ready again.
+    pub fn remaining(&self, now: Instant) -> Duration {
+        self.last.map_or(Duration::ZERO, |last| {
+            self.interval
+                .saturating_sub(now.saturating_duration_since(last))
+        })
+    }
+}
+
+/// A progress log line like `42% at 1.8x, ETA 12:03`. `encoded` is how much of
+/// the file was encoded in `elapsed`, the ETA assumes the speed stays the same.
+pub fn describe(
+    position: Duration,
+    length: Duration,
+    encoded: Duration,
+    elapsed: Duration,
+) -> String {
+    let percent = if length.is_zero() {
+        0.0
+    } else {
+        (position.as_secs_f64() / length.as_secs_f64() * 100.0).min(100.0)
+    };
+    let speed = if elapsed.is_zero() {
+        0.0
+    } else {
+        encoded.as_secs_f64() / elapsed.as_secs_f64()
+    };
+    let mut line = format!("{percent:.0}% at {speed:.1}x");
+    let remaining = length.saturating_sub(position);
+    if speed > 0.0 && !remaining.is_zero() {
+        let eta = remaining.as_secs_f64() / speed;
+        line.push_str(&format!(
+            ", ETA {}",
+            Duration::from_secs(eta.round() as u64).human_duration()
+        ));
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(length: u64, reported: &[u64]) -> (Vec<Advance>, u64) {
+        let mut progress = FileProgress::new(length);
+        let advances = reported.iter().map(|&r| progress.update(r)).collect();
+        (advances, progress.overflow())
+    }
+
+    fn advance(position: u64, overflow: u64) -> Advance {
+        Advance { position, overflow }
+    }
+
+    #[test]
+    fn test_within_length() {
+        let (advances, overflow) = run(1000, &[100, 600, 1000]);
+        assert_eq!(
+            vec![advance(100, 0), advance(500, 0), advance(400, 0)],
+            advances
+        );
+        assert_eq!(0, overflow);
+    }
+
+    #[test]
+    fn test_overshoot() {
+        for (reported, expected_overflow) in [(1001, 1), (1500, 500), (10_000, 9000)] {
+            let (advances, overflow) = run(1000, &[900, reported]);
+            assert_eq!(
+                vec![advance(900, 0), advance(100, expected_overflow)],
+                advances
+            );
+            assert_eq!(expected_overflow, overflow);
+            let total: u64 = advances.iter().map(|a| a.position).sum();
+            assert_eq!(1000, total);
+        }
+    }
+
+    #[test]
+    fn test_overshoot_in_steps() {
+        let (advances, overflow) = run(1000, &[1200, 1300, 1300, 2000]);
+        assert_eq!(
+            vec![
+                advance(1000, 200),
+                advance(0, 100),
+                advance(0, 0),
+                advance(0, 700)
+            ],
+            advances
+        );
+        assert_eq!(1000, overflow);
+    }
+
+    #[test]
+    fn test_backwards() {
+        let (advances, _) = run(1000, &[500, 400, 600]);
+        assert_eq!(
+            vec![advance(500, 0), advance(0, 0), advance(100, 0)],
+            advances
+        );
+    }
+
+    #[test]
+    fn test_unknown_length() {
+        let (advances, overflow) = run(0, &[500, 800]);
+        assert_eq!(vec![advance(0, 500), advance(0, 300)], advances);
+        assert_eq!(800, overflow);
+    }
+
+    /// The position and length of a total bar that only holds one file.
+    #[derive(Debug, Default, PartialEq)]
+    struct Total {
+        position: u64,
+        length: u64,
+    }
+
+    impl Total {
+        fn advance(&mut self, advance: Advance) {
+            self.position += advance.position + advance.overflow;
+            self.length += advance.overflow;
+        }
+
+        fn take_back(&mut self, credited: Advance) {
+            self.position -= credited.position + credited.overflow;
+            self.length -= credited.overflow;
+        }
+    }
+
+    /// Runs the attempts of a file, each reporting the positions, and returns the
+    /// total bar after each attempt.
+    fn attempts(length: u64, attempts: &[&[u64]]) -> Vec<Total> {
+        let mut progress = FileProgress::new(length).with_attempts(attempts.len() as u32);
+        let mut total = Total {
+            position: 0,
+            length,
+        };
+        let mut totals = vec![];
+        for (index, reported) in attempts.iter().enumerate() {
+            if index > 0 {
+                total.take_back(progress.restart(false));
+            }
+            for &reported in *reported {
+                total.advance(progress.update(reported));
+            }
+            totals.push(Total {
+                position: total.position,
+                length: total.length,
+            });
+        }
+        totals
+    }
+
+    #[test]
+    fn test_failed_attempt_is_taken_back() {
+        // failed at 80%, then encoded completely
+        let totals = attempts(1000, &[&[400, 800], &[300, 1000]]);
+        assert_eq!(
+            vec![
+                Total {
+                    position: 800,
+                    length: 1000
+                },
+                Total {
+                    position: 1000,
+                    length: 1000
+                }
+            ],
+            totals
+        );
+
+        // the bar is behind while a later attempt catches up
+        let totals = attempts(1000, &[&[900], &[100], &[500, 1000]]);
+        assert_eq!(vec![900, 100, 1000], positions(&totals));
+    }
+
+    fn positions(totals: &[Total]) -> Vec<u64> {
+        totals.iter().map(|total| total.position).collect()
+    }
+
+    #[test]
+    fn test_overflow_is_taken_back() {
+        // an attempt that ran past the metadata's length fails near its end
+        let totals = attempts(1000, &[&[1000, 1200], &[1200]]);
+        assert_eq!(
+            Total {
+                position: 1200,
+                length: 1200
+            },
+            totals[1]
+        );
+        // a retry that doesn't overflow leaves the length as it was
+        let totals = attempts(1000, &[&[1300], &[1000]]);
+        assert_eq!(
+            Total {
+                position: 1000,
+                length: 1000
+            },
+            totals[1]
+        );
+    }
+
+    #[test]
+    fn test_resumed_attempt() {
+        // a resumed encode starts at its offset, which counts once
+        let totals = attempts(1000, &[&[600, 700], &[600, 1000]]);
+        assert_eq!(vec![700, 1000], positions(&totals));
+    }
+
+    #[test]
+    fn test_attempt_label() {
+        let mut progress = FileProgress::new(1000).with_attempts(3);
+        assert_eq!(None, progress.attempt_label());
+        progress.restart(false);
+        assert_eq!(Some("attempt 2/3".into()), progress.attempt_label());
+        // switching to the CPU comes on top of the planned attempts
+        progress.restart(true);
+        assert_eq!(Some("attempt 3/4".into()), progress.attempt_label());
+        progress.restart(false);
+        progress.restart(false);
+        assert_eq!(Some("attempt 5/5".into()), progress.attempt_label());
+    }
+
+    #[test]
+    fn test_throttle_schedule() {
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+        let mut throttle = Throttle::starting_at(Duration::from_secs(30), start);
+        let emitted: Vec<u64> = (0..=100).filter(|&secs| throttle.ready(at(secs))).collect();
+        assert_eq!(vec![30, 60, 90], emitted);
+        assert_eq!(Duration::from_secs(20), throttle.remaining(at(100)));
+
+        // a late call starts the next interval from when it happened
+        let mut throttle = Throttle::starting_at(Duration::from_secs(30), start);
+        assert!(throttle.ready(at(45)));
+        assert!(!throttle.ready(at(60)));
+        assert!(throttle.ready(at(75)));
+        assert_eq!(Duration::ZERO, throttle.remaining(at(200)));
+    }
+
+    #[test]
+    fn test_throttle_ready_right_away() {
+        let start = Instant::now();
+        let mut throttle = Throttle::new(Duration::from_secs(5));
+        assert_eq!(Duration::ZERO, throttle.remaining(start));
+        assert!(throttle.ready(start));
+        assert!(!throttle.ready(start + Duration::from_secs(4)));
+        assert!(throttle.ready(start + Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn test_describe() {
+        let secs = Duration::from_secs;
+        assert_eq!(
+            "25% at 2.0x, ETA 45:00",
+            describe(secs(1800), secs(7200), secs(1800), secs(900))
+        );
+        // a resumed encode only counts what this run encoded for the speed
+        assert_eq!(
+            "50% at 0.5x, ETA 2:00:00",
+            describe(secs(3600), secs(7200), secs(600), secs(1200))
+        );
+        assert_eq!("0% at 0.0x", describe(secs(0), secs(60), secs(0), secs(0)));
+        // files without a known length don't get an ETA
+        assert_eq!(
+            "0% at 1.0x",
+            describe(secs(10), secs(0), secs(10), secs(10))
+        );
+    }
+}
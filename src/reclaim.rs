@@ -0,0 +1,378 @@
+use std::fmt;
+use std::fs;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use jiff::{SignedDuration, Span, SpanRelativeTo, Timestamp};
+use tracing::{info, warn};
+
+use crate::Result;
+use crate::audio_hash::AudioHash;
+use crate::database::{Database, TranscodeFile, TranscodeStatus};
+use crate::{paths, sidecar};
+
+/// Why the original of a file is kept.
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeepReason {
+    /// Transcoded with `--replace`, so there is no separate original.
+    Replaced,
+    /// Transcoded by an older version that didn't record the output path.
+    NoOutputPath,
+    NotVerified,
+    VerificationFailed,
+    /// `--verify-audio-hash` found that the audio differs from the source.
+    AudioMismatch,
+    /// Verified less than the grace period ago.
+    VerifiedRecently(Timestamp),
+    OutputMissing,
+    /// No output size was recorded to compare the output with.
+    NoRecordedSize,
+    /// The output isn't the size it had when it was encoded.
+    OutputChanged {
+        recorded: u64,
+        actual: u64,
+    },
+    OriginalMissing,
+    DeleteFailed(String),
+}
+
+impl fmt::Display for KeepReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeepReason::Replaced => write!(f, "the output replaced the original"),
+            KeepReason::NoOutputPath => write!(f, "no output path was recorded"),
+            KeepReason::NotVerified => write!(f, "the output hasn't been verified"),
+            KeepReason::VerificationFailed => write!(f, "the output failed verification"),
+            KeepReason::AudioMismatch => write!(f, "the audio of the output differs"),
+            KeepReason::VerifiedRecently(at) => write!(f, "the output was verified on {at}"),
+            KeepReason::OutputMissing => write!(f, "the output is missing"),
+            KeepReason::NoRecordedSize => write!(f, "no output size was recorded"),
+            KeepReason::OutputChanged { recorded, actual } => write!(
+                f,
+                "the output is {actual} bytes, but was {recorded} bytes when it was encoded"
+            ),
+            KeepReason::OriginalMissing => write!(f, "the original is already gone"),
+            KeepReason::DeleteFailed(error) => write!(f, "deleting the original failed: {error}"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ReclaimSummary {
+    /// The originals that were deleted, or would be in a dry run, with their sizes.
+    pub reclaimed: Vec<(Utf8PathBuf, u64)>,
+    pub kept: Vec<(Utf8PathBuf, KeepReason)>,
+}
+
+impl ReclaimSummary {
+    pub fn bytes(&self) -> u64 {
+        self.reclaimed.iter().map(|(_, size)| size).sum()
+    }
+}
+
+/// Checks that the original of a transcoded file can be deleted, returning its size.
+/// The output has to have passed a verification that is at least as old as
+/// `verified_before` and still be the size it was encoded at.
+fn check(
+    file: &TranscodeFile,
+    recorded_size: Option<u64>,
+    verified_before: Timestamp,
+) -> Result<u64, KeepReason> {
+    let output = file
+        .output_path
+        .as_deref()
+        .ok_or(KeepReason::NoOutputPath)?;
+    if paths::replaced_source(&file.path, output) {
+        return Err(KeepReason::Replaced);
+    }
+    let verified_at = file.verified_at.ok_or(KeepReason::NotVerified)?;
+    if file.verify_error.is_some() {
+        return Err(KeepReason::VerificationFailed);
+    }
+    if file.audio_hash == Some(AudioHash::Mismatch) {
+        return Err(KeepReason::AudioMismatch);
+    }
+    if verified_at > verified_before {
+        return Err(KeepReason::VerifiedRecently(verified_at));
+    }
+    let actual = fs::metadata(output)
+        .ok()
+        .filter(|m| m.is_file())
+        .ok_or(KeepReason::OutputMissing)?
+        .len();
+    let recorded = recorded_size.ok_or(KeepReason::NoRecordedSize)?;
+    if actual != recorded {
+        return Err(KeepReason::OutputChanged { recorded, actual });
+    }
+    fs::metadata(&file.path)
+        .ok()
+        .filter(|m| m.is_file())
+        .map(|m| m.len())
+        .ok_or(KeepReason::OriginalMissing)
+}
+
+fn delete(path: &Utf8Path) -> Result<(), KeepReason> {
+    fs::remove_file(path).map_err(|e| KeepReason::DeleteFailed(e.to_string()))
+}
+
+/// Parses a grace period like `30d`, `2w` or `12h`, where a day is 24 hours.
+pub fn parse_grace_period(s: &str) -> Result<SignedDuration, String> {
+    let span: Span = s
+        .parse()
+        .map_err(|_| format!("invalid duration '{s}', expected e.g. 30d or 12h"))?;
+    span.to_duration(SpanRelativeTo::days_are_24_hours())
+        .map_err(|e| format!("invalid duration '{s}': {e}"))
+}
+
+/// Deletes the originals of transcoded files whose outputs were verified at least
+/// `grace_period` ago, and marks them as reclaimed. Files with any doubt about
+/// their output are kept. The sidecars of deleted originals and of outputs that
+/// are gone are removed.
+pub fn reclaim(
+    database: &Database,
+    files: Vec<TranscodeFile>,
+    grace_period: SignedDuration,
+    dry_run: bool,
+) -> Result<ReclaimSummary> {
+    let verified_before = Timestamp::now() - grace_period;
+    let mut summary = ReclaimSummary::default();
+    for file in files {
+        if file.status != TranscodeStatus::Success {
+            continue;
+        }
+        let recorded_size = database
+            .crf_attempts(file.id)?
+            .last()
+            .map(|attempt| attempt.output_size as u64);
+        let result = check(&file, recorded_size, verified_before).and_then(|size| {
+            if !dry_run {
+                delete(&file.path)?;
+                sidecar::remove_orphan(&file.path);
+            }
+            Ok(size)
+        });
+        // the sidecar of an output that is gone has nothing left to describe
+        if result == Err(KeepReason::OutputMissing)
+            && !dry_run
+            && let Some(output) = &file.output_path
+        {
+            sidecar::remove_orphan(output);
+        }
+        match result {
+            Ok(size) => {
+                if dry_run {
+                    info!("would delete {}", file.path);
+                } else {
+                    info!("deleted {}", file.path);
+                    database.set_file_status(file.id, TranscodeStatus::Reclaimed, None)?;
+                }
+                summary.reclaimed.push((file.path, size));
+            }
+            Err(reason) => {
+                match &reason {
+                    KeepReason::DeleteFailed(_) => warn!("keeping {}: {reason}", file.path),
+                    _ => info!("keeping {}: {reason}", file.path),
+                }
+                summary.kept.push((file.path, reason));
+            }
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{FileFilter, NewTranscodeFile};
+    use crate::ffprobe::FfProbe;
+
+    struct Library {
+        _tempdir: tempfile::TempDir,
+        directory: Utf8PathBuf,
+        database: Database,
+    }
+
+    impl Library {
+        fn new() -> Result<Self> {
+            let tempdir = tempfile::tempdir()?;
+            let directory = Utf8Path::from_path(tempdir.path()).unwrap().to_owned();
+            Ok(Library {
+                _tempdir: tempdir,
+                directory,
+                database: Database::in_memory()?,
+            })
+        }
+
+        /// A transcoded file with a 10 byte original and a 4 byte output, optionally
+        /// verified.
+        fn transcoded(&self, name: &str, verify: Option<Option<&str>>) -> Result<Utf8PathBuf> {
+            let original = self.directory.join(format!("{name}.mkv"));
+            let output = self.directory.join(format!("{name}_av1.mp4"));
+            fs::write(&original, b"0123456789")?;
+            fs::write(&output, b"0123")?;
+            self.database.insert(NewTranscodeFile {
+                path: original.clone(),
+                file_size: 10,
+                ffprobe_info: FfProbe::default(),
+            })?;
+            let id = self.database.get_by_path(&original)?.unwrap().id;
+            self.database.insert_crf_attempt(id, 24, 4)?;
+            self.database.set_output_path(id, &output)?;
+            self.database
+                .set_file_status(id, TranscodeStatus::Success, None)?;
+            if let Some(error) = verify {
+                self.database.set_verification(id, error)?;
+            }
+            Ok(original)
+        }
+
+        fn files(&self) -> Result<Vec<TranscodeFile>> {
+            self.database.list_filtered(&FileFilter::default(), None)
+        }
+    }
+
+    fn reasons(summary: &ReclaimSummary) -> Vec<(&str, String)> {
+        summary
+            .kept
+            .iter()
+            .map(|(path, reason)| (path.file_stem().unwrap(), reason.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_grace_period() {
+        assert_eq!(
+            Ok(SignedDuration::from_hours(30 * 24)),
+            parse_grace_period("30d")
+        );
+        assert_eq!(
+            Ok(SignedDuration::from_hours(14 * 24)),
+            parse_grace_period("2w")
+        );
+        assert_eq!(
+            Ok(SignedDuration::from_hours(12)),
+            parse_grace_period("12h")
+        );
+        assert!(parse_grace_period("1 month").is_err());
+        assert!(parse_grace_period("soon").is_err());
+    }
+
+    #[test]
+    fn test_reclaim() -> Result<()> {
+        let library = Library::new()?;
+        let good = library.transcoded("good", Some(None))?;
+        library.transcoded("unverified", None)?;
+        library.transcoded("broken", Some(Some("decode error")))?;
+        let missing = library.transcoded("missing", Some(None))?;
+        fs::remove_file(library.directory.join("missing_av1.mp4"))?;
+        library.transcoded("changed", Some(None))?;
+        fs::write(library.directory.join("changed_av1.mp4"), b"012")?;
+        let gone = library.transcoded("gone", Some(None))?;
+        fs::remove_file(&gone)?;
+
+        let dry_run = reclaim(
+            &library.database,
+            library.files()?,
+            SignedDuration::ZERO,
+            true,
+        )?;
+        assert_eq!(vec![(good.clone(), 10)], dry_run.reclaimed);
+        assert!(good.is_file());
+        assert_eq!(
+            vec![
+                ("broken", "the output failed verification".to_string()),
+                (
+                    "changed",
+                    "the output is 3 bytes, but was 4 bytes when it was encoded".into()
+                ),
+                ("gone", "the original is already gone".into()),
+                ("missing", "the output is missing".into()),
+                ("unverified", "the output hasn't been verified".into()),
+            ],
+            reasons(&dry_run)
+        );
+
+        let summary = reclaim(
+            &library.database,
+            library.files()?,
+            SignedDuration::ZERO,
+            false,
+        )?;
+        assert_eq!(10, summary.bytes());
+        assert!(!good.exists());
+        assert!(library.directory.join("good_av1.mp4").is_file());
+        assert!(missing.is_file());
+        let file = library.database.get_by_path(&good)?.unwrap();
+        assert_eq!(TranscodeStatus::Reclaimed, file.status);
+
+        // reclaimed files aren't looked at again
+        let again = reclaim(
+            &library.database,
+            library.files()?,
+            SignedDuration::ZERO,
+            false,
+        )?;
+        assert!(again.reclaimed.is_empty());
+        assert_eq!(5, again.kept.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_grace_period() -> Result<()> {
+        let library = Library::new()?;
+        let original = library.transcoded("recent", Some(None))?;
+        let summary = reclaim(
+            &library.database,
+            library.files()?,
+            SignedDuration::from_hours(24 * 30),
+            false,
+        )?;
+        assert!(summary.reclaimed.is_empty());
+        assert!(
+            reasons(&summary)[0]
+                .1
+                .starts_with("the output was verified on")
+        );
+        assert!(original.is_file());
+        Ok(())
+    }
+
+    #[test]
+    fn test_replaced() -> Result<()> {
+        let library = Library::new()?;
+        let original = library.transcoded("replaced", Some(None))?;
+        let id = library.database.get_by_path(&original)?.unwrap().id;
+        library.database.set_output_path(id, &original)?;
+        let summary = reclaim(
+            &library.database,
+            library.files()?,
+            SignedDuration::ZERO,
+            false,
+        )?;
+        assert_eq!(
+            vec![("replaced", "the output replaced the original".to_string())],
+            reasons(&summary)
+        );
+        assert!(original.is_file());
+        Ok(())
+    }
+
+    #[test]
+    fn test_audio_mismatch() -> Result<()> {
+        let library = Library::new()?;
+        let original = library.transcoded("trimmed", Some(None))?;
+        let id = library.database.get_by_path(&original)?.unwrap().id;
+        library.database.set_audio_hash(id, AudioHash::Mismatch)?;
+        let summary = reclaim(
+            &library.database,
+            library.files()?,
+            SignedDuration::ZERO,
+            false,
+        )?;
+        assert_eq!(
+            vec![("trimmed", "the audio of the output differs".to_string())],
+            reasons(&summary)
+        );
+        assert!(original.is_file());
+        Ok(())
+    }
+}
@@ -0,0 +1,468 @@
+//! Several outputs of one source from a single ffmpeg run, e.g. an AV1 archive
+//! and a small H.264 copy for sharing. ffmpeg decodes the source once and feeds
+//! every output from it. Its `-progress` still reports once for the whole run.
+
+use std::collections::{BTreeMap, HashSet};
+use std::str::FromStr;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::{bail, eyre};
+use jiff::civil::Date;
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+use crate::config::EncodeSettings;
+use crate::constraints::Constraints;
+use crate::device::{Container, VideoCodec};
+use crate::ffprobe::FfProbe;
+use crate::output_template::{NameFields, OutputTemplate};
+use crate::paths::OutputPaths;
+use crate::speed::Speed;
+
+/// The built-in profiles of `--renditions`. Profiles in the config file under
+/// `[renditions.<name>]` have the same format and replace these by name.
+const BUILTIN_PROFILES: &str = r#"
+[archive]
+description = "The run's codec and settings at the source's resolution"
+
+[stream]
+description = "H.264 at up to 720p, for sharing and streaming"
+codec = "h264"
+max-height = 720
+"#;
+
+/// How the outputs of a rendition are encoded. What isn't set comes from the
+/// settings of the run.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RenditionProfile {
+    pub description: Option<String>,
+    pub codec: VideoCodec,
+    /// The codec's default CRF is used for H.264 and HEVC without one, the
+    /// run's CRF is meant for AV1.
+    pub crf: Option<u8>,
+    pub speed: Option<Speed>,
+    /// Sources taller than this are scaled down to it, keeping their aspect ratio.
+    pub max_height: Option<u32>,
+    /// The name of the outputs, `{stem}_<rendition>.{ext}` by default.
+    pub template: Option<String>,
+}
+
+/// A rendition as `--renditions` takes it, `<name>:<profile>`. A bare name
+/// is a rendition named after its profile.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenditionArg {
+    pub name: String,
+    pub profile: String,
+}
+
+impl FromStr for RenditionArg {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (name, profile) = value.split_once(':').unwrap_or((value, value));
+        let (name, profile) = (name.trim(), profile.trim());
+        // the name ends up in file names and templates
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!(
+                "invalid rendition name '{name}', use letters, digits, - and _"
+            ));
+        }
+        if profile.is_empty() {
+            return Err(format!("rendition '{name}' has no profile"));
+        }
+        Ok(RenditionArg {
+            name: name.into(),
+            profile: profile.into(),
+        })
+    }
+}
+
+/// One output of every file in a `--renditions` run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rendition {
+    pub name: String,
+    pub profile: RenditionProfile,
+    pub template: OutputTemplate,
+}
+
+impl Rendition {
+    /// The settings the rendition is encoded with, for a file the run would
+    /// encode with `settings`. 10-bit H.264 hardly plays anywhere and film grain
+    /// synthesis is only in libsvtav1, so they're left out for other codecs.
+    pub fn settings(&self, settings: &EncodeSettings) -> EncodeSettings {
+        let mut settings = settings.clone();
+        if let Some(crf) = self.profile.crf.or(self.profile.codec.default_crf()) {
+            settings.crf = crf;
+        }
+        if let Some(speed) = self.profile.speed {
+            settings.speed = speed;
+            settings.effort = None;
+        }
+        if self.profile.codec != VideoCodec::Av1 {
+            settings.film_grain = None;
+        }
+        if self.profile.codec == VideoCodec::H264 {
+            settings.ten_bit = false;
+        }
+        settings
+    }
+
+    /// The run's constraints for AV1 renditions. They are AV1 levels and
+    /// profiles, so other codecs only get their encoder.
+    pub fn constraints(&self, run: &Constraints) -> Constraints {
+        match self.profile.codec {
+            VideoCodec::Av1 => run.clone(),
+            codec => Constraints::for_codec(codec),
+        }
+    }
+
+    /// The height a source of this height is encoded at.
+    pub fn height(&self, source_height: u32) -> u32 {
+        match self.profile.max_height {
+            Some(max) if source_height > max => max,
+            _ => source_height,
+        }
+    }
+
+    /// Scales sources that are taller than the profile allows. The width is
+    /// rounded to an even number, which the encoders need for 4:2:0 video.
+    pub fn scale_args(&self, source_height: u32) -> Vec<String> {
+        let height = self.height(source_height);
+        if height == source_height {
+            return vec![];
+        }
+        vec!["-vf".into(), format!("scale=-2:{height}")]
+    }
+
+    /// Where the run's outputs of this rendition go: the run's directories,
+    /// with the rendition's template and codec.
+    pub fn paths(&self, run: &OutputPaths) -> OutputPaths {
+        OutputPaths {
+            template: self.template.clone(),
+            codec: self.profile.codec,
+            accepted_codecs: vec![],
+            ..run.clone()
+        }
+    }
+
+    /// What is wrong with an output of the rendition: video in another codec
+    /// or taller than the profile allows.
+    pub fn violations(&self, output: &FfProbe, source_height: u32) -> Vec<String> {
+        let mut violations = vec![];
+        let codec = output.video_codec();
+        if codec != self.profile.codec.name() {
+            let codec = if codec.is_empty() { "missing" } else { codec };
+            violations.push(format!(
+                "its video is {codec} instead of {}",
+                self.profile.codec
+            ));
+        }
+        let (_, height) = output.resolution();
+        if height > self.height(source_height) {
+            violations.push(format!(
+                "it is {height} pixels high instead of {}",
+                self.height(source_height)
+            ));
+        }
+        violations
+    }
+}
+
+/// The built-in profiles by name.
+pub fn builtin() -> BTreeMap<String, RenditionProfile> {
+    toml::from_str(BUILTIN_PROFILES).expect("the built-in rendition profiles are valid")
+}
+
+/// The renditions of `--renditions`, with the profiles from the config file
+/// or else the built-in ones. Every rendition has to give its outputs names of
+/// their own.
+pub fn resolve(
+    args: &[RenditionArg],
+    configured: &BTreeMap<String, RenditionProfile>,
+) -> Result<Vec<Rendition>> {
+    let mut profiles = builtin();
+    profiles.extend(configured.clone());
+    let mut renditions: Vec<Rendition> = vec![];
+    for arg in args {
+        if renditions.iter().any(|r| r.name == arg.name) {
+            bail!("there are two renditions named {}", arg.name);
+        }
+        let profile = profiles.get(&arg.profile).cloned().ok_or_else(|| {
+            eyre!(
+                "unknown rendition profile {}, the known ones are {}",
+                arg.profile,
+                profiles.keys().cloned().collect::<Vec<_>>().join(", ")
+            )
+        })?;
+        // the renditions share the run's audio, which WebM doesn't take
+        if profile.codec.container() != Container::Mp4 {
+            bail!(
+                "rendition {}: {} is only written to {}, renditions are MP4",
+                arg.name,
+                profile.codec,
+                profile.codec.container()
+            );
+        }
+        let template = profile
+            .template
+            .clone()
+            .unwrap_or_else(|| format!("{{stem}}_{}.{{ext}}", arg.name))
+            .parse()
+            .map_err(|e| eyre!("rendition {}: {e}", arg.name))?;
+        renditions.push(Rendition {
+            name: arg.name.clone(),
+            profile,
+            template,
+        });
+    }
+    let mut names = HashSet::new();
+    for rendition in &renditions {
+        let name = rendition.template.render(&NameFields {
+            stem: "video",
+            codec: rendition.profile.codec.name(),
+            ext: rendition.profile.codec.container().extension(),
+            crf: 0,
+            height: 0,
+            date: Date::default(),
+        });
+        if !names.insert(name.clone()) {
+            bail!(
+                "renditions would write to the same file, {name}: give {} a template of its own",
+                rendition.name
+            );
+        }
+    }
+    Ok(renditions)
+}
+
+/// One output of a `--renditions` encode of a file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Leg<'a> {
+    pub rendition: &'a Rendition,
+    pub settings: EncodeSettings,
+    pub constraints: Constraints,
+    /// Where ffmpeg writes the output.
+    pub tmp: Utf8PathBuf,
+    /// Where the output is moved to once it passed its checks.
+    pub output: Utf8PathBuf,
+}
+
+/// The arguments of one ffmpeg run that writes several outputs. `encodes` are
+/// the arguments of the single encodes of the same input, with the output they
+/// write to. What comes up to the input is taken from the first one.
+pub fn multi_output_args(encodes: &[(Vec<String>, &Utf8Path)]) -> Vec<String> {
+    let after_input = |args: &[String]| {
+        args.iter()
+            .position(|arg| arg == "-i")
+            .expect("the encoder arguments contain an input")
+            + 2
+    };
+    let Some((first, _)) = encodes.first() else {
+        return vec![];
+    };
+    let mut args = first[..after_input(first)].to_vec();
+    args.extend(["-progress", "-", "-nostats"].map(String::from));
+    for (encode, output) in encodes {
+        args.extend(encode[after_input(encode)..].iter().cloned());
+        args.push(output.to_string());
+    }
+    args
+}
+
+/// An output of a rendition, recorded with the file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct RenditionOutput {
+    pub name: String,
+    pub output_path: Utf8PathBuf,
+    pub output_size: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffprobe::fixtures;
+
+    fn renditions(args: &str) -> Result<Vec<Rendition>> {
+        let args: Vec<RenditionArg> = args.split(',').map(|arg| arg.parse().unwrap()).collect();
+        resolve(&args, &BTreeMap::new())
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Ok(RenditionArg {
+                name: "share".into(),
+                profile: "stream".into()
+            }),
+            "share:stream".parse()
+        );
+        assert_eq!(
+            Ok(RenditionArg {
+                name: "archive".into(),
+                profile: "archive".into()
+            }),
+            "archive".parse()
+        );
+        assert!("my share:stream".parse::<RenditionArg>().is_err());
+        assert!("{stem}:stream".parse::<RenditionArg>().is_err());
+        assert!("share:".parse::<RenditionArg>().is_err());
+    }
+
+    #[test]
+    fn test_resolve() -> Result<()> {
+        let resolved = renditions("archive,share:stream")?;
+        assert_eq!("{stem}_archive.{ext}", resolved[0].template.to_string());
+        assert_eq!(VideoCodec::Av1, resolved[0].profile.codec);
+        assert_eq!("{stem}_share.{ext}", resolved[1].template.to_string());
+        assert_eq!(VideoCodec::H264, resolved[1].profile.codec);
+        assert_eq!(Some(720), resolved[1].profile.max_height);
+
+        let error = renditions("archive,archive").unwrap_err();
+        assert_eq!("there are two renditions named archive", error.to_string());
+        let error = renditions("share:phone").unwrap_err();
+        assert_eq!(
+            "unknown rendition profile phone, the known ones are archive, stream",
+            error.to_string()
+        );
+
+        // configured profiles replace the built-in ones, and need names of their own
+        let configured: BTreeMap<String, RenditionProfile> = toml::from_str(
+            r#"
+            [stream]
+            codec = "hevc"
+            crf = 30
+            template = "{stem} [{codec}].{ext}"
+
+            [phone]
+            codec = "hevc"
+            max-height = 480
+            template = "{stem} [{codec}].{ext}"
+
+            [web]
+            codec = "vp9"
+            "#,
+        )?;
+        let args = |args: &[&str]| -> Vec<RenditionArg> {
+            args.iter().map(|arg| arg.parse().unwrap()).collect()
+        };
+        let resolved = resolve(&args(&["archive", "share:stream"]), &configured)?;
+        assert_eq!(VideoCodec::Hevc, resolved[1].profile.codec);
+        assert_eq!(Some(30), resolved[1].profile.crf);
+        let error = resolve(&args(&["share:stream", "phone"]), &configured).unwrap_err();
+        assert_eq!(
+            "renditions would write to the same file, video [hevc].mp4: give phone a template of its own",
+            error.to_string()
+        );
+        let error = resolve(&args(&["web"]), &configured).unwrap_err();
+        assert_eq!(
+            "rendition web: vp9 is only written to webm, renditions are MP4",
+            error.to_string()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_settings() -> Result<()> {
+        let resolved = renditions("archive,share:stream")?;
+        let run = crate::config::merge(
+            &crate::config::TranscodeSettings {
+                crf: Some(30),
+                film_grain: Some(8),
+                ten_bit: Some(true),
+                ..Default::default()
+            },
+            None,
+            &Default::default(),
+            VideoCodec::Av1,
+        );
+        assert_eq!(run, resolved[0].settings(&run));
+        let stream = resolved[1].settings(&run);
+        assert_eq!(23, stream.crf);
+        assert_eq!(None, stream.film_grain);
+        assert!(!stream.ten_bit);
+
+        let constraints = Constraints {
+            max_level: Some("5.1".parse().map_err(|e: String| eyre!(e))?),
+            ..Default::default()
+        };
+        assert_eq!(constraints, resolved[0].constraints(&constraints));
+        assert_eq!(
+            VideoCodec::H264,
+            resolved[1].constraints(&constraints).codec()
+        );
+        assert_eq!(None, resolved[1].constraints(&constraints).max_level);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scale() -> Result<()> {
+        let resolved = renditions("archive,share:stream")?;
+        assert!(resolved[0].scale_args(2160).is_empty());
+        assert_eq!(vec!["-vf", "scale=-2:720"], resolved[1].scale_args(1080));
+        // smaller sources aren't scaled up
+        assert!(resolved[1].scale_args(576).is_empty());
+        assert_eq!(576, resolved[1].height(576));
+        Ok(())
+    }
+
+    #[test]
+    fn test_violations() -> Result<()> {
+        let resolved = renditions("archive,share:stream")?;
+        // the 1080p H.264 and 1600p HEVC fixtures as outputs
+        let fixtures = fixtures();
+        let mut scaled = fixtures[0].clone();
+        scaled.streams[0].width = Some(1280);
+        scaled.streams[0].height = Some(720);
+        assert!(resolved[1].violations(&scaled, 1080).is_empty());
+        assert_eq!(
+            vec!["it is 1080 pixels high instead of 720"],
+            resolved[1].violations(&fixtures[0], 1080)
+        );
+        assert_eq!(
+            vec![
+                "its video is hevc instead of h264",
+                "it is 1600 pixels high instead of 720"
+            ],
+            resolved[1].violations(&fixtures[1], 1600)
+        );
+        assert_eq!(
+            vec!["its video is h264 instead of av1"],
+            resolved[0].violations(&fixtures[0], 1080)
+        );
+        assert_eq!(
+            vec!["its video is missing instead of av1"],
+            resolved[0].violations(&FfProbe::default(), 1080)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_multi_output_args() {
+        let encode = |args: &str| -> Vec<String> { args.split(' ').map(String::from).collect() };
+        let args = multi_output_args(&[
+            (
+                encode("-y -i in.mkv -c:v libsvtav1 -crf 24 -c:a copy"),
+                Utf8Path::new("a.mp4"),
+            ),
+            (
+                encode("-y -i in.mkv -c:v libx264 -crf 23 -vf scale=-2:720 -c:a copy"),
+                Utf8Path::new("b.mp4"),
+            ),
+        ]);
+        assert_eq!(
+            encode(
+                "-y -i in.mkv -progress - -nostats \
+                 -c:v libsvtav1 -crf 24 -c:a copy a.mp4 \
+                 -c:v libx264 -crf 23 -vf scale=-2:720 -c:a copy b.mp4"
+            ),
+            args
+        );
+        assert!(multi_output_args(&[]).is_empty());
+    }
+}
@@ -0,0 +1,205 @@
+use camino::Utf8PathBuf;
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+use crate::audio::{AudioDecision, AudioOptions, AudioTrack};
+use crate::config::EncodeSettings;
+use crate::constraints::Constraints;
+use crate::encoder_rules::Encoder;
+use crate::transcode::GpuMode;
+
+/// What was done with one audio track.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AudioMapping {
+    pub track: AudioTrack,
+    pub decision: AudioDecision,
+}
+
+/// Everything that decides how a file is encoded, once the command line, the
+/// config file, the directory override and the encoder rules were combined.
+/// Stored with the file at encode time so that `transcode --repeat-options`
+/// can encode it the same way again.
+///
+/// Records written by newer versions may have fields this one doesn't know,
+/// which are ignored.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ResolvedOptions {
+    /// Version of the transcoder that resolved the options.
+    pub version: String,
+    pub encoder: Encoder,
+    pub settings: EncodeSettings,
+    #[serde(default)]
+    pub constraints: Constraints,
+    pub audio: AudioOptions,
+    #[serde(default)]
+    pub audio_tracks: Vec<AudioMapping>,
+    /// The `.transcoder.toml` the settings came from, if any.
+    pub directory_override: Option<Utf8PathBuf>,
+    pub encoder_rule: Option<String>,
+    /// The arguments ffmpeg was run with, recorded for `--reproducible` encodes
+    /// so that they can be replayed by hand.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ffmpeg_args: Option<Vec<String>>,
+}
+
+impl ResolvedOptions {
+    pub fn new(
+        gpu: Option<&GpuMode>,
+        settings: &EncodeSettings,
+        constraints: &Constraints,
+        audio: &AudioOptions,
+        audio_decisions: &[(AudioTrack, AudioDecision)],
+    ) -> Self {
+        ResolvedOptions {
+            version: env!("CARGO_PKG_VERSION").into(),
+            encoder: Encoder::from_gpu(gpu),
+            settings: settings.clone(),
+            constraints: constraints.clone(),
+            audio: audio.clone(),
+            audio_tracks: audio_decisions
+                .iter()
+                .map(|(track, decision)| AudioMapping {
+                    track: track.clone(),
+                    decision: decision.clone(),
+                })
+                .collect(),
+            directory_override: None,
+            encoder_rule: None,
+            ffmpeg_args: None,
+        }
+    }
+
+    pub fn gpu(&self) -> Option<GpuMode> {
+        self.encoder.gpu()
+    }
+
+    pub fn audio_decisions(&self) -> Vec<(AudioTrack, AudioDecision)> {
+        self.audio_tracks
+            .iter()
+            .map(|mapping| (mapping.track.clone(), mapping.decision.clone()))
+            .collect()
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        Ok(serde_json::from_str(json)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::VideoCodec;
+    use crate::speed::Preset;
+
+    fn options() -> ResolvedOptions {
+        let mut options = ResolvedOptions::new(
+            Some(&GpuMode::Nvidia),
+            &EncodeSettings {
+                crf: 30,
+                speed: "3".parse().unwrap(),
+                effort: None,
+                film_grain: Some(8),
+                ten_bit: true,
+                max_fps: Some(29.97),
+                reproducible: false,
+                low_memory: None,
+            },
+            &Constraints {
+                max_level: Some("5.1".parse().unwrap()),
+                profile: Some(crate::constraints::Profile::Main),
+                video: None,
+            },
+            &AudioOptions {
+                reencode_above: Some(256_000),
+                codec: "aac".into(),
+                bitrate: 160_000,
+                drop: false,
+                drop_commentary: false,
+                allowed_codecs: vec![],
+            },
+            &[
+                (
+                    AudioTrack {
+                        index: 0,
+                        codec: "flac".into(),
+                        bitrate: None,
+                        channels: Some(2),
+                        title: None,
+                        default: false,
+                        commentary: false,
+                    },
+                    AudioDecision::Encode {
+                        codec: "aac".into(),
+                        bitrate: 160_000,
+                    },
+                ),
+                (
+                    AudioTrack {
+                        index: 1,
+                        codec: "ac3".into(),
+                        bitrate: Some(192_000),
+                        channels: Some(6),
+                        title: None,
+                        default: false,
+                        commentary: false,
+                    },
+                    AudioDecision::Copy,
+                ),
+            ],
+        );
+        options.directory_override = Some("/videos/.transcoder.toml".into());
+        options.encoder_rule = Some("screencasts".into());
+        options
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let options = options();
+        let json = options.to_json()?;
+        assert_eq!(options, ResolvedOptions::from_json(&json)?);
+        assert_eq!(Some(GpuMode::Nvidia), options.gpu());
+        assert_eq!(2, options.audio_decisions().len());
+
+        let value: serde_json::Value = serde_json::from_str(&json)?;
+        assert_eq!("nvidia", value["encoder"]);
+        assert_eq!(30, value["settings"]["crf"]);
+        assert_eq!(3, value["settings"]["speed"]);
+        assert_eq!("5.1", value["constraints"]["max-level"]);
+        assert_eq!("copy", value["audio-tracks"][1]["decision"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_fields() -> Result<()> {
+        let mut value = serde_json::to_value(options())?;
+        value["tonemap"] = "hable".into();
+        value["settings"]["lookahead"] = 40.into();
+        value["audio-tracks"][0]["language"] = "eng".into();
+        let options = ResolvedOptions::from_json(&value.to_string())?;
+        assert_eq!(30, options.settings.crf);
+
+        // fields added after the first version may be missing in old records
+        let old = r#"{
+            "version": "0.1.3",
+            "encoder": "cpu",
+            "settings": {"crf": 24, "effort": 7, "film-grain": null, "ten-bit": false, "max-fps": null},
+            "audio": {"reencode-above": null, "codec": "aac", "bitrate": 160000}
+        }"#;
+        let options = ResolvedOptions::from_json(old)?;
+        assert_eq!(None, options.gpu());
+        // the effort of a record from before the speed scale is used as it was
+        assert_eq!(Some(7), options.settings.effort);
+        assert_eq!(
+            Preset::Svt(7),
+            options.settings.preset(None, VideoCodec::Av1)
+        );
+        assert_eq!(Constraints::default(), options.constraints);
+        assert!(options.audio_tracks.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,371 @@
+use std::fmt;
+use std::sync::Arc;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use human_repr::{HumanCount, HumanDuration};
+
+/// How often a running encoder is sampled.
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The CPU time and memory of a process at one point.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Sample {
+    /// User and system time of all threads so far.
+    pub cpu_time: Duration,
+    /// The most memory the process had resident so far, in bytes.
+    pub peak_rss: u64,
+}
+
+/// Reads the resource usage of a running process.
+pub trait ProcessProbe: Send + Sync {
+    /// `None` when the process is gone or the platform isn't supported.
+    fn sample(&self, pid: u32) -> Option<Sample>;
+}
+
+/// For platforms where the usage isn't collected.
+#[derive(Debug)]
+pub struct NoProbe;
+
+impl ProcessProbe for NoProbe {
+    fn sample(&self, _pid: u32) -> Option<Sample> {
+        None
+    }
+}
+
+/// Reads `/proc/<pid>/stat` and `/proc/<pid>/status`.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+pub struct ProcProbe {
+    ticks_per_second: u64,
+}
+
+#[cfg(target_os = "linux")]
+impl ProcProbe {
+    pub fn new() -> Option<Self> {
+        let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+        (ticks > 0).then_some(ProcProbe {
+            ticks_per_second: ticks as u64,
+        })
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl ProcessProbe for ProcProbe {
+    fn sample(&self, pid: u32) -> Option<Sample> {
+        let stat = std::fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+        // the status of a process that exited has no memory lines anymore
+        let status = std::fs::read_to_string(format!("/proc/{pid}/status")).unwrap_or_default();
+        Some(Sample {
+            cpu_time: parse_cpu_time(&stat, self.ticks_per_second)?,
+            peak_rss: parse_peak_rss(&status).unwrap_or(0),
+        })
+    }
+}
+
+/// The probe for this platform.
+pub fn probe() -> Arc<dyn ProcessProbe> {
+    #[cfg(target_os = "linux")]
+    if let Some(probe) = ProcProbe::new() {
+        return Arc::new(probe);
+    }
+    Arc::new(NoProbe)
+}
+
+/// Reads the user and system time from the contents of `/proc/<pid>/stat`. The
+/// command name in parentheses may contain spaces and parentheses itself, so the
+/// fields are counted from the last `)`.
+fn parse_cpu_time(stat: &str, ticks_per_second: u64) -> Option<Duration> {
+    let (_, fields) = stat.rsplit_once(')')?;
+    let fields: Vec<&str> = fields.split_whitespace().collect();
+    // utime and stime are fields 14 and 15, the state is field 3
+    let user: u64 = fields.get(11)?.parse().ok()?;
+    let system: u64 = fields.get(12)?.parse().ok()?;
+    Some(Duration::from_secs_f64(
+        (user + system) as f64 / ticks_per_second as f64,
+    ))
+}
+
+/// Reads the peak resident set size, `VmHWM`, from the contents of
+/// `/proc/<pid>/status`.
+fn parse_peak_rss(status: &str) -> Option<u64> {
+    let value = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))?
+        .trim();
+    let kilobytes: u64 = value
+        .strip_suffix("kB")
+        .unwrap_or(value)
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kilobytes * 1024)
+}
+
+/// What one encode used.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ResourceUsage {
+    pub cpu_time: Duration,
+    pub peak_rss: u64,
+    pub wall_time: Duration,
+}
+
+impl ResourceUsage {
+    /// The CPU time per wall time in percent, above 100 when several cores
+    /// were busy.
+    pub fn cpu_percent(&self) -> f64 {
+        if self.wall_time.is_zero() {
+            0.0
+        } else {
+            self.cpu_time.as_secs_f64() / self.wall_time.as_secs_f64() * 100.0
+        }
+    }
+
+    /// Adds the usage of another process of the same encode, e.g. the next
+    /// segment of a `--resumable` encode.
+    pub fn add(&mut self, other: &ResourceUsage) {
+        self.cpu_time += other.cpu_time;
+        self.peak_rss = self.peak_rss.max(other.peak_rss);
+        self.wall_time += other.wall_time;
+    }
+}
+
+/// The samples of one process. CPU time and peak memory only grow, so the
+/// largest values seen are the usage.
+#[derive(Debug, Default)]
+struct Samples {
+    latest: Option<Sample>,
+}
+
+impl Samples {
+    fn add(&mut self, sample: Sample) {
+        let latest = self.latest.get_or_insert_default();
+        latest.cpu_time = latest.cpu_time.max(sample.cpu_time);
+        latest.peak_rss = latest.peak_rss.max(sample.peak_rss);
+    }
+
+    fn usage(&self, wall_time: Duration) -> Option<ResourceUsage> {
+        self.latest.map(|sample| ResourceUsage {
+            cpu_time: sample.cpu_time,
+            peak_rss: sample.peak_rss,
+            wall_time,
+        })
+    }
+}
+
+/// Samples a process on a background thread until [`Monitor::finish`] or until
+/// it's dropped, so the thread never outlives the encode.
+pub struct Monitor {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<Samples>>,
+    started: Instant,
+}
+
+impl Monitor {
+    pub fn start(probe: Arc<dyn ProcessProbe>, pid: u32, interval: Duration) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let mut samples = Samples::default();
+            loop {
+                if let Some(sample) = probe.sample(pid) {
+                    samples.add(sample);
+                }
+                match stopped.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    _ => break,
+                }
+            }
+            if let Some(sample) = probe.sample(pid) {
+                samples.add(sample);
+            }
+            samples
+        });
+        Monitor {
+            stop: Some(stop),
+            thread: Some(thread),
+            started: Instant::now(),
+        }
+    }
+
+    fn stop(&mut self) -> Option<Samples> {
+        drop(self.stop.take());
+        self.thread.take()?.join().ok()
+    }
+
+    /// Takes a last sample and returns the usage, `None` if the process couldn't
+    /// be sampled at all. Has to be called before the process is waited for,
+    /// while its CPU time can still be read.
+    pub fn finish(mut self) -> Option<ResourceUsage> {
+        let wall_time = self.started.elapsed();
+        self.stop()?.usage(wall_time)
+    }
+}
+
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// The resource usage of all encodes of a run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UsageSummary {
+    pub encodes: usize,
+    pub cpu_time: Duration,
+    /// The largest peak memory of a single encode.
+    pub peak_rss: u64,
+    pub wall_time: Duration,
+}
+
+impl UsageSummary {
+    /// Sums up the usage of the encodes of a run that took `wall_time`, `None`
+    /// when none was collected.
+    pub fn new(usages: &[ResourceUsage], wall_time: Duration) -> Option<Self> {
+        (!usages.is_empty()).then(|| UsageSummary {
+            encodes: usages.len(),
+            cpu_time: usages.iter().map(|usage| usage.cpu_time).sum(),
+            peak_rss: usages.iter().map(|usage| usage.peak_rss).max().unwrap_or(0),
+            wall_time,
+        })
+    }
+
+    /// How many cores were busy on average over the run.
+    pub fn average_cores(&self) -> f64 {
+        if self.wall_time.is_zero() {
+            0.0
+        } else {
+            self.cpu_time.as_secs_f64() / self.wall_time.as_secs_f64()
+        }
+    }
+}
+
+impl fmt::Display for UsageSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of CPU time for {} encodes, average {:.1} CPU-cores utilized, at most {} of memory per encode",
+            self.cpu_time.human_duration(),
+            self.encodes,
+            self.average_cores(),
+            self.peak_rss.human_count_bytes()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_cpu_time() {
+        let stat = "4242 (ffmpeg (x) 2) R 1 4242 4242 0 -1 4194304 51 0 0 0 1250 250 0 0 20 0 9 0 1000 1 1 18446744073709551615";
+        assert_eq!(Some(Duration::from_secs(15)), parse_cpu_time(stat, 100));
+        assert_eq!(None, parse_cpu_time("4242 (ffmpeg) R 1", 100));
+        assert_eq!(None, parse_cpu_time("", 100));
+    }
+
+    #[test]
+    fn test_parse_peak_rss() {
+        let status = "Name:\tffmpeg\nVmPeak:\t 4000000 kB\nVmHWM:\t  2048 kB\nVmRSS:\t  1024 kB\n";
+        assert_eq!(Some(2048 * 1024), parse_peak_rss(status));
+        assert_eq!(None, parse_peak_rss("Name:\tffmpeg\nState:\tZ (zombie)\n"));
+    }
+
+    #[test]
+    fn test_samples() {
+        let mut samples = Samples::default();
+        assert_eq!(None, samples.usage(Duration::from_secs(1)));
+        samples.add(Sample {
+            cpu_time: Duration::from_secs(10),
+            peak_rss: 2000,
+        });
+        // the last sample of an exited process has no memory
+        samples.add(Sample {
+            cpu_time: Duration::from_secs(40),
+            peak_rss: 0,
+        });
+        let usage = samples.usage(Duration::from_secs(10)).unwrap();
+        assert_eq!(Duration::from_secs(40), usage.cpu_time);
+        assert_eq!(2000, usage.peak_rss);
+        assert_eq!(400.0, usage.cpu_percent());
+
+        let mut total = usage;
+        total.add(&ResourceUsage {
+            cpu_time: Duration::from_secs(20),
+            peak_rss: 1000,
+            wall_time: Duration::from_secs(10),
+        });
+        assert_eq!(300.0, total.cpu_percent());
+        assert_eq!(2000, total.peak_rss);
+    }
+
+    #[test]
+    fn test_summary() {
+        let usage = |cpu: u64, peak_rss: u64| ResourceUsage {
+            cpu_time: Duration::from_secs(cpu),
+            peak_rss,
+            wall_time: Duration::from_secs(60),
+        };
+        assert_eq!(None, UsageSummary::new(&[], Duration::from_secs(60)));
+        let summary = UsageSummary::new(
+            &[usage(600, 1_000_000_000), usage(252, 3_000_000_000)],
+            Duration::from_secs(60),
+        )
+        .unwrap();
+        assert!((summary.average_cores() - 14.2).abs() < 1e-9);
+        assert_eq!(3_000_000_000, summary.peak_rss);
+        assert!(
+            summary
+                .to_string()
+                .contains("for 2 encodes, average 14.2 CPU-cores utilized"),
+            "{summary}"
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_proc_probe() {
+        let probe = ProcProbe::new().unwrap();
+        let sample = probe.sample(std::process::id()).unwrap();
+        assert!(sample.peak_rss > 0);
+        assert_eq!(None, probe.sample(u32::MAX));
+    }
+
+    /// Counts the samples and reports more CPU time each time.
+    struct FakeProbe(Mutex<u64>);
+
+    impl ProcessProbe for FakeProbe {
+        fn sample(&self, _pid: u32) -> Option<Sample> {
+            let mut count = self.0.lock().unwrap();
+            *count += 1;
+            Some(Sample {
+                cpu_time: Duration::from_secs(*count),
+                peak_rss: 100,
+            })
+        }
+    }
+
+    #[test]
+    fn test_monitor() {
+        let probe = Arc::new(FakeProbe(Mutex::new(0)));
+        let monitor = Monitor::start(probe.clone(), 1, Duration::from_millis(1));
+        thread::sleep(Duration::from_millis(20));
+        let usage = monitor.finish().unwrap();
+        let samples = *probe.0.lock().unwrap();
+        assert!(samples > 1);
+        assert_eq!(Duration::from_secs(samples), usage.cpu_time);
+
+        // dropping the monitor stops the thread as well
+        let monitor = Monitor::start(probe.clone(), 1, Duration::from_secs(3600));
+        drop(monitor);
+        assert_eq!(samples + 2, *probe.0.lock().unwrap());
+
+        assert_eq!(
+            None,
+            Monitor::start(Arc::new(NoProbe), 1, Duration::from_millis(1)).finish()
+        );
+    }
+}
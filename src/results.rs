@@ -0,0 +1,557 @@
+use std::fs;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::bail;
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::Result;
+use crate::database::{Database, TranscodeFile, TranscodeStatus};
+use crate::paths;
+use crate::renditions::RenditionOutput;
+use crate::source_changes::SourceChange;
+
+/// Version of the format of [`ResultsDump`]. Results of version 1 have the
+/// file's rowid, which `VACUUM` may have given to another file since.
+pub const DUMP_VERSION: u32 = 2;
+
+/// How long to wait before each attempt to write the buffered results once the
+/// run is over, about two minutes together.
+pub const RETRY_DELAYS: [Duration; 7] = [
+    Duration::from_secs(1),
+    Duration::from_secs(2),
+    Duration::from_secs(4),
+    Duration::from_secs(8),
+    Duration::from_secs(16),
+    Duration::from_secs(32),
+    Duration::from_secs(64),
+];
+
+/// The outcome of a file that is written to the database when it is done.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct FileResult {
+    /// The file's id, dumps of version 1 have its rowid.
+    #[serde(alias = "rowid")]
+    pub id: i64,
+    pub path: Utf8PathBuf,
+    pub status: TranscodeStatus,
+    pub output_path: Option<Utf8PathBuf>,
+    pub error_message: Option<String>,
+    /// What changed in the source since it was scanned, for failures.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub source_changes: Vec<SourceChange>,
+    /// The outputs of a file transcoded with `--renditions`, `output_path` is
+    /// the first of them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub renditions: Vec<RenditionOutput>,
+}
+
+impl FileResult {
+    pub fn transcoded(id: i64, path: &Utf8Path, output_path: &Utf8Path) -> Self {
+        FileResult {
+            id,
+            path: path.to_owned(),
+            status: TranscodeStatus::Success,
+            output_path: Some(output_path.to_owned()),
+            error_message: None,
+            source_changes: vec![],
+            renditions: vec![],
+        }
+    }
+
+    /// A file encoded with `--no-finalize`, whose output waits for `finalize`.
+    pub fn encoded(id: i64, path: &Utf8Path, output_path: &Utf8Path) -> Self {
+        FileResult {
+            status: TranscodeStatus::Encoded,
+            ..FileResult::transcoded(id, path, output_path)
+        }
+    }
+
+    /// Puts a file that failed only because its storage was gone back into the
+    /// queue.
+    pub fn requeued(id: i64, path: &Utf8Path) -> Self {
+        FileResult {
+            id,
+            path: path.to_owned(),
+            status: TranscodeStatus::Pending,
+            output_path: None,
+            error_message: None,
+            source_changes: vec![],
+            renditions: vec![],
+        }
+    }
+
+    pub fn failed(id: i64, path: &Utf8Path, error_message: String) -> Self {
+        FileResult {
+            id,
+            path: path.to_owned(),
+            status: TranscodeStatus::Error,
+            output_path: None,
+            error_message: Some(error_message),
+            source_changes: vec![],
+            renditions: vec![],
+        }
+    }
+}
+
+/// Where the results of files are written, the database outside of tests.
+pub trait ResultWriter: Send + Sync {
+    fn write(&self, result: &FileResult) -> Result<()>;
+}
+
+impl ResultWriter for Database {
+    fn write(&self, result: &FileResult) -> Result<()> {
+        self.record_result(result)
+    }
+}
+
+/// Results that couldn't be written to the database, as written next to it by
+/// [`ResultBuffer::flush`] and read by `import --results-only`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ResultsDump {
+    pub version: u32,
+    pub written_at: Timestamp,
+    pub results: Vec<FileResult>,
+}
+
+impl ResultsDump {
+    pub fn read(path: &Utf8Path) -> Result<Self> {
+        let dump: ResultsDump = serde_json::from_str(&fs::read_to_string(path)?)?;
+        if dump.version > DUMP_VERSION {
+            bail!(
+                "{path} was written by a newer version of the transcoder (format {}, this one reads up to {DUMP_VERSION})",
+                dump.version
+            );
+        }
+        Ok(dump)
+    }
+
+    pub fn write(&self, path: &Utf8Path) -> Result<()> {
+        paths::write_atomically(path, serde_json::to_string_pretty(self)?.as_bytes())
+    }
+}
+
+/// The file next to the database that results are dumped to.
+pub fn dump_path(database: &Utf8Path, now: Timestamp) -> Utf8PathBuf {
+    format!("{database}.results-{}.json", now.as_second()).into()
+}
+
+/// What [`ResultBuffer::flush`] did with the buffered results.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Flushed {
+    /// No results were buffered.
+    Nothing,
+    /// The database recovered and took this many results.
+    Written(usize),
+    /// The database didn't recover, this many results are in the file.
+    Dumped { count: usize, path: Utf8PathBuf },
+}
+
+/// Writes the results of files as they finish. After the first failed write,
+/// results are kept in memory until the run is over and [`ResultBuffer::flush`]
+/// tries again.
+pub struct ResultBuffer {
+    writer: Box<dyn ResultWriter>,
+    failed: AtomicBool,
+    buffered: Mutex<Vec<FileResult>>,
+    delays: Vec<Duration>,
+}
+
+impl ResultBuffer {
+    pub fn new(writer: Box<dyn ResultWriter>) -> Self {
+        ResultBuffer {
+            writer,
+            failed: AtomicBool::new(false),
+            buffered: Mutex::default(),
+            delays: RETRY_DELAYS.to_vec(),
+        }
+    }
+
+    /// Sets the delays before each attempt of [`ResultBuffer::flush`].
+    #[cfg(test)]
+    pub fn with_delays(self, delays: Vec<Duration>) -> Self {
+        Self { delays, ..self }
+    }
+
+    /// Whether a write to the database failed during the run.
+    pub fn failed(&self) -> bool {
+        self.failed.load(Ordering::SeqCst)
+    }
+
+    /// Marks the database as failed, so that results are only buffered from now
+    /// on. Returns whether it hadn't failed before.
+    pub fn fail(&self) -> bool {
+        !self.failed.swap(true, Ordering::SeqCst)
+    }
+
+    /// Writes the result, or buffers it when the database failed before. The
+    /// result is also buffered when the write fails, the error is returned so
+    /// the caller can stop the run.
+    pub fn record(&self, result: FileResult) -> Result<()> {
+        if self.failed() {
+            self.buffered.lock().unwrap().push(result);
+            return Ok(());
+        }
+        self.writer.write(&result).inspect_err(|_| {
+            self.buffered.lock().unwrap().push(result.clone());
+        })
+    }
+
+    /// Writes the buffered results, trying again after each of the delays. When
+    /// the database doesn't recover, the results that are left are dumped to
+    /// `dump`.
+    pub fn flush(&self, dump: &Utf8Path) -> Result<Flushed> {
+        let mut buffered = self.buffered.lock().unwrap();
+        if buffered.is_empty() {
+            return Ok(Flushed::Nothing);
+        }
+        let count = buffered.len();
+        for (attempt, delay) in self.delays.iter().enumerate() {
+            thread::sleep(*delay);
+            let mut error = None;
+            buffered.retain(|result| match self.writer.write(result) {
+                Ok(()) => false,
+                Err(e) => {
+                    error.get_or_insert(e);
+                    true
+                }
+            });
+            match error {
+                None => return Ok(Flushed::Written(count)),
+                Some(e) => warn!(
+                    "Attempt {} of {} to write {} results to the database failed: {e}",
+                    attempt + 1,
+                    self.delays.len(),
+                    buffered.len()
+                ),
+            }
+        }
+        let results = std::mem::take(&mut *buffered);
+        let count = results.len();
+        ResultsDump {
+            version: DUMP_VERSION,
+            written_at: Timestamp::now(),
+            results,
+        }
+        .write(dump)?;
+        Ok(Flushed::Dumped {
+            count,
+            path: dump.to_owned(),
+        })
+    }
+}
+
+/// Writes the results of a dump to the database. Files are found by their id,
+/// as long as they still have the path of the result, by their path otherwise.
+/// Results of files that are no longer in the database are left out. Returns
+/// how many were written.
+pub fn import_results(database: &Database, dump: &ResultsDump) -> Result<usize> {
+    let mut imported = 0;
+    for result in &dump.results {
+        let Some(file) = find_file(database, dump.version, result)? else {
+            warn!(
+                "{} is no longer in the database, not importing its result",
+                result.path
+            );
+            continue;
+        };
+        info!("{}: importing the result {}", result.path, result.status);
+        database.record_result(&FileResult {
+            id: file.id,
+            ..result.clone()
+        })?;
+        imported += 1;
+    }
+    Ok(imported)
+}
+
+fn find_file(
+    database: &Database,
+    version: u32,
+    result: &FileResult,
+) -> Result<Option<TranscodeFile>> {
+    if version >= 2
+        && let Some(file) = database.get(result.id)?
+        && file.path == result.path
+    {
+        return Ok(Some(file));
+    }
+    database.get_by_path(&result.path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+
+    use color_eyre::eyre::eyre;
+
+    use super::*;
+    use crate::database::NewTranscodeFile;
+    use crate::ffprobe::FfProbe;
+
+    /// Fails all writes after the first `working` ones, until `recover_after`
+    /// more writes were attempted.
+    #[derive(Default)]
+    struct FlakyWriter {
+        working: usize,
+        recover_after: Option<usize>,
+        attempts: AtomicUsize,
+        written: Mutex<Vec<FileResult>>,
+    }
+
+    impl ResultWriter for Arc<FlakyWriter> {
+        fn write(&self, result: &FileResult) -> Result<()> {
+            let attempt = self.attempts.fetch_add(1, Ordering::SeqCst);
+            let failing = attempt >= self.working
+                && self
+                    .recover_after
+                    .is_none_or(|after| attempt < self.working + after);
+            if failing {
+                return Err(eyre!("disk I/O error"));
+            }
+            self.written.lock().unwrap().push(result.clone());
+            Ok(())
+        }
+    }
+
+    fn result(id: i64) -> FileResult {
+        FileResult::transcoded(
+            id,
+            format!("/videos/{id}.mkv").as_str().into(),
+            format!("/videos/{id}_av1.mp4").as_str().into(),
+        )
+    }
+
+    fn buffer(writer: FlakyWriter) -> (Arc<FlakyWriter>, ResultBuffer) {
+        let writer = Arc::new(writer);
+        let buffer =
+            ResultBuffer::new(Box::new(writer.clone())).with_delays(vec![Duration::ZERO; 3]);
+        (writer, buffer)
+    }
+
+    #[test]
+    fn test_no_failure() -> Result<()> {
+        let (writer, buffer) = buffer(FlakyWriter {
+            working: usize::MAX,
+            ..Default::default()
+        });
+        buffer.record(result(1))?;
+        buffer.record(result(2))?;
+        assert_eq!(2, writer.written.lock().unwrap().len());
+        assert_eq!(
+            Flushed::Nothing,
+            buffer.flush("/nonexistent/dump.json".into())?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_escalation() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let dump = Utf8Path::from_path(tempdir.path())
+            .unwrap()
+            .join("t.db.results-1.json");
+        let (writer, buffer) = buffer(FlakyWriter {
+            working: 1,
+            ..Default::default()
+        });
+        buffer.record(result(1))?;
+        // the failed write is returned so the run stops, and its result kept
+        assert!(buffer.record(result(2)).is_err());
+        assert!(buffer.fail());
+        assert!(!buffer.fail());
+        // later results aren't even tried
+        buffer.record(result(3))?;
+        assert_eq!(2, writer.attempts.load(Ordering::SeqCst));
+
+        // one attempt per delay, then the results go to the file
+        let flushed = buffer.flush(&dump)?;
+        assert_eq!(
+            Flushed::Dumped {
+                count: 2,
+                path: dump.clone()
+            },
+            flushed
+        );
+        assert_eq!(8, writer.attempts.load(Ordering::SeqCst));
+        assert_eq!(vec![result(1)], *writer.written.lock().unwrap());
+        let dumped = ResultsDump::read(&dump)?;
+        assert_eq!(vec![result(2), result(3)], dumped.results);
+        Ok(())
+    }
+
+    #[test]
+    fn test_recovery() -> Result<()> {
+        let (writer, buffer) = buffer(FlakyWriter {
+            working: 0,
+            // the first attempt of the flush fails for both results, the
+            // second for one of them
+            recover_after: Some(4),
+            ..Default::default()
+        });
+        assert!(buffer.record(result(1)).is_err());
+        buffer.fail();
+        buffer.record(result(2))?;
+        assert_eq!(
+            Flushed::Written(2),
+            buffer.flush("/nonexistent/dump.json".into())?
+        );
+        assert_eq!(vec![result(2), result(1)], *writer.written.lock().unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn test_dump_format() -> Result<()> {
+        let dump = ResultsDump {
+            version: DUMP_VERSION,
+            written_at: "2026-10-16T12:00:00Z".parse()?,
+            results: vec![
+                result(1),
+                FileResult::failed(2, "/videos/2.mkv".into(), "ffmpeg failed".into()),
+            ],
+        };
+        let json: serde_json::Value = serde_json::to_value(&dump)?;
+        assert_eq!(
+            serde_json::json!({
+                "version": 2,
+                "written-at": "2026-10-16T12:00:00Z",
+                "results": [
+                    {
+                        "id": 1,
+                        "path": "/videos/1.mkv",
+                        "status": "success",
+                        "output-path": "/videos/1_av1.mp4",
+                        "error-message": null
+                    },
+                    {
+                        "id": 2,
+                        "path": "/videos/2.mkv",
+                        "status": "error",
+                        "output-path": null,
+                        "error-message": "ffmpeg failed"
+                    }
+                ]
+            }),
+            json
+        );
+
+        let tempdir = tempfile::tempdir()?;
+        let path = Utf8Path::from_path(tempdir.path())
+            .unwrap()
+            .join("dump.json");
+        dump.write(&path)?;
+        assert_eq!(dump, ResultsDump::read(&path)?);
+        let newer = ResultsDump {
+            version: DUMP_VERSION + 1,
+            ..dump
+        };
+        newer.write(&path)?;
+        assert!(ResultsDump::read(&path).is_err());
+
+        assert_eq!(
+            Utf8PathBuf::from("/data/transcoder.db.results-1760616000.json"),
+            dump_path(
+                "/data/transcoder.db".into(),
+                "2025-10-16T12:00:00Z".parse()?
+            )
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_import() -> Result<()> {
+        let database = Database::in_memory()?;
+        database.insert(NewTranscodeFile {
+            path: "/videos/1.mkv".into(),
+            file_size: 1000,
+            ffprobe_info: FfProbe::default(),
+        })?;
+        let id = database.list()?[0].id;
+        let dump = ResultsDump {
+            version: DUMP_VERSION,
+            written_at: Timestamp::now(),
+            // ids of files that aren't in the database are looked up by path
+            results: vec![
+                FileResult {
+                    id: 99,
+                    ..result(1)
+                },
+                result(2),
+            ],
+        };
+        assert_eq!(1, import_results(&database, &dump)?);
+        let file = database.get_by_path("/videos/1.mkv".into())?.unwrap();
+        assert_eq!(id, file.id);
+        assert_eq!(TranscodeStatus::Success, file.status);
+        assert_eq!(Some("/videos/1_av1.mp4".into()), file.output_path);
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_round_trip() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let directory = Utf8Path::from_path(tempdir.path()).unwrap();
+        let database = Database::open(&directory.join("transcoder.db"))?;
+        let files: Vec<_> = (1..=3)
+            .map(|index| NewTranscodeFile {
+                path: format!("/videos/{index}.mkv").into(),
+                file_size: 1000,
+                ffprobe_info: FfProbe::default(),
+            })
+            .collect();
+        database.insert_batch(&files)?;
+        let id = |path: &str| -> Result<i64> { Ok(database.get_by_path(path.into())?.unwrap().id) };
+        let (second, third) = (id("/videos/2.mkv")?, id("/videos/3.mkv")?);
+        let dump = ResultsDump {
+            version: DUMP_VERSION,
+            written_at: Timestamp::now(),
+            results: vec![result(second), result(third)],
+        };
+        let path = directory.join("dump.json");
+        dump.write(&path)?;
+
+        // the database changes before the dump is imported
+        database.forget(&crate::database::FileFilter {
+            path_contains: Some("/1.mkv".into()),
+            ..Default::default()
+        })?;
+        database.compact(false)?;
+        database.merge_duplicates(third, "/videos/3 (moved).mkv".into(), &[])?;
+
+        let dump = ResultsDump::read(&path)?;
+        assert_eq!(1, import_results(&database, &dump)?);
+        let file = database.get(second)?.unwrap();
+        assert_eq!("/videos/2.mkv", file.path);
+        assert_eq!(TranscodeStatus::Success, file.status);
+        // a file that has another path now isn't given the result
+        let moved = database.get(third)?.unwrap();
+        assert_eq!(TranscodeStatus::Pending, moved.status);
+
+        // dumps from before there were ids are imported by path
+        let old = serde_json::json!({
+            "version": 1,
+            "written-at": "2026-10-16T12:00:00Z",
+            "results": [{
+                "rowid": third,
+                "path": "/videos/3 (moved).mkv",
+                "status": "error",
+                "output-path": null,
+                "error-message": "ffmpeg failed"
+            }]
+        });
+        fs::write(&path, old.to_string())?;
+        let dump = ResultsDump::read(&path)?;
+        assert_eq!(third, dump.results[0].id);
+        assert_eq!(1, import_results(&database, &dump)?);
+        assert_eq!(TranscodeStatus::Error, database.get(third)?.unwrap().status);
+        Ok(())
+    }
+}
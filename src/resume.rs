@@ -0,0 +1,722 @@
+use std::fs;
+use std::io::ErrorKind;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::bail;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use crate::Result;
+use crate::binaries::{self, Binary};
+use crate::ffprobe::commandline_error;
+
+/// Length of the segments a resumable encode is written in. An interruption loses
+/// at most the segment that was being written.
+pub const SEGMENT_SECONDS: f64 = 60.0;
+
+const STATE_FILE: &str = "state.json";
+const CONCAT_LIST: &str = "concat.txt";
+
+/// How far apart the end of one segment and the start of the next may be. A join
+/// is exact up to about a frame.
+const JOIN_TOLERANCE: f64 = 0.1;
+
+/// How much the joined output may differ from the source's duration, on top of
+/// the joins. Audio packets don't end exactly where the video does.
+const DURATION_TOLERANCE: f64 = 0.5;
+
+/// One run of ffmpeg, starting at `offset` seconds into the source.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Part {
+    offset: f64,
+}
+
+/// The sidecar state of a resumable encode.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct ResumeState {
+    source_size: u64,
+    /// The encoder arguments, so that an encode with other settings starts over.
+    fingerprint: String,
+    parts: Vec<Part>,
+    /// Set once the last part ran to the end of the source.
+    finished: bool,
+}
+
+/// A finished segment, with its start and end in seconds of the source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub path: Utf8PathBuf,
+    pub start: f64,
+    pub end: f64,
+}
+
+/// Parses the csv segment list ffmpeg writes, which has a
+/// `file name,start,end` line for every segment once it's complete. The times are
+/// relative to the part, which starts at `offset` in the source.
+fn parse_segment_list(contents: &str, directory: &Utf8Path, offset: f64) -> Vec<Segment> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.rsplitn(3, ',');
+            let end: f64 = fields.next()?.trim().parse().ok()?;
+            let start: f64 = fields.next()?.trim().parse().ok()?;
+            let name = fields.next()?.trim().trim_matches('"');
+            let name = Utf8Path::new(name).file_name()?;
+            Some(Segment {
+                path: directory.join(name),
+                start: offset + start,
+                end: offset + end,
+            })
+        })
+        .collect()
+}
+
+/// Identifies the encoder settings of `args`, leaving out the input and output,
+/// which contains the process id.
+pub fn fingerprint(args: &[String], input: &Utf8Path, output: &Utf8Path) -> String {
+    args.iter()
+        .filter(|arg| *arg != input.as_str() && *arg != output.as_str())
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The arguments for a part: seeking to `offset` and writing segments with a key
+/// frame at the start of each, plus a list of the finished ones. `args` has to
+/// end with the output path, which is replaced.
+fn part_args(
+    args: &[String],
+    offset: f64,
+    segment_seconds: f64,
+    list: &Utf8Path,
+    pattern: &Utf8Path,
+) -> Vec<String> {
+    let mut args = args.to_vec();
+    args.pop();
+    if offset > 0.0
+        && let Some(input) = args.iter().position(|arg| arg == "-i")
+    {
+        args.splice(input..input, ["-ss".into(), format!("{offset:.6}")]);
+    }
+    args.extend([
+        "-force_key_frames".into(),
+        format!("expr:gte(t,n_forced*{segment_seconds})"),
+        "-f".into(),
+        "segment".into(),
+        "-segment_time".into(),
+        segment_seconds.to_string(),
+        "-segment_format".into(),
+        "matroska".into(),
+        "-reset_timestamps".into(),
+        "1".into(),
+        "-segment_list".into(),
+        list.to_string(),
+        "-segment_list_type".into(),
+        "csv".into(),
+        pattern.to_string(),
+    ]);
+    args
+}
+
+/// The input of ffmpeg's concat demuxer.
+fn concat_list(segments: &[Segment]) -> String {
+    segments
+        .iter()
+        .map(|segment| format!("file '{}'\n", segment.path.as_str().replace('\'', r"'\''")))
+        .collect()
+}
+
+fn concat_args(list: &Utf8Path, output: &Utf8Path) -> Vec<String> {
+    [
+        "-y",
+        "-v",
+        "error",
+        "-f",
+        "concat",
+        "-safe",
+        "0",
+        "-i",
+        list.as_str(),
+        "-map",
+        "0",
+        "-c",
+        "copy",
+        "-movflags",
+        "+faststart",
+        output.as_str(),
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect()
+}
+
+/// Joins the segments in `list` into `output` without encoding them again.
+pub fn concat(list: &Utf8Path, output: &Utf8Path) -> Result<()> {
+    let output = binaries::command(Binary::Ffmpeg)
+        .args(concat_args(list, output))
+        .output()
+        .map_err(|e| binaries::spawn_error(Binary::Ffmpeg, e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(commandline_error("ffmpeg", output))
+    }
+}
+
+/// Checks that the segments cover the source from start to end without gaps or
+/// overlaps.
+fn check_segments(segments: &[Segment], duration: f64) -> Result<()> {
+    let Some(first) = segments.first() else {
+        bail!("the encode didn't produce any segments");
+    };
+    if first.start.abs() > JOIN_TOLERANCE {
+        bail!(
+            "the first segment starts at {:.3}s instead of 0",
+            first.start
+        );
+    }
+    for pair in segments.windows(2) {
+        let gap = pair[1].start - pair[0].end;
+        if gap.abs() > JOIN_TOLERANCE {
+            bail!(
+                "the segments don't join at {:.3}s: {} ends at {:.3}s and {} starts at {:.3}s",
+                pair[0].end,
+                pair[0].path,
+                pair[0].end,
+                pair[1].path,
+                pair[1].start
+            );
+        }
+    }
+    let end = segments.last().map_or(0.0, |s| s.end);
+    if duration > 0.0 && (end - duration).abs() > DURATION_TOLERANCE {
+        bail!("the segments end at {end:.3}s, but the source is {duration:.3}s long");
+    }
+    Ok(())
+}
+
+/// Checks the duration of the joined output against the source's.
+fn check_duration(expected: f64, joined: Option<f64>, parts: usize) -> Result<()> {
+    let Some(joined) = joined else {
+        bail!("the joined output has no duration");
+    };
+    let tolerance = DURATION_TOLERANCE + JOIN_TOLERANCE * parts.saturating_sub(1) as f64;
+    if expected > 0.0 && (joined - expected).abs() > tolerance {
+        bail!("the joined output is {joined:.3}s long, but the source is {expected:.3}s long");
+    }
+    Ok(())
+}
+
+/// Encodes a file in segments that survive the process being interrupted.
+///
+/// Every run of ffmpeg is a part that starts where the previous one stopped and
+/// writes segments to the resume directory, along with a list that ffmpeg appends
+/// a segment to once it's complete. A state file records the parts and the
+/// settings. After an interruption, segments that aren't in a list are removed and
+/// the next part seeks to the end of the last complete segment, which starts with
+/// a key frame. Once the source is encoded to the end, the segments are joined and
+/// the result is checked against the source's duration.
+pub struct ResumableEncode {
+    directory: Utf8PathBuf,
+    source_size: u64,
+    /// Duration of the source in seconds.
+    duration: f64,
+    fingerprint: String,
+    segment_seconds: f64,
+}
+
+impl ResumableEncode {
+    pub fn new(
+        directory: Utf8PathBuf,
+        source_size: u64,
+        duration: f64,
+        fingerprint: String,
+    ) -> Self {
+        ResumableEncode {
+            directory,
+            source_size,
+            duration,
+            fingerprint,
+            segment_seconds: SEGMENT_SECONDS,
+        }
+    }
+
+    fn state_file(&self) -> Utf8PathBuf {
+        self.directory.join(STATE_FILE)
+    }
+
+    fn segment_list(&self, part: usize) -> Utf8PathBuf {
+        self.directory.join(format!("part-{part}.csv"))
+    }
+
+    fn segment_pattern(&self, part: usize) -> Utf8PathBuf {
+        self.directory.join(format!("part-{part}-%05d.mkv"))
+    }
+
+    /// Loads the state of an earlier run. State for other settings or a changed
+    /// source is removed, and a new encode starts with an empty directory.
+    fn load(&self) -> Result<ResumeState> {
+        match fs::read_to_string(self.state_file()) {
+            Ok(contents) => match serde_json::from_str::<ResumeState>(&contents) {
+                Ok(state)
+                    if state.source_size == self.source_size
+                        && state.fingerprint == self.fingerprint =>
+                {
+                    return Ok(state);
+                }
+                Ok(_) => info!(
+                    "{} is for other settings or a changed source, starting over",
+                    self.directory
+                ),
+                Err(e) => warn!("ignoring unreadable state in {}: {e}", self.directory),
+            },
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        self.discard()?;
+        fs::create_dir_all(&self.directory)?;
+        Ok(ResumeState {
+            source_size: self.source_size,
+            fingerprint: self.fingerprint.clone(),
+            parts: vec![],
+            finished: false,
+        })
+    }
+
+    /// Writes the state to a temp file first, so an interruption can't leave
+    /// half of it behind.
+    fn save(&self, state: &ResumeState) -> Result<()> {
+        let tmp = self.directory.join(format!("{STATE_FILE}.tmp"));
+        fs::write(&tmp, serde_json::to_vec_pretty(state)?)?;
+        fs::rename(tmp, self.state_file())?;
+        Ok(())
+    }
+
+    /// The complete segments of all parts, in order.
+    fn segments(&self, state: &ResumeState) -> Result<Vec<Segment>> {
+        let mut segments = vec![];
+        for (index, part) in state.parts.iter().enumerate() {
+            let contents = match fs::read_to_string(self.segment_list(index)) {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+                Err(e) => return Err(e.into()),
+            };
+            segments.extend(parse_segment_list(&contents, &self.directory, part.offset));
+        }
+        Ok(segments)
+    }
+
+    /// Removes the segments that were being written when an earlier run stopped.
+    fn remove_incomplete(&self, segments: &[Segment]) -> Result<()> {
+        for entry in fs::read_dir(&self.directory)? {
+            let path = Utf8PathBuf::try_from(entry?.path())?;
+            if path.extension() == Some("mkv") && !segments.iter().any(|s| s.path == path) {
+                debug!("removing incomplete segment {path}");
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Encodes the part of the source that isn't done yet. `encode` runs ffmpeg
+    /// with the arguments for the part and the offset in seconds it starts at.
+    pub fn encode(
+        &self,
+        args: &[String],
+        encode: impl FnOnce(&[String], f64) -> Result<()>,
+    ) -> Result<()> {
+        let mut state = self.load()?;
+        if state.finished {
+            info!("{} was already encoded completely", self.directory);
+            return Ok(());
+        }
+        let segments = self.segments(&state)?;
+        self.remove_incomplete(&segments)?;
+        let offset = segments.last().map_or(0.0, |s| s.end);
+        if offset > 0.0 {
+            info!(
+                "resuming the encode in {} at {offset:.3}s of {:.3}s",
+                self.directory, self.duration
+            );
+        }
+
+        let index = state.parts.len();
+        state.parts.push(Part { offset });
+        self.save(&state)?;
+        let args = part_args(
+            args,
+            offset,
+            self.segment_seconds,
+            &self.segment_list(index),
+            &self.segment_pattern(index),
+        );
+        encode(&args, offset)?;
+
+        state.finished = true;
+        self.save(&state)
+    }
+
+    /// Joins the segments into `output` and checks that nothing of the source is
+    /// missing or doubled at the joins. `probe_duration` reads the duration of
+    /// the joined file.
+    pub fn finish(
+        &self,
+        output: &Utf8Path,
+        concat: impl FnOnce(&Utf8Path, &Utf8Path) -> Result<()>,
+        probe_duration: impl FnOnce(&Utf8Path) -> Result<Option<f64>>,
+    ) -> Result<()> {
+        let state = self.load()?;
+        if !state.finished {
+            bail!("the encode in {} isn't finished", self.directory);
+        }
+        let segments = self.segments(&state)?;
+        check_segments(&segments, self.duration)?;
+        let list = self.directory.join(CONCAT_LIST);
+        fs::write(&list, concat_list(&segments))?;
+        concat(&list, output)?;
+        check_duration(self.duration, probe_duration(output)?, state.parts.len())
+    }
+
+    /// Removes the segments and the state.
+    pub fn discard(&self) -> Result<()> {
+        match fs::remove_dir_all(&self.directory) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Write;
+
+    use super::*;
+    use crate::ffprobe::ffprobe;
+    use crate::testsupport::{self, Sample};
+
+    /// Pretends to be ffmpeg: writes segments of `segment_seconds` from `offset`
+    /// and lists them, stopping before `crash_at` with a half written segment.
+    fn fake_ffmpeg(
+        args: &[String],
+        offset: f64,
+        duration: f64,
+        crash_at: Option<f64>,
+    ) -> Result<()> {
+        let value = |flag: &str| {
+            let index = args.iter().position(|a| a == flag).unwrap();
+            args[index + 1].clone()
+        };
+        let segment_seconds: f64 = value("-segment_time").parse()?;
+        let list = Utf8PathBuf::from(value("-segment_list"));
+        let pattern = args.last().unwrap();
+        let mut listed = String::new();
+        let mut start = 0.0;
+        for index in 0.. {
+            let path = Utf8PathBuf::from(pattern.replace("%05d", &format!("{index:05}")));
+            fs::write(&path, b"segment")?;
+            let end = (start + segment_seconds).min(duration - offset);
+            if crash_at.is_some_and(|crash_at| offset + end > crash_at) {
+                fs::write(&list, &listed)?;
+                bail!("ffmpeg was killed");
+            }
+            writeln!(listed, "{},{start:.6},{end:.6}", path.file_name().unwrap())?;
+            fs::write(&list, &listed)?;
+            if offset + end >= duration {
+                return Ok(());
+            }
+            start = end;
+        }
+        unreachable!()
+    }
+
+    fn resumable(directory: &Utf8Path, fingerprint: &str) -> ResumableEncode {
+        ResumableEncode {
+            directory: directory.join(".transcoder-resume-1"),
+            source_size: 1000,
+            duration: 25.0,
+            fingerprint: fingerprint.into(),
+            segment_seconds: 10.0,
+        }
+    }
+
+    fn encoder_args(output: &str) -> Vec<String> {
+        ["-y", "-i", "/a.mkv", "-c:v", "libsvtav1", output]
+            .into_iter()
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn test_part_args() {
+        let args = part_args(
+            &encoder_args("/out.mp4"),
+            120.5,
+            60.0,
+            "/r/part-1.csv".into(),
+            "/r/part-1-%05d.mkv".into(),
+        );
+        assert_eq!(
+            vec![
+                "-y",
+                "-ss",
+                "120.500000",
+                "-i",
+                "/a.mkv",
+                "-c:v",
+                "libsvtav1",
+                "-force_key_frames",
+                "expr:gte(t,n_forced*60)",
+                "-f",
+                "segment",
+                "-segment_time",
+                "60",
+                "-segment_format",
+                "matroska",
+                "-reset_timestamps",
+                "1",
+                "-segment_list",
+                "/r/part-1.csv",
+                "-segment_list_type",
+                "csv",
+                "/r/part-1-%05d.mkv",
+            ],
+            args
+        );
+        let first = part_args(
+            &encoder_args("/out.mp4"),
+            0.0,
+            60.0,
+            "/r/part-0.csv".into(),
+            "/r/part-0-%05d.mkv".into(),
+        );
+        assert!(!first.contains(&"-ss".to_string()));
+        assert_eq!(
+            "-y libsvtav1",
+            fingerprint(
+                &["-y", "/a.mkv", "libsvtav1", "/.transcoder-1-100.tmp.mp4"].map(String::from),
+                "/a.mkv".into(),
+                "/.transcoder-1-100.tmp.mp4".into()
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_segment_list() {
+        let segments = parse_segment_list(
+            "part-1-00000.mkv,0.000000,60.000000\n\"/r/part-1-00001.mkv\",60.0,75.5\nbroken\n",
+            "/r".into(),
+            30.0,
+        );
+        assert_eq!(
+            vec![
+                Segment {
+                    path: "/r/part-1-00000.mkv".into(),
+                    start: 30.0,
+                    end: 90.0
+                },
+                Segment {
+                    path: "/r/part-1-00001.mkv".into(),
+                    start: 90.0,
+                    end: 105.5
+                },
+            ],
+            segments
+        );
+        assert_eq!(
+            "file '/r/it'\\''s.mkv'\n",
+            concat_list(&[Segment {
+                path: "/r/it's.mkv".into(),
+                start: 0.0,
+                end: 1.0
+            }])
+        );
+    }
+
+    #[test]
+    fn test_checks() {
+        let segment = |start, end| Segment {
+            path: "/r/s.mkv".into(),
+            start,
+            end,
+        };
+        assert!(check_segments(&[segment(0.0, 10.0), segment(10.04, 25.0)], 25.0).is_ok());
+        assert!(check_segments(&[], 25.0).is_err());
+        // a gap, an overlap, a late start and a missing end
+        assert!(check_segments(&[segment(0.0, 10.0), segment(10.5, 25.0)], 25.0).is_err());
+        assert!(check_segments(&[segment(0.0, 10.0), segment(9.5, 25.0)], 25.0).is_err());
+        assert!(check_segments(&[segment(1.0, 25.0)], 25.0).is_err());
+        assert!(check_segments(&[segment(0.0, 20.0)], 25.0).is_err());
+
+        assert!(check_duration(25.0, Some(25.3), 1).is_ok());
+        assert!(check_duration(25.0, Some(25.65), 3).is_ok());
+        assert!(check_duration(25.0, Some(25.65), 1).is_err());
+        assert!(check_duration(25.0, Some(20.0), 1).is_err());
+        assert!(check_duration(25.0, None, 1).is_err());
+    }
+
+    #[test]
+    fn test_resume_after_crash() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let directory = Utf8Path::from_path(tempdir.path()).unwrap();
+        let encode = resumable(directory, "-crf 24");
+
+        // killed while writing the third segment
+        let result = encode.encode(&encoder_args("/tmp.mp4"), |args, offset| {
+            assert_eq!(0.0, offset);
+            fake_ffmpeg(args, offset, 25.0, Some(22.0))
+        });
+        assert!(result.is_err());
+        assert!(encode.directory.join("part-0-00002.mkv").is_file());
+
+        // the next run starts at the last complete segment
+        let mut resumed_at = None;
+        encode.encode(&encoder_args("/tmp.mp4"), |args, offset| {
+            resumed_at = Some(offset);
+            assert!(args.windows(2).any(|w| w == ["-ss", "20.000000"]));
+            fake_ffmpeg(args, offset, 25.0, None)
+        })?;
+        assert_eq!(Some(20.0), resumed_at);
+        assert!(!encode.directory.join("part-0-00002.mkv").exists());
+
+        // a finished encode isn't run again
+        encode.encode(&encoder_args("/tmp.mp4"), |_, _| panic!("already finished"))?;
+
+        let output = directory.join("out.mp4");
+        encode.finish(
+            &output,
+            |list, output| {
+                let list = fs::read_to_string(list)?;
+                let names: Vec<_> = list
+                    .lines()
+                    .map(|line| line.rsplit('/').next().unwrap())
+                    .collect();
+                assert_eq!(
+                    vec![
+                        "part-0-00000.mkv'",
+                        "part-0-00001.mkv'",
+                        "part-1-00000.mkv'"
+                    ],
+                    names
+                );
+                fs::write(output, b"joined")?;
+                Ok(())
+            },
+            |_| Ok(Some(25.02)),
+        )?;
+        assert!(output.is_file());
+
+        // the joined file is checked
+        let result = encode.finish(&output, |_, _| Ok(()), |_| Ok(Some(20.0)));
+        assert!(result.is_err());
+
+        encode.discard()?;
+        assert!(!encode.directory.exists());
+        encode.discard()?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_other_settings_start_over() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let directory = Utf8Path::from_path(tempdir.path()).unwrap();
+        let first = resumable(directory, "-crf 24");
+        let _ = first.encode(&encoder_args("/tmp.mp4"), |args, offset| {
+            fake_ffmpeg(args, offset, 25.0, Some(15.0))
+        });
+
+        let other = resumable(directory, "-crf 30");
+        other.encode(&encoder_args("/tmp.mp4"), |args, offset| {
+            assert_eq!(0.0, offset);
+            fake_ffmpeg(args, offset, 25.0, None)
+        })?;
+        let state = other.load()?;
+        assert_eq!(1, state.parts.len());
+        assert!(state.finished);
+
+        // an unfinished encode can't be joined
+        let unfinished = ResumableEncode {
+            source_size: 2000,
+            ..resumable(directory, "-crf 30")
+        };
+        assert!(
+            unfinished
+                .finish(&directory.join("out.mp4"), |_, _| Ok(()), |_| Ok(None))
+                .is_err()
+        );
+        Ok(())
+    }
+
+    /// Runs the real ffmpeg on a sample file, stopping the first part early.
+    #[test]
+    fn test_resume_with_ffmpeg() -> Result<()> {
+        if !testsupport::has_ffmpeg() {
+            return Ok(());
+        }
+        let tempdir = tempfile::tempdir()?;
+        let directory = Utf8Path::from_path(tempdir.path()).unwrap();
+        let input = &testsupport::sample(Sample::SMALL)?;
+        let duration = ffprobe(input)?.duration().unwrap();
+        let output = directory.join("out.mp4");
+        let args: Vec<String> = [
+            "-y",
+            "-i",
+            input.as_str(),
+            "-map",
+            "0:v",
+            "-c:v",
+            "mpeg4",
+            "-q:v",
+            "5",
+            output.as_str(),
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+        let encode = ResumableEncode {
+            segment_seconds: 1.0,
+            ..ResumableEncode::new(
+                directory.join(".transcoder-resume-1"),
+                1000,
+                duration,
+                fingerprint(&args, input, &output),
+            )
+        };
+        let run = |args: &[String]| -> Result<()> {
+            let output = binaries::command(Binary::Ffmpeg).args(args).output()?;
+            if output.status.success() {
+                Ok(())
+            } else {
+                Err(commandline_error("ffmpeg", output))
+            }
+        };
+
+        // the first part only gets through half of the file
+        let state = encode.load()?;
+        let mut stopped_early = part_args(
+            &args,
+            0.0,
+            encode.segment_seconds,
+            &encode.segment_list(0),
+            &encode.segment_pattern(0),
+        );
+        let input_index = stopped_early.iter().position(|a| a == "-i").unwrap();
+        stopped_early.splice(
+            input_index..input_index,
+            ["-t".into(), format!("{:.3}", duration / 2.0)],
+        );
+        encode.save(&ResumeState {
+            parts: vec![Part { offset: 0.0 }],
+            ..state
+        })?;
+        run(&stopped_early)?;
+
+        encode.encode(&args, |args, offset| {
+            assert!(offset > 0.0 && offset < duration);
+            run(args)
+        })?;
+        encode.finish(&output, concat, |output| Ok(ffprobe(output)?.duration()))?;
+        let joined = ffprobe(&output)?.duration().unwrap();
+        assert!((joined - duration).abs() < 0.5, "{joined} != {duration}");
+        Ok(())
+    }
+}
@@ -0,0 +1,241 @@
+//! Reviewing the outputs of `transcode --no-finalize` by hand before they are
+//! put in place: `review` lists them, `accept` finalizes the ones that look
+//! right and `reject` throws the others away.
+
+use std::fs;
+use std::process::Command;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::bail;
+use tracing::{info, warn};
+
+use crate::Result;
+use crate::database::{Database, TranscodeFile, TranscodeStatus};
+use crate::encoder_rules;
+use crate::sidecar;
+
+/// An encoded file waiting for review.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReviewEntry {
+    pub path: Utf8PathBuf,
+    pub source_size: u64,
+    pub output: Utf8PathBuf,
+    /// `None` when the output is missing.
+    pub output_size: Option<u64>,
+}
+
+impl ReviewEntry {
+    pub fn new(file: &TranscodeFile) -> Option<Self> {
+        let output = file.output_path.clone()?;
+        Some(ReviewEntry {
+            path: file.path.clone(),
+            source_size: file.file_size as u64,
+            output_size: fs::metadata(&output).ok().map(|m| m.len()),
+            output,
+        })
+    }
+
+    /// The arguments of an mpv that plays the source and the output side by side,
+    /// both scaled to the same height.
+    pub fn mpv_args(&self) -> Vec<String> {
+        vec![
+            format!("--external-file={}", self.output),
+            "--lavfi-complex=[vid1]scale=-2:720[a];[vid2]scale=-2:720[b];[a][b]hstack[vo]"
+                .to_string(),
+            self.path.to_string(),
+        ]
+    }
+
+    /// The mpv command to copy into a shell.
+    pub fn mpv_command(&self) -> String {
+        let mut command = String::from("mpv");
+        for arg in self.mpv_args() {
+            command.push(' ');
+            command.push_str(&shell_quote(&arg));
+        }
+        command
+    }
+
+    /// Plays the source and the output side by side and waits for mpv to close.
+    pub fn play(&self) -> Result<()> {
+        let status = Command::new("mpv").args(self.mpv_args()).status()?;
+        if !status.success() {
+            bail!("mpv exited with {status}");
+        }
+        Ok(())
+    }
+}
+
+/// Quotes an argument for POSIX shells if it needs it.
+fn shell_quote(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
+
+/// The files whose path matches the glob pattern.
+pub fn matching(files: Vec<TranscodeFile>, pattern: &str) -> Vec<TranscodeFile> {
+    files
+        .into_iter()
+        .filter(|file| encoder_rules::glob_matches(pattern, file.path.as_str()))
+        .collect()
+}
+
+#[derive(Debug, Default)]
+pub struct RejectSummary {
+    pub rejected: usize,
+    /// The bytes of the outputs that were deleted.
+    pub deleted_bytes: u64,
+}
+
+/// Deletes the outputs of the files and marks them as rejected, so that runs
+/// leave them alone unless they are forced. Files that aren't encoded are left
+/// alone, an output that is already gone doesn't stop a file from being rejected.
+pub fn reject(database: &Database, files: Vec<TranscodeFile>) -> Result<RejectSummary> {
+    let mut summary = RejectSummary::default();
+    for file in files {
+        if file.status != TranscodeStatus::Encoded {
+            continue;
+        }
+        if let Some(output) = file.output_path.as_deref() {
+            delete_output(output, &file.path, &mut summary)?;
+        }
+        if database.reject(file.id)? {
+            info!("{}: rejected", file.path);
+            summary.rejected += 1;
+        }
+    }
+    Ok(summary)
+}
+
+fn delete_output(output: &Utf8Path, source: &Utf8Path, summary: &mut RejectSummary) -> Result<()> {
+    if output == source {
+        warn!("Not deleting {output}, it is the source");
+        return Ok(());
+    }
+    match fs::metadata(output) {
+        Ok(metadata) => {
+            fs::remove_file(output)?;
+            summary.deleted_bytes += metadata.len();
+            sidecar::remove_orphan(output);
+        }
+        Err(_) => warn!("The output {output} of {source} is already gone"),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{FileFilter, NewTranscodeFile};
+    use crate::ffprobe::FfProbe;
+    use crate::finalize::{self, FinalizeOptions};
+    use crate::results::FileResult;
+    use crate::verify::Window;
+
+    const SOURCE: &[u8] = b"the source, before it was encoded";
+    const OUTPUT: &[u8] = b"the output";
+
+    /// Two sources in a temp directory, both encoded with `--no-finalize`.
+    fn setup(directory: &Utf8Path) -> Result<Database> {
+        let db = Database::in_memory()?;
+        for name in ["keep", "redo"] {
+            let source = directory.join(format!("{name}.mkv"));
+            fs::write(&source, SOURCE)?;
+            fs::write(directory.join(format!("{name}_av1.mp4")), OUTPUT)?;
+            db.insert_batch(&[NewTranscodeFile {
+                path: source.clone(),
+                file_size: SOURCE.len() as u64,
+                ffprobe_info: FfProbe::default(),
+            }])?;
+            let id = db.get_by_path(&source)?.unwrap().id;
+            db.record_result(&FileResult::encoded(
+                id,
+                &source,
+                &directory.join(format!("{name}_av1.mp4")),
+            ))?;
+        }
+        Ok(db)
+    }
+
+    fn encoded(db: &Database) -> Result<Vec<TranscodeFile>> {
+        db.list_filtered(
+            &FileFilter {
+                status: Some(TranscodeStatus::Encoded),
+                ..Default::default()
+            },
+            None,
+        )
+    }
+
+    fn intact(_: &Utf8Path, _: Option<Window>) -> Result<String> {
+        Ok(String::new())
+    }
+
+    #[test]
+    fn test_entries() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        let db = setup(dir)?;
+        fs::remove_file(dir.join("redo_av1.mp4"))?;
+        let entries: Vec<_> = encoded(&db)?.iter().filter_map(ReviewEntry::new).collect();
+        assert_eq!(2, entries.len());
+        assert_eq!(SOURCE.len() as u64, entries[0].source_size);
+        assert_eq!(Some(OUTPUT.len() as u64), entries[0].output_size);
+        assert_eq!(None, entries[1].output_size);
+
+        let entry = ReviewEntry {
+            path: "/movies/It's here.mkv".into(),
+            source_size: 0,
+            output: "/movies/out.mp4".into(),
+            output_size: None,
+        };
+        assert_eq!(
+            r"mpv --external-file=/movies/out.mp4 '--lavfi-complex=[vid1]scale=-2:720[a];[vid2]scale=-2:720[b];[a][b]hstack[vo]' '/movies/It'\''s here.mkv'",
+            entry.mpv_command()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_accept_and_reject() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        let db = setup(dir)?;
+        let keep = dir.join("keep.mkv");
+        let redo = dir.join("redo.mkv");
+
+        let summary = reject(&db, matching(encoded(&db)?, "**/redo*"))?;
+        assert_eq!(1, summary.rejected);
+        assert_eq!(OUTPUT.len() as u64, summary.deleted_bytes);
+        let file = db.get_by_path(&redo)?.unwrap();
+        assert_eq!(TranscodeStatus::Rejected, file.status);
+        assert_eq!(None, file.output_path);
+        assert!(!dir.join("redo_av1.mp4").exists());
+        assert_eq!(SOURCE, fs::read(&redo)?);
+
+        // accepting finalizes what is left
+        let options = FinalizeOptions {
+            verify: false,
+            replace: true,
+        };
+        let files = matching(encoded(&db)?, "**/*.mkv");
+        assert_eq!(1, files.len());
+        let summary = finalize::finalize(&db, files, options, intact)?;
+        assert_eq!(1, summary.finalized);
+        let file = db.get_by_path(&keep)?.unwrap();
+        assert_eq!(TranscodeStatus::Success, file.status);
+        assert_eq!(OUTPUT, fs::read(&keep)?);
+        assert!(!dir.join("keep_av1.mp4").exists());
+
+        // neither can be rejected any more
+        let files = matching(db.list_filtered(&FileFilter::default(), None)?, "**");
+        assert_eq!(0, reject(&db, files)?.rejected);
+        assert_eq!(OUTPUT, fs::read(&keep)?);
+        assert_eq!(SOURCE, fs::read(&redo)?);
+        Ok(())
+    }
+}
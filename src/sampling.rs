@@ -0,0 +1,129 @@
+use std::collections::BTreeMap;
+
+/// A small pseudo random number generator (SplitMix64). The same seed always
+/// gives the same numbers, so a sample can be picked again with `--seed`.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`. `n` must not be zero.
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    pub fn shuffle<T>(&mut self, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            items.swap(i, self.below(i + 1));
+        }
+    }
+}
+
+/// Picks `n` random items, spread over the groups that `key` puts them in. Every
+/// group gets a file before any group gets a second one, so a library that is
+/// mostly one kind of file still has the rare kinds in the sample. When there
+/// are more groups than `n`, random groups are left out.
+pub fn stratified<T, K: Ord>(items: Vec<T>, key: impl Fn(&T) -> K, n: usize, seed: u64) -> Vec<T> {
+    let mut rng = Rng::new(seed);
+    let mut groups: BTreeMap<K, Vec<T>> = BTreeMap::new();
+    for item in items {
+        groups.entry(key(&item)).or_default().push(item);
+    }
+    let mut groups: Vec<Vec<T>> = groups.into_values().collect();
+    rng.shuffle(&mut groups);
+    for group in &mut groups {
+        rng.shuffle(group);
+    }
+
+    let mut sample = vec![];
+    while sample.len() < n && groups.iter().any(|group| !group.is_empty()) {
+        for group in &mut groups {
+            if sample.len() == n {
+                break;
+            }
+            if let Some(item) = group.pop() {
+                sample.push(item);
+            }
+        }
+    }
+    sample
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    /// 1000 files, almost all of them in the first group.
+    fn skewed() -> Vec<(&'static str, usize)> {
+        let mut items: Vec<_> = (0..970).map(|i| ("1080p h264", i)).collect();
+        items.extend((970..990).map(|i| ("4k hevc", i)));
+        items.extend((990..998).map(|i| ("sd mpeg2", i)));
+        items.extend((998..1000).map(|i| ("720p vc1", i)));
+        items
+    }
+
+    #[test]
+    fn test_deterministic() {
+        let first = stratified(skewed(), |(group, _)| *group, 10, 42);
+        assert_eq!(first, stratified(skewed(), |(group, _)| *group, 10, 42));
+        assert_ne!(first, stratified(skewed(), |(group, _)| *group, 10, 43));
+    }
+
+    #[test]
+    fn test_every_group_is_sampled() {
+        for seed in 0..50 {
+            let sample = stratified(skewed(), |(group, _)| *group, 10, seed);
+            assert_eq!(10, sample.len());
+            let groups: HashSet<_> = sample.iter().map(|(group, _)| *group).collect();
+            assert_eq!(4, groups.len(), "seed {seed}");
+            // the two rare files are both in, the rest is split evenly
+            let count = |name| sample.iter().filter(|(group, _)| *group == name).count();
+            assert_eq!(2, count("720p vc1"));
+            let mut others = vec![count("1080p h264"), count("4k hevc"), count("sd mpeg2")];
+            others.sort();
+            assert_eq!(vec![2, 3, 3], others);
+            let unique: HashSet<_> = sample.iter().map(|(_, i)| *i).collect();
+            assert_eq!(10, unique.len());
+        }
+    }
+
+    #[test]
+    fn test_fewer_files_than_asked_for() {
+        let items = vec![("a", 1), ("a", 2), ("b", 3)];
+        let mut sample = stratified(items.clone(), |(group, _)| *group, 10, 7);
+        sample.sort();
+        assert_eq!(items, sample);
+        assert!(stratified(Vec::<(u8, u8)>::new(), |(group, _)| *group, 3, 7).is_empty());
+    }
+
+    #[test]
+    fn test_more_groups_than_files() {
+        let items: Vec<_> = (0..20).map(|i| (i, i)).collect();
+        let seen: HashSet<_> = (0..20)
+            .flat_map(|seed| stratified(items.clone(), |(group, _)| *group, 3, seed))
+            .collect();
+        // different seeds leave out different groups
+        assert!(seen.len() > 3);
+        for seed in 0..20 {
+            assert_eq!(
+                3,
+                stratified(items.clone(), |(group, _)| *group, 3, seed).len()
+            );
+        }
+    }
+}
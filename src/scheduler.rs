@@ -0,0 +1,373 @@
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+
+use tracing::{debug, warn};
+
+struct Job<T> {
+    item: T,
+    memory: u64,
+    /// Whether the item is encoded on a GPU with a limited number of sessions.
+    gpu: bool,
+}
+
+struct State<T> {
+    pending: VecDeque<Job<T>>,
+    memory_in_use: u64,
+    running: usize,
+    /// Items that hold one of the GPU sessions.
+    gpu_running: usize,
+    paused: bool,
+}
+
+/// How many GPU encodes can run at the same time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct GpuSessions {
+    pub limit: usize,
+    /// Admit GPU items on the CPU when all sessions are taken, instead of
+    /// holding them back.
+    pub cpu_fill: bool,
+}
+
+/// Hands out work items to worker threads, holding back items whose predicted
+/// memory usage would push the total over the memory budget, and GPU items
+/// when all GPU sessions are taken.
+pub struct Scheduler<T> {
+    state: Mutex<State<T>>,
+    condvar: Condvar,
+    memory_budget: u64,
+    gpu_sessions: Option<GpuSessions>,
+}
+
+/// A work item that was admitted by the scheduler. The reserved memory and GPU
+/// session are released when this is dropped.
+pub struct Admission<'a, T> {
+    scheduler: &'a Scheduler<T>,
+    pub item: T,
+    pub memory: u64,
+    /// A GPU item that has to be encoded on the CPU because all GPU sessions are
+    /// taken, with `cpu_fill`.
+    pub on_cpu: bool,
+    gpu_session: bool,
+}
+
+impl<T> Drop for Admission<'_, T> {
+    fn drop(&mut self) {
+        self.scheduler.release(self.memory, self.gpu_session);
+    }
+}
+
+impl<T> Scheduler<T> {
+    /// Creates a scheduler from items and their predicted memory usage in bytes.
+    pub fn new(items: impl IntoIterator<Item = (T, u64)>, memory_budget: u64) -> Self {
+        let pending = items
+            .into_iter()
+            .map(|(item, memory)| Job {
+                item,
+                memory,
+                gpu: false,
+            })
+            .collect();
+        Self {
+            state: Mutex::new(State {
+                pending,
+                memory_in_use: 0,
+                running: 0,
+                gpu_running: 0,
+                paused: false,
+            }),
+            condvar: Condvar::new(),
+            memory_budget,
+            gpu_sessions: None,
+        }
+    }
+
+    /// Limits how many of the items that `needs_gpu` picks run at the same time.
+    pub fn with_gpu_sessions(
+        mut self,
+        sessions: GpuSessions,
+        needs_gpu: impl Fn(&T) -> bool,
+    ) -> Self {
+        for job in self.state.get_mut().unwrap().pending.iter_mut() {
+            job.gpu = needs_gpu(&job.item);
+        }
+        Self {
+            gpu_sessions: Some(sessions),
+            ..self
+        }
+    }
+
+    /// Starts the first `slots` items as a mix of sizes: the first item, then the
+    /// smallest of the others by `size`, in their order. Starting the biggest
+    /// items all at once means the highest memory usage of the run, and a long
+    /// wait until the first file is done.
+    pub fn with_mixed_start(mut self, slots: usize, size: impl Fn(&T) -> u64) -> Self {
+        let pending = &mut self.state.get_mut().unwrap().pending;
+        if slots < 2 || pending.len() <= slots {
+            return self;
+        }
+        let mut by_size: Vec<_> = (1..pending.len()).collect();
+        by_size.sort_by_key(|&index| size(&pending[index].item));
+        let mut first_wave = vec![false; pending.len()];
+        first_wave[0] = true;
+        for &index in by_size.iter().take(slots - 1) {
+            first_wave[index] = true;
+        }
+        let (mut start, rest): (VecDeque<_>, VecDeque<_>) = pending
+            .drain(..)
+            .zip(first_wave)
+            .partition(|(_, first)| *first);
+        start.extend(rest);
+        *pending = start.into_iter().map(|(job, _)| job).collect();
+        self
+    }
+
+    /// Blocks until the next item can be admitted. Returns `None` once all items
+    /// have been handed out.
+    ///
+    /// Items are admitted in order, but an item that doesn't fit into the remaining
+    /// budget lets later, smaller items go first. An item that is bigger than the
+    /// whole budget is admitted on its own once nothing else is running. GPU items
+    /// wait for a free session the same way, or go to the CPU with `cpu_fill`.
+    pub fn next(&self) -> Option<Admission<'_, T>> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.pending.is_empty() {
+                return None;
+            }
+            if state.paused {
+                state = self.condvar.wait(state).unwrap();
+                continue;
+            }
+
+            let available = self.memory_budget.saturating_sub(state.memory_in_use);
+            let sessions_taken = self
+                .gpu_sessions
+                .is_some_and(|sessions| state.gpu_running >= sessions.limit);
+            let cpu_fill = self.gpu_sessions.is_some_and(|sessions| sessions.cpu_fill);
+            let index = match state.pending.iter().position(|job| {
+                job.memory <= available && !(job.gpu && sessions_taken && !cpu_fill)
+            }) {
+                Some(index) => Some(index),
+                None if state.running == 0 => {
+                    warn!(
+                        "predicted memory usage of the next file exceeds the memory budget, running it on its own"
+                    );
+                    Some(0)
+                }
+                None => None,
+            };
+
+            if let Some(index) = index {
+                let job = state.pending.remove(index).expect("index must be valid");
+                let gpu_session = job.gpu && !sessions_taken;
+                state.memory_in_use += job.memory;
+                state.running += 1;
+                if gpu_session {
+                    state.gpu_running += 1;
+                }
+                debug!(
+                    "admitted job with {} bytes, {} bytes in use",
+                    job.memory, state.memory_in_use
+                );
+                return Some(Admission {
+                    scheduler: self,
+                    item: job.item,
+                    memory: job.memory,
+                    on_cpu: job.gpu && sessions_taken,
+                    gpu_session,
+                });
+            }
+
+            state = self.condvar.wait(state).unwrap();
+        }
+    }
+
+    /// Stops admitting new items until resumed. Items that are already running
+    /// are not affected.
+    pub fn set_paused(&self, paused: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.paused = paused;
+        self.condvar.notify_all();
+    }
+
+    /// Drops all pending items so that workers stop once their current item is done.
+    /// Returns the number of dropped items.
+    pub fn stop(&self) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let dropped = state.pending.len();
+        state.pending.clear();
+        self.condvar.notify_all();
+        dropped
+    }
+
+    fn release(&self, memory: u64, gpu_session: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.memory_in_use -= memory;
+        state.running -= 1;
+        if gpu_session {
+            state.gpu_running -= 1;
+        }
+        self.condvar.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_admits_everything_within_budget() {
+        let scheduler = Scheduler::new([(1, 10), (2, 10), (3, 10)], 100);
+        let first = scheduler.next().unwrap();
+        let second = scheduler.next().unwrap();
+        let third = scheduler.next().unwrap();
+        assert_eq!((1, 2, 3), (first.item, second.item, third.item));
+        assert!(scheduler.next().is_none());
+    }
+
+    #[test]
+    fn test_smaller_items_skip_ahead() {
+        let scheduler = Scheduler::new([("4k", 80), ("4k", 80), ("1080p", 20)], 100);
+        let first = scheduler.next().unwrap();
+        assert_eq!(80, first.memory);
+        let second = scheduler.next().unwrap();
+        assert_eq!("1080p", second.item);
+        drop(first);
+        let third = scheduler.next().unwrap();
+        assert_eq!("4k", third.item);
+    }
+
+    #[test]
+    fn test_oversized_item_runs_alone() {
+        let scheduler = Scheduler::new([("huge", 500), ("small", 10)], 100);
+        let first = scheduler.next().unwrap();
+        assert_eq!("small", first.item);
+        drop(first);
+        let second = scheduler.next().unwrap();
+        assert_eq!("huge", second.item);
+    }
+
+    #[test]
+    fn test_gpu_sessions() {
+        let sessions = GpuSessions {
+            limit: 1,
+            cpu_fill: false,
+        };
+        let scheduler = Scheduler::new([("gpu 1", 10), ("gpu 2", 10), ("cpu", 10)], 100)
+            .with_gpu_sessions(sessions, |item| item.starts_with("gpu"));
+        let first = scheduler.next().unwrap();
+        assert_eq!(("gpu 1", false), (first.item, first.on_cpu));
+        // the CPU item goes ahead of the GPU item that has to wait for the session
+        let second = scheduler.next().unwrap();
+        assert_eq!(("cpu", false), (second.item, second.on_cpu));
+        thread::scope(|s| {
+            let worker = s.spawn(|| scheduler.next().map(|a| (a.item, a.on_cpu)));
+            thread::sleep(Duration::from_millis(20));
+            assert!(!worker.is_finished());
+            drop(second);
+            thread::sleep(Duration::from_millis(20));
+            assert!(!worker.is_finished());
+            drop(first);
+            assert_eq!(Some(("gpu 2", false)), worker.join().unwrap());
+        });
+    }
+
+    #[test]
+    fn test_cpu_fill() {
+        let sessions = GpuSessions {
+            limit: 2,
+            cpu_fill: true,
+        };
+        let scheduler = Scheduler::new((1..=5).map(|item| (item, 10)), 100)
+            .with_gpu_sessions(sessions, |_| true);
+        let first = scheduler.next().unwrap();
+        let second = scheduler.next().unwrap();
+        let third = scheduler.next().unwrap();
+        assert_eq!(
+            [(1, false), (2, false), (3, true)],
+            [&first, &second, &third].map(|a| (a.item, a.on_cpu))
+        );
+        // a file on the CPU doesn't hold a session
+        drop(third);
+        let fourth = scheduler.next().unwrap();
+        assert_eq!((4, true), (fourth.item, fourth.on_cpu));
+        drop(first);
+        let fifth = scheduler.next().unwrap();
+        assert_eq!((5, false), (fifth.item, fifth.on_cpu));
+    }
+
+    #[test]
+    fn test_mixed_start() {
+        // biggest first, the sizes are also the memory
+        let sizes = [100, 90, 80, 70, 40, 30, 20, 10];
+        let scheduler =
+            Scheduler::new(sizes.map(|size| (size, size)), 1000).with_mixed_start(4, |size| *size);
+        let admitted: Vec<_> = (0..4).map(|_| scheduler.next().unwrap()).collect();
+        assert_eq!(
+            vec![100, 30, 20, 10],
+            admitted.iter().map(|a| a.item).collect::<Vec<_>>()
+        );
+        drop(admitted);
+        let rest: Vec<_> = (0..4).map(|_| scheduler.next().unwrap().item).collect();
+        assert_eq!(vec![90, 80, 70, 40], rest);
+
+        // with as many slots as files, they all start at once anyway
+        let scheduler =
+            Scheduler::new(sizes.map(|size| (size, size)), 1000).with_mixed_start(8, |size| *size);
+        let all: Vec<_> = (0..8).map(|_| scheduler.next().unwrap().item).collect();
+        assert_eq!(sizes.to_vec(), all);
+    }
+
+    #[test]
+    fn test_pause_and_resume() {
+        let scheduler = Scheduler::new([(1, 10), (2, 10)], 100);
+        scheduler.set_paused(true);
+        thread::scope(|s| {
+            let worker = s.spawn(|| scheduler.next().map(|a| a.item));
+            thread::sleep(Duration::from_millis(20));
+            assert!(!worker.is_finished());
+            scheduler.set_paused(false);
+            assert_eq!(Some(1), worker.join().unwrap());
+        });
+    }
+
+    #[test]
+    fn test_stop() {
+        let scheduler = Scheduler::new([(1, 10), (2, 10), (3, 10)], 100);
+        scheduler.set_paused(true);
+        thread::scope(|s| {
+            let worker = s.spawn(|| scheduler.next().map(|a| a.item));
+            thread::sleep(Duration::from_millis(20));
+            assert_eq!(3, scheduler.stop());
+            assert_eq!(None, worker.join().unwrap());
+        });
+    }
+
+    #[test]
+    fn test_budget_is_never_exceeded() {
+        let sizes = [60, 30, 50, 10, 40, 20, 70, 30, 10, 50];
+        let scheduler = Scheduler::new(sizes.iter().map(|s| (*s, *s)), 100);
+        let in_use = AtomicU64::new(0);
+        let high_water_mark = AtomicU64::new(0);
+
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    while let Some(admission) = scheduler.next() {
+                        let now =
+                            in_use.fetch_add(admission.memory, Ordering::SeqCst) + admission.memory;
+                        high_water_mark.fetch_max(now, Ordering::SeqCst);
+                        thread::sleep(Duration::from_millis(5));
+                        in_use.fetch_sub(admission.memory, Ordering::SeqCst);
+                    }
+                });
+            }
+        });
+
+        assert!(high_water_mark.load(Ordering::SeqCst) <= 100);
+    }
+}
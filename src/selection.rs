@@ -0,0 +1,1033 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::Args;
+use jiff::{Timestamp, Zoned};
+use serde::{Serialize, Serializer};
+
+use crate::Result;
+use crate::codecs::{CodecInfo, CodecRule, CodecRules};
+use crate::collect::VideoFile;
+use crate::database::{Database, FileFilter, TranscodeFile, TranscodeStatus};
+use crate::device::{self, Conformance, DeviceProfile, VideoCodec};
+use crate::ordering::FileSortOrder;
+use crate::output_template::{self, OutputTemplate};
+use crate::paths::{self, OutputFields, OutputLocation, OutputPaths};
+use crate::preflight::{self, OutputInfo};
+use crate::sampling;
+
+/// Flags that decide which files a run picks. Shared by `transcode` and `queue`,
+/// so that both always agree on what will run.
+#[derive(Debug, Clone, Args)]
+pub struct SelectionArgs {
+    /// Limit how many files to process
+    #[clap(short, long)]
+    pub number: Option<i64>,
+
+    /// Order in which files are picked
+    #[clap(long, value_enum, default_value_t = FileSortOrder::BiggestFirst)]
+    pub order: FileSortOrder,
+
+    /// Transcode files again even if they were already transcoded or their output exists
+    #[clap(long)]
+    pub force: bool,
+
+    /// Write transcoded files to this directory instead of next to the originals
+    #[clap(long)]
+    pub output_dir: Option<Utf8PathBuf>,
+
+    /// Also skip files whose output is in this directory, where an earlier run
+    /// wrote it. Can be given several times
+    #[clap(long)]
+    pub previous_output_dir: Vec<Utf8PathBuf>,
+
+    /// Name of the transcoded files. Placeholders are {stem}, {ext}, {codec},
+    /// {crf}, {height} and {date}, e.g. "{stem} [AV1 CRF{crf}].{ext}"
+    #[clap(long, default_value = output_template::DEFAULT_TEMPLATE)]
+    pub output_template: OutputTemplate,
+
+    /// Don't transcode files of this codec, optionally only if they match all of the
+    /// comma separated conditions on bpp, bitrate or profile, e.g. "hevc:bpp<0.08"
+    /// or "h264:profile=High 10". Replaces the default of hevc and av1, or of
+    /// no codecs with --device. "--exclude-codec av1" re-encodes HEVC files to AV1
+    #[clap(long)]
+    pub exclude_codec: Vec<CodecRule>,
+
+    /// Encode the video to this codec, with libx265, hevc_nvenc or hevc_qsv for
+    /// HEVC and libvpx-vp9 into WebM for VP9. The {codec} and {ext} of the
+    /// output names follow it
+    #[clap(long, value_enum, conflicts_with = "device")]
+    pub codec: Option<VideoCodec>,
+
+    /// Make the outputs play on this device: chromecast, appletv4k, webh264 or
+    /// one from the [devices] section of the config file. Files that play on it
+    /// already are skipped, files with only other audio, subtitles or container
+    /// are remuxed
+    #[clap(long)]
+    pub device: Option<String>,
+
+    /// Pick this many random files spread over resolutions and codecs instead,
+    /// to try settings on before running them on the whole library. The run is
+    /// marked as a sample, its files are listed by `list --run <id>`
+    #[clap(long, conflicts_with = "number")]
+    pub sample_random: Option<usize>,
+
+    /// Seed for --sample-random, to pick the same files again
+    #[clap(long, requires = "sample_random")]
+    pub seed: Option<u64>,
+
+    #[clap(flatten)]
+    pub filter: FileFilter,
+}
+
+impl SelectionArgs {
+    /// Where the outputs of the selected files go. `{crf}` in the template stands
+    /// for `default_crf` until a file's settings are resolved.
+    pub fn output_paths(&self, default_crf: u8, device: Option<&DeviceProfile>) -> OutputPaths {
+        OutputPaths {
+            output_dir: self.output_dir.clone(),
+            previous_output_dirs: self.previous_output_dir.clone(),
+            template: self.output_template.clone(),
+            date: Zoned::now().date(),
+            default_crf,
+            codec: self.codec(device),
+            accepted_codecs: device.map(DeviceProfile::codecs).unwrap_or_default(),
+            ..Default::default()
+        }
+    }
+
+    /// The codec that is encoded to: the one the device plays, `--codec` or AV1.
+    pub fn codec(&self, device: Option<&DeviceProfile>) -> VideoCodec {
+        match device {
+            Some(device) => device.target().codec,
+            None => self.codec.unwrap_or_default(),
+        }
+    }
+
+    /// The profile of `--device`, from the config file or the built-in ones.
+    pub fn device(
+        &self,
+        configured: &BTreeMap<String, DeviceProfile>,
+    ) -> Result<Option<DeviceProfile>> {
+        self.device
+            .as_deref()
+            .map(|name| device::find(name, configured))
+            .transpose()
+    }
+
+    /// The codec rules of the run. With a device, which codecs are transcoded
+    /// depends on what it plays.
+    fn codec_rules(&self, device: Option<&DeviceProfile>) -> CodecRules {
+        match device {
+            Some(_) if self.exclude_codec.is_empty() => CodecRules::none(),
+            _ => CodecRules::new(self.exclude_codec.clone()),
+        }
+    }
+}
+
+/// Why a file was not selected for transcoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// Pinned with `pin`, which even `--force` respects.
+    Pinned,
+    AlreadyTranscoded,
+    /// Its output was thrown away with `reject`.
+    Rejected,
+    Claimed,
+    IgnoredCodec,
+    Missing,
+    /// Below a directory with the ignore file.
+    IgnoreFile,
+    OutputExists(OutputLocation),
+    /// Recorded by `scan --record-skipped`.
+    SkippedByScan,
+    /// Less than `--min-free-space` was left when the file came up.
+    LowDiskSpace,
+    /// No encode saved enough compared to the original.
+    TooLittleSavings,
+    /// Plays on the `--device` as it is.
+    Conforms,
+    /// Its size or modification time changed since `queue --plan` planned it.
+    ChangedSincePlan,
+}
+
+/// Serialized as its slug, e.g. `output-exists`.
+impl Serialize for SkipReason {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.slug())
+    }
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SkipReason::Pinned => write!(f, "pinned"),
+            SkipReason::AlreadyTranscoded => write!(f, "already transcoded"),
+            SkipReason::Rejected => write!(f, "rejected in review"),
+            SkipReason::Claimed => write!(f, "being transcoded by another worker"),
+            SkipReason::IgnoredCodec => write!(f, "ignored codec"),
+            SkipReason::Missing => write!(f, "missing"),
+            SkipReason::IgnoreFile => write!(f, "ignored by an ignore file"),
+            SkipReason::OutputExists(OutputLocation::Current) => write!(f, "output exists"),
+            SkipReason::OutputExists(location) => write!(f, "output exists {location}"),
+            SkipReason::SkippedByScan => write!(f, "skipped by the scan"),
+            SkipReason::LowDiskSpace => write!(f, "not enough free space"),
+            SkipReason::TooLittleSavings => write!(f, "saved too little"),
+            SkipReason::Conforms => write!(f, "plays on the device already"),
+            SkipReason::ChangedSincePlan => write!(f, "changed since it was planned"),
+        }
+    }
+}
+
+impl SkipReason {
+    /// Short name used in summaries.
+    pub fn slug(&self) -> &'static str {
+        match self {
+            SkipReason::Pinned => "pinned",
+            SkipReason::AlreadyTranscoded => "already-transcoded",
+            SkipReason::Rejected => "rejected",
+            SkipReason::Claimed => "claimed",
+            SkipReason::IgnoredCodec => "ignored-codec",
+            SkipReason::Missing => "missing",
+            SkipReason::IgnoreFile => "ignore-file",
+            SkipReason::OutputExists(_) => "output-exists",
+            SkipReason::SkippedByScan => "skipped-by-scan",
+            SkipReason::LowDiskSpace => "low-disk-space",
+            SkipReason::TooLittleSavings => "too-little-savings",
+            SkipReason::Conforms => "conforms",
+            SkipReason::ChangedSincePlan => "changed-since-plan",
+        }
+    }
+
+    /// Whether the file was skipped because it was already done, as opposed to
+    /// being left out.
+    pub fn is_existing(&self) -> bool {
+        matches!(
+            self,
+            SkipReason::AlreadyTranscoded | SkipReason::OutputExists(_)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SkippedFile {
+    pub path: Utf8PathBuf,
+    pub reason: SkipReason,
+}
+
+#[derive(Debug, Default)]
+pub struct Selection {
+    pub files: Vec<VideoFile>,
+    pub skipped: Vec<SkippedFile>,
+    /// The seed the files were picked with for `--sample-random`.
+    pub sample_seed: Option<u64>,
+}
+
+/// A selection from a database that no scan added files to, which is usually
+/// a first run or the wrong `--database`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmptyQueue {
+    pub database: Option<Utf8PathBuf>,
+}
+
+impl fmt::Display for EmptyQueue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no files queued, run `transcoder scan <path>` first")?;
+        if let Some(database) = &self.database {
+            write!(f, "; database: {database}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for EmptyQueue {}
+
+/// Checks whether a file would be skipped by the transcoder. With `force`, files
+/// that were already transcoded, rejected or whose output exists are transcoded again,
+/// but pinned files are still skipped. `is_file` checks that the source exists,
+/// `inspect` looks at possible outputs as for [`preflight::find_existing_output`].
+pub fn check(
+    file: &VideoFile,
+    paths: &OutputPaths,
+    force: bool,
+    codecs: &CodecRules,
+    is_file: impl Fn(&Utf8Path) -> bool,
+    inspect: impl Fn(&Utf8Path) -> Option<OutputInfo>,
+) -> Option<SkipReason> {
+    if file.pinned {
+        Some(SkipReason::Pinned)
+    } else if matches!(
+        file.status,
+        TranscodeStatus::Encoded | TranscodeStatus::Success | TranscodeStatus::Reclaimed
+    ) && !force
+    {
+        Some(SkipReason::AlreadyTranscoded)
+    } else if file.status == TranscodeStatus::Rejected && !force {
+        Some(SkipReason::Rejected)
+    } else if file.status == TranscodeStatus::InProgress {
+        Some(SkipReason::Claimed)
+    } else if file.status == TranscodeStatus::Skipped {
+        Some(SkipReason::SkippedByScan)
+    } else if codecs.excluding(&CodecInfo::from(file)).is_some() {
+        Some(SkipReason::IgnoredCodec)
+    } else if !is_file(&file.path) {
+        Some(SkipReason::Missing)
+    } else if force {
+        None
+    } else {
+        let fields = OutputFields {
+            crf: None,
+            height: file.resolution.1,
+            codec: None,
+        };
+        preflight::find_existing_output(
+            paths,
+            &file.path,
+            fields,
+            file.output_path.as_deref(),
+            inspect,
+        )
+        .map(|(_, location)| SkipReason::OutputExists(location))
+    }
+}
+
+/// Pulls candidates in order until `number` files that will actually be
+/// transcoded are found, or the candidates run out.
+pub fn select(
+    candidates: impl IntoIterator<Item = VideoFile>,
+    number: Option<usize>,
+    paths: &OutputPaths,
+    force: bool,
+    codecs: &CodecRules,
+    is_file: impl Fn(&Utf8Path) -> bool,
+    inspect: impl Fn(&Utf8Path) -> Option<OutputInfo>,
+) -> Selection {
+    let mut skipped = vec![];
+    let files = candidates
+        .into_iter()
+        .filter_map(
+            |file| match check(&file, paths, force, codecs, &is_file, &inspect) {
+                Some(reason) => {
+                    skipped.push(SkippedFile {
+                        path: file.path,
+                        reason,
+                    });
+                    None
+                }
+                None => Some(file),
+            },
+        )
+        .take(number.unwrap_or(usize::MAX))
+        .collect();
+
+    Selection {
+        files,
+        skipped,
+        sample_seed: None,
+    }
+}
+
+/// How many files are read from the database at once while selecting.
+const PAGE_SIZE: usize = 256;
+
+/// Picks the files for a run from the database, without changing anything.
+/// With a device, the files that play on it already are skipped. A database
+/// without any files is an [`EmptyQueue`] error.
+///
+/// The candidates are read a page at a time, so with `--number` only as many
+/// are parsed and checked as it takes to find enough files. A random sample is
+/// picked from all files that would be transcoded.
+pub fn select_from_database(
+    database: &Database,
+    args: &SelectionArgs,
+    paths: &OutputPaths,
+    device: Option<&DeviceProfile>,
+) -> Result<Selection> {
+    if database.is_empty()? {
+        return Err(EmptyQueue {
+            database: database.path().map(paths::absolute),
+        }
+        .into());
+    }
+    let mut conforming = vec![];
+    let mut error = None;
+    let rows: Box<dyn Iterator<Item = Result<TranscodeFile>>> = match args.order.size_order() {
+        Some(order) => Box::new(database.iter_filtered(&args.filter, order, PAGE_SIZE)),
+        None => Box::new(
+            database
+                .list_filtered(&args.filter, None)?
+                .into_iter()
+                .map(Ok),
+        ),
+    };
+    let candidates = rows
+        .map_while(|row| row.map_err(|e| error = Some(e)).ok())
+        .filter_map(|file| {
+            if let Some(device) = device
+                && let Some(info) = file.ffprobe()
+                && device.conformance(&info) == Conformance::Conforms
+            {
+                conforming.push(SkippedFile {
+                    path: file.path,
+                    reason: SkipReason::Conforms,
+                });
+                return None;
+            }
+            Some(VideoFile::from(file))
+        });
+    let candidates: Box<dyn Iterator<Item = VideoFile>> = match args.order.size_order() {
+        Some(_) => Box::new(candidates),
+        None => Box::new(args.order.sort(candidates.collect()).into_iter()),
+    };
+    let number = match args.sample_random {
+        Some(_) => None,
+        None => args.number.map(|n| n as usize),
+    };
+    let mut selection = select(
+        candidates,
+        number,
+        paths,
+        args.force,
+        &args.codec_rules(device),
+        |p| p.is_file(),
+        preflight::inspect_output,
+    );
+    if let Some(e) = error {
+        return Err(e);
+    }
+    selection.skipped.extend(conforming);
+    if let Some(n) = args.sample_random {
+        let seed = args
+            .seed
+            .unwrap_or_else(|| Timestamp::now().as_nanosecond() as u64);
+        let files = sampling::stratified(
+            std::mem::take(&mut selection.files),
+            |file| (file.tier(), file.codec.clone()),
+            n,
+            seed,
+        );
+        // in the order of --order, so that the sample runs like a normal run
+        selection.files = args.order.sort(files);
+        selection.sample_seed = Some(seed);
+    }
+    Ok(selection)
+}
+
+/// Number of skipped files per reason, most common first.
+pub fn skip_counts(skipped: &[SkippedFile]) -> Vec<(SkipReason, usize)> {
+    let mut counts: Vec<(SkipReason, usize)> = vec![];
+    for file in skipped {
+        match counts
+            .iter_mut()
+            .find(|(reason, _)| reason.slug() == file.reason.slug())
+        {
+            Some((_, count)) => *count += 1,
+            None => counts.push((file.reason, 1)),
+        }
+    }
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.slug().cmp(b.0.slug())));
+    counts
+}
+
+/// What a run did with the files it looked at.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RunSummary {
+    /// Files that were actually encoded.
+    pub transcoded: usize,
+    pub failed: usize,
+    /// Files that were skipped because they were already done.
+    pub skipped_existing: usize,
+    /// Files that were skipped for any other reason.
+    pub skipped_ignored: usize,
+    /// Files skipped when they were selected or when they came up.
+    pub skipped: Vec<SkippedFile>,
+}
+
+impl RunSummary {
+    pub fn new(transcoded: usize, failed: usize, skipped: Vec<SkippedFile>) -> Self {
+        let skipped_existing = skipped.iter().filter(|s| s.reason.is_existing()).count();
+        RunSummary {
+            transcoded,
+            failed,
+            skipped_existing,
+            skipped_ignored: skipped.len() - skipped_existing,
+            skipped,
+        }
+    }
+
+    /// Whether the run didn't encode anything, e.g. because a filter matched
+    /// only files that were done already.
+    pub fn nothing_done(&self) -> bool {
+        self.transcoded == 0
+    }
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&summary(self.transcoded, &self.skipped))?;
+        if self.failed > 0 {
+            write!(f, ", {} failed", self.failed)?;
+        }
+        Ok(())
+    }
+}
+
+/// The final line of a run, e.g. `0 transcoded, 37 skipped (36 output-exists, 1 ignored-codec)`.
+pub fn summary(transcoded: usize, skipped: &[SkippedFile]) -> String {
+    let mut summary = format!("{transcoded} transcoded, {} skipped", skipped.len());
+    if !skipped.is_empty() {
+        let counts: Vec<_> = skip_counts(skipped)
+            .into_iter()
+            .map(|(reason, count)| format!("{count} {}", reason.slug()))
+            .collect();
+        summary.push_str(&format!(" ({})", counts.join(", ")));
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::database::NewTranscodeFile;
+    use crate::ffprobe::FfProbe;
+
+    fn candidate(path: &str, codec: &str, status: TranscodeStatus) -> VideoFile {
+        VideoFile {
+            id: 0,
+            path: path.into(),
+            duration: 60.0,
+            resolution: (1920, 1080),
+            bitrate: 0,
+            video_bitrate: 0,
+            audio_bitrate: 0,
+            frame_rate: 24.0,
+            codec: codec.into(),
+            profile: None,
+            container: "mp4".into(),
+            file_size: 1000,
+            status,
+            audio_tracks: vec![],
+            pix_fmt: None,
+            start_offset: None,
+            pinned: false,
+            output_path: None,
+            title: None,
+            chapters: vec![],
+        }
+    }
+
+    fn fake_fs(files: &[&str]) -> impl Fn(&Utf8Path) -> bool + Clone {
+        let files: HashSet<Utf8PathBuf> = files.iter().map(Utf8PathBuf::from).collect();
+        move |path| files.contains(path)
+    }
+
+    /// Every file of the fake filesystem is a valid output.
+    fn outputs(fs: impl Fn(&Utf8Path) -> bool) -> impl Fn(&Utf8Path) -> Option<OutputInfo> {
+        move |path| {
+            fs(path).then(|| OutputInfo {
+                size: 1000,
+                video_codec: Some("av1".into()),
+            })
+        }
+    }
+
+    #[test]
+    fn test_check() {
+        let fs = fake_fs(&["/a.mkv", "/b.mkv", "/b_av1.mp4"]);
+        let paths = OutputPaths::default();
+        let codecs = CodecRules::default();
+        let pending = TranscodeStatus::Pending;
+        assert_eq!(
+            None,
+            check(
+                &candidate("/a.mkv", "h264", pending),
+                &paths,
+                false,
+                &codecs,
+                &fs,
+                outputs(&fs)
+            )
+        );
+        assert_eq!(
+            Some(SkipReason::OutputExists(OutputLocation::Current)),
+            check(
+                &candidate("/b.mkv", "h264", pending),
+                &paths,
+                false,
+                &codecs,
+                &fs,
+                outputs(&fs)
+            )
+        );
+        assert_eq!(
+            Some(SkipReason::Missing),
+            check(
+                &candidate("/c.mkv", "h264", pending),
+                &paths,
+                false,
+                &codecs,
+                &fs,
+                outputs(&fs)
+            )
+        );
+        assert_eq!(
+            Some(SkipReason::IgnoredCodec),
+            check(
+                &candidate("/a.mkv", "hevc", pending),
+                &paths,
+                false,
+                &codecs,
+                &fs,
+                outputs(&fs)
+            )
+        );
+        assert_eq!(
+            Some(SkipReason::Claimed),
+            check(
+                &candidate("/a.mkv", "h264", TranscodeStatus::InProgress),
+                &paths,
+                true,
+                &codecs,
+                &fs,
+                outputs(&fs)
+            )
+        );
+        assert_eq!(
+            Some(SkipReason::SkippedByScan),
+            check(
+                &candidate("/a.mkv", "h264", TranscodeStatus::Skipped),
+                &paths,
+                true,
+                &codecs,
+                &fs,
+                outputs(&fs)
+            )
+        );
+        // an output that waits for `finalize` is transcoded as well
+        for status in [TranscodeStatus::Success, TranscodeStatus::Encoded] {
+            assert_eq!(
+                Some(SkipReason::AlreadyTranscoded),
+                check(
+                    &candidate("/a.mkv", "h264", status),
+                    &paths,
+                    false,
+                    &codecs,
+                    &fs,
+                    outputs(&fs)
+                )
+            );
+        }
+        // a rejected output takes --force to be encoded again
+        let rejected = candidate("/a.mkv", "h264", TranscodeStatus::Rejected);
+        assert_eq!(
+            Some(SkipReason::Rejected),
+            check(&rejected, &paths, false, &codecs, &fs, outputs(&fs))
+        );
+        assert_eq!(
+            None,
+            check(&rejected, &paths, true, &codecs, &fs, outputs(&fs))
+        );
+    }
+
+    #[test]
+    fn test_codec_rules() {
+        let fs = fake_fs(&["/a.mkv"]);
+        let paths = OutputPaths::default();
+        let codecs = CodecRules::new(vec!["hevc:bpp<0.08".parse().unwrap()]);
+        let mut file = candidate("/a.mkv", "hevc", TranscodeStatus::Pending);
+        // 1080p24 at 30 Mbit/s from an old hardware encoder
+        file.video_bitrate = 30_000_000;
+        assert_eq!(
+            None,
+            check(&file, &paths, false, &codecs, &fs, outputs(&fs))
+        );
+        file.video_bitrate = 2_000_000;
+        assert_eq!(
+            Some(SkipReason::IgnoredCodec),
+            check(&file, &paths, false, &codecs, &fs, outputs(&fs))
+        );
+        // the rules replace the defaults
+        let file = candidate("/a.mkv", "av1", TranscodeStatus::Pending);
+        assert_eq!(
+            None,
+            check(&file, &paths, false, &codecs, &fs, outputs(&fs))
+        );
+    }
+
+    #[test]
+    fn test_force() {
+        let fs = fake_fs(&["/a.mkv", "/a_av1.mp4"]);
+        let paths = OutputPaths::default();
+        let codecs = CodecRules::default();
+        let done = candidate("/a.mkv", "h264", TranscodeStatus::Success);
+        assert_eq!(None, check(&done, &paths, true, &codecs, &fs, outputs(&fs)));
+        assert_eq!(
+            Some(SkipReason::IgnoredCodec),
+            check(
+                &candidate("/a.mkv", "av1", TranscodeStatus::Success),
+                &paths,
+                true,
+                &codecs,
+                &fs,
+                outputs(&fs)
+            )
+        );
+    }
+
+    #[test]
+    fn test_pinned() -> Result<()> {
+        let database = Database::in_memory()?;
+        for path in ["/reference/a.mkv", "/b.mkv"] {
+            database.insert(NewTranscodeFile {
+                path: path.into(),
+                file_size: 1000,
+                ffprobe_info: FfProbe::default(),
+            })?;
+        }
+        assert_eq!(1, database.pin("/reference/**", Some("keep as is"))?);
+        let candidates = || -> Result<Vec<VideoFile>> {
+            Ok(database
+                .list_filtered(&FileFilter::default(), None)?
+                .into_iter()
+                .map(VideoFile::from)
+                .collect())
+        };
+
+        // --force doesn't override a pin
+        let fs = fake_fs(&["/reference/a.mkv", "/b.mkv"]);
+        let selection = select(
+            candidates()?,
+            None,
+            &OutputPaths::default(),
+            true,
+            &CodecRules::default(),
+            &fs,
+            outputs(&fs),
+        );
+        assert_eq!(1, selection.files.len());
+        assert_eq!("/b.mkv", selection.files[0].path);
+        assert_eq!(SkipReason::Pinned, selection.skipped[0].reason);
+        assert!(!selection.skipped[0].reason.is_existing());
+
+        assert_eq!(1, database.unpin("/reference/*.mkv")?);
+        let selection = select(
+            candidates()?,
+            None,
+            &OutputPaths::default(),
+            true,
+            &CodecRules::default(),
+            &fs,
+            outputs(&fs),
+        );
+        assert_eq!(2, selection.files.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_number_counts_eligible_files() {
+        let fs = fake_fs(&[
+            "/1.mkv",
+            "/1_av1.mp4",
+            "/2.mkv",
+            "/4.mkv",
+            "/5.mkv",
+            "/6.mkv",
+        ]);
+        let candidates = vec![
+            candidate("/1.mkv", "h264", TranscodeStatus::Pending),
+            candidate("/2.mkv", "av1", TranscodeStatus::Pending),
+            candidate("/3.mkv", "h264", TranscodeStatus::Pending),
+            candidate("/4.mkv", "h264", TranscodeStatus::Pending),
+            candidate("/5.mkv", "h264", TranscodeStatus::Pending),
+            candidate("/6.mkv", "h264", TranscodeStatus::Pending),
+        ];
+
+        let selection = select(
+            candidates,
+            Some(2),
+            &OutputPaths::default(),
+            false,
+            &CodecRules::default(),
+            &fs,
+            outputs(&fs),
+        );
+        let paths: Vec<_> = selection.files.iter().map(|f| f.path.as_str()).collect();
+        assert_eq!(vec!["/4.mkv", "/5.mkv"], paths);
+        let reasons: Vec<_> = selection.skipped.iter().map(|s| s.reason).collect();
+        assert_eq!(
+            vec![
+                SkipReason::OutputExists(OutputLocation::Current),
+                SkipReason::IgnoredCodec,
+                SkipReason::Missing
+            ],
+            reasons
+        );
+    }
+
+    #[test]
+    fn test_output_elsewhere() {
+        let fs = fake_fs(&["/v/a.mkv", "/old/a_av1.mp4", "/v/b.mkv", "/v/b_av1.mp4"]);
+        let paths = OutputPaths {
+            output_dir: Some("/new".into()),
+            previous_output_dirs: vec!["/old".into()],
+            ..Default::default()
+        };
+        let codecs = CodecRules::default();
+        let a = candidate("/v/a.mkv", "h264", TranscodeStatus::Pending);
+        let b = candidate("/v/b.mkv", "h264", TranscodeStatus::Pending);
+        assert_eq!(
+            Some(SkipReason::OutputExists(OutputLocation::PreviousOutputDir)),
+            check(&a, &paths, false, &codecs, &fs, outputs(&fs))
+        );
+        assert_eq!(
+            Some(SkipReason::OutputExists(OutputLocation::Sibling)),
+            check(&b, &paths, false, &codecs, &fs, outputs(&fs))
+        );
+        assert_eq!(
+            "output exists in a previous output directory",
+            SkipReason::OutputExists(OutputLocation::PreviousOutputDir).to_string()
+        );
+        // an output that isn't AV1 doesn't count
+        let not_av1 = |path: &Utf8Path| {
+            fs(path).then(|| OutputInfo {
+                size: 1000,
+                video_codec: Some("h264".into()),
+            })
+        };
+        assert_eq!(None, check(&a, &paths, false, &codecs, &fs, not_av1));
+        // --force transcodes them again
+        assert_eq!(None, check(&a, &paths, true, &codecs, &fs, outputs(&fs)));
+
+        // the different locations are counted together
+        let selection = select(vec![a, b], None, &paths, false, &codecs, &fs, outputs(&fs));
+        assert_eq!(
+            "0 transcoded, 2 skipped (2 output-exists)",
+            summary(0, &selection.skipped)
+        );
+    }
+
+    #[test]
+    fn test_queue_exhausted() {
+        let fs = fake_fs(&["/1.mkv"]);
+        let candidates = vec![
+            candidate("/1.mkv", "h264", TranscodeStatus::Pending),
+            candidate("/2.mkv", "h264", TranscodeStatus::Pending),
+        ];
+        let selection = select(
+            candidates,
+            Some(5),
+            &OutputPaths::default(),
+            false,
+            &CodecRules::default(),
+            &fs,
+            outputs(&fs),
+        );
+        assert_eq!(1, selection.files.len());
+        assert_eq!(1, selection.skipped.len());
+    }
+
+    #[test]
+    fn test_all_skipped() {
+        let fs = fake_fs(&[
+            "/a.mkv",
+            "/a_av1.mp4",
+            "/b.mkv",
+            "/b_av1.mp4",
+            "/c.mkv",
+            "/c_av1.mp4",
+            "/d.mkv",
+        ]);
+        let candidates = vec![
+            candidate("/a.mkv", "h264", TranscodeStatus::Pending),
+            candidate("/b.mkv", "h264", TranscodeStatus::Pending),
+            candidate("/c.mkv", "h264", TranscodeStatus::Pending),
+            candidate("/d.mkv", "hevc", TranscodeStatus::Pending),
+        ];
+        let selection = select(
+            candidates,
+            None,
+            &OutputPaths::default(),
+            false,
+            &CodecRules::default(),
+            &fs,
+            outputs(&fs),
+        );
+        assert!(selection.files.is_empty());
+        assert_eq!(
+            vec![
+                (SkipReason::OutputExists(OutputLocation::Current), 3),
+                (SkipReason::IgnoredCodec, 1)
+            ],
+            skip_counts(&selection.skipped)
+        );
+        assert_eq!(
+            "0 transcoded, 4 skipped (3 output-exists, 1 ignored-codec)",
+            summary(0, &selection.skipped)
+        );
+        assert_eq!("2 transcoded, 0 skipped", summary(2, &[]));
+    }
+
+    #[derive(clap::Parser)]
+    struct Cli {
+        #[clap(flatten)]
+        selection: SelectionArgs,
+    }
+
+    fn parse(args: &[&str]) -> Result<SelectionArgs, clap::Error> {
+        <Cli as clap::Parser>::try_parse_from(["test"].iter().chain(args)).map(|cli| cli.selection)
+    }
+
+    #[test]
+    fn test_equal_sizes_are_picked_the_same_way() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        let chunk = |i: u32| dir.join(format!("chunk-{i:02}.ts"));
+        let database = Database::in_memory()?;
+        // fixed size DVR chunks, added in no particular order
+        for i in [7, 3, 11, 0, 9, 14, 1, 5, 12, 8, 2, 13, 6, 10, 4] {
+            std::fs::write(chunk(i), b"")?;
+            database.insert(NewTranscodeFile {
+                path: chunk(i),
+                file_size: 1_000_000,
+                ffprobe_info: FfProbe::default(),
+            })?;
+        }
+        for order in ["biggest-first", "smallest-first", "interleaved"] {
+            let args = parse(&["-n", "5", "--order", order])?;
+            let picked = || -> Result<Vec<_>> {
+                let selection =
+                    select_from_database(&database, &args, &OutputPaths::default(), None)?;
+                Ok(selection.files.into_iter().map(|f| f.path).collect())
+            };
+            let first = picked()?;
+            assert_eq!(first, picked()?, "{order}");
+            let expected: Vec<_> = (0..5).map(chunk).collect();
+            assert_eq!(expected, first, "{order}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_empty_queue() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = Utf8Path::from_path(dir.path())
+            .unwrap()
+            .canonicalize_utf8()?;
+        let database = Database::open(&dir.join("transcoder.db"))?;
+        let args = parse(&[])?;
+        let error = select_from_database(&database, &args, &OutputPaths::default(), None)
+            .err()
+            .unwrap();
+        assert_eq!(
+            Some(&EmptyQueue {
+                database: Some(dir.join("transcoder.db"))
+            }),
+            error.downcast_ref::<EmptyQueue>()
+        );
+        assert_eq!(
+            format!(
+                "no files queued, run `transcoder scan <path>` first; database: {dir}/transcoder.db"
+            ),
+            error.to_string()
+        );
+
+        // a queue whose files are all skipped has nothing to transcode, but isn't empty
+        database.insert(NewTranscodeFile {
+            path: dir.join("movie.mkv"),
+            file_size: 1000,
+            ffprobe_info: FfProbe::default(),
+        })?;
+        let selection = select_from_database(&database, &args, &OutputPaths::default(), None)?;
+        assert!(selection.files.is_empty());
+        assert_eq!(SkipReason::Missing, selection.skipped[0].reason);
+        Ok(())
+    }
+
+    #[test]
+    fn test_codec() {
+        let args = parse(&["--codec", "hevc"]).unwrap();
+        assert_eq!(VideoCodec::Hevc, args.codec(None));
+        let paths = args.output_paths(28, None);
+        assert_eq!(
+            "/movies/a_hevc.mp4",
+            paths.output(
+                "/movies/a.mkv".into(),
+                OutputFields {
+                    crf: None,
+                    height: 1080,
+                    codec: None,
+                }
+            )
+        );
+        assert_eq!(VideoCodec::Av1, parse(&[]).unwrap().codec(None));
+        // H.264 is only for devices
+        assert!(parse(&["--codec", "h264"]).is_err());
+        assert!(parse(&["--codec", "hevc", "--device", "chromecast"]).is_err());
+    }
+
+    #[test]
+    fn test_run_summary() {
+        let skipped = |path: &str, reason| SkippedFile {
+            path: path.into(),
+            reason,
+        };
+
+        // only skips, e.g. a filter that matches files that are done already
+        let all_skipped = RunSummary::new(
+            0,
+            0,
+            vec![
+                skipped("/a.mkv", SkipReason::OutputExists(OutputLocation::Current)),
+                skipped("/b.mkv", SkipReason::AlreadyTranscoded),
+                skipped("/c.mkv", SkipReason::IgnoredCodec),
+                skipped("/d.mkv", SkipReason::TooLittleSavings),
+            ],
+        );
+        assert!(all_skipped.nothing_done());
+        assert_eq!(2, all_skipped.skipped_existing);
+        assert_eq!(2, all_skipped.skipped_ignored);
+        assert_eq!(
+            "0 transcoded, 4 skipped (1 already-transcoded, 1 ignored-codec, 1 output-exists, 1 too-little-savings)",
+            all_skipped.to_string()
+        );
+
+        let mixed = RunSummary::new(
+            2,
+            1,
+            vec![
+                skipped("/a.mkv", SkipReason::OutputExists(OutputLocation::Current)),
+                skipped("/b.mkv", SkipReason::LowDiskSpace),
+            ],
+        );
+        assert!(!mixed.nothing_done());
+        assert_eq!(1, mixed.skipped_existing);
+        assert_eq!(1, mixed.skipped_ignored);
+        assert_eq!(
+            "2 transcoded, 2 skipped (1 low-disk-space, 1 output-exists), 1 failed",
+            mixed.to_string()
+        );
+        let json = serde_json::to_value(&mixed).unwrap();
+        assert_eq!(1, json["skipped_existing"]);
+        assert_eq!(1, json["skipped_ignored"]);
+        assert_eq!("low-disk-space", json["skipped"][1]["reason"]);
+        assert_eq!("/b.mkv", json["skipped"][1]["path"]);
+
+        let all_encoded = RunSummary {
+            transcoded: 3,
+            ..Default::default()
+        };
+        assert!(!all_encoded.nothing_done());
+        assert_eq!(0, all_encoded.skipped_existing);
+        assert_eq!("3 transcoded, 0 skipped", all_encoded.to_string());
+
+        // failures alone don't count as done
+        let failed = RunSummary {
+            failed: 2,
+            ..Default::default()
+        };
+        assert!(failed.nothing_done());
+    }
+}
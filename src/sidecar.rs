@@ -0,0 +1,396 @@
+//! Reports written next to the outputs with `transcode --sidecar`, for media
+//! managers that ingest sidecar files. A report is the file's result as the
+//! results dump has it, with what else is known about the encode. `verify` and
+//! `finalize` update the reports that exist when they run later.
+
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+
+use camino::{Utf8Path, Utf8PathBuf};
+use color_eyre::eyre::{bail, eyre};
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::Result;
+use crate::audio_hash::AudioHash;
+use crate::database::{Database, TranscodeFile};
+use crate::paths;
+use crate::resolved_options::ResolvedOptions;
+use crate::results::FileResult;
+use crate::version::FfmpegVersion;
+
+/// Version of the format of [`Sidecar`].
+pub const SIDECAR_VERSION: u32 = 1;
+
+/// Appended to the name of the output to get the name of its report.
+pub const SUFFIX: &str = ".transcoder.json";
+
+/// How much of the start and of the end of a source the hash reads.
+const HASH_CHUNK: u64 = 64 * 1024;
+
+/// The report of an output.
+pub fn path_for(output: &Utf8Path) -> Utf8PathBuf {
+    format!("{output}{SUFFIX}").into()
+}
+
+/// The output a report belongs to, `None` for files that aren't reports.
+pub fn output_of(path: &Utf8Path) -> Option<Utf8PathBuf> {
+    path.as_str()
+        .strip_suffix(SUFFIX)
+        .filter(|output| !output.is_empty() && !output.ends_with('/'))
+        .map(Utf8PathBuf::from)
+}
+
+/// The hash media managers identify files by, from OpenSubtitles: the size plus
+/// the sum of the little endian 64 bit words of the first and the last 64 KiB.
+pub fn source_hash(path: &Utf8Path) -> Result<String> {
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let chunk = HASH_CHUNK.min(size);
+    let mut hash = size;
+    for offset in [0, size - chunk] {
+        let mut buffer = vec![0; chunk as usize];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut buffer)?;
+        for word in buffer.chunks_exact(8) {
+            hash = hash.wrapping_add(u64::from_le_bytes(word.try_into().unwrap()));
+        }
+    }
+    Ok(format!("{hash:016x}"))
+}
+
+/// What the report says about the source.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct SourceInfo {
+    pub size: u64,
+    /// See [`source_hash`]. Taken before the output was moved, which may have
+    /// replaced the source.
+    pub opensubtitles_hash: Option<String>,
+    /// In seconds.
+    pub duration: Option<f64>,
+    pub codec: Option<String>,
+}
+
+/// What `verify`, `finalize --verify` and `--verify-audio-hash` found.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Verification {
+    pub verified_at: Option<Timestamp>,
+    /// The decode errors, `None` if the output was verified and passed.
+    pub error: Option<String>,
+    pub audio: Option<AudioHash>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Sidecar {
+    pub version: u32,
+    pub written_at: Timestamp,
+    pub result: FileResult,
+    pub source: SourceInfo,
+    pub output_size: Option<u64>,
+    pub options: Option<ResolvedOptions>,
+    pub encode_seconds: Option<f64>,
+    /// How many times faster than real time the encode was.
+    pub speed: Option<f64>,
+    pub verification: Verification,
+    pub transcoder_version: String,
+    pub ffmpeg: Option<FfmpegVersion>,
+}
+
+impl Sidecar {
+    /// The report of a file's output as the database has it.
+    pub fn new(
+        file: &TranscodeFile,
+        source_hash: Option<String>,
+        ffmpeg: Option<FfmpegVersion>,
+    ) -> Result<Self> {
+        let output = file
+            .output_path
+            .clone()
+            .ok_or_else(|| eyre!("no output was recorded for {}", file.path))?;
+        let probe = file.ffprobe();
+        let duration = probe.as_ref().and_then(|probe| probe.duration());
+        Ok(Sidecar {
+            version: SIDECAR_VERSION,
+            written_at: Timestamp::now(),
+            source: SourceInfo {
+                size: file.file_size as u64,
+                opensubtitles_hash: source_hash,
+                duration,
+                codec: probe.map(|probe| probe.video_codec().to_string()),
+            },
+            output_size: fs::metadata(&output).ok().map(|metadata| metadata.len()),
+            options: file.resolved_options()?,
+            encode_seconds: file.encode_seconds,
+            speed: duration
+                .zip(file.encode_seconds)
+                .filter(|(_, seconds)| *seconds > 0.0)
+                .map(|(duration, seconds)| duration / seconds),
+            verification: Verification {
+                verified_at: file.verified_at,
+                error: file.verify_error.clone(),
+                audio: file.audio_hash,
+            },
+            transcoder_version: env!("CARGO_PKG_VERSION").into(),
+            ffmpeg,
+            result: FileResult {
+                id: file.id,
+                path: file.path.clone(),
+                status: file.status,
+                output_path: Some(output),
+                error_message: file.error_message.clone(),
+                source_changes: file.source_changes()?,
+                renditions: file.renditions()?,
+            },
+        })
+    }
+
+    pub fn read(path: &Utf8Path) -> Result<Self> {
+        let sidecar: Sidecar = serde_json::from_str(&fs::read_to_string(path)?)?;
+        if sidecar.version > SIDECAR_VERSION {
+            bail!(
+                "{path} was written by a newer version of the transcoder (format {}, this one reads up to {SIDECAR_VERSION})",
+                sidecar.version
+            );
+        }
+        Ok(sidecar)
+    }
+
+    pub fn write(&self, path: &Utf8Path) -> Result<()> {
+        paths::write_atomically(path, serde_json::to_string_pretty(self)?.as_bytes())
+    }
+}
+
+/// The report at `path`, `None` if there is none.
+fn existing(path: &Utf8Path) -> Result<Option<Sidecar>> {
+    match Sidecar::read(path) {
+        Ok(sidecar) => Ok(Some(sidecar)),
+        Err(e)
+            if e.downcast_ref::<std::io::Error>().map(|e| e.kind())
+                == Some(ErrorKind::NotFound) =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes the report of a file's output. Without a hash, the one of an earlier
+/// report is kept, the source may be gone by now.
+pub fn write(database: &Database, file: &TranscodeFile, source_hash: Option<String>) -> Result<()> {
+    let output = file
+        .output_path
+        .as_deref()
+        .ok_or_else(|| eyre!("no output was recorded for {}", file.path))?;
+    let path = path_for(output);
+    let source_hash = match source_hash {
+        Some(hash) => Some(hash),
+        None => existing(&path)?.and_then(|sidecar| sidecar.source.opensubtitles_hash),
+    };
+    let ffmpeg = match file.run_id {
+        Some(run_id) => database.get_run(run_id)?.map(|run| run.ffmpeg()),
+        None => None,
+    };
+    Sidecar::new(file, source_hash, ffmpeg)?.write(&path)
+}
+
+/// Updates the report of a file's output, if it has one. `previous_output` is
+/// where the output was before it was moved, its report is moved along.
+/// Returns whether there was a report.
+pub fn update(database: &Database, id: i64, previous_output: Option<&Utf8Path>) -> Result<bool> {
+    let Some(file) = database.get(id)? else {
+        return Ok(false);
+    };
+    let Some(output) = file.output_path.as_deref() else {
+        return Ok(false);
+    };
+    let path = path_for(output);
+    let previous = previous_output
+        .map(path_for)
+        .filter(|previous| *previous != path);
+    let sidecar = match existing(&path)? {
+        Some(sidecar) => sidecar,
+        None => match previous.as_deref().map(existing).transpose()?.flatten() {
+            Some(sidecar) => sidecar,
+            None => return Ok(false),
+        },
+    };
+    write(database, &file, sidecar.source.opensubtitles_hash)?;
+    if let Some(previous) = previous
+        && previous.exists()
+    {
+        fs::remove_file(&previous)?;
+    }
+    Ok(true)
+}
+
+/// Updates the report of a file's output, warning when that fails. A report
+/// that is out of date doesn't fail the command that changed the file.
+pub fn try_update(database: &Database, id: i64, previous_output: Option<&Utf8Path>) {
+    if let Err(e) = update(database, id, previous_output) {
+        warn!("Could not update the sidecar of file {id}: {e:?}");
+    }
+}
+
+/// Removes the report of a file that was deleted.
+pub fn remove_orphan(deleted: &Utf8Path) {
+    let path = path_for(deleted);
+    if deleted.exists() || !path.is_file() {
+        return;
+    }
+    match fs::remove_file(&path) {
+        Ok(()) => info!("removed the sidecar {path}"),
+        Err(e) => warn!("Could not remove the sidecar {path}: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::NewTranscodeFile;
+
+    struct Setup {
+        _tempdir: tempfile::TempDir,
+        db: Database,
+        id: i64,
+        source: Utf8PathBuf,
+        output: Utf8PathBuf,
+    }
+
+    fn setup() -> Result<Setup> {
+        let tempdir = tempfile::tempdir()?;
+        let directory = Utf8Path::from_path(tempdir.path()).unwrap().to_owned();
+        let source = directory.join("movie.mkv");
+        let output = directory.join("movie_av1.mp4");
+        fs::write(&source, vec![0; 200_000])?;
+        fs::write(&output, b"the output")?;
+        let db = Database::in_memory()?;
+        db.insert(NewTranscodeFile {
+            path: source.clone(),
+            file_size: 200_000,
+            ffprobe_info: crate::ffprobe::fixtures()[0].clone(),
+        })?;
+        let id = db.get_by_path(&source)?.unwrap().id;
+        db.record_result(&FileResult::transcoded(id, &source, &output))?;
+        db.set_encode_time(id, 100.0)?;
+        Ok(Setup {
+            _tempdir: tempdir,
+            db,
+            id,
+            source,
+            output,
+        })
+    }
+
+    #[test]
+    fn test_paths() {
+        let output = Utf8Path::new("/movies/movie_av1.mp4");
+        let sidecar = path_for(output);
+        assert_eq!("/movies/movie_av1.mp4.transcoder.json", sidecar);
+        assert_eq!(Some(output.to_owned()), output_of(&sidecar));
+        assert_eq!(None, output_of(output));
+        assert_eq!(None, output_of("/movies/.transcoder.json".into()));
+    }
+
+    #[test]
+    fn test_source_hash() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let directory = Utf8Path::from_path(tempdir.path()).unwrap();
+        // only the size for a file of zeros
+        let path = directory.join("zeros.mkv");
+        fs::write(&path, vec![0; 200_000])?;
+        assert_eq!(format!("{:016x}", 200_000), source_hash(&path)?);
+
+        // the words of the start and of the end, the middle isn't read
+        let mut bytes = vec![0; 200_000];
+        bytes[0] = 1;
+        bytes[100_000] = 1;
+        bytes[199_992] = 2;
+        fs::write(&path, &bytes)?;
+        assert_eq!(format!("{:016x}", 200_000 + 3), source_hash(&path)?);
+
+        // a file smaller than a chunk is read twice
+        fs::write(&path, [1, 0, 0, 0, 0, 0, 0, 0, 0, 0])?;
+        assert_eq!(format!("{:016x}", 10 + 2), source_hash(&path)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<()> {
+        let setup = setup()?;
+        let hash = source_hash(&setup.source)?;
+        let file = setup.db.get(setup.id)?.unwrap();
+        write(&setup.db, &file, Some(hash.clone()))?;
+
+        let path = path_for(&setup.output);
+        let sidecar = Sidecar::read(&path)?;
+        assert_eq!(SIDECAR_VERSION, sidecar.version);
+        assert_eq!(setup.source, sidecar.result.path);
+        assert_eq!(Some(setup.output.clone()), sidecar.result.output_path);
+        assert_eq!(Some(hash.clone()), sidecar.source.opensubtitles_hash);
+        assert_eq!(200_000, sidecar.source.size);
+        assert_eq!(Some(10), sidecar.output_size);
+        let duration = crate::ffprobe::fixtures()[0].duration().unwrap();
+        let speed = sidecar.speed.unwrap();
+        assert!((speed - duration / 100.0).abs() < 1e-9, "{speed}");
+        assert_eq!(Verification::default(), sidecar.verification);
+        let json = serde_json::to_string(&sidecar)?;
+        assert_eq!(sidecar, serde_json::from_str(&json)?);
+
+        // a later verification is added, the hash of the source is kept
+        setup.db.set_verification(setup.id, None)?;
+        fs::remove_file(&setup.source)?;
+        assert!(update(&setup.db, setup.id, None)?);
+        let updated = Sidecar::read(&path)?;
+        assert!(updated.verification.verified_at.is_some());
+        assert_eq!(Some(hash), updated.source.opensubtitles_hash);
+
+        // newer formats aren't guessed at
+        fs::write(&path, json.replace("\"version\":1", "\"version\":2"))?;
+        assert!(Sidecar::read(&path).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_update() -> Result<()> {
+        let setup = setup()?;
+        // files without a report don't get one
+        assert!(!update(&setup.db, setup.id, None)?);
+        assert!(!path_for(&setup.output).exists());
+
+        // a report is moved along with its output
+        let file = setup.db.get(setup.id)?.unwrap();
+        write(&setup.db, &file, None)?;
+        fs::rename(&setup.output, &setup.source)?;
+        setup.db.record_result(&FileResult::transcoded(
+            setup.id,
+            &setup.source,
+            &setup.source,
+        ))?;
+        assert!(update(&setup.db, setup.id, Some(&setup.output))?);
+        assert!(!path_for(&setup.output).exists());
+        assert_eq!(
+            Some(setup.source.clone()),
+            Sidecar::read(&path_for(&setup.source))?.result.output_path
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_orphan() -> Result<()> {
+        let setup = setup()?;
+        let file = setup.db.get(setup.id)?.unwrap();
+        write(&setup.db, &file, None)?;
+        let path = path_for(&setup.output);
+        // the output is still there
+        remove_orphan(&setup.output);
+        assert!(path.exists());
+        fs::remove_file(&setup.output)?;
+        remove_orphan(&setup.output);
+        assert!(!path.exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+/// Parses sizes like `500`, `1.5G`, `10GB` or `10GiB`, case-insensitively.
+///
+/// `KB`, `MB`, `GB` and `TB` are decimal (SI) units, `KiB`, `MiB`, `GiB` and `TiB`
+/// binary (IEC) units. The single letters `K`, `M`, `G` and `T` are binary as well.
+pub fn parse_bytes(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, suffix) = value.split_at(split);
+    let multiplier: u64 = match suffix.trim().to_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kib" => 1 << 10,
+        "m" | "mib" => 1 << 20,
+        "g" | "gib" => 1 << 30,
+        "t" | "tib" => 1 << 40,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "tb" => 1_000_000_000_000,
+        _ => {
+            return Err(format!(
+                "unknown size unit in '{value}', expected e.g. 500M, 1.5GB or 2GiB"
+            ));
+        }
+    };
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("invalid size '{value}', expected e.g. 500M, 1.5GB or 2GiB"))?;
+
+    let bytes = number * multiplier as f64;
+    if bytes > u64::MAX as f64 {
+        return Err(format!("size '{value}' is too large"));
+    }
+    Ok(bytes.round() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_bytes() {
+        let cases = [
+            ("500", 500),
+            ("0", 0),
+            ("500b", 500),
+            ("1k", 1024),
+            ("1K", 1024),
+            ("1KiB", 1024),
+            ("1kb", 1000),
+            ("1.5G", 1_610_612_736),
+            ("1.5GB", 1_500_000_000),
+            ("10GiB", 10_737_418_240),
+            ("10gib", 10_737_418_240),
+            ("2 MiB", 2_097_152),
+            ("2MB", 2_000_000),
+            (" 3m ", 3_145_728),
+            ("1T", 1_099_511_627_776),
+            ("1TB", 1_000_000_000_000),
+            (".5k", 512),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(Ok(expected), parse_bytes(input), "input: {input}");
+        }
+    }
+
+    #[test]
+    fn test_parse_bytes_rejects_garbage() {
+        for input in [
+            "",
+            "G",
+            "abc",
+            "1.2.3G",
+            "10XB",
+            "-5M",
+            "1e3",
+            "5 G B",
+            "99999999999T",
+        ] {
+            assert!(parse_bytes(input).is_err(), "input: {input}");
+        }
+    }
+}
@@ -0,0 +1,300 @@
+//! What changed in a source since it was queued. Many failures come from files
+//! that were replaced after the scan, e.g. by a download manager upgrading them,
+//! so a failed transcode compares the source with what the scan recorded.
+
+use std::fmt;
+use std::fs;
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use camino::Utf8Path;
+use human_repr::{HumanCount, HumanDuration};
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::Result;
+use crate::database::TranscodeFile;
+use crate::ffprobe::FfProbe;
+
+/// How long the probe of a source after a failure may take, storage that stopped
+/// responding shouldn't hold up the run.
+pub const PROBE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Probes of the same file can disagree on its duration by a few frames.
+const DURATION_TOLERANCE: f64 = 1.0;
+
+/// What is known about a source at one point in time. Fields that are `None`
+/// aren't compared.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceState {
+    pub size: u64,
+    pub modified: Option<Timestamp>,
+    pub duration: Option<f64>,
+    pub codec: Option<String>,
+}
+
+impl SourceState {
+    pub fn new(size: u64, modified: Option<Timestamp>, probe: Option<&FfProbe>) -> Self {
+        SourceState {
+            size,
+            modified,
+            duration: probe.and_then(FfProbe::duration),
+            codec: probe.map(|probe| probe.video_codec().to_string()),
+        }
+    }
+
+    /// What the scan recorded for a file.
+    pub fn recorded(file: &TranscodeFile) -> Self {
+        SourceState::new(
+            file.file_size as u64,
+            file.source_modified,
+            file.ffprobe().as_ref(),
+        )
+    }
+}
+
+/// A difference between the recorded and the current state of a source.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "change", rename_all = "kebab-case")]
+pub enum SourceChange {
+    Gone,
+    Size {
+        recorded: u64,
+        current: u64,
+    },
+    Modified {
+        recorded: Timestamp,
+        current: Timestamp,
+    },
+    Duration {
+        recorded: f64,
+        current: f64,
+    },
+    Codec {
+        recorded: String,
+        current: String,
+    },
+}
+
+impl fmt::Display for SourceChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceChange::Gone => write!(f, "the file is gone"),
+            SourceChange::Size { recorded, current } => write!(
+                f,
+                "size {} -> {}",
+                recorded.human_count_bytes(),
+                current.human_count_bytes()
+            ),
+            SourceChange::Modified { recorded, current } => {
+                write!(f, "modified {recorded} -> {current}")
+            }
+            SourceChange::Duration { recorded, current } => write!(
+                f,
+                "duration {} -> {}",
+                recorded.human_duration(),
+                current.human_duration()
+            ),
+            SourceChange::Codec { recorded, current } => write!(f, "codec {recorded} -> {current}"),
+        }
+    }
+}
+
+/// The differences between two states of a source, comparing only what is known
+/// in both.
+pub fn diff(recorded: &SourceState, current: &SourceState) -> Vec<SourceChange> {
+    let mut changes = vec![];
+    if recorded.size != current.size {
+        changes.push(SourceChange::Size {
+            recorded: recorded.size,
+            current: current.size,
+        });
+    }
+    if let (Some(recorded), Some(current)) = (recorded.modified, current.modified)
+        && recorded != current
+    {
+        changes.push(SourceChange::Modified { recorded, current });
+    }
+    if let (Some(recorded), Some(current)) = (recorded.duration, current.duration)
+        && (recorded - current).abs() > DURATION_TOLERANCE
+    {
+        changes.push(SourceChange::Duration { recorded, current });
+    }
+    if let (Some(recorded), Some(current)) = (&recorded.codec, &current.codec)
+        && recorded != current
+    {
+        changes.push(SourceChange::Codec {
+            recorded: recorded.clone(),
+            current: current.clone(),
+        });
+    }
+    changes
+}
+
+/// What changed in a file's source since it was scanned. A source that is gone
+/// isn't probed, a probe that fails leaves the duration and the codec out.
+pub fn check(
+    file: &TranscodeFile,
+    probe: impl FnOnce(&Utf8Path) -> Result<FfProbe>,
+) -> Vec<SourceChange> {
+    let metadata = match fs::metadata(&file.path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return vec![SourceChange::Gone],
+        Err(e) => {
+            warn!("Could not check whether {} changed: {e}", file.path);
+            return vec![];
+        }
+    };
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|time| Timestamp::try_from(time).ok());
+    let current = match probe(&file.path) {
+        Ok(probe) => SourceState::new(metadata.len(), modified, Some(&probe)),
+        Err(e) => {
+            warn!("Could not probe {} again: {e}", file.path);
+            SourceState::new(metadata.len(), modified, None)
+        }
+    };
+    diff(&SourceState::recorded(file), &current)
+}
+
+/// The changes on one line, for logs and `show`.
+pub fn describe(changes: &[SourceChange]) -> String {
+    let changes: Vec<_> = changes.iter().map(ToString::to_string).collect();
+    format!(
+        "the source changed since it was queued: {}",
+        changes.join(", ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::eyre;
+
+    use super::*;
+    use crate::database::{Database, NewTranscodeFile};
+
+    fn state(size: u64, modified: i64, duration: f64, codec: &str) -> SourceState {
+        SourceState {
+            size,
+            modified: Some(Timestamp::from_second(modified).unwrap()),
+            duration: Some(duration),
+            codec: Some(codec.into()),
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let recorded = state(1000, 100, 3600.0, "h264");
+        assert!(diff(&recorded, &recorded).is_empty());
+        // probes that disagree by a few frames are the same file
+        assert!(diff(&recorded, &state(1000, 100, 3600.4, "h264")).is_empty());
+
+        let replaced = state(800, 200, 3500.0, "hevc");
+        assert_eq!(
+            vec![
+                SourceChange::Size {
+                    recorded: 1000,
+                    current: 800
+                },
+                SourceChange::Modified {
+                    recorded: Timestamp::from_second(100).unwrap(),
+                    current: Timestamp::from_second(200).unwrap()
+                },
+                SourceChange::Duration {
+                    recorded: 3600.0,
+                    current: 3500.0
+                },
+                SourceChange::Codec {
+                    recorded: "h264".into(),
+                    current: "hevc".into()
+                },
+            ],
+            diff(&recorded, &replaced)
+        );
+
+        // what is missing on either side isn't compared
+        let unprobed = SourceState::new(800, None, None);
+        assert_eq!(
+            vec![SourceChange::Size {
+                recorded: 1000,
+                current: 800
+            }],
+            diff(&recorded, &unprobed)
+        );
+        assert_eq!(
+            "the source changed since it was queued: size 1kB -> 800B, codec h264 -> hevc",
+            describe(&[
+                diff(&recorded, &replaced)[0].clone(),
+                diff(&recorded, &replaced)[3].clone()
+            ])
+        );
+    }
+
+    #[test]
+    fn test_check() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        let path = dir.join("movie.mkv");
+        fs::write(&path, b"the movie")?;
+        let db = Database::in_memory()?;
+        db.insert(NewTranscodeFile {
+            path: path.clone(),
+            file_size: 9,
+            ffprobe_info: crate::ffprobe::fixtures()[0].clone(),
+        })?;
+        let file = db.get_by_path(&path)?.unwrap();
+        assert!(check(&file, |_| Ok(crate::ffprobe::fixtures()[0].clone())).is_empty());
+
+        // the *arr stack put a different release in its place
+        fs::write(&path, b"another release")?;
+        let fixtures = crate::ffprobe::fixtures();
+        let changes = check(&file, |_| Ok(fixtures[1].clone()));
+        assert_eq!(
+            vec![
+                SourceChange::Size {
+                    recorded: 9,
+                    current: 15
+                },
+                SourceChange::Duration {
+                    recorded: fixtures[0].duration().unwrap(),
+                    current: fixtures[1].duration().unwrap()
+                },
+                SourceChange::Codec {
+                    recorded: "h264".into(),
+                    current: "hevc".into()
+                },
+            ],
+            changes
+        );
+        // a probe that timed out only leaves out what it would have found
+        let changes = check(&file, |_| Err(eyre!("ffprobe took longer than 30s")));
+        assert_eq!(1, changes.len());
+
+        // a source that is gone isn't probed
+        fs::remove_file(&path)?;
+        let changes = check(&file, |_| panic!("probed a missing file"));
+        assert_eq!(vec![SourceChange::Gone], changes);
+        Ok(())
+    }
+
+    #[test]
+    fn test_format() -> Result<()> {
+        let changes = vec![
+            SourceChange::Gone,
+            SourceChange::Size {
+                recorded: 1000,
+                current: 800,
+            },
+        ];
+        let json = serde_json::to_string(&changes)?;
+        assert_eq!(
+            r#"[{"change":"gone"},{"change":"size","recorded":1000,"current":800}]"#,
+            json
+        );
+        assert_eq!(changes, serde_json::from_str::<Vec<SourceChange>>(&json)?);
+        Ok(())
+    }
+}
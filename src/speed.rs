@@ -0,0 +1,426 @@
+//! `--speed`, one scale from 0 (slowest, best quality) to 10 (fastest) for all
+//! encoders, whose own presets go in different directions: SVT-AV1 is faster
+//! towards 13, libvpx-vp9 towards 8, NVENC slower towards p7, QSV and VAAPI
+//! slower towards 1 and AMF has three named levels.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::device::VideoCodec;
+use crate::transcode::GpuMode;
+
+/// The native presets for every step of the scale.
+///
+/// | speed | SVT-AV1 | x264/x265 | NVENC | QSV | libvpx-vp9 | AMF      |
+/// |-------|---------|-----------|-------|-----|------------|----------|
+/// | 0     | 0       | veryslow  | p7    | 1   | 0          | quality  |
+/// | 1     | 2       | veryslow  | p7    | 1   | 0          | quality  |
+/// | 2     | 3       | slower    | p7    | 2   | 1          | quality  |
+/// | 3     | 4       | slower    | p7    | 2   | 1          | quality  |
+/// | 4     | 5       | slow      | p7    | 3   | 2          | quality  |
+/// | 5     | 6       | slow      | p7    | 3   | 2          | quality  |
+/// | 6     | 7       | medium    | p7    | 4   | 3          | quality  |
+/// | 7     | 8       | medium    | p6    | 5   | 4          | balanced |
+/// | 8     | 10      | fast      | p5    | 6   | 5          | balanced |
+/// | 9     | 12      | faster    | p3    | 7   | 6          | speed    |
+/// | 10    | 13      | veryfast  | p1    | 7   | 8          | speed    |
+///
+/// The hardware encoders are fast at their slowest presets already, so NVENC
+/// and AMF stay at their slowest up to the default speed. VAAPI's
+/// `-compression_level` is the same quality level of the driver as QSV's
+/// preset, and gets its column.
+const TABLE: [Row; 11] = [
+    Row::new(0, "veryslow", 7, 1, 0, "quality"),
+    Row::new(2, "veryslow", 7, 1, 0, "quality"),
+    Row::new(3, "slower", 7, 2, 1, "quality"),
+    Row::new(4, "slower", 7, 2, 1, "quality"),
+    Row::new(5, "slow", 7, 3, 2, "quality"),
+    Row::new(6, "slow", 7, 3, 2, "quality"),
+    Row::new(7, "medium", 7, 4, 3, "quality"),
+    Row::new(8, "medium", 6, 5, 4, "balanced"),
+    Row::new(10, "fast", 5, 6, 5, "balanced"),
+    Row::new(12, "faster", 3, 7, 6, "speed"),
+    Row::new(13, "veryfast", 1, 7, 8, "speed"),
+];
+
+struct Row {
+    svt: u8,
+    x26x: &'static str,
+    nvenc: u8,
+    qsv: u8,
+    vpx: u8,
+    amf: &'static str,
+}
+
+impl Row {
+    const fn new(
+        svt: u8,
+        x26x: &'static str,
+        nvenc: u8,
+        qsv: u8,
+        vpx: u8,
+        amf: &'static str,
+    ) -> Self {
+        Row {
+            svt,
+            x26x,
+            nvenc,
+            qsv,
+            vpx,
+            amf,
+        }
+    }
+}
+
+/// A step of the scale, from 0 to [`Speed::MAX`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(try_from = "u8", into = "u8")]
+pub struct Speed(u8);
+
+impl Speed {
+    pub const MAX: u8 = TABLE.len() as u8 - 1;
+
+    /// The same encode time as SVT-AV1's preset 7, the default before the scale.
+    pub const DEFAULT: Speed = Speed(6);
+
+    pub fn value(self) -> u8 {
+        self.0
+    }
+}
+
+impl Default for Speed {
+    fn default() -> Self {
+        Speed::DEFAULT
+    }
+}
+
+impl TryFrom<u8> for Speed {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        if value > Speed::MAX {
+            return Err(format!(
+                "speed {value} is out of range, expected 0 (slowest) to {} (fastest)",
+                Speed::MAX
+            ));
+        }
+        Ok(Speed(value))
+    }
+}
+
+impl From<Speed> for u8 {
+    fn from(speed: Speed) -> Self {
+        speed.0
+    }
+}
+
+impl FromStr for Speed {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value: u8 = s.parse().map_err(|_| {
+            format!(
+                "invalid speed '{s}', expected 0 (slowest) to {} (fastest)",
+                Speed::MAX
+            )
+        })?;
+        Speed::try_from(value)
+    }
+}
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The encoders, as far as their presets go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Family {
+    Svt,
+    X26x,
+    Nvenc,
+    Qsv,
+    Vaapi,
+    Vpx,
+    Amf,
+}
+
+impl Family {
+    pub fn of(gpu: Option<&GpuMode>, codec: VideoCodec) -> Self {
+        match (gpu, codec) {
+            // only ever encoded on the CPU
+            (_, VideoCodec::Vp9) => Family::Vpx,
+            (Some(GpuMode::Nvidia), _) => Family::Nvenc,
+            (Some(GpuMode::Qsv), _) => Family::Qsv,
+            (Some(GpuMode::Vaapi), _) => Family::Vaapi,
+            (Some(GpuMode::Amf), _) => Family::Amf,
+            (None, VideoCodec::Av1) => Family::Svt,
+            (None, _) => Family::X26x,
+        }
+    }
+
+    /// The encoder's preset for a step of the scale.
+    pub fn preset(self, speed: Speed) -> Preset {
+        let row = &TABLE[speed.0 as usize];
+        match self {
+            Family::Svt => Preset::Svt(row.svt),
+            Family::X26x => Preset::X26x(row.x26x),
+            Family::Nvenc => Preset::Nvenc(row.nvenc),
+            Family::Qsv => Preset::Qsv(row.qsv),
+            Family::Vaapi => Preset::Vaapi(row.qsv),
+            Family::Vpx => Preset::Vpx(row.vpx),
+            Family::Amf => Preset::Amf(row.amf),
+        }
+    }
+
+    /// The encoder's preset for a value of the deprecated `--effort`, which is
+    /// passed on as it is. x264, x265 and AMF only have named presets and
+    /// libvpx-vp9 goes up to 8, they get the one that is about as fast as the
+    /// SVT-AV1 preset of that number.
+    pub fn native(self, effort: u8) -> Preset {
+        match self {
+            Family::Svt => Preset::Svt(effort),
+            Family::X26x => Preset::X26x(match effort {
+                0..=2 => "veryslow",
+                3..=4 => "slower",
+                5..=6 => "slow",
+                7..=8 => "medium",
+                9..=10 => "fast",
+                _ => "veryfast",
+            }),
+            Family::Nvenc => Preset::Nvenc(effort),
+            Family::Qsv => Preset::Qsv(effort),
+            Family::Vaapi => Preset::Vaapi(effort),
+            Family::Vpx => Preset::Vpx(
+                TABLE
+                    .iter()
+                    .rev()
+                    .find(|row| row.svt <= effort)
+                    .map_or(0, |row| row.vpx),
+            ),
+            Family::Amf => Preset::Amf(
+                TABLE
+                    .iter()
+                    .rev()
+                    .find(|row| row.svt <= effort)
+                    .map_or("quality", |row| row.amf),
+            ),
+        }
+    }
+}
+
+/// A preset as the encoder takes it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Preset {
+    /// 0 to 13, faster towards 13.
+    Svt(u8),
+    /// The named presets of x264 and x265.
+    X26x(&'static str),
+    /// p1 to p7, slower towards p7.
+    Nvenc(u8),
+    /// 1 (veryslow) to 7 (veryfast).
+    Qsv(u8),
+    /// `-compression_level`, 1 to 7 like QSV.
+    Vaapi(u8),
+    /// libvpx-vp9's `-cpu-used`, 0 to 8, faster towards 8.
+    Vpx(u8),
+    /// AMF's `-quality`: quality, balanced or speed.
+    Amf(&'static str),
+}
+
+impl fmt::Display for Preset {
+    /// The value of `-preset`, or of `-cpu-used` for libvpx-vp9, of
+    /// `-compression_level` for VAAPI and of `-quality` for AMF.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Preset::Svt(preset)
+            | Preset::Qsv(preset)
+            | Preset::Vaapi(preset)
+            | Preset::Vpx(preset) => {
+                write!(f, "{preset}")
+            }
+            Preset::X26x(preset) | Preset::Amf(preset) => write!(f, "{preset}"),
+            Preset::Nvenc(preset) => write!(f, "p{preset}"),
+        }
+    }
+}
+
+/// The preset to encode with: `--effort` as the encoder's own number if it was
+/// given, the step of the scale otherwise.
+pub fn resolve(speed: Speed, effort: Option<u8>, family: Family) -> Preset {
+    match effort {
+        Some(effort) => family.native(effort),
+        None => family.preset(speed),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn presets(family: Family) -> Vec<String> {
+        (0..=Speed::MAX)
+            .map(|speed| family.preset(Speed(speed)).to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_table() {
+        assert_eq!(
+            vec!["0", "2", "3", "4", "5", "6", "7", "8", "10", "12", "13"],
+            presets(Family::Svt)
+        );
+        assert_eq!(
+            vec![
+                "veryslow", "veryslow", "slower", "slower", "slow", "slow", "medium", "medium",
+                "fast", "faster", "veryfast"
+            ],
+            presets(Family::X26x)
+        );
+        assert_eq!(
+            vec![
+                "p7", "p7", "p7", "p7", "p7", "p7", "p7", "p6", "p5", "p3", "p1"
+            ],
+            presets(Family::Nvenc)
+        );
+        assert_eq!(
+            vec!["1", "1", "2", "2", "3", "3", "4", "5", "6", "7", "7"],
+            presets(Family::Qsv)
+        );
+        assert_eq!(
+            vec!["0", "0", "1", "1", "2", "2", "3", "4", "5", "6", "8"],
+            presets(Family::Vpx)
+        );
+        assert_eq!(
+            vec![
+                "quality", "quality", "quality", "quality", "quality", "quality", "quality",
+                "balanced", "balanced", "speed", "speed"
+            ],
+            presets(Family::Amf)
+        );
+    }
+
+    #[test]
+    fn test_faster_towards_the_end() {
+        // every encoder gets faster or stays, the native numbers go either way
+        let speed = |preset: Preset| match preset {
+            Preset::Svt(preset) => preset as i32,
+            Preset::X26x(name) => [
+                "veryslow", "slower", "slow", "medium", "fast", "faster", "veryfast",
+            ]
+            .iter()
+            .position(|n| *n == name)
+            .unwrap() as i32,
+            Preset::Nvenc(preset) => -(preset as i32),
+            Preset::Qsv(preset) | Preset::Vaapi(preset) | Preset::Vpx(preset) => preset as i32,
+            Preset::Amf(name) => ["quality", "balanced", "speed"]
+                .iter()
+                .position(|n| *n == name)
+                .unwrap() as i32,
+        };
+        for family in [
+            Family::Svt,
+            Family::X26x,
+            Family::Nvenc,
+            Family::Qsv,
+            Family::Vaapi,
+            Family::Vpx,
+            Family::Amf,
+        ] {
+            for step in 1..=Speed::MAX {
+                assert!(
+                    speed(family.preset(Speed(step))) >= speed(family.preset(Speed(step - 1))),
+                    "{family:?} at {step}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_boundaries() {
+        assert_eq!(Ok(Speed(0)), "0".parse());
+        assert_eq!(Ok(Speed(10)), "10".parse());
+        assert_eq!(Ok(Speed(10)), Speed::try_from(10));
+        for invalid in ["11", "255", "-1", "fast", ""] {
+            assert!(invalid.parse::<Speed>().is_err(), "{invalid}");
+        }
+        assert!(Speed::try_from(11).is_err());
+        assert!(serde_json::from_str::<Speed>("11").is_err());
+        assert_eq!(Speed(4), serde_json::from_str::<Speed>("4").unwrap());
+        assert_eq!("4", serde_json::to_string(&Speed(4)).unwrap());
+    }
+
+    #[test]
+    fn test_family() {
+        assert_eq!(Family::Svt, Family::of(None, VideoCodec::Av1));
+        assert_eq!(Family::X26x, Family::of(None, VideoCodec::Hevc));
+        assert_eq!(Family::X26x, Family::of(None, VideoCodec::H264));
+        assert_eq!(Family::Vpx, Family::of(None, VideoCodec::Vp9));
+        assert_eq!(
+            Family::Vpx,
+            Family::of(Some(&GpuMode::Nvidia), VideoCodec::Vp9)
+        );
+        assert_eq!(
+            Family::Vaapi,
+            Family::of(Some(&GpuMode::Vaapi), VideoCodec::Hevc)
+        );
+        assert_eq!(
+            Family::Amf,
+            Family::of(Some(&GpuMode::Amf), VideoCodec::Av1)
+        );
+        assert_eq!(
+            Family::Nvenc,
+            Family::of(Some(&GpuMode::Nvidia), VideoCodec::Av1)
+        );
+        assert_eq!(
+            Family::Qsv,
+            Family::of(Some(&GpuMode::Qsv), VideoCodec::Hevc)
+        );
+    }
+
+    #[test]
+    fn test_native_effort() {
+        // the same number means what the encoder makes of it
+        assert_eq!(
+            Preset::Svt(7),
+            resolve(Speed::DEFAULT, Some(7), Family::Svt)
+        );
+        assert_eq!(
+            Preset::Nvenc(7),
+            resolve(Speed::DEFAULT, Some(7), Family::Nvenc)
+        );
+        assert_eq!(
+            Preset::Qsv(7),
+            resolve(Speed::DEFAULT, Some(7), Family::Qsv)
+        );
+        // x264 and x265 keep the presets --effort gave them before
+        let x26x: Vec<_> = (0..=14)
+            .map(|effort| Family::X26x.native(effort).to_string())
+            .collect();
+        assert_eq!(
+            vec![
+                "veryslow", "veryslow", "veryslow", "slower", "slower", "slow", "slow", "medium",
+                "medium", "fast", "fast", "veryfast", "veryfast", "veryfast", "veryfast"
+            ],
+            x26x
+        );
+        // and libvpx-vp9 gets the cpu-used of the speed with that SVT-AV1 preset
+        let vpx: Vec<_> = (0..=14)
+            .map(|effort| Family::Vpx.native(effort).to_string())
+            .collect();
+        assert_eq!(
+            vec![
+                "0", "0", "0", "1", "1", "2", "2", "3", "4", "4", "5", "5", "6", "8", "8"
+            ],
+            vpx
+        );
+        // AMF the same way
+        assert_eq!(Preset::Amf("quality"), Family::Amf.native(7));
+        assert_eq!(Preset::Amf("balanced"), Family::Amf.native(8));
+        assert_eq!(Preset::Amf("speed"), Family::Amf.native(13));
+        assert_eq!(Preset::Svt(8), resolve(Speed(7), None, Family::Svt));
+    }
+}
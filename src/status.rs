@@ -0,0 +1,292 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use camino::{Utf8Path, Utf8PathBuf};
+use jiff::Timestamp;
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+use crate::database::{Database, StatusCount, TranscodeStatus};
+use crate::lock::LockHolder;
+
+/// A file that is currently being transcoded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ActiveFile {
+    pub path: Utf8PathBuf,
+    pub position_ms: u64,
+    pub duration_ms: u64,
+}
+
+/// How a file of the run ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileOutcome {
+    Encoded,
+    /// Skipped when it came up, e.g. because its output appeared in the meantime.
+    Skipped {
+        /// Whether it was skipped because it was already done.
+        existing: bool,
+    },
+    Failed,
+}
+
+/// Point-in-time status of a transcode run.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct StatusSnapshot {
+    pub paused: bool,
+    pub total_files: usize,
+    /// Files that are done, however they ended.
+    pub finished_files: usize,
+    #[serde(default)]
+    pub encoded_files: usize,
+    /// Files skipped because they were already done, e.g. their output appeared.
+    #[serde(default)]
+    pub skipped_existing: usize,
+    /// Files skipped for any other reason, e.g. the disk was full.
+    #[serde(default)]
+    pub skipped_ignored: usize,
+    pub failed_files: usize,
+    pub active: Vec<ActiveFile>,
+}
+
+impl StatusSnapshot {
+    /// Files that were skipped, however they were.
+    #[cfg_attr(not(feature = "http"), allow(dead_code))]
+    pub fn skipped_files(&self) -> usize {
+        self.skipped_existing + self.skipped_ignored
+    }
+}
+
+/// Live status of the current run, updated by the workers and read by observers.
+#[derive(Debug, Default)]
+pub struct RunStatus {
+    snapshot: Mutex<StatusSnapshot>,
+}
+
+impl RunStatus {
+    pub fn new(total_files: usize) -> Self {
+        Self {
+            snapshot: Mutex::new(StatusSnapshot {
+                total_files,
+                ..Default::default()
+            }),
+        }
+    }
+
+    pub fn start_file(&self, path: &Utf8Path, duration_ms: u64) {
+        self.snapshot.lock().unwrap().active.push(ActiveFile {
+            path: path.to_owned(),
+            position_ms: 0,
+            duration_ms,
+        });
+    }
+
+    pub fn update_file(&self, path: &Utf8Path, position_ms: u64) {
+        let mut snapshot = self.snapshot.lock().unwrap();
+        if let Some(file) = snapshot.active.iter_mut().find(|f| f.path == path) {
+            file.position_ms = position_ms;
+        }
+    }
+
+    pub fn finish_file(&self, path: &Utf8Path, outcome: FileOutcome) {
+        let mut snapshot = self.snapshot.lock().unwrap();
+        snapshot.active.retain(|f| f.path != path);
+        snapshot.finished_files += 1;
+        match outcome {
+            FileOutcome::Encoded => snapshot.encoded_files += 1,
+            FileOutcome::Skipped { existing: true } => snapshot.skipped_existing += 1,
+            FileOutcome::Skipped { existing: false } => snapshot.skipped_ignored += 1,
+            FileOutcome::Failed => snapshot.failed_files += 1,
+        }
+    }
+
+    #[cfg_attr(not(feature = "http"), allow(dead_code))]
+    pub fn set_paused(&self, paused: bool) {
+        self.snapshot.lock().unwrap().paused = paused;
+    }
+
+    pub fn snapshot(&self) -> StatusSnapshot {
+        self.snapshot.lock().unwrap().clone()
+    }
+}
+
+/// Number and total size of files.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FileCount {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+fn by_status(counts: Vec<StatusCount>) -> BTreeMap<String, FileCount> {
+    counts
+        .into_iter()
+        .map(|count| {
+            (
+                count.status.as_str().to_string(),
+                FileCount {
+                    files: count.files,
+                    bytes: count.bytes,
+                },
+            )
+        })
+        .collect()
+}
+
+/// The run that started last, with what became of the files it transcoded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LastRun {
+    pub id: i64,
+    #[serde(with = "jiff::fmt::serde::timestamp::second::required")]
+    pub started_on: Timestamp,
+    pub ffmpeg_version: String,
+    pub statuses: BTreeMap<String, FileCount>,
+}
+
+/// A file that a worker claimed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClaimedFile {
+    pub path: Utf8PathBuf,
+    pub worker: Option<String>,
+    #[serde(with = "jiff::fmt::serde::timestamp::second::optional")]
+    pub claimed_at: Option<Timestamp>,
+}
+
+/// Status of the whole queue for dashboards, written by `export-status` and
+/// served at `/snapshot` by `transcode --listen`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueueSnapshot {
+    #[serde(with = "jiff::fmt::serde::timestamp::second::required")]
+    pub generated_at: Timestamp,
+    /// Files and bytes per status, keyed by the status as stored in the database.
+    pub statuses: BTreeMap<String, FileCount>,
+    pub bytes_pending: u64,
+    pub bytes_saved: u64,
+    /// The process holding the instance lock, e.g. a running scan.
+    pub lock: Option<LockHolder>,
+    /// Files that workers are transcoding, on any machine.
+    pub claimed: Vec<ClaimedFile>,
+    pub last_run: Option<LastRun>,
+    /// Live progress, only known to the process that runs the queue.
+    pub run: Option<StatusSnapshot>,
+}
+
+impl QueueSnapshot {
+    pub fn collect(database: &Database) -> Result<Self> {
+        let statuses = by_status(database.status_counts(None)?);
+        let last_run = match database.latest_run()? {
+            Some(run) => Some(LastRun {
+                id: run.id,
+                started_on: run.started_on,
+                ffmpeg_version: run.ffmpeg_version,
+                statuses: by_status(database.status_counts(Some(run.id))?),
+            }),
+            None => None,
+        };
+        Ok(QueueSnapshot {
+            generated_at: database.now()?,
+            bytes_pending: statuses
+                .get(TranscodeStatus::Pending.as_str())
+                .map_or(0, |count| count.bytes),
+            statuses,
+            bytes_saved: database.bytes_saved()?,
+            lock: database.lock_holder()?,
+            claimed: database
+                .claims()?
+                .into_iter()
+                .map(|file| ClaimedFile {
+                    path: file.path,
+                    worker: file.claimed_by,
+                    claimed_at: file.claimed_at,
+                })
+                .collect(),
+            last_run,
+            run: None,
+        })
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::NewTranscodeFile;
+    use crate::ffprobe::FfProbe;
+    use crate::version::FfmpegVersion;
+
+    #[test]
+    fn test_finish_file() {
+        let status = RunStatus::new(4);
+        for (path, outcome) in [
+            ("/a.mkv", FileOutcome::Encoded),
+            ("/b.mkv", FileOutcome::Skipped { existing: true }),
+            ("/c.mkv", FileOutcome::Skipped { existing: false }),
+            ("/d.mkv", FileOutcome::Failed),
+        ] {
+            status.start_file(path.into(), 1000);
+            status.finish_file(path.into(), outcome);
+        }
+        let snapshot = status.snapshot();
+        assert_eq!(4, snapshot.finished_files);
+        assert_eq!(2, snapshot.skipped_files());
+        assert!(snapshot.active.is_empty());
+
+        let value = serde_json::to_value(&snapshot).unwrap();
+        assert_eq!(1, value["encoded_files"]);
+        assert_eq!(1, value["skipped_existing"]);
+        assert_eq!(1, value["skipped_ignored"]);
+        assert_eq!(1, value["failed_files"]);
+    }
+
+    #[test]
+    fn test_queue_snapshot() -> Result<()> {
+        let database = Database::in_memory()?;
+        for (path, size) in [("/a.mkv", 1000), ("/b.mkv", 3000), ("/c.mkv", 500)] {
+            database.insert(NewTranscodeFile {
+                path: path.into(),
+                file_size: size,
+                ffprobe_info: FfProbe::default(),
+            })?;
+        }
+        let run = database.insert_run(
+            &FfmpegVersion {
+                version: "7.1".into(),
+                libraries: Default::default(),
+            },
+            false,
+        )?;
+        let b = database.get_by_path("/b.mkv".into())?.unwrap().id;
+        database.insert_crf_attempt(b, 24, 1200)?;
+        database.set_file_status(b, TranscodeStatus::Success, None)?;
+        database.set_file_run(b, run)?;
+        let c = database.get_by_path("/c.mkv".into())?.unwrap().id;
+        assert!(database.claim(c, "nas:42", TranscodeStatus::Pending)?);
+
+        let snapshot = QueueSnapshot::collect(&database)?;
+        assert_eq!(
+            FileCount {
+                files: 1,
+                bytes: 1000
+            },
+            snapshot.statuses["pending"]
+        );
+        assert_eq!(1000, snapshot.bytes_pending);
+        assert_eq!(1800, snapshot.bytes_saved);
+        assert_eq!(None, snapshot.lock);
+        assert_eq!(Some("nas:42".into()), snapshot.claimed[0].worker);
+        let last_run = snapshot.last_run.as_ref().unwrap();
+        assert_eq!(run, last_run.id);
+        assert_eq!(1, last_run.statuses["success"].files);
+        assert_eq!(1, last_run.statuses.len());
+
+        let json = snapshot.to_json()?;
+        let value: serde_json::Value = serde_json::from_str(&json)?;
+        assert_eq!(3000, value["statuses"]["success"]["bytes"]);
+        assert_eq!(1, value["statuses"]["in_progress"]["files"]);
+        assert!(value["generated_at"].is_i64());
+        assert!(value["run"].is_null());
+        assert_eq!(snapshot, serde_json::from_str(&json)?);
+        Ok(())
+    }
+}
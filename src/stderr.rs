@@ -0,0 +1,129 @@
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, Read};
+use std::thread::{self, JoinHandle};
+
+use color_eyre::eyre::eyre;
+use tracing::debug;
+
+use crate::Result;
+
+/// How much of the end of ffmpeg's stderr is kept for the error message, in
+/// bytes. The actual error is in the last few lines.
+pub const TAIL_BYTES: usize = 64 * 1024;
+
+/// The last bytes a process wrote. Older bytes are dropped once it's full.
+#[derive(Debug, Clone)]
+pub struct Tail {
+    buffer: VecDeque<u8>,
+    capacity: usize,
+    dropped: u64,
+}
+
+impl Tail {
+    pub fn new(capacity: usize) -> Self {
+        Tail {
+            buffer: VecDeque::with_capacity(capacity.min(TAIL_BYTES)),
+            capacity,
+            dropped: 0,
+        }
+    }
+
+    pub fn push(&mut self, bytes: &[u8]) {
+        let bytes = if bytes.len() > self.capacity {
+            self.dropped += (bytes.len() - self.capacity) as u64;
+            &bytes[bytes.len() - self.capacity..]
+        } else {
+            bytes
+        };
+        let overflow = (self.buffer.len() + bytes.len()).saturating_sub(self.capacity);
+        self.buffer.drain(..overflow);
+        self.dropped += overflow as u64;
+        self.buffer.extend(bytes);
+    }
+
+    /// How many bytes were dropped from the start.
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    /// The kept bytes. When some were dropped, they start at the first complete
+    /// line.
+    pub fn into_bytes(self) -> Vec<u8> {
+        let mut bytes = Vec::from(self.buffer);
+        if self.dropped > 0
+            && let Some(newline) = bytes.iter().position(|&b| b == b'\n')
+        {
+            bytes.drain(..=newline);
+        }
+        bytes
+    }
+}
+
+/// Reads a pipe on its own thread until it's closed, so that the process never
+/// blocks on writing to it while its other output is read.
+pub struct Drain {
+    handle: JoinHandle<io::Result<Tail>>,
+}
+
+impl Drain {
+    /// Starts reading. Every line is logged at debug level with `label` in front.
+    pub fn start(reader: impl Read + Send + 'static, label: String, capacity: usize) -> Self {
+        let handle = thread::spawn(move || {
+            let mut reader = BufReader::new(reader);
+            let mut tail = Tail::new(capacity);
+            let mut line = vec![];
+            loop {
+                line.clear();
+                if reader.read_until(b'\n', &mut line)? == 0 {
+                    return Ok(tail);
+                }
+                debug!("{label}: {}", String::from_utf8_lossy(&line).trim_end());
+                tail.push(&line);
+            }
+        });
+        Drain { handle }
+    }
+
+    /// Waits until the pipe is closed, which happens when the process exits.
+    pub fn finish(self) -> Result<Tail> {
+        match self.handle.join() {
+            Ok(tail) => Ok(tail?),
+            Err(_) => Err(eyre!("the thread reading stderr panicked")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tail() {
+        let mut tail = Tail::new(10);
+        tail.push(b"abc\n");
+        tail.push(b"def\n");
+        assert_eq!(0, tail.dropped());
+        assert_eq!(b"abc\ndef\n".to_vec(), tail.clone().into_bytes());
+
+        // the first line was cut off, so it's left out
+        tail.push(b"ghi\n");
+        assert_eq!(2, tail.dropped());
+        assert_eq!(b"def\nghi\n".to_vec(), tail.into_bytes());
+
+        let mut tail = Tail::new(4);
+        tail.push(b"much more than four bytes");
+        assert_eq!(21, tail.dropped());
+        assert_eq!(b"ytes".to_vec(), tail.into_bytes());
+    }
+
+    #[test]
+    fn test_drain() -> Result<()> {
+        let lines: String = (0..10_000).map(|i| format!("warning {i}\n")).collect();
+        let tail = Drain::start(io::Cursor::new(lines.clone()), "test".into(), 100).finish()?;
+        assert_eq!(lines.len() as u64 - 100, tail.dropped());
+        let kept = String::from_utf8(tail.into_bytes())?;
+        assert!(kept.ends_with("warning 9999\n"));
+        assert!(kept.starts_with("warning"));
+        Ok(())
+    }
+}
@@ -0,0 +1,308 @@
+//! Notices when the storage of the sources disappears during a run, e.g. a NAS
+//! whose mount dropped, so the rest of the queue isn't failed one file after
+//! the other.
+
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::time::Duration;
+
+use camino::{Utf8Path, Utf8PathBuf};
+
+/// How many files in a row have to fail on their unreadable source before the
+/// run waits for the storage.
+pub const FAILURES_BEFORE_PAUSE: usize = 3;
+
+/// How long a run waits for the storage to come back by default.
+pub const DEFAULT_PATIENCE: Duration = Duration::from_secs(30 * 60);
+
+/// The first wait between two checks of the storage, doubled after every check.
+const FIRST_POLL: Duration = Duration::from_secs(5);
+
+/// The longest wait between two checks of the storage.
+const MAX_POLL: Duration = Duration::from_secs(5 * 60);
+
+/// What ffmpeg prints after the input's path when it can't open it.
+const FFMPEG_INPUT_ERRORS: &[&str] = &[
+    "No such file or directory",
+    "Input/output error",
+    "Stale file handle",
+    "Transport endpoint is not connected",
+    "Host is down",
+    "No route to host",
+];
+
+/// Whether an error comes from the source file not being readable, rather than
+/// from the file itself or the encode. ffmpeg reports failing to open its input
+/// as `<input>: <error>`, before it encodes anything.
+pub fn is_input_error(error: &color_eyre::Report, input: &Utf8Path) -> bool {
+    if error
+        .chain()
+        .filter_map(|cause| cause.downcast_ref::<io::Error>())
+        .any(is_storage_io_error)
+    {
+        return true;
+    }
+    let message = error.to_string();
+    FFMPEG_INPUT_ERRORS
+        .iter()
+        .any(|text| message.contains(&format!("{input}: {text}")))
+}
+
+fn is_storage_io_error(error: &io::Error) -> bool {
+    // EIO has no error kind of its own
+    #[cfg(unix)]
+    if error.raw_os_error() == Some(libc::EIO) {
+        return true;
+    }
+    matches!(
+        error.kind(),
+        ErrorKind::NotFound
+            | ErrorKind::StaleNetworkFileHandle
+            | ErrorKind::NotConnected
+            | ErrorKind::HostUnreachable
+            | ErrorKind::NetworkUnreachable
+            | ErrorKind::TimedOut
+    )
+}
+
+/// The directory that is checked to see whether a source's storage is there.
+pub fn watched_directory(source: &Utf8Path) -> &Utf8Path {
+    source.parent().unwrap_or(source)
+}
+
+/// Whether the directory can be listed, which fails while its mount is gone.
+pub fn is_readable(directory: &Utf8Path) -> bool {
+    fs::read_dir(directory).is_ok()
+}
+
+/// A file that failed because its storage was gone.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FailedFile {
+    pub id: i64,
+    pub path: Utf8PathBuf,
+}
+
+/// What the run does after a file ended.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    Continue,
+    /// The storage seems to be gone. The files go back into the queue, no more
+    /// files are started until it's back.
+    Pause(Vec<FailedFile>),
+    /// A file that was running when the storage went away failed while the run
+    /// waits. It goes back into the queue.
+    Requeue(FailedFile),
+}
+
+/// Counts the files that failed in a row because their source couldn't be read.
+#[derive(Debug)]
+pub struct Outage {
+    threshold: usize,
+    streak: Vec<FailedFile>,
+    waiting: bool,
+}
+
+impl Outage {
+    pub fn new(threshold: usize) -> Self {
+        Outage {
+            threshold,
+            streak: vec![],
+            waiting: false,
+        }
+    }
+
+    /// Records how a file ended. `storage_failure` is whether it failed because
+    /// its storage is gone, any other outcome ends the streak.
+    pub fn record(&mut self, file: FailedFile, storage_failure: bool) -> Action {
+        if !storage_failure {
+            self.streak.clear();
+            return Action::Continue;
+        }
+        if self.waiting {
+            return Action::Requeue(file);
+        }
+        self.streak.push(file);
+        if self.streak.len() >= self.threshold {
+            self.waiting = true;
+            Action::Pause(std::mem::take(&mut self.streak))
+        } else {
+            Action::Continue
+        }
+    }
+
+    /// The storage is back, failures are counted from zero again.
+    pub fn resume(&mut self) {
+        self.waiting = false;
+        self.streak.clear();
+    }
+}
+
+/// How waiting for the storage ended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Recovery {
+    /// The storage could be read again after this long.
+    Returned(Duration),
+    /// It was still gone after the patience ran out.
+    GaveUp,
+}
+
+/// Checks whether `directory` can be read again, waiting longer between every
+/// check, until it can or `patience` runs out. `sleep` waits between checks.
+pub fn wait_until_readable(
+    directory: &Utf8Path,
+    patience: Duration,
+    readable: impl Fn(&Utf8Path) -> bool,
+    mut sleep: impl FnMut(Duration),
+) -> Recovery {
+    let mut waited = Duration::ZERO;
+    let mut poll = FIRST_POLL;
+    loop {
+        if readable(directory) {
+            return Recovery::Returned(waited);
+        }
+        if waited >= patience {
+            return Recovery::GaveUp;
+        }
+        let delay = poll.min(patience - waited);
+        sleep(delay);
+        waited += delay;
+        poll = (poll * 2).min(MAX_POLL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use color_eyre::eyre::{WrapErr, eyre};
+
+    use super::*;
+
+    fn file(id: i64) -> FailedFile {
+        FailedFile {
+            id,
+            path: format!("/mnt/nas/{id}.mkv").into(),
+        }
+    }
+
+    #[test]
+    fn test_is_input_error() {
+        let input = Utf8Path::new("/mnt/nas/movie.mkv");
+        let ffmpeg = |stderr: &str| {
+            eyre!("command ffmpeg failed with exit code 1, stdout:\n''\nstderr:\n'{stderr}'")
+        };
+        assert!(is_input_error(
+            &ffmpeg("/mnt/nas/movie.mkv: No such file or directory\n"),
+            input
+        ));
+        assert!(is_input_error(
+            &ffmpeg("/mnt/nas/movie.mkv: Input/output error\n"),
+            input
+        ));
+        // errors of the output or the encode aren't the source's storage
+        assert!(!is_input_error(
+            &ffmpeg("/mnt/out/movie.mkv: No such file or directory\n"),
+            input
+        ));
+        assert!(!is_input_error(
+            &ffmpeg("Error while decoding stream #0:0: Invalid data found\n"),
+            input
+        ));
+
+        let io = |error: io::Error| Err::<(), _>(error).wrap_err("could not probe").unwrap_err();
+        assert!(is_input_error(&io(ErrorKind::NotFound.into()), input));
+        assert!(is_input_error(
+            &io(ErrorKind::StaleNetworkFileHandle.into()),
+            input
+        ));
+        #[cfg(unix)]
+        assert!(is_input_error(
+            &io(io::Error::from_raw_os_error(libc::EIO)),
+            input
+        ));
+        assert!(!is_input_error(
+            &io(ErrorKind::PermissionDenied.into()),
+            input
+        ));
+        assert!(!is_input_error(
+            &eyre!("encoder rejected the settings"),
+            input
+        ));
+    }
+
+    #[test]
+    fn test_pauses_after_consecutive_failures() {
+        let mut outage = Outage::new(3);
+        assert_eq!(Action::Continue, outage.record(file(1), true));
+        assert_eq!(Action::Continue, outage.record(file(2), true));
+        // a file that worked means the storage is there
+        assert_eq!(Action::Continue, outage.record(file(3), false));
+        assert_eq!(Action::Continue, outage.record(file(4), true));
+        assert_eq!(Action::Continue, outage.record(file(5), true));
+        assert_eq!(
+            Action::Pause(vec![file(4), file(5), file(6)]),
+            outage.record(file(6), true)
+        );
+
+        // files that were still running fail while the run waits
+        assert_eq!(Action::Requeue(file(7)), outage.record(file(7), true));
+        assert_eq!(Action::Continue, outage.record(file(8), false));
+        assert_eq!(Action::Requeue(file(9)), outage.record(file(9), true));
+
+        outage.resume();
+        assert_eq!(Action::Continue, outage.record(file(10), true));
+        assert_eq!(Action::Continue, outage.record(file(11), true));
+        assert_eq!(
+            Action::Pause(vec![file(10), file(11), file(12)]),
+            outage.record(file(12), true)
+        );
+    }
+
+    #[test]
+    fn test_waits_with_backoff() {
+        let directory = Utf8Path::new("/mnt/nas");
+        let sleeps = RefCell::new(vec![]);
+        let checks = RefCell::new(0);
+        // readable on the sixth check
+        let recovery = wait_until_readable(
+            directory,
+            Duration::from_secs(3600),
+            |_| {
+                *checks.borrow_mut() += 1;
+                *checks.borrow() == 6
+            },
+            |delay| sleeps.borrow_mut().push(delay.as_secs()),
+        );
+        assert_eq!(vec![5, 10, 20, 40, 80], *sleeps.borrow());
+        assert_eq!(Recovery::Returned(Duration::from_secs(155)), recovery);
+
+        // the waits stop growing and the last one ends with the patience
+        let sleeps = RefCell::new(vec![]);
+        let recovery = wait_until_readable(
+            directory,
+            Duration::from_secs(1000),
+            |_| false,
+            |delay| sleeps.borrow_mut().push(delay.as_secs()),
+        );
+        assert_eq!(Recovery::GaveUp, recovery);
+        assert_eq!(vec![5, 10, 20, 40, 80, 160, 300, 300, 85], *sleeps.borrow());
+
+        let recovery = wait_until_readable(
+            directory,
+            Duration::ZERO,
+            |_| true,
+            |_| panic!("no need to wait"),
+        );
+        assert_eq!(Recovery::Returned(Duration::ZERO), recovery);
+    }
+
+    #[test]
+    fn test_is_readable() -> crate::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        assert!(is_readable(dir));
+        assert!(!is_readable(&dir.join("unmounted")));
+        assert_eq!(dir, watched_directory(&dir.join("movie.mkv")));
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+//! Tiny sample videos generated with ffmpeg for tests that need real files.
+//!
+//! The samples are made from the `testsrc` and `sine` sources of `lavfi`, so
+//! they are the same on every run. They are cached under `target/test-samples`
+//! and only generated the first time a test asks for them.
+
+use std::fs;
+use std::sync::Mutex;
+
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::Result;
+use crate::binaries::{self, Binary};
+use crate::database::{Database, NewTranscodeFile};
+use crate::ffprobe::{commandline_error, ffprobe};
+
+/// Only one thread generates samples at a time, so that two tests asking for the
+/// same sample don't write it at once.
+static GENERATE: Mutex<()> = Mutex::new(());
+
+/// Whether ffmpeg and ffprobe can be run. Prints why the test is skipped when
+/// they can't, so the caller only has to return.
+pub fn has_ffmpeg() -> bool {
+    match binaries::check(&[Binary::Ffmpeg, Binary::Ffprobe]) {
+        Ok(()) => true,
+        Err(_) => {
+            eprintln!("ffmpeg is not installed, skipping");
+            false
+        }
+    }
+}
+
+/// A sample video to generate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sample {
+    pub width: u32,
+    pub height: u32,
+    pub seconds: f64,
+    /// The ffmpeg encoder for the video stream.
+    pub video_encoder: &'static str,
+    /// The ffmpeg encoder for the audio stream, `None` for no audio.
+    pub audio_encoder: Option<&'static str>,
+    /// The file extension, which picks the container.
+    pub extension: &'static str,
+}
+
+impl Sample {
+    /// Two seconds of MPEG-4 video and AAC audio in an MP4, which every ffmpeg
+    /// build can encode.
+    pub const SMALL: Sample = Sample {
+        width: 160,
+        height: 90,
+        seconds: 2.0,
+        video_encoder: "mpeg4",
+        audio_encoder: Some("aac"),
+        extension: "mp4",
+    };
+
+    /// Like [`Sample::SMALL`], but in a Matroska file with AC-3 audio.
+    pub const MKV: Sample = Sample {
+        audio_encoder: Some("ac3"),
+        extension: "mkv",
+        ..Sample::SMALL
+    };
+
+    /// A 1080p sample without audio.
+    pub const FULL_HD: Sample = Sample {
+        width: 1920,
+        height: 1080,
+        seconds: 1.0,
+        audio_encoder: None,
+        ..Sample::SMALL
+    };
+
+    pub fn file_name(&self) -> String {
+        format!(
+            "testsrc_{}x{}_{}ms_{}_{}.{}",
+            self.width,
+            self.height,
+            (self.seconds * 1000.0).round() as u64,
+            self.video_encoder,
+            self.audio_encoder.unwrap_or("noaudio"),
+            self.extension
+        )
+    }
+
+    fn args(&self, output: &Utf8Path) -> Vec<String> {
+        let mut args = vec![
+            "-y".to_string(),
+            "-v".into(),
+            "error".into(),
+            "-f".into(),
+            "lavfi".into(),
+            "-i".into(),
+            format!(
+                "testsrc=size={}x{}:rate=25:duration={}",
+                self.width, self.height, self.seconds
+            ),
+        ];
+        if self.audio_encoder.is_some() {
+            args.extend([
+                "-f".into(),
+                "lavfi".into(),
+                "-i".into(),
+                format!(
+                    "sine=frequency=440:sample_rate=48000:duration={}",
+                    self.seconds
+                ),
+            ]);
+        }
+        args.extend(["-c:v".into(), self.video_encoder.into()]);
+        match self.audio_encoder {
+            Some(encoder) => args.extend(["-c:a".into(), encoder.into()]),
+            None => args.push("-an".into()),
+        }
+        // keep the encoder version and creation time out of the file
+        args.extend([
+            "-map_metadata".into(),
+            "-1".into(),
+            "-fflags".into(),
+            "+bitexact".into(),
+            "-flags".into(),
+            "+bitexact".into(),
+            output.to_string(),
+        ]);
+        args
+    }
+}
+
+/// The directory the samples are cached in.
+pub fn sample_dir() -> Utf8PathBuf {
+    Utf8Path::new(env!("CARGO_MANIFEST_DIR")).join("target/test-samples")
+}
+
+/// The path of the sample, generating it if it isn't cached yet.
+pub fn sample(sample: Sample) -> Result<Utf8PathBuf> {
+    let directory = sample_dir();
+    let path = directory.join(sample.file_name());
+    let _guard = GENERATE.lock().unwrap_or_else(|e| e.into_inner());
+    if path.is_file() {
+        return Ok(path);
+    }
+    fs::create_dir_all(&directory)?;
+    // other test processes may generate the same sample, so it only appears
+    // under its real name once it's complete
+    let tmp = directory.join(format!("{}.{}", std::process::id(), sample.file_name()));
+    let output = binaries::command(Binary::Ffmpeg)
+        .args(sample.args(&tmp))
+        .output()
+        .map_err(|e| binaries::spawn_error(Binary::Ffmpeg, e))?;
+    if !output.status.success() {
+        let _ = fs::remove_file(&tmp);
+        return Err(commandline_error("ffmpeg", output));
+    }
+    fs::rename(&tmp, &path)?;
+    Ok(path)
+}
+
+/// An in-memory database with the samples in the queue, in the given order.
+pub fn seeded_database(samples: &[Sample]) -> Result<(Database, Vec<Utf8PathBuf>)> {
+    let database = Database::in_memory()?;
+    let mut paths = vec![];
+    for &s in samples {
+        let path = sample(s)?;
+        database.insert(NewTranscodeFile {
+            path: path.clone(),
+            file_size: fs::metadata(&path)?.len(),
+            ffprobe_info: ffprobe(&path)?,
+        })?;
+        paths.push(path);
+    }
+    Ok((database, paths))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_names() {
+        assert_eq!(
+            "testsrc_160x90_2000ms_mpeg4_aac.mp4",
+            Sample::SMALL.file_name()
+        );
+        assert_eq!(
+            "testsrc_1920x1080_1000ms_mpeg4_noaudio.mp4",
+            Sample::FULL_HD.file_name()
+        );
+    }
+
+    #[test]
+    fn test_generate_samples() -> Result<()> {
+        if !has_ffmpeg() {
+            return Ok(());
+        }
+        let (database, paths) = seeded_database(&[Sample::SMALL, Sample::MKV, Sample::FULL_HD])?;
+        assert_eq!(3, database.list()?.len());
+
+        let small = ffprobe(&paths[0])?;
+        assert_eq!("mpeg4", small.video_codec());
+        assert!((small.duration().unwrap() - 2.0).abs() < 0.1);
+        let full_hd = ffprobe(&paths[2])?;
+        assert_eq!((1920, 1080), full_hd.resolution());
+        assert!(!full_hd.streams.iter().any(|s| s.is_audio()));
+
+        // the second time comes from the cache
+        assert_eq!(paths[0], sample(Sample::SMALL)?);
+        Ok(())
+    }
+}
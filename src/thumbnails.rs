@@ -0,0 +1,199 @@
+use camino::{Utf8Path, Utf8PathBuf};
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+use tracing::{info, warn};
+
+use crate::Result;
+use crate::binaries::{self, Binary};
+use crate::database::{Database, TranscodeFile};
+use crate::ffprobe::commandline_error;
+
+/// Width of the generated thumbnails in pixels.
+const THUMBNAIL_WIDTH: u32 = 320;
+
+#[derive(Debug, Default)]
+pub struct ThumbnailSummary {
+    pub created: usize,
+    pub existing: usize,
+    pub failed: usize,
+}
+
+fn thumbnail_args(input: &Utf8Path, seek_seconds: f64, output: &Utf8Path) -> Vec<String> {
+    vec![
+        "-y".into(),
+        "-ss".into(),
+        format!("{seek_seconds:.3}"),
+        "-i".into(),
+        input.to_string(),
+        "-frames:v".into(),
+        "1".into(),
+        "-vf".into(),
+        format!("scale={THUMBNAIL_WIDTH}:-2"),
+        "-q:v".into(),
+        "5".into(),
+        output.to_string(),
+    ]
+}
+
+/// Extracts a single frame at the given position as a JPEG.
+pub fn extract_frame(input: &Utf8Path, seek_seconds: f64, output: &Utf8Path) -> Result<()> {
+    let output = binaries::command(Binary::Ffmpeg)
+        .args(thumbnail_args(input, seek_seconds, output))
+        .output()
+        .map_err(|e| binaries::spawn_error(Binary::Ffmpeg, e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(commandline_error("ffmpeg", output))
+    }
+}
+
+/// Creates a thumbnail from the middle of every file that doesn't have one yet in
+/// `directory`, named after the file's id. Failures are logged and counted
+/// without stopping the batch.
+pub fn generate(
+    database: &Database,
+    files: Vec<TranscodeFile>,
+    directory: &Utf8Path,
+    extract: impl Fn(&Utf8Path, f64, &Utf8Path) -> Result<()> + Sync,
+) -> Result<ThumbnailSummary> {
+    std::fs::create_dir_all(directory)?;
+
+    let mut summary = ThumbnailSummary::default();
+    let mut missing = vec![];
+    for file in files {
+        let thumbnail = directory.join(format!("{}.jpg", file.id));
+        if thumbnail.is_file() {
+            summary.existing += 1;
+            if file.thumbnail_path.as_ref() != Some(&thumbnail) {
+                database.set_thumbnail(file.id, &thumbnail)?;
+            }
+        } else {
+            missing.push((file, thumbnail));
+        }
+    }
+
+    let progress = ProgressBar::new(missing.len() as u64).with_style(
+        ProgressStyle::default_bar()
+            .template("Creating thumbnails {wide_bar:.cyan/blue} {pos}/{len} {eta}")
+            .expect("bad progressbar template"),
+    );
+    let results: Vec<(i64, Utf8PathBuf, Result<()>)> = missing
+        .into_par_iter()
+        .map(|(file, thumbnail)| {
+            let duration = file
+                .ffprobe()
+                .and_then(|info| info.duration())
+                .unwrap_or_default();
+            let result = extract(&file.path, duration / 2.0, &thumbnail);
+            progress.inc(1);
+            if let Err(e) = &result {
+                warn!("could not create a thumbnail for {}: {:?}", file.path, e);
+            }
+            (file.id, thumbnail, result)
+        })
+        .collect();
+    progress.finish_and_clear();
+
+    for (id, thumbnail, result) in results {
+        match result {
+            Ok(()) => {
+                database.set_thumbnail(id, &thumbnail)?;
+                summary.created += 1;
+            }
+            Err(_) => summary.failed += 1,
+        }
+    }
+    info!(
+        "created {} thumbnails, {} already existed, {} failed",
+        summary.created, summary.existing, summary.failed
+    );
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::bail;
+
+    use super::*;
+    use crate::database::NewTranscodeFile;
+    use crate::ffprobe::{FfProbe, Format};
+
+    #[test]
+    fn test_thumbnail_args() {
+        let args = thumbnail_args("/a.mkv".into(), 30.5, "/thumbs/1.jpg".into());
+        assert_eq!(
+            vec![
+                "-y",
+                "-ss",
+                "30.500",
+                "-i",
+                "/a.mkv",
+                "-frames:v",
+                "1",
+                "-vf",
+                "scale=320:-2",
+                "-q:v",
+                "5",
+                "/thumbs/1.jpg"
+            ],
+            args
+        );
+    }
+
+    #[test]
+    fn test_generate() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let directory = Utf8Path::from_path(tempdir.path()).unwrap();
+        let db = Database::in_memory()?;
+        let files: Vec<_> = ["/a.mkv", "/broken.mkv", "/c.mkv"]
+            .into_iter()
+            .map(|path| NewTranscodeFile {
+                path: path.into(),
+                file_size: 5,
+                ffprobe_info: FfProbe {
+                    format: Format {
+                        duration: Some("60.0".into()),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            })
+            .collect();
+        db.insert_batch(&files)?;
+        let c = db.get_by_path("/c.mkv".into())?.unwrap();
+        std::fs::write(directory.join(format!("{}.jpg", c.id)), b"")?;
+
+        let summary = generate(&db, db.list()?, directory, |input, seek, output| {
+            assert_eq!(30.0, seek);
+            if input == "/broken.mkv" {
+                bail!("no video stream");
+            }
+            std::fs::write(output, b"")?;
+            Ok(())
+        })?;
+        assert_eq!(
+            (1, 1, 1),
+            (summary.created, summary.existing, summary.failed)
+        );
+
+        let a = db.get_by_path("/a.mkv".into())?.unwrap();
+        assert_eq!(
+            Some(directory.join(format!("{}.jpg", a.id))),
+            a.thumbnail_path
+        );
+        assert!(
+            db.get_by_path("/broken.mkv".into())?
+                .unwrap()
+                .thumbnail_path
+                .is_none()
+        );
+        assert!(
+            db.get_by_path("/c.mkv".into())?
+                .unwrap()
+                .thumbnail_path
+                .is_some()
+        );
+        Ok(())
+    }
+}
@@ -1,43 +1,433 @@
+use std::collections::{HashMap, HashSet};
 use std::io::{BufRead, BufReader};
-use std::process::{Command, Stdio};
-use std::time::Duration;
-use std::{fmt, fs};
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use std::{fmt, fs, thread};
 
-use camino::Utf8Path;
+use camino::{Utf8Path, Utf8PathBuf};
 use clap::ValueEnum;
+use color_eyre::eyre::{WrapErr, bail, eyre};
 use console::{Emoji, Term};
-use human_repr::HumanCount;
+use human_repr::{HumanCount, HumanDuration};
 use indicatif::{
     FormattedDuration, MultiProgress, ProgressBar, ProgressDrawTarget, ProgressState, ProgressStyle,
 };
+use jiff::Timestamp;
 use once_cell::sync::Lazy;
-use rayon::ThreadPoolBuilder;
-use rayon::prelude::*;
 use regex::Regex;
-use tracing::{debug, info, warn};
+use sysinfo::System;
+use tracing::{debug, error, info, trace, warn};
 
 use crate::Result;
+use crate::audio::{self, AudioDecision, AudioOptions, AudioTrack};
+use crate::audio_hash::{self, AudioCheck, AudioHash};
+use crate::autocrf::{self, Attempt, AutoCrf, Decision};
+use crate::binaries::{self, Binary};
+use crate::capabilities::{self, SessionLimitReached};
 use crate::collect::VideoFile;
-use crate::database::{Database, TranscodeStatus};
-use crate::ffprobe::commandline_error;
+use crate::config::{
+    self, DirectoryOverride, DirectoryOverrides, EncodeSettings, TranscodeSettings,
+};
+use crate::constraints::Constraints;
+use crate::database::{Database, ScanSkipReason, TranscodeStatus};
+use crate::device::{self, Conformance, DeviceProfile, VideoCodec};
+use crate::encoder_rules::{Encoder, EncoderRules};
+use crate::energy::{self, EnergyOptions, EnergyReport, EnergyTracker};
+use crate::ffprobe::{self, Chapter, FfProbe, commandline_error, ffprobe};
+#[cfg(feature = "http")]
+use crate::http::{HttpOptions, StatusServer};
+use crate::io_limit::{self, IoMode, StagedCopy};
+use crate::low_memory;
+use crate::ordering::FileSortOrder;
+use crate::paths::{self, CrossDevice, OutputFields, OutputPaths};
+use crate::power::{EncodeClock, SleepInhibitor};
+use crate::preflight::{self, Finding, Verdict};
+use crate::progress::{self, FileProgress, Throttle};
+use crate::renditions::{self, Leg, Rendition, RenditionOutput};
+use crate::resolved_options::ResolvedOptions;
+use crate::resources::{self, Monitor, ProcessProbe, ResourceUsage, UsageSummary};
+use crate::results::{self, FileResult, Flushed, ResultBuffer};
+use crate::resume::{self, ResumableEncode};
+use crate::scheduler::{GpuSessions, Scheduler};
+use crate::selection::{RunSummary, SkipReason, SkippedFile};
+use crate::sidecar;
+use crate::source_changes::{self, SourceChange};
+use crate::speed::Preset;
+use crate::status::{FileOutcome, RunStatus};
+use crate::stderr;
+use crate::storage::{self, Outage, Recovery};
+use crate::version;
+
+/// How often a worker refreshes the claim on the file it's transcoding.
+const CLAIM_HEARTBEAT: Duration = Duration::from_secs(60);
+
+/// The threads of the CPU encoders with `--reproducible`. Their output depends on
+/// how the frames are split between threads, so it must not depend on the machine.
+const REPRODUCIBLE_THREADS: u32 = 4;
+
+/// Input of the test encode before a run, see [`Transcoder::warm_up`].
+const WARMUP_SOURCE: &str = "testsrc=size=640x360:rate=25:duration=2";
 
 static OUT_TIME_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new(r"out_time_us=(\d+)").unwrap());
 
-#[derive(Debug, Clone, ValueEnum)]
+/// The render node VAAPI encodes on without `--gpu-device`.
+pub const DEFAULT_VAAPI_DEVICE: &str = "/dev/dri/renderD128";
+
+#[derive(Debug, Clone, PartialEq, ValueEnum)]
 pub enum GpuMode {
     Nvidia,
     Qsv,
+    /// AMD and Intel GPUs on Linux
+    Vaapi,
+    /// AMD GPUs on Windows
+    Amf,
+}
+
+/// Container for `--stdout`, which has to be written without seeking back.
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum StreamFormat {
+    #[default]
+    Matroska,
+    /// Fragmented MP4
+    Mp4,
+}
+
+impl StreamFormat {
+    fn args(self) -> &'static [&'static str] {
+        match self {
+            StreamFormat::Matroska => &["-f", "matroska"],
+            StreamFormat::Mp4 => &[
+                "-f",
+                "mp4",
+                "-movflags",
+                "frag_keyframe+empty_moov+default_base_moof",
+            ],
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct TranscodeOptions {
-    pub crf: u8,
-    pub effort: u8,
+    /// Settings given on the command line.
+    pub cli: TranscodeSettings,
+    /// Settings from the config file.
+    pub config: TranscodeSettings,
     pub dry_run: bool,
     pub replace: bool,
+    /// Stop once the output is written, leaving the file `Encoded` until
+    /// `finalize` checks it and puts it in place.
+    pub no_finalize: bool,
+    /// Write a report next to each output, see [`sidecar`].
+    pub sidecar: bool,
+    /// Transcode files again even if their output exists.
+    pub force: bool,
+    /// Name used to claim files, so that several machines can share a database.
+    pub worker: String,
+    pub paths: OutputPaths,
+    /// What to do when the temp file can't be renamed to the output.
+    pub cross_device: CrossDevice,
     pub progress_hidden: bool,
+    /// How often the progress of each encode is logged.
+    pub progress_log_interval: Duration,
+    /// The encoder given on the command line, which takes precedence over the
+    /// encoder rules.
     pub gpu: Option<GpuMode>,
+    /// The render node of VAAPI encodes, [`DEFAULT_VAAPI_DEVICE`] when not set.
+    pub gpu_device: Option<Utf8PathBuf>,
+    pub encoder_rules: EncoderRules,
+    pub audio: AudioOptions,
+    /// Keep the system awake while a file is being transcoded.
+    pub inhibit_sleep: bool,
+    /// Encode a few seconds of a test source with the settings of the queue
+    /// before starting it, see [`Transcoder::warm_up`].
+    pub preflight_encode: bool,
+    /// Whether the files are a random sample from `--sample-random`, which is
+    /// recorded with the run.
+    pub sample_run: bool,
+    /// How long to wait for the storage of the sources to come back when it
+    /// disappears during the run, see [`storage`].
+    pub storage_patience: Duration,
+    /// How long the encodes of one file may take together before ffmpeg is
+    /// killed and the file fails.
+    pub file_timeout: Option<Duration>,
+    /// Profile and level limits for the encoded video.
+    pub constraints: Constraints,
+    /// The device the outputs have to play on. Files that play on it already
+    /// are skipped, files with only other audio, subtitles or container are
+    /// remuxed.
+    pub device: Option<DeviceProfile>,
+    /// Compare the hash of the copied audio of each output with the source's.
+    pub verify_audio_hash: bool,
     pub parallel: u32,
+    /// The order the files were selected in, `None` when they come from a plan,
+    /// which runs them in its own order.
+    pub order: Option<FileSortOrder>,
+    /// How many NVENC sessions the GPU allows at the same time, when it's limited.
+    pub gpu_sessions: Option<u32>,
+    /// Encode the files that don't get an NVENC session on the CPU, instead of
+    /// waiting for one.
+    pub cpu_fill: bool,
+    /// Files below a directory containing a file of this name are skipped.
+    pub ignore_file: String,
+    /// Upper bound for the predicted memory usage of all parallel encodes, in bytes.
+    pub max_memory: u64,
+    /// Don't start new files when the output filesystem has less free space than this.
+    pub min_free_space: Option<u64>,
+    /// Don't start new files once this many bytes have been saved.
+    pub stop_after_saved: Option<u64>,
+    /// Outputs have to be smaller than the original by this fraction to be kept.
+    pub min_savings: f64,
+    /// Subtract the estimated size of dropped audio from the original's size
+    /// before comparing it with the output.
+    pub video_only_size_check: bool,
+    /// Retry files that didn't save enough at higher CRF values.
+    pub auto_crf: Option<AutoCrf>,
+    /// Encode in segments, so that an interrupted encode continues where it stopped.
+    pub resumable: bool,
+    /// Read sources at most this fast on average, in bytes per second.
+    pub read_rate_limit: Option<u64>,
+    pub io_mode: IoMode,
+    pub energy: EnergyOptions,
+    /// Options recorded by an earlier encode, used for every file instead of
+    /// resolving them from the settings and rules.
+    pub repeat: Option<ResolvedOptions>,
+    /// Options from a plan of `queue --plan`, by the ID of the file they are for.
+    pub planned: HashMap<i64, ResolvedOptions>,
+    /// Outputs encoded from one decode of each file, instead of a single one.
+    pub renditions: Vec<Rendition>,
+    /// Serve the run's status over HTTP.
+    #[cfg(feature = "http")]
+    pub http: Option<HttpOptions>,
+}
+
+impl TranscodeOptions {
+    /// Options for a dry run without any limits, with the defaults of the
+    /// command line.
+    pub fn dry_run() -> Self {
+        TranscodeOptions {
+            cli: TranscodeSettings::default(),
+            config: TranscodeSettings::default(),
+            dry_run: true,
+            replace: false,
+            no_finalize: false,
+            sidecar: false,
+            force: false,
+            worker: "dry-run".into(),
+            paths: OutputPaths::default(),
+            cross_device: CrossDevice::default(),
+            progress_hidden: true,
+            progress_log_interval: progress::DEFAULT_LOG_INTERVAL,
+            gpu: None,
+            gpu_device: None,
+            gpu_sessions: None,
+            cpu_fill: false,
+            ignore_file: crate::collect::DEFAULT_IGNORE_FILE.into(),
+            encoder_rules: EncoderRules::default(),
+            audio: AudioOptions {
+                reencode_above: None,
+                codec: "aac".into(),
+                bitrate: 160_000,
+                drop: false,
+                drop_commentary: false,
+                allowed_codecs: vec![],
+            },
+            inhibit_sleep: false,
+            preflight_encode: true,
+            sample_run: false,
+            storage_patience: storage::DEFAULT_PATIENCE,
+            file_timeout: None,
+            constraints: Constraints::default(),
+            device: None,
+            verify_audio_hash: false,
+            parallel: 1,
+            order: Some(FileSortOrder::BiggestFirst),
+            max_memory: u64::MAX,
+            min_free_space: None,
+            stop_after_saved: None,
+            min_savings: 0.0,
+            video_only_size_check: false,
+            auto_crf: None,
+            resumable: false,
+            read_rate_limit: None,
+            io_mode: IoMode::Direct,
+            energy: EnergyOptions::default(),
+            repeat: None,
+            planned: HashMap::new(),
+            renditions: vec![],
+            #[cfg(feature = "http")]
+            http: None,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn for_tests() -> Self {
+        TranscodeOptions {
+            worker: "test:1".into(),
+            ..TranscodeOptions::dry_run()
+        }
+    }
+}
+
+/// Name of the ffmpeg encoder used for the GPU mode and codec.
+pub fn encoder_name(gpu: Option<&GpuMode>, codec: VideoCodec) -> &'static str {
+    match (gpu, codec) {
+        (Some(GpuMode::Nvidia), VideoCodec::Av1) => "av1_nvenc",
+        (Some(GpuMode::Nvidia), VideoCodec::Hevc) => "hevc_nvenc",
+        (Some(GpuMode::Nvidia), VideoCodec::H264) => "h264_nvenc",
+        (Some(GpuMode::Qsv), VideoCodec::Av1) => "av1_qsv",
+        (Some(GpuMode::Qsv), VideoCodec::Hevc) => "hevc_qsv",
+        (Some(GpuMode::Qsv), VideoCodec::H264) => "h264_qsv",
+        (Some(GpuMode::Vaapi), VideoCodec::Av1) => "av1_vaapi",
+        (Some(GpuMode::Vaapi), VideoCodec::Hevc) => "hevc_vaapi",
+        (Some(GpuMode::Vaapi), VideoCodec::H264) => "h264_vaapi",
+        (Some(GpuMode::Amf), VideoCodec::Av1) => "av1_amf",
+        (Some(GpuMode::Amf), VideoCodec::Hevc) => "hevc_amf",
+        (Some(GpuMode::Amf), VideoCodec::H264) => "h264_amf",
+        (None, VideoCodec::Av1) => "libsvtav1",
+        (None, VideoCodec::Hevc) => "libx265",
+        (None, VideoCodec::H264) => "libx264",
+        // only ever encoded on the CPU
+        (_, VideoCodec::Vp9) => "libvpx-vp9",
+    }
+}
+
+/// The encoder and its native preset for the settings, e.g. `libsvtav1 preset 7
+/// (speed 6)`, since the same speed is a different preset for every encoder.
+fn describe_preset(gpu: Option<&GpuMode>, settings: &EncodeSettings, codec: VideoCodec) -> String {
+    let source = match settings.effort {
+        Some(effort) => format!("--effort {effort}"),
+        None => format!("speed {}", settings.speed),
+    };
+    let option = match (gpu, codec) {
+        (_, VideoCodec::Vp9) => "cpu-used",
+        (Some(GpuMode::Vaapi), _) => "compression_level",
+        (Some(GpuMode::Amf), _) => "quality",
+        _ => "preset",
+    };
+    format!(
+        "{} {option} {} ({source})",
+        encoder_name(gpu, codec),
+        settings.preset(gpu, codec)
+    )
+}
+
+/// Picks the number of files to encode in parallel. An explicit value always wins.
+/// Otherwise CPU encoders get one file at a time since they already use all cores,
+/// while hardware encoders can run a few sessions side by side.
+pub fn resolve_parallel(
+    explicit: Option<u32>,
+    gpu: Option<&GpuMode>,
+    nvenc_session_limit: impl FnOnce() -> Option<u32>,
+) -> u32 {
+    if let Some(parallel) = explicit {
+        return parallel;
+    }
+
+    match gpu {
+        None => 1,
+        Some(GpuMode::Nvidia) => nvenc_session_limit().map_or(3, |limit| limit.min(3)),
+        Some(GpuMode::Qsv | GpuMode::Vaapi | GpuMode::Amf) => 2,
+    }
+}
+
+/// The scheduler for the files of a run. Only biggest-first runs start with a
+/// mix of sizes: the interleaved order starts the longest encodes first on
+/// purpose, and a plan runs in the order it was reviewed in.
+fn run_scheduler<T>(
+    jobs: Vec<(T, u64)>,
+    options: &TranscodeOptions,
+    parallel: u32,
+    size: impl Fn(&T) -> u64,
+) -> Scheduler<T> {
+    let scheduler = Scheduler::new(jobs, options.max_memory);
+    match options.order {
+        Some(FileSortOrder::BiggestFirst) => scheduler.with_mixed_start(parallel as usize, size),
+        _ => scheduler,
+    }
+}
+
+/// Returns 80% of the system's memory, used as the default memory budget.
+pub fn default_memory_budget() -> u64 {
+    let mut system = System::new();
+    system.refresh_memory();
+    system.total_memory() / 10 * 8
+}
+
+/// Rough estimate of the memory an encode needs, in bytes.
+fn estimate_memory(
+    resolution: (u32, u32),
+    gpu: Option<&GpuMode>,
+    settings: &EncodeSettings,
+) -> u64 {
+    const BASE: u64 = 256 * 1024 * 1024;
+    let pixels = resolution.0 as u64 * resolution.1 as u64;
+    match gpu {
+        // Hardware encoders keep most of their state in GPU memory.
+        Some(_) => BASE + pixels * 64,
+        // SVT-AV1 needs about 1 KB per pixel at the default presets, the slower
+        // presets use a longer lookahead and need more.
+        None => {
+            let bytes_per_pixel = match settings.preset(None, VideoCodec::Av1) {
+                Preset::Svt(0..=3) => 1280,
+                Preset::Svt(4..=8) => 1024,
+                _ => 768,
+            };
+            let frames = match settings.low_memory {
+                Some(_) => {
+                    (pixels * bytes_per_pixel) as f64 * low_memory::memory_factor(resolution)
+                }
+                None => (pixels * bytes_per_pixel) as f64,
+            };
+            BASE + frames as u64
+        }
+    }
+}
+
+/// Whether the process was killed by SIGKILL, which is what the OOM killer sends.
+#[cfg(unix)]
+fn was_killed(status: &ExitStatus) -> bool {
+    use std::os::unix::process::ExitStatusExt;
+
+    status.signal() == Some(9)
+}
+
+#[cfg(not(unix))]
+fn was_killed(_status: &ExitStatus) -> bool {
+    false
+}
+
+/// Kills ffmpeg when it's still running at the file's deadline, see
+/// `--file-timeout`. A hung ffmpeg writes no progress, so this can't wait for
+/// the next progress line.
+struct Watchdog {
+    stop: mpsc::Sender<()>,
+    thread: thread::JoinHandle<bool>,
+}
+
+impl Watchdog {
+    fn start(process: Arc<Mutex<Child>>, deadline: Instant) -> Self {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = thread::spawn(move || {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match stopped.recv_timeout(timeout) {
+                Err(RecvTimeoutError::Timeout) => {
+                    let _ = process.lock().unwrap().kill();
+                    true
+                }
+                _ => false,
+            }
+        });
+        Self { stop, thread }
+    }
+
+    /// Stops watching and returns whether ffmpeg was killed.
+    fn finish(self) -> bool {
+        drop(self.stop);
+        self.thread.join().unwrap_or(false)
+    }
 }
 
 fn trim_path(path: &Utf8Path) -> String {
@@ -54,6 +444,459 @@ fn trim_path(path: &Utf8Path) -> String {
     }
 }
 
+/// Fails an output that doesn't play on the device, e.g. because the encoder
+/// ignored the level.
+fn check_device(device: &DeviceProfile, output: &Utf8Path) -> Result<()> {
+    let violations = device.violations(&ffprobe(output)?);
+    if !violations.is_empty() {
+        bail!(
+            "the output doesn't play on {}, {}",
+            device.name,
+            device::describe(&violations)
+        );
+    }
+    Ok(())
+}
+
+/// Shifts the timestamps of sources that don't start at zero, see
+/// [`FfProbe::start_offset`](crate::ffprobe::FfProbe::start_offset). ffmpeg
+/// already subtracts the input's start time from all streams, subtitles
+/// included, so `-copyts` isn't needed to keep them in sync.
+fn timestamp_args(start_offset: Option<f64>) -> Vec<String> {
+    match start_offset {
+        Some(_) => vec!["-avoid_negative_ts".into(), "make_zero".into()],
+        None => vec![],
+    }
+}
+
+/// Keeps the container metadata of the source on the output. The title is also
+/// set explicitly, because muxers store it under different keys and not every
+/// one of them picks it up from the copied tags. A `reproducible` output only
+/// gets the title: the other tags can hold the time of the encode, and the
+/// bitexact flags keep the muxers and encoders from writing their versions or
+/// random IDs.
+fn metadata_args(title: Option<&str>, reproducible: bool) -> Vec<String> {
+    let mut args: Vec<String> = if reproducible {
+        [
+            "-map_metadata",
+            "-1",
+            "-metadata:s",
+            "creation_time=",
+            "-fflags",
+            "+bitexact",
+            "-flags",
+            "+bitexact",
+        ]
+        .map(String::from)
+        .into()
+    } else {
+        vec!["-map_metadata".to_string(), "0".into()]
+    };
+    if let Some(title) = title {
+        args.extend(["-metadata".into(), format!("title={title}")]);
+    }
+    args
+}
+
+/// Why the output doesn't have the title of the source, `None` when it kept it.
+fn lost_title(source: Option<&str>, output: &FfProbe) -> Option<String> {
+    let source = source?;
+    match output.title() {
+        Some(title) if title == source => None,
+        Some(title) => Some(format!("its title is \"{title}\" instead of \"{source}\"")),
+        None => Some(format!("it lost the title \"{source}\"")),
+    }
+}
+
+fn is_matroska(path: &Utf8Path) -> bool {
+    path.extension().is_some_and(|extension| {
+        extension.eq_ignore_ascii_case("mkv") || extension.eq_ignore_ascii_case("webm")
+    })
+}
+
+/// How far a chapter of the output may be from where it is in the source, the
+/// muxer moves them to the nearest keyframe.
+const CHAPTER_TOLERANCE: f64 = 0.5;
+
+/// Why the chapters of the output don't match those of the source, `None` when
+/// they do. ffmpeg subtracts the start time of a source from its chapters like
+/// from its streams, so the source's are compared shifted by `start_offset`.
+fn lost_chapters(
+    source: &[Chapter],
+    start_offset: Option<f64>,
+    output: &FfProbe,
+) -> Option<String> {
+    if source.is_empty() {
+        return None;
+    }
+    if source.len() != output.chapters.len() {
+        return Some(format!(
+            "it has {} chapters instead of {}",
+            output.chapters.len(),
+            source.len()
+        ));
+    }
+    let shift = start_offset.unwrap_or_default();
+    let moved = |source: Option<f64>, output: Option<f64>| match (source, output) {
+        (Some(source), Some(output)) => (source - shift - output).abs() > CHAPTER_TOLERANCE,
+        _ => false,
+    };
+    source
+        .iter()
+        .zip(&output.chapters)
+        .enumerate()
+        .find(|(_, (source, output))| {
+            moved(source.start_time, output.start_time) || moved(source.end_time, output.end_time)
+        })
+        .map(|(index, (source, output))| {
+            format!(
+                "its chapter {} is at {output} instead of {source}",
+                index + 1
+            )
+        })
+}
+
+/// The ffmpeg arguments for the input and the encoders, without the output.
+fn encoder_args(
+    input: &Utf8Path,
+    gpu: Option<&GpuMode>,
+    settings: &EncodeSettings,
+    constraints: &Constraints,
+    audio_args: &[String],
+    start_offset: Option<f64>,
+) -> Vec<String> {
+    let codec = constraints.codec();
+    let encoder = encoder_name(gpu, codec);
+    let crf = settings.crf.to_string();
+    let qp = hardware_qp(codec, settings.crf).to_string();
+    let preset = settings.preset(gpu, codec).to_string();
+    let mut args: Vec<String> = match gpu {
+        Some(GpuMode::Nvidia) => vec![
+            "-y",
+            "-i",
+            input.as_str(),
+            "-c:v",
+            encoder,
+            "-preset",
+            &preset,
+            "-tune",
+            "hq",
+            "-cq",
+            &crf,
+            "-rc-lookahead",
+            "24",
+            "-b_adapt",
+            "1",
+            "-temporal-aq",
+            "1",
+            "-spatial-aq",
+            "1",
+        ],
+        Some(GpuMode::Qsv) => vec![
+            "-hwaccel",
+            "qsv",
+            "-y",
+            "-i",
+            input.as_str(),
+            "-c:v",
+            encoder,
+            "-preset",
+            &preset,
+            // the H.264 and HEVC encoders only take the quality as ICQ
+            if codec == VideoCodec::Av1 {
+                "-crf"
+            } else {
+                "-global_quality"
+            },
+            &crf,
+        ],
+        // frames stay on the GPU from decoding to encoding
+        Some(GpuMode::Vaapi) => vec![
+            "-hwaccel",
+            "vaapi",
+            "-hwaccel_device",
+            DEFAULT_VAAPI_DEVICE,
+            "-hwaccel_output_format",
+            "vaapi",
+            "-y",
+            "-i",
+            input.as_str(),
+            "-c:v",
+            encoder,
+            "-compression_level",
+            &preset,
+            "-rc_mode",
+            "CQP",
+            "-qp",
+            &qp,
+        ],
+        Some(GpuMode::Amf) => vec![
+            "-y",
+            "-i",
+            input.as_str(),
+            "-c:v",
+            encoder,
+            "-quality",
+            &preset,
+            "-rc",
+            "cqp",
+            "-qp_i",
+            &qp,
+            "-qp_p",
+            &qp,
+        ],
+        // constant quality needs the bitrate limit off
+        None if codec == VideoCodec::Vp9 => vec![
+            "-y",
+            "-i",
+            input.as_str(),
+            "-c:v",
+            encoder,
+            "-cpu-used",
+            &preset,
+            "-crf",
+            &crf,
+            "-b:v",
+            "0",
+            "-row-mt",
+            "1",
+        ],
+        None => vec![
+            "-y",
+            "-i",
+            input.as_str(),
+            "-c:v",
+            encoder,
+            "-preset",
+            &preset,
+            "-crf",
+            &crf,
+        ],
+    }
+    .into_iter()
+    .map(String::from)
+    .collect();
+
+    if gpu == Some(&GpuMode::Vaapi) {
+        // sources the GPU can't decode arrive as frames in memory and are
+        // uploaded, in the bit depth that is encoded
+        let format = if settings.ten_bit { "p010" } else { "nv12" };
+        args.extend(["-vf".into(), format!("format={format}|vaapi,hwupload")]);
+    } else if settings.ten_bit {
+        let pix_fmt = match gpu {
+            Some(_) => "p010le",
+            None => "yuv420p10le",
+        };
+        args.extend(["-pix_fmt".into(), pix_fmt.into()]);
+    } else if codec != VideoCodec::Av1 {
+        // devices that play H.264 or HEVC often only play 8 bits of them
+        let pix_fmt = match gpu {
+            Some(GpuMode::Qsv | GpuMode::Amf) => "nv12",
+            _ => "yuv420p",
+        };
+        args.extend(["-pix_fmt".into(), pix_fmt.into()]);
+    }
+    let (constraint_args, mut svt_params) = constraints.encoder_args(gpu, settings.ten_bit);
+    args.extend(constraint_args);
+    if let Some(film_grain) = settings.film_grain {
+        match (gpu, codec) {
+            (None, VideoCodec::Av1) => svt_params.push(format!("film-grain={film_grain}")),
+            _ => warn!("film grain synthesis is only supported by libsvtav1, ignoring it"),
+        }
+    }
+    if let Some(low_memory) = &settings.low_memory {
+        match (gpu, codec) {
+            (None, VideoCodec::Av1) => {
+                svt_params.extend(low_memory.svt_params(settings.reproducible))
+            }
+            _ => warn!("--low-memory only tunes libsvtav1, ignoring it"),
+        }
+    }
+    if settings.reproducible {
+        match (gpu, codec) {
+            (None, VideoCodec::Av1) => svt_params.push(format!("lp={REPRODUCIBLE_THREADS}")),
+            (None, VideoCodec::Hevc) => {
+                let threads = format!("pools={REPRODUCIBLE_THREADS}:frame-threads=1");
+                match args.iter().position(|arg| arg == "-x265-params") {
+                    Some(index) => args[index + 1] = format!("{}:{threads}", args[index + 1]),
+                    None => args.extend(["-x265-params".into(), threads]),
+                }
+            }
+            (None, VideoCodec::H264 | VideoCodec::Vp9) => {
+                args.extend(["-threads".into(), REPRODUCIBLE_THREADS.to_string()])
+            }
+            // refused by the preflight checks
+            (Some(_), _) => {}
+        }
+    }
+    if !svt_params.is_empty() {
+        args.extend(["-svtav1-params".into(), svt_params.join(":")]);
+    }
+    if let Some(max_fps) = settings.max_fps {
+        args.extend(["-fpsmax".into(), max_fps.to_string()]);
+    }
+
+    args.extend(audio_args.iter().cloned());
+    args.extend(timestamp_args(start_offset));
+    args
+}
+
+/// The QP of the VAAPI and AMF encoders for a CRF. AV1's quantizer index goes
+/// up to 255, four times the CRF scale, the others take the CRF as it is.
+fn hardware_qp(codec: VideoCodec, crf: u8) -> u32 {
+    match codec {
+        VideoCodec::Av1 => (u32::from(crf) * 4).min(255),
+        _ => u32::from(crf),
+    }
+}
+
+/// The arguments with the render node of `--gpu-device` in place of the
+/// default one. Only VAAPI encodes name a device.
+fn gpu_device_args(args: &[String], device: Option<&Utf8Path>) -> Vec<String> {
+    let mut args = args.to_vec();
+    if let Some(device) = device
+        && let Some(index) = args.iter().position(|arg| arg == "-hwaccel_device")
+    {
+        args[index + 1] = device.to_string();
+    }
+    args
+}
+
+/// The ffmpeg arguments that copy the video of a file that only needs other
+/// audio, subtitles or container to play on a device.
+fn remux_args(
+    input: &Utf8Path,
+    output: &Utf8Path,
+    audio_args: &[String],
+    start_offset: Option<f64>,
+) -> Vec<String> {
+    let mut args: Vec<String> = ["-y", "-i", input.as_str(), "-c:v", "copy"]
+        .map(String::from)
+        .into();
+    args.extend(audio_args.iter().cloned());
+    args.extend(timestamp_args(start_offset));
+    args.extend(["-progress", "-", "-nostats", output.as_str()].map(String::from));
+    args
+}
+
+/// The arguments with `-readrate` before the input, which makes ffmpeg read it
+/// at most that many times faster than real time.
+fn paced_args(args: &[String], readrate: Option<f64>) -> Vec<String> {
+    let mut args = args.to_vec();
+    if let Some(readrate) = readrate
+        && let Some(input) = args.iter().position(|arg| arg == "-i")
+    {
+        args.splice(
+            input..input,
+            ["-readrate".to_string(), format!("{readrate:.3}")],
+        );
+    }
+    args
+}
+
+fn ffmpeg_args(
+    input: &Utf8Path,
+    output: &Utf8Path,
+    gpu: Option<&GpuMode>,
+    settings: &EncodeSettings,
+    constraints: &Constraints,
+    audio_args: &[String],
+    start_offset: Option<f64>,
+) -> Vec<String> {
+    let mut args = encoder_args(input, gpu, settings, constraints, audio_args, start_offset);
+    args.extend(
+        ["-progress", "-", "-nostats", output.as_str()]
+            .into_iter()
+            .map(String::from),
+    );
+    args
+}
+
+/// The arguments of one ffmpeg run that encodes all renditions of a file, see
+/// [`renditions::multi_output_args`].
+fn rendition_args(
+    input: &Utf8Path,
+    legs: &[Leg],
+    audio_args: &[String],
+    file: &VideoFile,
+) -> Vec<String> {
+    let encodes: Vec<_> = legs
+        .iter()
+        .map(|leg| {
+            let mut args = encoder_args(
+                input,
+                None,
+                &leg.settings,
+                &leg.constraints,
+                audio_args,
+                file.start_offset,
+            );
+            args.extend(leg.rendition.scale_args(file.resolution.1));
+            (args, leg.tmp.as_path())
+        })
+        .collect();
+    renditions::multi_output_args(&encodes)
+}
+
+/// Arguments that encode the generated [`WARMUP_SOURCE`] with the encoder
+/// settings of a file and throw the result away. Audio and timestamp arguments
+/// depend on the input, so they are left out.
+fn warmup_args(
+    gpu: Option<&GpuMode>,
+    settings: &EncodeSettings,
+    constraints: &Constraints,
+) -> Vec<String> {
+    let mut args = encoder_args(
+        Utf8Path::new(WARMUP_SOURCE),
+        gpu,
+        settings,
+        constraints,
+        &[],
+        None,
+    );
+    let input = args
+        .iter()
+        .position(|arg| arg == "-i")
+        .expect("the encoder arguments contain an input");
+    args.splice(input..input, ["-f".to_string(), "lavfi".into()]);
+    // only ffmpeg's error ends up on stderr
+    args.splice(
+        0..0,
+        ["-hide_banner".to_string(), "-v".into(), "error".into()],
+    );
+    args.extend(["-an", "-f", "null", "-"].map(String::from));
+    args
+}
+
+/// Runs the test encode with the real ffmpeg.
+fn warmup_encode(args: &[String]) -> Result<()> {
+    let output = binaries::command(Binary::Ffmpeg)
+        .args(args)
+        .output()
+        .map_err(|e| binaries::spawn_error(Binary::Ffmpeg, e))?;
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(commandline_error("ffmpeg", output))
+    }
+}
+
+/// Arguments that write the encode to stdout. ffmpeg's stats stay on stderr.
+fn stream_args(
+    input: &Utf8Path,
+    gpu: Option<&GpuMode>,
+    settings: &EncodeSettings,
+    constraints: &Constraints,
+    audio_args: &[String],
+    start_offset: Option<f64>,
+    format: StreamFormat,
+) -> Vec<String> {
+    let mut args = encoder_args(input, gpu, settings, constraints, audio_args, start_offset);
+    args.extend(format.args().iter().map(|arg| arg.to_string()));
+    args.push("pipe:1".into());
+    args
+}
+
 fn ffmpeg_progress_bar(file: &VideoFile, hidden: bool) -> ProgressBar {
     if hidden {
         ProgressBar::hidden()
@@ -90,11 +933,85 @@ fn ffmpeg_progress_bar(file: &VideoFile, hidden: bool) -> ProgressBar {
     }
 }
 
+/// The ffmpeg run of the renditions of a file and what their outputs are
+/// checked against.
+struct RenditionEncode<'a> {
+    args: &'a [String],
+    legs: &'a [Leg<'a>],
+    compared_size: u64,
+    audio_decisions: &'a [(AudioTrack, AudioDecision)],
+}
+
+/// The progress bar of a file that is being encoded, the total bar and what the
+/// file added to it.
+struct FileBars<'a> {
+    file: &'a ProgressBar,
+    total: &'a ProgressBar,
+    progress: FileProgress,
+    /// When ffmpeg is killed if the file's encodes still aren't done, from
+    /// `--file-timeout`.
+    deadline: Option<Instant>,
+}
+
+impl FileBars<'_> {
+    /// Starts the file's bar over for another attempt, taking what the earlier
+    /// attempts added back from the total.
+    fn restart(&mut self, path: &Utf8Path, unplanned: bool) {
+        let credited = self.progress.restart(unplanned);
+        self.total.dec(credited.position + credited.overflow);
+        self.total.dec_length(credited.overflow);
+        self.file.reset();
+        if let Some(attempt) = self.progress.attempt_label() {
+            self.file.set_message(format!(
+                "Transcoding file '{}' ({attempt})",
+                trim_path(path)
+            ));
+        }
+    }
+}
+
+/// What happened to a file that came up in a run.
+#[derive(Debug, Clone, PartialEq)]
+enum Outcome {
+    /// Encoded, saving this many bytes.
+    Transcoded(u64),
+    Skipped(SkipReason),
+    /// Only checked in a dry run.
+    Checked,
+}
+
+/// How a single file is encoded.
+struct FileSettings {
+    settings: EncodeSettings,
+    gpu: Option<GpuMode>,
+    directory_override: Option<DirectoryOverride>,
+    /// Name of the encoder rule that matched the file.
+    rule: Option<String>,
+}
+
 pub struct Transcoder {
     options: TranscodeOptions,
     files: Vec<VideoFile>,
     progress: MultiProgress,
     database: Database,
+    overrides: DirectoryOverrides,
+    status: RunStatus,
+    /// What a dry run found for each file and the encoder rule it used, by id.
+    verdicts: Mutex<HashMap<i64, (Verdict, Option<String>)>>,
+    /// Files that were skipped when they came up.
+    skipped: Mutex<Vec<SkippedFile>>,
+    /// Set once no more files are started, so running files don't start retries.
+    stopping: AtomicBool,
+    /// Tracks the energy per file when a source for it is available.
+    energy: Option<EnergyTracker>,
+    /// Reads the CPU time and memory of the running encoders.
+    probe: Arc<dyn ProcessProbe>,
+    /// The resource usage of every encode of the run.
+    usages: Mutex<Vec<ResourceUsage>>,
+    /// Writes how files ended, and keeps it in memory once the database failed.
+    results: ResultBuffer,
+    /// Runs the test encode before the queue starts.
+    warmup: fn(&[String]) -> Result<()>,
 }
 
 impl Transcoder {
@@ -104,12 +1021,153 @@ impl Transcoder {
         if options.progress_hidden {
             progress.set_draw_target(ProgressDrawTarget::hidden());
         }
+        let energy = if options.dry_run {
+            None
+        } else {
+            energy::meter(&options.energy).map(EnergyTracker::new)
+        };
         Self {
+            energy,
+            results: ResultBuffer::new(Box::new(database.clone())),
             database,
             options,
+            status: RunStatus::new(files.len()),
             files,
             progress,
+            overrides: DirectoryOverrides::default(),
+            verdicts: Mutex::default(),
+            skipped: Mutex::default(),
+            stopping: AtomicBool::new(false),
+            probe: resources::probe(),
+            usages: Mutex::default(),
+            warmup: warmup_encode,
+        }
+    }
+
+    /// Resolves the encoder and settings for a file. The command line comes
+    /// first, then the directory override, then the first encoder rule that
+    /// matches the file, then the config file. Repeated options are used as
+    /// they were recorded, as are the options of a plan.
+    fn settings_for(&self, file: &VideoFile) -> Result<FileSettings> {
+        if let Some(recorded) = self.recorded(file) {
+            return Ok(FileSettings {
+                settings: recorded.settings.clone(),
+                gpu: recorded.gpu(),
+                directory_override: None,
+                rule: recorded.encoder_rule.clone(),
+            });
+        }
+        let directory_override = self.overrides.for_file(&file.path)?;
+        let rule = self.options.encoder_rules.first_match(file);
+        let config = match &rule {
+            Some(matched) => matched.rule.transcode.or(&self.options.config),
+            None => self.options.config.clone(),
+        };
+        let mut settings = config::merge(
+            &self.options.cli,
+            directory_override.as_ref().map(|o| &o.settings),
+            &config,
+            self.options.constraints.codec(),
+        );
+        if let Some(device) = &self.options.device
+            && !device.plays_ten_bit()
+        {
+            settings.ten_bit = false;
+        }
+        if settings.low_memory.is_some() {
+            settings.low_memory = Some(low_memory::for_resolution(file.resolution));
+        }
+        let gpu = self.options.gpu.clone().or_else(|| {
+            rule.as_ref()
+                .and_then(|matched| matched.rule.encoder)
+                .and_then(|encoder| encoder.gpu())
+        });
+        Ok(FileSettings {
+            settings,
+            gpu,
+            directory_override,
+            rule: rule.map(|matched| matched.name),
+        })
+    }
+
+    /// The options recorded by an earlier encode or planned for the file.
+    fn recorded(&self, file: &VideoFile) -> Option<&ResolvedOptions> {
+        self.options
+            .repeat
+            .as_ref()
+            .or_else(|| self.options.planned.get(&file.id))
+    }
+
+    /// The options a file would be encoded with, as `queue --plan` records them.
+    pub fn resolved_options(&self, file: &VideoFile) -> Result<ResolvedOptions> {
+        let FileSettings {
+            settings,
+            gpu,
+            directory_override,
+            rule,
+        } = self.settings_for(file)?;
+        let mut resolved = ResolvedOptions::new(
+            gpu.as_ref(),
+            &settings,
+            &self.options.constraints,
+            &self.options.audio,
+            &self.audio_decisions(file),
+        );
+        resolved.directory_override = match self.recorded(file) {
+            Some(recorded) => recorded.directory_override.clone(),
+            None => directory_override.map(|o| o.path),
+        };
+        resolved.encoder_rule = rule;
+        Ok(resolved)
+    }
+
+    fn audio_decisions(&self, file: &VideoFile) -> Vec<(AudioTrack, AudioDecision)> {
+        match self.recorded(file) {
+            Some(recorded) => recorded.audio_decisions(),
+            None => audio::decide_all(&file.audio_tracks, &self.options.audio),
+        }
+    }
+
+    /// Encodes a single file to stdout with the settings it would get in a run,
+    /// e.g. to preview them with `mpv -`. Nothing is written to the database.
+    pub fn stream(&self, file: &VideoFile, format: StreamFormat) -> Result<()> {
+        let FileSettings {
+            settings,
+            gpu,
+            directory_override,
+            rule,
+        } = self.settings_for(file)?;
+        if let Some(directory_override) = &directory_override {
+            info!("Using settings from {}", directory_override.path);
         }
+        if let Some(rule) = &rule {
+            info!("Using encoder rule {rule}");
+        }
+        let audio_args = audio::audio_args(&self.audio_decisions(file), &self.options.audio);
+        let args = gpu_device_args(
+            &stream_args(
+                &file.path,
+                gpu.as_ref(),
+                &settings,
+                &self.options.constraints,
+                &audio_args,
+                file.start_offset,
+                format,
+            ),
+            self.options.gpu_device.as_deref(),
+        );
+        info!("Streaming {} with ffmpeg {}", file.path, args.join(" "));
+        let status = binaries::command(Binary::Ffmpeg)
+            .args(&args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit())
+            .status()
+            .map_err(|e| binaries::spawn_error(Binary::Ffmpeg, e))?;
+        if !status.success() {
+            bail!("ffmpeg failed to stream {}: {status}", file.path);
+        }
+        Ok(())
     }
 
     #[allow(unused)]
@@ -139,234 +1197,2895 @@ impl Transcoder {
         Ok(())
     }
 
-    fn transcode_file(&self, file: &VideoFile, total_progress: &ProgressBar) -> Result<()> {
-        let progress = self
-            .progress
-            .add(ffmpeg_progress_bar(file, self.options.progress_hidden));
-        let stem = file.path.file_stem().expect("file must have a name");
-        let out_file = file.path.with_file_name(format!("{stem}_av1.mp4"));
-        if out_file.is_file() {
-            info!("File {} already exists, skipping", out_file.as_str());
-            return Ok(());
-        }
-        let tmp_file = file.path.with_file_name(format!("{stem}_tmp.mp4"));
-        let effort = match self.options.gpu {
-            Some(GpuMode::Nvidia) => format!("p{}", self.options.effort),
-            Some(GpuMode::Qsv) | None => self.options.effort.to_string(),
-        };
-        let crf = self.options.crf.to_string();
-        let args = match self.options.gpu {
-            Some(GpuMode::Nvidia) => {
-                vec![
-                    "-y",
-                    "-i",
-                    file.path.as_str(),
-                    "-c:v",
-                    "av1_nvenc",
-                    "-preset",
-                    "p7",
-                    "-tune",
-                    "hq",
-                    "-cq",
-                    &crf,
-                    "-rc-lookahead",
-                    "24",
-                    "-b_adapt",
-                    "1",
-                    "-temporal-aq",
-                    "1",
-                    "-spatial-aq",
-                    "1",
-                    "-c:a",
-                    "copy",
-                    "-progress",
-                    "-",
-                    "-nostats",
-                    tmp_file.as_str(),
-                ]
-            }
-            Some(GpuMode::Qsv) => {
-                vec![
-                    "-hwaccel",
-                    "qsv",
-                    "-y",
-                    "-i",
-                    file.path.as_str(),
-                    "-c:v",
-                    "av1_qsv",
-                    "-preset",
-                    &effort,
-                    "-crf",
-                    &crf,
-                    "-c:a",
-                    "copy",
-                    "-progress",
-                    "-",
-                    "-nostats",
-                    tmp_file.as_str(),
-                ]
-            }
-            None => {
-                vec![
-                    "-y",
-                    "-i",
-                    file.path.as_str(),
-                    "-c:v",
-                    "libsvtav1",
-                    "-preset",
-                    &effort,
-                    "-crf",
-                    &crf,
-                    "-c:a",
-                    "copy",
-                    "-progress",
-                    "-",
-                    "-nostats",
-                    tmp_file.as_str(),
-                ]
+    /// Makes sure the output and temp directories can be written before starting and
+    /// records which source directories are read-only, checking each of them once.
+    fn resolve_output_paths(&self) -> Result<OutputPaths> {
+        let mut output_paths = self.options.paths.clone();
+        for directory in [&output_paths.output_dir, &output_paths.tmp_dir]
+            .into_iter()
+            .flatten()
+        {
+            if !paths::is_writable(directory) {
+                bail!("{directory} is not writable");
             }
-        };
-        if self.options.dry_run {
-            let args: Vec<_> = args
-                .iter()
-                .map(|s| {
-                    if s.contains(' ') {
-                        format!("\"{}\"", s)
-                    } else {
-                        s.to_string()
-                    }
-                })
-                .collect();
-            let args = args.join(" ");
-
-            info!(
-                "Would transcode file '{}' with size {}",
-                file.path.file_name().expect("file must have a name"),
-                file.file_size.human_count_bytes()
+        }
+        if output_paths.writes_to_source_dir(self.options.replace) {
+            output_paths.unwritable = paths::unwritable_directories(
+                self.files.iter().map(|f| f.path.as_path()),
+                paths::is_writable,
             );
-            info!("Command to run: ffmpeg {}", args);
-            progress.tick();
-            progress.finish_and_clear();
-            total_progress.inc((file.duration * 1000.0) as u64);
-            return Ok(());
         }
+        Ok(output_paths)
+    }
 
-        let mut process = Command::new("ffmpeg")
-            .args(args)
-            .stderr(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()?;
+    /// What the run did, including the files that were skipped when they were
+    /// selected.
+    pub fn summary(&self, selection_skipped: &[SkippedFile]) -> RunSummary {
+        let snapshot = self.status.snapshot();
+        let mut skipped = selection_skipped.to_vec();
+        skipped.extend(self.skipped.lock().unwrap().iter().cloned());
+        RunSummary::new(snapshot.encoded_files, snapshot.failed_files, skipped)
+    }
 
-        let stdout = process.stdout.take().unwrap();
-        let reader = BufReader::new(stdout);
+    /// The wall time and energy of the files, if there was a source for the energy.
+    pub fn energy_report(&self) -> Option<EnergyReport> {
+        self.energy.as_ref()?.report()
+    }
 
-        let file_name = trim_path(&file.path);
-        info!("Transcoding file {}", file_name);
+    /// The resource usage of the encodes of the run, if any could be collected.
+    pub fn usage_summary(&self, wall_time: Duration) -> Option<UsageSummary> {
+        UsageSummary::new(&self.usages.lock().unwrap(), wall_time)
+    }
 
-        progress.tick();
-        let mut last_postion = 0;
-        for line in reader.lines() {
-            let line = line?;
-            debug!("{}", line);
-            if let Some(captures) = OUT_TIME_REGEX.captures(&line) {
-                let duration: u64 = captures.get(1).unwrap().as_str().parse::<u64>()?;
-                let duration = Duration::from_micros(duration);
-                let millis = duration.as_millis() as u64;
-                info!(
-                    "{}: {} / {}",
-                    file_name,
-                    millis,
-                    (file.duration * 1000.0) as u64
-                );
-                let delta = millis - last_postion;
-                progress.inc(delta);
-                total_progress.inc(delta);
-                last_postion = millis;
-            }
+    /// The dry run verdicts and encoder rules in the order of the files.
+    pub fn verdicts(&self) -> Vec<(Utf8PathBuf, Verdict, Option<String>)> {
+        let mut verdicts = self.verdicts.lock().unwrap();
+        self.files
+            .iter()
+            .filter_map(|file| {
+                verdicts
+                    .remove(&file.id)
+                    .map(|(verdict, rule)| (file.path.clone(), verdict, rule))
+            })
+            .collect()
+    }
+
+    /// Probes the encoded file and warns about what [`Transcoder::check_probe`]
+    /// finds.
+    fn check_output(&self, file: &VideoFile, output: &Utf8Path) {
+        match ffprobe(output) {
+            Ok(info) => self.check_probe(file, output, &info, &self.options.constraints),
+            Err(e) => warn!("Could not check the encoded file {output}: {e:?}"),
         }
-        progress.finish_and_clear();
+    }
 
-        let output = process.wait_with_output()?;
-        if output.status.success() {
-            let new_file_size = fs::metadata(&tmp_file)?.len();
-            info!(
-                "Transcoded file {} to size {} from {}",
-                file_name,
-                new_file_size.human_count_bytes(),
-                file.file_size.human_count_bytes()
-            );
+    /// Warns about a profile and level that don't match the constraints, a title
+    /// that got lost, a start time that wasn't shifted to zero and Matroska
+    /// chapters that got lost or moved.
+    fn check_probe(
+        &self,
+        file: &VideoFile,
+        output: &Utf8Path,
+        info: &FfProbe,
+        constraints: &Constraints,
+    ) {
+        for violation in constraints.violations(info) {
+            warn!("{output} may not play on the target device: {violation}");
+        }
+        if let Some(lost) = lost_title(file.title.as_deref(), info) {
+            warn!("{output}: {lost}");
+        }
+        if file.start_offset.is_some()
+            && let Some(start) = info.start_offset()
+        {
+            warn!("{output} starts at {start}s instead of 0, players may show an A/V offset");
+        }
+        if is_matroska(output)
+            && let Some(lost) = lost_chapters(&file.chapters, file.start_offset, info)
+        {
+            warn!("{output}: {lost}");
+        }
+    }
 
-            if new_file_size >= file.file_size {
+    /// Records how a file ended. After a failed write, no more files are started
+    /// and the results are kept until the run is over. Failures are recorded with
+    /// what changed in the source since it was scanned.
+    fn record(&self, mut result: FileResult) {
+        if result.status == TranscodeStatus::Error && !self.options.dry_run {
+            result.source_changes = self.source_changes(result.id);
+            if !result.source_changes.is_empty() {
                 warn!(
-                    "Transcoded file {} is larger than original, skipping",
-                    file_name
+                    "{}: {}",
+                    result.path,
+                    source_changes::describe(&result.source_changes)
                 );
-                fs::remove_file(tmp_file)?;
-                return Ok(());
             }
+        }
+        if let Err(e) = self.results.record(result) {
+            self.database_failed(&e);
+        }
+    }
 
-            if self.options.replace {
-                fs::remove_file(&file.path)?;
-                fs::rename(tmp_file, &file.path)?;
-            } else {
-                fs::rename(tmp_file, out_file)?;
+    fn source_changes(&self, id: i64) -> Vec<SourceChange> {
+        match self.database.get(id) {
+            Ok(Some(file)) => source_changes::check(&file, |path| {
+                ffprobe::ffprobe_with_timeout(path, source_changes::PROBE_TIMEOUT)
+            }),
+            Ok(None) => vec![],
+            Err(e) => {
+                warn!("Could not check whether the source of file {id} changed: {e:?}");
+                vec![]
             }
+        }
+    }
 
-            self.database
-                .set_file_status(file.rowid, TranscodeStatus::Success, None)?;
-            Ok(())
-        } else {
-            let error = commandline_error("ffmpeg", output);
-            self.database.set_file_status(
-                file.rowid,
-                TranscodeStatus::Error,
-                Some(error.to_string()),
-            )?;
+    /// Writes the report of a file's output. The output is kept when that fails.
+    fn write_sidecar(&self, id: i64, source_hash: Option<String>) {
+        let written = self
+            .database
+            .get(id)
+            .and_then(|file| file.ok_or_else(|| eyre!("file {id} is not in the database")))
+            .and_then(|file| sidecar::write(&self.database, &file, source_hash));
+        if let Err(e) = written {
+            warn!("Could not write the sidecar of file {id}: {e:?}");
+        }
+    }
 
-            Err(error)
+    /// Passes on a failed write of information about an encode that doesn't
+    /// decide how the file ended, so the encode isn't lost.
+    fn bookkeeping(&self, write: Result<()>) {
+        if let Err(e) = write {
+            self.database_failed(&e);
         }
     }
 
-    pub fn transcode_all(&self) -> Result<()> {
-        let pool = ThreadPoolBuilder::new()
-            .num_threads(self.options.parallel as usize)
-            .build()?;
-        let term = Term::stderr();
-        if !self.options.progress_hidden {
-            term.clear_screen()?;
-            term.hide_cursor()?;
+    /// Stops the run when a write to the database failed. Running encodes finish,
+    /// their results are kept in memory.
+    fn database_failed(&self, error: &color_eyre::Report) {
+        if self.results.fail() {
+            error!("Could not write to the database, not starting any more files: {error:?}");
+            self.stopping.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Writes the results that were kept in memory after a failed write to the
+    /// database, or dumps them next to it when it doesn't recover.
+    fn flush_results(&self) -> Result<()> {
+        let database = self
+            .database
+            .path()
+            .unwrap_or(Utf8Path::new("transcoder.db"));
+        match self
+            .results
+            .flush(&results::dump_path(database, Timestamp::now()))?
+        {
+            Flushed::Nothing => {}
+            Flushed::Written(count) => {
+                info!("The database recovered, wrote the results of {count} files")
+            }
+            Flushed::Dumped { count, path } => bail!(
+                "the database didn't recover, the results of {count} files are in {path}. \
+                 Merge them with `transcoder import --results-only {path}` once it can be written again."
+            ),
+        }
+        if self.results.failed() {
+            warn!("The run was stopped after a failed write to the database");
+        }
+        Ok(())
+    }
+
+    /// Compares the copied audio of the output with the source and records the
+    /// result. Differences are logged as warnings, errors of the check don't fail
+    /// the file.
+    fn verify_audio_hash(
+        &self,
+        file: &VideoFile,
+        audio_decisions: &[(AudioTrack, AudioDecision)],
+        output: &Utf8Path,
+    ) -> Option<AudioHash> {
+        let check = audio_hash::check(
+            audio_decisions,
+            &self.options.audio,
+            &file.path,
+            output,
+            audio_hash::md5,
+        );
+        match &check {
+            Ok(AudioCheck::Match) => info!("{}: the audio matches the source", file.path),
+            Ok(AudioCheck::Mismatch { source, output }) => warn!(
+                "{}: the audio of the output differs from the source (MD5 {output} instead of {source})",
+                file.path
+            ),
+            Ok(AudioCheck::Skipped(reason)) => {
+                debug!("{}: not comparing the audio, {reason}", file.path)
+            }
+            Err(e) => warn!("Could not compare the audio of {}: {e:?}", file.path),
         }
+        check.ok().and_then(|check| check.result())
+    }
 
-        pool.install(|| {
-            let len = self.files.len();
-            info!("transcoding {len} files");
+    /// Records how long an encode of a file took and the resources it used.
+    fn record_encode(
+        &self,
+        file: &VideoFile,
+        encode_time: Duration,
+        usage: Option<&ResourceUsage>,
+    ) {
+        self.bookkeeping(
+            self.database
+                .set_encode_time(file.id, encode_time.as_secs_f64()),
+        );
+        if let Some(usage) = usage {
+            info!(
+                "{}: {} of CPU time ({:.0}% CPU), peak memory {}",
+                trim_path(&file.path),
+                usage.cpu_time.human_duration(),
+                usage.cpu_percent(),
+                usage.peak_rss.human_count_bytes()
+            );
+            self.bookkeeping(self.database.set_resource_usage(file.id, usage));
+            self.usages.lock().unwrap().push(*usage);
+        }
+    }
 
-            let total_duration = self
-                .files
+    /// Transcodes one file. `on_cpu` moves a file that would be encoded with
+    /// NVENC to the CPU, when all sessions are taken.
+    fn transcode_file(
+        &self,
+        file: &VideoFile,
+        output_paths: &OutputPaths,
+        total_progress: &ProgressBar,
+        on_cpu: bool,
+    ) -> Result<Outcome> {
+        let progress = self
+            .progress
+            .add(ffmpeg_progress_bar(file, self.options.progress_hidden));
+        let tmp_file = output_paths.tmp(&file.path, file.id);
+        let FileSettings {
+            mut settings,
+            mut gpu,
+            directory_override,
+            rule,
+        } = self.settings_for(file)?;
+        if on_cpu && gpu.is_some() {
+            info!(
+                "{}: all NVENC sessions are in use, encoding on the CPU",
+                file.path
+            );
+            gpu = None;
+        }
+        if !self.options.renditions.is_empty() && gpu.is_some() {
+            info!("{}: renditions are encoded on the CPU", file.path);
+            gpu = None;
+        }
+        if self.options.constraints.codec() == VideoCodec::Vp9 && gpu.is_some() {
+            info!("{}: VP9 is encoded on the CPU", file.path);
+            gpu = None;
+        }
+        match &rule {
+            Some(rule) => info!(
+                "{}: using encoder rule {rule} with {}",
+                file.path,
+                encoder_name(gpu.as_ref(), self.options.constraints.codec())
+            ),
+            None => debug!("{}: no encoder rule matches", file.path),
+        }
+        settings.crf = autocrf::starting_crf(
+            settings.crf,
+            file.bits_per_pixel(),
+            self.options.auto_crf.as_ref(),
+        );
+        let audio_decisions = self.audio_decisions(file);
+        let mut audio_args = audio::audio_args(&audio_decisions, &self.options.audio);
+        let mut remux = false;
+        if let Some(device) = &self.options.device {
+            let probe = self.probe_info(file)?;
+            match device.conformance(&probe) {
+                Conformance::Conforms => {
+                    progress.finish_and_clear();
+                    info!(
+                        "Skipping {}: it plays on {} already",
+                        file.path, device.name
+                    );
+                    return Ok(Outcome::Skipped(SkipReason::Conforms));
+                }
+                Conformance::Remux(violations) => {
+                    info!(
+                        "{}: remuxing for {}, {}",
+                        file.path,
+                        device.name,
+                        device::describe(&violations)
+                    );
+                    remux = true;
+                }
+                Conformance::Encode(violations) => debug!(
+                    "{}: encoding for {}, {}",
+                    file.path,
+                    device.name,
+                    device::describe(&violations)
+                ),
+            }
+            audio_args = device.stream_args(&probe, &audio_args);
+        }
+        audio_args.extend(metadata_args(file.title.as_deref(), settings.reproducible));
+        let compared_size = if self.options.video_only_size_check {
+            file.file_size
+                .saturating_sub(audio::dropped_bytes(&audio_decisions, file.duration))
+        } else {
+            file.file_size
+        };
+        let input = match self.options.io_mode {
+            IoMode::Direct => file.path.clone(),
+            IoMode::Staged => output_paths
+                .staged(&file.path, file.id)
+                .ok_or_else(|| eyre!("--io-mode staged needs a --tmp-dir"))?,
+        };
+        let legs: Vec<_> = self
+            .options
+            .renditions
+            .iter()
+            .map(|rendition| {
+                let settings = rendition.settings(&settings);
+                let fields = OutputFields {
+                    crf: Some(settings.crf),
+                    height: file.resolution.1,
+                    codec: None,
+                };
+                Leg {
+                    rendition,
+                    constraints: rendition.constraints(&self.options.constraints),
+                    tmp: output_paths.rendition_tmp(&file.path, file.id, &rendition.name),
+                    output: rendition.paths(output_paths).output(&file.path, fields),
+                    settings,
+                }
+            })
+            .collect();
+        let mut args = if remux {
+            remux_args(&input, &tmp_file, &audio_args, file.start_offset)
+        } else if !legs.is_empty() {
+            rendition_args(&input, &legs, &audio_args, file)
+        } else {
+            ffmpeg_args(
+                &input,
+                &tmp_file,
+                gpu.as_ref(),
+                &settings,
+                &self.options.constraints,
+                &audio_args,
+                file.start_offset,
+            )
+        };
+        let findings =
+            preflight::preflight(file, output_paths, &self.options, &settings, gpu.as_ref());
+        let verdict = Verdict::from_findings(&findings);
+        if self.options.dry_run {
+            info!("{}: {verdict}", file.path);
+            self.verdicts
+                .lock()
+                .unwrap()
+                .insert(file.id, (verdict.clone(), rule.clone()));
+        }
+        match verdict {
+            Verdict::Fail(error) => {
+                progress.finish_and_clear();
+                if !self.options.dry_run {
+                    self.record(FileResult::failed(file.id, &file.path, error.clone()));
+                }
+                return Err(eyre!(error));
+            }
+            Verdict::Skip(reason) => {
+                progress.finish_and_clear();
+                info!("Skipping {}: {reason}", file.path);
+                let reason = findings
+                    .iter()
+                    .find_map(Finding::skip_reason)
+                    .expect("a finding skipped the file");
+                if reason == SkipReason::IgnoreFile && !self.options.dry_run {
+                    self.bookkeeping(
+                        self.database
+                            .set_skipped(file.id, ScanSkipReason::IgnoreFile),
+                    );
+                }
+                return Ok(Outcome::Skipped(reason));
+            }
+            Verdict::Transcode(warnings) => {
+                if !self.options.dry_run {
+                    for warning in warnings {
+                        warn!("{}: {warning}", file.path);
+                    }
+                }
+            }
+        }
+        if self.options.dry_run {
+            let args: Vec<_> = self
+                .command_args(file, &args)
                 .iter()
-                .map(|f| Duration::from_secs_f64(f.duration).as_millis() as u64)
-                .sum();
+                .map(|s| {
+                    if s.contains(' ') {
+                        format!("\"{}\"", s)
+                    } else {
+                        s.to_string()
+                    }
+                })
+                .collect();
+            let args = args.join(" ");
+
+            info!(
+                "Would transcode file '{}' with size {}",
+                file.path.file_name().expect("file must have a name"),
+                file.file_size.human_count_bytes()
+            );
+            match &directory_override {
+                Some(directory_override) => {
+                    info!("Using settings from {}", directory_override.path)
+                }
+                None => info!("No directory override applies"),
+            }
+            if !remux && legs.is_empty() {
+                info!(
+                    "Encoding with {}",
+                    describe_preset(gpu.as_ref(), &settings, self.options.constraints.codec())
+                );
+            }
+            for leg in &legs {
+                info!(
+                    "Rendition {}: encoding with {} to {}",
+                    leg.rendition.name,
+                    describe_preset(None, &leg.settings, leg.constraints.codec()),
+                    leg.output
+                );
+            }
+            for (track, decision) in &audio_decisions {
+                info!("{track}: {decision}");
+            }
+            if compared_size != file.file_size {
+                info!(
+                    "Comparing the output with {} without the dropped audio",
+                    compared_size.human_count_bytes()
+                );
+            }
+            if self.options.io_mode == IoMode::Staged {
+                info!("Copying the source to {input} first");
+            }
+            info!("Command to run: ffmpeg {}", args);
+            progress.tick();
+            progress.finish_and_clear();
+            total_progress.inc((file.duration * 1000.0) as u64);
+            return Ok(Outcome::Checked);
+        }
 
-            let total_progress = self.progress.add(if self.options.progress_hidden {
-                ProgressBar::hidden()
+        self.database.set_encoder_rule(file.id, rule.as_deref())?;
+        let mut resolved = ResolvedOptions::new(
+            gpu.as_ref(),
+            &settings,
+            &self.options.constraints,
+            &self.options.audio,
+            &audio_decisions,
+        );
+        resolved.directory_override = match self.recorded(file) {
+            Some(recorded) => recorded.directory_override.clone(),
+            None => directory_override.map(|o| o.path),
+        };
+        resolved.encoder_rule = rule;
+        let file_name = trim_path(&file.path);
+        let staged = match self.options.io_mode {
+            IoMode::Direct => None,
+            IoMode::Staged => Some(self.stage(file, &input)?),
+        };
+        let mut history = vec![];
+        let mut resumable: Option<ResumableEncode>;
+        let mut bars = FileBars {
+            file: &progress,
+            total: total_progress,
+            progress: FileProgress::new((file.duration * 1000.0) as u64).with_attempts(
+                autocrf::max_attempts(settings.crf, self.options.auto_crf.as_ref()),
+            ),
+            deadline: self
+                .options
+                .file_timeout
+                .map(|timeout| Instant::now() + timeout),
+        };
+        if !legs.is_empty() {
+            resolved.ffmpeg_args = settings.reproducible.then(|| args.clone());
+            self.database
+                .set_encode_options(file.id, &resolved)
+                .inspect_err(|e| self.database_failed(e))?;
+            let encode = RenditionEncode {
+                args: &args,
+                legs: &legs,
+                compared_size,
+                audio_decisions: &audio_decisions,
+            };
+            return self.encode_renditions(file, encode, &mut bars);
+        }
+        loop {
+            resolved.settings = settings.clone();
+            resolved.ffmpeg_args = settings.reproducible.then(|| args.clone());
+            self.database
+                .set_encode_options(file.id, &resolved)
+                .inspect_err(|e| self.database_failed(e))?;
+            // a remux is over too quickly to be worth resuming, and segments
+            // aren't the same bytes as one encode
+            resumable = if remux || settings.reproducible {
+                None
             } else {
-                ProgressBar::new(total_duration).with_style(
-                    ProgressStyle::default_bar()
-                        .template("Total progress: {wide_bar:.cyan/blue} {eta}")
-                        .expect("bad progressbar template"),
-                )
+                self.resumable_encode(file, output_paths, &args, &tmp_file)
+            };
+            let encoded = match &resumable {
+                Some(resumable) => {
+                    self.encode_resumable(file, resumable, &args, &tmp_file, &mut bars)
+                }
+                None => self.encode(file, &args, 0.0, &mut bars),
+            };
+            let (encode_time, usage) = match encoded {
+                Err(e) if gpu.is_some() && e.is::<SessionLimitReached>() => {
+                    warn!("{}: {e}, encoding on the CPU instead", file.path);
+                    let _ = fs::remove_file(&tmp_file);
+                    if let Some(resumable) = &resumable {
+                        resumable.discard()?;
+                    }
+                    gpu = None;
+                    resolved.encoder = Encoder::Cpu;
+                    bars.restart(&file.path, true);
+                    args = ffmpeg_args(
+                        &input,
+                        &tmp_file,
+                        None,
+                        &settings,
+                        &self.options.constraints,
+                        &audio_args,
+                        file.start_offset,
+                    );
+                    continue;
+                }
+                encoded => encoded.inspect_err(|_| progress.finish_and_clear())?,
+            };
+            let new_file_size = fs::metadata(&tmp_file)?.len();
+            if remux {
+                info!(
+                    "Remuxed file {} to size {} from {} in {}",
+                    file_name,
+                    new_file_size.human_count_bytes(),
+                    file.file_size.human_count_bytes(),
+                    encode_time.human_duration()
+                );
+            } else {
+                info!(
+                    "Transcoded file {} at CRF {} to size {} from {} in {}",
+                    file_name,
+                    settings.crf,
+                    new_file_size.human_count_bytes(),
+                    file.file_size.human_count_bytes(),
+                    encode_time.human_duration()
+                );
+            }
+            self.record_encode(file, encode_time, usage.as_ref());
+            // ffmpeg reads the whole source in a direct encode
+            let read_rate = match &staged {
+                Some(staged) => Some(staged.stats.rate()),
+                None => (!encode_time.is_zero())
+                    .then(|| file.file_size as f64 / encode_time.as_secs_f64()),
+            };
+            if let Some(read_rate) = read_rate {
+                info!(
+                    "{}: read the source at {} ({})",
+                    file_name,
+                    io_limit::format_rate(read_rate),
+                    self.options.io_mode
+                );
+                self.bookkeeping(self.database.set_read_rate(
+                    file.id,
+                    self.options.io_mode,
+                    read_rate,
+                ));
+            }
+            history.push(Attempt {
+                crf: settings.crf,
+                output_size: new_file_size,
             });
-            total_progress.tick();
+            // a remux is kept whatever its size, it's needed to play the file
+            if remux {
+                break;
+            }
+            self.bookkeeping(self.database.insert_crf_attempt(
+                file.id,
+                settings.crf,
+                new_file_size,
+            ));
 
-            self.files.par_iter().enumerate().for_each(|(_, file)| {
-                match self.transcode_file(file, &total_progress) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        warn!("Could not transcode file {}: {:?}", file.path, e);
+            // another attempt would only be killed at the deadline
+            let timed_out = bars
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline);
+            let decision = autocrf::decide(
+                &history,
+                compared_size,
+                self.options.min_savings,
+                self.options.auto_crf.as_ref(),
+                self.stopping.load(Ordering::SeqCst) || timed_out,
+            );
+            match decision {
+                Decision::Keep => break,
+                Decision::Retry { crf } => {
+                    info!(
+                        "{} saved too little at CRF {}, trying again at CRF {crf}",
+                        file_name, settings.crf
+                    );
+                    fs::remove_file(&tmp_file)?;
+                    if let Some(resumable) = &resumable {
+                        resumable.discard()?;
+                    }
+                    settings.crf = crf;
+                    bars.restart(&file.path, false);
+                    args = ffmpeg_args(
+                        &input,
+                        &tmp_file,
+                        gpu.as_ref(),
+                        &settings,
+                        &self.options.constraints,
+                        &audio_args,
+                        file.start_offset,
+                    );
+                }
+                Decision::GiveUp => {
+                    progress.finish_and_clear();
+                    warn!(
+                        "Transcoded file {} did not save enough compared to the original, skipping",
+                        file_name
+                    );
+                    fs::remove_file(tmp_file)?;
+                    if let Some(resumable) = &resumable {
+                        resumable.discard()?;
                     }
+                    return Ok(Outcome::Skipped(SkipReason::TooLittleSavings));
+                }
+            }
+        }
+        progress.finish_and_clear();
+        drop(staged);
+        let new_file_size = history.last().expect("kept an attempt").output_size;
+
+        if !self.options.constraints.is_empty()
+            || file.start_offset.is_some()
+            || (!file.chapters.is_empty() && is_matroska(&tmp_file))
+        {
+            self.check_output(file, &tmp_file);
+        }
+        if let Some(device) = &self.options.device
+            && let Err(error) = check_device(device, &tmp_file)
+        {
+            let _ = fs::remove_file(&tmp_file);
+            self.record(FileResult::failed(file.id, &file.path, error.to_string()));
+            return Err(error);
+        }
+        // before moving the output, which may replace the source
+        if self.options.verify_audio_hash
+            && let Some(result) = self.verify_audio_hash(file, &audio_decisions, &tmp_file)
+        {
+            self.bookkeeping(self.database.set_audio_hash(file.id, result));
+        }
+
+        // named after the CRF that was kept
+        let fields = OutputFields {
+            crf: Some(settings.crf),
+            height: file.resolution.1,
+            codec: remux.then(|| VideoCodec::from_name(&file.codec)).flatten(),
+        };
+        // the output may replace the source
+        let source_hash = if self.options.sidecar {
+            sidecar::source_hash(&file.path)
+                .inspect_err(|e| warn!("Could not hash {}: {e}", file.path))
+                .ok()
+        } else {
+            None
+        };
+        let out_file = output_paths.output(&file.path, fields);
+        let replacement = paths::replacement(&file.path, &tmp_file);
+        let output_path = if self.options.replace {
+            &replacement
+        } else {
+            let full_name = output_paths.full_output_name(&file.path, fields);
+            if out_file.file_name() != Some(full_name.as_str()) {
+                warn!(
+                    "{}: {full_name} is too long for the filesystem, the output is {out_file}",
+                    file_name
+                );
+            }
+            &out_file
+        };
+        if let Err(error) = paths::move_file(&tmp_file, output_path) {
+            let _ = fs::remove_file(&tmp_file);
+            let error = eyre!("could not move the output to {output_path}: {error}");
+            self.record(FileResult::failed(file.id, &file.path, error.to_string()));
+            return Err(error);
+        }
+        // a WebM output replaces the source under a name of its own
+        if self.options.replace
+            && *output_path != file.path
+            && let Err(e) = fs::remove_file(&file.path)
+        {
+            warn!(
+                "{}: could not remove the source replaced by {output_path}: {e}",
+                file_name
+            );
+        }
+
+        if let Some(resumable) = &resumable {
+            resumable.discard()?;
+        }
+        if self.options.no_finalize {
+            info!(
+                "{}: the output {output_path} waits for `finalize`",
+                file_name
+            );
+            self.record(FileResult::encoded(file.id, &file.path, output_path));
+        } else {
+            self.record(FileResult::transcoded(file.id, &file.path, output_path));
+        }
+        if self.options.sidecar {
+            self.write_sidecar(file.id, source_hash);
+        }
+        Ok(Outcome::Transcoded(
+            file.file_size.saturating_sub(new_file_size),
+        ))
+    }
+
+    /// Encodes all renditions of a file in one ffmpeg run and checks each output
+    /// on its own. An output that doesn't save enough is discarded, one that
+    /// isn't what its profile asks for fails the file. The first output that is
+    /// kept counts as the file's output.
+    fn encode_renditions(
+        &self,
+        file: &VideoFile,
+        encode: RenditionEncode,
+        bars: &mut FileBars,
+    ) -> Result<Outcome> {
+        let file_name = trim_path(&file.path);
+        let remove_all = || {
+            for leg in encode.legs {
+                let _ = fs::remove_file(&leg.tmp);
+            }
+        };
+        let encoded = self.encode(file, encode.args, 0.0, bars);
+        bars.file.finish_and_clear();
+        let (encode_time, usage) = encoded.inspect_err(|_| remove_all())?;
+        info!(
+            "Transcoded file {} to {} renditions in {}",
+            file_name,
+            encode.legs.len(),
+            encode_time.human_duration()
+        );
+        self.record_encode(file, encode_time, usage.as_ref());
+
+        let mut kept = vec![];
+        let mut audio_hash = None;
+        for leg in encode.legs {
+            let name = &leg.rendition.name;
+            let size = fs::metadata(&leg.tmp)?.len();
+            info!(
+                "{}: rendition {name} at CRF {} has size {} from {}",
+                file_name,
+                leg.settings.crf,
+                size.human_count_bytes(),
+                file.file_size.human_count_bytes()
+            );
+            if !autocrf::saves_enough(encode.compared_size, size, self.options.min_savings) {
+                warn!(
+                    "{}: rendition {name} did not save enough compared to the original, discarding it",
+                    file_name
+                );
+                let _ = fs::remove_file(&leg.tmp);
+                continue;
+            }
+            let violations = match ffprobe(&leg.tmp) {
+                Ok(info) => {
+                    self.check_probe(file, &leg.tmp, &info, &leg.constraints);
+                    leg.rendition.violations(&info, file.resolution.1)
                 }
+                Err(e) => vec![format!("it could not be probed: {e}")],
+            };
+            if !violations.is_empty() {
+                remove_all();
+                let error = eyre!(
+                    "rendition {name} failed its checks, {}",
+                    violations.join(", ")
+                );
+                self.record(FileResult::failed(file.id, &file.path, error.to_string()));
+                return Err(error);
+            }
+            // a mismatch of any output is what gets recorded
+            if self.options.verify_audio_hash
+                && audio_hash != Some(AudioHash::Mismatch)
+                && let Some(result) = self.verify_audio_hash(file, encode.audio_decisions, &leg.tmp)
+            {
+                audio_hash = Some(result);
+            }
+            kept.push((leg, size));
+        }
+        let Some(&(_, primary_size)) = kept.first() else {
+            return Ok(Outcome::Skipped(SkipReason::TooLittleSavings));
+        };
+        if let Some(result) = audio_hash {
+            self.bookkeeping(self.database.set_audio_hash(file.id, result));
+        }
+        let source_hash = if self.options.sidecar {
+            sidecar::source_hash(&file.path)
+                .inspect_err(|e| warn!("Could not hash {}: {e}", file.path))
+                .ok()
+        } else {
+            None
+        };
+
+        let mut outputs = vec![];
+        for (leg, size) in &kept {
+            if let Err(error) = paths::move_file(&leg.tmp, &leg.output) {
+                remove_all();
+                let error = eyre!(
+                    "could not move the output of rendition {} to {}: {error}",
+                    leg.rendition.name,
+                    leg.output
+                );
+                self.record(FileResult::failed(file.id, &file.path, error.to_string()));
+                return Err(error);
+            }
+            outputs.push(RenditionOutput {
+                name: leg.rendition.name.clone(),
+                output_path: leg.output.clone(),
+                output_size: *size,
             });
+        }
+        self.record(FileResult {
+            renditions: outputs,
+            ..FileResult::transcoded(file.id, &file.path, &kept[0].0.output)
+        });
+        if self.options.sidecar {
+            self.write_sidecar(file.id, source_hash);
+        }
+        Ok(Outcome::Transcoded(
+            file.file_size.saturating_sub(primary_size),
+        ))
+    }
+
+    /// The probe of a file stored in the database, or a new one if it's missing.
+    fn probe_info(&self, file: &VideoFile) -> Result<FfProbe> {
+        match self.database.get(file.id)?.and_then(|file| file.ffprobe()) {
+            Some(info) => Ok(info),
+            None => ffprobe(&file.path),
+        }
+    }
+
+    /// The segments and state of a `--resumable` encode with these arguments.
+    fn resumable_encode(
+        &self,
+        file: &VideoFile,
+        output_paths: &OutputPaths,
+        args: &[String],
+        tmp_file: &Utf8Path,
+    ) -> Option<ResumableEncode> {
+        self.options.resumable.then(|| {
+            ResumableEncode::new(
+                output_paths.resume_dir(&file.path, file.id),
+                file.file_size,
+                file.duration,
+                resume::fingerprint(args, &file.path, tmp_file),
+            )
+        })
+    }
+
+    /// Encodes the rest of a file in segments and joins them into `tmp_file`,
+    /// returning the time the encode took in this run and the resources of all
+    /// segments. Segments that don't join up are discarded and the file is
+    /// marked as failed.
+    fn encode_resumable(
+        &self,
+        file: &VideoFile,
+        resumable: &ResumableEncode,
+        args: &[String],
+        tmp_file: &Utf8Path,
+        bars: &mut FileBars,
+    ) -> Result<(Duration, Option<ResourceUsage>)> {
+        let mut encode_time = Duration::ZERO;
+        let mut usage: Option<ResourceUsage> = None;
+        resumable.encode(args, |args, offset| {
+            let segment;
+            (encode_time, segment) = self.encode(file, args, offset, bars)?;
+            if let Some(segment) = segment {
+                usage.get_or_insert_default().add(&segment);
+            }
+            Ok(())
+        })?;
+        let joined = resumable.finish(tmp_file, resume::concat, |output| {
+            Ok(ffprobe(output)?.duration())
         });
+        if let Err(error) = joined {
+            resumable.discard()?;
+            self.record(FileResult::failed(file.id, &file.path, error.to_string()));
+            return Err(error);
+        }
+        Ok((encode_time, usage))
+    }
+
+    /// Runs one encode of a file with the output in `args`, starting `offset`
+    /// seconds into it, and returns the time it took and the resources ffmpeg
+    /// used, where they can be collected. A failed encode marks the file as failed.
+    fn encode(
+        &self,
+        file: &VideoFile,
+        args: &[String],
+        offset: f64,
+        bars: &mut FileBars,
+    ) -> Result<(Duration, Option<ResourceUsage>)> {
+        let mut command = binaries::command(Binary::Ffmpeg);
+        command.args(self.command_args(file, args));
+        self.run_encoder(file, command, offset, bars)
+    }
+
+    /// The arguments ffmpeg is run with for a file: paced by the read rate
+    /// limit, on the render node of `--gpu-device`.
+    fn command_args(&self, file: &VideoFile, args: &[String]) -> Vec<String> {
+        gpu_device_args(
+            &paced_args(args, self.readrate(file)),
+            self.options.gpu_device.as_deref(),
+        )
+    }
+
+    /// The `-readrate` that keeps a direct encode of the file under the
+    /// `--read-rate-limit`.
+    fn readrate(&self, file: &VideoFile) -> Option<f64> {
+        match (self.options.io_mode, self.options.read_rate_limit) {
+            (IoMode::Direct, Some(limit)) => {
+                io_limit::readrate(limit, file.file_size, file.duration)
+            }
+            _ => None,
+        }
+    }
+
+    /// Copies the source to the temp directory for `--io-mode staged`. A failed
+    /// copy marks the file as failed.
+    fn stage(&self, file: &VideoFile, staged: &Utf8Path) -> Result<StagedCopy> {
+        info!(
+            "{}: copying the source to {staged}{}",
+            trim_path(&file.path),
+            self.options
+                .read_rate_limit
+                .map(|limit| format!(" at {}", io_limit::format_rate(limit as f64)))
+                .unwrap_or_default()
+        );
+        io_limit::stage(&file.path, staged, self.options.read_rate_limit).map_err(|e| {
+            let error = eyre!("could not copy the source to {staged}: {e}");
+            self.record(FileResult::failed(file.id, &file.path, error.to_string()));
+            error
+        })
+    }
+
+    /// Runs the encoder and follows its progress on stdout, see [`Transcoder::encode`].
+    fn run_encoder(
+        &self,
+        file: &VideoFile,
+        mut command: Command,
+        offset: f64,
+        bars: &mut FileBars,
+    ) -> Result<(Duration, Option<ResourceUsage>)> {
+        let mut process = command
+            .stderr(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| binaries::spawn_error(Binary::Ffmpeg, e))?;
+        let monitor = Monitor::start(self.probe.clone(), process.id(), resources::SAMPLE_INTERVAL);
+
+        let file_name = trim_path(&file.path);
+        // read while the progress is, otherwise ffmpeg blocks once it wrote a
+        // pipe buffer full of warnings and the encode never ends
+        let stderr = stderr::Drain::start(
+            process.stderr.take().unwrap(),
+            file_name.clone(),
+            stderr::TAIL_BYTES,
+        );
+        let stdout = process.stdout.take().unwrap();
+        let reader = BufReader::new(stdout);
+        let process = Arc::new(Mutex::new(process));
+        let watchdog = bars
+            .deadline
+            .map(|deadline| Watchdog::start(process.clone(), deadline));
+
+        info!("Transcoding file {}", file_name);
+
+        bars.file.tick();
+        let offset = (offset * 1000.0) as u64;
+        if offset > 0 {
+            let advance = bars.progress.update(offset);
+            bars.file.inc(advance.position);
+            bars.total.inc(advance.position);
+        }
+        let mut last_heartbeat = Instant::now();
+        let mut clock = EncodeClock::start();
+        let mut progress_log =
+            Throttle::starting_at(self.options.progress_log_interval, Instant::now());
+        for line in reader.lines() {
+            let line = line?;
+            trace!("{}", line);
+            if let Some(suspended) = clock.tick() {
+                info!(
+                    "The system was suspended for {} while transcoding {}",
+                    suspended.human_duration(),
+                    file_name
+                );
+            }
+            if let Some(captures) = OUT_TIME_REGEX.captures(&line) {
+                let duration: u64 = captures.get(1).unwrap().as_str().parse::<u64>()?;
+                let duration = Duration::from_micros(duration);
+                let millis = offset + duration.as_millis() as u64;
+                trace!(
+                    "{}: {} / {}",
+                    file_name,
+                    millis,
+                    (file.duration * 1000.0) as u64
+                );
+                if progress_log.ready(Instant::now()) {
+                    info!(
+                        "{}: {}",
+                        file_name,
+                        progress::describe(
+                            Duration::from_millis(millis),
+                            Duration::from_secs_f64(file.duration.max(0.0)),
+                            Duration::from_millis(millis - offset),
+                            clock.active(),
+                        )
+                    );
+                }
+                let advance = bars.progress.update(millis);
+                bars.file.inc(advance.position);
+                bars.total.inc(advance.position);
+                if advance.overflow > 0 {
+                    if bars.progress.overflow() == advance.overflow {
+                        info!(
+                            "{} runs longer than its metadata says, correcting the total progress",
+                            file.path
+                        );
+                    }
+                    bars.total.inc_length(advance.overflow);
+                    bars.total.inc(advance.overflow);
+                }
+                self.status.update_file(&file.path, millis);
+                if last_heartbeat.elapsed() >= CLAIM_HEARTBEAT {
+                    if let Err(e) = self.database.touch_claim(file.id, &self.options.worker) {
+                        warn!("Could not refresh the claim on {}: {:?}", file.path, e);
+                    }
+                    last_heartbeat = Instant::now();
+                }
+            }
+        }
+
+        // ffmpeg closed its output, the CPU time can be read until it's waited for
+        let timed_out = watchdog.is_some_and(Watchdog::finish);
+        let usage = monitor.finish();
+        let stderr = stderr.finish()?;
+        if stderr.dropped() > 0 {
+            debug!(
+                "{file_name}: only the last {} of ffmpeg's stderr are kept",
+                (stderr::TAIL_BYTES as u64).human_count_bytes()
+            );
+        }
+        let output = Output {
+            status: process.lock().unwrap().wait()?,
+            stdout: vec![],
+            stderr: stderr.into_bytes(),
+        };
+        if timed_out {
+            let error = eyre!(
+                "gave up on {} after --file-timeout {}, ffmpeg was killed",
+                file.path,
+                self.options
+                    .file_timeout
+                    .unwrap_or_default()
+                    .human_duration()
+            );
+            self.record(FileResult::failed(file.id, &file.path, error.to_string()));
+            Err(error)
+        } else if output.status.success() {
+            Ok((clock.active(), usage))
+        } else if capabilities::is_session_limit_error(&String::from_utf8_lossy(&output.stderr)) {
+            // not a failure of the file, it's encoded on the CPU instead
+            Err(SessionLimitReached.into())
+        } else {
+            let error = if was_killed(&output.status) {
+                eyre!(
+                    "ffmpeg was killed while transcoding {}, most likely by the out-of-memory killer. \
+                     Try a lower --parallel or --max-memory value.",
+                    file.path
+                )
+            } else {
+                commandline_error("ffmpeg", output)
+            };
+            self.record(FileResult::failed(file.id, &file.path, error.to_string()));
+
+            Err(error)
+        }
+    }
+
+    /// Stops starting files while the storage of the sources is gone and waits
+    /// for it to come back. When it doesn't, the run stops with the remaining
+    /// files still pending.
+    fn wait_for_storage(
+        &self,
+        scheduler: &Scheduler<&VideoFile>,
+        directory: &Utf8Path,
+        failed: usize,
+    ) {
+        scheduler.set_paused(true);
+        self.status.set_paused(true);
+        let patience = self.options.storage_patience;
+        warn!(
+            "{failed} files in a row failed because {directory} can't be read, the storage \
+             seems to be unavailable. Not starting any files until it's back, waiting up to {}. \
+             The failed files are queued again.",
+            patience.human_duration()
+        );
+        match storage::wait_until_readable(directory, patience, storage::is_readable, thread::sleep)
+        {
+            Recovery::Returned(waited) => {
+                info!(
+                    "{directory} is back after {}, resuming the queue",
+                    waited.human_duration()
+                );
+                scheduler.set_paused(false);
+                self.status.set_paused(false);
+            }
+            Recovery::GaveUp => {
+                let dropped = scheduler.stop();
+                self.stopping.store(true, Ordering::SeqCst);
+                scheduler.set_paused(false);
+                error!(
+                    "{directory} is still unavailable after {}, stopping the run. \
+                     The {dropped} files that weren't started stay pending.",
+                    patience.human_duration()
+                );
+            }
+        }
+    }
+
+    /// Encodes a few seconds of a generated test source with the settings of
+    /// the queue, once for every distinct set of encoder arguments, so that
+    /// settings ffmpeg rejects stop the run before any file is touched.
+    fn warm_up(&self) -> Result<()> {
+        let mut checked = HashSet::new();
+        for file in &self.files {
+            let FileSettings { settings, gpu, .. } = self.settings_for(file)?;
+            let args = gpu_device_args(
+                &warmup_args(gpu.as_ref(), &settings, &self.options.constraints),
+                self.options.gpu_device.as_deref(),
+            );
+            if !checked.insert(args.clone()) {
+                continue;
+            }
+            info!(
+                "test encode with {} for {}",
+                encoder_name(gpu.as_ref(), self.options.constraints.codec()),
+                file.path
+            );
+            debug!("test encode arguments: {args:?}");
+            (self.warmup)(&args).wrap_err_with(|| {
+                format!(
+                    "ffmpeg rejected the settings for {} in a test encode, no files were \
+                     transcoded. Skip the test encode with --no-preflight-encode",
+                    file.path
+                )
+            })?;
+        }
+        Ok(())
+    }
+
+    pub fn transcode_all(&self) -> Result<()> {
+        if self.files.is_empty() {
+            info!("nothing to transcode");
+            return Ok(());
+        }
+        let output_paths = self.resolve_output_paths()?;
+        if self.options.repeat.is_none() && self.options.planned.is_empty() {
+            let settings = config::merge(
+                &self.options.cli,
+                None,
+                &self.options.config,
+                self.options.constraints.codec(),
+            );
+            info!(
+                "encoding with {}, unless encoder rules or directory overrides change it",
+                describe_preset(
+                    self.options.gpu.as_ref(),
+                    &settings,
+                    self.options.constraints.codec()
+                )
+            );
+        }
+        if self.options.preflight_encode && !self.options.dry_run {
+            self.warm_up()?;
+        }
+        let files: Vec<_> = self.files.iter().collect();
+        let run_id = if self.options.dry_run {
+            None
+        } else {
+            let ffmpeg = version::ffmpeg_version()?;
+            info!("using {ffmpeg}");
+            Some(self.database.insert_run(&ffmpeg, self.options.sample_run)?)
+        };
+        let mut jobs = vec![];
+        let mut nvenc_files = HashSet::new();
+        for &file in &files {
+            let file_settings = self.settings_for(file)?;
+            let mut memory = estimate_memory(
+                file.resolution,
+                file_settings.gpu.as_ref(),
+                &file_settings.settings,
+            );
+            if file_settings.gpu == Some(GpuMode::Nvidia) {
+                nvenc_files.insert(file.id);
+                if self.options.cpu_fill {
+                    // may end up on the CPU
+                    memory = memory.max(estimate_memory(
+                        file.resolution,
+                        None,
+                        &file_settings.settings,
+                    ));
+                }
+            }
+            debug!(
+                "{}: expected memory usage {}",
+                file.path,
+                memory.human_count_bytes()
+            );
+            jobs.push((file, memory));
+        }
+        info!(
+            "memory budget for parallel encodes: {}",
+            self.options.max_memory.human_count_bytes()
+        );
+        // no more workers than files
+        let parallel = self.options.parallel.min(files.len() as u32).max(1);
+        let mut scheduler = run_scheduler(jobs, &self.options, parallel, |file| file.file_size);
+        if let Some(limit) = self.options.gpu_sessions
+            && limit < parallel
+            && !nvenc_files.is_empty()
+        {
+            if self.options.cpu_fill {
+                info!(
+                    "the GPU allows {limit} NVENC sessions, the other files are encoded on the CPU"
+                );
+            } else {
+                warn!(
+                    "the GPU allows only {limit} NVENC sessions, at most {limit} files are encoded at once on it"
+                );
+            }
+            scheduler = scheduler.with_gpu_sessions(
+                GpuSessions {
+                    limit: limit as usize,
+                    cpu_fill: self.options.cpu_fill,
+                },
+                |file: &&VideoFile| nvenc_files.contains(&file.id),
+            );
+        }
+
+        let term = Term::stderr();
+        if !self.options.progress_hidden {
+            term.clear_screen()?;
+            term.hide_cursor()?;
+        }
+
+        let len = files.len();
+        info!("transcoding {len} files");
+        self.progress
+            .println(format!("Transcoding {len} files, {parallel} in parallel"))?;
+
+        let total_duration = files
+            .iter()
+            .map(|f| Duration::from_secs_f64(f.duration).as_millis() as u64)
+            .sum();
+
+        let total_progress = self.progress.add(if self.options.progress_hidden {
+            ProgressBar::hidden()
+        } else {
+            ProgressBar::new(total_duration).with_style(
+                ProgressStyle::default_bar()
+                    .template("Total progress: {wide_bar:.cyan/blue} {eta}")
+                    .expect("bad progressbar template"),
+            )
+        });
+        total_progress.tick();
+
+        let total_saved = AtomicU64::new(0);
+        let outage = Mutex::new(Outage::new(storage::FAILURES_BEFORE_PAUSE));
+        thread::scope(|scope| -> Result<()> {
+            #[cfg(feature = "http")]
+            let server = match &self.options.http {
+                Some(http) => Some(StatusServer::start(
+                    scope,
+                    http,
+                    &self.status,
+                    || crate::status::QueueSnapshot::collect(&self.database),
+                    |paused| {
+                        info!("{} the queue", if paused { "pausing" } else { "resuming" });
+                        scheduler.set_paused(paused);
+                        self.status.set_paused(paused);
+                    },
+                )?),
+                None => None,
+            };
+
+            let workers: Vec<_> = (0..parallel)
+                .map(|_| {
+                    scope.spawn(|| {
+                        while let Some(admission) = scheduler.next() {
+                            let file = admission.item;
+                            if self.results.failed() {
+                                let dropped = scheduler.stop();
+                                info!("{} files were not started", dropped + 1);
+                                break;
+                            }
+                            if !self.options.dry_run
+                                && let Some(min_free_space) = self.options.min_free_space
+                                && let Some(finding) =
+                                    preflight::check_free_space(file, &output_paths, min_free_space)
+                            {
+                                let dropped = scheduler.stop();
+                                self.stopping.store(true, Ordering::SeqCst);
+                                warn!("Not starting any more files: {finding}");
+                                info!("{} files were not started", dropped + 1);
+                                break;
+                            }
+                            if !self.options.dry_run {
+                                match self.database.claim(
+                                    file.id,
+                                    &self.options.worker,
+                                    file.status,
+                                ) {
+                                    Ok(true) => {}
+                                    Ok(false) => {
+                                        info!(
+                                            "{} was claimed by another worker, skipping",
+                                            file.path
+                                        );
+                                        continue;
+                                    }
+                                    Err(e) => {
+                                        self.database_failed(
+                                            &e.wrap_err(format!("could not claim {}", file.path)),
+                                        );
+                                        continue;
+                                    }
+                                }
+                            }
+                            self.status
+                                .start_file(&file.path, (file.duration * 1000.0) as u64);
+                            if let Some(energy) = &self.energy {
+                                energy.start_file(file.id, &file.path);
+                            }
+                            // held per file, so the system can still sleep while the queue is paused
+                            let inhibitor = (self.options.inhibit_sleep && !self.options.dry_run)
+                                .then(|| {
+                                    SleepInhibitor::acquire(&format!("Transcoding {}", file.path))
+                                })
+                                .flatten();
+                            let result = self.transcode_file(
+                                file,
+                                &output_paths,
+                                &total_progress,
+                                admission.on_cpu,
+                            );
+                            drop(inhibitor);
+                            self.status.finish_file(
+                                &file.path,
+                                match &result {
+                                    Ok(Outcome::Transcoded(_)) => FileOutcome::Encoded,
+                                    Ok(Outcome::Skipped(reason)) => FileOutcome::Skipped {
+                                        existing: reason.is_existing(),
+                                    },
+                                    Ok(Outcome::Checked) => {
+                                        FileOutcome::Skipped { existing: false }
+                                    }
+                                    Err(_) => FileOutcome::Failed,
+                                },
+                            );
+                            if let Ok(Outcome::Skipped(reason)) = &result {
+                                self.skipped.lock().unwrap().push(SkippedFile {
+                                    path: file.path.clone(),
+                                    reason: *reason,
+                                });
+                            }
+                            if let Some(energy) = &self.energy {
+                                energy.finish_file(file.id);
+                            }
+                            if !self.options.dry_run
+                                && !self.results.failed()
+                                && let Err(e) =
+                                    self.database.release_claim(file.id, &self.options.worker)
+                            {
+                                warn!("Could not release the claim on {}: {:?}", file.path, e);
+                            }
+                            if let Ok(Outcome::Transcoded(saved)) = result {
+                                let saved = total_saved.fetch_add(saved, Ordering::SeqCst) + saved;
+                                if let Some(limit) = self.options.stop_after_saved
+                                    && saved >= limit
+                                {
+                                    let dropped = scheduler.stop();
+                                    self.stopping.store(true, Ordering::SeqCst);
+                                    if dropped > 0 {
+                                        info!(
+                                            "Saved {}, not starting the remaining {dropped} files",
+                                            saved.human_count_bytes()
+                                        );
+                                    }
+                                }
+                            }
+                            if let Some(run_id) = run_id
+                                && !self.results.failed()
+                                && let Err(e) = self.database.set_file_run(file.id, run_id)
+                            {
+                                warn!("Could not record the run for {}: {:?}", file.path, e);
+                            }
+                            if let Err(e) = &result {
+                                warn!("Could not transcode file {}: {:?}", file.path, e);
+                            }
+                            if !self.options.dry_run {
+                                let directory = storage::watched_directory(&file.path);
+                                let storage_failure = result.as_ref().is_err_and(|e| {
+                                    storage::is_input_error(e, &file.path)
+                                        && !storage::is_readable(directory)
+                                });
+                                let failed = storage::FailedFile {
+                                    id: file.id,
+                                    path: file.path.clone(),
+                                };
+                                let action = outage.lock().unwrap().record(failed, storage_failure);
+                                match action {
+                                    storage::Action::Continue => {}
+                                    storage::Action::Requeue(file) => {
+                                        self.record(FileResult::requeued(file.id, &file.path));
+                                    }
+                                    storage::Action::Pause(files) => {
+                                        for file in &files {
+                                            self.record(FileResult::requeued(file.id, &file.path));
+                                        }
+                                        self.wait_for_storage(&scheduler, directory, files.len());
+                                        outage.lock().unwrap().resume();
+                                    }
+                                }
+                            }
+                        }
+                    })
+                })
+                .collect();
+            let results: Vec<_> = workers.into_iter().map(|w| w.join()).collect();
+
+            #[cfg(feature = "http")]
+            if let Some(server) = server {
+                server.shutdown();
+            }
+            for result in results {
+                if let Err(panic) = result {
+                    std::panic::resume_unwind(panic);
+                }
+            }
+            Ok(())
+        })?;
+        self.flush_results()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{NewTranscodeFile, TranscodeStatus};
+    use crate::speed::Speed;
+
+    #[test]
+    fn test_run_order() {
+        // biggest first, the sizes are also the memory
+        let sizes = [100, 90, 80, 70, 40, 30, 20, 10];
+        let started = |order: Option<FileSortOrder>| {
+            let options = TranscodeOptions {
+                order,
+                ..TranscodeOptions::dry_run()
+            };
+            let scheduler = run_scheduler(
+                sizes.map(|size| (size, size)).to_vec(),
+                &options,
+                4,
+                |size| *size,
+            );
+            let admitted: Vec<_> = (0..4).map(|_| scheduler.next().unwrap()).collect();
+            admitted.iter().map(|a| a.item).collect::<Vec<_>>()
+        };
+        // biggest first starts with a mix of sizes
+        assert_eq!(
+            vec![100, 30, 20, 10],
+            started(Some(FileSortOrder::BiggestFirst))
+        );
+        // the interleaved order and a plan start in their own order
+        assert_eq!(
+            vec![100, 90, 80, 70],
+            started(Some(FileSortOrder::Interleaved))
+        );
+        assert_eq!(vec![100, 90, 80, 70], started(None));
+    }
+
+    #[test]
+    fn test_resolve_parallel() {
+        let no_limit = || None;
+        assert_eq!(4, resolve_parallel(Some(4), None, no_limit));
+        assert_eq!(
+            4,
+            resolve_parallel(Some(4), Some(&GpuMode::Nvidia), || Some(2))
+        );
+        assert_eq!(1, resolve_parallel(None, None, no_limit));
+        assert_eq!(2, resolve_parallel(None, Some(&GpuMode::Qsv), no_limit));
+        assert_eq!(3, resolve_parallel(None, Some(&GpuMode::Nvidia), no_limit));
+        assert_eq!(
+            3,
+            resolve_parallel(None, Some(&GpuMode::Nvidia), || Some(8))
+        );
+        assert_eq!(
+            2,
+            resolve_parallel(None, Some(&GpuMode::Nvidia), || Some(2))
+        );
+    }
+
+    #[test]
+    fn test_database_failure_stops_the_run() -> Result<()> {
+        struct ReadOnly;
+        impl results::ResultWriter for ReadOnly {
+            fn write(&self, _: &FileResult) -> Result<()> {
+                Err(eyre!("attempt to write a readonly database"))
+            }
+        }
+        let mut transcoder = Transcoder::new(
+            Database::in_memory()?,
+            TranscodeOptions::for_tests(),
+            vec![],
+        );
+        transcoder.results = ResultBuffer::new(Box::new(ReadOnly)).with_delays(vec![]);
+
+        transcoder.bookkeeping(Ok(()));
+        assert!(!transcoder.stopping.load(Ordering::SeqCst));
+        transcoder.record(FileResult::transcoded(
+            1,
+            "/a.mkv".into(),
+            "/a_av1.mp4".into(),
+        ));
+        assert!(transcoder.stopping.load(Ordering::SeqCst));
+        assert!(transcoder.results.failed());
+        transcoder.record(FileResult::failed(
+            2,
+            "/b.mkv".into(),
+            "ffmpeg failed".into(),
+        ));
+
+        let tempdir = tempfile::tempdir()?;
+        let dump = Utf8Path::from_path(tempdir.path())
+            .unwrap()
+            .join("dump.json");
+        let Flushed::Dumped { count, .. } = transcoder.results.flush(&dump)? else {
+            panic!("the results weren't dumped");
+        };
+        assert_eq!(2, count);
+        Ok(())
+    }
+
+    #[test]
+    fn test_nothing_to_transcode() -> Result<()> {
+        let options = TranscodeOptions {
+            dry_run: false,
+            ..TranscodeOptions::for_tests()
+        };
+        let transcoder = Transcoder::new(Database::in_memory()?, options, vec![]);
+        // returns before looking for ffmpeg or touching the terminal
+        transcoder.transcode_all()?;
+        assert!(transcoder.summary(&[]).nothing_done());
+        Ok(())
+    }
+
+    #[test]
+    fn test_skips_are_counted() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let directory = Utf8Path::from_path(tempdir.path()).unwrap();
+        let file = |id: i64, name: &str| VideoFile {
+            id,
+            path: directory.join(name),
+            duration: 60.0,
+            resolution: (1920, 1080),
+            bitrate: 0,
+            video_bitrate: 0,
+            audio_bitrate: 0,
+            frame_rate: 30.0,
+            codec: "h264".into(),
+            profile: None,
+            container: "matroska".into(),
+            file_size: 1000,
+            status: TranscodeStatus::Pending,
+            audio_tracks: vec![],
+            pix_fmt: None,
+            start_offset: None,
+            pinned: false,
+            output_path: None,
+            title: None,
+            chapters: vec![],
+        };
+        fs::write(directory.join("done.mkv"), b"")?;
+        fs::write(directory.join("done_av1.mp4"), b"")?;
+        fs::write(directory.join("new.mkv"), b"")?;
+        let files = vec![file(1, "done.mkv"), file(2, "gone.mkv"), file(3, "new.mkv")];
+        let selected = [SkippedFile {
+            path: directory.join("old.mkv"),
+            reason: SkipReason::AlreadyTranscoded,
+        }];
+
+        let transcoder =
+            Transcoder::new(Database::in_memory()?, TranscodeOptions::for_tests(), files);
+        transcoder.transcode_all()?;
+        let summary = transcoder.summary(&selected);
+        // a dry run only checks the files that would be encoded, and an empty
+        // leftover output doesn't count as one
+        assert!(summary.nothing_done());
+        assert_eq!(0, summary.failed);
+        assert_eq!(1, summary.skipped_existing);
+        assert_eq!(1, summary.skipped_ignored);
+        assert_eq!(
+            "0 transcoded, 2 skipped (1 already-transcoded, 1 missing)",
+            summary.to_string()
+        );
+        let snapshot = transcoder.status.snapshot();
+        assert_eq!(3, snapshot.finished_files);
+        assert_eq!(0, snapshot.skipped_existing);
+        assert_eq!(3, snapshot.skipped_ignored);
+        assert_eq!(0, snapshot.encoded_files);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stream_args() {
+        let settings = config::merge(
+            &TranscodeSettings::default(),
+            None,
+            &TranscodeSettings::default(),
+            VideoCodec::Av1,
+        );
+        let copy = vec!["-c:a".to_string(), "copy".to_string()];
+        let args = stream_args(
+            "in.mkv".into(),
+            None,
+            &settings,
+            &Constraints::default(),
+            &copy,
+            None,
+            StreamFormat::Matroska,
+        );
+        assert_eq!(
+            ["-c:a", "copy", "-f", "matroska", "pipe:1"],
+            args[args.len() - 5..]
+        );
+        assert!(!args.iter().any(|arg| arg == "-progress"));
+
+        let args = stream_args(
+            "in.mkv".into(),
+            Some(&GpuMode::Nvidia),
+            &settings,
+            &Constraints::default(),
+            &copy,
+            None,
+            StreamFormat::Mp4,
+        );
+        let movflags = args.iter().position(|arg| arg == "-movflags").unwrap();
+        assert!(args[movflags + 1].contains("empty_moov"));
+        assert_eq!("pipe:1", args.last().unwrap());
+        assert_eq!(
+            ffmpeg_args(
+                "in.mkv".into(),
+                "out.mp4".into(),
+                Some(&GpuMode::Nvidia),
+                &settings,
+                &Constraints::default(),
+                &copy,
+                None
+            )[..args.len() - 6],
+            args[..args.len() - 6]
+        );
+    }
+
+    #[test]
+    fn test_codec_args() {
+        let settings = config::merge(
+            &TranscodeSettings::default(),
+            None,
+            &TranscodeSettings::default(),
+            VideoCodec::Hevc,
+        );
+        let hevc = Constraints::for_codec(VideoCodec::Hevc);
+        let copy = vec!["-c:a".to_string(), "copy".to_string()];
+        let encoder = |gpu: Option<&GpuMode>| {
+            let args = ffmpeg_args(
+                "in.mkv".into(),
+                "out.mp4".into(),
+                gpu,
+                &settings,
+                &hevc,
+                &copy,
+                None,
+            );
+            let c = args.iter().position(|arg| arg == "-c:v").unwrap();
+            args[c + 1..c + 6].to_vec()
+        };
+        assert_eq!(
+            ["libx265", "-preset", "medium", "-crf", "28"],
+            encoder(None)[..]
+        );
+        assert_eq!("hevc_nvenc", encoder(Some(&GpuMode::Nvidia))[0]);
+        let args = ffmpeg_args(
+            "in.mkv".into(),
+            "out.mp4".into(),
+            Some(&GpuMode::Nvidia),
+            &settings,
+            &hevc,
+            &copy,
+            None,
+        );
+        assert!(args.windows(2).any(|w| w == ["-cq", "28"]));
+        assert!(!args.iter().any(|arg| arg == "-svtav1-params"));
+        assert_eq!(["hevc_qsv", "-preset"], encoder(Some(&GpuMode::Qsv))[..2]);
+        // AV1 without a device has no constraints
+        assert!(Constraints::for_codec(VideoCodec::Av1).is_empty());
+    }
+
+    #[test]
+    fn test_vaapi_args() {
+        let settings = config::merge(
+            &TranscodeSettings {
+                crf: Some(30),
+                ..Default::default()
+            },
+            None,
+            &TranscodeSettings::default(),
+            VideoCodec::Av1,
+        );
+        let copy = vec!["-c:a".to_string(), "copy".to_string()];
+        let args = |constraints: &Constraints| {
+            ffmpeg_args(
+                "in.mkv".into(),
+                "out.mp4".into(),
+                Some(&GpuMode::Vaapi),
+                &settings,
+                constraints,
+                &copy,
+                None,
+            )
+        };
+        let av1 = args(&Constraints::default());
+        let input = av1.iter().position(|arg| arg == "-i").unwrap();
+        assert_eq!(
+            [
+                "-hwaccel",
+                "vaapi",
+                "-hwaccel_device",
+                "/dev/dri/renderD128",
+                "-hwaccel_output_format",
+                "vaapi",
+                "-y"
+            ],
+            av1[..input]
+        );
+        let c = av1.iter().position(|arg| arg == "-c:v").unwrap();
+        assert_eq!(
+            [
+                "av1_vaapi",
+                "-compression_level",
+                "4",
+                "-rc_mode",
+                "CQP",
+                "-qp",
+                "120"
+            ],
+            av1[c + 1..c + 8]
+        );
+        assert!(
+            av1.windows(2)
+                .any(|w| w == ["-vf", "format=nv12|vaapi,hwupload"])
+        );
+        assert!(!av1.iter().any(|arg| arg == "-pix_fmt"));
+
+        // HEVC takes the CRF as its QP
+        let hevc = args(&Constraints::for_codec(VideoCodec::Hevc));
+        assert!(hevc.windows(2).any(|w| w == ["-c:v", "hevc_vaapi"]));
+        assert!(hevc.windows(2).any(|w| w == ["-qp", "30"]));
+        assert_eq!(252, hardware_qp(VideoCodec::Av1, 63));
+
+        // --gpu-device replaces the render node
+        let moved = gpu_device_args(&av1, Some("/dev/dri/renderD129".into()));
+        assert_eq!("/dev/dri/renderD129", moved[3]);
+        assert_eq!(av1, gpu_device_args(&av1, None));
+        let cpu = ffmpeg_args(
+            "in.mkv".into(),
+            "out.mp4".into(),
+            None,
+            &settings,
+            &Constraints::default(),
+            &copy,
+            None,
+        );
+        assert_eq!(
+            cpu,
+            gpu_device_args(&cpu, Some("/dev/dri/renderD129".into()))
+        );
+    }
+
+    #[test]
+    fn test_gpu_args() {
+        let settings = config::merge(
+            &TranscodeSettings {
+                crf: Some(30),
+                ..Default::default()
+            },
+            None,
+            &TranscodeSettings::default(),
+            VideoCodec::Av1,
+        );
+        let copy = vec!["-c:a".to_string(), "copy".to_string()];
+        let args = |gpu: Option<&GpuMode>| {
+            ffmpeg_args(
+                "in.mkv".into(),
+                "out.mp4".into(),
+                gpu,
+                &settings,
+                &Constraints::default(),
+                &copy,
+                None,
+            )
+        };
+        let output = ["-c:a", "copy", "-progress", "-", "-nostats", "out.mp4"];
+        for (gpu, expected) in [
+            (
+                None,
+                vec![
+                    "-y",
+                    "-i",
+                    "in.mkv",
+                    "-c:v",
+                    "libsvtav1",
+                    "-preset",
+                    "7",
+                    "-crf",
+                    "30",
+                ],
+            ),
+            (
+                Some(GpuMode::Nvidia),
+                vec![
+                    "-y",
+                    "-i",
+                    "in.mkv",
+                    "-c:v",
+                    "av1_nvenc",
+                    "-preset",
+                    "p7",
+                    "-tune",
+                    "hq",
+                    "-cq",
+                    "30",
+                    "-rc-lookahead",
+                    "24",
+                    "-b_adapt",
+                    "1",
+                    "-temporal-aq",
+                    "1",
+                    "-spatial-aq",
+                    "1",
+                ],
+            ),
+            (
+                Some(GpuMode::Qsv),
+                vec![
+                    "-hwaccel", "qsv", "-y", "-i", "in.mkv", "-c:v", "av1_qsv", "-preset", "4",
+                    "-crf", "30",
+                ],
+            ),
+            (
+                Some(GpuMode::Vaapi),
+                vec![
+                    "-hwaccel",
+                    "vaapi",
+                    "-hwaccel_device",
+                    "/dev/dri/renderD128",
+                    "-hwaccel_output_format",
+                    "vaapi",
+                    "-y",
+                    "-i",
+                    "in.mkv",
+                    "-c:v",
+                    "av1_vaapi",
+                    "-compression_level",
+                    "4",
+                    "-rc_mode",
+                    "CQP",
+                    "-qp",
+                    "120",
+                    "-vf",
+                    "format=nv12|vaapi,hwupload",
+                ],
+            ),
+            (
+                Some(GpuMode::Amf),
+                vec![
+                    "-y", "-i", "in.mkv", "-c:v", "av1_amf", "-quality", "quality", "-rc", "cqp",
+                    "-qp_i", "120", "-qp_p", "120",
+                ],
+            ),
+        ] {
+            let mut expected = expected;
+            expected.extend(output);
+            assert_eq!(expected, args(gpu.as_ref()), "{gpu:?}");
+        }
+    }
+
+    #[test]
+    fn test_amf_args() {
+        let settings = |speed: u8, ten_bit: bool| {
+            config::merge(
+                &TranscodeSettings {
+                    speed: Some(Speed::try_from(speed).unwrap()),
+                    ten_bit: Some(ten_bit),
+                    ..Default::default()
+                },
+                None,
+                &TranscodeSettings::default(),
+                VideoCodec::Hevc,
+            )
+        };
+        let copy = vec!["-c:a".to_string(), "copy".to_string()];
+        let hevc = Constraints::for_codec(VideoCodec::Hevc);
+        let args = |settings: &EncodeSettings| {
+            ffmpeg_args(
+                "in.mkv".into(),
+                "out.mp4".into(),
+                Some(&GpuMode::Amf),
+                settings,
+                &hevc,
+                &copy,
+                None,
+            )
+        };
+        // HEVC takes the CRF as its QP, and 8 bits are uploaded as NV12
+        let eight_bit = args(&settings(6, false));
+        let c = eight_bit.iter().position(|arg| arg == "-c:v").unwrap();
+        assert_eq!(
+            [
+                "hevc_amf", "-quality", "quality", "-rc", "cqp", "-qp_i", "28", "-qp_p", "28",
+                "-pix_fmt", "nv12"
+            ],
+            eight_bit[c + 1..c + 12]
+        );
+        let ten_bit = args(&settings(6, true));
+        assert!(ten_bit.windows(2).any(|w| w == ["-pix_fmt", "p010le"]));
+
+        // faster speeds trade quality
+        let quality = |speed| {
+            let args = args(&settings(speed, false));
+            let index = args.iter().position(|arg| arg == "-quality").unwrap();
+            args[index + 1].clone()
+        };
+        assert_eq!("balanced", quality(7));
+        assert_eq!("speed", quality(10));
+        assert_eq!(
+            "hevc_amf quality balanced (speed 8)",
+            describe_preset(Some(&GpuMode::Amf), &settings(8, false), VideoCodec::Hevc)
+        );
+    }
+
+    #[test]
+    fn test_vp9_args() {
+        let settings = config::merge(
+            &TranscodeSettings {
+                speed: Some(Speed::try_from(4).unwrap()),
+                ..Default::default()
+            },
+            None,
+            &TranscodeSettings::default(),
+            VideoCodec::Vp9,
+        );
+        let vp9 = Constraints::for_codec(VideoCodec::Vp9);
+        let audio = vec!["-c:a".to_string(), "libopus".to_string()];
+        let args = ffmpeg_args(
+            "in.mp4".into(),
+            "out.webm".into(),
+            None,
+            &settings,
+            &vp9,
+            &audio,
+            None,
+        );
+        let c = args.iter().position(|arg| arg == "-c:v").unwrap();
+        assert_eq!(
+            [
+                "libvpx-vp9",
+                "-cpu-used",
+                "2",
+                "-crf",
+                "31",
+                "-b:v",
+                "0",
+                "-row-mt",
+                "1"
+            ],
+            args[c + 1..c + 10]
+        );
+        assert!(args.windows(2).any(|w| w == ["-pix_fmt", "yuv420p"]));
+        assert!(!args.iter().any(|arg| arg == "-preset"));
+        assert_eq!(Some(&"out.webm".to_string()), args.last());
+        // there's no hardware VP9 encoder to pick
+        assert_eq!(
+            "libvpx-vp9",
+            encoder_name(Some(&GpuMode::Qsv), VideoCodec::Vp9)
+        );
+    }
+
+    #[test]
+    fn test_start_offset_args() {
+        let settings = config::merge(
+            &TranscodeSettings::default(),
+            None,
+            &TranscodeSettings::default(),
+            VideoCodec::Av1,
+        );
+        let copy = vec!["-c:a".to_string(), "copy".to_string()];
+        let args = ffmpeg_args(
+            "IMG_4021.MOV".into(),
+            "out.mp4".into(),
+            None,
+            &settings,
+            &Constraints::default(),
+            &copy,
+            Some(-0.02322),
+        );
+        assert_eq!(
+            [
+                "-c:a",
+                "copy",
+                "-avoid_negative_ts",
+                "make_zero",
+                "-progress",
+                "-",
+                "-nostats",
+                "out.mp4"
+            ],
+            args[args.len() - 8..]
+        );
+        assert!(!args.iter().any(|arg| arg == "-copyts"));
+
+        // composes with the audio mapping and the stream output
+        let args = stream_args(
+            "IMG_4021.MOV".into(),
+            Some(&GpuMode::Qsv),
+            &settings,
+            &Constraints::default(),
+            &["-an".to_string()],
+            Some(1.4),
+            StreamFormat::Matroska,
+        );
+        let an = args.iter().position(|arg| arg == "-an").unwrap();
+        assert_eq!(
+            ["-avoid_negative_ts", "make_zero", "-f"],
+            args[an + 1..an + 4]
+        );
+
+        let args = ffmpeg_args(
+            "in.mkv".into(),
+            "out.mp4".into(),
+            None,
+            &settings,
+            &Constraints::default(),
+            &copy,
+            None,
+        );
+        assert!(!args.iter().any(|arg| arg == "-avoid_negative_ts"));
+    }
+
+    #[test]
+    fn test_rendition_args() -> Result<()> {
+        let db = Database::in_memory()?;
+        let path = Utf8PathBuf::from("/mnt/videos/The Expanse - S01E01 - Dulcinea.mkv");
+        db.insert(crate::database::NewTranscodeFile {
+            path: path.clone(),
+            file_size: 2211163528,
+            ffprobe_info: crate::ffprobe::chaptered_fixture(),
+        })?;
+        let file = VideoFile::from(db.get_by_path(&path)?.unwrap());
+        let args = vec!["archive".parse().unwrap(), "share:stream".parse().unwrap()];
+        let resolved = renditions::resolve(&args, &Default::default())?;
+        let settings = config::merge(
+            &TranscodeSettings::default(),
+            None,
+            &TranscodeSettings::default(),
+            VideoCodec::Av1,
+        );
+        let paths = OutputPaths::default();
+        let legs: Vec<_> = resolved
+            .iter()
+            .map(|rendition| Leg {
+                rendition,
+                settings: rendition.settings(&settings),
+                constraints: rendition.constraints(&Constraints::default()),
+                tmp: paths.rendition_tmp(&file.path, file.id, &rendition.name),
+                output: Utf8PathBuf::new(),
+            })
+            .collect();
+        let copy = vec!["-c:a".to_string(), "copy".to_string()];
+        let args = rendition_args(&file.path, &legs, &copy, &file);
+
+        // one input, then each output with its own encoder
+        assert_eq!(1, args.iter().filter(|arg| *arg == "-i").count());
+        let outputs: Vec<_> = args
+            .iter()
+            .enumerate()
+            .filter(|(_, arg)| arg.ends_with(".tmp.mp4"))
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(2, outputs.len());
+        assert!(args[outputs[0]].ends_with(".archive.tmp.mp4"));
+        assert!(args[outputs[1]].ends_with(".share.tmp.mp4"));
+        let (archive, share) = (&args[..outputs[0]], &args[outputs[0]..outputs[1]]);
+        assert!(archive.contains(&"libsvtav1".to_string()));
+        assert!(!archive.contains(&"-vf".to_string()));
+        assert!(share.contains(&"libx264".to_string()));
+        // the 1080p source is scaled down to the stream profile's 720 lines
+        assert!(share.windows(2).any(|w| w == ["-vf", "scale=-2:720"]));
+        assert_eq!(
+            2,
+            args.windows(2).filter(|w| w == &["-c:a", "copy"]).count()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_encoder_rules() -> Result<()> {
+        let config: crate::config::Config = toml::from_str(
+            r#"
+            [transcode]
+            crf = 28
+            effort = 5
+
+            [[encoder-rules]]
+            name = "screencasts"
+            path = "**/Screencasts/**"
+            encoder = "nvidia"
+            transcode = { crf = 35 }
+            "#,
+        )?;
+        let file = |path: &str| VideoFile {
+            id: 1,
+            path: path.into(),
+            duration: 60.0,
+            resolution: (1920, 1080),
+            bitrate: 0,
+            video_bitrate: 0,
+            audio_bitrate: 0,
+            frame_rate: 30.0,
+            codec: "h264".into(),
+            profile: None,
+            container: "mp4".into(),
+            file_size: 1000,
+            status: TranscodeStatus::Pending,
+            audio_tracks: vec![],
+            pix_fmt: None,
+            start_offset: None,
+            pinned: false,
+            output_path: None,
+            title: None,
+            chapters: vec![],
+        };
+        let options = TranscodeOptions {
+            config: config.transcode,
+            encoder_rules: config.encoder_rules,
+            ..TranscodeOptions::for_tests()
+        };
+        let transcoder = Transcoder::new(Database::in_memory()?, options.clone(), vec![]);
+
+        let screencast = transcoder.settings_for(&file("/nonexistent/Screencasts/talk.mp4"))?;
+        assert_eq!(Some("screencasts"), screencast.rule.as_deref());
+        assert!(matches!(screencast.gpu, Some(GpuMode::Nvidia)));
+        assert_eq!(35, screencast.settings.crf);
+        assert_eq!(Some(5), screencast.settings.effort);
+        let other = transcoder.settings_for(&file("/nonexistent/Films/film.mp4"))?;
+        assert_eq!(None, other.rule);
+        assert!(other.gpu.is_none());
+        assert_eq!(28, other.settings.crf);
+
+        // the command line overrides the rule
+        let options = TranscodeOptions {
+            cli: TranscodeSettings {
+                crf: Some(20),
+                ..Default::default()
+            },
+            gpu: Some(GpuMode::Qsv),
+            ..options
+        };
+        let transcoder = Transcoder::new(Database::in_memory()?, options.clone(), vec![]);
+        let screencast = transcoder.settings_for(&file("/nonexistent/Screencasts/talk.mp4"))?;
+        assert_eq!(Some("screencasts"), screencast.rule.as_deref());
+        assert!(matches!(screencast.gpu, Some(GpuMode::Qsv)));
+        assert_eq!(20, screencast.settings.crf);
+
+        // repeated options ignore the command line, the config and the rules
+        let settings = EncodeSettings {
+            crf: 31,
+            speed: Speed::DEFAULT,
+            effort: Some(4),
+            film_grain: None,
+            ten_bit: true,
+            max_fps: None,
+            reproducible: false,
+            low_memory: None,
+        };
+        let mut repeat =
+            ResolvedOptions::new(None, &settings, &options.constraints, &options.audio, &[]);
+        repeat.encoder_rule = Some("old rule".into());
+        let options = TranscodeOptions {
+            repeat: Some(repeat),
+            ..options
+        };
+        let transcoder = Transcoder::new(Database::in_memory()?, options, vec![]);
+        let screencast = transcoder.settings_for(&file("/nonexistent/Screencasts/talk.mp4"))?;
+        assert_eq!(Some("old rule"), screencast.rule.as_deref());
+        assert!(screencast.gpu.is_none());
+        assert_eq!(settings, screencast.settings);
+        Ok(())
+    }
+
+    #[test]
+    fn test_drop_audio() -> Result<()> {
+        let track = |index, codec: &str| AudioTrack {
+            index,
+            codec: codec.into(),
+            bitrate: Some(256_000),
+            channels: Some(2),
+            title: None,
+            default: false,
+            commentary: false,
+        };
+        let file = VideoFile {
+            id: 1,
+            path: "/nonexistent/camera.mkv".into(),
+            duration: 60.0,
+            resolution: (1920, 1080),
+            bitrate: 0,
+            video_bitrate: 0,
+            audio_bitrate: 0,
+            frame_rate: 30.0,
+            codec: "h264".into(),
+            profile: None,
+            container: "matroska".into(),
+            file_size: 10_000_000,
+            status: TranscodeStatus::Pending,
+            audio_tracks: vec![track(0, "pcm_s16le"), track(1, "aac")],
+            pix_fmt: None,
+            start_offset: None,
+            pinned: false,
+            output_path: None,
+            title: None,
+            chapters: vec![],
+        };
+        let mut options = TranscodeOptions::for_tests();
+        options.audio.drop = true;
+        let transcoder = Transcoder::new(Database::in_memory()?, options.clone(), vec![]);
+        let settings = transcoder.settings_for(&file)?.settings;
+
+        let decisions = transcoder.audio_decisions(&file);
+        assert_eq!(2, decisions.len());
+        assert!(
+            decisions
+                .iter()
+                .all(|(_, decision)| *decision == AudioDecision::Drop)
+        );
+        let args = ffmpeg_args(
+            &file.path,
+            "out.mp4".into(),
+            None,
+            &settings,
+            &Constraints::default(),
+            &audio::audio_args(&decisions, &options.audio),
+            file.start_offset,
+        );
+        let an = args.iter().position(|arg| arg == "-an").unwrap();
+        assert_eq!("-progress", args[an + 1]);
+        assert!(!args.iter().any(|arg| arg == "-c:a" || arg == "0:a?"));
+
+        // recorded, so that --repeat-options drops the audio as well
+        let resolved = ResolvedOptions::new(
+            None,
+            &settings,
+            &Constraints::default(),
+            &options.audio,
+            &decisions,
+        );
+        let repeated = ResolvedOptions::from_json(&resolved.to_json()?)?;
+        assert!(repeated.audio.drop);
+        assert_eq!(decisions, repeated.audio_decisions());
+        Ok(())
+    }
+
+    #[test]
+    fn test_lost_chapters() {
+        let source = crate::ffprobe::chaptered_fixture();
+        let chapters = source.chapters.clone();
+        assert_eq!(None, lost_chapters(&chapters, None, &source));
+        // outputs of sources without chapters aren't checked
+        assert_eq!(
+            None,
+            lost_chapters(&[], None, &crate::ffprobe::fixtures()[1])
+        );
+
+        let mut output = source.clone();
+        output.chapters.pop();
+        assert_eq!(
+            Some("it has 3 chapters instead of 4".into()),
+            lost_chapters(&chapters, None, &output)
+        );
+        assert_eq!(
+            Some("it has 0 chapters instead of 4".into()),
+            lost_chapters(&chapters, None, &crate::ffprobe::fixtures()[1])
+        );
+
+        // moved to a keyframe nearby
+        let mut output = source.clone();
+        output.chapters[1].start_time = Some(95.88);
+        assert_eq!(None, lost_chapters(&chapters, None, &output));
+        output.chapters[2].end_time = Some(2640.0);
+        assert_eq!(
+            Some(
+                "its chapter 3 is at 0:22:22.050 - 0:44:00.000 \"Chapter 03\" instead of 0:22:22.050 - 0:43:58.221 \"Chapter 03\""
+                    .into()
+            ),
+            lost_chapters(&chapters, None, &output)
+        );
+
+        // a source that started at 1.4s has its chapters shifted with it
+        let mut output = source.clone();
+        for chapter in &mut output.chapters {
+            chapter.start_time = chapter.start_time.map(|start| start - 1.4);
+            chapter.end_time = chapter.end_time.map(|end| end - 1.4);
+        }
+        assert_eq!(None, lost_chapters(&chapters, Some(1.4), &output));
+        assert!(lost_chapters(&chapters, None, &output).is_some());
+    }
+
+    #[test]
+    fn test_lost_title() {
+        // the probes of an MP4 and a Matroska output
+        for output in crate::ffprobe::fixtures() {
+            let title = output.title().unwrap();
+            assert_eq!(None, lost_title(Some(title), &output));
+            assert_eq!(None, lost_title(None, &output));
+            assert_eq!(
+                Some(format!(
+                    "its title is \"{title}\" instead of \"Theatrical Cut\""
+                )),
+                lost_title(Some("Theatrical Cut"), &output)
+            );
+            let stripped = FfProbe {
+                format: Default::default(),
+                ..output
+            };
+            assert_eq!(
+                Some("it lost the title \"Dune\"".to_string()),
+                lost_title(Some("Dune"), &stripped)
+            );
+        }
+        assert_eq!(
+            vec!["-map_metadata", "0", "-metadata", "title=Director's Cut"],
+            metadata_args(Some("Director's Cut"), false)
+        );
+        assert_eq!(vec!["-map_metadata", "0"], metadata_args(None, false));
+    }
+
+    #[test]
+    fn test_title_survives_the_encode() -> Result<()> {
+        use crate::testsupport::{self, Sample};
+
+        if !testsupport::has_ffmpeg() {
+            return Ok(());
+        }
+        let dir = tempfile::tempdir()?;
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        for source in [Sample::SMALL, Sample::MKV] {
+            let titled = dir.join(format!("titled.{}", source.extension));
+            let output = binaries::command(Binary::Ffmpeg)
+                .args(["-v", "error", "-i"])
+                .arg(testsupport::sample(source)?)
+                .args(["-c", "copy", "-metadata", "title=Director's Cut"])
+                .arg(&titled)
+                .output()?;
+            assert!(output.status.success(), "{output:?}");
+            let file = VideoFile::probe(&titled)?;
+            assert_eq!(Some("Director's Cut"), file.title.as_deref());
+
+            for extension in ["mp4", "mkv"] {
+                let encoded = dir.join(format!("encoded.{extension}"));
+                let output = binaries::command(Binary::Ffmpeg)
+                    .args(["-y", "-v", "error", "-i"])
+                    .arg(&titled)
+                    .args(["-c:v", "mpeg4", "-c:a", "copy"])
+                    .args(metadata_args(file.title.as_deref(), false))
+                    .arg(&encoded)
+                    .output()?;
+                assert!(output.status.success(), "{output:?}");
+                assert_eq!(
+                    None,
+                    lost_title(file.title.as_deref(), &ffprobe(&encoded)?),
+                    "{} to {extension}",
+                    source.extension
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_paced_args() {
+        let args = remux_args("/nas/a.mkv".into(), "/tmp/a.mp4".into(), &[], None);
+        assert_eq!(args, paced_args(&args, None));
+        let paced = paced_args(&args, Some(2.5));
+        assert_eq!(["-y", "-readrate", "2.500", "-i", "/nas/a.mkv"], paced[..5]);
+        assert_eq!(args[2..], paced[4..]);
+    }
+
+    #[test]
+    fn test_reproducible_args() -> Result<()> {
+        let settings = EncodeSettings {
+            reproducible: true,
+            ..config::merge(
+                &TranscodeSettings::default(),
+                None,
+                &TranscodeSettings::default(),
+                VideoCodec::Av1,
+            )
+        };
+        let args = |constraints: &Constraints| {
+            encoder_args("in.mkv".into(), None, &settings, constraints, &[], None).join(" ")
+        };
+        assert!(args(&Constraints::default()).ends_with("-svtav1-params lp=4"));
+        let appletv = device::find("appletv4k", &Default::default())?;
+        assert!(
+            args(&Constraints::for_device(appletv.target())?)
+                .ends_with("-x265-params level-idc=5.1:pools=4:frame-threads=1")
+        );
+        let web = device::find("webh264", &Default::default())?;
+        assert!(args(&Constraints::for_device(web.target())?).ends_with("-threads 4"));
+        assert_eq!(
+            "-map_metadata -1 -metadata:s creation_time= -fflags +bitexact -flags +bitexact -metadata title=Dune",
+            metadata_args(Some("Dune"), true).join(" ")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_low_memory() -> Result<()> {
+        let options = TranscodeOptions {
+            cli: TranscodeSettings {
+                low_memory: Some(true),
+                film_grain: Some(8),
+                ..Default::default()
+            },
+            ..TranscodeOptions::for_tests()
+        };
+        let db = Database::in_memory()?;
+        let path = Utf8PathBuf::from("/nonexistent/4k.mkv");
+        db.insert(crate::database::NewTranscodeFile {
+            path: path.clone(),
+            file_size: 1000,
+            ffprobe_info: crate::ffprobe::fixtures()[0].clone(),
+        })?;
+        let file = VideoFile {
+            resolution: (3840, 2160),
+            ..VideoFile::from(db.get_by_path(&path)?.unwrap())
+        };
+        let transcoder = Transcoder::new(db, options, vec![]);
+        // the parameters follow the file's resolution and are recorded with it
+        let settings = transcoder.settings_for(&file)?.settings;
+        assert_eq!(
+            Some(low_memory::for_resolution((3840, 2160))),
+            settings.low_memory
+        );
+        let args = encoder_args(
+            "in.mkv".into(),
+            None,
+            &settings,
+            &Constraints::default(),
+            &[],
+            None,
+        );
+        assert_eq!(
+            "-svtav1-params film-grain=8:lookahead=9:hierarchical-levels=3:lp=2",
+            args[args.len() - 2..].join(" ")
+        );
+        // composes with the threads of a reproducible encode
+        let reproducible = EncodeSettings {
+            reproducible: true,
+            ..settings.clone()
+        };
+        let args = encoder_args(
+            "in.mkv".into(),
+            None,
+            &reproducible,
+            &Constraints::default(),
+            &[],
+            None,
+        );
+        assert_eq!(
+            "film-grain=8:lookahead=9:hierarchical-levels=3:lp=4",
+            args[args.len() - 1]
+        );
+        // the hardware encoders have no such parameters
+        let args = encoder_args(
+            "in.mkv".into(),
+            Some(&GpuMode::Nvidia),
+            &settings,
+            &Constraints::default(),
+            &[],
+            None,
+        );
+        assert!(!args.iter().any(|arg| arg.contains("lookahead=")));
+
+        let default = EncodeSettings {
+            low_memory: None,
+            ..settings.clone()
+        };
+        for resolution in [(1920, 1080), (3840, 2160)] {
+            let low = estimate_memory(resolution, None, &settings);
+            let full = estimate_memory(resolution, None, &default);
+            assert!(low < full, "{resolution:?}");
+        }
+        assert_eq!(
+            estimate_memory((3840, 2160), Some(&GpuMode::Nvidia), &settings),
+            estimate_memory((3840, 2160), Some(&GpuMode::Nvidia), &default)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_reproducible_encode() -> Result<()> {
+        use crate::testsupport::{self, Sample};
+
+        if !testsupport::has_ffmpeg() {
+            return Ok(());
+        }
+        let dir = tempfile::tempdir()?;
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        let source = testsupport::sample(Sample::SMALL)?;
+        let settings = EncodeSettings {
+            crf: 40,
+            speed: Speed::try_from(Speed::MAX).unwrap(),
+            reproducible: true,
+            ..config::merge(
+                &TranscodeSettings::default(),
+                None,
+                &TranscodeSettings::default(),
+                VideoCodec::Av1,
+            )
+        };
+        let mut encodes = vec![];
+        for run in 0..2 {
+            // a second apart, so that the time of the encode would show
+            if run > 0 {
+                thread::sleep(Duration::from_secs(1));
+            }
+            let output = dir.join(format!("{run}.mp4"));
+            let args = ffmpeg_args(
+                &source,
+                &output,
+                None,
+                &settings,
+                &Constraints::default(),
+                &metadata_args(Some("Archive"), true),
+                None,
+            );
+            let result = binaries::command(Binary::Ffmpeg).args(&args).output()?;
+            if String::from_utf8_lossy(&result.stderr).contains("Unknown encoder") {
+                eprintln!("ffmpeg has no libsvtav1, skipping");
+                return Ok(());
+            }
+            assert!(result.status.success(), "{result:?}");
+            encodes.push(fs::read(&output)?);
+        }
+        assert!(encodes[0] == encodes[1], "the encodes differ");
+        Ok(())
+    }
+
+    #[test]
+    fn test_warmup_args() {
+        let settings = config::merge(
+            &TranscodeSettings::default(),
+            None,
+            &TranscodeSettings::default(),
+            VideoCodec::Av1,
+        );
+        let args = warmup_args(None, &settings, &Constraints::default());
+        let input = args.iter().position(|arg| arg == "-i").unwrap();
+        assert_eq!(
+            ["-f", "lavfi", "-i", WARMUP_SOURCE],
+            args[input - 2..input + 2]
+        );
+        assert_eq!(["-an", "-f", "null", "-"], args[args.len() - 4..]);
+        // the encoder arguments after the input are the ones of a real encode
+        let encode = ffmpeg_args(
+            "in.mkv".into(),
+            "out.mp4".into(),
+            None,
+            &settings,
+            &Constraints::default(),
+            &[],
+            None,
+        );
+        let encode_input = encode.iter().position(|arg| arg == "-i").unwrap();
+        assert_eq!(
+            encode[encode_input + 2..encode.len() - 4],
+            args[input + 2..args.len() - 4]
+        );
+    }
+
+    #[test]
+    fn test_rejected_settings_stop_the_run() -> Result<()> {
+        static WARMUPS: AtomicU64 = AtomicU64::new(0);
+        /// Fails like ffmpeg does for SVT-AV1 presets above 13.
+        fn fake_ffmpeg(args: &[String]) -> Result<()> {
+            WARMUPS.fetch_add(1, Ordering::SeqCst);
+            let preset = args.iter().position(|arg| arg == "-preset").unwrap();
+            if args[preset + 1].parse::<u8>()? > 13 {
+                bail!("[libsvtav1 @ 0x5581] Error setting option preset to value 20.");
+            }
+            Ok(())
+        }
+
+        let tempdir = tempfile::tempdir()?;
+        let directory = Utf8Path::from_path(tempdir.path()).unwrap();
+        let file = |id: i64, name: &str| VideoFile {
+            id,
+            path: directory.join(name),
+            duration: 60.0,
+            resolution: (1920, 1080),
+            bitrate: 0,
+            video_bitrate: 0,
+            audio_bitrate: 0,
+            frame_rate: 30.0,
+            codec: "h264".into(),
+            profile: None,
+            container: "matroska".into(),
+            file_size: 1000,
+            status: TranscodeStatus::Pending,
+            audio_tracks: vec![],
+            pix_fmt: None,
+            start_offset: None,
+            pinned: false,
+            output_path: None,
+            title: None,
+            chapters: vec![],
+        };
+        fs::write(directory.join("a.mkv"), b"")?;
+        fs::write(directory.join("b.mkv"), b"")?;
+        let mut options = TranscodeOptions {
+            dry_run: false,
+            ..TranscodeOptions::for_tests()
+        };
+        options.cli.effort = Some(20);
+        let files = vec![file(1, "a.mkv"), file(2, "b.mkv")];
+        let mut transcoder = Transcoder::new(Database::in_memory()?, options, files);
+        transcoder.warmup = fake_ffmpeg;
+
+        let error = transcoder.transcode_all().unwrap_err();
+        assert!(format!("{error:?}").contains("Error setting option preset"));
+        assert!(error.to_string().contains("--no-preflight-encode"));
+        // both files have the same settings, so they're only tried once
+        assert_eq!(1, WARMUPS.load(Ordering::SeqCst));
+        let mut names: Vec<_> = fs::read_dir(directory)?
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(vec!["a.mkv", "b.mkv"], names);
+        assert_eq!(0, transcoder.status.snapshot().finished_files);
+        Ok(())
+    }
+
+    /// ffmpeg writing more warnings than fit in the pipe before its next progress
+    /// line used to block the encode forever.
+    #[cfg(unix)]
+    #[test]
+    fn test_missing_source_is_a_storage_error() -> Result<()> {
+        let file = VideoFile {
+            id: 1,
+            path: "/mnt/nas/gone.mkv".into(),
+            duration: 2.0,
+            resolution: (1920, 1080),
+            bitrate: 0,
+            video_bitrate: 0,
+            audio_bitrate: 0,
+            frame_rate: 30.0,
+            codec: "h264".into(),
+            profile: None,
+            container: "matroska".into(),
+            file_size: 1000,
+            status: TranscodeStatus::Pending,
+            audio_tracks: vec![],
+            pix_fmt: None,
+            start_offset: None,
+            pinned: false,
+            output_path: None,
+            title: None,
+            chapters: vec![],
+        };
+        let transcoder = Transcoder::new(
+            Database::in_memory()?,
+            TranscodeOptions::for_tests(),
+            vec![],
+        );
+        let progress = ProgressBar::hidden();
+        let fake_ffmpeg = |stderr: &str| {
+            let mut command = Command::new("sh");
+            command.args(["-c", &format!("echo '{stderr}' >&2; exit 1")]);
+            command
+        };
+
+        let error = transcoder
+            .run_encoder(
+                &file,
+                fake_ffmpeg("/mnt/nas/gone.mkv: No such file or directory"),
+                0.0,
+                &mut FileBars {
+                    file: &progress,
+                    total: &progress,
+                    progress: FileProgress::new(0),
+                    deadline: None,
+                },
+            )
+            .unwrap_err();
+        assert!(storage::is_input_error(&error, &file.path));
+        assert!(!storage::is_readable(storage::watched_directory(
+            &file.path
+        )));
+
+        let error = transcoder
+            .run_encoder(
+                &file,
+                fake_ffmpeg("Error while decoding stream #0:0: Invalid data found"),
+                0.0,
+                &mut FileBars {
+                    file: &progress,
+                    total: &progress,
+                    progress: FileProgress::new(0),
+                    deadline: None,
+                },
+            )
+            .unwrap_err();
+        assert!(!storage::is_input_error(&error, &file.path));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_stderr_is_read_during_the_encode() -> Result<()> {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            let encode = || -> Result<(Duration, Option<ResourceUsage>)> {
+                let file = VideoFile {
+                    id: 1,
+                    path: "/videos/vfr.mkv".into(),
+                    duration: 2.0,
+                    resolution: (1920, 1080),
+                    bitrate: 0,
+                    video_bitrate: 0,
+                    audio_bitrate: 0,
+                    frame_rate: 30.0,
+                    codec: "h264".into(),
+                    profile: None,
+                    container: "matroska".into(),
+                    file_size: 1000,
+                    status: TranscodeStatus::Pending,
+                    audio_tracks: vec![],
+                    pix_fmt: None,
+                    start_offset: None,
+                    pinned: false,
+                    output_path: None,
+                    title: None,
+                    chapters: vec![],
+                };
+                let transcoder = Transcoder::new(
+                    Database::in_memory()?,
+                    TranscodeOptions::for_tests(),
+                    vec![],
+                );
+                let mut fake_ffmpeg = Command::new("sh");
+                fake_ffmpeg.args([
+                    "-c",
+                    "yes 'Past duration 0.999992 too large' | head -c 4000000 >&2; \
+                     echo out_time_us=1000000; echo progress=end; \
+                     echo 'Conversion failed!' >&2; exit 1",
+                ]);
+                let progress = ProgressBar::hidden();
+                let mut bars = FileBars {
+                    file: &progress,
+                    total: &progress,
+                    progress: FileProgress::new(2000),
+                    deadline: None,
+                };
+                transcoder.run_encoder(&file, fake_ffmpeg, 0.0, &mut bars)
+            };
+            let _ = sender.send(encode());
+        });
+
+        let result = receiver
+            .recv_timeout(Duration::from_secs(60))
+            .expect("the encode is stuck on a full stderr pipe");
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("Conversion failed!"));
+        assert!(error.len() < 2 * stderr::TAIL_BYTES);
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_timeout() -> Result<()> {
+        let database = Database::in_memory()?;
+        database.insert(NewTranscodeFile {
+            path: "/videos/hung.mkv".into(),
+            file_size: 1000,
+            ffprobe_info: FfProbe::default(),
+        })?;
+        let id = database.get_by_path("/videos/hung.mkv".into())?.unwrap().id;
+        let file = VideoFile {
+            id,
+            path: "/videos/hung.mkv".into(),
+            duration: 2.0,
+            resolution: (1920, 1080),
+            bitrate: 0,
+            video_bitrate: 0,
+            audio_bitrate: 0,
+            frame_rate: 30.0,
+            codec: "h264".into(),
+            profile: None,
+            container: "matroska".into(),
+            file_size: 1000,
+            status: TranscodeStatus::Pending,
+            audio_tracks: vec![],
+            pix_fmt: None,
+            start_offset: None,
+            pinned: false,
+            output_path: None,
+            title: None,
+            chapters: vec![],
+        };
+        let options = TranscodeOptions {
+            dry_run: false,
+            file_timeout: Some(Duration::from_millis(200)),
+            ..TranscodeOptions::for_tests()
+        };
+        let transcoder = Transcoder::new(database.clone(), options, vec![]);
+        // stops writing progress and hangs, as ffmpeg does on a stuck mount
+        let mut hung_ffmpeg = Command::new("sh");
+        hung_ffmpeg.args(["-c", "echo out_time_us=1000; exec sleep 30"]);
+        let progress = ProgressBar::hidden();
+        let mut bars = FileBars {
+            file: &progress,
+            total: &progress,
+            progress: FileProgress::new(2000),
+            deadline: Some(Instant::now() + Duration::from_millis(200)),
+        };
+
+        let start = Instant::now();
+        let error = transcoder
+            .run_encoder(&file, hung_ffmpeg, 0.0, &mut bars)
+            .unwrap_err();
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert!(error.to_string().contains("--file-timeout"), "{error}");
+        let row = database.get(id)?.unwrap();
+        assert_eq!(TranscodeStatus::Error, row.status);
+
+        // an encode that finishes in time isn't affected
+        let mut quick_ffmpeg = Command::new("sh");
+        quick_ffmpeg.args(["-c", "echo progress=end"]);
+        bars.deadline = Some(Instant::now() + Duration::from_secs(30));
+        transcoder.run_encoder(&file, quick_ffmpeg, 0.0, &mut bars)?;
         Ok(())
     }
 }
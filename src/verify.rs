@@ -0,0 +1,268 @@
+use camino::{Utf8Path, Utf8PathBuf};
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+use tracing::{info, warn};
+
+use crate::Result;
+use crate::binaries::{self, Binary};
+use crate::database::{Database, TranscodeFile};
+use crate::ffprobe::commandline_error;
+use crate::sidecar;
+
+/// How many parts of a file are decoded when sampling.
+const SAMPLE_COUNT: usize = 4;
+
+/// A part of a file to decode, in seconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Window {
+    pub start: f64,
+    pub length: f64,
+}
+
+/// The parts of a file to decode when only sampling `sample_seconds` at several
+/// offsets: evenly spread from the start to the end of the file, so the same file
+/// is always checked at the same positions. Returns `None` when the samples would
+/// cover most of the file anyway and it's decoded completely.
+pub fn sample_windows(duration: f64, sample_seconds: f64) -> Option<Vec<Window>> {
+    if sample_seconds <= 0.0 || duration <= sample_seconds * SAMPLE_COUNT as f64 {
+        return None;
+    }
+    let last_start = duration - sample_seconds;
+    Some(
+        (0..SAMPLE_COUNT)
+            .map(|i| Window {
+                start: last_start * i as f64 / (SAMPLE_COUNT - 1) as f64,
+                length: sample_seconds,
+            })
+            .collect(),
+    )
+}
+
+fn decode_args(input: &Utf8Path, window: Option<Window>) -> Vec<String> {
+    let mut args: Vec<String> = vec!["-v".into(), "error".into()];
+    if let Some(window) = window {
+        args.extend([
+            "-ss".into(),
+            format!("{:.3}", window.start),
+            "-t".into(),
+            format!("{:.3}", window.length),
+        ]);
+    }
+    args.extend([
+        "-i".into(),
+        input.to_string(),
+        "-f".into(),
+        "null".into(),
+        "-".into(),
+    ]);
+    args
+}
+
+/// Decodes the file, or a part of it, without writing anything. Returns the
+/// decode errors ffmpeg reported, which are empty for an intact file.
+pub fn decode(input: &Utf8Path, window: Option<Window>) -> Result<String> {
+    let output = binaries::command(Binary::Ffmpeg)
+        .args(decode_args(input, window))
+        .output()
+        .map_err(|e| binaries::spawn_error(Binary::Ffmpeg, e))?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stderr).trim().to_owned())
+    } else {
+        Err(commandline_error("ffmpeg", output))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct VerifySummary {
+    pub passed: usize,
+    /// Outputs that are missing or have decode errors, with the errors.
+    pub failed: Vec<(Utf8PathBuf, String)>,
+    /// Files without a recorded output path, transcoded by older versions.
+    pub unrecorded: usize,
+}
+
+/// Checks the output of one file, returning the errors if it failed.
+pub fn verify_file(
+    output: &Utf8Path,
+    duration: f64,
+    sample_seconds: Option<f64>,
+    decode: &(impl Fn(&Utf8Path, Option<Window>) -> Result<String> + Sync),
+) -> Option<String> {
+    if !output.is_file() {
+        return Some("the output file is missing".into());
+    }
+    let windows = match sample_seconds.and_then(|s| sample_windows(duration, s)) {
+        Some(windows) => windows.into_iter().map(Some).collect(),
+        None => vec![None],
+    };
+    let errors: Vec<String> = windows
+        .into_iter()
+        .filter_map(|window| match decode(output, window) {
+            Ok(errors) if errors.is_empty() => None,
+            Ok(errors) => Some(errors),
+            Err(e) => Some(e.to_string()),
+        })
+        .collect();
+    (!errors.is_empty()).then(|| errors.join("\n"))
+}
+
+/// Decodes the outputs of the files in parallel and records the results in the
+/// database and the outputs' sidecars. Decode errors are collected without stopping the batch.
+pub fn verify(
+    database: &Database,
+    files: Vec<TranscodeFile>,
+    sample_seconds: Option<f64>,
+    decode: impl Fn(&Utf8Path, Option<Window>) -> Result<String> + Sync,
+) -> Result<VerifySummary> {
+    let mut summary = VerifySummary::default();
+    let mut outputs = vec![];
+    for file in files {
+        match file.output_path.clone() {
+            Some(output) => outputs.push((file, output)),
+            None => summary.unrecorded += 1,
+        }
+    }
+
+    let progress = ProgressBar::new(outputs.len() as u64).with_style(
+        ProgressStyle::default_bar()
+            .template("Verifying {wide_bar:.cyan/blue} {pos}/{len} {eta}")
+            .expect("bad progressbar template"),
+    );
+    let results: Vec<(i64, Utf8PathBuf, Option<String>)> = outputs
+        .into_par_iter()
+        .map(|(file, output)| {
+            let duration = file
+                .ffprobe()
+                .and_then(|info| info.duration())
+                .unwrap_or_default();
+            let error = verify_file(&output, duration, sample_seconds, &decode);
+            progress.inc(1);
+            if let Some(error) = &error {
+                warn!("{output} failed verification: {error}");
+            }
+            (file.id, output, error)
+        })
+        .collect();
+    progress.finish_and_clear();
+
+    for (id, output, error) in results {
+        database.set_verification(id, error.as_deref())?;
+        sidecar::try_update(database, id, None);
+        match error {
+            Some(error) => summary.failed.push((output, error)),
+            None => summary.passed += 1,
+        }
+    }
+    info!(
+        "verified {} outputs, {} failed, {} without a recorded output",
+        summary.passed + summary.failed.len(),
+        summary.failed.len(),
+        summary.unrecorded
+    );
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::database::NewTranscodeFile;
+    use crate::ffprobe::{FfProbe, Format};
+
+    #[test]
+    fn test_sample_windows() {
+        let windows = sample_windows(100.0, 10.0).unwrap();
+        let starts: Vec<_> = windows.iter().map(|w| w.start).collect();
+        assert_eq!(vec![0.0, 30.0, 60.0, 90.0], starts);
+        assert!(windows.iter().all(|w| w.length == 10.0));
+        assert_eq!(Some(windows), sample_windows(100.0, 10.0));
+
+        // samples that would cover the whole file decode all of it
+        assert_eq!(None, sample_windows(40.0, 10.0));
+        assert_eq!(None, sample_windows(0.0, 10.0));
+        assert_eq!(None, sample_windows(100.0, 0.0));
+    }
+
+    #[test]
+    fn test_decode_args() {
+        let window = Window {
+            start: 30.0,
+            length: 10.0,
+        };
+        assert_eq!(
+            vec![
+                "-v", "error", "-ss", "30.000", "-t", "10.000", "-i", "/a.mp4", "-f", "null", "-"
+            ],
+            decode_args("/a.mp4".into(), Some(window))
+        );
+        assert_eq!(
+            vec!["-v", "error", "-i", "/a.mp4", "-f", "null", "-"],
+            decode_args("/a.mp4".into(), None)
+        );
+    }
+
+    #[test]
+    fn test_verify() -> Result<()> {
+        let tempdir = tempfile::tempdir()?;
+        let directory = Utf8Path::from_path(tempdir.path()).unwrap();
+        let db = Database::in_memory()?;
+        let files: Vec<_> = ["/a.mkv", "/broken.mkv", "/gone.mkv", "/old.mkv"]
+            .into_iter()
+            .map(|path| NewTranscodeFile {
+                path: path.into(),
+                file_size: 5,
+                ffprobe_info: FfProbe {
+                    format: Format {
+                        duration: Some("600.0".into()),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            })
+            .collect();
+        db.insert_batch(&files)?;
+        for name in ["a", "broken", "gone"] {
+            let file = db
+                .get_by_path(format!("/{name}.mkv").as_str().into())?
+                .unwrap();
+            let output = directory.join(format!("{name}_av1.mp4"));
+            if name != "gone" {
+                fs::write(&output, b"")?;
+            }
+            db.set_output_path(file.id, &output)?;
+        }
+
+        let summary = verify(&db, db.list()?, Some(5.0), |output, window| {
+            assert_eq!(5.0, window.unwrap().length);
+            if output.as_str().contains("broken") && window.unwrap().start > 500.0 {
+                return Ok("corrupt decoded frame".into());
+            }
+            Ok(String::new())
+        })?;
+        assert_eq!(1, summary.passed);
+        assert_eq!(1, summary.unrecorded);
+        let failed: Vec<_> = summary
+            .failed
+            .iter()
+            .map(|(path, error)| (path.file_name().unwrap(), error.as_str()))
+            .collect();
+        assert_eq!(2, failed.len());
+        assert!(failed.contains(&("broken_av1.mp4", "corrupt decoded frame")));
+        assert!(failed.contains(&("gone_av1.mp4", "the output file is missing")));
+
+        let a = db.get_by_path("/a.mkv".into())?.unwrap();
+        assert!(a.verified_at.is_some());
+        assert!(a.verify_error.is_none());
+        let failed: Vec<_> = db.verify_failed()?.into_iter().map(|f| f.path).collect();
+        assert_eq!(2, failed.len());
+        assert!(failed.contains(&"/broken.mkv".into()));
+
+        let broken = db.get_by_path("/broken.mkv".into())?.unwrap();
+        db.requeue(broken.id)?;
+        let broken = db.get_by_path("/broken.mkv".into())?.unwrap();
+        assert!(broken.verify_error.is_none() && broken.output_path.is_none());
+        assert_eq!(1, db.verify_failed()?.len());
+        Ok(())
+    }
+}
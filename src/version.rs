@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use color_eyre::eyre::eyre;
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+use crate::binaries::{self, Binary};
+use crate::ffprobe::commandline_error;
+
+/// The ffmpeg build used for a run, parsed from `ffmpeg -version`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FfmpegVersion {
+    /// The version from the first line, e.g. `6.1.1-3ubuntu5` or `N-113045-g6d1b6a2b3c-20231230`.
+    pub version: String,
+    /// Versions of the ffmpeg libraries, e.g. `libavcodec` -> `60.31.102`.
+    pub libraries: BTreeMap<String, String>,
+}
+
+impl fmt::Display for FfmpegVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ffmpeg {}", self.version)?;
+        if let Some(libavcodec) = self.libraries.get("libavcodec") {
+            write!(f, " (libavcodec {libavcodec})")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a library line like `libavcodec     60. 31.102 / 60. 31.102`, using
+/// the runtime version on the right.
+fn parse_library(line: &str) -> Option<(String, String)> {
+    let (name, versions) = line.trim().split_once(char::is_whitespace)?;
+    if !name.starts_with("lib") {
+        return None;
+    }
+    let version = versions.rsplit('/').next()?;
+    let version: String = version.chars().filter(|c| !c.is_whitespace()).collect();
+    Some((name.to_string(), version))
+}
+
+pub fn parse(output: &str) -> Result<FfmpegVersion> {
+    let mut lines = output.lines();
+    let version = lines
+        .next()
+        .and_then(|line| line.strip_prefix("ffmpeg version "))
+        .and_then(|rest| rest.split_whitespace().next())
+        .ok_or_else(|| eyre!("unexpected ffmpeg -version output: {output}"))?;
+    let libraries = lines.filter_map(parse_library).collect();
+
+    Ok(FfmpegVersion {
+        version: version.to_string(),
+        libraries,
+    })
+}
+
+/// Runs `ffmpeg -version` and parses its output.
+pub fn ffmpeg_version() -> Result<FfmpegVersion> {
+    let output = binaries::command(Binary::Ffmpeg)
+        .arg("-version")
+        .output()
+        .map_err(|e| binaries::spawn_error(Binary::Ffmpeg, e))?;
+    if output.status.success() {
+        parse(&String::from_utf8_lossy(&output.stdout))
+    } else {
+        Err(commandline_error("ffmpeg", output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UBUNTU: &str =
+        "ffmpeg version 6.1.1-3ubuntu5 Copyright (c) 2000-2023 the FFmpeg developers
+built with gcc 13 (Ubuntu 13.2.0-23ubuntu3)
+configuration: --prefix=/usr --extra-version=3ubuntu5 --toolchain=hardened --enable-libsvtav1
+libavutil      58. 29.100 / 58. 29.100
+libavcodec     60. 31.102 / 60. 31.102
+libavformat    60. 16.100 / 60. 16.100
+libswscale      7.  5.100 /  7.  5.100
+libpostproc    57.  3.100 / 57.  3.100
+";
+
+    const BTBN_MASTER: &str =
+        "ffmpeg version N-113045-g6d1b6a2b3c-20231230 Copyright (c) 2000-2023 the FFmpeg developers
+built with gcc 13.2.0 (crosstool-NG 1.26.0.65_ecc5e41)
+configuration: --prefix=/ffbuild/prefix --pkg-config-flags=--static --enable-libsvtav1
+libavutil      58. 36.101 / 58. 36.101
+libavcodec     60. 37.100 / 60. 37.100
+";
+
+    const BTBN_RELEASE: &str =
+        "ffmpeg version n7.0.1-5-g5f8a8f5b93-20240608 Copyright (c) 2000-2024 the FFmpeg developers
+built with gcc 13.2.0 (crosstool-NG 1.26.0.65_ecc5e41)
+libavcodec     61.  3.100 / 61.  3.100
+";
+
+    const STATIC: &str = "ffmpeg version 6.0-static https://johnvansickle.com/ffmpeg/  Copyright (c) 2000-2023 the FFmpeg developers
+built with gcc 8 (Debian 8.3.0-6)
+libavcodec     60.  3.100 / 60.  3.100
+";
+
+    #[test]
+    fn test_parse_versions() -> Result<()> {
+        let ubuntu = parse(UBUNTU)?;
+        assert_eq!("6.1.1-3ubuntu5", ubuntu.version);
+        assert_eq!("60.31.102", ubuntu.libraries["libavcodec"]);
+        assert_eq!("7.5.100", ubuntu.libraries["libswscale"]);
+        assert_eq!(5, ubuntu.libraries.len());
+
+        let master = parse(BTBN_MASTER)?;
+        assert_eq!("N-113045-g6d1b6a2b3c-20231230", master.version);
+        assert_eq!("60.37.100", master.libraries["libavcodec"]);
+
+        let release = parse(BTBN_RELEASE)?;
+        assert_eq!("n7.0.1-5-g5f8a8f5b93-20240608", release.version);
+        assert_eq!(
+            "ffmpeg n7.0.1-5-g5f8a8f5b93-20240608 (libavcodec 61.3.100)",
+            release.to_string()
+        );
+
+        assert_eq!("6.0-static", parse(STATIC)?.version);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_garbage() {
+        assert!(parse("").is_err());
+        assert!(parse("command not found").is_err());
+    }
+}
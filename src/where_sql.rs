@@ -0,0 +1,303 @@
+//! The condition of `--where`, a piece of SQL that is added to the filters of a
+//! command. It's checked to be a single expression that only reads, so it can't
+//! end the query it's put into or run a statement of its own.
+
+use std::fmt;
+use std::str::FromStr;
+
+use rusqlite::types::Value;
+
+/// Words that only appear in statements that change the database or read other
+/// databases, or that combine whole queries.
+const FORBIDDEN_WORDS: &[&str] = &[
+    "ALTER",
+    "ANALYZE",
+    "ATTACH",
+    "BEGIN",
+    "COMMIT",
+    "CREATE",
+    "DELETE",
+    "DETACH",
+    "DROP",
+    "EXCEPT",
+    "INSERT",
+    "INTERSECT",
+    "INTO",
+    "LOAD_EXTENSION",
+    "PRAGMA",
+    "RECURSIVE",
+    "REINDEX",
+    "RELEASE",
+    "ROLLBACK",
+    "SAVEPOINT",
+    "TRANSACTION",
+    "UNION",
+    "UPDATE",
+    "VACUUM",
+    "WITH",
+];
+
+/// A condition from `--where` with `?` for every value passed with `--param`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WhereSql {
+    /// The condition split at its placeholders.
+    parts: Vec<String>,
+}
+
+impl WhereSql {
+    /// How many `?` the condition has.
+    pub fn placeholders(&self) -> usize {
+        self.parts.len() - 1
+    }
+
+    /// The condition in parentheses, with its placeholders numbered from `first`
+    /// so that it can follow the other conditions of a query.
+    pub fn to_sql(&self, first: usize) -> String {
+        let mut sql = String::from("(");
+        for (index, part) in self.parts.iter().enumerate() {
+            if index > 0 {
+                sql.push_str(&format!("?{}", first + index - 1));
+            }
+            sql.push_str(part);
+        }
+        sql.push(')');
+        sql
+    }
+}
+
+impl fmt::Display for WhereSql {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.parts.join("?"))
+    }
+}
+
+impl FromStr for WhereSql {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().is_empty() {
+            return Err("the condition is empty".into());
+        }
+        let chars: Vec<char> = s.chars().collect();
+        let mut parts = vec![];
+        let mut part = String::new();
+        let mut depth = 0usize;
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            match c {
+                '\'' | '"' | '`' | '[' => {
+                    let close = if c == '[' { ']' } else { c };
+                    let end = quoted_end(&chars, i, close).ok_or_else(|| {
+                        format!("the quote {c} at position {} is never closed", i + 1)
+                    })?;
+                    part.extend(&chars[i..=end]);
+                    i = end + 1;
+                    continue;
+                }
+                ';' => return Err("only a single condition is allowed, without ;".into()),
+                '-' if chars.get(i + 1) == Some(&'-') => {
+                    return Err("comments are not allowed".into());
+                }
+                '/' if chars.get(i + 1) == Some(&'*') => {
+                    return Err("comments are not allowed".into());
+                }
+                '\0' => return Err("the condition contains a NUL character".into()),
+                '(' => depth += 1,
+                ')' => {
+                    depth = depth
+                        .checked_sub(1)
+                        .ok_or_else(|| format!("the ) at position {} has no matching (", i + 1))?;
+                }
+                '?' => {
+                    if chars.get(i + 1).is_some_and(char::is_ascii_digit) {
+                        return Err("use ? without a number for the values of --param".into());
+                    }
+                    parts.push(std::mem::take(&mut part));
+                    i += 1;
+                    continue;
+                }
+                ':' | '@' | '$' if chars.get(i + 1).is_some_and(|&c| is_word_char(c)) => {
+                    return Err(format!(
+                        "named parameters like {c}name are not supported, use ? and --param"
+                    ));
+                }
+                c if is_word_char(c) => {
+                    let end = (i..chars.len())
+                        .find(|&j| !is_word_char(chars[j]))
+                        .unwrap_or(chars.len());
+                    let word: String = chars[i..end].iter().collect();
+                    let upper = word.to_uppercase();
+                    if FORBIDDEN_WORDS.contains(&upper.as_str()) {
+                        return Err(format!(
+                            "{upper} is not allowed, the condition can only read the files"
+                        ));
+                    }
+                    part.push_str(&word);
+                    i = end;
+                    continue;
+                }
+                _ => {}
+            }
+            part.push(c);
+            i += 1;
+        }
+        if depth > 0 {
+            return Err(format!("{depth} ( are never closed"));
+        }
+        parts.push(part);
+        Ok(WhereSql { parts })
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+/// The index of the quote that closes the one at `start`. A doubled quote
+/// inside stands for the quote itself.
+fn quoted_end(chars: &[char], start: usize, close: char) -> Option<usize> {
+    let mut i = start + 1;
+    while i < chars.len() {
+        if chars[i] == close {
+            if close != ']' && chars.get(i + 1) == Some(&close) {
+                i += 2;
+                continue;
+            }
+            return Some(i);
+        }
+        i += 1;
+    }
+    None
+}
+
+/// The value of a `--param`. Numbers are bound as numbers, so that they compare
+/// like the columns they are compared with.
+pub fn param_value(param: &str) -> Value {
+    if let Ok(integer) = param.parse::<i64>() {
+        Value::Integer(integer)
+    } else if let Ok(real) = param.parse::<f64>()
+        && real.is_finite()
+    {
+        Value::Real(real)
+    } else {
+        Value::Text(param.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<WhereSql, String> {
+        s.parse()
+    }
+
+    #[test]
+    fn test_valid_conditions() {
+        for condition in [
+            "file_size > 5e9",
+            "file_size > 5e9 AND json_extract(ffprobe_json(ffprobe_info), '$.format.format_name') LIKE '%avi%'",
+            "path LIKE '%; DROP TABLE runs; --%'",
+            "path = 'it''s here'",
+            "\"status\" = 'error' OR [library] IS NULL",
+            "id IN (SELECT file_id FROM crf_attempts WHERE crf > 30)",
+            "replace(path, '/mnt', '') LIKE '/movies/%'",
+            "updated_on > strftime('%s', 'now', '-7 days')",
+            "note IS NOT NULL AND (pinned OR status = 'skipped')",
+            "path LIKE '%(%'",
+            "CASE WHEN file_size > 1e9 THEN 1 ELSE 0 END = 1",
+        ] {
+            let sql = parse(condition).unwrap_or_else(|e| panic!("{condition}: {e}"));
+            assert_eq!(condition, sql.to_string());
+            assert_eq!(0, sql.placeholders());
+            assert_eq!(format!("({condition})"), sql.to_sql(1));
+        }
+    }
+
+    #[test]
+    fn test_placeholders() {
+        let sql = parse("file_size > ? AND path LIKE ? AND note = '?'").unwrap();
+        assert_eq!(2, sql.placeholders());
+        assert_eq!(
+            "(file_size > ?3 AND path LIKE ?4 AND note = '?')",
+            sql.to_sql(3)
+        );
+        assert_eq!(
+            "file_size > ? AND path LIKE ? AND note = '?'",
+            sql.to_string()
+        );
+    }
+
+    #[test]
+    fn test_rejected_conditions() {
+        for (condition, error) in [
+            ("", "empty"),
+            ("   ", "empty"),
+            ("1; DROP TABLE transcode_files", ";"),
+            ("1;", ";"),
+            ("status = 'error';", ";"),
+            ("1 -- and the rest", "comments"),
+            ("1 /* comment */", "comments"),
+            ("path = 'unterminated", "never closed"),
+            ("\"status = 'error'", "never closed"),
+            ("[status = 1", "never closed"),
+            ("`status = 1", "never closed"),
+            ("1) OR (1", "no matching ("),
+            (") OR 1 = (1", "no matching ("),
+            ("(1 OR 1", "never closed"),
+            ("1 = 1 UNION SELECT * FROM runs", "UNION"),
+            (
+                "pragma_table_info('runs') IS NULL OR 1 IN (PRAGMA user_version)",
+                "PRAGMA",
+            ),
+            ("ATTACH 'other.db' AS other", "ATTACH"),
+            (
+                "id IN (WITH RECURSIVE x AS (SELECT 1) SELECT * FROM x)",
+                "WITH",
+            ),
+            ("load_extension('evil.so') IS NULL", "LOAD_EXTENSION"),
+            ("1 = 1) ; DELETE FROM runs WHERE (1", "no matching ("),
+            ("id IN (DELETE FROM runs RETURNING id)", "DELETE"),
+            ("id IN (INSERT INTO runs DEFAULT VALUES)", "INSERT"),
+            ("(SELECT 1 INTO x)", "INTO"),
+            ("UpDaTe", "UPDATE"),
+            ("file_size > ?1", "without a number"),
+            ("file_size > :size", "named parameters"),
+            ("file_size > @size", "named parameters"),
+            ("file_size > $size", "named parameters"),
+            ("path = 'a'\0", "NUL"),
+        ] {
+            let result = parse(condition);
+            assert!(
+                result.as_ref().is_err_and(|e| e.contains(error)),
+                "{condition:?} gave {result:?}, expected an error about {error}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_forbidden_words_in_literals_and_names() {
+        // only whole words outside of quotes count
+        for condition in [
+            "path LIKE '%drop%'",
+            "note = 'pragma'",
+            "\"update\" = 1",
+            "path LIKE '%Union Station%'",
+            "updated_on > 0",
+            "created_on > 0",
+            "library = 'Withnail'",
+        ] {
+            assert!(parse(condition).is_ok(), "{condition}");
+        }
+    }
+
+    #[test]
+    fn test_param_value() {
+        assert_eq!(Value::Integer(5), param_value("5"));
+        assert_eq!(Value::Real(5e9), param_value("5e9"));
+        assert_eq!(Value::Text("%avi%".into()), param_value("%avi%"));
+        assert_eq!(Value::Text("NaN".into()), param_value("NaN"));
+        assert_eq!(Value::Text("inf".into()), param_value("inf"));
+    }
+}